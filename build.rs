@@ -0,0 +1,43 @@
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Embeds the commit and time of the build for `GET /version`, and the
+/// version of resvg for the fingerprint of `--deterministic-render`.
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=EPS_GIT_COMMIT={}", commit.trim());
+
+    // Reproducible builds set the time themselves
+    let timestamp = env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        now.map_or(0, |since| since.as_secs()).to_string()
+    });
+    println!("cargo:rustc-env=EPS_BUILD_TIMESTAMP={timestamp}");
+
+    // Part of the render pipeline fingerprint of --deterministic-render
+    let resvg_version = std::fs::read_to_string("Cargo.lock")
+        .ok()
+        .and_then(|lock| {
+            let package = lock
+                .split("[[package]]")
+                .find(|package| package.contains("\nname = \"resvg\"\n"))?;
+            let version = package.split("\nversion = \"").nth(1)?;
+            Some(version[..version.find('"')?].to_owned())
+        })
+        .unwrap_or_default();
+    println!("cargo:rustc-env=EPS_RESVG_VERSION={resvg_version}");
+
+    for path in [".git/HEAD", ".git/refs", "src", "Cargo.toml", "Cargo.lock"] {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
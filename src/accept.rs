@@ -0,0 +1,154 @@
+use mime::Mime;
+
+/// A media range from an `Accept` header together with its quality value.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MediaRange {
+    pub mime: Mime,
+    pub q: f32,
+}
+
+impl MediaRange {
+    fn matches(&self, mime: &Mime) -> bool {
+        (self.mime.type_() == mime::STAR || self.mime.type_() == mime.type_())
+            && (self.mime.subtype() == mime::STAR || self.mime.subtype() == mime.subtype())
+    }
+
+    /// Higher is more specific: `*/*` < `type/*` < `type/subtype`.
+    fn specificity(&self) -> u8 {
+        match (self.mime.type_(), self.mime.subtype()) {
+            (mime::STAR, _) => 0,
+            (_, mime::STAR) => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Parses an `Accept` header value, skipping malformed entries.
+pub(crate) fn parse_accept(header: &str) -> Vec<MediaRange> {
+    header
+        .split(',')
+        .filter_map(|range| {
+            let mime: Mime = range.trim().parse().ok()?;
+            let q = match mime.get_param("q") {
+                Some(q) => q.as_str().parse::<f32>().ok()?.clamp(0.0, 1.0),
+                None => 1.0,
+            };
+            Some(MediaRange { mime, q })
+        })
+        .collect()
+}
+
+/// Picks the entry of `supported` the client prefers most. The quality of each
+/// supported type comes from the most specific matching range; ties are broken
+/// by the order of `supported`. A missing header accepts anything.
+pub(crate) fn negotiate<'a>(accept: Option<&str>, supported: &'a [Mime]) -> Option<&'a Mime> {
+    let ranges = match accept {
+        Some(accept) => parse_accept(accept),
+        None => return supported.first(),
+    };
+
+    let mut best: Option<(&Mime, f32)> = None;
+    for mime in supported {
+        let q = ranges
+            .iter()
+            .filter(|range| range.matches(mime))
+            .max_by_key(|range| range.specificity())
+            .map_or(0.0, |range| range.q);
+        if q > 0.0 && !matches!(best, Some((_, best_q)) if best_q >= q) {
+            best = Some((mime, q));
+        }
+    }
+    best.map(|(mime, _)| mime)
+}
+
+/// Whether an `Accept-Encoding` header allows `encoding`. Unlike `identity`,
+/// other encodings are only acceptable if listed, explicitly or as `*`.
+pub(crate) fn accepts_encoding(header: Option<&str>, encoding: &str) -> bool {
+    let Some(header) = header else {
+        return false;
+    };
+    let mut wildcard = None;
+    for entry in header.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return q > 0.0;
+        }
+        if name == "*" {
+            wildcard = Some(q > 0.0);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supported() -> Vec<Mime> {
+        vec![
+            mime::IMAGE_PNG,
+            mime::IMAGE_SVG,
+            mime::IMAGE_BMP,
+            mime::APPLICATION_OCTET_STREAM,
+        ]
+    }
+
+    #[test]
+    fn parse_accept_q_values() {
+        let ranges = parse_accept("image/png;q=0.5, image/*, invalid, text/plain;q=2");
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].q, 0.5);
+        assert_eq!(ranges[1].q, 1.0);
+        assert_eq!(ranges[2].q, 1.0);
+    }
+
+    #[test]
+    fn accepts_encoding_gzip() {
+        let cases = [
+            (None, false),
+            (Some("gzip"), true),
+            (Some("deflate, GZIP;q=0.5"), true),
+            (Some("gzip;q=0, *"), false),
+            (Some("br, *"), true),
+            (Some("br, *;q=0"), false),
+            (Some("identity"), false),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(accepts_encoding(header, "gzip"), expected, "{header:?}");
+        }
+    }
+
+    #[test]
+    fn negotiate_preference() {
+        let supported = supported();
+        let cases = [
+            (None, Some(mime::IMAGE_PNG)),
+            (Some("image/svg+xml"), Some(mime::IMAGE_SVG)),
+            (Some("*/*"), Some(mime::IMAGE_PNG)),
+            (Some("image/*"), Some(mime::IMAGE_PNG)),
+            (
+                Some("image/png;q=0.5, image/bmp;q=0.8"),
+                Some(mime::IMAGE_BMP),
+            ),
+            (
+                Some("image/*;q=0.9, application/octet-stream"),
+                Some(mime::APPLICATION_OCTET_STREAM),
+            ),
+            (Some("image/*, image/png;q=0"), Some(mime::IMAGE_SVG)),
+            (Some("text/html, application/json"), None),
+            (Some("image/png;q=0"), None),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(
+                negotiate(accept, &supported).cloned(),
+                expected,
+                "{accept:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::image_handler::EpdMac;
+
+/// Length of a MAC in hex digits, the start of every artifact's file name.
+const MAC_CHARS: usize = 16;
+
+/// A file kept for a MAC in the image directory, named `<mac><suffix>`.
+///
+/// Every file name of a MAC is built by [`file_name`] and recognized by
+/// [`parse`], so that deleting a MAC and cleaning up the image directory know
+/// about every artifact added here.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum Artifact {
+    Svg,
+    Png,
+    Bmp,
+    /// Metadata of [`FileState`](crate::state::FileState).
+    Meta,
+    /// The latest boot report of a device as it sent it.
+    BootReport,
+    /// The PNG replaced by the last change, kept for
+    /// [`ImageHandler::diff`](crate::image_handler::ImageHandler::diff).
+    PreviousPng,
+    /// The posted document of an SVG stored with its text converted to
+    /// paths or its colors mapped.
+    OriginalSvg,
+    /// A render prepared for
+    /// [`ImageHandler::promote`](crate::image_handler::ImageHandler::promote).
+    StagingSvg,
+    StagingPng,
+    /// The render replaced by a broadcast, see
+    /// [`ImageHandler::start_broadcast`](crate::image_handler::ImageHandler::start_broadcast)
+    /// and [`Artifact::saved_for_broadcast`].
+    BroadcastPng,
+    BroadcastSvg,
+    BroadcastBmp,
+    BroadcastPreviousPng,
+    BroadcastOriginalSvg,
+}
+
+impl Artifact {
+    pub const ALL: [Artifact; 14] = [
+        Artifact::Svg,
+        Artifact::Png,
+        Artifact::Bmp,
+        Artifact::Meta,
+        Artifact::BootReport,
+        Artifact::PreviousPng,
+        Artifact::OriginalSvg,
+        Artifact::StagingSvg,
+        Artifact::StagingPng,
+        Artifact::BroadcastPng,
+        Artifact::BroadcastSvg,
+        Artifact::BroadcastBmp,
+        Artifact::BroadcastPreviousPng,
+        Artifact::BroadcastOriginalSvg,
+    ];
+
+    /// The images a MAC is known by; it is not found without any of them.
+    pub const IMAGES: [Artifact; 3] = [Artifact::Svg, Artifact::Bmp, Artifact::Png];
+
+    /// What a render of a MAC replaces, which a broadcast saves.
+    pub const LIVE_RENDER: [Artifact; 5] = [
+        Artifact::Svg,
+        Artifact::Png,
+        Artifact::Bmp,
+        Artifact::PreviousPng,
+        Artifact::OriginalSvg,
+    ];
+
+    /// What follows the MAC in the file name. Also part of the entity tags of
+    /// the file, so it must not change.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Artifact::Svg => ".svg",
+            Artifact::Png => ".png",
+            Artifact::Bmp => ".bmp",
+            Artifact::Meta => ".meta.json",
+            Artifact::BootReport => ".bootreport.bin",
+            Artifact::PreviousPng => ".png.prev",
+            Artifact::OriginalSvg => ".svg.orig",
+            Artifact::StagingSvg => ".staging.svg",
+            Artifact::StagingPng => ".staging.png",
+            Artifact::BroadcastPng => ".png.broadcast",
+            Artifact::BroadcastSvg => ".svg.broadcast",
+            Artifact::BroadcastBmp => ".bmp.broadcast",
+            Artifact::BroadcastPreviousPng => ".png.prev.broadcast",
+            Artifact::BroadcastOriginalSvg => ".svg.orig.broadcast",
+        }
+    }
+
+    /// Where a broadcast keeps this artifact of the [`Self::LIVE_RENDER`]
+    /// until it ends.
+    pub fn saved_for_broadcast(self) -> Option<Artifact> {
+        match self {
+            Artifact::Svg => Some(Artifact::BroadcastSvg),
+            Artifact::Png => Some(Artifact::BroadcastPng),
+            Artifact::Bmp => Some(Artifact::BroadcastBmp),
+            Artifact::PreviousPng => Some(Artifact::BroadcastPreviousPng),
+            Artifact::OriginalSvg => Some(Artifact::BroadcastOriginalSvg),
+            Artifact::Meta
+            | Artifact::BootReport
+            | Artifact::StagingSvg
+            | Artifact::StagingPng
+            | Artifact::BroadcastPng
+            | Artifact::BroadcastSvg
+            | Artifact::BroadcastBmp
+            | Artifact::BroadcastPreviousPng
+            | Artifact::BroadcastOriginalSvg => None,
+        }
+    }
+
+    /// The artifact this one is kept alongside, which makes it an orphan once
+    /// that is gone.
+    pub fn depends_on(self) -> Option<Artifact> {
+        match self {
+            Artifact::PreviousPng => Some(Artifact::Png),
+            Artifact::OriginalSvg => Some(Artifact::Svg),
+            Artifact::StagingPng => Some(Artifact::StagingSvg),
+            Artifact::Svg
+            | Artifact::Png
+            | Artifact::Bmp
+            | Artifact::Meta
+            | Artifact::BootReport
+            | Artifact::StagingSvg
+            | Artifact::BroadcastPng
+            | Artifact::BroadcastSvg
+            | Artifact::BroadcastBmp
+            | Artifact::BroadcastPreviousPng
+            | Artifact::BroadcastOriginalSvg => None,
+        }
+    }
+
+    /// Whether deleting the images of a MAC removes it. Its metadata is
+    /// removed through the state store instead, and the boot report is the
+    /// device's own.
+    pub fn deleted_with_images(self) -> bool {
+        match self {
+            Artifact::Svg
+            | Artifact::Png
+            | Artifact::Bmp
+            | Artifact::PreviousPng
+            | Artifact::OriginalSvg
+            | Artifact::StagingSvg
+            | Artifact::StagingPng
+            | Artifact::BroadcastPng
+            | Artifact::BroadcastSvg
+            | Artifact::BroadcastBmp
+            | Artifact::BroadcastPreviousPng
+            | Artifact::BroadcastOriginalSvg => true,
+            Artifact::Meta | Artifact::BootReport => false,
+        }
+    }
+
+    /// Whether `--dedup` keeps it once per content. The metadata and boot
+    /// reports differ per MAC anyway.
+    pub fn content_addressed(self) -> bool {
+        match self {
+            Artifact::Svg
+            | Artifact::Png
+            | Artifact::Bmp
+            | Artifact::PreviousPng
+            | Artifact::OriginalSvg
+            | Artifact::StagingSvg
+            | Artifact::StagingPng
+            | Artifact::BroadcastPng
+            | Artifact::BroadcastSvg
+            | Artifact::BroadcastBmp
+            | Artifact::BroadcastPreviousPng
+            | Artifact::BroadcastOriginalSvg => true,
+            Artifact::Meta | Artifact::BootReport => false,
+        }
+    }
+
+    /// Name of the file of its kind in messages, like `PNG`.
+    pub fn kind(self) -> String {
+        self.suffix().trim_start_matches('.').to_uppercase()
+    }
+}
+
+/// Name of the file `artifact` of `mac` in the image directory.
+pub(crate) fn file_name(mac: EpdMac, artifact: Artifact) -> String {
+    mac.to_string() + artifact.suffix()
+}
+
+/// The MAC and artifact of a file name made by [`file_name`]; `None` for any
+/// other file.
+pub(crate) fn parse(name: &str) -> Option<(EpdMac, Artifact)> {
+    if !name.is_char_boundary(MAC_CHARS) {
+        return None;
+    }
+    let (mac, suffix) = name.split_at(MAC_CHARS);
+    let artifact = Artifact::ALL
+        .into_iter()
+        .find(|artifact| artifact.suffix() == suffix)?;
+    Some((mac.parse().ok()?, artifact))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn mac() -> EpdMac {
+        "0011223344556677".parse().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        for artifact in Artifact::ALL {
+            let name = file_name(mac(), artifact);
+            assert_eq!(parse(&name), Some((mac(), artifact)), "{name}");
+        }
+        // Stops compiling when a variant is added, until it is also listed
+        // in `ALL`
+        let position = |artifact| match artifact {
+            Artifact::Svg => 0,
+            Artifact::Png => 1,
+            Artifact::Bmp => 2,
+            Artifact::Meta => 3,
+            Artifact::BootReport => 4,
+            Artifact::PreviousPng => 5,
+            Artifact::OriginalSvg => 6,
+            Artifact::StagingSvg => 7,
+            Artifact::StagingPng => 8,
+            Artifact::BroadcastPng => 9,
+            Artifact::BroadcastSvg => 10,
+            Artifact::BroadcastBmp => 11,
+            Artifact::BroadcastPreviousPng => 12,
+            Artifact::BroadcastOriginalSvg => 13,
+        };
+        for artifact in Artifact::LIVE_RENDER {
+            let saved = artifact.saved_for_broadcast().unwrap();
+            assert_eq!(saved.saved_for_broadcast(), None, "{artifact:?}");
+        }
+        for (n, artifact) in Artifact::ALL.into_iter().enumerate() {
+            assert_eq!(position(artifact), n, "{artifact:?}");
+        }
+    }
+
+    #[test]
+    fn no_collisions() {
+        let suffixes: HashSet<_> = Artifact::ALL.iter().map(|a| a.suffix()).collect();
+        assert_eq!(suffixes.len(), Artifact::ALL.len());
+        for artifact in Artifact::ALL {
+            let suffix = artifact.suffix();
+            assert!(suffix.starts_with('.'), "{suffix}");
+            // The name of one artifact never reads as another one of a MAC
+            // that ends with the start of its suffix
+            for other in Artifact::ALL {
+                let name = file_name(mac(), other) + suffix;
+                assert_eq!(parse(&name), None, "{name}");
+            }
+        }
+
+        for name in [
+            "",
+            ".png",
+            "0011223344556677",
+            "001122334455667.png",
+            "00112233445566778.png",
+            "001122334455667g.png",
+            "0011223344556677.PNG",
+            "0011223344556677.png.tmp",
+            "001122334455667ä.png",
+            "broadcast.json",
+            "state.sqlite3",
+        ] {
+            assert_eq!(parse(name), None, "{name}");
+        }
+        assert_eq!(
+            parse("00112233445566AA.png"),
+            Some(("00112233445566aa".parse().unwrap(), Artifact::Png))
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{auth, error::AppError, image_handler::EpdMac, ip_filter::ClientIp, AppState};
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Operation {
+    Render,
+    Upload,
+    Delete,
+    Playlist,
+    Groups,
+    ResponseHeaders,
+    Promote,
+    DisplayProfile,
+    RenderOptions,
+    Broadcast,
+    PollInterval,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub operation: Operation,
+    pub mac: String,
+    pub client_ip: Option<IpAddr>,
+    pub request_id: Option<String>,
+    pub principal: Option<String>,
+    /// SHA-256 of the stored PNG after the operation.
+    pub content_hash: Option<String>,
+}
+
+/// Request details recorded with every audit entry.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RequestContext {
+    pub client_ip: Option<IpAddr>,
+    pub request_id: Option<String>,
+    pub principal: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for RequestContext {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let client_ip = parts.extensions.get::<ClientIp>().map(|&ClientIp(ip)| ip);
+        let request_id = parts
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        Ok(RequestContext {
+            client_ip,
+            request_id,
+            principal: auth::principal(parts, state).await,
+        })
+    }
+}
+
+enum Command {
+    Append(AuditEntry),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Append-only JSON lines log of mutating operations. Lines are written by a
+/// single background task so they never interleave and requests don't wait
+/// for the disk.
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    sender: mpsc::UnboundedSender<Command>,
+}
+
+impl AuditLog {
+    /// Starts the writer task. Once the file would grow beyond `max_bytes` it
+    /// is rotated to `<path>.1`, replacing the previous rotation.
+    pub fn spawn(path: PathBuf, max_bytes: u64) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let writer_path = path.clone();
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                match command {
+                    Command::Append(entry) => {
+                        if let Err(e) = append(&writer_path, &entry, max_bytes).await {
+                            tracing::error!("Could not write audit log entry {entry:?}: {e:#}");
+                        }
+                    }
+                    Command::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        AuditLog { path, sender }
+    }
+
+    pub fn record(
+        &self,
+        operation: Operation,
+        mac: EpdMac,
+        context: RequestContext,
+        content_hash: Option<String>,
+    ) {
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            operation,
+            mac: mac.to_string(),
+            client_ip: context.client_ip,
+            request_id: context.request_id,
+            principal: context.principal,
+            content_hash,
+        };
+        if self.sender.send(Command::Append(entry)).is_err() {
+            tracing::error!("Audit log writer is gone");
+        }
+    }
+
+    /// Returns the last `limit` entries, optionally only those for `mac`,
+    /// oldest first.
+    pub async fn query(&self, mac: Option<EpdMac>, limit: usize) -> eyre::Result<Vec<AuditEntry>> {
+        // Wait for everything recorded so far to reach the file
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(Command::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+
+        let mut entries = Vec::new();
+        for path in [rotated_path(&self.path), self.path.clone()] {
+            let data = match fs::read_to_string(&path).await {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let mac = mac.map(|mac| mac.to_string());
+            entries.extend(
+                data.lines()
+                    .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                    .filter(|entry| !matches!(&mac, Some(mac) if *mac != entry.mac)),
+            );
+        }
+        let skip = entries.len().saturating_sub(limit);
+        entries.drain(..skip);
+        Ok(entries)
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    rotated.into()
+}
+
+async fn append(path: &Path, entry: &AuditEntry, max_bytes: u64) -> eyre::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+
+    let size = match fs::metadata(path).await {
+        Ok(meta) => meta.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    if size > 0 && size + line.len() as u64 > max_bytes {
+        fs::rename(path, rotated_path(path)).await?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rotation() {
+        let temp_dir = TestDir::temp();
+        let path = temp_dir.path("audit.log");
+        let log = AuditLog::spawn(path.clone(), 600);
+
+        let mac: EpdMac = "0011223344556677".parse().unwrap();
+        for _ in 0..20 {
+            log.record(Operation::Render, mac, RequestContext::default(), None);
+        }
+
+        let entries = log.query(None, 100).await.unwrap();
+        assert!(rotated_path(&path).exists());
+        assert!(std::fs::metadata(&path).unwrap().len() <= 600);
+        // Only the current file and a single rotation are kept
+        assert!(entries.len() < 20);
+        assert_eq!(log.query(Some(mac), 2).await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use axum::http::{header, request::Parts};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use tokio::task;
+
+use crate::{htpasswd::Htpasswd, AppState};
+
+/// Principal recorded for requests authenticated with the admin key.
+pub(crate) const ADMIN_PRINCIPAL: &str = "admin";
+
+/// Users allowed to authenticate with Basic auth, from `--htpasswd`.
+pub(crate) struct Credentials {
+    path: Option<PathBuf>,
+    htpasswd: RwLock<Arc<Htpasswd>>,
+}
+
+impl Credentials {
+    /// Loads the htpasswd file at `path`. A file that can't be loaded is
+    /// logged and lets nobody in until it is reloaded successfully.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let htpasswd = match &path {
+            Some(path) => Htpasswd::load(path).unwrap_or_else(|e| {
+                tracing::error!("{e:#}");
+                Htpasswd::default()
+            }),
+            None => Htpasswd::default(),
+        };
+        Credentials {
+            path,
+            htpasswd: RwLock::new(Arc::new(htpasswd)),
+        }
+    }
+
+    /// Re-reads the htpasswd file, keeping the previous users if it fails.
+    pub fn reload(&self) -> eyre::Result<()> {
+        if let Some(path) = &self.path {
+            let htpasswd = Htpasswd::load(path)?;
+            *self.htpasswd.write().unwrap() = Arc::new(htpasswd);
+        }
+        Ok(())
+    }
+}
+
+/// Who authenticated a request, cached in its extensions.
+#[derive(Debug, Clone)]
+struct Authenticated(Option<String>);
+
+/// Returns who authenticated the request, if anyone: [`ADMIN_PRINCIPAL`] for
+/// the admin key, otherwise the user name of Basic credentials.
+pub(crate) async fn principal(parts: &mut Parts, state: &AppState) -> Option<String> {
+    if let Some(Authenticated(principal)) = parts.extensions.get() {
+        return principal.clone();
+    }
+    let principal = authenticate(parts, state).await;
+    parts.extensions.insert(Authenticated(principal.clone()));
+    principal
+}
+
+async fn authenticate(parts: &Parts, state: &AppState) -> Option<String> {
+    let authorization = parts.headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        let admin_key = state.image_handler.config().admin_key.as_ref()?;
+        return constant_time_eq(token.as_bytes(), admin_key.as_bytes())
+            .then(|| ADMIN_PRINCIPAL.to_owned());
+    }
+
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    let (user, password) = (user.to_owned(), password.to_owned());
+    let htpasswd = state.credentials.htpasswd.read().unwrap().clone();
+    // bcrypt takes long enough to hold up other requests
+    task::spawn_blocking(move || htpasswd.verify(&user, &password).then_some(user))
+        .await
+        .ok()?
+}
+
+/// Compares without short-circuiting on the first differing byte.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    any::Any,
+    fmt::Display,
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use eyre::eyre;
+use sha2::{Digest, Sha256};
+use tokio::task;
+
+use crate::{error::AppError, image_handler::EpdMac};
+
+/// What a task that may panic works on, logged with its panics to reproduce
+/// them.
+#[derive(Debug, Clone)]
+pub(crate) struct PanicContext {
+    task: &'static str,
+    mac: EpdMac,
+    source_hash: Option<String>,
+}
+
+impl PanicContext {
+    pub fn new(task: &'static str, mac: EpdMac) -> Self {
+        PanicContext {
+            task,
+            mac,
+            source_hash: None,
+        }
+    }
+
+    /// Adds the SHA-256 of the input of the task.
+    pub fn source(self, source: &[u8]) -> Self {
+        PanicContext {
+            source_hash: Some(hex::encode(Sha256::digest(source))),
+            ..self
+        }
+    }
+}
+
+impl Display for PanicContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of MAC {}", self.task, self.mac)?;
+        if let Some(source_hash) = &self.source_hash {
+            write!(f, " from source {source_hash}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs work on untrusted input, turning its panics into internal server
+/// errors instead of dropped connections.
+#[derive(Debug, Default)]
+pub(crate) struct PanicSafe {
+    panics: AtomicU64,
+}
+
+impl PanicSafe {
+    /// Panics caught since startup.
+    pub fn total(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// Runs `work` on the current thread.
+    pub fn call<T>(
+        &self,
+        context: &PanicContext,
+        work: impl FnOnce() -> Result<T, AppError>,
+    ) -> Result<T, AppError> {
+        panic::catch_unwind(AssertUnwindSafe(work))
+            .unwrap_or_else(|payload| Err(self.panicked(context, payload)))
+    }
+
+    /// Runs `work` on the blocking thread pool.
+    pub async fn spawn_blocking<T: Send + 'static>(
+        &self,
+        context: PanicContext,
+        work: impl FnOnce() -> Result<T, AppError> + Send + 'static,
+    ) -> Result<T, AppError> {
+        match task::spawn_blocking(move || panic::catch_unwind(AssertUnwindSafe(work))).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(payload)) => Err(self.panicked(&context, payload)),
+            Err(e) => Err(AppError::InternalServerError(
+                eyre!(e).wrap_err(format!("The {context} was cancelled")),
+            )),
+        }
+    }
+
+    fn panicked(&self, context: &PanicContext, payload: Box<dyn Any + Send>) -> AppError {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        tracing::error!("The {context} panicked: {message}");
+        AppError::InternalServerError(eyre!("The {context} panicked: {message}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: EpdMac = EpdMac([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+    #[tokio::test]
+    async fn catches_panics() {
+        let safe = PanicSafe::default();
+        let context = PanicContext::new("test", MAC).source(b"<svg/>");
+        assert_eq!(safe.call(&context, || Ok(1)).unwrap(), 1);
+
+        let e = safe
+            .call(&context, || -> Result<(), _> { panic!("at {}", 3) })
+            .unwrap_err();
+        assert!(matches!(e, AppError::InternalServerError(_)));
+        let message = e.to_string();
+        assert!(
+            message.contains("test of MAC 0011223344556677"),
+            "{message}"
+        );
+        assert!(message.ends_with("panicked: at 3"), "{message}");
+
+        let e = safe
+            .spawn_blocking(context, || -> Result<(), _> { panic!("blocking") })
+            .await
+            .unwrap_err();
+        assert!(e
+            .to_string()
+            .contains(&hex::encode(Sha256::digest(b"<svg/>"))));
+        assert_eq!(safe.total(), 2);
+    }
+}
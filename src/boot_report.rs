@@ -0,0 +1,102 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Turns the opaque boot report of a device into the fields the server
+/// understands, for devices whose vendor format is known.
+pub(crate) trait BootReportDecoder: Send + Sync {
+    /// `None` unless the report is in the format of the decoder.
+    fn decode(&self, report: &[u8]) -> Option<BootFields>;
+}
+
+/// What a boot report told about the device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BootFields {
+    /// Battery voltage in millivolts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery_mv: Option<u16>,
+    /// Firmware version like `1.4`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<String>,
+    /// Vendor code of why the device last reset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset_reason: Option<u8>,
+}
+
+/// The latest boot report of a MAC, whose bytes are stored next to its
+/// metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BootReport {
+    pub received: DateTime<Utc>,
+    pub bytes: usize,
+    /// Absent if no decoder recognized the report.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoded: Option<BootFields>,
+}
+
+/// The common type-length-value format: one byte each of type and length,
+/// then the value. Battery voltage is type 1 with a big-endian `u16`,
+/// the firmware version type 2 with major and minor byte and the reset
+/// reason type 3 with one byte. Other types are skipped.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct StandardTlv;
+
+const TLV_BATTERY: u8 = 1;
+const TLV_FIRMWARE: u8 = 2;
+const TLV_RESET_REASON: u8 = 3;
+
+impl BootReportDecoder for StandardTlv {
+    fn decode(&self, mut report: &[u8]) -> Option<BootFields> {
+        let mut fields = BootFields::default();
+        while let [kind, len, rest @ ..] = report {
+            let value = rest.get(..usize::from(*len))?;
+            match (*kind, value) {
+                (TLV_BATTERY, &[high, low]) => {
+                    fields.battery_mv = Some(u16::from_be_bytes([high, low]))
+                }
+                (TLV_FIRMWARE, &[major, minor]) => {
+                    fields.firmware = Some(format!("{major}.{minor}"))
+                }
+                (TLV_RESET_REASON, &[reason]) => fields.reset_reason = Some(reason),
+                (TLV_BATTERY | TLV_FIRMWARE | TLV_RESET_REASON, _) => return None,
+                _ => {}
+            }
+            report = &rest[value.len()..];
+        }
+        // A dangling type byte means this isn't the format
+        (report.is_empty() && fields != BootFields::default()).then_some(fields)
+    }
+}
+
+/// The fields of the first of `decoders` that recognizes `report`.
+pub(crate) fn decode(decoders: &[Box<dyn BootReportDecoder>], report: &[u8]) -> Option<BootFields> {
+    decoders.iter().find_map(|decoder| decoder.decode(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_tlv() {
+        let report = [
+            0x01, 0x02, 0x0b, 0xb8, // battery 3000 mV
+            0x7f, 0x03, 0xaa, 0xbb, 0xcc, // unknown
+            0x02, 0x02, 0x01, 0x04, // firmware 1.4
+            0x03, 0x01, 0x05, // reset reason 5
+        ];
+        assert_eq!(
+            StandardTlv.decode(&report),
+            Some(BootFields {
+                battery_mv: Some(3000),
+                firmware: Some("1.4".to_owned()),
+                reset_reason: Some(5),
+            })
+        );
+        // Truncated, dangling, malformed and without known fields
+        assert_eq!(StandardTlv.decode(&report[..report.len() - 1]), None);
+        assert_eq!(StandardTlv.decode(&[0x03, 0x01, 0x05, 0x01]), None);
+        assert_eq!(StandardTlv.decode(&[0x01, 0x01, 0x05]), None);
+        assert_eq!(StandardTlv.decode(&[0x7f, 0x00]), None);
+        assert_eq!(StandardTlv.decode(b""), None);
+    }
+}
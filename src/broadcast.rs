@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ErrorBody,
+    image_handler::EpdMac,
+    metadata::{RenderRecord, Rerender},
+    render_options::RenderOverrides,
+};
+
+/// Marker file in the image directory of the broadcast in progress.
+pub(crate) const BROADCAST_FILE: &str = "broadcast.json";
+
+/// Body of `POST /broadcast`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Broadcast {
+    /// SVG fragment, rendered like the body of `render_svg` for every MAC.
+    pub svg: String,
+    /// Render options of all MACs, like the query of `render_svg`.
+    #[serde(flatten)]
+    pub options: RenderOverrides,
+}
+
+/// A broadcast shown until `DELETE /broadcast` restores the images it
+/// replaced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ActiveBroadcast {
+    #[serde(flatten)]
+    pub broadcast: Broadcast,
+    pub started: DateTime<Utc>,
+    /// The MACs whose images were saved before showing the broadcast.
+    #[serde(default)]
+    pub targets: BTreeMap<EpdMac, Target>,
+}
+
+/// A MAC of a broadcast.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Target {
+    /// Whether the broadcast was rendered for it.
+    pub shown: bool,
+    /// Its scheduled render, which the broadcast replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rerender: Option<Rerender>,
+    /// The last entry of its render log, which is the one of the saved
+    /// render again once it is restored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_render: Option<RenderRecord>,
+    /// SHA-256 of the PNG of the broadcast. Once the live PNG is another
+    /// one, it was rendered during the broadcast and is kept instead of the
+    /// saved render.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub png_hash: Option<String>,
+}
+
+/// Outcome of showing a broadcast on a MAC or of restoring the MAC.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BroadcastResult {
+    pub mac: String,
+    /// Why it failed, as it would have been returned by `render_svg`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marker_round_trip() {
+        let active: ActiveBroadcast = serde_json::from_str(
+            r#"{
+                "svg": "<text>Fire drill</text>",
+                "rotate": 180,
+                "started": "2024-03-01T08:00:00Z",
+                "targets": {"AABBCCDDEEFFAABB": {"shown": true}}
+            }"#,
+        )
+        .unwrap();
+        let mac: EpdMac = "aabbccddeeffaabb".parse().unwrap();
+        assert!(active.targets[&mac].shown);
+        assert!(!active.broadcast.options.is_empty());
+        let json = serde_json::to_string(&active).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ActiveBroadcast>(&json).unwrap(),
+            active
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use std::{collections::BTreeSet, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use eyre::bail;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    display_profile::DisplayProfile, lint::LintOverrides, metadata::MacMetadata,
+    precondition::Validators, region::Regions,
+};
+
+/// Image of a MAC that can be part of a bundle.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Part {
+    Svg,
+    Png,
+    Raw,
+}
+
+impl FromStr for Part {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "svg" => Ok(Part::Svg),
+            "png" => Ok(Part::Png),
+            "raw" => Ok(Part::Raw),
+            _ => bail!("Unknown bundle part '{s}', expected svg, png or raw"),
+        }
+    }
+}
+
+/// The images to put into a bundle, from its `include` query parameter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Include {
+    pub parts: BTreeSet<Part>,
+    /// Whether the parts were requested, which includes them regardless of
+    /// their size.
+    pub explicit: bool,
+}
+
+impl Default for Include {
+    /// All parts, leaving out those over the size limit.
+    fn default() -> Self {
+        Include {
+            parts: [Part::Svg, Part::Png, Part::Raw].into(),
+            explicit: false,
+        }
+    }
+}
+
+impl FromStr for Include {
+    type Err = eyre::Error;
+
+    /// Comma separated parts, like `svg,raw`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split(',')
+            .filter(|part| !part.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Include {
+            parts,
+            explicit: true,
+        })
+    }
+}
+
+/// Panel the images of a bundle are made for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Profile {
+    pub width: u32,
+    pub height: u32,
+    /// Colors of the panel in the order of their indices in the raw image,
+    /// absent for black and white panels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette: Option<String>,
+    /// How the images are put onto the panel, already applied to them.
+    #[serde(flatten)]
+    pub display: DisplayProfile,
+    /// Parts of the panel that can be fetched on their own.
+    #[serde(default, skip_serializing_if = "Regions::is_empty")]
+    pub regions: Regions,
+    /// Lint thresholds replacing the configured ones for renders of the MAC.
+    #[serde(default, skip_serializing_if = "LintOverrides::is_empty")]
+    pub lint: LintOverrides,
+}
+
+/// Everything a gateway needs to serve a MAC offline, as one JSON document.
+/// Images are base64 encoded and absent if they don't exist or weren't
+/// included.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Bundle {
+    pub mac: String,
+    pub etag: String,
+    pub profile: Profile,
+    pub metadata: MacMetadata,
+    /// SHA-256 of the stored SVG.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub svg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub png: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+    /// Parts left out for exceeding `--bundle-max-bytes`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub omitted: Vec<Part>,
+}
+
+impl Bundle {
+    /// Adds `data` as `part`, unless it is a PNG or raw image larger than
+    /// `max_bytes` that wasn't requested explicitly.
+    pub fn insert(&mut self, part: Part, data: &[u8], include: &Include, max_bytes: u64) {
+        if part != Part::Svg && !include.explicit && data.len() as u64 > max_bytes {
+            self.omitted.push(part);
+            return;
+        }
+        let encoded = Some(BASE64.encode(data));
+        match part {
+            Part::Svg => self.svg = encoded,
+            Part::Png => self.png = encoded,
+            Part::Raw => self.raw = encoded,
+        }
+    }
+}
+
+/// Validators of a bundle, changing whenever one of the files it is made of
+/// or what it includes changes. `files` are the validators of the SVG, PNG
+/// and metadata files, `None` for missing ones.
+pub(crate) fn validators(
+    files: [Option<&Validators>; 3],
+    include: &Include,
+    profile: &Profile,
+) -> Option<Validators> {
+    let last_modified = files
+        .iter()
+        .flatten()
+        .map(|file| file.last_modified)
+        .max()?;
+    let mut hash = Sha256::new();
+    for file in files {
+        hash.update(file.map_or("-", |file| file.etag.as_str()));
+        hash.update([0]);
+    }
+    hash.update(format!(
+        "{:?}{}{:?}",
+        include.parts, include.explicit, profile
+    ));
+    let hash = hash.finalize();
+    Some(Validators {
+        etag: format!("\"bundle-{}\"", hex::encode(&hash[..16])),
+        last_modified,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_include() {
+        let include: Include = "raw,svg".parse().unwrap();
+        assert_eq!(include.parts, [Part::Svg, Part::Raw].into());
+        assert!(include.explicit);
+        assert!("png,bmp".parse::<Include>().is_err());
+        assert!(!Include::default().explicit);
+    }
+}
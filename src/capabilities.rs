@@ -0,0 +1,226 @@
+use std::fmt::Display;
+
+use clap::ValueEnum;
+use eyre::{bail, ensure};
+use hyper::{header::HeaderName, HeaderMap};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+
+use crate::{derived::DerivedFormat, rle};
+
+const FORMAT: HeaderName = HeaderName::from_static("x-eps-format");
+const COMPRESSION: HeaderName = HeaderName::from_static("x-eps-compression");
+pub(crate) const UNCOMPRESSED_LENGTH: HeaderName =
+    HeaderName::from_static("x-eps-uncompressed-length");
+
+/// Formats a device can decode, for `GET /macs/:mac/payload`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PayloadFormat {
+    /// The raw panel format of `GET /macs/:mac/raw`.
+    Raw1,
+    Bmp,
+    Png,
+}
+
+impl PayloadFormat {
+    /// The format stored or derived for this one.
+    pub fn derived(self) -> Option<DerivedFormat> {
+        match self {
+            PayloadFormat::Raw1 => Some(DerivedFormat::Raw),
+            PayloadFormat::Bmp => Some(DerivedFormat::Bmp),
+            PayloadFormat::Png => None,
+        }
+    }
+
+    pub fn mime(self) -> Mime {
+        match self {
+            PayloadFormat::Raw1 => mime::APPLICATION_OCTET_STREAM,
+            PayloadFormat::Bmp => mime::IMAGE_BMP,
+            PayloadFormat::Png => mime::IMAGE_PNG,
+        }
+    }
+}
+
+impl Display for PayloadFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PayloadFormat::Raw1 => "raw1",
+            PayloadFormat::Bmp => "bmp",
+            PayloadFormat::Png => "png",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Order of the pixels in each byte of the raw format.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BitOrder {
+    /// The leftmost pixel is the most significant bit.
+    #[default]
+    Msb,
+    Lsb,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compression {
+    #[default]
+    None,
+    /// Runs of white and black pixels of the raw format, see [`rle::encode`].
+    Rle,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Rle => write!(f, "rle"),
+        }
+    }
+}
+
+/// What a device reported it can decode, in `PUT /macs/:mac/capabilities`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Capabilities {
+    pub formats: Vec<PayloadFormat>,
+    #[serde(default)]
+    pub bit_order: BitOrder,
+    /// Largest payload in bytes the device can receive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_payload: Option<usize>,
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+}
+
+impl Capabilities {
+    /// The capabilities assumed for devices that didn't report any.
+    pub fn only(format: PayloadFormat) -> Self {
+        Capabilities {
+            formats: vec![format],
+            bit_order: BitOrder::Msb,
+            max_payload: None,
+            compression: vec![],
+        }
+    }
+
+    pub fn validate(&self) -> eyre::Result<()> {
+        ensure!(!self.formats.is_empty(), "At least one format is required");
+        Ok(())
+    }
+
+    /// The supported formats, the ones that are quickest to decode first.
+    pub fn preferred_formats(&self) -> impl Iterator<Item = PayloadFormat> + '_ {
+        [PayloadFormat::Raw1, PayloadFormat::Bmp, PayloadFormat::Png]
+            .into_iter()
+            .filter(|format| self.formats.contains(format))
+    }
+
+    /// Prepares `data` in `format` for the device: applies its bit order and
+    /// compresses raw data if that makes it smaller. Fails if the result
+    /// exceeds `max_payload`.
+    pub fn encode(&self, format: PayloadFormat, mut data: Vec<u8>) -> eyre::Result<Payload> {
+        if format == PayloadFormat::Raw1 && self.bit_order == BitOrder::Lsb {
+            for byte in &mut data {
+                *byte = byte.reverse_bits();
+            }
+        }
+        let uncompressed_len = data.len();
+        let mut compression = Compression::None;
+        if format == PayloadFormat::Raw1 && self.compression.contains(&Compression::Rle) {
+            let compressed = rle::encode(&data);
+            if compressed.len() < data.len() {
+                data = compressed;
+                compression = Compression::Rle;
+            }
+        }
+        if let Some(max_payload) = self.max_payload {
+            if data.len() > max_payload {
+                bail!(
+                    "The {format} payload has {} bytes, more than the {max_payload} the device accepts",
+                    data.len()
+                );
+            }
+        }
+        Ok(Payload {
+            format,
+            compression,
+            uncompressed_len,
+            data,
+        })
+    }
+}
+
+/// An image encoded for a device.
+#[derive(Debug)]
+pub(crate) struct Payload {
+    pub format: PayloadFormat,
+    pub compression: Compression,
+    pub uncompressed_len: usize,
+    pub data: Vec<u8>,
+}
+
+impl Payload {
+    /// Compressed payloads are opaque bytes.
+    pub fn content_type(&self) -> Mime {
+        match self.compression {
+            Compression::None => self.format.mime(),
+            Compression::Rle => mime::APPLICATION_OCTET_STREAM,
+        }
+    }
+
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = compression_headers(self.compression, self.uncompressed_len);
+        headers.insert(FORMAT, self.format.to_string().parse().unwrap());
+        headers
+    }
+}
+
+/// Describes data compressed with `compression`, and for compressed data how
+/// large it is decompressed, so that devices can allocate the buffer first.
+pub(crate) fn compression_headers(compression: Compression, uncompressed_len: usize) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(COMPRESSION, compression.to_string().parse().unwrap());
+    if compression != Compression::None {
+        headers.insert(UNCOMPRESSED_LENGTH, uncompressed_len.into());
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_for_device() {
+        let capabilities = Capabilities {
+            formats: vec![PayloadFormat::Png, PayloadFormat::Raw1],
+            bit_order: BitOrder::Lsb,
+            max_payload: Some(4),
+            compression: vec![Compression::None, Compression::Rle],
+        };
+        assert_eq!(
+            capabilities.preferred_formats().collect::<Vec<_>>(),
+            [PayloadFormat::Raw1, PayloadFormat::Png]
+        );
+
+        let payload = capabilities
+            .encode(PayloadFormat::Raw1, vec![0xff; 6])
+            .unwrap();
+        assert_eq!(payload.compression, Compression::Rle);
+        assert_eq!(payload.data, [48]);
+        assert_eq!(payload.uncompressed_len, 6);
+
+        // Not worth compressing
+        let payload = capabilities
+            .encode(PayloadFormat::Raw1, vec![0x80, 0x40])
+            .unwrap();
+        assert_eq!(payload.compression, Compression::None);
+        assert_eq!(payload.data, [0x01, 0x02]);
+
+        assert!(capabilities
+            .encode(PayloadFormat::Raw1, vec![1, 2, 3, 4, 5])
+            .is_err());
+    }
+}
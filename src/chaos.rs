@@ -0,0 +1,255 @@
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    body::{self, Body, HttpBody},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use eyre::{ensure, eyre, Context};
+use futures_util::stream;
+use hyper::{body::Buf, header, HeaderMap, Request, StatusCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, units::HumanDuration};
+
+/// SVGs containing this panic while they are rendered, in debug builds, for
+/// testing that panics are caught.
+pub(crate) const PANIC_MARKER: &[u8] = b"eps-server:chaos-panic";
+
+/// Failures injected into the responses of matching routes, read from the
+/// TOML file of `--chaos-config` and changed with `PUT /chaos`. Only
+/// compiled with the `chaos` feature, for testing how clients cope.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ChaosConfig {
+    /// The first rule whose route matches a request applies to it.
+    #[serde(default)]
+    pub rules: Vec<ChaosRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ChaosRule {
+    /// Path like `/macs/*/png`, where `*` matches one segment. Matches all
+    /// paths if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Added before the request is handled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<HumanDuration>,
+    /// Probability from 0 to 1 of answering with `error_status` instead.
+    #[serde(default)]
+    pub error_rate: f64,
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+    /// Ends the response body after this many bytes, keeping the headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncate_after: Option<u64>,
+    /// Replaces the ETag with a new one on every response.
+    #[serde(default)]
+    pub etag_churn: bool,
+}
+
+fn default_error_status() -> u16 {
+    503
+}
+
+impl ChaosConfig {
+    pub fn validate(&self) -> eyre::Result<()> {
+        for rule in &self.rules {
+            ensure!(
+                (0.0..=1.0).contains(&rule.error_rate),
+                "error_rate must be between 0 and 1, got {}",
+                rule.error_rate
+            );
+            ensure!(
+                matches!(rule.error_status, 500 | 503),
+                "error_status must be 500 or 503, got {}",
+                rule.error_status
+            );
+        }
+        Ok(())
+    }
+
+    fn rule(&self, path: &str) -> Option<&ChaosRule> {
+        self.rules.iter().find(|rule| match &rule.route {
+            Some(route) => matches(route, path),
+            None => true,
+        })
+    }
+}
+
+/// Whether `path` matches `route`, whose `*` segments match any segment.
+fn matches(route: &str, path: &str) -> bool {
+    let route = route.trim_end_matches('/').split('/');
+    let path = path.trim_end_matches('/').split('/');
+    route.clone().count() == path.clone().count()
+        && route
+            .zip(path)
+            .all(|(expected, segment)| expected == "*" || expected == segment)
+}
+
+/// The active chaos rules.
+#[derive(Debug, Default)]
+pub(crate) struct Chaos {
+    config: RwLock<ChaosConfig>,
+}
+
+impl Chaos {
+    /// Starts with the rules in `path`. A file that can't be read is logged
+    /// and ignored.
+    pub fn load(path: Option<&Path>) -> Self {
+        let Some(path) = path else {
+            return Chaos::default();
+        };
+        let config = read(path).unwrap_or_else(|e| {
+            tracing::error!("Ignoring chaos config {}: {e:#}", path.display());
+            ChaosConfig::default()
+        });
+        tracing::warn!("Injecting failures by {} chaos rules", config.rules.len());
+        Chaos {
+            config: RwLock::new(config),
+        }
+    }
+
+    pub fn config(&self) -> ChaosConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: ChaosConfig) -> eyre::Result<()> {
+        config.validate()?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> eyre::Result<ChaosConfig> {
+    let text = std::fs::read_to_string(path)?;
+    let config: ChaosConfig = toml::from_str(&text).wrap_err("Invalid TOML")?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Applies the rule matching the request, if any.
+pub(crate) async fn inject(
+    chaos: Arc<Chaos>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let rule = chaos
+        .config
+        .read()
+        .unwrap()
+        .rule(request.uri().path())
+        .cloned();
+    let Some(rule) = rule else {
+        return next.run(request).await;
+    };
+
+    if let Some(latency) = rule.latency {
+        tokio::time::sleep(latency.get()).await;
+    }
+    if rule.error_rate > 0.0 && rand::thread_rng().gen_bool(rule.error_rate) {
+        let e = eyre!("Injected failure");
+        return match rule.error_status {
+            500 => AppError::InternalServerError(e),
+            _ => AppError::ServiceUnavailable(e),
+        }
+        .into_response();
+    }
+
+    let mut response = next.run(request).await;
+    if rule.etag_churn {
+        churn_etag(response.headers_mut());
+    }
+    match rule.truncate_after {
+        Some(limit) if response.status() == StatusCode::OK => {
+            response.map(|body| body::boxed(truncate(body, limit)))
+        }
+        _ => response,
+    }
+}
+
+fn churn_etag(headers: &mut HeaderMap) {
+    if headers.contains_key(header::ETAG) {
+        let etag = format!("\"chaos-{:016x}\"", rand::thread_rng().gen::<u64>());
+        headers.insert(header::ETAG, etag.parse().unwrap());
+    }
+}
+
+/// Ends `body` after `limit` bytes.
+fn truncate<B>(body: B, limit: u64) -> Body
+where
+    B: HttpBody + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    Body::wrap_stream(stream::unfold(
+        (body, limit),
+        |(mut body, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            match body.data().await? {
+                Ok(mut chunk) => {
+                    let chunk = chunk.copy_to_bytes(chunk.remaining().min(remaining as usize));
+                    let remaining = remaining - chunk.len() as u64;
+                    Some((Ok(chunk), (body, remaining)))
+                }
+                Err(e) => Some((Err(std::io::Error::other(e)), (body, 0))),
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_matching() {
+        assert!(matches("/macs/*/png", "/macs/0011223344556677/png"));
+        assert!(matches("/macs/*/png/", "/macs/0011223344556677/png"));
+        assert!(!matches("/macs/*/png", "/macs/0011223344556677/svg"));
+        assert!(!matches("/macs/*", "/macs/0011223344556677/png"));
+        assert!(matches("/macs", "/macs"));
+    }
+
+    #[test]
+    fn parse_toml() {
+        let config: ChaosConfig = toml::from_str(
+            r#"
+            [[rules]]
+            route = "/macs/*/png"
+            latency = "250ms"
+            truncate_after = 100
+
+            [[rules]]
+            error_rate = 0.5
+            error_status = 500
+            etag_churn = true
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.rules.len(), 2);
+        assert_eq!(config.rules[0].error_status, 503);
+        assert_eq!(
+            config
+                .rule("/macs/0011223344556677/png")
+                .unwrap()
+                .truncate_after,
+            Some(100)
+        );
+        assert!(config.rule("/macs").unwrap().etag_churn);
+
+        let mut invalid = config.clone();
+        invalid.rules[1].error_rate = 1.5;
+        assert!(invalid.validate().is_err());
+        let mut invalid = config;
+        invalid.rules[1].error_status = 404;
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::{io, path::Path, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    idempotency::IDEMPOTENCY_FILE, maintenance::MAINTENANCE_FILE, replication::REPLICATION_FILE,
+    shard,
+};
+
+/// What a cleanup of the image directory found, see
+/// `POST /admin/cleanup`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CleanupReport {
+    /// Temporary files of interrupted writes older than `--temp-file-grace`,
+    /// which are always removed.
+    pub temp_files: Vec<CleanedFile>,
+    /// Files kept alongside an image that no longer exists, like the
+    /// previous PNG of a deleted MAC, and contents of `--dedup` that no
+    /// image refers to.
+    pub orphans: Vec<CleanedFile>,
+    /// Whether the orphans were removed too.
+    pub orphans_removed: bool,
+    pub files_removed: usize,
+    pub bytes_reclaimed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CleanedFile {
+    pub name: String,
+    pub bytes: u64,
+}
+
+impl CleanupReport {
+    pub fn count_removed(&mut self) {
+        let mut removed = self.temp_files.iter().collect::<Vec<_>>();
+        if self.orphans_removed {
+            removed.extend(&self.orphans);
+        }
+        self.files_removed = removed.len();
+        self.bytes_reclaimed = removed.iter().map(|file| file.bytes).sum();
+    }
+
+    /// Logs what was found in the image directory `dir`.
+    pub fn log(&self, dir: &Path) {
+        tracing::info!(
+            "Cleanup of {} removed {} files, reclaiming {} bytes",
+            dir.display(),
+            self.files_removed,
+            self.bytes_reclaimed
+        );
+        if !self.orphans_removed {
+            for orphan in &self.orphans {
+                tracing::warn!(
+                    "Orphaned file {} ({} bytes), remove it with POST /admin/cleanup?delete=true",
+                    orphan.name,
+                    orphan.bytes
+                );
+            }
+        }
+    }
+}
+
+/// Whether `name` is the temporary file of a write of a file for which
+/// `is_known` holds, like `.0011223344556677.png.3.tmp`, or of the state
+/// files of the server.
+pub(crate) fn is_temp_file(name: &str, is_known: impl Fn(&str) -> bool) -> bool {
+    if let Some(state) = name.strip_suffix(".tmp") {
+        if [MAINTENANCE_FILE, IDEMPOTENCY_FILE, REPLICATION_FILE].contains(&state) {
+            return true;
+        }
+    }
+    let Some(inner) = name
+        .strip_prefix('.')
+        .and_then(|name| name.strip_suffix(".tmp"))
+    else {
+        return false;
+    };
+    let Some((target, counter)) = inner.rsplit_once('.') else {
+        return false;
+    };
+    !counter.is_empty() && counter.bytes().all(|b| b.is_ascii_digit()) && is_known(target)
+}
+
+/// Removes the temporary files in `dir` and its shard directories `depth`
+/// levels deep that `is_temp` accepts and that were last modified before
+/// `before`.
+pub(crate) async fn remove_temp_files(
+    dir: &Path,
+    depth: u8,
+    before: SystemTime,
+    is_temp: impl Fn(&str) -> bool,
+) -> io::Result<Vec<CleanedFile>> {
+    let mut removed = vec![];
+    for path in shard::list_paths(dir, depth).await?.into_iter().flatten() {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !is_temp(name) {
+            continue;
+        }
+        let meta = match fs::metadata(&path).await {
+            Ok(meta) if meta.is_file() => meta,
+            _ => continue,
+        };
+        if meta.modified()? >= before {
+            continue;
+        }
+        match fs::remove_file(&path).await {
+            Ok(()) => removed.push(CleanedFile {
+                name: name.to_owned(),
+                bytes: meta.len(),
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("Could not remove {}: {e}", path.display()),
+        }
+    }
+    removed.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_file_names() {
+        let is_known = |name: &str| name == "0011223344556677.png";
+        for name in [
+            ".0011223344556677.png.3.tmp",
+            "maintenance.json.tmp",
+            "idempotency.json.tmp",
+        ] {
+            assert!(is_temp_file(name, is_known), "{name}");
+        }
+        for name in [
+            ".notes.txt.1.tmp",
+            ".0011223344556677.png.tmp",
+            ".0011223344556677.png.x.tmp",
+            "0011223344556677.png.3.tmp",
+            "notes.tmp",
+        ] {
+            assert!(!is_temp_file(name, is_known), "{name}");
+        }
+    }
+}
@@ -0,0 +1,436 @@
+//! Typed client for the HTTP API, built with the `client` feature. Requests
+//! and responses use the same types as the server, so both sides change
+//! together.
+
+use std::fmt::{self, Display};
+
+use chrono_tz::Tz;
+use eyre::eyre;
+use hyper::{header, StatusCode};
+use mime::Mime;
+use reqwest::{RequestBuilder, Response};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    audit::AuditEntry,
+    capabilities::{Compression, UNCOMPRESSED_LENGTH},
+    error::ErrorBody,
+    image_handler::EpdMac,
+    metadata::RenderRecord,
+    raster::{Autofix, Fit},
+    rerender_job::RerenderJob,
+    rle,
+    schedule::Schedule,
+    version::VersionInfo,
+    AuditQuery, ImageQuery, MacListingDetail, PngQuery, RawFormatQuery, RerenderQuery, Stats,
+};
+
+#[derive(Debug)]
+pub(crate) enum ClientError {
+    /// The request could not be sent or the response not be read.
+    Http(reqwest::Error),
+    /// The server answered with an error response.
+    Api { status: StatusCode, body: ErrorBody },
+    /// The response didn't have the expected shape.
+    InvalidResponse(eyre::Error),
+}
+
+// For callers of the client, see [`EpsClient`]
+#[allow(dead_code)]
+impl ClientError {
+    /// The `code` of the server's error response, e.g. `not_found`.
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            Self::Api { body, .. } => Some(&body.code),
+            Self::Http(_) | Self::InvalidResponse(_) => None,
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Api { status, .. } => Some(*status),
+            Self::Http(e) => e.status(),
+            Self::InvalidResponse(_) => None,
+        }
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "Request failed: {e}"),
+            Self::Api { status, body } => write!(f, "{status} ({}): {}", body.code, body.message),
+            Self::InvalidResponse(e) => write!(f, "Invalid response: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(e) => Some(e),
+            Self::Api { .. } | Self::InvalidResponse(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+/// Options of [`EpsClient::render_svg`].
+#[derive(Debug, Default, Clone, Serialize)]
+pub(crate) struct RenderOptions {
+    /// Renders the SVG again on this schedule to update its placeholders.
+    pub rerender: Option<Schedule>,
+    /// Time zone of the placeholders instead of the server's default.
+    pub timezone: Option<Tz>,
+}
+
+// The entry point for programs built on the client, not used by the server
+#[allow(dead_code)]
+pub(crate) struct EpsClient {
+    http: reqwest::Client,
+    base_url: String,
+    admin_key: Option<String>,
+}
+
+#[allow(dead_code)]
+impl EpsClient {
+    /// Creates a client for the server at `base_url`, e.g.
+    /// `http://localhost:3000`.
+    pub fn new(base_url: &str) -> Self {
+        EpsClient {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            admin_key: None,
+        }
+    }
+
+    /// Sends `key` as bearer token, required for admin routes if the server
+    /// has an admin key.
+    pub fn with_admin_key(mut self, key: impl Into<String>) -> Self {
+        self.admin_key = Some(key.into());
+        self
+    }
+
+    pub async fn list_macs(&self) -> Result<Vec<EpdMac>, ClientError> {
+        let macs: Vec<String> = json(self.get("/macs")).await?;
+        macs.iter()
+            .map(|mac| mac.parse().map_err(ClientError::InvalidResponse))
+            .collect()
+    }
+
+    pub async fn list_macs_detail(&self) -> Result<MacListingDetail, ClientError> {
+        json(self.get("/macs").query(&[("detail", true)])).await
+    }
+
+    pub async fn get_svg(&self, mac: EpdMac) -> Result<String, ClientError> {
+        Ok(send(self.get(&format!("/macs/{mac}/svg")))
+            .await?
+            .text()
+            .await?)
+    }
+
+    pub async fn get_png(&self, mac: EpdMac) -> Result<Vec<u8>, ClientError> {
+        bytes(self.get(&format!("/macs/{mac}/png"))).await
+    }
+
+    pub async fn get_bmp(&self, mac: EpdMac) -> Result<Vec<u8>, ClientError> {
+        bytes(self.get(&format!("/macs/{mac}/bmp"))).await
+    }
+
+    pub async fn get_raw(&self, mac: EpdMac) -> Result<Vec<u8>, ClientError> {
+        bytes(self.get(&format!("/macs/{mac}/raw"))).await
+    }
+
+    /// The raw image of `mac`, transferred run-length encoded.
+    pub async fn get_raw_rle(&self, mac: EpdMac) -> Result<Vec<u8>, ClientError> {
+        let request = self
+            .get(&format!("/macs/{mac}/raw"))
+            .query(&RawFormatQuery {
+                compress: Compression::Rle,
+            });
+        let response = send(request).await?;
+        let len = response
+            .headers()
+            .get(UNCOMPRESSED_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse().ok())
+            .ok_or_else(|| {
+                ClientError::InvalidResponse(eyre!("Missing {UNCOMPRESSED_LENGTH} header"))
+            })?;
+        let encoded = response.bytes().await?;
+        rle::decode(&encoded, len).map_err(ClientError::InvalidResponse)
+    }
+
+    /// Renders an SVG fragment or a gzip compressed one for `mac`.
+    pub async fn render_svg(
+        &self,
+        mac: EpdMac,
+        body: impl Into<reqwest::Body>,
+        options: &RenderOptions,
+    ) -> Result<(), ClientError> {
+        let request = self
+            .post(&format!("/macs/{mac}/render_svg"))
+            .query(options)
+            .header(header::CONTENT_TYPE, mime::IMAGE_SVG.as_ref())
+            .body(body);
+        send(request).await?;
+        Ok(())
+    }
+
+    /// Uploads a JPEG, PNG or BMP image that is fitted onto the panel.
+    pub async fn post_image(
+        &self,
+        mac: EpdMac,
+        content_type: &Mime,
+        data: Vec<u8>,
+        fit: Fit,
+    ) -> Result<(), ClientError> {
+        let request = self
+            .post(&format!("/macs/{mac}/image"))
+            .query(&ImageQuery { fit })
+            .header(header::CONTENT_TYPE, content_type.as_ref())
+            .body(data);
+        send(request).await?;
+        Ok(())
+    }
+
+    /// Uploads a PNG made for the panel.
+    pub async fn post_png(
+        &self,
+        mac: EpdMac,
+        data: Vec<u8>,
+        autofix: Option<Autofix>,
+    ) -> Result<(), ClientError> {
+        let request = self
+            .post(&format!("/macs/{mac}/png"))
+            .query(&PngQuery { autofix })
+            .header(header::CONTENT_TYPE, mime::IMAGE_PNG.as_ref())
+            .body(data);
+        send(request).await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, mac: EpdMac) -> Result<(), ClientError> {
+        let url = format!("{}/macs/{mac}", self.base_url);
+        send(self.authorize(self.http.delete(url))).await?;
+        Ok(())
+    }
+
+    pub async fn render_log(&self, mac: EpdMac) -> Result<Vec<RenderRecord>, ClientError> {
+        json(self.get(&format!("/macs/{mac}/render_log"))).await
+    }
+
+    /// Version and capabilities of the server, to check before using
+    /// optional routes.
+    pub async fn version(&self) -> Result<VersionInfo, ClientError> {
+        json(self.get("/version")).await
+    }
+
+    pub async fn stats(&self) -> Result<Stats, ClientError> {
+        json(self.get("/stats")).await
+    }
+
+    /// The newest `limit` audit log entries, optionally only those of `mac`.
+    pub async fn audit(
+        &self,
+        mac: Option<EpdMac>,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, ClientError> {
+        let query = AuditQuery {
+            mac: mac.map(|mac| mac.to_string()),
+            limit,
+        };
+        json(self.get("/audit").query(&query)).await
+    }
+
+    /// Starts re-rendering the stored SVGs of all MACs starting with
+    /// `mac_prefix`, only those whose PNG isn't the size of the panel if
+    /// `stale_only`.
+    pub async fn start_rerender(
+        &self,
+        mac_prefix: Option<&str>,
+        stale_only: bool,
+    ) -> Result<RerenderJob, ClientError> {
+        let query = RerenderQuery {
+            mac_prefix: mac_prefix.map(str::to_owned),
+            stale_only,
+        };
+        json(self.post("/admin/rerender").query(&query)).await
+    }
+
+    pub async fn rerender_job(&self, id: u64) -> Result<RerenderJob, ClientError> {
+        json(self.get(&format!("/admin/rerender/{id}"))).await
+    }
+
+    fn get(&self, path: &str) -> RequestBuilder {
+        self.authorize(self.http.get(format!("{}{path}", self.base_url)))
+    }
+
+    fn post(&self, path: &str) -> RequestBuilder {
+        self.authorize(self.http.post(format!("{}{path}", self.base_url)))
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.admin_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+}
+
+/// Sends `request` and turns error responses into [`ClientError::Api`].
+async fn send(request: RequestBuilder) -> Result<Response, ClientError> {
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let text = response.text().await?;
+    match serde_json::from_str(&text) {
+        Ok(body) => Err(ClientError::Api { status, body }),
+        Err(_) => Err(ClientError::InvalidResponse(eyre!(
+            "{status} without an error body: {text}"
+        ))),
+    }
+}
+
+async fn json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, ClientError> {
+    let text = send(request).await?.text().await?;
+    serde_json::from_str(&text).map_err(|e| ClientError::InvalidResponse(e.into()))
+}
+
+async fn bytes(request: RequestBuilder) -> Result<Vec<u8>, ClientError> {
+    Ok(send(request).await?.bytes().await?.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use crate::{
+        app,
+        tests::{get_test_fixture, Fixture},
+    };
+
+    use super::*;
+
+    const MAC: &str = "0011223344556677";
+    const SVG: &str = r#"<rect width="10" height="10"/>"#;
+
+    /// Serves the app of `fix` on a free local port.
+    fn serve(fix: &Fixture) -> EpsClient {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app(fix.config.clone()).into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(server);
+        EpsClient::new(&format!("http://{addr}/"))
+    }
+
+    #[tokio::test]
+    async fn render_read_and_delete() {
+        let fix = get_test_fixture();
+        let client = serve(&fix);
+        let mac: EpdMac = MAC.parse().unwrap();
+
+        client
+            .render_svg(mac, SVG, &RenderOptions::default())
+            .await
+            .unwrap();
+
+        let macs = client.list_macs().await.unwrap();
+        assert_eq!(macs, [mac, "aabbccddeeffaabb".parse().unwrap()]);
+        let detail = client.list_macs_detail().await.unwrap();
+        assert!(detail.macs[0].has_svg);
+        assert!(client.get_svg(mac).await.unwrap().contains(SVG));
+        assert!(client.get_png(mac).await.unwrap().starts_with(b"\x89PNG"));
+        assert!(client.get_bmp(mac).await.unwrap().starts_with(b"BM"));
+        let raw = client.get_raw(mac).await.unwrap();
+        assert_eq!(raw.len(), 128 * 296 / 8);
+        assert_eq!(client.get_raw_rle(mac).await.unwrap(), raw);
+        assert_eq!(client.render_log(mac).await.unwrap().len(), 1);
+        assert_eq!(client.stats().await.unwrap().render_duration_ms.count, 1);
+        let version = client.version().await.unwrap();
+        assert!(version.features.iter().any(|feature| feature == "client"));
+
+        client.delete(mac).await.unwrap();
+        let e = client.get_png(mac).await.unwrap_err();
+        assert_eq!(e.status(), Some(StatusCode::NOT_FOUND));
+        assert_eq!(e.code(), Some("not_found"));
+    }
+
+    #[tokio::test]
+    async fn render_options() {
+        let fix = get_test_fixture();
+        let client = serve(&fix);
+        let mac: EpdMac = MAC.parse().unwrap();
+
+        let options = RenderOptions {
+            rerender: Some("hourly@05".parse().unwrap()),
+            timezone: Some(Tz::Europe__Berlin),
+        };
+        client.render_svg(mac, SVG, &options).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_errors() {
+        let fix = get_test_fixture();
+        let client = serve(&fix);
+        let mac: EpdMac = MAC.parse().unwrap();
+
+        let mut png = Vec::new();
+        image::GrayImage::new(10, 10)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let e = client.post_png(mac, png.clone(), None).await.unwrap_err();
+        let ClientError::Api { status, body } = e else {
+            panic!("unexpected error {e}");
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.code, "dimension_mismatch");
+        assert!(body.details.is_some());
+
+        client
+            .post_image(mac, &mime::IMAGE_PNG, png, Fit::Contain)
+            .await
+            .unwrap();
+        let e = client
+            .post_image(mac, &mime::TEXT_PLAIN, b"text".to_vec(), Fit::Contain)
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), Some("unsupported_media_type"));
+    }
+
+    #[tokio::test]
+    async fn admin_routes() {
+        let mut fix = get_test_fixture();
+        fix.config.admin_key = Some("secret".to_owned());
+        let client = serve(&fix);
+
+        let e = client.start_rerender(None, false).await.unwrap_err();
+        assert_eq!(e.code(), Some("unauthorized"));
+
+        let client = client.with_admin_key("secret");
+        let job = client.start_rerender(Some("00"), false).await.unwrap();
+        assert_eq!(client.rerender_job(job.id).await.unwrap().id, job.id);
+        let e = client.rerender_job(job.id + 1).await.unwrap_err();
+        assert_eq!(e.code(), Some("not_found"));
+
+        let mac: EpdMac = MAC.parse().unwrap();
+        client
+            .render_svg(mac, SVG, &RenderOptions::default())
+            .await
+            .unwrap();
+        let entries = client.audit(Some(mac), 10).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].principal.as_deref(),
+            Some(crate::auth::ADMIN_PRINCIPAL)
+        );
+    }
+}
@@ -0,0 +1,16 @@
+use chrono::{DateTime, Utc};
+
+/// Source of the current time, injectable so time-dependent behavior can be
+/// tested without waiting.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
@@ -0,0 +1,128 @@
+use std::{collections::HashMap, future::Future, hash::Hash, sync::Mutex};
+
+use tokio::sync::watch;
+
+/// Work that is running, keyed by what makes its result. Callers with the
+/// key of running work wait for its result instead of repeating it.
+pub(crate) struct InFlight<K, T> {
+    running: Mutex<HashMap<K, watch::Receiver<Option<T>>>>,
+}
+
+impl<K, T> Default for InFlight<K, T> {
+    fn default() -> Self {
+        InFlight {
+            running: Mutex::default(),
+        }
+    }
+}
+
+/// Forgets the work of `key` when it finishes or is cancelled.
+struct Running<'a, K: Eq + Hash, T> {
+    in_flight: &'a InFlight<K, T>,
+    key: &'a K,
+}
+
+impl<K: Eq + Hash, T> Drop for Running<'_, K, T> {
+    fn drop(&mut self) {
+        self.in_flight.running.lock().unwrap().remove(self.key);
+    }
+}
+
+impl<K: Clone + Eq + Hash, T: Clone> InFlight<K, T> {
+    /// Runs `work` unless work of `key` is running already, and returns the
+    /// result of either. If the running work is cancelled, as when its
+    /// client disconnects, one of the waiting callers runs `work` instead.
+    pub async fn run<F, Fut>(&self, key: K, work: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let mut work = Some(work);
+        loop {
+            let joined = {
+                let mut running = self.running.lock().unwrap();
+                match running.get(&key) {
+                    Some(receiver) => Err(receiver.clone()),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        running.insert(key.clone(), receiver);
+                        Ok(sender)
+                    }
+                }
+            };
+            let mut receiver = match joined {
+                Ok(sender) => {
+                    let _running = Running {
+                        in_flight: self,
+                        key: &key,
+                    };
+                    let work = work.take().expect("Only run once");
+                    let result = work().await;
+                    sender.send_replace(Some(result.clone()));
+                    return result;
+                }
+                Err(receiver) => receiver,
+            };
+            loop {
+                if let Some(result) = receiver.borrow_and_update().clone() {
+                    return result;
+                }
+                if receiver.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesces_same_key() {
+        let in_flight = InFlight::<&str, usize>::default();
+        let runs = AtomicUsize::new(0);
+        let (release, released) = oneshot::channel::<()>();
+        let work = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            runs.load(Ordering::SeqCst)
+        };
+
+        let leader = in_flight.run("a", || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            released.await.unwrap();
+            7
+        });
+        let followers =
+            futures_util::future::join(in_flight.run("a", work), in_flight.run("a", work));
+        let other = in_flight.run("b", work);
+        let (leader, followers, other) = futures_util::future::join3(leader, followers, async {
+            let other = other.await;
+            release.send(()).unwrap();
+            other
+        })
+        .await;
+        assert_eq!((leader, followers), (7, (7, 7)));
+        assert_eq!(other, 2);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(in_flight.running.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_hands_over() {
+        let in_flight = InFlight::<&str, usize>::default();
+        let mut leader = Box::pin(in_flight.run("a", std::future::pending));
+        let mut follower = Box::pin(in_flight.run("a", || async { 3 }));
+        // Both are waiting, then the leader's client goes away
+        assert!(futures_util::poll!(&mut leader).is_pending());
+        assert!(futures_util::poll!(&mut follower).is_pending());
+        drop(leader);
+        assert_eq!(follower.await, 3);
+    }
+}
@@ -0,0 +1,899 @@
+//! Read-only CoAP (RFC 7252) access to the images for devices on
+//! constrained networks, see `--coap-listen`. Large payloads are sent in
+//! blocks (RFC 7959), and devices may observe (RFC 7641) the packed
+//! framebuffer of their MAC to be told about changes. Requests are subject
+//! to the same networks and maintenance as HTTP reads.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use hyper::{Method, StatusCode};
+use tokio::{net::UdpSocket, sync::broadcast::error::RecvError};
+
+use crate::{error::AppError, events::EventKind, image_handler::EpdMac, AppState};
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xff;
+/// Largest datagram read; requests are small.
+const MAX_DATAGRAM: usize = 1500;
+
+const GET: u8 = 0x01;
+const CONTENT: u8 = 0x45;
+const BAD_REQUEST: u8 = 0x80;
+const BAD_OPTION: u8 = 0x82;
+const FORBIDDEN: u8 = 0x83;
+const NOT_FOUND: u8 = 0x84;
+const METHOD_NOT_ALLOWED: u8 = 0x85;
+const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+const SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+const ETAG: u16 = 4;
+const OBSERVE: u16 = 6;
+const URI_PATH: u16 = 11;
+const CONTENT_FORMAT: u16 = 12;
+const MAX_AGE: u16 = 14;
+const BLOCK2: u16 = 23;
+
+const TEXT_PLAIN: u32 = 0;
+const OCTET_STREAM: u32 = 42;
+/// Block size exponent of 1024-byte blocks, the largest there is.
+const MAX_SZX: u8 = 6;
+
+/// Observers of one MAC; further registrations are answered without being
+/// registered.
+const MAX_OBSERVERS_PER_MAC: usize = 8;
+const MAX_OBSERVERS: usize = 1024;
+/// How long a registration lasts. It is sent as the Max-Age of the
+/// notifications, after which clients register again (RFC 7641 §3.3.1), so
+/// that observers that went away without a reset don't pile up.
+const OBSERVATION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Type {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+/// A CoAP message, with its options sorted by number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Message {
+    pub kind: Type,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let (&first, rest) = data.split_first()?;
+        let token_len = usize::from(first & 0x0f);
+        if first >> 6 != VERSION || token_len > 8 || rest.len() < 3 + token_len {
+            return None;
+        }
+        let kind = match (first >> 4) & 0x03 {
+            0 => Type::Confirmable,
+            1 => Type::NonConfirmable,
+            2 => Type::Acknowledgement,
+            _ => Type::Reset,
+        };
+        let code = rest[0];
+        let message_id = u16::from_be_bytes([rest[1], rest[2]]);
+        let token = rest[3..3 + token_len].to_vec();
+
+        let mut rest = &rest[3 + token_len..];
+        let mut options = vec![];
+        let mut payload = vec![];
+        let mut number = 0u16;
+        while let Some((&byte, after)) = rest.split_first() {
+            if byte == PAYLOAD_MARKER {
+                if after.is_empty() {
+                    return None;
+                }
+                payload = after.to_vec();
+                break;
+            }
+            rest = after;
+            let delta = extended(byte >> 4, &mut rest)?;
+            let len = usize::from(extended(byte & 0x0f, &mut rest)?);
+            number = number.checked_add(delta)?;
+            if rest.len() < len {
+                return None;
+            }
+            options.push((number, rest[..len].to_vec()));
+            rest = &rest[len..];
+        }
+        Some(Message {
+            kind,
+            code,
+            message_id,
+            token,
+            options,
+            payload,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let kind = match self.kind {
+            Type::Confirmable => 0,
+            Type::NonConfirmable => 1,
+            Type::Acknowledgement => 2,
+            Type::Reset => 3,
+        };
+        let mut data = vec![VERSION << 6 | kind << 4 | self.token.len() as u8, self.code];
+        data.extend(self.message_id.to_be_bytes());
+        data.extend(&self.token);
+        let mut number = 0;
+        for (option, value) in &self.options {
+            let (delta, delta_ext) = nibble(option - number);
+            let (len, len_ext) = nibble(value.len() as u16);
+            data.push(delta << 4 | len);
+            data.extend(delta_ext);
+            data.extend(len_ext);
+            data.extend(value);
+            number = *option;
+        }
+        if !self.payload.is_empty() {
+            data.push(PAYLOAD_MARKER);
+            data.extend(&self.payload);
+        }
+        data
+    }
+
+    pub fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == number)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        let value = self.option(number)?;
+        (value.len() <= 4).then(|| value.iter().fold(0, |acc, &b| acc << 8 | u32::from(b)))
+    }
+
+    pub fn path(&self) -> Vec<String> {
+        self.options
+            .iter()
+            .filter(|(option, _)| *option == URI_PATH)
+            .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+            .collect()
+    }
+
+    /// Adds `option`, keeping the options sorted.
+    pub fn set_option(&mut self, number: u16, value: Vec<u8>) {
+        let at = self
+            .options
+            .partition_point(|(option, _)| *option <= number);
+        self.options.insert(at, (number, value));
+    }
+
+    pub fn set_uint_option(&mut self, number: u16, value: u32) {
+        let bytes = value.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        self.set_option(number, bytes[skip..].to_vec());
+    }
+}
+
+/// Reads the extended form of an option delta or length nibble.
+fn extended(nibble: u8, rest: &mut &[u8]) -> Option<u16> {
+    match nibble {
+        0..=12 => Some(u16::from(nibble)),
+        13 => {
+            let (&byte, after) = rest.split_first()?;
+            *rest = after;
+            Some(u16::from(byte) + 13)
+        }
+        14 => {
+            let bytes = rest.get(..2)?;
+            let value = u16::from_be_bytes([bytes[0], bytes[1]]).checked_add(269)?;
+            *rest = &rest[2..];
+            Some(value)
+        }
+        _ => None,
+    }
+}
+
+fn nibble(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, vec![]),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+/// A Block2 option: which block of a payload, and whether more follow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Block {
+    pub num: u32,
+    pub more: bool,
+    /// Size exponent, blocks are `16 << szx` bytes.
+    pub szx: u8,
+}
+
+impl Block {
+    pub fn decode(value: u32) -> Option<Self> {
+        let szx = (value & 0x07) as u8;
+        (szx != 7).then_some(Block {
+            num: value >> 4,
+            more: value & 0x08 != 0,
+            szx,
+        })
+    }
+
+    pub fn encode(self) -> u32 {
+        self.num << 4 | u32::from(self.more) << 3 | u32::from(self.szx)
+    }
+}
+
+/// A device observing the packed framebuffer of a MAC.
+#[derive(Debug, Clone)]
+struct Observer {
+    peer: SocketAddr,
+    token: Vec<u8>,
+    /// Block size of the registration, used for notifications.
+    szx: u8,
+    registered: Instant,
+    /// Of the last notification, which the client resets to cancel the
+    /// observation.
+    message_id: Option<u16>,
+}
+
+impl Observer {
+    /// Whether both are the same registration.
+    fn same(&self, other: &Observer) -> bool {
+        self.peer == other.peer && self.token == other.token
+    }
+
+    /// Seconds until the registration expires, if it hasn't.
+    fn remaining(&self, now: Instant) -> Option<u32> {
+        let left = OBSERVATION_LIFETIME.checked_sub(now.duration_since(self.registered))?;
+        (!left.is_zero()).then(|| left.as_secs().max(1) as u32)
+    }
+}
+
+/// A CoAP endpoint serving the images of one state.
+pub(crate) struct CoapServer {
+    socket: UdpSocket,
+    state: Arc<AppState>,
+    observers: Mutex<HashMap<EpdMac, Vec<Observer>>>,
+    next_message_id: AtomicU16,
+    /// Sequence number of notifications, 24 bits on the wire.
+    observe_seq: AtomicU32,
+}
+
+impl CoapServer {
+    pub fn new(socket: UdpSocket, state: Arc<AppState>) -> Self {
+        CoapServer {
+            socket,
+            state,
+            observers: Mutex::default(),
+            next_message_id: AtomicU16::new(initial_message_id()),
+            observe_seq: AtomicU32::new(2),
+        }
+    }
+
+    /// Answers requests and notifies observers until the socket fails.
+    pub async fn run(self: Arc<Self>) {
+        tokio::spawn(self.clone().notify());
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::error!("CoAP socket failed: {e}");
+                    return;
+                }
+            };
+            let Some(request) = Message::decode(&buf[..len]) else {
+                tracing::debug!("Ignoring malformed CoAP message from {peer}");
+                continue;
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Some(response) = server.handle(request, peer).await {
+                    server.send(&response, peer).await;
+                }
+            });
+        }
+    }
+
+    async fn send(&self, message: &Message, peer: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&message.encode(), peer).await {
+            tracing::warn!("Sending a CoAP message to {peer} failed: {e}");
+        }
+    }
+
+    async fn handle(&self, request: Message, peer: SocketAddr) -> Option<Message> {
+        let kind = match request.kind {
+            Type::Confirmable => Type::Acknowledgement,
+            Type::NonConfirmable => Type::NonConfirmable,
+            // Nothing is sent confirmable, so there is nothing to match
+            Type::Acknowledgement => return None,
+            // The client doesn't want the notification
+            Type::Reset => {
+                self.cancel(peer, request.message_id);
+                return None;
+            }
+        };
+        let message_id = match kind {
+            Type::Acknowledgement => request.message_id,
+            _ => self.message_id(),
+        };
+        let mut response = Message {
+            kind,
+            code: CONTENT,
+            message_id,
+            token: request.token.clone(),
+            options: vec![],
+            payload: vec![],
+        };
+        if let Err(e) = self.admit(peer) {
+            if let AppError::Maintenance(maintenance) = &e {
+                let retry_after = maintenance.retry_after(Utc::now());
+                response.set_uint_option(MAX_AGE, u32::try_from(retry_after).unwrap_or(u32::MAX));
+            }
+            response.code = code(&e);
+            return Some(response);
+        }
+        if request.code != GET {
+            response.code = METHOD_NOT_ALLOWED;
+            return Some(response);
+        }
+        let block = match request.uint_option(BLOCK2) {
+            Some(value) => match Block::decode(value) {
+                Some(block) => block,
+                None => {
+                    response.code = BAD_OPTION;
+                    return Some(response);
+                }
+            },
+            None => Block {
+                num: 0,
+                more: false,
+                szx: MAX_SZX,
+            },
+        };
+
+        let path = request.path();
+        let segments: Vec<_> = path.iter().map(String::as_str).collect();
+        let (mac, resource) = match segments[..] {
+            ["macs", mac, resource @ ("raw" | "hash")] => (mac, resource),
+            _ => {
+                response.code = NOT_FOUND;
+                return Some(response);
+            }
+        };
+        let Ok(mac) = mac.parse::<EpdMac>() else {
+            response.code = BAD_REQUEST;
+            return Some(response);
+        };
+
+        if resource == "hash" {
+            match self.state.image_handler.png_hash(mac).await {
+                Some(hash) => {
+                    response.set_uint_option(CONTENT_FORMAT, TEXT_PLAIN);
+                    response.payload = hash.into_bytes();
+                }
+                None => response.code = NOT_FOUND,
+            }
+            return Some(response);
+        }
+
+        let observe = request.uint_option(OBSERVE);
+        let observer = Observer {
+            peer,
+            token: request.token.clone(),
+            szx: block.szx.min(MAX_SZX),
+            registered: Instant::now(),
+            message_id: None,
+        };
+        let registered = match observe {
+            Some(0) => self.observe(mac, observer.clone()),
+            // Later blocks of an observed payload are fetched without
+            // deregistering
+            Some(_) => {
+                self.forget(mac, &observer);
+                false
+            }
+            None if block.num == 0 => {
+                self.forget(mac, &observer);
+                false
+            }
+            None => false,
+        };
+        if let Err(code) = self.raw_block(mac, block, &mut response).await {
+            response.code = code;
+            self.forget(mac, &observer);
+            return Some(response);
+        }
+        // Without the option the client knows it isn't registered
+        if registered && block.num == 0 {
+            response.set_uint_option(OBSERVE, self.next_observe_seq());
+            response.set_uint_option(MAX_AGE, OBSERVATION_LIFETIME.as_secs() as u32);
+        }
+        Some(response)
+    }
+
+    /// Applies the networks allowed to read and the maintenance to `peer`.
+    fn admit(&self, peer: SocketAddr) -> Result<(), AppError> {
+        let ip = peer.ip().to_canonical();
+        self.state.ip_filter.check(&Method::GET, Some(ip))?;
+        match self.state.maintenance.active(Utc::now()) {
+            Some(maintenance) => Err(AppError::Maintenance(maintenance)),
+            None => Ok(()),
+        }
+    }
+
+    /// Registers `observer`, replacing an earlier registration with its
+    /// token, unless there are too many already.
+    fn observe(&self, mac: EpdMac, observer: Observer) -> bool {
+        let now = Instant::now();
+        let mut observers = self.observers.lock().unwrap();
+        for macs in observers.values_mut() {
+            macs.retain(|other| !other.same(&observer) && other.remaining(now).is_some());
+        }
+        observers.retain(|_, macs| !macs.is_empty());
+        let total: usize = observers.values().map(Vec::len).sum();
+        let of_mac = observers.get(&mac).map_or(0, Vec::len);
+        if total >= MAX_OBSERVERS || of_mac >= MAX_OBSERVERS_PER_MAC {
+            tracing::debug!("Not registering another CoAP observer of {mac}");
+            return false;
+        }
+        observers.entry(mac).or_default().push(observer);
+        true
+    }
+
+    fn forget(&self, mac: EpdMac, observer: &Observer) {
+        let mut observers = self.observers.lock().unwrap();
+        if let Some(macs) = observers.get_mut(&mac) {
+            macs.retain(|other| !other.same(observer));
+            if macs.is_empty() {
+                observers.remove(&mac);
+            }
+        }
+    }
+
+    /// Ends the observation whose notification `message_id` `peer` reset.
+    fn cancel(&self, peer: SocketAddr, message_id: u16) {
+        let mut observers = self.observers.lock().unwrap();
+        for macs in observers.values_mut() {
+            macs.retain(|other| other.peer != peer || other.message_id != Some(message_id));
+        }
+        observers.retain(|_, macs| !macs.is_empty());
+    }
+
+    /// The unexpired observers of `mac`, dropping the others.
+    fn observers_of(&self, mac: EpdMac) -> Vec<Observer> {
+        let now = Instant::now();
+        let mut observers = self.observers.lock().unwrap();
+        let Some(macs) = observers.get_mut(&mac) else {
+            return vec![];
+        };
+        macs.retain(|observer| observer.remaining(now).is_some());
+        let current = macs.clone();
+        if macs.is_empty() {
+            observers.remove(&mac);
+        }
+        current
+    }
+
+    /// Remembers the `message_id` of the last notification to `observer`.
+    fn notified(&self, mac: EpdMac, observer: &Observer, message_id: u16) {
+        let mut observers = self.observers.lock().unwrap();
+        let registration = observers
+            .get_mut(&mac)
+            .and_then(|macs| macs.iter_mut().find(|other| other.same(observer)));
+        if let Some(registration) = registration {
+            registration.message_id = Some(message_id);
+        }
+    }
+
+    /// Puts `block` of the packed framebuffer of `mac` into `response`.
+    async fn raw_block(&self, mac: EpdMac, block: Block, response: &mut Message) -> Result<(), u8> {
+        let raw = self
+            .state
+            .image_handler
+            .get_raw(mac)
+            .await
+            .map_err(|e| code(&e))?;
+        let hash = self
+            .state
+            .image_handler
+            .png_hash(mac)
+            .await
+            .unwrap_or_default();
+        let szx = block.szx.min(MAX_SZX);
+        let size = 16usize << szx;
+        let start = block.num as usize * size;
+        if start > 0 && start >= raw.len() {
+            return Err(BAD_OPTION);
+        }
+        let end = raw.len().min(start + size);
+        let more = end < raw.len();
+        // The first bytes of the PNG hash, to tell blocks of different images
+        // apart
+        if let Ok(etag) = hex::decode(&hash[..hash.len().min(16)]) {
+            response.set_option(ETAG, etag);
+        }
+        response.set_uint_option(CONTENT_FORMAT, OCTET_STREAM);
+        if more || block.num > 0 {
+            let block = Block {
+                num: block.num,
+                more,
+                szx,
+            };
+            response.set_uint_option(BLOCK2, block.encode());
+        }
+        response.payload = raw[start..end].to_vec();
+        Ok(())
+    }
+
+    /// Sends the first block of the changed framebuffer to the observers of
+    /// its MAC, or ends their observation if it was deleted.
+    async fn notify(self: Arc<Self>) {
+        let (_, mut events) = self.state.image_handler.events().subscribe(None, None);
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("CoAP notifications skipped {skipped} events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let Ok(mac) = event.mac.parse::<EpdMac>() else {
+                continue;
+            };
+            for observer in self.observers_of(mac) {
+                let mut notification = Message {
+                    kind: Type::NonConfirmable,
+                    code: CONTENT,
+                    message_id: self.message_id(),
+                    token: observer.token.clone(),
+                    options: vec![],
+                    payload: vec![],
+                };
+                let block = Block {
+                    num: 0,
+                    more: false,
+                    szx: observer.szx,
+                };
+                let result = match event.kind {
+                    EventKind::Delete => Err(NOT_FOUND),
+                    EventKind::Render | EventKind::Upload => {
+                        self.raw_block(mac, block, &mut notification).await
+                    }
+                };
+                match result {
+                    Ok(()) => {
+                        notification.set_uint_option(OBSERVE, self.next_observe_seq());
+                        let remaining = observer.remaining(Instant::now()).unwrap_or(1);
+                        notification.set_uint_option(MAX_AGE, remaining);
+                        self.notified(mac, &observer, notification.message_id);
+                    }
+                    // An error response ends the observation
+                    Err(code) => {
+                        notification.code = code;
+                        self.forget(mac, &observer);
+                    }
+                }
+                self.send(&notification, observer.peer).await;
+            }
+        }
+    }
+
+    fn message_id(&self) -> u16 {
+        self.next_message_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn next_observe_seq(&self) -> u32 {
+        self.observe_seq.fetch_add(1, Ordering::Relaxed) & 0xff_ffff
+    }
+}
+
+fn code(error: &AppError) -> u8 {
+    match error.status() {
+        StatusCode::NOT_FOUND => NOT_FOUND,
+        StatusCode::FORBIDDEN => FORBIDDEN,
+        StatusCode::SERVICE_UNAVAILABLE => SERVICE_UNAVAILABLE,
+        status if status.is_client_error() => BAD_REQUEST,
+        _ => INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Varies with the start time, so that a restarted server doesn't repeat the
+/// IDs that clients may still deduplicate.
+fn initial_message_id() -> u16 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.subsec_nanos());
+    nanos as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        image_handler::{ImageHandler, RerenderOptions},
+        maintenance::Maintenance,
+        render_queue::Priority,
+        tests::get_test_fixture,
+    };
+
+    const MAC: &str = "123456789abcdef1";
+
+    fn get(message_id: u16, path: &[&str], options: &[(u16, u32)]) -> Message {
+        let mut request = Message {
+            kind: Type::Confirmable,
+            code: GET,
+            message_id,
+            token: vec![0xca, 0xfe],
+            options: path
+                .iter()
+                .map(|segment| (URI_PATH, segment.as_bytes().to_vec()))
+                .collect(),
+            payload: vec![],
+        };
+        for &(number, value) in options {
+            request.set_uint_option(number, value);
+        }
+        request
+    }
+
+    async fn exchange(client: &UdpSocket, request: &Message) -> Message {
+        client.send(&request.encode()).await.unwrap();
+        receive(client).await
+    }
+
+    async fn receive(client: &UdpSocket) -> Message {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let len = timeout(Duration::from_secs(10), client.recv(&mut buf))
+            .await
+            .expect("No CoAP response")
+            .unwrap();
+        Message::decode(&buf[..len]).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut message = get(7, &["macs", MAC, "raw"], &[(BLOCK2, 0x26)]);
+        message.set_option(300, vec![1; 20]);
+        message.payload = b"payload".to_vec();
+        assert_eq!(Message::decode(&message.encode()), Some(message));
+        assert_eq!(Message::decode(&[0x40, GET, 0, 1, PAYLOAD_MARKER]), None);
+    }
+
+    #[tokio::test]
+    async fn blockwise_and_observe() {
+        let fix = get_test_fixture();
+        let handler = Arc::new(ImageHandler::new(fix.config.clone()));
+        let render = |svg: &'static str| {
+            let handler = handler.clone();
+            async move {
+                let mac = MAC.parse().unwrap();
+                let options = RerenderOptions::default();
+                let priority = Priority::Interactive;
+                handler
+                    .post_svg_body_to(&[mac], svg, options, priority)
+                    .await
+                    .unwrap();
+            }
+        };
+        render(r#"<rect width="64" height="296"/>"#).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let state = crate::app_state(handler.clone(), None);
+        tokio::spawn(Arc::new(CoapServer::new(socket, state)).run());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+
+        // 128x296 pixels in 1024 byte blocks
+        let expected = handler.get_raw(MAC.parse().unwrap()).await.unwrap();
+        let mut raw = vec![];
+        for num in 0.. {
+            let block = Block {
+                num,
+                more: false,
+                szx: MAX_SZX,
+            }
+            .encode();
+            let request = get(num as u16 + 1, &["macs", MAC, "raw"], &[(BLOCK2, block)]);
+            let response = exchange(&client, &request).await;
+            assert_eq!(response.kind, Type::Acknowledgement);
+            assert_eq!(response.message_id, request.message_id);
+            assert_eq!(response.code, CONTENT);
+            raw.extend(&response.payload);
+            let block = Block::decode(response.uint_option(BLOCK2).unwrap()).unwrap();
+            if !block.more {
+                break;
+            }
+        }
+        assert!(expected.len() > 1024);
+        assert_eq!(raw, expected);
+
+        let response = exchange(&client, &get(20, &["macs", MAC, "hash"], &[])).await;
+        let hash = handler.png_hash(MAC.parse().unwrap()).await.unwrap();
+        assert_eq!(response.payload, hash.as_bytes());
+        let response = exchange(&client, &get(21, &["macs", "0011223344556688", "raw"], &[])).await;
+        assert_eq!(response.code, NOT_FOUND);
+
+        let response = exchange(&client, &get(22, &["macs", MAC, "raw"], &[(OBSERVE, 0)])).await;
+        assert_eq!(response.code, CONTENT);
+        let registered = response.uint_option(OBSERVE).unwrap();
+        let etag = response.option(ETAG).unwrap().to_vec();
+
+        render(r#"<rect width="128" height="296"/>"#).await;
+        let notification = receive(&client).await;
+        assert_eq!(notification.kind, Type::NonConfirmable);
+        assert_eq!(notification.token, vec![0xca, 0xfe]);
+        assert!(notification.uint_option(OBSERVE).unwrap() > registered);
+        assert_ne!(notification.option(ETAG).unwrap(), etag);
+        let block = Block::decode(notification.uint_option(BLOCK2).unwrap()).unwrap();
+        assert_eq!((block.num, block.more), (0, true));
+        let expected = handler.get_raw(MAC.parse().unwrap()).await.unwrap();
+        assert_eq!(notification.payload, &expected[..1024]);
+    }
+
+    #[tokio::test]
+    async fn access() {
+        let mut fix = get_test_fixture();
+        fix.config.allow_read_from = vec!["10.0.0.0/8".parse().unwrap()];
+        let denied = crate::app_state(Arc::new(ImageHandler::new(fix.config.clone())), None);
+        fix.config.allow_read_from = vec![];
+        let allowed = crate::app_state(Arc::new(ImageHandler::new(fix.config)), None);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 5683));
+        let request = get(1, &["macs", "0011223344556677", "raw"], &[(OBSERVE, 0)]);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = CoapServer::new(socket, denied);
+        let response = server.handle(request.clone(), peer).await.unwrap();
+        assert_eq!(response.code, FORBIDDEN);
+        assert!(server.observers.lock().unwrap().is_empty());
+
+        let maintenance = Maintenance {
+            until: Utc::now() + chrono::Duration::minutes(10),
+            message: String::new(),
+        };
+        allowed.maintenance.start(maintenance).await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = CoapServer::new(socket, allowed.clone());
+        let response = server.handle(request, peer).await.unwrap();
+        assert_eq!(response.code, SERVICE_UNAVAILABLE);
+        assert!(response.uint_option(MAX_AGE).unwrap() > 500);
+        assert!(server.observers.lock().unwrap().is_empty());
+
+        allowed.maintenance.end().await.unwrap();
+        let request = get(2, &["macs", "0011223344556677", "hash"], &[]);
+        let response = server.handle(request, peer).await.unwrap();
+        assert_eq!(response.code, CONTENT);
+    }
+
+    #[tokio::test]
+    async fn observer_limits() {
+        let fix = get_test_fixture();
+        let state = crate::app_state(Arc::new(ImageHandler::new(fix.config)), None);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = CoapServer::new(socket, state);
+        let count = |server: &CoapServer| -> usize {
+            server
+                .observers
+                .lock()
+                .unwrap()
+                .values()
+                .map(Vec::len)
+                .sum()
+        };
+        let observer = |port: u16, token: u8, registered: Instant| Observer {
+            peer: SocketAddr::from(([127, 0, 0, 1], port)),
+            token: vec![token],
+            szx: MAX_SZX,
+            registered,
+            message_id: None,
+        };
+        let (mac, other) = (MAC.parse().unwrap(), "0011223344556677".parse().unwrap());
+        let now = Instant::now();
+
+        // A token is registered once, for the MAC it was last used with
+        assert!(server.observe(mac, observer(1, 1, now)));
+        assert!(server.observe(mac, observer(1, 1, now)));
+        assert!(server.observe(other, observer(1, 1, now)));
+        assert_eq!(count(&server), 1);
+        assert!(server.observers_of(mac).is_empty());
+
+        for port in 2..=MAX_OBSERVERS_PER_MAC as u16 {
+            assert!(server.observe(other, observer(port, 1, now)));
+        }
+        assert!(!server.observe(other, observer(100, 1, now)));
+        assert!(server.observe(mac, observer(100, 1, now)));
+
+        // Expired registrations make room
+        server.observers.lock().unwrap().clear();
+        let expired = now - OBSERVATION_LIFETIME - Duration::from_secs(1);
+        let stale = (1..=MAX_OBSERVERS_PER_MAC as u16)
+            .map(|port| observer(port, 1, expired))
+            .collect();
+        server.observers.lock().unwrap().insert(mac, stale);
+        assert!(server.observe(mac, observer(100, 1, now)));
+        assert_eq!(server.observers_of(mac).len(), 1);
+
+        server.observers.lock().unwrap().clear();
+        for port in 0..MAX_OBSERVERS as u16 {
+            let mac = EpdMac([0, 0, 0, 0, 0, 0, (port >> 8) as u8, port as u8]);
+            assert!(server.observe(mac, observer(port, 1, now)));
+        }
+        assert!(!server.observe(mac, observer(u16::MAX, 1, now)));
+    }
+
+    #[tokio::test]
+    async fn reset_cancels_observation() {
+        let fix = get_test_fixture();
+        let handler = Arc::new(ImageHandler::new(fix.config.clone()));
+        let render = |svg: &'static str| {
+            let handler = handler.clone();
+            async move {
+                let mac = MAC.parse().unwrap();
+                let options = RerenderOptions::default();
+                let priority = Priority::Interactive;
+                handler
+                    .post_svg_body_to(&[mac], svg, options, priority)
+                    .await
+                    .unwrap();
+            }
+        };
+        render(r#"<rect width="64" height="296"/>"#).await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = Arc::new(CoapServer::new(
+            socket,
+            crate::app_state(handler.clone(), None),
+        ));
+        tokio::spawn(server.clone().run());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+
+        let response = exchange(&client, &get(1, &["macs", MAC, "raw"], &[(OBSERVE, 0)])).await;
+        assert!(response.uint_option(OBSERVE).is_some());
+        assert_eq!(
+            response.uint_option(MAX_AGE),
+            Some(OBSERVATION_LIFETIME.as_secs() as u32)
+        );
+        render(r#"<rect width="128" height="296"/>"#).await;
+        let notification = receive(&client).await;
+        assert_eq!(notification.token, vec![0xca, 0xfe]);
+
+        let reset = Message {
+            kind: Type::Reset,
+            code: 0,
+            message_id: notification.message_id,
+            token: vec![],
+            options: vec![],
+            payload: vec![],
+        };
+        client.send(&reset.encode()).await.unwrap();
+        timeout(Duration::from_secs(10), async {
+            while !server.observers.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The observation wasn't cancelled");
+    }
+}
@@ -0,0 +1,400 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use eyre::{bail, eyre};
+use serde::{Deserialize, Serialize};
+use usvg::{NodeKind, Opacity, Paint, ShapeRendering};
+
+/// What a paint is replaced with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MappedPaint {
+    Black,
+    White,
+    /// Removes the fill or stroke.
+    None,
+}
+
+impl FromStr for MappedPaint {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "black" => Ok(MappedPaint::Black),
+            "white" => Ok(MappedPaint::White),
+            "none" => Ok(MappedPaint::None),
+            _ => bail!("Invalid paint '{s}', expected black, white or none"),
+        }
+    }
+}
+
+impl Display for MappedPaint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MappedPaint::Black => "black",
+            MappedPaint::White => "white",
+            MappedPaint::None => "none",
+        })
+    }
+}
+
+/// An explicit mapping like `#e30613=white`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ColorMapping {
+    pub from: [u8; 3],
+    pub to: MappedPaint,
+}
+
+impl FromStr for ColorMapping {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (color, paint) = s
+            .split_once('=')
+            .ok_or_else(|| eyre!("Invalid color mapping '{s}', expected #rrggbb=black"))?;
+        let hex = color.trim().trim_start_matches('#');
+        let from = <[u8; 3]>::try_from(hex::decode(hex).unwrap_or_default())
+            .map_err(|_| eyre!("Invalid color '{color}', expected #rrggbb"))?;
+        Ok(ColorMapping {
+            from,
+            to: paint.trim().parse()?,
+        })
+    }
+}
+
+impl Display for ColorMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}={}", hex::encode(self.from), self.to)
+    }
+}
+
+/// The `color_map` query parameter of a render.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum ColorMapRequest {
+    /// Renders the colors as posted, even with `--map-colors`.
+    Preserve,
+    /// The configured mapping, even without `--map-colors`.
+    Auto,
+    /// The configured mapping with these mappings taking precedence.
+    Custom(Vec<ColorMapping>),
+}
+
+impl FromStr for ColorMapRequest {
+    type Err = eyre::Error;
+
+    /// Parses `preserve`, `auto` or comma separated mappings.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(ColorMapRequest::Preserve),
+            "auto" => Ok(ColorMapRequest::Auto),
+            _ => Ok(ColorMapRequest::Custom(
+                s.split(',').map(str::parse).collect::<eyre::Result<_>>()?,
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for ColorMapRequest {
+    type Error = eyre::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ColorMapRequest> for String {
+    fn from(request: ColorMapRequest) -> Self {
+        match request {
+            ColorMapRequest::Preserve => "preserve".to_owned(),
+            ColorMapRequest::Auto => "auto".to_owned(),
+            ColorMapRequest::Custom(mappings) => mappings
+                .iter()
+                .map(ColorMapping::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+}
+
+/// Maps the paints of an SVG to black, white or none before it is rendered,
+/// so that a two-color panel shows crisp shapes instead of dithered colors.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ColorMap {
+    /// Colors mapped regardless of their luminance.
+    pub colors: HashMap<[u8; 3], MappedPaint>,
+    /// Relative luminance from 0 to 1 below which other colors become black
+    /// and above which they become white.
+    pub threshold: f64,
+}
+
+/// The mapping applied to a render, as reported in its render log entry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ColorMapReport {
+    pub threshold: f64,
+    /// Every distinct paint of the SVG, ordered by its first use.
+    pub mapped: Vec<MappedColor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MappedColor {
+    /// A color like `#e30613`, or a gradient like `url(#fade)`.
+    pub from: String,
+    pub to: MappedPaint,
+    /// Whether an explicit mapping applied instead of the luminance.
+    pub explicit: bool,
+}
+
+impl ColorMap {
+    pub fn new(mappings: &[ColorMapping], threshold: f64) -> Self {
+        ColorMap {
+            colors: mappings
+                .iter()
+                .map(|mapping| (mapping.from, mapping.to))
+                .collect(),
+            threshold,
+        }
+    }
+
+    /// Adds `mappings`, replacing those of the same colors.
+    pub fn extend(&mut self, mappings: &[ColorMapping]) {
+        self.colors
+            .extend(mappings.iter().map(|mapping| (mapping.from, mapping.to)));
+    }
+
+    /// Rewrites the fills and strokes of `tree`. Semi-transparent paints are
+    /// judged by their luminance over white and become opaque; gradients by
+    /// the mean luminance of their stops. Embedded raster images are left
+    /// alone.
+    pub fn apply(&self, tree: &usvg::Tree) -> ColorMapReport {
+        let gradients: HashMap<String, f64> = tree
+            .root()
+            .descendants()
+            .filter_map(|node| match &*node.borrow() {
+                NodeKind::LinearGradient(gradient) => {
+                    Some((gradient.id.clone(), mean_luminance(&gradient.base.stops)))
+                }
+                NodeKind::RadialGradient(gradient) => {
+                    Some((gradient.id.clone(), mean_luminance(&gradient.base.stops)))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut report = ColorMapReport {
+            threshold: self.threshold,
+            mapped: Vec::new(),
+        };
+        for mut node in tree.root().descendants() {
+            let mut node = node.borrow_mut();
+            let NodeKind::Path(path) = &mut *node else {
+                continue;
+            };
+            let mut mapped = false;
+            if let Some(fill) = path.fill.take() {
+                if let Some((paint, explicit)) = self.map(&fill.paint, fill.opacity, &gradients) {
+                    report.record(&fill.paint, paint, explicit);
+                    path.fill = replace(paint).map(|paint| usvg::Fill {
+                        paint,
+                        opacity: Opacity::default(),
+                        ..fill
+                    });
+                    mapped = true;
+                } else {
+                    path.fill = Some(fill);
+                }
+            }
+            if let Some(stroke) = path.stroke.take() {
+                if let Some((paint, explicit)) = self.map(&stroke.paint, stroke.opacity, &gradients)
+                {
+                    report.record(&stroke.paint, paint, explicit);
+                    path.stroke = replace(paint).map(|paint| usvg::Stroke {
+                        paint,
+                        opacity: Opacity::default(),
+                        ..stroke
+                    });
+                    mapped = true;
+                } else {
+                    path.stroke = Some(stroke);
+                }
+            }
+            if mapped {
+                // Anti-aliased edges would be dithered into speckles
+                path.rendering_mode = ShapeRendering::CrispEdges;
+            }
+        }
+        report
+    }
+
+    /// The replacement of `paint`, and whether it was mapped explicitly.
+    /// Patterns are kept, as their content is mapped by itself.
+    fn map(
+        &self,
+        paint: &Paint,
+        opacity: Opacity,
+        gradients: &HashMap<String, f64>,
+    ) -> Option<(MappedPaint, bool)> {
+        let luminance = match paint {
+            Paint::Color(color) => {
+                if let Some(&mapped) = self.colors.get(&[color.red, color.green, color.blue]) {
+                    return Some((mapped, true));
+                }
+                luminance(color)
+            }
+            Paint::Link(id) => *gradients.get(id)?,
+        };
+        let over_white = 1.0 - opacity.value() * (1.0 - luminance);
+        let paint = if over_white < self.threshold {
+            MappedPaint::Black
+        } else {
+            MappedPaint::White
+        };
+        Some((paint, false))
+    }
+}
+
+impl ColorMapReport {
+    fn record(&mut self, paint: &Paint, to: MappedPaint, explicit: bool) {
+        let from = match paint {
+            Paint::Color(color) => {
+                format!("#{}", hex::encode([color.red, color.green, color.blue]))
+            }
+            Paint::Link(id) => format!("url(#{id})"),
+        };
+        if !self.mapped.iter().any(|mapped| mapped.from == from) {
+            self.mapped.push(MappedColor { from, to, explicit });
+        }
+    }
+}
+
+fn replace(paint: MappedPaint) -> Option<Paint> {
+    match paint {
+        MappedPaint::Black => Some(Paint::Color(usvg::Color::black())),
+        MappedPaint::White => Some(Paint::Color(usvg::Color::white())),
+        MappedPaint::None => None,
+    }
+}
+
+/// Relative luminance as defined by WCAG, from 0 for black to 1 for white.
+pub(crate) fn luminance(color: &usvg::Color) -> f64 {
+    let linear = |channel: u8| {
+        let c = f64::from(channel) / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(color.red) + 0.7152 * linear(color.green) + 0.0722 * linear(color.blue)
+}
+
+fn mean_luminance(stops: &[usvg::Stop]) -> f64 {
+    if stops.is_empty() {
+        return 1.0;
+    }
+    let sum: f64 = stops
+        .iter()
+        .map(|stop| 1.0 - stop.opacity.value() * (1.0 - luminance(&stop.color)))
+        .sum();
+    sum / stops.len() as f64
+}
+
+/// Parses a luminance threshold from 0 to 1.
+pub(crate) fn threshold(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(threshold) if (0.0..=1.0).contains(&threshold) => Ok(threshold),
+        _ => Err(format!("'{s}' is not a number from 0 to 1")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(svg: &str) -> usvg::Tree {
+        usvg::Tree::from_str(svg, &usvg::Options::default().to_ref()).unwrap()
+    }
+
+    fn paints(tree: &usvg::Tree) -> Vec<(Option<String>, Option<String>)> {
+        let name = |paint: &Paint| match paint {
+            Paint::Color(color) => hex::encode([color.red, color.green, color.blue]),
+            Paint::Link(id) => id.clone(),
+        };
+        tree.root()
+            .descendants()
+            .filter_map(|node| match &*node.borrow() {
+                NodeKind::Path(path) => Some((
+                    path.fill.as_ref().map(|fill| name(&fill.paint)),
+                    path.stroke.as_ref().map(|stroke| name(&stroke.paint)),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            "preserve".parse::<ColorMapRequest>().unwrap(),
+            ColorMapRequest::Preserve
+        );
+        let request: ColorMapRequest = "#FF0000=white,000080=none".parse().unwrap();
+        assert_eq!(
+            request,
+            ColorMapRequest::Custom(vec![
+                ColorMapping {
+                    from: [255, 0, 0],
+                    to: MappedPaint::White
+                },
+                ColorMapping {
+                    from: [0, 0, 128],
+                    to: MappedPaint::None
+                },
+            ])
+        );
+        assert_eq!(String::from(request), "#ff0000=white,#000080=none");
+        assert!("#ff0000=red".parse::<ColorMapRequest>().is_err());
+        assert!("#ff00=black".parse::<ColorMapRequest>().is_err());
+    }
+
+    #[test]
+    fn luminance_and_explicit_mappings() {
+        let tree = tree(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10">
+                <defs><linearGradient id="fade">
+                    <stop offset="0" stop-color="#ffffff"/><stop offset="1" stop-color="#eeeeee"/>
+                </linearGradient></defs>
+                <rect width="10" height="10" fill="#000080" stroke="#e30613"/>
+                <rect width="5" height="5" fill="#ffd700" fill-opacity="0.1"/>
+                <rect width="5" height="5" fill="#c0c0c0"/>
+                <rect width="5" height="5" fill="url(#fade)"/>
+            </svg>"##,
+        );
+        let mut map = ColorMap::new(&["#c0c0c0=none".parse().unwrap()], 0.18);
+        map.extend(&["#e30613=white".parse().unwrap()]);
+        let report = map.apply(&tree);
+        assert_eq!(
+            paints(&tree),
+            [
+                (Some("000000".to_owned()), Some("ffffff".to_owned())),
+                (Some("ffffff".to_owned()), None),
+                (None, None),
+                (Some("ffffff".to_owned()), None),
+            ]
+        );
+        let mapped: Vec<_> = report
+            .mapped
+            .iter()
+            .map(|mapped| (mapped.from.as_str(), mapped.to, mapped.explicit))
+            .collect();
+        assert_eq!(
+            mapped,
+            [
+                ("#000080", MappedPaint::Black, false),
+                ("#e30613", MappedPaint::White, true),
+                ("#ffd700", MappedPaint::White, false),
+                ("#c0c0c0", MappedPaint::None, true),
+                ("url(#fade)", MappedPaint::White, false),
+            ]
+        );
+    }
+}
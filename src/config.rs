@@ -1,14 +1,103 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use chrono_tz::Tz;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    capabilities::PayloadFormat,
+    color_map::{self, ColorMap, ColorMapRequest, ColorMapping},
+    dependency::Dependency,
+    derived::DerivedFormat,
+    ip_filter::Cidr,
+    raster::{Palette, ACEP_PALETTE},
+    state::StateBackend,
+    substitute,
+    tenant::{TenantKey, TENANTS_DIR},
+    units::{self, ByteSize, HumanDuration},
+    validated_config::ValidatedConfig,
+};
+
+const UNITS_HELP: &str = "Durations accept values like 90s, 12h or 1h 30m, sizes values like \
+    512KiB or 2MB. Plain numbers are seconds and bytes, unless documented otherwise.";
 
 #[derive(Debug, Clone, Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = UNITS_HELP)]
 pub(crate) struct Config {
     /// Image directory
     #[arg(short, long, value_name = "IMAGE_DIR")]
     pub image_dir: PathBuf,
 
+    /// Object store to keep the images in instead of the image directory,
+    /// like `s3://bucket/prefix`. Credentials are read from the usual
+    /// `AWS_*` environment variables
+    #[arg(long, value_name = "URL")]
+    pub storage: Option<Url>,
+
+    /// Proxy for the outbound connections of the server, like those to
+    /// `--storage`, e.g. `http://proxy.internal:3128`
+    #[arg(long, value_name = "URL")]
+    pub https_proxy: Option<Url>,
+
+    /// Host or domain connected to without `--https-proxy`, like
+    /// `.internal`; comma separated or repeated
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',')]
+    pub no_proxy: Vec<String>,
+
+    /// PEM file of CA certificates trusted by outbound TLS connections in
+    /// addition to the default ones
+    #[arg(long, value_name = "PEM")]
+    pub extra_ca_cert: Option<PathBuf>,
+
+    /// URL clients reach the server at, like `https://eps.example.com`, for
+    /// the links of provisioning QR codes. Defaults to `http://` and the
+    /// `Host` of the request
+    #[arg(long, value_name = "URL")]
+    pub public_url: Option<Url>,
+
+    /// Serve the stored images, but don't register the routes that change
+    /// them
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Check the configuration and what the server needs at startup, like
+    /// `--storage` being reachable, then exit with 0 if all is well or 1
+    /// without serving
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Levels of subdirectories of the image directory, named after the
+    /// first hex digits of the MAC, that the files of a MAC are kept in.
+    /// Speeds up listing large fleets; files still in the top directory are
+    /// found as well
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub shard_depth: u8,
+
+    /// Move the files in the top directory into the `--shard-depth`
+    /// subdirectories at startup. Interrupted migrations are resumed by
+    /// starting again
+    #[arg(long)]
+    pub migrate_shards: bool,
+
+    /// Keep each distinct image once in `objects/` of the image directory,
+    /// with the files of MACs as hard links to it. Unreferenced contents are
+    /// removed by `POST /admin/cleanup?delete=true`, and existing files are
+    /// linked by `POST /admin/dedup`
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Where the per-MAC metadata like render logs, groups and check-ins is
+    /// kept. Images are files either way
+    #[arg(long, value_enum, default_value_t = StateBackend::Files)]
+    pub state_backend: StateBackend,
+
+    /// Import the metadata of the state backend not selected by
+    /// `--state-backend` at startup, e.g. the sidecar files into the SQLite
+    /// database or back. The source is left as it is
+    #[arg(long)]
+    pub migrate_state: bool,
+
     /// EPD height
     #[arg(short = 'H', long)]
     pub epd_height: u32,
@@ -16,4 +105,511 @@ pub(crate) struct Config {
     /// EPD width
     #[arg(short = 'W', long)]
     pub epd_width: u32,
+
+    /// What serving the PNG of a MAC does if it isn't the size of the panel,
+    /// as after changing `--epd-width` or `--epd-height`
+    #[arg(long, value_enum, default_value_t = StaleDimensions::Rerender)]
+    pub stale_dimensions: StaleDimensions,
+
+    /// Dithering applied when converting uploaded raster images
+    #[arg(long, value_enum, default_value_t = Dither::FloydSteinberg)]
+    pub dither: Dither,
+
+    /// Colors of the panel. `palette` maps rendered and uploaded images to
+    /// the `--palette` colors, and serves their indices as the raw format
+    #[arg(long, value_enum, default_value_t = ColorMode::Mono)]
+    pub color_mode: ColorMode,
+
+    /// Comma separated colors of a palette panel, in the order of their
+    /// indices. Adjust them to calibrate for the inks of a panel
+    #[arg(long, default_value = ACEP_PALETTE)]
+    pub palette: Palette,
+
+    /// Time zone for time placeholders and re-render schedules
+    #[arg(long, default_value_t = Tz::UTC)]
+    pub timezone: Tz,
+
+    /// strftime format of `{{RENDERED_AT}}` in SVGs posted with
+    /// `?substitute=true`, in the time zone of the render
+    #[arg(long, default_value = "%Y-%m-%d %H:%M", value_parser = substitute::time_format)]
+    pub rendered_at_format: String,
+
+    /// Strip editor metadata, comments and whitespace from posted SVGs and
+    /// round their coordinates before storing and rendering them
+    #[arg(long)]
+    pub optimize_svg: bool,
+
+    /// Store posted SVGs with their text converted to outlines, so that
+    /// re-rendering them doesn't depend on the installed fonts. The posted
+    /// document stays available at `GET /macs/:mac/svg/original`. Renders
+    /// override this with `?text=paths` or `?text=live`
+    #[arg(long)]
+    pub convert_text_to_paths: bool,
+
+    /// Font families tried first, in order, for characters the font of a
+    /// text lacks, like `Noto Sans Arabic, Noto Sans`; comma separated or
+    /// repeated. The first also renders text whose families aren't installed
+    #[arg(long, value_name = "FAMILIES", value_delimiter = ',')]
+    pub font_fallbacks: Vec<String>,
+
+    /// Render text with only an embedded reference font, ignoring the
+    /// installed fonts and `--font-fallbacks`, and with the layout settings
+    /// pinned, so that golden tests of renders pass on any host. Renders
+    /// record the fingerprint of the pipeline
+    #[arg(long)]
+    pub deterministic_render: bool,
+
+    /// Map the fill and stroke colors of posted SVGs to black, white or none
+    /// before rendering them: those of `--color-map` as given, others by
+    /// their luminance. Mapped SVGs are stored with their text converted to
+    /// paths. Renders override this with `?color_map=preserve`,
+    /// `?color_map=auto` or mappings like `?color_map=%23e30613=white`
+    #[arg(long)]
+    pub map_colors: bool,
+
+    /// Color mapped regardless of its luminance, like `#e30613=white` with
+    /// `black`, `white` or `none`; may be repeated
+    #[arg(long = "color-map", value_name = "COLOR=PAINT")]
+    pub color_map: Vec<ColorMapping>,
+
+    /// Relative luminance from 0 to 1 below which colors are mapped to black
+    /// and above which to white, about middle gray by default
+    #[arg(long, default_value_t = 0.18, value_parser = color_map::threshold)]
+    pub luminance_threshold: f64,
+
+    /// Strokes thinner than this many panel pixels are reported by the lint
+    /// of renders
+    #[arg(long, default_value_t = 1.0)]
+    pub lint_min_stroke_width: f64,
+
+    /// Text smaller than this many panel pixels is reported by the lint of
+    /// renders
+    #[arg(long, default_value_t = 8.0)]
+    pub lint_min_font_size: f64,
+
+    /// Fills whose luminance is within this distance of
+    /// `--luminance-threshold` are reported by the lint of renders, as they
+    /// dither into noise
+    #[arg(long, default_value_t = 0.1)]
+    pub lint_gray_band: f64,
+
+    /// Decimals kept when rounding coordinates with `--optimize-svg`
+    #[arg(long, default_value_t = 3)]
+    pub svg_precision: u8,
+
+    /// Consecutive failed renders of the same posted SVG after which it is
+    /// rejected without rendering it, until another SVG is posted or the
+    /// quarantine is cleared; 0 disables this
+    #[arg(long, default_value_t = 3)]
+    pub quarantine_after: u32,
+
+    /// Start stored SVG documents with an XML declaration, for clients whose
+    /// XML parsers require one
+    #[arg(long)]
+    pub xml_declaration: bool,
+
+    /// Convert `<MAC>.bmp` files without a PNG, as left by older tools, to
+    /// PNGs at startup. The BMPs are kept
+    #[arg(long)]
+    pub migrate_legacy_bmp: bool,
+
+    /// Number of SVGs rendered at the same time
+    #[arg(long, default_value_t = 4)]
+    pub max_concurrent_renders: usize,
+
+    /// Waiting interactive renders admitted in a row before a waiting batch
+    /// render, like those of group renders and re-render jobs
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+    pub interactive_per_batch: u32,
+
+    /// Number of MACs a single render_svg request may target
+    #[arg(long, default_value_t = 16)]
+    pub max_render_targets: usize,
+
+    /// Size of the chunks image files are streamed in
+    #[arg(long, default_value = "4KiB")]
+    pub stream_chunk_bytes: ByteSize,
+
+    /// Size per second image downloads are paced to, like `2KiB`, for
+    /// clients on slow links that lose data sent in bursts
+    #[arg(long, value_parser = units::nonzero_size)]
+    pub max_download_rate: Option<ByteSize>,
+
+    /// Time after which a throttled download is aborted
+    #[arg(long, default_value = "5m")]
+    pub max_transfer_secs: HumanDuration,
+
+    /// Size above which the PNG and raw image are left out of bundles,
+    /// unless they are requested with `include`
+    #[arg(long, default_value = "256KiB")]
+    pub bundle_max_bytes: ByteSize,
+
+    /// Size above which boot reports of devices are rejected
+    #[arg(long, default_value = "4KiB")]
+    pub boot_report_max_bytes: ByteSize,
+
+    /// Percentage of the panel a render may change before devices are told
+    /// to use a full refresh
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub full_refresh_changed_percent: u8,
+
+    /// Image changes devices may show with partial refreshes before they are
+    /// told to use a full refresh
+    #[arg(long, default_value_t = 5)]
+    pub max_partial_refreshes: u32,
+
+    /// Shortest poll interval suggested to devices in
+    /// `X-EPS-Suggested-Poll-Seconds`, however often their image changes
+    #[arg(long, default_value = "1m", value_parser = units::nonzero_duration)]
+    pub poll_min: HumanDuration,
+
+    /// Longest poll interval suggested to devices, however rarely their
+    /// image changes
+    #[arg(long, default_value = "1h", value_parser = units::nonzero_duration)]
+    pub poll_max: HumanDuration,
+
+    /// Address of the full API
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:3000")]
+    pub listen: std::net::SocketAddr,
+
+    /// Address of a second listener that only serves what the devices need:
+    /// image reads, check-ins and the routes of the devices themselves, but
+    /// no admin routes and nothing that changes the images
+    #[arg(long, value_name = "ADDR")]
+    pub device_listen: Option<std::net::SocketAddr>,
+
+    /// Also accept HTTP/2 without TLS from clients that start with it (h2c
+    /// with prior knowledge), so that browsers behind a proxy speaking h2c
+    /// can multiplex requests on one connection
+    #[arg(long)]
+    pub http2: bool,
+
+    /// Time clients have to send a request's headers and body. Slow
+    /// requests are answered with 408, responses aren't limited
+    #[arg(long, default_value = "30s")]
+    pub request_timeout: HumanDuration,
+
+    /// Time to the response after which a request is logged as slow; plain
+    /// numbers are milliseconds
+    #[arg(long, default_value = "2s", value_parser = units::millis)]
+    pub slow_request_threshold: HumanDuration,
+
+    /// Time for which the response to a mutating request with an
+    /// `Idempotency-Key` header is replayed to retries with the same key
+    #[arg(long, default_value = "24h")]
+    pub idempotency_ttl: HumanDuration,
+
+    /// Idempotency keys remembered at once; the least recently used are
+    /// forgotten first
+    #[arg(long, default_value_t = 1000)]
+    pub idempotency_max_keys: usize,
+
+    /// Time after which a render is logged as stuck
+    #[arg(long, default_value = "1m")]
+    pub render_stuck_secs: HumanDuration,
+
+    /// Time after which a stuck render marks the server as not ready
+    #[arg(long, default_value = "5m")]
+    pub render_degraded_secs: HumanDuration,
+
+    /// Integration whose failing health probe makes the server not ready,
+    /// instead of only reporting it as degraded: `storage` for `--storage`.
+    /// Repeated for several
+    #[arg(long, value_enum, value_name = "DEPENDENCY")]
+    pub require_dependency: Vec<Dependency>,
+
+    /// Age after which a temporary file of an interrupted write is removed
+    /// on startup and by `POST /admin/cleanup`
+    #[arg(long, default_value = "1h")]
+    pub temp_file_grace: HumanDuration,
+
+    /// Size above which posted SVGs are buffered in a temporary file in the
+    /// image directory instead of memory while they arrive
+    #[arg(long, default_value = "256KiB")]
+    pub svg_spill_threshold: ByteSize,
+
+    /// Key required as `Authorization: Bearer <key>` on admin routes; admin
+    /// routes are open if unset
+    #[arg(long, env = "EPS_ADMIN_KEY")]
+    pub admin_key: Option<String>,
+
+    /// Apache htpasswd file with bcrypt or apr1 hashes; its users may use
+    /// admin routes with HTTP Basic auth. Re-read on `POST /admin/reload`
+    #[arg(long)]
+    pub htpasswd: Option<PathBuf>,
+
+    /// Tenant `<name>=<key>` whose images are kept in the subdirectory
+    /// `tenants/<name>` and served below `/t/<name>/` to requests with
+    /// `Authorization: Bearer <key>`; may be repeated. The routes without
+    /// prefix serve the image directory itself
+    #[arg(long = "tenant", value_name = "NAME=KEY")]
+    pub tenants: Vec<TenantKey>,
+
+    /// Network, like `10.0.0.0/8` or `fd00::/8`, allowed to use mutating
+    /// routes; may be repeated. All are allowed if unset
+    #[arg(long = "allow-write-from", value_name = "CIDR")]
+    pub allow_write_from: Vec<Cidr>,
+
+    /// Network denied mutating routes even if allowed by
+    /// `--allow-write-from`; may be repeated
+    #[arg(long = "deny-from", value_name = "CIDR")]
+    pub deny_from: Vec<Cidr>,
+
+    /// Network allowed to use read-only routes; may be repeated. All are
+    /// allowed if unset
+    #[arg(long = "allow-read-from", value_name = "CIDR")]
+    pub allow_read_from: Vec<Cidr>,
+
+    /// Proxy whose `X-Forwarded-For` header names the client; may be
+    /// repeated
+    #[arg(long = "trusted-proxy", value_name = "CIDR")]
+    pub trusted_proxies: Vec<Cidr>,
+
+    /// Memory for PNGs and SVGs of frequently fetched MACs, served without
+    /// reading them again; 0 disables this
+    #[arg(long, default_value = "0")]
+    pub image_cache_bytes: ByteSize,
+
+    /// Size up to which a PNG or SVG is kept in `--image-cache-bytes`
+    #[arg(long, default_value = "1MiB")]
+    pub image_cache_file_bytes: ByteSize,
+
+    /// Time for which a missing image is remembered, answering repeated
+    /// requests for it without touching the disk; 0 disables this
+    #[arg(long, default_value = "5s")]
+    pub negative_cache_ttl: HumanDuration,
+
+    /// Convert all PNGs to the `--warmup-formats` in the background at
+    /// startup, so that the first requests after a restart don't all have to
+    /// convert at once
+    #[arg(long)]
+    pub warmup_derived: bool,
+
+    /// Formats converted by `--warmup-derived`
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [DerivedFormat::Bmp, DerivedFormat::Raw]
+    )]
+    pub warmup_formats: Vec<DerivedFormat>,
+
+    /// Conversions run at the same time by `--warmup-derived`
+    #[arg(long, default_value_t = 1)]
+    pub warmup_concurrency: usize,
+
+    /// Format of `GET /macs/:mac/payload` for devices that didn't report
+    /// their capabilities
+    #[arg(long, value_enum, default_value_t = PayloadFormat::Png)]
+    pub default_payload_format: PayloadFormat,
+
+    /// Number of change events kept for clients resuming the event stream
+    #[arg(long, default_value_t = 1000)]
+    pub event_history: usize,
+
+    /// Interval of pings to WebSocket sessions; a session whose pong hasn't
+    /// arrived by the next ping is closed
+    #[arg(long, default_value = "30s")]
+    pub ws_ping_interval: HumanDuration,
+
+    /// MACs a WebSocket session can subscribe to
+    #[arg(long, default_value_t = 64)]
+    pub ws_max_subscriptions: usize,
+
+    /// Audit log file [default: <IMAGE_DIR>/audit.log]
+    #[arg(long)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Size after which the audit log is rotated
+    #[arg(long, default_value = "10MiB")]
+    pub audit_log_max_bytes: ByteSize,
+
+    /// Time for which the daily counts of renders and fetches per MAC are
+    /// kept in `<IMAGE_DIR>/stats`, like `90d` or `2w`; plain numbers are
+    /// days and 0 keeps them forever
+    #[arg(long, default_value = "90d", value_parser = units::days)]
+    pub stats_retention_days: HumanDuration,
+
+    /// Executable run after each successful render with the MAC and the
+    /// path of a copy of the PNG as arguments and the render as JSON on
+    /// stdin, for converting to formats the server doesn't support
+    #[arg(long, value_name = "PATH")]
+    pub post_render_cmd: Option<PathBuf>,
+
+    /// Time after which `--post-render-cmd` is killed
+    #[arg(long, default_value = "30s")]
+    pub post_render_timeout: HumanDuration,
+
+    /// Runs of `--post-render-cmd` at the same time
+    #[arg(long, default_value_t = 2)]
+    pub post_render_concurrency: usize,
+
+    /// Rhai script run before each render, which may change the SVG and
+    /// the render options, see `examples/store_code.rhai`. Reloaded with
+    /// `POST /admin/reload`
+    #[cfg(feature = "script")]
+    #[arg(long, value_name = "PATH")]
+    pub render_script: Option<PathBuf>,
+
+    /// Time after which a run of `--render-script` is stopped and its
+    /// render rejected
+    #[cfg(feature = "script")]
+    #[arg(long, default_value = "100ms")]
+    pub render_script_timeout: HumanDuration,
+
+    /// Size of the strings `--render-script` may build into one value, which
+    /// also limits the elements of its arrays and maps
+    #[cfg(feature = "script")]
+    #[arg(long, default_value = "4MiB", value_parser = units::nonzero_size)]
+    pub render_script_max_bytes: ByteSize,
+
+    /// Fail the request that rendered if `--post-render-cmd` fails, instead
+    /// of only recording the failure in the render log
+    #[arg(long)]
+    pub post_render_strict: bool,
+
+    /// Base URL of a standby server that every change of the images is
+    /// forwarded to through its API, see `GET /replication/status`. Only
+    /// http is supported, and the replica must not be `--read-only`
+    #[arg(long, value_name = "URL")]
+    pub replica_url: Option<Url>,
+
+    /// Sent as `Authorization: Bearer <key>` to `--replica-url`: its admin
+    /// key, without which a replica that replicates too sends the changes
+    /// on
+    #[arg(long, env = "EPS_REPLICA_KEY")]
+    pub replica_key: Option<String>,
+
+    /// Changes kept for `--replica-url` while it is unreachable; the oldest
+    /// ones are dropped beyond that. Later changes of a MAC replace earlier
+    /// ones
+    #[arg(long, default_value_t = 10000)]
+    pub replica_queue_max: usize,
+
+    /// TOML file of failures to inject into responses, see `GET /chaos`
+    #[cfg(feature = "chaos")]
+    #[arg(long, value_name = "TOML")]
+    pub chaos_config: Option<PathBuf>,
+
+    /// UDP address, like `[::]:5683`, of a CoAP server for devices that
+    /// fetch their raw images and hashes over CoAP
+    #[cfg(feature = "coap")]
+    #[arg(long, value_name = "ADDR")]
+    pub coap_listen: Option<std::net::SocketAddr>,
+}
+
+/// The duration and size options of a validated configuration, normalized,
+/// for `GET /config`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Limits {
+    pub stream_chunk_bytes: ByteSize,
+    pub max_download_rate: Option<ByteSize>,
+    pub max_transfer_secs: HumanDuration,
+    pub bundle_max_bytes: ByteSize,
+    pub request_timeout: HumanDuration,
+    pub slow_request_threshold: HumanDuration,
+    pub render_stuck_secs: HumanDuration,
+    pub render_degraded_secs: HumanDuration,
+    pub svg_spill_threshold: ByteSize,
+    pub negative_cache_ttl: HumanDuration,
+    pub audit_log_max_bytes: ByteSize,
+}
+
+impl From<&ValidatedConfig> for Limits {
+    fn from(config: &ValidatedConfig) -> Self {
+        Limits {
+            stream_chunk_bytes: config.stream_chunk_bytes,
+            max_download_rate: config.max_download_rate,
+            max_transfer_secs: config.max_transfer_secs,
+            bundle_max_bytes: config.bundle_max_bytes,
+            request_timeout: config.request_timeout,
+            slow_request_threshold: config.slow_request_threshold,
+            render_stuck_secs: config.render_stuck_secs,
+            render_degraded_secs: config.render_degraded_secs,
+            svg_spill_threshold: config.svg_spill_threshold,
+            negative_cache_ttl: config.negative_cache_ttl,
+            audit_log_max_bytes: config.audit_log_max_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Dither {
+    FloydSteinberg,
+    Threshold,
+}
+
+/// How text of posted SVGs is stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TextMode {
+    /// Converted to paths with the fonts installed at the time of the render
+    Paths,
+    /// As posted, rendered with the fonts installed at the time of each render
+    Live,
+}
+
+impl Config {
+    /// The configuration of `tenant`: its images are in its subdirectory of
+    /// [`TENANTS_DIR`], and its key is the admin key of its routes.
+    pub fn for_tenant(&self, tenant: &TenantKey) -> Config {
+        Config {
+            image_dir: self.image_dir.join(TENANTS_DIR).join(&tenant.name),
+            admin_key: Some(tenant.key.clone()),
+            htpasswd: None,
+            audit_log: None,
+            tenants: vec![],
+            ..self.clone()
+        }
+    }
+
+    pub fn text_mode(&self) -> TextMode {
+        if self.convert_text_to_paths {
+            TextMode::Paths
+        } else {
+            TextMode::Live
+        }
+    }
+
+    /// Whether colors are mapped unless a render asks otherwise.
+    pub fn color_map_request(&self) -> ColorMapRequest {
+        if self.map_colors {
+            ColorMapRequest::Auto
+        } else {
+            ColorMapRequest::Preserve
+        }
+    }
+
+    /// The color mapping of a render with the resolved `color_map` option
+    /// `request`, if its colors are mapped.
+    pub fn color_map(&self, request: &ColorMapRequest) -> Option<ColorMap> {
+        let map = ColorMap::new(&self.color_map, self.luminance_threshold);
+        match request {
+            ColorMapRequest::Preserve => None,
+            ColorMapRequest::Auto => Some(map),
+            ColorMapRequest::Custom(mappings) => {
+                let mut map = map;
+                map.extend(mappings);
+                Some(map)
+            }
+        }
+    }
+}
+
+/// See `--stale-dimensions`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub(crate) enum StaleDimensions {
+    /// Render the stored SVG again at the new size. PNGs uploaded directly
+    /// are flagged
+    Rerender,
+    /// Serve the PNG as it is with `X-EPS-Stale-Dimensions: true`
+    Flag,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ColorMode {
+    /// Black and white
+    Mono,
+    Palette,
 }
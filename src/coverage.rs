@@ -0,0 +1,143 @@
+use image::{ImageFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::region::Region;
+
+/// Channel value below which a pixel, shown on white, counts as drawn. Keeps
+/// the faint fringes of antialiasing out.
+const INK_THRESHOLD: u8 = 250;
+
+/// How much of the panel a render draws on, to tell whether there is room
+/// for more content.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Coverage {
+    /// Pixels that aren't background, in percent of the panel.
+    pub percent: f64,
+    /// Smallest rectangle with all drawn pixels, absent if there are none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounding_box: Option<Region>,
+    /// Drawn pixels of each quarter of the panel, in percent of the quarter.
+    pub quadrants: Quadrants,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Quadrants {
+    pub top_left: f64,
+    pub top_right: f64,
+    pub bottom_left: f64,
+    pub bottom_right: f64,
+}
+
+/// The coverage of `png` on a white panel, `None` if it can't be decoded.
+pub(crate) fn analyze(png: &[u8]) -> Option<Coverage> {
+    let image = image::load_from_memory_with_format(png, ImageFormat::Png).ok()?;
+    Some(of_image(&image.to_rgba8()))
+}
+
+fn of_image(image: &RgbaImage) -> Coverage {
+    let (width, height) = image.dimensions();
+    let (mid_x, mid_y) = (width / 2, height / 2);
+    // Drawn pixels of the quarters, left to right and top to bottom
+    let mut ink = [0u64; 4];
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let [r, g, b, a] = pixel.0;
+        let over_white = |c: u8| 255 - (255 - c as u32) * a as u32 / 255;
+        if [r, g, b]
+            .iter()
+            .all(|&c| over_white(c) >= INK_THRESHOLD as u32)
+        {
+            continue;
+        }
+        ink[usize::from(x >= mid_x) + 2 * usize::from(y >= mid_y)] += 1;
+        bounds = Some(match bounds {
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            }
+            None => (x, y, x, y),
+        });
+    }
+
+    let areas = [
+        mid_x * mid_y,
+        (width - mid_x) * mid_y,
+        mid_x * (height - mid_y),
+        (width - mid_x) * (height - mid_y),
+    ];
+    let percent = |ink: u64, area: u64| match area {
+        0 => 0.0,
+        area => (ink as f64 * 10000.0 / area as f64).round() / 100.0,
+    };
+    let quadrant = |i: usize| percent(ink[i], areas[i] as u64);
+    Coverage {
+        percent: percent(ink.iter().sum(), width as u64 * height as u64),
+        bounding_box: bounds.map(|(left, top, right, bottom)| Region {
+            x: left,
+            y: top,
+            w: right - left + 1,
+            h: bottom - top + 1,
+        }),
+        quadrants: Quadrants {
+            top_left: quadrant(0),
+            top_right: quadrant(1),
+            bottom_left: quadrant(2),
+            bottom_right: quadrant(3),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn left_half() {
+        let image = RgbaImage::from_fn(100, 60, |x, _| match x {
+            0..=49 => Rgba([0, 0, 0, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        });
+        let coverage = of_image(&image);
+        assert_eq!(coverage.percent, 50.0);
+        assert_eq!(
+            coverage.bounding_box,
+            Some(Region {
+                x: 0,
+                y: 0,
+                w: 50,
+                h: 60
+            })
+        );
+        assert_eq!(
+            coverage.quadrants,
+            Quadrants {
+                top_left: 100.0,
+                top_right: 0.0,
+                bottom_left: 100.0,
+                bottom_right: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn blank() {
+        // Transparent, faint and white pixels all show the panel
+        let image = RgbaImage::from_fn(10, 10, |x, _| match x {
+            0..=3 => Rgba([0, 0, 0, 0]),
+            4..=6 => Rgba([0, 0, 0, 2]),
+            _ => Rgba([255, 255, 255, 255]),
+        });
+        let coverage = of_image(&image);
+        assert_eq!(coverage.percent, 0.0);
+        assert_eq!(coverage.bounding_box, None);
+
+        let dot = RgbaImage::from_fn(10, 10, |x, y| match (x, y) {
+            (9, 9) => Rgba([255, 255, 0, 255]),
+            _ => Rgba([255, 255, 255, 0]),
+        });
+        let coverage = of_image(&dot);
+        assert_eq!(coverage.percent, 1.0);
+        assert_eq!(coverage.quadrants.bottom_right, 4.0);
+    }
+}
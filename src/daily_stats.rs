@@ -0,0 +1,265 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::image_handler::{EpdMac, ImageHandler};
+
+/// Subdirectory of the image directory with a `<yyyy-mm-dd>.json` file per
+/// day.
+pub(crate) const STATS_DIR: &str = "stats";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Counter {
+    Render,
+    Fetch,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Counts {
+    pub renders: u64,
+    /// Image responses, not counting those answered with 304.
+    pub fetches: u64,
+}
+
+type Day = BTreeMap<EpdMac, Counts>;
+
+/// A row of `GET /stats/daily`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DailyRow {
+    pub date: NaiveDate,
+    pub mac: EpdMac,
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+/// Renders and fetches per MAC and day, in the time zone of `--timezone`.
+/// Requests only add to counters in memory, which [`run`] adds to the files
+/// of their days every minute, so a crash loses at most that minute.
+pub(crate) struct DailyStats {
+    dir: PathBuf,
+    timezone: Tz,
+    /// Days of files kept, counting today, or 0 to keep all.
+    retention_days: u32,
+    /// Counts not yet added to the files.
+    pending: Mutex<BTreeMap<NaiveDate, Day>>,
+    /// Serializes flushes, which read, add to and write the files.
+    flushing: tokio::sync::Mutex<()>,
+}
+
+impl DailyStats {
+    /// Keeps the files of the days that end within `retention`, or all if it
+    /// is zero.
+    pub fn new(dir: PathBuf, timezone: Tz, retention: Duration) -> Self {
+        DailyStats {
+            dir,
+            timezone,
+            retention_days: u32::try_from(retention.as_secs().div_ceil(24 * 3600))
+                .unwrap_or(u32::MAX),
+            pending: Mutex::default(),
+            flushing: tokio::sync::Mutex::default(),
+        }
+    }
+
+    pub fn count(&self, counter: Counter, mac: EpdMac, now: DateTime<Utc>) {
+        let date = now.with_timezone(&self.timezone).date_naive();
+        let mut pending = self.pending.lock().unwrap();
+        let counts = pending.entry(date).or_default().entry(mac).or_default();
+        match counter {
+            Counter::Render => counts.renders += 1,
+            Counter::Fetch => counts.fetches += 1,
+        }
+    }
+
+    /// Adds the pending counts to the files of their days and removes the
+    /// files of days older than the retention. Counts that could not be
+    /// written are kept for the next flush.
+    pub async fn flush(&self, now: DateTime<Utc>) -> eyre::Result<()> {
+        let _flushing = self.flushing.lock().await;
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut result = Ok(());
+        for (date, counts) in pending {
+            if let Err(e) = self.add(date, &counts).await {
+                self.restore(date, counts);
+                result = Err(e);
+            }
+        }
+        result?;
+
+        if self.retention_days == 0 {
+            return Ok(());
+        }
+        let today = now.with_timezone(&self.timezone).date_naive();
+        let oldest = today - chrono::Duration::days(i64::from(self.retention_days) - 1);
+        for (date, path) in self.files().await? {
+            if date < oldest {
+                fs::remove_file(path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn add(&self, date: NaiveDate, counts: &Day) -> eyre::Result<()> {
+        let path = self.dir.join(format!("{date}.json"));
+        let mut day = read_day(&path).await?;
+        for (&mac, counts) in counts {
+            let total = day.entry(mac).or_default();
+            total.renders += counts.renders;
+            total.fetches += counts.fetches;
+        }
+        fs::create_dir_all(&self.dir).await?;
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec(&day)?).await?;
+        fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    fn restore(&self, date: NaiveDate, counts: Day) {
+        let mut pending = self.pending.lock().unwrap();
+        let day = pending.entry(date).or_default();
+        for (mac, counts) in counts {
+            let pending = day.entry(mac).or_default();
+            pending.renders += counts.renders;
+            pending.fetches += counts.fetches;
+        }
+    }
+
+    /// The stored days with their files.
+    async fn files(&self) -> io::Result<Vec<(NaiveDate, PathBuf)>> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let date = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+                .and_then(|date| date.parse().ok());
+            if let Some(date) = date {
+                files.push((date, entry.path()));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// The stored rows of the days from `from` to `to`, both inclusive,
+    /// ordered by day and MAC. Counts since the last flush are left out.
+    pub async fn rows(
+        &self,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> eyre::Result<Vec<DailyRow>> {
+        let mut rows = Vec::new();
+        for (date, path) in self.files().await? {
+            if from.is_some_and(|from| date < from) || to.is_some_and(|to| date > to) {
+                continue;
+            }
+            rows.extend(
+                read_day(&path)
+                    .await?
+                    .into_iter()
+                    .map(|(mac, counts)| DailyRow { date, mac, counts }),
+            );
+        }
+        Ok(rows)
+    }
+}
+
+async fn read_day(path: &Path) -> eyre::Result<Day> {
+    match fs::read(path).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Day::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Formats `rows` as CSV with a header row.
+pub(crate) fn to_csv(rows: &[DailyRow]) -> String {
+    let mut csv = String::from("date,mac,renders,fetches\r\n");
+    for row in rows {
+        let _ = write!(
+            csv,
+            "{},{},{},{}\r\n",
+            row.date, row.mac, row.counts.renders, row.counts.fetches
+        );
+    }
+    csv
+}
+
+pub(crate) async fn run(image_handler: Arc<ImageHandler>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(e) = image_handler.flush_stats().await {
+            tracing::error!("Could not write the daily statistics: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::Europe::Berlin;
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+
+    const MAC: EpdMac = EpdMac([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn flush_adds_and_prunes() {
+        let temp_dir = TestDir::temp();
+        let stats = DailyStats::new(
+            temp_dir.path(STATS_DIR),
+            Berlin,
+            Duration::from_secs(2 * 86400),
+        );
+
+        // Already the 2nd in Berlin
+        stats.count(Counter::Render, MAC, utc("2024-03-01T23:30:00Z"));
+        stats.flush(utc("2024-03-01T23:30:00Z")).await.unwrap();
+        stats.count(Counter::Fetch, MAC, utc("2024-03-02T08:00:00Z"));
+        stats.count(Counter::Fetch, MAC, utc("2024-03-02T09:00:00Z"));
+        stats.flush(utc("2024-03-02T09:00:00Z")).await.unwrap();
+        let day = |renders, fetches| Counts { renders, fetches };
+        assert_eq!(
+            stats.rows(None, None).await.unwrap(),
+            [DailyRow {
+                date: "2024-03-02".parse().unwrap(),
+                mac: MAC,
+                counts: day(1, 2),
+            }]
+        );
+
+        stats.count(Counter::Render, MAC, utc("2024-03-03T12:00:00Z"));
+        stats.flush(utc("2024-03-03T12:00:00Z")).await.unwrap();
+        stats.count(Counter::Render, MAC, utc("2024-03-04T12:00:00Z"));
+        stats.flush(utc("2024-03-04T12:00:00Z")).await.unwrap();
+        let dates: Vec<_> = stats
+            .rows(None, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| row.date.to_string())
+            .collect();
+        assert_eq!(dates, ["2024-03-03", "2024-03-04"]);
+        let to = "2024-03-03".parse().ok();
+        assert_eq!(stats.rows(None, to).await.unwrap().len(), 1);
+    }
+}
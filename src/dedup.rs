@@ -0,0 +1,340 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use axum::{async_trait, body::Bytes};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    sync::RwLock,
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    artifact,
+    cleanup::CleanedFile,
+    storage::{self, ByteStream, FileMeta, ImageStore, LocalStore},
+};
+
+/// Directory in the image directory with the contents of `--dedup`.
+pub(crate) const OBJECTS_DIR: &str = "objects";
+
+/// Bytes of the images kept by `--dedup`, for `GET /stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DedupUsage {
+    /// Distinct contents, including those no file refers to anymore.
+    pub objects: usize,
+    /// Files of MACs that refer to one of the objects.
+    pub references: u64,
+    /// Size of the files of MACs, as if each had its own copy.
+    pub logical_bytes: u64,
+    /// Size of the objects, which is what the disk holds.
+    pub physical_bytes: u64,
+}
+
+/// Outcome of `POST /admin/dedup`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DedupReport {
+    /// Files of the flat layout that were linked to an object.
+    pub migrated: usize,
+    pub usage: DedupUsage,
+}
+
+/// Files in a local directory, where the images of MACs are hard links to
+/// one file per content in [`OBJECTS_DIR`], named by its SHA-256. The link
+/// count of an object tells how many files refer to it, so deleting the
+/// images of one MAC leaves the others intact, and objects without
+/// references are removed by [`DedupStore::collect_garbage`]. Reads are
+/// those of plain files; entity tags are the content hash.
+#[derive(Debug)]
+pub(crate) struct DedupStore {
+    files: LocalStore,
+    objects: PathBuf,
+    /// Hashes of the objects by the device and inode their links share.
+    hashes: Mutex<HashMap<(u64, u64), String>>,
+    /// Held by writes from creating an object until it is linked, and by
+    /// the removal of unreferenced objects.
+    gc: RwLock<()>,
+}
+
+impl DedupStore {
+    pub fn new(dir: PathBuf, shard_depth: u8) -> Self {
+        DedupStore {
+            objects: dir.join(OBJECTS_DIR),
+            files: LocalStore::new(dir, shard_depth),
+            hashes: Mutex::default(),
+            gc: RwLock::new(()),
+        }
+    }
+
+    /// The metadata of a file with the content hash as its tag, if it is
+    /// linked to an object.
+    async fn file_meta(&self, meta: &std::fs::Metadata) -> io::Result<FileMeta> {
+        let mut file_meta = storage::file_meta(meta)?;
+        if meta.nlink() > 1 {
+            let key = (meta.dev(), meta.ino());
+            let known = self.hashes.lock().unwrap().get(&key).cloned();
+            file_meta.tag = match known {
+                Some(hash) => Some(hash),
+                None => {
+                    self.index().await?;
+                    self.hashes.lock().unwrap().get(&key).cloned()
+                }
+            };
+        }
+        Ok(file_meta)
+    }
+
+    /// Reads the inodes of all objects into `hashes`.
+    async fn index(&self) -> io::Result<()> {
+        let mut hashes = HashMap::new();
+        for (hash, meta) in self.objects().await? {
+            hashes.insert((meta.dev(), meta.ino()), hash);
+        }
+        *self.hashes.lock().unwrap() = hashes;
+        Ok(())
+    }
+
+    /// The objects with their metadata, none if there is no object
+    /// directory yet.
+    async fn objects(&self) -> io::Result<Vec<(String, std::fs::Metadata)>> {
+        let mut entries = match fs::read_dir(&self.objects).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut objects = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(hash) = entry
+                .file_name()
+                .to_str()
+                .filter(|name| is_hash(name))
+                .map(str::to_owned)
+            else {
+                continue;
+            };
+            match entry.metadata().await {
+                Ok(meta) => objects.push((hash, meta)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(objects)
+    }
+
+    /// Writes the object of `data` unless it exists, and returns its path
+    /// and hash.
+    async fn store_object(&self, data: &[u8]) -> io::Result<(PathBuf, String)> {
+        let hash = hex::encode(Sha256::digest(data));
+        let path = self.objects.join(&hash);
+        match fs::metadata(&path).await {
+            Ok(_) => return Ok((path, hash)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        fs::create_dir_all(&self.objects).await?;
+        let temp_path = self.objects.join(storage::temp_name(&hash));
+        let result = async {
+            fs::write(&temp_path, data).await?;
+            fs::rename(&temp_path, &path).await
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+        Ok((path, hash))
+    }
+
+    /// Replaces the file `name` with a link to `object`.
+    async fn link(&self, object: &PathBuf, name: &str) -> io::Result<()> {
+        let path = self.files.path(name);
+        if let Some(shard) = path.parent() {
+            fs::create_dir_all(shard).await?;
+        }
+        let temp_path = path.with_file_name(storage::temp_name(name));
+        let result = async {
+            fs::hard_link(object, &temp_path).await?;
+            fs::rename(&temp_path, &path).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+        result
+    }
+
+    /// Moves the file `name` of the flat layout into an object, returning
+    /// whether it was one that is kept once per content and not linked yet.
+    /// The caller keeps others from writing it meanwhile.
+    pub async fn migrate(&self, name: &str) -> io::Result<bool> {
+        if !is_content_addressed(name) {
+            return Ok(false);
+        }
+        let meta = self.files.with_fallback(name, fs::metadata).await?;
+        if meta.nlink() > 1 {
+            return Ok(false);
+        }
+        let data = self.files.read(name).await?;
+        self.put(name, data.into()).await?;
+        Ok(true)
+    }
+
+    /// The objects no file refers to anymore, which are removed with
+    /// `remove`. Named like `objects/<sha256>`.
+    pub async fn collect_garbage(&self, remove: bool) -> io::Result<Vec<CleanedFile>> {
+        let _gc = self.gc.write().await;
+        let mut unreferenced = Vec::new();
+        for (hash, meta) in self.objects().await? {
+            if meta.nlink() > 1 {
+                continue;
+            }
+            if remove {
+                match fs::remove_file(self.objects.join(&hash)).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                }
+                self.hashes
+                    .lock()
+                    .unwrap()
+                    .remove(&(meta.dev(), meta.ino()));
+            }
+            unreferenced.push(CleanedFile {
+                name: format!("{OBJECTS_DIR}/{hash}"),
+                bytes: meta.len(),
+            });
+        }
+        unreferenced.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(unreferenced)
+    }
+
+    /// Where the objects are kept.
+    pub fn objects_dir(&self) -> &Path {
+        &self.objects
+    }
+
+    pub async fn usage(&self) -> io::Result<DedupUsage> {
+        let mut usage = DedupUsage::default();
+        for (_, meta) in self.objects().await? {
+            let references = meta.nlink().saturating_sub(1);
+            usage.objects += 1;
+            usage.references += references;
+            usage.logical_bytes += references * meta.len();
+            usage.physical_bytes += meta.len();
+        }
+        Ok(usage)
+    }
+}
+
+#[async_trait]
+impl ImageStore for DedupStore {
+    async fn get(&self, name: &str, chunk_size: usize) -> io::Result<(ByteStream, FileMeta)> {
+        let file = self.files.with_fallback(name, File::open).await?;
+        let meta = self.file_meta(&file.metadata().await?).await?;
+        Ok((ReaderStream::with_capacity(file, chunk_size).boxed(), meta))
+    }
+
+    async fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        self.files.read(name).await
+    }
+
+    /// Files other than images, like the metadata, are written as they are.
+    async fn put(&self, name: &str, data: Bytes) -> io::Result<()> {
+        if !is_content_addressed(name) {
+            return self.files.put(name, data).await;
+        }
+        let _gc = self.gc.read().await;
+        let (object, hash) = self.store_object(&data).await?;
+        self.link(&object, name).await?;
+        let meta = fs::metadata(&object).await?;
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert((meta.dev(), meta.ino()), hash);
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        self.files.delete(name).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<io::Result<OsString>>> {
+        self.files.list().await
+    }
+
+    async fn stat(&self, name: &str) -> io::Result<FileMeta> {
+        let meta = self.files.with_fallback(name, fs::metadata).await?;
+        self.file_meta(&meta).await
+    }
+}
+
+fn is_content_addressed(name: &str) -> bool {
+    artifact::parse(name).is_some_and(|(_, artifact)| artifact.content_addressed())
+}
+
+/// Whether `name` is that of an object.
+pub(crate) fn is_hash(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shared_objects() {
+        let temp_dir = TestDir::temp();
+        let store = DedupStore::new(temp_dir.root().to_owned(), 1);
+        for mac in ["0011223344556677", "1111111111111111"] {
+            store
+                .put(&format!("{mac}.png"), "same".into())
+                .await
+                .unwrap();
+        }
+        store
+            .put("0011223344556677.meta.json", "{}".into())
+            .await
+            .unwrap();
+        let usage = store.usage().await.unwrap();
+        assert_eq!(usage.objects, 1);
+        assert_eq!(usage.references, 2);
+        assert_eq!((usage.logical_bytes, usage.physical_bytes), (8, 4));
+
+        let meta = store.stat("1111111111111111.png").await.unwrap();
+        assert_eq!(meta.tag, Some(hex::encode(Sha256::digest(b"same"))));
+        assert_eq!(
+            store.stat("0011223344556677.meta.json").await.unwrap().tag,
+            None
+        );
+        // Found again after a restart
+        let reopened = DedupStore::new(temp_dir.root().to_owned(), 1);
+        assert_eq!(
+            reopened.stat("1111111111111111.png").await.unwrap().tag,
+            meta.tag
+        );
+
+        // Replacing one keeps the other
+        store
+            .put("0011223344556677.png", "other".into())
+            .await
+            .unwrap();
+        assert_eq!(store.read("1111111111111111.png").await.unwrap(), b"same");
+        store.delete("1111111111111111.png").await.unwrap();
+        let unreferenced = store.collect_garbage(false).await.unwrap();
+        assert_eq!(unreferenced.len(), 1);
+        assert_eq!(unreferenced[0].bytes, 4);
+        store.collect_garbage(true).await.unwrap();
+        assert_eq!(store.usage().await.unwrap().objects, 1);
+        assert_eq!(store.read("0011223344556677.png").await.unwrap(), b"other");
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::image_handler::ImageHandler;
+
+/// Time a health probe may take before it counts as failed.
+pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An integration that the server uses besides rendering and its image
+/// directory, see `--require-dependency`.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Dependency {
+    /// The object store given by `--storage`
+    Storage,
+}
+
+/// Result of the latest health probe of a dependency.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Health {
+    pub healthy: bool,
+    /// Whether its failure makes the server not ready, rather than degraded.
+    pub required: bool,
+    pub checked: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The health of the dependencies, as found by their probes.
+pub(crate) struct Dependencies {
+    required: BTreeSet<Dependency>,
+    health: Mutex<BTreeMap<Dependency, Health>>,
+}
+
+impl Dependencies {
+    pub fn new(required: &[Dependency]) -> Self {
+        Dependencies {
+            required: required.iter().copied().collect(),
+            health: Mutex::default(),
+        }
+    }
+
+    /// Keeps the result of a probe of `dependency`, logging when its health
+    /// changed.
+    pub fn record(&self, dependency: Dependency, result: Result<(), String>, now: DateTime<Utc>) {
+        let required = self.required.contains(&dependency);
+        let name = dependency_name(dependency);
+        let mut health = self.health.lock().unwrap();
+        let was_healthy = health.get(&dependency).map(|health| health.healthy);
+        match (&result, was_healthy) {
+            (Err(e), None | Some(true)) if required => {
+                tracing::error!("Required dependency {name} is failing, not ready: {e}")
+            }
+            (Err(e), None | Some(true)) => tracing::warn!("Dependency {name} is failing: {e}"),
+            (Ok(()), Some(false)) => tracing::info!("Dependency {name} recovered"),
+            (Ok(()), None) => tracing::debug!("Dependency {name} is healthy"),
+            _ => {}
+        }
+        health.insert(
+            dependency,
+            Health {
+                healthy: result.is_ok(),
+                required,
+                checked: now,
+                error: result.err(),
+            },
+        );
+    }
+
+    /// The latest health of each probed dependency.
+    pub fn health(&self) -> BTreeMap<Dependency, Health> {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Whether a dependency that is not required is failing.
+    pub fn degraded(&self) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .values()
+            .any(|health| !health.healthy && !health.required)
+    }
+
+    /// The required dependencies whose latest probe failed.
+    pub fn failing_required(&self) -> Vec<Dependency> {
+        self.health
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, health)| !health.healthy && health.required)
+            .map(|(&dependency, _)| dependency)
+            .collect()
+    }
+}
+
+/// The name of `dependency` as given to `--require-dependency`.
+pub(crate) fn dependency_name(dependency: Dependency) -> String {
+    dependency
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_owned())
+}
+
+/// Probes the dependencies of `image_handler` every `period`, after the
+/// probes at startup.
+pub(crate) async fn run(image_handler: Arc<ImageHandler>, period: Duration) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        image_handler.check_dependencies().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_health() {
+        let now = Utc::now();
+        let dependencies = Dependencies::new(&[]);
+        assert!(dependencies.health().is_empty());
+        assert!(!dependencies.degraded());
+
+        dependencies.record(Dependency::Storage, Err("refused".to_owned()), now);
+        assert!(dependencies.degraded());
+        assert!(dependencies.failing_required().is_empty());
+        let health = &dependencies.health()[&Dependency::Storage];
+        assert_eq!(health.error.as_deref(), Some("refused"));
+        assert!(!health.required);
+
+        dependencies.record(Dependency::Storage, Ok(()), now);
+        assert!(!dependencies.degraded());
+
+        let dependencies = Dependencies::new(&[Dependency::Storage]);
+        dependencies.record(Dependency::Storage, Err("refused".to_owned()), now);
+        assert!(!dependencies.degraded());
+        assert_eq!(dependencies.failing_required(), [Dependency::Storage]);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::body::Bytes;
+use clap::ValueEnum;
+use futures_util::{stream, StreamExt};
+use image::{DynamicImage, ImageFormat};
+
+use crate::{
+    config::Dither,
+    image_handler::{EpdMac, ImageHandler},
+    raster::{self, Palette},
+};
+
+/// Formats converted from the stored PNGs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ValueEnum)]
+pub(crate) enum DerivedFormat {
+    Bmp,
+    Raw,
+}
+
+impl DerivedFormat {
+    /// Converts `image` for a black and white panel, or one with the colors
+    /// of `palette`.
+    pub fn convert(self, image: &DynamicImage, palette: Option<&Palette>) -> eyre::Result<Vec<u8>> {
+        match (self, palette) {
+            (DerivedFormat::Bmp, None) => bmp(raster::flatten(image).into()),
+            (DerivedFormat::Bmp, Some(palette)) => {
+                bmp(palette.quantize(image, Dither::Threshold).into())
+            }
+            (DerivedFormat::Raw, None) => Ok(raster::pack_1bpp(&raster::flatten(image))),
+            (DerivedFormat::Raw, Some(palette)) => {
+                Ok(palette.pack_4bpp(&palette.quantize(image, Dither::Threshold)))
+            }
+        }
+    }
+}
+
+fn bmp(image: DynamicImage) -> eyre::Result<Vec<u8>> {
+    let mut bmp = io::Cursor::new(vec![]);
+    image.write_to(&mut bmp, ImageFormat::Bmp)?;
+    Ok(bmp.into_inner())
+}
+
+/// Conversions of the stored PNGs, valid as long as the PNG they were
+/// converted from has the same entity tag.
+#[derive(Debug, Default)]
+pub(crate) struct DerivedCache {
+    entries: Mutex<HashMap<(EpdMac, DerivedFormat), Cached>>,
+}
+
+#[derive(Debug)]
+struct Cached {
+    source: String,
+    data: Bytes,
+}
+
+impl DerivedCache {
+    /// The conversion of the PNG of `mac` with the entity tag `source`.
+    pub fn get(&self, mac: EpdMac, format: DerivedFormat, source: &str) -> Option<Bytes> {
+        let entries = self.entries.lock().unwrap();
+        let cached = entries.get(&(mac, format))?;
+        (cached.source == source).then(|| cached.data.clone())
+    }
+
+    pub fn insert(&self, mac: EpdMac, format: DerivedFormat, source: String, data: Bytes) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert((mac, format), Cached { source, data });
+    }
+
+    /// Drops all conversions of `mac`, e.g. because its PNG changed.
+    pub fn forget(&self, mac: EpdMac) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|&(cached_mac, _), _| cached_mac != mac);
+    }
+
+    #[cfg(test)]
+    pub fn contains(&self, mac: EpdMac, format: DerivedFormat) -> bool {
+        self.entries.lock().unwrap().contains_key(&(mac, format))
+    }
+}
+
+/// Progress of converting all PNGs at startup.
+#[derive(Debug, Default)]
+pub(crate) struct Warmup {
+    started: AtomicBool,
+    total: AtomicUsize,
+    done: AtomicUsize,
+}
+
+impl Warmup {
+    /// Percentage of the conversions done, `None` if no warm-up was started.
+    pub fn percent(&self) -> Option<f64> {
+        if !self.started.load(Ordering::Relaxed) {
+            return None;
+        }
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return Some(100.0);
+        }
+        Some(self.done.load(Ordering::Relaxed) as f64 * 100.0 / total as f64)
+    }
+}
+
+/// Converts the PNGs of all MACs to `formats`, `concurrency` conversions at
+/// a time, skipping conversions that are cached already. Requests are
+/// served as usual meanwhile.
+pub(crate) async fn warm_up(
+    image_handler: Arc<ImageHandler>,
+    formats: Vec<DerivedFormat>,
+    concurrency: usize,
+) {
+    let macs = match image_handler.get_macs().await {
+        Ok(listing) => listing.macs,
+        Err(e) => {
+            tracing::error!("Warm-up failed to list the images: {e}");
+            return;
+        }
+    };
+    let jobs: Vec<_> = macs
+        .iter()
+        .filter(|entry| entry.has_png)
+        .flat_map(|entry| formats.iter().map(move |&format| (entry.mac, format)))
+        .collect();
+
+    let warmup = image_handler.warmup();
+    let total = jobs.len();
+    warmup.total.store(total, Ordering::Relaxed);
+    warmup.started.store(true, Ordering::Relaxed);
+    tracing::info!("Warming up {total} derived images");
+
+    stream::iter(jobs)
+        .for_each_concurrent(concurrency.max(1), |(mac, format)| {
+            let image_handler = &image_handler;
+            async move {
+                if let Err(e) = image_handler.derived(mac, format).await {
+                    tracing::warn!("Warm-up of {format:?} of {mac} failed: {e}");
+                }
+                let done = warmup.done.fetch_add(1, Ordering::Relaxed) + 1;
+                if done * 10 / total != (done - 1) * 10 / total {
+                    tracing::info!("Warm-up {}% done", done * 100 / total);
+                }
+            }
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: EpdMac = EpdMac([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+    #[test]
+    fn cache_follows_source() {
+        let cache = DerivedCache::default();
+        cache.insert(
+            MAC,
+            DerivedFormat::Raw,
+            "\"a\"".into(),
+            Bytes::from_static(b"raw"),
+        );
+
+        assert_eq!(
+            cache.get(MAC, DerivedFormat::Raw, "\"a\""),
+            Some(Bytes::from_static(b"raw"))
+        );
+        assert_eq!(cache.get(MAC, DerivedFormat::Raw, "\"b\""), None);
+        assert_eq!(cache.get(MAC, DerivedFormat::Bmp, "\"a\""), None);
+
+        cache.forget(MAC);
+        assert!(!cache.contains(MAC, DerivedFormat::Raw));
+    }
+}
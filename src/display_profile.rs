@@ -0,0 +1,151 @@
+use eyre::eyre;
+use image::{imageops, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// Clockwise rotation of the rendered image onto the panel, for panels
+/// mounted sideways or upside down.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Degrees", into = "u16")]
+pub(crate) enum Rotation {
+    #[default]
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+/// Degrees as a number, or as text in query strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Degrees {
+    Number(u16),
+    Text(String),
+}
+
+impl TryFrom<Degrees> for Rotation {
+    type Error = eyre::Error;
+
+    fn try_from(degrees: Degrees) -> Result<Self, Self::Error> {
+        let degrees = match degrees {
+            Degrees::Number(degrees) => degrees.to_string(),
+            Degrees::Text(degrees) => degrees,
+        };
+        match degrees.as_str() {
+            "0" => Ok(Rotation::Deg0),
+            "90" => Ok(Rotation::Deg90),
+            "180" => Ok(Rotation::Deg180),
+            "270" => Ok(Rotation::Deg270),
+            _ => Err(eyre!("rotate must be 0, 90, 180 or 270, got {degrees}")),
+        }
+    }
+}
+
+impl From<Rotation> for u16 {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::Deg0 => 0,
+            Rotation::Deg90 => 90,
+            Rotation::Deg180 => 180,
+            Rotation::Deg270 => 270,
+        }
+    }
+}
+
+/// Mirroring of the rendered image, for panels seen through a mirror film.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Flip {
+    #[default]
+    None,
+    /// Swaps left and right.
+    Horizontal,
+    /// Swaps top and bottom.
+    Vertical,
+    Both,
+}
+
+/// How the images of a MAC are put onto its panel, set with `PUT
+/// /macs/:mac/profile`. The stored SVG stays as designed; its rendered image
+/// is first rotated and then flipped along the axes of the panel, so that
+/// `horizontal` always swaps what is left and right on the mounted panel.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DisplayProfile {
+    #[serde(default)]
+    pub rotate: Rotation,
+    #[serde(default)]
+    pub flip: Flip,
+}
+
+impl DisplayProfile {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Size of the SVG canvas whose rotated image fits a panel of `width` x
+    /// `height`.
+    pub fn canvas_size(self, width: u32, height: u32) -> (u32, u32) {
+        match self.rotate {
+            Rotation::Deg0 | Rotation::Deg180 => (width, height),
+            Rotation::Deg90 | Rotation::Deg270 => (height, width),
+        }
+    }
+
+    /// Rotates and then flips the rendered `image`.
+    pub fn apply(self, image: RgbaImage) -> RgbaImage {
+        let rotated = match self.rotate {
+            Rotation::Deg0 => image,
+            Rotation::Deg90 => imageops::rotate90(&image),
+            Rotation::Deg180 => imageops::rotate180(&image),
+            Rotation::Deg270 => imageops::rotate270(&image),
+        };
+        match self.flip {
+            Flip::None => rotated,
+            Flip::Horizontal => imageops::flip_horizontal(&rotated),
+            Flip::Vertical => imageops::flip_vertical(&rotated),
+            Flip::Both => imageops::rotate180(&rotated),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+    /// 3x2 with only the top left pixel black.
+    fn corner() -> RgbaImage {
+        RgbaImage::from_fn(3, 2, |x, y| match (x, y) {
+            (0, 0) => BLACK,
+            _ => Rgba([255; 4]),
+        })
+    }
+
+    fn black_pixel(image: &RgbaImage) -> (u32, u32, u32, u32) {
+        let (x, y, _) = image
+            .enumerate_pixels()
+            .find(|(_, _, pixel)| **pixel == BLACK)
+            .unwrap();
+        (image.width(), image.height(), x, y)
+    }
+
+    #[test]
+    fn rotate_then_flip() {
+        let profile = |rotate, flip| DisplayProfile { rotate, flip };
+        let apply = |rotate, flip| black_pixel(&profile(rotate, flip).apply(corner()));
+        assert_eq!(apply(Rotation::Deg0, Flip::None), (3, 2, 0, 0));
+        // The top left corner ends up top right, then is mirrored back
+        assert_eq!(apply(Rotation::Deg90, Flip::None), (2, 3, 1, 0));
+        assert_eq!(apply(Rotation::Deg90, Flip::Horizontal), (2, 3, 0, 0));
+        assert_eq!(apply(Rotation::Deg90, Flip::Vertical), (2, 3, 1, 2));
+        assert_eq!(apply(Rotation::Deg270, Flip::Both), (2, 3, 1, 0));
+        assert_eq!(
+            profile(Rotation::Deg270, Flip::None).canvas_size(128, 296),
+            (296, 128)
+        );
+
+        assert!(serde_json::from_str::<DisplayProfile>(r#"{"rotate":45}"#).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::borrow::Cow;
+
+use eyre::{bail, eyre};
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const UTF16LE_BOM: &[u8] = b"\xFF\xFE";
+const UTF16BE_BOM: &[u8] = b"\xFE\xFF";
+const UTF32LE_BOM: &[u8] = b"\xFF\xFE\0\0";
+const UTF32BE_BOM: &[u8] = b"\0\0\xFE\xFF";
+/// Bytes looked at to guess the encoding of text without a byte order mark.
+const SNIFF_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Utf16 {
+    Le,
+    Be,
+}
+
+/// Whether `data` starts with the byte order mark of an encoding other than
+/// UTF-8, so that it is no UTF-8 to begin with.
+pub(crate) fn has_bom(data: &[u8]) -> bool {
+    [UTF16LE_BOM, UTF16BE_BOM, UTF32BE_BOM]
+        .iter()
+        .any(|bom| data.starts_with(bom))
+}
+
+/// The error for text starting with `head` that is neither UTF-8 nor
+/// UTF-16, naming its encoding if it can be told.
+pub(crate) fn unsupported(head: &[u8]) -> eyre::Report {
+    let encoding = if head.starts_with(UTF32LE_BOM) {
+        Some("UTF-32LE".to_owned())
+    } else if head.starts_with(UTF32BE_BOM) {
+        Some("UTF-32BE".to_owned())
+    } else {
+        declared_encoding(head)
+    };
+    match encoding {
+        Some(encoding) => eyre!("SVG is encoded as {encoding}, only UTF-8 and UTF-16 are accepted"),
+        None => eyre!("SVG is not valid UTF-8"),
+    }
+}
+
+/// The text of a posted SVG, converted from UTF-16 if it has its byte order
+/// mark or looks like it, and [normalized](normalize).
+pub(crate) fn decode(data: Vec<u8>) -> eyre::Result<String> {
+    if data.starts_with(UTF32LE_BOM) || data.starts_with(UTF32BE_BOM) {
+        return Err(unsupported(&data));
+    }
+    let text = match utf16(&data) {
+        Some(order) => decode_utf16(&data, order)?,
+        None => String::from_utf8(data).map_err(|e| unsupported(e.as_bytes()))?,
+    };
+    Ok(match normalize(&text) {
+        Cow::Borrowed(normalized) if normalized.len() == text.len() => text,
+        normalized => normalized.into_owned(),
+    })
+}
+
+/// Removes a leading byte order mark and turns CRLF and lone CR line endings
+/// into LF, as XML parsers would.
+pub(crate) fn normalize(text: &str) -> Cow<'_, str> {
+    let text = text.strip_prefix('\u{FEFF}').unwrap_or(text);
+    if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// The byte order of `data` if it is UTF-16: by its byte order mark, or
+/// because mostly every other byte of its start is zero, as in markup.
+fn utf16(data: &[u8]) -> Option<Utf16> {
+    if data.starts_with(UTF16LE_BOM) {
+        return Some(Utf16::Le);
+    }
+    if data.starts_with(UTF16BE_BOM) {
+        return Some(Utf16::Be);
+    }
+    let head = &data[..data.len().min(SNIFF_LEN) & !1];
+    if head.is_empty() {
+        return None;
+    }
+    let units = head.len() / 2;
+    let zeros = |offset: usize| {
+        head.iter()
+            .skip(offset)
+            .step_by(2)
+            .filter(|&&b| b == 0)
+            .count()
+    };
+    let mostly = |count: usize| count * 4 >= units * 3;
+    let rarely = |count: usize| count * 4 < units;
+    let (even, odd) = (zeros(0), zeros(1));
+    if mostly(odd) && rarely(even) {
+        Some(Utf16::Le)
+    } else if mostly(even) && rarely(odd) {
+        Some(Utf16::Be)
+    } else {
+        None
+    }
+}
+
+fn decode_utf16(data: &[u8], order: Utf16) -> eyre::Result<String> {
+    let name = match order {
+        Utf16::Le => "UTF-16LE",
+        Utf16::Be => "UTF-16BE",
+    };
+    if !data.len().is_multiple_of(2) {
+        bail!("SVG is not valid {name}, it has an odd number of bytes");
+    }
+    let units = data.chunks_exact(2).map(|pair| match order {
+        Utf16::Le => u16::from_le_bytes([pair[0], pair[1]]),
+        Utf16::Be => u16::from_be_bytes([pair[0], pair[1]]),
+    });
+    char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .map_err(|e| eyre!("SVG is not valid {name}: {e}"))
+}
+
+/// The encoding named by the XML declaration at the start of `head`, if
+/// there is one.
+fn declared_encoding(head: &[u8]) -> Option<String> {
+    let head = head.strip_prefix(UTF8_BOM).unwrap_or(head);
+    let head = &head[..head.len().min(SNIFF_LEN)];
+    let declaration = head.strip_prefix(b"<?xml")?;
+    let declaration = &declaration[..declaration.windows(2).position(|w| w == b"?>")?];
+    let declaration = String::from_utf8_lossy(declaration);
+    let value = declaration.split_once("encoding")?.1.trim_start();
+    let value = value.strip_prefix('=')?.trim_start();
+    let quote = value.chars().next().filter(|c| ['"', '\''].contains(c))?;
+    let (encoding, _) = value[1..].split_once(quote)?;
+    Some(encoding.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16le(text: &str, bom: bool) -> Vec<u8> {
+        let mut data = if bom { UTF16LE_BOM.to_vec() } else { vec![] };
+        data.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        data
+    }
+
+    #[test]
+    fn decode_utf16_and_utf8() {
+        let text = "<text>Grüße 𝄞</text>\n";
+        assert_eq!(decode(utf16le(text, true)).unwrap(), text);
+        assert_eq!(decode(utf16le(text, false)).unwrap(), text);
+        let be: Vec<u8> = UTF16BE_BOM
+            .iter()
+            .copied()
+            .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+            .collect();
+        assert_eq!(decode(be).unwrap(), text);
+        assert_eq!(decode(text.as_bytes().to_vec()).unwrap(), text);
+
+        let mut bom = UTF8_BOM.to_vec();
+        bom.extend_from_slice(b"<text>\r\na\rb</text>");
+        assert_eq!(decode(bom).unwrap(), "<text>\na\nb</text>");
+    }
+
+    #[test]
+    fn reject_other_encodings() {
+        let latin1 = b"<?xml version=\"1.0\" encoding='ISO-8859-1'?><text>\xE4</text>";
+        let e = decode(latin1.to_vec()).unwrap_err();
+        assert!(e.to_string().contains("ISO-8859-1"), "{e}");
+
+        let mut utf32 = UTF32LE_BOM.to_vec();
+        utf32.extend(b"<\0\0\0");
+        let e = decode(utf32).unwrap_err();
+        assert!(e.to_string().contains("UTF-32LE"), "{e}");
+
+        let e = decode(b"<text>\xFF</text>".to_vec()).unwrap_err();
+        assert_eq!(e.to_string(), "SVG is not valid UTF-8");
+
+        let mut unpaired = UTF16LE_BOM.to_vec();
+        unpaired.extend(b"\x00\xD8");
+        assert!(decode(unpaired).is_err());
+    }
+}
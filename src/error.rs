@@ -1,22 +1,280 @@
-use std::{error::Error, fmt::Display};
+use std::{borrow::Cow, error::Error, fmt::Display, io, sync::Arc};
 
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    accept, audit::REQUEST_ID_HEADER, lint::LintWarning, maintenance::Maintenance,
+    raster::DimensionMismatch,
+};
 
 #[derive(Debug)]
 pub(crate) enum AppError {
     InternalServerError(eyre::Error),
     NotFound(eyre::Error),
     BadRequest(eyre::Error),
+    UnsupportedMediaType(eyre::Error),
+    NotAcceptable(eyre::Error),
+    Unauthorized(eyre::Error),
+    Forbidden(eyre::Error),
+    ServiceUnavailable(eyre::Error),
+    PreconditionFailed(eyre::Error),
+    PayloadTooLarge(eyre::Error),
+    RequestTimeout(eyre::Error),
+    /// The PNG of a MAC is missing but its SVG exists, so it can be
+    /// regenerated.
+    PngMissingSvgPresent(eyre::Error),
+    /// There is no earlier image of a MAC to compare the current one with.
+    NoComparisonImage(eyre::Error),
+    /// The posted SVG failed to render too often in a row and isn't tried
+    /// again.
+    Quarantined(eyre::Error),
+    /// A MAC has no staged render to promote.
+    NothingStaged(eyre::Error),
+    /// The MAC segment of a URL isn't a MAC, as opposed to a valid MAC
+    /// without images, which is [`AppError::NotFound`].
+    InvalidMac(eyre::Error),
+    /// Several MACs end with the suffix of a `/macs/~<suffix>/` shorthand.
+    AmbiguousMac(Vec<String>),
+    DimensionMismatch(DimensionMismatch),
+    /// A render with `strict_lint` has content that won't survive it.
+    Lint(Vec<LintWarning>),
+    /// The error of an identical request that this one waited for, see
+    /// [`InFlight`](crate::coalesce::InFlight).
+    Coalesced(Arc<AppError>),
+    /// The server is in maintenance, see `POST /admin/maintenance`.
+    Maintenance(Maintenance),
+    /// `--render-script` failed or exceeded its budget.
+    #[cfg(feature = "script")]
+    Script(eyre::Error),
+    UnknownRoute(String),
+    MethodNotAllowed,
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
+/// JSON body of every error response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ErrorBody {
+    pub code: Cow<'static, str>,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+/// Full error chain of an error response, picked up by [`log_errors`].
+pub(crate) struct ErrorReport(pub String);
+
+/// Body of an error response, picked up by [`negotiate_errors`] to render it
+/// in the format the client prefers.
+#[derive(Clone)]
+struct ErrorPage(ErrorBody);
+
+/// Shown to browsers, filled in by [`html_page`].
+const ERROR_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{status} - eps-server</title>
+<style>
+body { font-family: sans-serif; max-width: 40em; margin: 4em auto; color: #222; }
+code { background: #eee; padding: 0 0.2em; }
+.id { color: #666; font-size: smaller; }
+</style>
+</head>
+<body>
+<h1>{status}</h1>
+<p>{message}</p>
+{path}<p class="id">Request id: <code>{request_id}</code></p>
+<p><a href="/macs">Back to the list of displays</a></p>
+</body>
+</html>
+"#;
+
+impl AppError {
+    /// Replaces the message shown to clients with `message`, keeping the
+    /// previous error as the source for the logs.
+    pub fn context(self, message: impl Display + Send + Sync + 'static) -> Self {
+        match self {
+            Self::InternalServerError(e) => Self::InternalServerError(e.wrap_err(message)),
+            Self::NotFound(e) => Self::NotFound(e.wrap_err(message)),
+            Self::BadRequest(e) => Self::BadRequest(e.wrap_err(message)),
+            Self::UnsupportedMediaType(e) => Self::UnsupportedMediaType(e.wrap_err(message)),
+            Self::NotAcceptable(e) => Self::NotAcceptable(e.wrap_err(message)),
+            Self::Unauthorized(e) => Self::Unauthorized(e.wrap_err(message)),
+            Self::Forbidden(e) => Self::Forbidden(e.wrap_err(message)),
+            Self::ServiceUnavailable(e) => Self::ServiceUnavailable(e.wrap_err(message)),
+            Self::PreconditionFailed(e) => Self::PreconditionFailed(e.wrap_err(message)),
+            Self::PayloadTooLarge(e) => Self::PayloadTooLarge(e.wrap_err(message)),
+            Self::RequestTimeout(e) => Self::RequestTimeout(e.wrap_err(message)),
+            Self::PngMissingSvgPresent(e) => Self::PngMissingSvgPresent(e.wrap_err(message)),
+            Self::NoComparisonImage(e) => Self::NoComparisonImage(e.wrap_err(message)),
+            Self::Quarantined(e) => Self::Quarantined(e.wrap_err(message)),
+            Self::NothingStaged(e) => Self::NothingStaged(e.wrap_err(message)),
+            Self::InvalidMac(e) => Self::InvalidMac(e.wrap_err(message)),
+            #[cfg(feature = "script")]
+            Self::Script(e) => Self::Script(e.wrap_err(message)),
+            e @ (Self::AmbiguousMac(_)
+            | Self::DimensionMismatch(_)
+            | Self::Lint(_)
+            | Self::Coalesced(_)
+            | Self::Maintenance(_)
+            | Self::UnknownRoute(_)
+            | Self::MethodNotAllowed) => e,
+        }
+    }
+
+    fn report(&self) -> Option<&eyre::Error> {
+        match self {
+            Self::InternalServerError(e)
+            | Self::NotFound(e)
+            | Self::BadRequest(e)
+            | Self::UnsupportedMediaType(e)
+            | Self::NotAcceptable(e)
+            | Self::Unauthorized(e)
+            | Self::Forbidden(e)
+            | Self::ServiceUnavailable(e)
+            | Self::PreconditionFailed(e)
+            | Self::PayloadTooLarge(e)
+            | Self::RequestTimeout(e)
+            | Self::PngMissingSvgPresent(e)
+            | Self::NoComparisonImage(e)
+            | Self::Quarantined(e)
+            | Self::NothingStaged(e)
+            | Self::InvalidMac(e) => Some(e),
+            #[cfg(feature = "script")]
+            Self::Script(e) => Some(e),
+            Self::Coalesced(e) => e.report(),
+            Self::AmbiguousMac(_)
+            | Self::DimensionMismatch(_)
+            | Self::Lint(_)
+            | Self::Maintenance(_)
+            | Self::UnknownRoute(_)
+            | Self::MethodNotAllowed => None,
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Self::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotFound(_) | Self::UnknownRoute(_) => StatusCode::NOT_FOUND,
+            Self::BadRequest(_)
+            | Self::InvalidMac(_)
+            | Self::DimensionMismatch(_)
+            | Self::Lint(_) => StatusCode::BAD_REQUEST,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::ServiceUnavailable(_) | Self::Maintenance(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RequestTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            Self::PngMissingSvgPresent(_)
+            | Self::NoComparisonImage(_)
+            | Self::NothingStaged(_)
+            | Self::AmbiguousMac(_) => StatusCode::CONFLICT,
+            Self::Quarantined(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            #[cfg(feature = "script")]
+            Self::Script(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::Coalesced(e) => e.status(),
+        }
+    }
+
+    fn code(&self) -> &'static str {
         match self {
-            Self::InternalServerError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
-            Self::NotFound(e) => (StatusCode::NOT_FOUND, e.to_string()),
-            Self::BadRequest(e) => (StatusCode::BAD_REQUEST, e.to_string()),
+            Self::InternalServerError(_) => "internal_server_error",
+            Self::NotFound(_) => "not_found",
+            Self::BadRequest(_) => "bad_request",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::NotAcceptable(_) => "not_acceptable",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::ServiceUnavailable(_) => "service_unavailable",
+            Self::PreconditionFailed(_) => "precondition_failed",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::RequestTimeout(_) => "request_timeout",
+            Self::PngMissingSvgPresent(_) => "png_missing_svg_present",
+            Self::NoComparisonImage(_) => "no_comparison_image",
+            Self::Quarantined(_) => "quarantined",
+            Self::NothingStaged(_) => "nothing_staged",
+            Self::InvalidMac(_) => "invalid_mac",
+            Self::AmbiguousMac(_) => "ambiguous_mac",
+            Self::DimensionMismatch(_) => "dimension_mismatch",
+            Self::Lint(_) => "lint_failed",
+            #[cfg(feature = "script")]
+            Self::Script(_) => "script_failed",
+            Self::Maintenance(_) => "maintenance",
+            Self::UnknownRoute(_) => "unknown_route",
+            Self::MethodNotAllowed => "method_not_allowed",
+            Self::Coalesced(e) => e.code(),
+        }
+    }
+}
+
+impl AppError {
+    /// The body of the error response, for errors reported in other bodies.
+    pub fn body(&self) -> ErrorBody {
+        if let Self::Coalesced(e) = self {
+            return e.body();
+        }
+        let path = match self {
+            Self::UnknownRoute(path) => Some(path.clone()),
+            _ => None,
+        };
+        let details = match self {
+            Self::DimensionMismatch(mismatch) => serde_json::to_value(mismatch).ok(),
+            Self::AmbiguousMac(macs) => Some(serde_json::json!({ "candidates": macs })),
+            Self::Lint(warnings) => Some(serde_json::json!({ "lint": warnings })),
+            Self::Maintenance(maintenance) => Some(serde_json::json!({"until": maintenance.until})),
+            _ => None,
+        };
+        // Internal errors may mention paths or other details of the deployment
+        let message = match self {
+            Self::InternalServerError(_) => "internal server error".to_owned(),
+            _ => self.to_string(),
+        };
+        ErrorBody {
+            code: self.code().into(),
+            message,
+            path,
+            details,
         }
-        .into_response()
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let body = self.body();
+        let mut response = (self.status(), Json(body.clone())).into_response();
+        response.extensions_mut().insert(ErrorPage(body));
+        if let Self::Unauthorized(_) = self {
+            // Makes browsers prompt for Basic credentials
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"eps-server\""),
+            );
+        }
+        if let Self::Maintenance(maintenance) = &self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                maintenance.retry_after(Utc::now()).into(),
+            );
+        }
+        if let Some(report) = self.report() {
+            response
+                .extensions_mut()
+                .insert(ErrorReport(format!("{report:?}")));
+        }
+        response
     }
 }
 
@@ -26,9 +284,175 @@ impl Display for AppError {
             AppError::InternalServerError(e) => e,
             AppError::NotFound(e) => e,
             AppError::BadRequest(e) => e,
+            AppError::UnsupportedMediaType(e) => e,
+            AppError::NotAcceptable(e) => e,
+            AppError::Unauthorized(e) => e,
+            AppError::Forbidden(e) => e,
+            AppError::ServiceUnavailable(e) => e,
+            AppError::PreconditionFailed(e) => e,
+            AppError::PayloadTooLarge(e) => e,
+            AppError::RequestTimeout(e) => e,
+            AppError::PngMissingSvgPresent(e) => e,
+            AppError::NoComparisonImage(e) => e,
+            AppError::Quarantined(e) => e,
+            AppError::NothingStaged(e) => e,
+            AppError::InvalidMac(e) => e,
+            #[cfg(feature = "script")]
+            AppError::Script(e) => e,
+            AppError::AmbiguousMac(macs) => {
+                return write!(f, "Several MACs match: {}.", macs.join(", "))
+            }
+            AppError::DimensionMismatch(mismatch) => return write!(f, "{mismatch}"),
+            AppError::Lint(warnings) => {
+                let warnings: Vec<_> = warnings.iter().map(ToString::to_string).collect();
+                return write!(
+                    f,
+                    "The SVG won't survive rendering: {}.",
+                    warnings.join(", ")
+                );
+            }
+            AppError::Maintenance(Maintenance { until, message }) if message.is_empty() => {
+                return write!(f, "The server is in maintenance until {until}.")
+            }
+            AppError::Maintenance(Maintenance { message, .. }) => return write!(f, "{message}"),
+            AppError::UnknownRoute(_) => return write!(f, "unknown route"),
+            AppError::MethodNotAllowed => return write!(f, "method not allowed"),
+            AppError::Coalesced(e) => return write!(f, "{e}"),
         };
         write!(f, "{error}")
     }
 }
 
 impl Error for AppError {}
+
+/// Logs the full error chain of error responses together with the request
+/// that caused them; clients only get the outermost message.
+pub(crate) async fn log_errors<B>(request: Request<B>, next: Next<B>) -> Response {
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let response = next.run(request).await;
+    if let Some(ErrorReport(report)) = response.extensions().get() {
+        let status = response.status();
+        if status.is_server_error() {
+            tracing::error!("{method} {uri} failed with {status}: {report}");
+        } else {
+            tracing::warn!("{method} {uri} failed with {status}: {report}");
+        }
+    }
+    response
+}
+
+/// Renders error responses as an HTML page for clients that prefer HTML, like
+/// browsers, and as plain text for clients that accept neither HTML nor JSON.
+pub(crate) async fn negotiate_errors<B>(request: Request<B>, next: Next<B>) -> Response {
+    let header_value = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let accept = header_value(header::ACCEPT.as_str());
+    let request_id = header_value(REQUEST_ID_HEADER).unwrap_or_default();
+    let response = next.run(request).await;
+    let Some(ErrorPage(error)) = response.extensions().get().cloned() else {
+        return response;
+    };
+
+    let supported = [mime::APPLICATION_JSON, mime::TEXT_HTML, mime::TEXT_PLAIN];
+    let status = response.status();
+    let (content_type, text) = match accept::negotiate(accept.as_deref(), &supported) {
+        Some(mime) if *mime == mime::APPLICATION_JSON => return response,
+        Some(mime) if *mime == mime::TEXT_HTML => (
+            mime::TEXT_HTML_UTF_8,
+            html_page(status, &error, &request_id),
+        ),
+        _ => (
+            mime::TEXT_PLAIN_UTF_8,
+            plain_text(status, &error, &request_id),
+        ),
+    };
+    // Keeps the other headers, like Retry-After
+    let mut page = ([(header::CONTENT_TYPE, content_type.as_ref())], text).into_response();
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.extend(page.headers_mut().drain());
+    Response::from_parts(parts, page.into_body())
+}
+
+fn status_line(status: StatusCode) -> String {
+    format!(
+        "{} {}",
+        status.as_str(),
+        status.canonical_reason().unwrap_or_default()
+    )
+}
+
+fn html_page(status: StatusCode, error: &ErrorBody, request_id: &str) -> String {
+    let path = match &error.path {
+        Some(path) => format!("<p>Path: <code>{}</code></p>\n", escape_html(path)),
+        None => String::new(),
+    };
+    ERROR_PAGE
+        .replace("{status}", &status_line(status))
+        .replace("{message}", &escape_html(&error.message))
+        .replace("{path}", &path)
+        .replace("{request_id}", &escape_html(request_id))
+}
+
+fn plain_text(status: StatusCode, error: &ErrorBody, request_id: &str) -> String {
+    let mut text = format!("{}\n\n{}\n", status_line(status), error.message);
+    if let Some(path) = &error.path {
+        text += &format!("Path: {path}\n");
+    }
+    text += &format!("Request id: {request_id}\n");
+    text
+}
+
+/// Escapes text for the content or attribute values of an HTML page.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Shorthands for converting any error into an [`AppError`] of a given kind.
+pub(crate) trait ResultExt<T> {
+    fn internal(self) -> Result<T, AppError>;
+    fn bad_request(self) -> Result<T, AppError>;
+}
+
+impl<T, E: Into<eyre::Error>> ResultExt<T> for Result<T, E> {
+    fn internal(self) -> Result<T, AppError> {
+        self.map_err(|e| AppError::InternalServerError(e.into()))
+    }
+
+    fn bad_request(self) -> Result<T, AppError> {
+        self.map_err(|e| AppError::BadRequest(e.into()))
+    }
+}
+
+/// Maps filesystem errors by kind, so a missing file is a 404 and a
+/// temporarily failing disk a 503.
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Self::NotFound(e.into()),
+            io::ErrorKind::PermissionDenied => Self::Forbidden(e.into()),
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::OutOfMemory => Self::ServiceUnavailable(e.into()),
+            _ => Self::InternalServerError(e.into()),
+        }
+    }
+}
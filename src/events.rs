@@ -0,0 +1,192 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::image_handler::EpdMac;
+
+/// Number of live events buffered per subscriber before it lags behind.
+const SUBSCRIBER_BUFFER: usize = 256;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EventKind {
+    /// An SVG was rendered into a new image.
+    Render,
+    /// A raster image was uploaded.
+    Upload,
+    Delete,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Render => "render",
+            Self::Upload => "upload",
+            Self::Delete => "delete",
+        }
+    }
+}
+
+/// A change of the images of a MAC.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Event {
+    /// Increases by one with every event since startup, starting at 1.
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub kind: EventKind,
+    pub mac: String,
+}
+
+/// Events after a cursor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct History {
+    pub events: Vec<Event>,
+    /// Events after the cursor were dropped from the buffer; clients should
+    /// reload all MACs.
+    pub resync: bool,
+}
+
+/// The most recent events in a ring buffer, and a channel for live ones.
+pub(crate) struct EventLog {
+    inner: Mutex<Inner>,
+    sender: broadcast::Sender<Event>,
+}
+
+struct Inner {
+    next_seq: u64,
+    capacity: usize,
+    buffer: VecDeque<Event>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        EventLog {
+            inner: Mutex::new(Inner {
+                next_seq: 1,
+                capacity,
+                buffer: VecDeque::with_capacity(capacity),
+            }),
+            sender,
+        }
+    }
+
+    pub fn publish(&self, kind: EventKind, mac: EpdMac, timestamp: DateTime<Utc>) {
+        let mut inner = self.inner.lock().unwrap();
+        let event = Event {
+            seq: inner.next_seq,
+            timestamp,
+            kind,
+            mac: mac.to_string(),
+        };
+        inner.next_seq += 1;
+        if inner.capacity > 0 {
+            if inner.buffer.len() == inner.capacity {
+                inner.buffer.pop_front();
+            }
+            inner.buffer.push_back(event.clone());
+        }
+        // Sent under the lock so subscribers see events in order; there may
+        // be no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    /// Buffered events with a sequence number above `since`, optionally only
+    /// those of `mac`.
+    pub fn history(&self, since: u64, mac: Option<EpdMac>) -> History {
+        let inner = self.inner.lock().unwrap();
+        inner.history(since, mac)
+    }
+
+    /// Like [`EventLog::history`], together with a receiver of all later
+    /// events so that none are missed in between.
+    pub fn subscribe(
+        &self,
+        since: Option<u64>,
+        mac: Option<EpdMac>,
+    ) -> (History, broadcast::Receiver<Event>) {
+        let inner = self.inner.lock().unwrap();
+        let history = match since {
+            Some(since) => inner.history(since, mac),
+            None => History {
+                events: Vec::new(),
+                resync: false,
+            },
+        };
+        (history, self.sender.subscribe())
+    }
+}
+
+impl Inner {
+    fn history(&self, since: u64, mac: Option<EpdMac>) -> History {
+        let mac = mac.map(|mac| mac.to_string());
+        let first_buffered = self.buffer.front().map_or(self.next_seq, |event| event.seq);
+        History {
+            events: self
+                .buffer
+                .iter()
+                .filter(|event| event.seq > since)
+                .filter(|event| !matches!(&mac, Some(mac) if *mac != event.mac))
+                .cloned()
+                .collect(),
+            resync: since.saturating_add(1) < first_buffered,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(s: &str) -> EpdMac {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let log = EventLog::new(3);
+        let now = Utc::now();
+        for _ in 0..2 {
+            log.publish(EventKind::Render, mac("0011223344556677"), now);
+        }
+        log.publish(EventKind::Delete, mac("aabbccddeeffaabb"), now);
+
+        let history = log.history(0, None);
+        assert_eq!(history.events.len(), 3);
+        assert!(!history.resync);
+
+        let history = log.history(1, Some(mac("0011223344556677")));
+        let seqs: Vec<_> = history.events.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, [2]);
+
+        log.publish(EventKind::Upload, mac("aabbccddeeffaabb"), now);
+        let history = log.history(0, None);
+        assert_eq!(history.events.first().map(|event| event.seq), Some(2));
+        assert!(history.resync);
+        // Nothing after 1 was dropped
+        assert!(!log.history(1, None).resync);
+        assert!(log.history(4, None).events.is_empty());
+    }
+
+    #[test]
+    fn empty_buffer() {
+        let log = EventLog::new(0);
+        log.publish(EventKind::Render, mac("0011223344556677"), Utc::now());
+        assert!(log.history(0, None).resync);
+        assert!(!log.history(1, None).resync);
+    }
+
+    #[tokio::test]
+    async fn subscribe_without_gaps() {
+        let log = EventLog::new(10);
+        let now = Utc::now();
+        log.publish(EventKind::Render, mac("0011223344556677"), now);
+        let (history, mut receiver) = log.subscribe(Some(0), None);
+        log.publish(EventKind::Delete, mac("0011223344556677"), now);
+
+        assert_eq!(history.events.len(), 1);
+        assert_eq!(receiver.recv().await.unwrap().seq, 2);
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashSet, path::Path};
+
+use sha2::{Digest, Sha256};
+use usvg::fontdb::{Database, Source};
+
+/// The only font of `--deterministic-render`, see `fonts/LICENSE`.
+const REFERENCE_FONT: &[u8] = include_bytes!("../fonts/DejaVuSansMono.ttf");
+const REFERENCE_FAMILY: &str = "DejaVu Sans Mono";
+
+/// The installed fonts with the faces of the `fallbacks` families first, in
+/// their order, as usvg falls back to the first face that has a character
+/// the font of a text lacks.
+pub(crate) fn database(fallbacks: &[String]) -> Database {
+    let mut installed = Database::new();
+    installed.load_system_fonts();
+
+    let fallback_faces = fallbacks.iter().flat_map(|family| {
+        let family = family.trim();
+        let faces = installed.faces().iter();
+        faces.filter(move |face| face.family.eq_ignore_ascii_case(family))
+    });
+    let mut ordered = Database::new();
+    let mut loaded = HashSet::new();
+    for face in fallback_faces.chain(installed.faces()) {
+        // Collections are loaded with all their faces at once
+        if let Some(path) = source_path(&face.source) {
+            if !loaded.insert(path) {
+                continue;
+            }
+        }
+        ordered.load_font_source(face.source.clone());
+    }
+    for family in fallbacks {
+        if !ordered
+            .faces()
+            .iter()
+            .any(|face| face.family.eq_ignore_ascii_case(family.trim()))
+        {
+            tracing::warn!("The fallback font family {family} is not installed");
+        }
+    }
+    ordered
+}
+
+/// The options of `--deterministic-render`: the embedded reference font
+/// instead of the installed ones, and every setting that affects how SVGs
+/// are laid out pinned rather than left to the defaults of usvg.
+pub(crate) fn deterministic_options() -> usvg::Options {
+    let mut fontdb = Database::new();
+    fontdb.load_font_data(REFERENCE_FONT.to_vec());
+    usvg::Options {
+        resources_dir: None,
+        dpi: 96.0,
+        font_family: REFERENCE_FAMILY.to_owned(),
+        font_size: 12.0,
+        languages: vec!["en".to_owned()],
+        shape_rendering: usvg::ShapeRendering::GeometricPrecision,
+        text_rendering: usvg::TextRendering::OptimizeLegibility,
+        image_rendering: usvg::ImageRendering::OptimizeQuality,
+        fontdb,
+        ..usvg::Options::default()
+    }
+}
+
+/// Identifies the render pipeline of `options`, like `resvg-0.23.0-1a2b…`:
+/// the version of resvg and a hash of the options and of the reference
+/// font. Renders with the same fingerprint come out the same.
+pub(crate) fn pipeline_fingerprint(options: &usvg::Options) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!(
+        "{} {} {} {:?} {:?} {:?} {:?} {}",
+        options.dpi,
+        options.font_family,
+        options.font_size,
+        options.languages,
+        options.shape_rendering,
+        options.text_rendering,
+        options.image_rendering,
+        options.keep_named_groups,
+    ));
+    hasher.update(REFERENCE_FONT);
+    let hash = hasher.finalize();
+    format!(
+        "resvg-{}-{}",
+        env!("EPS_RESVG_VERSION"),
+        hex::encode(&hash[..8])
+    )
+}
+
+fn source_path(source: &Source) -> Option<&Path> {
+    match source {
+        Source::File(path) | Source::SharedFile(path, _) => Some(path),
+        Source::Binary(_) => None,
+    }
+}
+
+/// Whether any face of `fontdb` has a glyph for `c`.
+pub(crate) fn covers(fontdb: &Database, c: char) -> bool {
+    fontdb.faces().iter().any(|face| {
+        fontdb
+            .with_face_data(face.id, |data, index| {
+                ttf_parser::Face::from_slice(data, index)
+                    .ok()?
+                    .glyph_index(c)
+            })
+            .flatten()
+            .is_some()
+    })
+}
+
+/// Characters shaping consumes without drawing a glyph, like the marks
+/// that control the direction of text.
+pub(crate) fn is_invisible(c: char) -> bool {
+    c.is_whitespace()
+        || c.is_control()
+        || matches!(
+            c,
+            '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' | '\u{feff}'
+        )
+}
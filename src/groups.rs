@@ -0,0 +1,104 @@
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+use eyre::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ErrorBody, image_handler::EpdMac, render_options::RenderOverrides};
+
+const MAX_GROUP_NAME_LEN: usize = 64;
+
+/// Label that several MACs share, like `aisle-3`: ASCII letters, digits, `-`
+/// and `_`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct GroupName(String);
+
+impl FromStr for GroupName {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > MAX_GROUP_NAME_LEN {
+            bail!("Group name must have 1 to {MAX_GROUP_NAME_LEN} characters, got '{s}'");
+        }
+        if !s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Group name may only contain ASCII letters, digits, '-' and '_', got '{s}'");
+        }
+        Ok(GroupName(s.to_owned()))
+    }
+}
+
+impl Display for GroupName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for GroupName {
+    type Error = eyre::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<GroupName> for String {
+    fn from(name: GroupName) -> Self {
+        name.0
+    }
+}
+
+/// Body of a render of all members of a group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct GroupRender {
+    /// SVG fragment, rendered like the body of `render_svg`.
+    pub svg: String,
+    /// Fragments rendered instead of `svg` for single members.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub overrides: BTreeMap<EpdMac, String>,
+    /// Render options of all members, like the query of `render_svg`.
+    #[serde(flatten)]
+    pub options: RenderOverrides,
+}
+
+/// Outcome of a group render for one member.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct GroupRenderResult {
+    pub mac: String,
+    /// Why the render failed, as it would have been returned by `render_svg`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_name_parse() {
+        for s in ["aisle-3", "cold_room", "Office"] {
+            assert_eq!(s.parse::<GroupName>().unwrap().to_string(), s);
+        }
+        let long = "a".repeat(MAX_GROUP_NAME_LEN + 1);
+        for s in ["", "aisle 3", "../etc", "kühl", long.as_str()] {
+            assert!(s.parse::<GroupName>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn group_render_overrides() {
+        let render: GroupRender = serde_json::from_str(
+            r#"{"svg": "<rect/>", "overrides": {"AABBCCDDEEFFAABB": "<circle/>"}}"#,
+        )
+        .unwrap();
+        let mac: EpdMac = "aabbccddeeffaabb".parse().unwrap();
+        assert_eq!(render.overrides[&mac], "<circle/>");
+
+        assert!(serde_json::from_str::<GroupRender>(
+            r#"{"svg": "<rect/>", "overrides": {"nope": "<circle/>"}}"#
+        )
+        .is_err());
+    }
+}
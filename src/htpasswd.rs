@@ -0,0 +1,163 @@
+use std::{collections::HashMap, path::Path};
+
+use eyre::{bail, Context};
+use md5::{Digest, Md5};
+
+const APR1_PREFIX: &str = "$apr1$";
+const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Users and password hashes from an Apache htpasswd file. Only bcrypt
+/// (`$2y$`, created by `htpasswd -B`) and apr1 (`$apr1$`, `htpasswd -m`)
+/// hashes are supported.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Htpasswd {
+    users: HashMap<String, String>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+        Self::parse(&text).wrap_err_with(|| format!("Invalid htpasswd file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> eyre::Result<Self> {
+        let mut users = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                bail!("Line {} is not <user>:<hash>", i + 1);
+            };
+            if !(hash.starts_with("$2") || hash.starts_with(APR1_PREFIX)) {
+                bail!("Line {}: only bcrypt and apr1 hashes are supported", i + 1);
+            }
+            users.insert(user.to_owned(), hash.to_owned());
+        }
+        Ok(Htpasswd { users })
+    }
+
+    /// Whether `password` is the password of `user`. Slow by design for
+    /// bcrypt hashes.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let Some(hash) = self.users.get(user) else {
+            return false;
+        };
+        match hash.strip_prefix(APR1_PREFIX) {
+            Some(rest) => {
+                let salt = rest.split('$').next().unwrap_or_default();
+                crate::auth::constant_time_eq(apr1(password, salt).as_bytes(), hash.as_bytes())
+            }
+            None => bcrypt::verify(password, hash).unwrap_or(false),
+        }
+    }
+}
+
+/// The Apache variant of the MD5 crypt hash of `password`.
+fn apr1(password: &str, salt: &str) -> String {
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+    let mut hasher = Md5::new()
+        .chain_update(password)
+        .chain_update(APR1_PREFIX)
+        .chain_update(salt);
+    for chunk in password.chunks(16) {
+        hasher.update(&alternate[..chunk.len()]);
+    }
+    let mut i = password.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            hasher.update([0]);
+        } else {
+            hasher.update(&password[..1]);
+        }
+        i >>= 1;
+    }
+    let mut digest = hasher.finalize();
+
+    for round in 0..1000 {
+        let mut hasher = Md5::new();
+        if round & 1 == 1 {
+            hasher.update(password);
+        } else {
+            hasher.update(digest);
+        }
+        if round % 3 != 0 {
+            hasher.update(salt);
+        }
+        if round % 7 != 0 {
+            hasher.update(password);
+        }
+        if round & 1 == 1 {
+            hasher.update(digest);
+        } else {
+            hasher.update(password);
+        }
+        digest = hasher.finalize();
+    }
+
+    let mut out = format!("{APR1_PREFIX}{}$", String::from_utf8_lossy(salt));
+    let mut push = |mut value: u32, chars: usize| {
+        for _ in 0..chars {
+            out.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32,
+            4,
+        );
+    }
+    push(digest[11] as u32, 2);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apr1_matches_openssl() {
+        // openssl passwd -apr1 -salt <salt> <password>
+        assert_eq!(
+            apr1("myPassword", "r31....."),
+            "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/"
+        );
+        assert_eq!(apr1("secret", "xyz"), "$apr1$xyz$HXgo9gtz4gpj4JWTLYmjB0");
+        assert_eq!(
+            apr1("pässwörd-with-more-than-16-chars", "abcdefgh"),
+            "$apr1$abcdefgh$msTMU/mj6aOe./7YYLrHa/"
+        );
+    }
+
+    #[test]
+    fn verify_users() {
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        let htpasswd = Htpasswd::parse(&format!(
+            "# comment\nalice:$apr1$xyz$HXgo9gtz4gpj4JWTLYmjB0\n\nbob:{bcrypt}\n"
+        ))
+        .unwrap();
+
+        assert!(htpasswd.verify("alice", "secret"));
+        assert!(!htpasswd.verify("alice", "Secret"));
+        assert!(htpasswd.verify("bob", "hunter2"));
+        assert!(!htpasswd.verify("bob", "hunter3"));
+        assert!(!htpasswd.verify("carol", "secret"));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(Htpasswd::parse("alice").is_err());
+        // Plain text and crypt(3) hashes
+        assert!(Htpasswd::parse("alice:secret").is_err());
+    }
+}
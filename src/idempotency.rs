@@ -0,0 +1,371 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use eyre::eyre;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Request, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::{mpsc, watch},
+};
+
+use crate::{config::Config, error::AppError, resource::is_write, AppState};
+
+/// File in the image directory that keeps the stored responses across
+/// restarts.
+pub(crate) const IDEMPOTENCY_FILE: &str = "idempotency.json";
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+const REPLAY: HeaderName = HeaderName::from_static("x-eps-idempotent-replay");
+const MAX_KEY_LEN: usize = 255;
+/// Time for which stored responses are collected before the file is
+/// written, so that a burst of requests writes it once.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// What a key is remembered for: the method and path of the request, so
+/// that the same key for different routes or MACs doesn't collide.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Scope {
+    method: String,
+    path: String,
+    key: String,
+}
+
+/// The response to the first request with a key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredResponse {
+    stored_at: DateTime<Utc>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    #[serde(with = "base64_body")]
+    body: Bytes,
+}
+
+impl StoredResponse {
+    fn response(&self, replay: bool) -> Response {
+        let mut response = (
+            StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            self.body.clone(),
+        )
+            .into_response();
+        let headers = response.headers_mut();
+        headers.clear();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        if replay {
+            headers.insert(REPLAY, HeaderValue::from_static("true"));
+        }
+        response
+    }
+}
+
+mod base64_body {
+    use axum::body::Bytes;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64
+            .decode(encoded)
+            .map(Bytes::from)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug)]
+enum Entry {
+    /// The first request is still running; its response is sent once done.
+    Running(watch::Receiver<Option<Arc<StoredResponse>>>),
+    Done {
+        response: Arc<StoredResponse>,
+        last_used: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Persisted {
+    scope: Scope,
+    last_used: DateTime<Utc>,
+    response: StoredResponse,
+}
+
+/// What a request with a key does.
+enum Claim {
+    Replay(Arc<StoredResponse>),
+    Wait(watch::Receiver<Option<Arc<StoredResponse>>>),
+    Run(watch::Sender<Option<Arc<StoredResponse>>>),
+}
+
+/// Responses to mutating requests with an `Idempotency-Key` header, replayed
+/// to retries with the same key instead of running them again.
+#[derive(Debug)]
+pub(crate) struct IdempotencyKeys {
+    path: PathBuf,
+    ttl: chrono::Duration,
+    max_keys: usize,
+    entries: Mutex<HashMap<Scope, Entry>>,
+    /// Wakes the task writing the file after a response was stored.
+    changed: mpsc::Sender<()>,
+    /// Serializes writes of the file.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl IdempotencyKeys {
+    /// Resumes the keys persisted at `path` before a restart and starts the
+    /// task writing the file in the background. A file that can't be read is
+    /// logged and ignored.
+    pub fn spawn(path: PathBuf, config: &Config) -> Arc<Self> {
+        let persisted = match read(&path) {
+            Ok(persisted) => persisted,
+            Err(e) => {
+                tracing::error!("Ignoring idempotency keys {}: {e}", path.display());
+                vec![]
+            }
+        };
+        let entries = persisted
+            .into_iter()
+            .map(|entry| {
+                let done = Entry::Done {
+                    response: Arc::new(entry.response),
+                    last_used: entry.last_used,
+                };
+                (entry.scope, done)
+            })
+            .collect();
+        let (changed, receiver) = mpsc::channel(1);
+        let keys = Arc::new(IdempotencyKeys {
+            path,
+            ttl: chrono::Duration::from_std(config.idempotency_ttl.get())
+                .unwrap_or(chrono::Duration::MAX),
+            max_keys: config.idempotency_max_keys,
+            entries: Mutex::new(entries),
+            changed,
+            saving: tokio::sync::Mutex::new(()),
+        });
+        tokio::spawn(save_changes(Arc::downgrade(&keys), receiver));
+        keys
+    }
+
+    fn claim(&self, scope: &Scope, now: DateTime<Utc>) -> Claim {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(scope) {
+            Some(Entry::Done {
+                response,
+                last_used,
+            }) if now - response.stored_at < self.ttl => {
+                *last_used = now;
+                return Claim::Replay(response.clone());
+            }
+            Some(Entry::Running(receiver)) => return Claim::Wait(receiver.clone()),
+            _ => {}
+        }
+        entries.retain(|_, entry| match entry {
+            Entry::Done { response, .. } => now - response.stored_at < self.ttl,
+            Entry::Running(_) => true,
+        });
+        while entries.len() >= self.max_keys.max(1) {
+            let oldest = entries
+                .iter()
+                .filter_map(|(scope, entry)| match entry {
+                    Entry::Done { last_used, .. } => Some((*last_used, scope)),
+                    Entry::Running(_) => None,
+                })
+                .min_by_key(|(last_used, _)| *last_used)
+                .map(|(_, scope)| scope.clone());
+            let Some(oldest) = oldest else { break };
+            entries.remove(&oldest);
+        }
+        let (sender, receiver) = watch::channel(None);
+        entries.insert(scope.clone(), Entry::Running(receiver));
+        Claim::Run(sender)
+    }
+
+    /// Stores `response` as the outcome of `scope`, or forgets the key if
+    /// there is none to replay, so that the next request runs again.
+    fn finish(&self, scope: &Scope, response: Option<Arc<StoredResponse>>) {
+        let mut entries = self.entries.lock().unwrap();
+        match response {
+            Some(response) => {
+                let last_used = response.stored_at;
+                entries.insert(
+                    scope.clone(),
+                    Entry::Done {
+                        response,
+                        last_used,
+                    },
+                );
+                // Already due to be saved if full
+                let _ = self.changed.try_send(());
+            }
+            None => {
+                entries.remove(scope);
+            }
+        }
+    }
+
+    /// Writes the stored responses to the file, logging failures.
+    pub async fn save(&self) {
+        let _saving = self.saving.lock().await;
+        let persisted: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(scope, entry)| match entry {
+                Entry::Done {
+                    response,
+                    last_used,
+                } => Some(Persisted {
+                    scope: scope.clone(),
+                    last_used: *last_used,
+                    response: (**response).clone(),
+                }),
+                Entry::Running(_) => None,
+            })
+            .collect();
+        if let Err(e) = write(&self.path, &persisted).await {
+            tracing::warn!("Could not save idempotency keys: {e}");
+        }
+    }
+}
+
+/// Forgets the key of a request that ended without a response to store,
+/// e.g. because its client went away, and wakes up the waiting duplicates.
+struct RunGuard<'a> {
+    keys: &'a IdempotencyKeys,
+    scope: &'a Scope,
+    finished: bool,
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.keys.finish(self.scope, None);
+        }
+    }
+}
+
+/// Replays the stored response to a mutating request whose
+/// `Idempotency-Key` was seen for the same method and path before, marked
+/// with `X-EPS-Idempotent-Replay: true`. Duplicates of a request that is
+/// still running wait for its response. Server errors aren't stored, so
+/// that retries run again. Stored responses are written to the file after
+/// the response was sent.
+pub(crate) async fn replay(
+    state: Arc<AppState>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    if !is_write(request.method()) {
+        return next.run(request).await;
+    }
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_owned(),
+        _ => {
+            return AppError::BadRequest(eyre!(
+                "The Idempotency-Key must be 1 to {MAX_KEY_LEN} visible ASCII characters."
+            ))
+            .into_response()
+        }
+    };
+    let scope = Scope {
+        method: request.method().to_string(),
+        path: request.uri().path().to_owned(),
+        key,
+    };
+    let keys = &state.idempotency_keys;
+
+    let sender = loop {
+        match keys.claim(&scope, Utc::now()) {
+            Claim::Replay(response) => return response.response(true),
+            // Claimed again after the first request ended either way
+            Claim::Wait(mut receiver) => {
+                let _ = receiver.changed().await;
+            }
+            Claim::Run(sender) => break sender,
+        }
+    };
+    let mut guard = RunGuard {
+        keys,
+        scope: &scope,
+        finished: false,
+    };
+    let response = next.run(request).await;
+    if response.status().is_server_error() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return AppError::InternalServerError(eyre!(e)).into_response(),
+    };
+    let stored = Arc::new(StoredResponse {
+        stored_at: Utc::now(),
+        status: parts.status.as_u16(),
+        headers: parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect(),
+        body,
+    });
+    keys.finish(&scope, Some(stored.clone()));
+    guard.finished = true;
+    sender.send_replace(Some(stored.clone()));
+    stored.response(false)
+}
+
+/// Writes the file of `keys` after responses were stored, until the keys
+/// are dropped.
+async fn save_changes(keys: Weak<IdempotencyKeys>, mut changed: mpsc::Receiver<()>) {
+    while changed.recv().await.is_some() {
+        tokio::time::sleep(SAVE_DELAY).await;
+        let Some(keys) = keys.upgrade() else {
+            return;
+        };
+        keys.save().await;
+    }
+}
+
+fn read(path: &Path) -> io::Result<Vec<Persisted>> {
+    match std::fs::read(path) {
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+async fn write(path: &Path, persisted: &[Persisted]) -> io::Result<()> {
+    let json = serde_json::to_vec(persisted)?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json).await?;
+    fs::rename(&temp, path).await
+}
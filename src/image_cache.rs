@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use axum::body::Bytes;
+
+use crate::{artifact::Artifact, image_handler::EpdMac};
+
+/// Stored files of MACs kept in memory, see `--image-cache-bytes`. An entry
+/// is only served for the entity tag of the file it was read with, so a
+/// replaced file is never served from the cache.
+#[derive(Debug)]
+pub(crate) struct ImageCache {
+    max_bytes: usize,
+    max_file_bytes: usize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// A file of a MAC by its artifact.
+type Key = (EpdMac, Artifact);
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    bytes: usize,
+    /// Advanced on every use, for the least recently used entry.
+    tick: u64,
+    /// Advanced whenever files are forgotten, so that reads started before
+    /// aren't cached afterwards.
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    etag: String,
+    data: Bytes,
+    last_used: u64,
+}
+
+impl ImageCache {
+    /// A cache of up to `max_bytes`, of files up to `max_file_bytes` each.
+    pub fn new(max_bytes: usize, max_file_bytes: usize) -> Self {
+        ImageCache {
+            max_bytes,
+            max_file_bytes,
+            inner: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a file of `len` bytes is kept.
+    pub fn fits(&self, len: u64) -> bool {
+        len <= self.max_file_bytes.min(self.max_bytes) as u64
+    }
+
+    /// The file `artifact` of `mac`, if it was cached with `etag`.
+    /// Counts as a hit or a miss.
+    pub fn get(&self, mac: EpdMac, artifact: Artifact, etag: &str) -> Option<Bytes> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let data = inner
+            .entries
+            .get_mut(&(mac, artifact))
+            .filter(|entry| entry.etag == etag)
+            .map(|entry| {
+                entry.last_used = tick;
+                entry.data.clone()
+            });
+        let counter = match data {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        data
+    }
+
+    /// To be taken before reading a file that is inserted afterwards.
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    /// Keeps `data`, read with `etag` after [`Self::generation`] returned
+    /// `generation`, unless any files were forgotten since, as they may
+    /// have been replaced meanwhile. Evicts the least recently used files
+    /// to make room.
+    pub fn insert(
+        &self,
+        mac: EpdMac,
+        artifact: Artifact,
+        etag: String,
+        data: Bytes,
+        generation: u64,
+    ) {
+        if !self.fits(data.len() as u64) {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            return;
+        }
+        if let Some(replaced) = inner.entries.remove(&(mac, artifact)) {
+            inner.bytes -= replaced.data.len();
+        }
+        while inner.bytes + data.len() > self.max_bytes {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            let Some(oldest) = oldest else { break };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.bytes -= evicted.data.len();
+            }
+        }
+        inner.tick += 1;
+        inner.bytes += data.len();
+        let last_used = inner.tick;
+        inner.entries.insert(
+            (mac, artifact),
+            Entry {
+                etag,
+                data,
+                last_used,
+            },
+        );
+    }
+
+    /// Drops all files of `mac`, e.g. because they were replaced.
+    pub fn forget(&self, mac: EpdMac) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        let mut freed = 0;
+        inner.entries.retain(|(cached, _), entry| {
+            let keep = *cached != mac;
+            if !keep {
+                freed += entry.data.len();
+            }
+            keep
+        });
+        inner.bytes -= freed;
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ImageCache::new(10, 6);
+        let macs: Vec<EpdMac> = ["0000000000000001", "0000000000000002", "0000000000000003"]
+            .iter()
+            .map(|mac| mac.parse().unwrap())
+            .collect();
+        let generation = cache.generation();
+        cache.insert(
+            macs[0],
+            Artifact::Png,
+            "a".into(),
+            Bytes::from("aaaa"),
+            generation,
+        );
+        cache.insert(
+            macs[1],
+            Artifact::Png,
+            "b".into(),
+            Bytes::from("bbbb"),
+            generation,
+        );
+        assert!(cache.get(macs[0], Artifact::Png, "a").is_some());
+        cache.insert(
+            macs[2],
+            Artifact::Png,
+            "c".into(),
+            Bytes::from("cccc"),
+            generation,
+        );
+        assert!(cache.get(macs[1], Artifact::Png, "b").is_none());
+        assert!(cache.get(macs[0], Artifact::Png, "a").is_some());
+        assert!(cache.get(macs[2], Artifact::Png, "c").is_some());
+        // Only for the entity tag it was read with
+        assert!(cache.get(macs[2], Artifact::Png, "other").is_none());
+        assert_eq!((cache.hits(), cache.misses()), (3, 2));
+
+        cache.insert(
+            macs[1],
+            Artifact::Svg,
+            "d".into(),
+            Bytes::from("too large"),
+            generation,
+        );
+        assert!(cache.get(macs[1], Artifact::Svg, "d").is_none());
+    }
+
+    #[test]
+    fn forget() {
+        let cache = ImageCache::new(100, 100);
+        let mac: EpdMac = "0000000000000001".parse().unwrap();
+        let generation = cache.generation();
+        cache.insert(
+            mac,
+            Artifact::Png,
+            "a".into(),
+            Bytes::from("old"),
+            generation,
+        );
+        cache.forget(mac);
+        assert!(cache.get(mac, Artifact::Png, "a").is_none());
+        // Read before the files were replaced
+        cache.insert(
+            mac,
+            Artifact::Png,
+            "a".into(),
+            Bytes::from("old"),
+            generation,
+        );
+        assert!(cache.get(mac, Artifact::Png, "a").is_none());
+        cache.insert(
+            mac,
+            Artifact::Png,
+            "b".into(),
+            Bytes::from("new"),
+            cache.generation(),
+        );
+        assert_eq!(cache.get(mac, Artifact::Png, "b").unwrap(), "new");
+    }
+}
@@ -1,151 +1,3188 @@
-use crate::{config::Config, error::AppError};
+use crate::{
+    artifact::{self, file_name, Artifact},
+    blocking::{PanicContext, PanicSafe},
+    boot_report::{self, BootReport, BootReportDecoder, StandardTlv},
+    broadcast::{ActiveBroadcast, Broadcast, Target, BROADCAST_FILE},
+    bundle::{self, Bundle, Include, Part, Profile},
+    capabilities::{Capabilities, Payload},
+    cleanup::{self, CleanedFile, CleanupReport},
+    clock::Clock,
+    coalesce::InFlight,
+    color_map::{ColorMap, ColorMapReport},
+    config::{ColorMode, Dither, StaleDimensions, TextMode},
+    coverage,
+    daily_stats::{Counter, DailyStats, STATS_DIR},
+    dedup::{self, DedupReport, DedupUsage},
+    dependency::{Dependencies, Dependency, PROBE_TIMEOUT},
+    derived::{DerivedCache, DerivedFormat, Warmup},
+    display_profile::DisplayProfile,
+    encoding,
+    error::{AppError, ResultExt},
+    events::{EventKind, EventLog},
+    fonts,
+    groups::{GroupName, GroupRender},
+    image_cache::ImageCache,
+    lint::{self, LintOverrides, LintThresholds, LintWarning},
+    metadata::{
+        Checkin, MacMetadata, PlaylistState, RenderFailures, RenderRecord, Rerender, RENDER_LOG_LEN,
+    },
+    negative_cache::NegativeCache,
+    playlist::{Playlist, PlaylistStatus},
+    poll_interval::{PollCadence, PollInterval, PollLimits},
+    post_render::PostRenderHook,
+    precondition::Validators,
+    raster::{self, Autofix, Fit, Palette},
+    refresh::{self, Refresh, RefreshHint, RefreshThresholds},
+    region::{Align, Region, Regions},
+    render_options::{RenderOptions, RenderOverrides},
+    render_queue::{Priority, QueueDepth, RenderQueue},
+    replication::Replicator,
+    response_headers::ResponseHeaders,
+    schedule::{self, Schedule},
+    state::{self, StateStore},
+    storage::{ByteStream, Storage},
+    substitute::{self, substitute},
+    svg_optimize, svgz,
+    units::HumanDuration,
+    validated_config::ValidatedConfig,
+    verify::{self, VerifyReport, VerifyStatus},
+    watchdog::{RenderWatchdog, StuckRender},
+};
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use eyre::{eyre, Context};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     fmt::Display,
-    fs::{read_dir, remove_file},
-    io::Write,
+    future::Future,
+    io::{self, Write},
     path::Path,
     str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
 };
-use tokio::{fs::File, io::AsyncWriteExt, task};
-use tokio_util::io::ReaderStream;
+use tokio::task;
 
 const MAC_LEN: usize = 8;
-const SVG_EXT: &str = ".svg";
-const BMP_EXT: &str = ".bmp";
-const PNG_EXT: &str = ".png";
+const XML_DECLARATION: &str = r#"<?xml version="1.0" encoding="UTF-8"?>"#;
+
+pub(crate) struct ImageHandler {
+    config: ValidatedConfig,
+    storage: Storage,
+    /// The metadata of the MACs, see `--state-backend`.
+    state: Arc<dyn StateStore>,
+    svg_opts: usvg::Options,
+    /// See [`RenderRecord::pipeline`].
+    pipeline: Option<String>,
+    clock: Arc<dyn Clock>,
+    /// Limits how many SVGs are rendered at the same time.
+    render_queue: RenderQueue,
+    watchdog: RenderWatchdog,
+    dependencies: Dependencies,
+    render_logs: Mutex<HashMap<EpdMac, VecDeque<u64>>>,
+    /// Coverage in percent of the latest render of each MAC since startup.
+    coverages: Mutex<HashMap<EpdMac, f64>>,
+    /// Poll interval last suggested to each MAC since startup.
+    poll_suggestions: Mutex<HashMap<EpdMac, u32>>,
+    /// Failed renders as stored in the metadata, once it was read.
+    render_failures: Mutex<HashMap<EpdMac, Option<RenderFailures>>>,
+    /// Serializes replacing the live images of a MAC.
+    mac_locks: Mutex<HashMap<EpdMac, Arc<tokio::sync::Mutex<()>>>>,
+    events: EventLog,
+    missing: NegativeCache,
+    derived: DerivedCache,
+    /// PNGs and SVGs kept in memory, if `--image-cache-bytes` is set.
+    image_cache: Option<ImageCache>,
+    warmup: Warmup,
+    stats: DailyStats,
+    post_render: Option<PostRenderHook>,
+    #[cfg(feature = "script")]
+    render_script: Option<Arc<crate::render_script::RenderScript>>,
+    /// The changes to forward to `--replica-url`.
+    replicator: Option<Replicator>,
+    /// Catches panics of work on posted and stored images.
+    panics: PanicSafe,
+    /// Tried in order on boot reports, see [`Self::put_boot_report`].
+    boot_decoders: Vec<Box<dyn BootReportDecoder>>,
+    /// Posted renders, keyed by their MACs and the hash of their SVG and
+    /// options, see [`Self::post_svg_body_to`].
+    posted: InFlight<(Vec<EpdMac>, String), PostedRender>,
+    /// Serializes starting, extending and ending broadcasts.
+    broadcast_lock: tokio::sync::Mutex<()>,
+}
+
+/// The renders of a posted SVG, shared by identical posts.
+type PostedRender = Result<Vec<Rendered>, Arc<AppError>>;
+
+/// A stored render.
+#[derive(Debug, Clone)]
+pub(crate) struct Rendered {
+    pub record: RenderRecord,
+    pub png: Vec<u8>,
+    /// There was no PNG before.
+    pub created: bool,
+}
+
+/// What the staging slot of a MAC holds, for `GET /macs/:mac/staging`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StagingStatus {
+    /// When the staged images become live, if they were posted with
+    /// `?activate_at`.
+    pub activate_at: Option<DateTime<Utc>>,
+}
+
+/// Options of a posted render.
+#[derive(Debug, Default, Clone, Serialize)]
+pub(crate) struct RerenderOptions {
+    /// Explicit schedule, inferred as daily at midnight if placeholders are used
+    pub schedule: Option<Schedule>,
+    /// Overrides the configured time zone for this MAC
+    pub timezone: Option<Tz>,
+    /// Overrides the resolved render options, kept for scheduled re-renders
+    pub render: RenderOverrides,
+    /// Rejects the render if its lint reports anything
+    pub strict_lint: bool,
+    /// Replaces the tokens of [`substitute`] for each MAC
+    pub substitute: bool,
+}
+
+/// An SVG document ready to be rendered and stored.
+struct PreparedDocument {
+    /// The document to store.
+    buf: Vec<u8>,
+    /// The posted document, if its text was converted to paths.
+    original: Option<Vec<u8>>,
+    /// How the colors were mapped.
+    report: Option<ColorMapReport>,
+}
+
+/// A render of a posted SVG for the MACs with the same `options`.
+struct PreparedRender {
+    /// The MAC whose tokens were substituted, if any, as the render only
+    /// fits that one then.
+    mac: Option<EpdMac>,
+    options: RenderOptions,
+    thresholds: LintThresholds,
+    lint: Vec<LintWarning>,
+    buf: Vec<u8>,
+    original: Option<Vec<u8>>,
+    report: Option<ColorMapReport>,
+    png: Vec<u8>,
+}
+
+impl PreparedRender {
+    fn matches(&self, mac: EpdMac, options: &RenderOptions, thresholds: &LintThresholds) -> bool {
+        self.mac.is_none_or(|substituted| substituted == mac)
+            && self.options == *options
+            && self.thresholds == *thresholds
+    }
+}
+
+impl ImageHandler {
+    #[cfg(test)]
+    pub fn new(config: crate::config::Config) -> Self {
+        Self::with_clock(config, Arc::new(crate::clock::SystemClock))
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(config: crate::config::Config, clock: Arc<dyn Clock>) -> Self {
+        let config = ValidatedConfig::new(config).expect("Invalid configuration");
+        let storage = Storage::from_config(&config).expect("Invalid storage configuration");
+        Self::with_storage(config, clock, storage).expect("Invalid state configuration")
+    }
+
+    /// Without any fonts, as if they were uninstalled.
+    #[cfg(test)]
+    pub fn without_fonts(mut self) -> Self {
+        self.svg_opts.fontdb = usvg::fontdb::Database::new();
+        self
+    }
+
+    pub fn with_storage(
+        config: ValidatedConfig,
+        clock: Arc<dyn Clock>,
+        storage: Storage,
+    ) -> eyre::Result<Self> {
+        let state = state::from_config(&config, &storage)?;
+        let svg_opts = if config.deterministic_render {
+            fonts::deterministic_options()
+        } else {
+            let mut svg_opts = usvg::Options {
+                fontdb: fonts::database(&config.font_fallbacks),
+                ..usvg::Options::default()
+            };
+            // Also for text whose families aren't installed
+            if let Some(family) = config.font_fallbacks.first() {
+                svg_opts.font_family = family.trim().to_owned();
+            }
+            svg_opts
+        };
+        let pipeline = config
+            .deterministic_render
+            .then(|| fonts::pipeline_fingerprint(&svg_opts));
+
+        Ok(ImageHandler {
+            storage,
+            state,
+            render_queue: RenderQueue::new(
+                config.max_concurrent_renders,
+                config.interactive_per_batch,
+            ),
+            watchdog: RenderWatchdog::new(
+                config.render_stuck_secs.get(),
+                config.render_degraded_secs.get(),
+            ),
+            dependencies: Dependencies::new(&config.require_dependency),
+            events: EventLog::new(config.event_history),
+            missing: NegativeCache::new(
+                chrono::Duration::from_std(config.negative_cache_ttl.get())
+                    .unwrap_or(chrono::Duration::MAX),
+            ),
+            derived: DerivedCache::default(),
+            image_cache: (config.image_cache_bytes.as_usize() > 0).then(|| {
+                ImageCache::new(
+                    config.image_cache_bytes.as_usize(),
+                    config.image_cache_file_bytes.as_usize(),
+                )
+            }),
+            warmup: Warmup::default(),
+            stats: DailyStats::new(
+                config.image_dir.join(STATS_DIR),
+                config.timezone,
+                config.stats_retention_days.get(),
+            ),
+            post_render: PostRenderHook::from_config(&config),
+            #[cfg(feature = "script")]
+            render_script: crate::render_script::RenderScript::from_config(&config)?.map(Arc::new),
+            replicator: Replicator::from_config(&config),
+            posted: InFlight::default(),
+            broadcast_lock: tokio::sync::Mutex::default(),
+            panics: PanicSafe::default(),
+            boot_decoders: vec![Box::new(StandardTlv)],
+            config,
+            svg_opts,
+            pipeline,
+            clock,
+            render_logs: Mutex::default(),
+            coverages: Mutex::default(),
+            poll_suggestions: Mutex::default(),
+            render_failures: Mutex::default(),
+            mac_locks: Mutex::default(),
+        })
+    }
+
+    /// Compiles `--render-script` again, see
+    /// [`RenderScript::reload`](crate::render_script::RenderScript::reload).
+    #[cfg(feature = "script")]
+    pub fn reload_render_script(&self) -> Result<(), AppError> {
+        match &self.render_script {
+            Some(script) => script.reload(),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `--render-script`, if any, on the `svg` of a render of `mac` on
+    /// the blocking thread pool. Applies the options it overrides and
+    /// returns the SVG it changed.
+    #[cfg_attr(not(feature = "script"), allow(unused_variables))]
+    async fn run_render_script(
+        &self,
+        mac: EpdMac,
+        svg: &str,
+        options: &mut RenderOptions,
+    ) -> Result<Option<String>, AppError> {
+        #[cfg(feature = "script")]
+        if let Some(script) = self.render_script.clone() {
+            let context = PanicContext::new("render script", mac).source(svg.as_bytes());
+            let (svg, current) = (svg.to_owned(), options.clone());
+            let output = self
+                .panics
+                .spawn_blocking(context, move || script.run(mac, &svg, &current))
+                .await?;
+            options.apply(&output.options);
+            return Ok(output.svg);
+        }
+        Ok(None)
+    }
+
+    /// Imports the metadata of the state backend not selected by
+    /// `--state-backend`, see `--migrate-state`, returning how many MACs
+    /// were imported.
+    pub async fn migrate_state(&self) -> eyre::Result<usize> {
+        let from = state::migration_source(&self.config, &self.storage)?;
+        state::migrate(from.as_ref(), self.state.as_ref()).await
+    }
+
+    pub fn config(&self) -> &ValidatedConfig {
+        &self.config
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Changes of all images, see [`EventKind`].
+    pub fn events(&self) -> &EventLog {
+        &self.events
+    }
+
+    /// SHA-256 of the stored PNG, if there is one.
+    pub async fn png_hash(&self, mac: EpdMac) -> Option<String> {
+        let png = self
+            .storage
+            .read(&file_name(mac, Artifact::Png))
+            .await
+            .ok()?;
+        Some(hex::encode(Sha256::digest(png)))
+    }
+
+    /// Whether the stored PNG of `mac` isn't the size of the panel, as after
+    /// changing `--epd-width` or `--epd-height`. False without a PNG.
+    pub async fn dimensions_stale(&self, mac: EpdMac) -> bool {
+        let Ok(png) = self.storage.read(&file_name(mac, Artifact::Png)).await else {
+            return false;
+        };
+        raster::png_dimensions(&png).is_some_and(|dimensions| {
+            (dimensions.width, dimensions.height) != (self.config.epd_width, self.config.epd_height)
+        })
+    }
+
+    /// Renders the stored SVG of `mac` again if its PNG has stale dimensions
+    /// and `--stale-dimensions` is `rerender`, unless `--read-only`. Returns
+    /// whether the PNG is still stale, as PNGs uploaded directly can't be
+    /// fixed.
+    pub async fn fix_stale_dimensions(&self, mac: EpdMac) -> bool {
+        if !self.dimensions_stale(mac).await {
+            return false;
+        }
+        if self.config.stale_dimensions == StaleDimensions::Flag
+            || self.config.read_only
+            || !self.svg_exists(mac, self.clock.now()).await
+        {
+            return true;
+        }
+        match self.rerender(mac, Priority::Interactive).await {
+            Ok(_) => false,
+            Err(e) => {
+                tracing::warn!("Could not re-render MAC {mac} at the new panel size: {e}");
+                true
+            }
+        }
+    }
+
+    /// Validators of the representation `mime` of the image of `mac`,
+    /// optionally with a content `encoding`. `None` if there is no image.
+    pub async fn validators(
+        &self,
+        mac: EpdMac,
+        mime: &Mime,
+        encoding: Option<&str>,
+    ) -> Result<Option<Validators>, AppError> {
+        let artifact = if *mime == mime::IMAGE_SVG {
+            Artifact::Svg
+        } else {
+            Artifact::Png
+        };
+        let meta = match self
+            .lookup(
+                mac,
+                artifact,
+                self.storage.metadata(&file_name(mac, artifact)),
+            )
+            .await
+        {
+            Ok(meta) => meta,
+            Err(AppError::NotFound(_) | AppError::PngMissingSvgPresent(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut variant = mime.subtype().to_string();
+        if let Some(encoding) = encoding {
+            variant = format!("{variant}-{encoding}");
+        }
+        Ok(Some(Validators::from_metadata(&meta, &variant)))
+    }
+
+    /// The MACs of [`Self::get_macs`] whose hex digits end with the lowercase
+    /// `suffix`.
+    pub async fn macs_ending_with(&self, suffix: &str) -> Result<Vec<EpdMac>, AppError> {
+        Ok(self
+            .get_macs()
+            .await?
+            .macs
+            .into_iter()
+            .map(|entry| entry.mac)
+            .filter(|mac| mac.to_string().ends_with(suffix))
+            .collect())
+    }
+
+    pub async fn get_macs(&self) -> Result<MacListing, AppError> {
+        let mut pngs = BTreeSet::new();
+        let mut svgs = BTreeSet::new();
+        let mut bmps = BTreeSet::new();
+        let mut skipped = 0;
+
+        for entry in self.storage.list().await.internal()? {
+            let name = match entry {
+                Ok(name) => name,
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable directory entry: {e}");
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let path = Path::new(&name);
+            match path.to_str().and_then(artifact::parse) {
+                Some((mac, Artifact::Png)) => {
+                    pngs.insert(mac);
+                }
+                Some((mac, Artifact::Svg)) => {
+                    svgs.insert(mac);
+                }
+                Some((mac, Artifact::Bmp)) => {
+                    bmps.insert(mac);
+                }
+                Some(_) => {}
+                None if path.extension().is_some_and(|ext| ext == "png") => {
+                    tracing::warn!("Skipping {}: not a valid MAC file name", path.display());
+                    skipped += 1;
+                }
+                None => {}
+            }
+        }
+
+        let macs = pngs
+            .union(&bmps)
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .union(&svgs)
+            .map(|&mac| MacEntry {
+                mac,
+                has_png: pngs.contains(&mac),
+                has_svg: svgs.contains(&mac),
+                bmp_only: !pngs.contains(&mac) && bmps.contains(&mac),
+            })
+            .collect();
+        Ok(MacListing { macs, skipped })
+    }
+
+    /// Converts the BMPs of MACs without a PNG to PNGs of the same size,
+    /// keeping the BMPs. Files that can't be converted are skipped.
+    pub async fn migrate_legacy_bmp(&self) -> Result<BmpMigration, AppError> {
+        let mut migration = BmpMigration::default();
+        for entry in self.get_macs().await?.macs {
+            if !entry.bmp_only {
+                continue;
+            }
+            let mac = entry.mac;
+            match self.convert_legacy_bmp(mac).await {
+                Ok(()) => {
+                    self.missing.forget(mac);
+                    migration.migrated += 1;
+                }
+                Err(e) => {
+                    tracing::warn!("Skipping legacy BMP of {mac}: {e:#}");
+                    migration.failed += 1;
+                }
+            }
+        }
+        Ok(migration)
+    }
+
+    async fn convert_legacy_bmp(&self, mac: EpdMac) -> eyre::Result<()> {
+        let bmp = self.storage.read(&file_name(mac, Artifact::Bmp)).await?;
+        let png = task::spawn_blocking(move || {
+            let image = image::load_from_memory_with_format(&bmp, ImageFormat::Bmp)
+                .wrap_err("Invalid BMP")?;
+            let mut png = io::Cursor::new(vec![]);
+            image.write_to(&mut png, ImageFormat::Png)?;
+            Ok::<_, eyre::Error>(png.into_inner())
+        })
+        .await??;
+        self.storage
+            .write_atomic(&file_name(mac, Artifact::Png), &png)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_svg(&self, mac: EpdMac) -> Result<ByteStream, AppError> {
+        self.get_file(mac, Artifact::Svg).await
+    }
+
+    /// The stored SVG of `mac`, gzip compressed.
+    pub async fn get_svg_gzip(&self, mac: EpdMac) -> Result<Vec<u8>, AppError> {
+        let svg = self
+            .lookup(
+                mac,
+                Artifact::Svg,
+                self.storage.read(&file_name(mac, Artifact::Svg)),
+            )
+            .await?;
+        let context = PanicContext::new("compression", mac);
+        self.panics
+            .spawn_blocking(context, move || svgz::compress(&svg).internal())
+            .await
+    }
+
+    pub async fn get_png(&self, mac: EpdMac) -> Result<ByteStream, AppError> {
+        self.get_file(mac, Artifact::Png).await
+    }
+
+    pub async fn get_bmp(&self, mac: EpdMac) -> Result<Bytes, AppError> {
+        self.derived(mac, DerivedFormat::Bmp).await
+    }
+
+    pub async fn get_raw(&self, mac: EpdMac) -> Result<Bytes, AppError> {
+        self.derived(mac, DerivedFormat::Raw).await
+    }
+
+    /// The stored PNG of `mac` converted to `format`, cached until the PNG
+    /// changes.
+    pub async fn derived(&self, mac: EpdMac, format: DerivedFormat) -> Result<Bytes, AppError> {
+        let png_name = file_name(mac, Artifact::Png);
+        let meta = self
+            .lookup(mac, Artifact::Png, self.storage.metadata(&png_name))
+            .await?;
+        let source = Validators::from_metadata(&meta, Artifact::Png.suffix()).etag;
+        if let Some(data) = self.derived.get(mac, format, &source) {
+            return Ok(data);
+        }
+
+        let png = self
+            .lookup(mac, Artifact::Png, self.storage.read(&png_name))
+            .await?;
+        let palette = self.palette().cloned();
+        let context = PanicContext::new("conversion", mac).source(&png);
+        let data: Bytes = self
+            .panics
+            .spawn_blocking(context, move || {
+                let image =
+                    image::load_from_memory_with_format(&png, ImageFormat::Png).internal()?;
+                format.convert(&image, palette.as_ref()).internal()
+            })
+            .await?
+            .into();
+        self.derived.insert(mac, format, source, data.clone());
+        Ok(data)
+    }
+
+    pub async fn put_capabilities(
+        &self,
+        mac: EpdMac,
+        capabilities: Capabilities,
+    ) -> Result<Capabilities, AppError> {
+        let stored = capabilities.clone();
+        self.update_metadata(mac, |meta| meta.capabilities = Some(stored))
+            .await
+            .internal()?;
+        Ok(capabilities)
+    }
+
+    pub async fn get_capabilities(&self, mac: EpdMac) -> Result<Option<Capabilities>, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(meta.capabilities)
+    }
+
+    /// Stores the latest boot report of `mac` with the fields a decoder
+    /// recognized.
+    pub async fn put_boot_report(
+        &self,
+        mac: EpdMac,
+        report: &[u8],
+    ) -> Result<BootReport, AppError> {
+        self.storage
+            .write_atomic(&file_name(mac, Artifact::BootReport), report)
+            .await
+            .internal()?;
+        let boot_report = BootReport {
+            received: self.clock.now(),
+            bytes: report.len(),
+            decoded: boot_report::decode(&self.boot_decoders, report),
+        };
+        let stored = boot_report.clone();
+        self.update_metadata(mac, |meta| meta.boot_report = Some(stored))
+            .await
+            .internal()?;
+        Ok(boot_report)
+    }
+
+    /// The latest boot report of `mac` with its bytes.
+    pub async fn get_boot_report(&self, mac: EpdMac) -> Result<(BootReport, Vec<u8>), AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        let report = self
+            .storage
+            .read_optional(&file_name(mac, Artifact::BootReport))
+            .await
+            .internal()?;
+        match (meta.boot_report, report) {
+            (Some(boot_report), Some(report)) => Ok((boot_report, report)),
+            _ => Err(AppError::NotFound(eyre!(
+                "The device of {mac} sent no boot report."
+            ))),
+        }
+    }
+
+    /// What the device of `mac` last reported about itself.
+    pub async fn get_checkin(&self, mac: EpdMac) -> Result<Checkin, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(Checkin {
+            boot: meta
+                .boot_report
+                .as_ref()
+                .and_then(|report| report.decoded.clone())
+                .unwrap_or_default(),
+            boot_report: meta.boot_report.map(|report| report.received),
+            full_refresh: meta.full_refresh,
+            capabilities: meta.capabilities,
+            suggested_poll_secs: self.suggest_poll(mac, &meta.poll),
+        })
+    }
+
+    pub async fn put_response_headers(
+        &self,
+        mac: EpdMac,
+        headers: ResponseHeaders,
+    ) -> Result<ResponseHeaders, AppError> {
+        let stored = headers.clone();
+        self.update_metadata(mac, |meta| meta.response_headers = stored)
+            .await
+            .internal()?;
+        Ok(headers)
+    }
+
+    pub async fn get_response_headers(&self, mac: EpdMac) -> Result<ResponseHeaders, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(meta.response_headers)
+    }
+
+    /// The image of `mac` in the format its device decodes best, see
+    /// [`Capabilities::preferred_formats`]. Devices that didn't report their
+    /// capabilities get `--default-payload-format`.
+    pub async fn payload(&self, mac: EpdMac) -> Result<Payload, AppError> {
+        let capabilities = self
+            .get_capabilities(mac)
+            .await?
+            .unwrap_or_else(|| Capabilities::only(self.config.default_payload_format));
+        let mut too_large = None;
+        for format in capabilities.preferred_formats() {
+            let data = match format.derived() {
+                Some(derived) => self.derived(mac, derived).await?.to_vec(),
+                None => {
+                    let png_name = file_name(mac, Artifact::Png);
+                    self.lookup(mac, Artifact::Png, self.storage.read(&png_name))
+                        .await?
+                }
+            };
+            match capabilities.encode(format, data) {
+                Ok(payload) => return Ok(payload),
+                Err(e) => too_large = Some(e),
+            }
+        }
+        Err(AppError::PayloadTooLarge(too_large.unwrap_or_else(|| {
+            eyre!("The device of {mac} reported no formats")
+        })))
+    }
+
+    /// The colors of the panel, `None` for black and white panels.
+    fn palette(&self) -> Option<&Palette> {
+        (self.config.color_mode == ColorMode::Palette).then_some(&self.config.palette)
+    }
+
+    /// Progress of the warm-up of derived formats.
+    pub fn warmup(&self) -> &Warmup {
+        &self.warmup
+    }
+
+    #[cfg(test)]
+    pub fn derived_cache(&self) -> &DerivedCache {
+        &self.derived
+    }
+
+    /// The stored PNG, or SVG for `mime`, of `mac` from the image cache, read
+    /// and kept there if it isn't yet. `None` without `--image-cache-bytes`
+    /// and for files too large to keep, which are to be streamed instead.
+    pub async fn cached_image(&self, mac: EpdMac, mime: &Mime) -> Result<Option<Bytes>, AppError> {
+        let Some(cache) = &self.image_cache else {
+            return Ok(None);
+        };
+        let artifact = if *mime == mime::IMAGE_SVG {
+            Artifact::Svg
+        } else {
+            Artifact::Png
+        };
+        let name = file_name(mac, artifact);
+        let meta = self
+            .lookup(mac, artifact, self.storage.metadata(&name))
+            .await?;
+        if !cache.fits(meta.len) {
+            return Ok(None);
+        }
+        let etag = Validators::from_metadata(&meta, artifact.suffix()).etag;
+        if let Some(data) = cache.get(mac, artifact, &etag) {
+            return Ok(Some(data));
+        }
+
+        let generation = cache.generation();
+        let chunk_size = self.config.stream_chunk_bytes.as_usize();
+        let (stream, meta) = self
+            .lookup(
+                mac,
+                artifact,
+                self.storage.open_with_meta(&name, chunk_size),
+            )
+            .await?;
+        let chunks: Vec<Bytes> = stream.try_collect().await.internal()?;
+        let data = Bytes::from(chunks.concat());
+        // The metadata of the opened file, even if it was replaced meanwhile
+        let etag = Validators::from_metadata(&meta, artifact.suffix()).etag;
+        cache.insert(mac, artifact, etag, data.clone(), generation);
+        Ok(Some(data))
+    }
+
+    async fn get_file(&self, mac: EpdMac, artifact: Artifact) -> Result<ByteStream, AppError> {
+        let chunk_size = self.config.stream_chunk_bytes.as_usize();
+        let (stream, _) = self
+            .lookup(
+                mac,
+                artifact,
+                self.storage
+                    .open_with_meta(&file_name(mac, artifact), chunk_size),
+            )
+            .await?;
+        Ok(stream)
+    }
+
+    /// Runs `lookup` of the file `artifact` of `mac`, unless it was found
+    /// missing within the configured time. Then the lookup is skipped and it
+    /// fails the same way.
+    async fn lookup<T>(
+        &self,
+        mac: EpdMac,
+        artifact: Artifact,
+        lookup: impl Future<Output = io::Result<T>>,
+    ) -> Result<T, AppError> {
+        let now = self.clock.now();
+        if !self.missing.contains(mac, artifact, now) {
+            match lookup.await {
+                Ok(found) => return Ok(found),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.missing.insert(mac, artifact, now)
+                }
+                Err(e) => return Err(image_error(e, mac, artifact)),
+            }
+        }
+        if artifact == Artifact::Png && self.svg_exists(mac, now).await {
+            return Err(AppError::PngMissingSvgPresent(eyre!(
+                "The PNG image of MAC {mac} is missing but its SVG exists; \
+                 regenerate it with POST /macs/{mac}/regenerate."
+            )));
+        }
+        Err(image_error(io::ErrorKind::NotFound.into(), mac, artifact))
+    }
+
+    /// Whether `mac` has an SVG, consulting the negative cache first.
+    async fn svg_exists(&self, mac: EpdMac, now: DateTime<Utc>) -> bool {
+        if self.missing.contains(mac, Artifact::Svg, now) {
+            return false;
+        }
+        match self.storage.metadata(&file_name(mac, Artifact::Svg)).await {
+            Ok(_) => true,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    self.missing.insert(mac, Artifact::Svg, now);
+                }
+                false
+            }
+        }
+    }
+
+    /// Counts an image response for the daily statistics.
+    pub fn count_fetch(&self, mac: EpdMac) {
+        self.stats.count(Counter::Fetch, mac, self.clock.now());
+    }
+
+    pub fn daily_stats(&self) -> &DailyStats {
+        &self.stats
+    }
+
+    pub async fn flush_stats(&self) -> eyre::Result<()> {
+        self.stats.flush(self.clock.now()).await
+    }
+
+    /// Fetches served from the image cache since startup.
+    pub fn image_cache_hits(&self) -> u64 {
+        self.image_cache.as_ref().map_or(0, ImageCache::hits)
+    }
+
+    /// Fetches that read the file to put it into the image cache since
+    /// startup.
+    pub fn image_cache_misses(&self) -> u64 {
+        self.image_cache.as_ref().map_or(0, ImageCache::misses)
+    }
+
+    /// Negative cache lookups that didn't touch the disk since startup.
+    pub fn negative_cache_hits(&self) -> u64 {
+        self.missing.hits()
+    }
+
+    /// Announces a change of the images of `mac`.
+    fn images_changed(&self, kind: EventKind, mac: EpdMac, timestamp: DateTime<Utc>) {
+        if kind == EventKind::Render {
+            self.stats.count(Counter::Render, mac, timestamp);
+        }
+        self.missing.forget(mac);
+        self.derived.forget(mac);
+        if let Some(cache) = &self.image_cache {
+            cache.forget(mac);
+        }
+        self.events.publish(kind, mac, timestamp);
+        if let Some(replicator) = &self.replicator {
+            replicator.enqueue(kind, mac, timestamp);
+        }
+    }
+
+    pub async fn delete_images(&self, mac: EpdMac) -> Result<(), AppError> {
+        let images: Vec<String> = Artifact::IMAGES
+            .into_iter()
+            .map(|artifact| file_name(mac, artifact))
+            .collect();
+        let removed = self
+            .storage
+            .remove_set(&images.iter().map(String::as_str).collect::<Vec<_>>())
+            .await
+            .map_err(|e| {
+                AppError::from(e).context(format!("Could not delete images of MAC {mac}."))
+            })?;
+        if removed == 0 {
+            return Err(AppError::NotFound(eyre!(
+                "Could not find any images for MAC {}.",
+                mac
+            )));
+        }
+
+        // Everything else kept for the images, whichever artifacts there are
+        let others: Vec<String> = Artifact::ALL
+            .into_iter()
+            .filter(|artifact| {
+                artifact.deleted_with_images() && !Artifact::IMAGES.contains(artifact)
+            })
+            .map(|artifact| file_name(mac, artifact))
+            .collect();
+        self.storage
+            .remove_set(&others.iter().map(String::as_str).collect::<Vec<_>>())
+            .await
+            .internal()?;
+        self.state.remove(mac).await.internal()?;
+        self.images_changed(EventKind::Delete, mac, self.clock.now());
+        Ok(())
+    }
+
+    pub async fn post_svg_body(
+        &self,
+        mac: EpdMac,
+        svg_body: &str,
+        options: RerenderOptions,
+        priority: Priority,
+    ) -> Result<Rendered, AppError> {
+        let mut rendered = self
+            .post_svg_body_to(&[mac], svg_body, options, priority)
+            .await?;
+        Ok(rendered.remove(0))
+    }
+
+    /// Renders `svg_body` once and stores the result for each of `macs`,
+    /// returning the renders in the same order. A byte order mark and CRLF
+    /// line endings are removed first. Posting the same SVG with the same
+    /// options while it is rendering for the same MACs waits for that render
+    /// and returns its result.
+    pub async fn post_svg_body_to(
+        &self,
+        macs: &[EpdMac],
+        svg_body: &str,
+        options: RerenderOptions,
+        priority: Priority,
+    ) -> Result<Vec<Rendered>, AppError> {
+        let svg_body = &*encoding::normalize(svg_body);
+        let mut hasher = Sha256::new();
+        hasher.update(svg_body);
+        hasher.update(serde_json::to_vec(&options).internal()?);
+        let key = (macs.to_vec(), hex::encode(hasher.finalize()));
+        let render = || async {
+            self.render_svg_body_to(macs, svg_body, options, priority)
+                .await
+                .map_err(Arc::new)
+        };
+        self.posted
+            .run(key, render)
+            .await
+            .map_err(|e| Arc::try_unwrap(e).unwrap_or_else(AppError::Coalesced))
+    }
+
+    async fn render_svg_body_to(
+        &self,
+        macs: &[EpdMac],
+        svg_body: &str,
+        options: RerenderOptions,
+        priority: Priority,
+    ) -> Result<Vec<Rendered>, AppError> {
+        if macs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let source_hash = hex::encode(Sha256::digest(svg_body));
+        for &mac in macs {
+            if let Some(failures) = self.quarantined(mac).await {
+                if failures.source_hash == source_hash {
+                    return Err(AppError::Quarantined(eyre!(
+                        "Rendering this SVG for MAC {mac} failed {} times in a row, so it is \
+                         rejected until another SVG is posted or \
+                         DELETE /macs/{mac}/quarantine clears it.",
+                        failures.count
+                    )));
+                }
+            }
+        }
+        let optimized;
+        let svg_body = if self.config.optimize_svg {
+            optimized =
+                svg_optimize::optimize(svg_body, self.config.svg_precision).bad_request()?;
+            optimized.as_str()
+        } else {
+            svg_body
+        };
+
+        let now = self.clock.now();
+        let tz = options.timezone.unwrap_or(self.config.timezone);
+        let substituted =
+            schedule::substitute_now(svg_body, now.with_timezone(&tz)).bad_request()?;
+        let time_dependent = substituted.is_some();
+
+        let started = Instant::now();
+        let mut resolved = Vec::with_capacity(macs.len());
+        // SVGs changed by `--render-script`
+        let mut scripted = HashMap::new();
+        for &mac in macs {
+            let (mut render_options, thresholds) =
+                self.render_settings(mac, &options.render).await?;
+            let fragment = substituted.as_deref().unwrap_or(svg_body);
+            if let Some(svg) = self
+                .run_render_script(mac, fragment, &mut render_options)
+                .await?
+            {
+                scripted.insert(mac, svg);
+            }
+            resolved.push((render_options, thresholds));
+        }
+        // MACs with the same options share a render
+        let mut prepared: Vec<PreparedRender> = Vec::new();
+        for (&mac, (render_options, thresholds)) in macs.iter().zip(&resolved) {
+            if prepared
+                .iter()
+                .any(|render| render.matches(mac, render_options, thresholds))
+            {
+                continue;
+            }
+            let fragment = match scripted.get(&mac) {
+                Some(svg) => svg.as_str(),
+                None => substituted.as_deref().unwrap_or(svg_body),
+            };
+            let fragment = if options.substitute {
+                Cow::Owned(self.substitute(mac, fragment, &tz, render_options.profile()))
+            } else {
+                Cow::Borrowed(fragment)
+            };
+            let document = self
+                .document(&fragment, render_options.profile())
+                .internal()?;
+            let lint = self.lint(&document, render_options, *thresholds);
+            if options.strict_lint && !lint.is_empty() {
+                return Err(AppError::Lint(lint));
+            }
+            let PreparedDocument {
+                buf,
+                original,
+                report,
+            } = self.prepare_document(document, render_options)?;
+            let png = match self.render_png(mac, &buf, render_options, priority).await {
+                Ok(png) => png,
+                Err(e) => {
+                    for &mac in macs {
+                        self.record_failure(mac, &source_hash).await;
+                    }
+                    return Err(e);
+                }
+            };
+            prepared.push(PreparedRender {
+                mac: (options.substitute || scripted.contains_key(&mac)).then_some(mac),
+                options: render_options.clone(),
+                thresholds: *thresholds,
+                lint,
+                buf,
+                original,
+                report,
+                png,
+            });
+        }
+
+        let schedule = match options.schedule {
+            Some(schedule) => Some(schedule),
+            None if time_dependent => Some(Schedule::default()),
+            None => None,
+        };
+        let scheduled = schedule.is_some();
+        let mut renders = Vec::with_capacity(macs.len());
+        for (&mac, (render_options, thresholds)) in macs.iter().zip(&resolved) {
+            let render = prepared
+                .iter()
+                .find(|render| render.matches(mac, render_options, thresholds))
+                .expect("Every distinct options are rendered");
+            // Until its render is logged, for other SVGs posted meanwhile
+            let _lock = self.lock_mac(mac).await;
+            let mut rendered = self
+                .write_render(mac, &render.buf, render.png.clone(), started)
+                .await?;
+            rendered.record.color_map = render.report.clone();
+            rendered.record.lint = render.lint.clone();
+            rendered.record.options = Some(render.options.clone());
+            self.keep_original(mac, render.original.as_deref()).await?;
+            self.render_failures.lock().unwrap().insert(mac, None);
+            let result = self
+                .record_render(mac, rendered.record.clone(), |meta| {
+                    meta.render_failures = None;
+                    if meta.rerender.is_some() || scheduled {
+                        meta.rerender = schedule.map(|schedule| Rerender {
+                            schedule,
+                            source: svg_body.to_owned(),
+                            timezone: options.timezone,
+                            overrides: options.render.clone(),
+                            substitute: options.substitute,
+                            last_render: now,
+                        });
+                    }
+                })
+                .await;
+            match result {
+                // Losing the schedule would silently stop future renders
+                Err(e) if scheduled => return Err(AppError::InternalServerError(e)),
+                Err(e) => tracing::warn!("Could not store render log of {mac}: {e:#}"),
+                Ok(()) => {}
+            }
+            renders.push(rendered);
+        }
+        for (&mac, rendered) in macs.iter().zip(&renders) {
+            self.check_post_render(mac, &rendered.record)?;
+        }
+        Ok(renders)
+    }
+
+    /// Fails with `--post-render-strict` if the post-render command failed
+    /// for `record`.
+    fn check_post_render(&self, mac: EpdMac, record: &RenderRecord) -> Result<(), AppError> {
+        match &record.post_render {
+            Some(report) if self.config.post_render_strict && !report.success => {
+                Err(AppError::InternalServerError(eyre!(
+                    "The post-render command failed for MAC {mac}: {report:?}"
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Renders `svg_body` into the staging slot of `mac`, leaving its live
+    /// images and devices alone until it is promoted: at `activate_at` if
+    /// given, see [`Self::run_due_activations`], otherwise by a request. The
+    /// staged images and activation time replace those staged before.
+    /// Returns the render if `activate_at` has passed and it was promoted
+    /// right away.
+    pub async fn stage_svg_body(
+        &self,
+        mac: EpdMac,
+        svg_body: &str,
+        overrides: &RenderOverrides,
+        priority: Priority,
+        activate_at: Option<DateTime<Utc>>,
+    ) -> Result<Option<Rendered>, AppError> {
+        let optimized;
+        let svg_body = if self.config.optimize_svg {
+            optimized =
+                svg_optimize::optimize(svg_body, self.config.svg_precision).bad_request()?;
+            optimized.as_str()
+        } else {
+            svg_body
+        };
+        let now = self.clock.now().with_timezone(&self.config.timezone);
+        let substituted = schedule::substitute_now(svg_body, now).bad_request()?;
+        let options = self.render_options(mac, overrides).await?;
+        let document = self
+            .document(
+                substituted.as_deref().unwrap_or(svg_body),
+                options.profile(),
+            )
+            .internal()?;
+        let buf = self.prepare_document(document, &options)?.buf;
+        let png = self.render_png(mac, &buf, &options, priority).await?;
+
+        // Not promoted halfway by a due activation
+        let lock = self.lock_mac(mac).await;
+        self.storage
+            .write_atomic(&file_name(mac, Artifact::StagingPng), &png)
+            .await
+            .internal()?;
+        self.storage
+            .write_atomic(&file_name(mac, Artifact::StagingSvg), &buf)
+            .await
+            .internal()?;
+        self.missing.forget(mac);
+        self.update_metadata(mac, |meta| meta.activate_at = activate_at)
+            .await
+            .internal()?;
+        drop(lock);
+        if activate_at.is_some_and(|at| at <= self.clock.now()) {
+            return self.promote(mac).await.map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Whether `mac` has staged images, and when they become live.
+    pub async fn get_staging(&self, mac: EpdMac) -> Result<StagingStatus, AppError> {
+        match self
+            .storage
+            .metadata(&file_name(mac, Artifact::StagingPng))
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(AppError::NotFound(eyre!("MAC {mac} has nothing staged.")));
+            }
+            Err(e) => return Err(AppError::from(e)),
+        }
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(StagingStatus {
+            activate_at: meta.activate_at,
+        })
+    }
+
+    pub async fn get_staging_png(&self, mac: EpdMac) -> Result<ByteStream, AppError> {
+        self.get_file(mac, Artifact::StagingPng).await
+    }
+
+    /// Replaces the live images of `mac` with the staged ones, as if they
+    /// had just been rendered.
+    pub async fn promote(&self, mac: EpdMac) -> Result<Rendered, AppError> {
+        let _lock = self.lock_mac(mac).await;
+        self.promote_locked(mac).await
+    }
+
+    /// [`Self::promote`] while holding the lock of `mac`.
+    async fn promote_locked(&self, mac: EpdMac) -> Result<Rendered, AppError> {
+        let started = Instant::now();
+        let png_name = file_name(mac, Artifact::StagingPng);
+        let svg_name = file_name(mac, Artifact::StagingSvg);
+        let png = self.storage.read_optional(&png_name).await.internal()?;
+        let svg = self.storage.read_optional(&svg_name).await.internal()?;
+        let (Some(png), Some(svg)) = (png, svg) else {
+            return Err(AppError::NothingStaged(eyre!(
+                "MAC {mac} has nothing staged to promote; stage a render with \
+                 POST /macs/{mac}/staging/render_svg."
+            )));
+        };
+
+        let rendered = self.write_render(mac, &svg, png, started).await?;
+        self.keep_original(mac, None).await?;
+        self.storage
+            .remove_set(&[&png_name, &svg_name])
+            .await
+            .internal()?;
+        self.render_failures.lock().unwrap().insert(mac, None);
+        let result = self
+            .record_render(mac, rendered.record.clone(), |meta| {
+                meta.render_failures = None;
+                meta.rerender = None;
+                meta.activate_at = None;
+            })
+            .await;
+        if let Err(e) = result {
+            tracing::warn!("Could not store render log of {mac}: {e:#}");
+        }
+        self.check_post_render(mac, &rendered.record)?;
+        Ok(rendered)
+    }
+
+    /// Discards the staged images of `mac`, cancelling their activation.
+    pub async fn discard_staging(&self, mac: EpdMac) -> Result<(), AppError> {
+        let _lock = self.lock_mac(mac).await;
+        let removed = self
+            .storage
+            .remove_set(&[
+                &file_name(mac, Artifact::StagingSvg),
+                &file_name(mac, Artifact::StagingPng),
+            ])
+            .await
+            .internal()?;
+        self.update_metadata(mac, |meta| meta.activate_at = None)
+            .await
+            .internal()?;
+        if removed == 0 {
+            return Err(AppError::NotFound(eyre!("MAC {mac} has nothing staged.")));
+        }
+        Ok(())
+    }
+
+    /// Promotes the staged images whose activation time has passed and
+    /// returns how many were promoted. Failed promotions are logged and
+    /// retried on the next call. Nothing is promoted during a broadcast.
+    pub async fn run_due_activations(&self) -> Result<usize, AppError> {
+        if self.active_broadcast().await?.is_some() {
+            return Ok(0);
+        }
+        let now = self.clock.now();
+        let mut promoted = 0;
+        for mac in self.meta_macs().await? {
+            let due = |meta: &MacMetadata| meta.activate_at.is_some_and(|at| at <= now);
+            match self.state.load(mac).await {
+                Ok(meta) if due(&meta) => {}
+                Ok(_) => continue,
+                Err(e) => {
+                    tracing::warn!("Could not read metadata of {mac}: {e:#}");
+                    continue;
+                }
+            }
+            // Again, as a later render may have replaced the staged images
+            let _lock = self.lock_mac(mac).await;
+            match self.state.load(mac).await {
+                Ok(meta) if due(&meta) => {}
+                _ => continue,
+            }
+            match self.promote_locked(mac).await {
+                Ok(_) => promoted += 1,
+                Err(AppError::NothingStaged(e)) => {
+                    tracing::warn!("Dropping the activation of {mac}: {e}");
+                    let result = self
+                        .update_metadata(mac, |meta| meta.activate_at = None)
+                        .await;
+                    if let Err(e) = result {
+                        tracing::warn!("Could not store metadata of {mac}: {e:#}");
+                    }
+                }
+                Err(e) => tracing::warn!("Activating the staged images of {mac} failed: {e}"),
+            }
+        }
+        Ok(promoted)
+    }
+
+    /// Repeats all scheduled renders whose next point in time has passed and
+    /// returns how many were rendered. Failed renders are logged and retried
+    /// on the next call. Nothing is rendered during a broadcast.
+    pub async fn run_due_rerenders(&self) -> Result<usize, AppError> {
+        if self.active_broadcast().await?.is_some() {
+            return Ok(0);
+        }
+        let now = self.clock.now();
+        let mut rendered = 0;
+        for mac in self.meta_macs().await? {
+            let meta = match self.state.load(mac).await {
+                Ok(meta) => meta,
+                Err(e) => {
+                    tracing::warn!("Could not read metadata of {mac}: {e:#}");
+                    continue;
+                }
+            };
+            let Some(rerender) = meta.rerender else {
+                continue;
+            };
+            let tz = rerender.timezone.unwrap_or(self.config.timezone);
+            if rerender.schedule.next_after(rerender.last_render, tz) > now {
+                continue;
+            }
+
+            let record = match self
+                .render_scheduled(mac, &rerender, now, Priority::Batch)
+                .await
+            {
+                Ok(record) => record,
+                Err(e) => {
+                    tracing::warn!("Scheduled render of {mac} failed: {e}");
+                    continue;
+                }
+            };
+
+            let result = self
+                .record_render(mac, record, |meta| {
+                    if let Some(rerender) = meta.rerender.as_mut() {
+                        rerender.last_render = now;
+                    }
+                })
+                .await;
+            if let Err(e) = result {
+                tracing::warn!("Could not store metadata of {mac}: {e:#}");
+            }
+            rendered += 1;
+        }
+        Ok(rendered)
+    }
+
+    /// Stores the playlist of `mac` and renders its active entry. The
+    /// playlist replaces a scheduled render.
+    pub async fn put_playlist(
+        &self,
+        mac: EpdMac,
+        playlist: Playlist,
+    ) -> Result<PlaylistStatus, AppError> {
+        if playlist.entries.is_empty() {
+            return Err(AppError::BadRequest(eyre!(
+                "A playlist needs at least one entry."
+            )));
+        }
+        let now = self.clock.now();
+        let tz = playlist.timezone.unwrap_or(self.config.timezone);
+        for (i, entry) in playlist.entries.iter().enumerate() {
+            self.check_fragment(&entry.svg, now.with_timezone(&tz))
+                .map_err(|e| AppError::BadRequest(e.wrap_err(format!("Invalid entry {i}"))))?;
+        }
+
+        let active = playlist.active_at(now, tz);
+        let record = match active {
+            Some(i) => Some(self.render_entry(mac, &playlist, i, now).await?),
+            None => None,
+        };
+        let state = PlaylistState {
+            playlist: playlist.clone(),
+            rendered: active,
+        };
+        let update = |meta: &mut MacMetadata| {
+            meta.rerender = None;
+            meta.playlist = Some(state);
+        };
+        match record {
+            Some(record) => self.record_render(mac, record, update).await,
+            None => self.update_metadata(mac, update).await,
+        }
+        .internal()?;
+
+        Ok(PlaylistStatus {
+            active,
+            next_transition: playlist.next_transition(now, tz),
+            playlist,
+        })
+    }
+
+    pub async fn get_playlist(&self, mac: EpdMac) -> Result<PlaylistStatus, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        let state = meta
+            .playlist
+            .ok_or_else(|| AppError::NotFound(eyre!("No playlist for MAC {mac}.")))?;
+        let now = self.clock.now();
+        let tz = state.playlist.timezone.unwrap_or(self.config.timezone);
+        Ok(PlaylistStatus {
+            active: state.playlist.active_at(now, tz),
+            next_transition: state.playlist.next_transition(now, tz),
+            playlist: state.playlist,
+        })
+    }
+
+    /// Replaces the groups of `mac`.
+    pub async fn put_groups(
+        &self,
+        mac: EpdMac,
+        groups: BTreeSet<GroupName>,
+    ) -> Result<BTreeSet<GroupName>, AppError> {
+        let stored = groups.clone();
+        self.update_metadata(mac, |meta| meta.groups = stored)
+            .await
+            .internal()?;
+        Ok(groups)
+    }
+
+    pub async fn get_groups(&self, mac: EpdMac) -> Result<BTreeSet<GroupName>, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(meta.groups)
+    }
+
+    /// The MACs in `group`, ordered by byte value.
+    pub async fn group_members(&self, group: &GroupName) -> Result<Vec<EpdMac>, AppError> {
+        let mut members = Vec::new();
+        for mac in self.meta_macs().await? {
+            match self.state.load(mac).await {
+                Ok(meta) if meta.groups.contains(group) => members.push(mac),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Could not read metadata of {mac}: {e:#}"),
+            }
+        }
+        members.sort();
+        Ok(members)
+    }
+
+    /// Renders `render` for every member of `group`. A failed render doesn't
+    /// stop the others; the results are in the order of the members.
+    pub async fn render_group(
+        &self,
+        group: &GroupName,
+        render: &GroupRender,
+        priority: Priority,
+    ) -> Result<Vec<(EpdMac, Result<(), AppError>)>, AppError> {
+        let members = self.group_members(group).await?;
+        if members.is_empty() {
+            return Err(AppError::NotFound(eyre!("Group {group} has no members.")));
+        }
+        // Renders are limited by the render permits anyway
+        let results = stream::iter(members)
+            .map(|mac| async move {
+                let svg = render.overrides.get(&mac).unwrap_or(&render.svg);
+                let options = RerenderOptions {
+                    render: render.options.clone(),
+                    ..RerenderOptions::default()
+                };
+                let result = self
+                    .post_svg_body(mac, svg, options, priority)
+                    .await
+                    .map(|_| ());
+                (mac, result)
+            })
+            .buffered(self.config.max_concurrent_renders.max(1))
+            .collect()
+            .await;
+        Ok(results)
+    }
+
+    /// The broadcast in progress, see [`Self::start_broadcast`].
+    pub async fn active_broadcast(&self) -> Result<Option<ActiveBroadcast>, AppError> {
+        match self
+            .storage
+            .read_optional(BROADCAST_FILE)
+            .await
+            .internal()?
+        {
+            Some(json) => Ok(Some(serde_json::from_slice(&json).internal()?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_broadcast(&self, active: &ActiveBroadcast) -> Result<(), AppError> {
+        let json = serde_json::to_vec_pretty(active).internal()?;
+        self.storage
+            .write_atomic(BROADCAST_FILE, &json)
+            .await
+            .internal()
+    }
+
+    /// Shows `broadcast` on every MAC with images, after saving them for
+    /// [`Self::end_broadcast`]. Scheduled renders and playlists pause until
+    /// then. Posting the same broadcast again renders it for the MACs it
+    /// failed for, another one replaces it on all of them. The results are
+    /// ordered by MAC.
+    pub async fn start_broadcast(
+        &self,
+        broadcast: Broadcast,
+    ) -> Result<Vec<(EpdMac, Result<(), AppError>)>, AppError> {
+        let _lock = self.broadcast_lock.lock().await;
+        let mut active = match self.active_broadcast().await? {
+            Some(active) if active.broadcast == broadcast => active,
+            Some(mut active) => {
+                active.broadcast = broadcast;
+                for target in active.targets.values_mut() {
+                    target.shown = false;
+                }
+                active
+            }
+            None => ActiveBroadcast {
+                broadcast,
+                started: self.clock.now(),
+                targets: BTreeMap::new(),
+            },
+        };
+        let macs = self
+            .get_macs()
+            .await?
+            .macs
+            .into_iter()
+            .map(|entry| entry.mac);
+        self.show_broadcast(&mut active, macs.collect()).await
+    }
+
+    /// Shows the broadcast in progress on the MACs that got their first
+    /// images since it started, and returns on how many.
+    pub async fn extend_broadcast(&self) -> Result<usize, AppError> {
+        let _lock = self.broadcast_lock.lock().await;
+        let Some(mut active) = self.active_broadcast().await? else {
+            return Ok(0);
+        };
+        let macs: Vec<_> = self
+            .get_macs()
+            .await?
+            .macs
+            .into_iter()
+            .map(|entry| entry.mac)
+            .filter(|mac| !active.targets.contains_key(mac))
+            .collect();
+        let results = self.show_broadcast(&mut active, macs).await?;
+        for (mac, result) in &results {
+            if let Err(e) = result {
+                tracing::warn!("Showing the broadcast on {mac} failed: {e:#}");
+            }
+        }
+        Ok(results.iter().filter(|(_, result)| result.is_ok()).count())
+    }
+
+    /// Shows `active` on those of `macs` it isn't shown on yet. The images
+    /// of each MAC are saved once, or again if it was rendered since, and
+    /// the marker is stored before any of them are replaced.
+    async fn show_broadcast(
+        &self,
+        active: &mut ActiveBroadcast,
+        macs: Vec<EpdMac>,
+    ) -> Result<Vec<(EpdMac, Result<(), AppError>)>, AppError> {
+        let mut results = Vec::new();
+        let mut pending = Vec::new();
+        for mac in macs {
+            let rendered_since = match active.targets.get(&mac) {
+                Some(target) => self.rendered_since(mac, target).await,
+                None => false,
+            };
+            match active.targets.get(&mac) {
+                Some(target) if target.shown && !rendered_since => results.push((mac, Ok(()))),
+                Some(_) if !rendered_since => pending.push(mac),
+                _ => match self.save_for_broadcast(mac).await {
+                    Ok(target) => {
+                        active.targets.insert(mac, target);
+                        pending.push(mac);
+                    }
+                    Err(e) => results.push((mac, Err(e))),
+                },
+            }
+        }
+        self.store_broadcast(active).await?;
+
+        let broadcast = &active.broadcast;
+        // Renders are limited by the render permits anyway
+        let shown: Vec<_> = stream::iter(pending)
+            .map(|mac| async move {
+                let options = RerenderOptions {
+                    render: broadcast.options.clone(),
+                    ..RerenderOptions::default()
+                };
+                let result = self
+                    .post_svg_body(mac, &broadcast.svg, options, Priority::Interactive)
+                    .await
+                    .map(|_| ());
+                (mac, result)
+            })
+            .buffered(self.config.max_concurrent_renders.max(1))
+            .collect()
+            .await;
+        for (mac, result) in &shown {
+            if let Some(target) = active.targets.get_mut(mac) {
+                target.shown = result.is_ok();
+                target.png_hash = match result {
+                    Ok(()) => self.png_hash(*mac).await,
+                    Err(_) => None,
+                };
+            }
+        }
+        self.store_broadcast(active).await?;
+        results.extend(shown);
+        results.sort_by_key(|(mac, _)| *mac);
+        Ok(results)
+    }
+
+    /// Copies the live render of `mac` for restoring it after a broadcast.
+    async fn save_for_broadcast(&self, mac: EpdMac) -> Result<Target, AppError> {
+        let _lock = self.lock_mac(mac).await;
+        for live in Artifact::LIVE_RENDER {
+            let saved = file_name(mac, live.saved_for_broadcast().expect("A live artifact"));
+            match self
+                .storage
+                .read_optional(&file_name(mac, live))
+                .await
+                .internal()?
+            {
+                Some(data) => self.storage.write_atomic(&saved, &data).await.internal()?,
+                None => {
+                    self.storage.remove_set(&[&saved]).await.internal()?;
+                }
+            }
+        }
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(Target {
+            shown: false,
+            rerender: meta.rerender,
+            last_render: meta.render_log.back().cloned(),
+            png_hash: None,
+        })
+    }
+
+    /// Restores the renders that the broadcast in progress replaced, except
+    /// those rendered again during it, and ends it, returning the results
+    /// ordered by MAC. The MACs that failed are restored by calling it
+    /// again.
+    pub async fn end_broadcast(&self) -> Result<Vec<(EpdMac, Result<(), AppError>)>, AppError> {
+        let _lock = self.broadcast_lock.lock().await;
+        let Some(mut active) = self.active_broadcast().await? else {
+            return Err(AppError::NotFound(eyre!("No broadcast is in progress.")));
+        };
+        let mut results = Vec::new();
+        for (mac, target) in std::mem::take(&mut active.targets) {
+            let result = self.restore_after_broadcast(mac, &target).await;
+            if result.is_err() {
+                active.targets.insert(mac, target);
+            }
+            results.push((mac, result));
+        }
+        if active.targets.is_empty() {
+            self.storage
+                .remove_set(&[BROADCAST_FILE])
+                .await
+                .internal()?;
+        } else {
+            self.store_broadcast(&active).await?;
+        }
+        Ok(results)
+    }
+
+    /// Whether another PNG than the broadcast was stored for `mac` since it
+    /// was shown.
+    async fn rendered_since(&self, mac: EpdMac, target: &Target) -> bool {
+        match &target.png_hash {
+            Some(hash) => self.png_hash(mac).await.as_ref() != Some(hash),
+            None => false,
+        }
+    }
+
+    /// Restores the render of `mac` saved by [`Self::save_for_broadcast`],
+    /// unless another one was rendered during the broadcast, which is newer.
+    async fn restore_after_broadcast(&self, mac: EpdMac, target: &Target) -> Result<(), AppError> {
+        let _lock = self.lock_mac(mac).await;
+        let saved: Vec<_> = Artifact::LIVE_RENDER
+            .into_iter()
+            .map(|live| {
+                let saved = live.saved_for_broadcast().expect("A live artifact");
+                (file_name(mac, live), file_name(mac, saved))
+            })
+            .collect();
+        let rendered_since = self.rendered_since(mac, target).await;
+        if rendered_since {
+            tracing::info!("Keeping the render of {mac} posted during the broadcast");
+        } else {
+            for (live, saved) in &saved {
+                match self.storage.read_optional(saved).await.internal()? {
+                    Some(data) => self.storage.write_atomic(live, &data).await.internal()?,
+                    None => {
+                        self.storage.remove_set(&[live]).await.internal()?;
+                    }
+                }
+            }
+            let rerender = target.rerender.clone();
+            let last_render = target.last_render.clone();
+            self.update_metadata(mac, |meta| {
+                meta.rerender = rerender;
+                if let Some(record) = last_render {
+                    meta.restore_render(record);
+                }
+            })
+            .await
+            .internal()?;
+        }
+        let saved: Vec<_> = saved.iter().map(|(_, saved)| saved.as_str()).collect();
+        self.storage.remove_set(&saved).await.internal()?;
+        if !rendered_since {
+            self.images_changed(EventKind::Render, mac, self.clock.now());
+        }
+        Ok(())
+    }
+
+    /// Renders the entries of all playlists that became active since their
+    /// last render and returns how many were rendered. Failed renders are
+    /// logged and retried on the next call. Nothing is rendered during a
+    /// broadcast.
+    pub async fn run_playlists(&self) -> Result<usize, AppError> {
+        if self.active_broadcast().await?.is_some() {
+            return Ok(0);
+        }
+        let now = self.clock.now();
+        let mut rendered = 0;
+        for mac in self.meta_macs().await? {
+            let meta = match self.state.load(mac).await {
+                Ok(meta) => meta,
+                Err(e) => {
+                    tracing::warn!("Could not read metadata of {mac}: {e:#}");
+                    continue;
+                }
+            };
+            let Some(state) = meta.playlist else {
+                continue;
+            };
+            let tz = state.playlist.timezone.unwrap_or(self.config.timezone);
+            let active = state.playlist.active_at(now, tz);
+            let Some(index) = active.filter(|_| active != state.rendered) else {
+                continue;
+            };
+
+            let record = match self.render_entry(mac, &state.playlist, index, now).await {
+                Ok(record) => record,
+                Err(e) => {
+                    tracing::warn!("Playlist render of {mac} failed: {e}");
+                    continue;
+                }
+            };
+            tracing::info!(%mac, entry = index, "Playlist switched to another entry");
+
+            let result = self
+                .record_render(mac, record, |meta| {
+                    if let Some(state) = meta.playlist.as_mut() {
+                        state.rendered = Some(index);
+                    }
+                })
+                .await;
+            if let Err(e) = result {
+                tracing::warn!("Could not store metadata of {mac}: {e:#}");
+            }
+            rendered += 1;
+        }
+        Ok(rendered)
+    }
+
+    async fn render_entry(
+        &self,
+        mac: EpdMac,
+        playlist: &Playlist,
+        index: usize,
+        now: DateTime<Utc>,
+    ) -> Result<RenderRecord, AppError> {
+        let tz = playlist.timezone.unwrap_or(self.config.timezone);
+        let source = &playlist.entries[index].svg;
+        let fragment = schedule::substitute_now(source, now.with_timezone(&tz)).bad_request()?;
+        let rendered = self
+            .render_fragment(
+                mac,
+                fragment.as_deref().unwrap_or(source),
+                &RenderOverrides::default(),
+                Priority::Batch,
+            )
+            .await?;
+        Ok(rendered.record)
+    }
+
+    /// Checks that `svg_body` can be rendered without rendering it.
+    fn check_fragment(&self, svg_body: &str, now: DateTime<Tz>) -> eyre::Result<()> {
+        let fragment = schedule::substitute_now(svg_body, now)?;
+        let document = self.document(
+            fragment.as_deref().unwrap_or(svg_body),
+            DisplayProfile::default(),
+        )?;
+        usvg::Tree::from_data(&document, &self.svg_opts.to_ref())?;
+        Ok(())
+    }
+
+    /// Checks for every MAC with an SVG or PNG that the stored PNG is a
+    /// render of the stored SVG, allowing channels of pixels to differ by
+    /// `pixel_tolerance`. With `regenerate`, mismatched and missing PNGs are
+    /// rendered again.
+    pub async fn verify(
+        &self,
+        pixel_tolerance: u8,
+        regenerate: bool,
+    ) -> Result<Vec<VerifyReport>, AppError> {
+        let mut files: BTreeMap<EpdMac, (bool, bool)> = BTreeMap::new();
+        for name in self.storage.list().await.internal()?.into_iter().flatten() {
+            let (mac, is_svg) = match name.to_str().and_then(artifact::parse) {
+                Some((mac, Artifact::Svg)) => (mac, true),
+                Some((mac, Artifact::Png)) => (mac, false),
+                _ => continue,
+            };
+            let entry = files.entry(mac).or_default();
+            if is_svg {
+                entry.0 = true;
+            } else {
+                entry.1 = true;
+            }
+        }
+
+        // Renders are limited by the render permits anyway
+        let reports = stream::iter(files)
+            .map(|(mac, (has_svg, has_png))| {
+                self.verify_mac(mac, has_svg, has_png, pixel_tolerance, regenerate)
+            })
+            .buffered(self.config.max_concurrent_renders.max(1))
+            .collect()
+            .await;
+        Ok(reports)
+    }
+
+    /// Removes the temporary files of interrupted writes older than
+    /// `--temp-file-grace` and lists the files kept for an image that no
+    /// longer exists, removing them too with `delete_orphans`. Files not
+    /// named like the ones of the server are never touched.
+    pub async fn cleanup(&self, delete_orphans: bool) -> Result<CleanupReport, AppError> {
+        let mut report = CleanupReport::default();
+        // Object stores are written without temporary files
+        if self.config.storage.is_none() {
+            let before = SystemTime::now()
+                .checked_sub(self.config.temp_file_grace.get())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            report.temp_files = cleanup::remove_temp_files(
+                &self.config.image_dir,
+                self.config.shard_depth,
+                before,
+                |name| {
+                    cleanup::is_temp_file(name, |target| {
+                        artifact::parse(target).is_some() || target == BROADCAST_FILE
+                    })
+                },
+            )
+            .await
+            .internal()?;
+            if let Some(dedup) = self.storage.dedup() {
+                let objects = cleanup::remove_temp_files(dedup.objects_dir(), 0, before, |name| {
+                    cleanup::is_temp_file(name, dedup::is_hash)
+                })
+                .await
+                .internal()?;
+                report.temp_files.extend(objects);
+            }
+        }
+
+        let names: BTreeSet<String> = self
+            .storage
+            .list()
+            .await
+            .internal()?
+            .into_iter()
+            .flatten()
+            .filter_map(|name| name.into_string().ok())
+            .collect();
+        for name in &names {
+            let Some((mac, source)) = artifact::parse(name)
+                .and_then(|(mac, artifact)| Some((mac, artifact.depends_on()?)))
+            else {
+                continue;
+            };
+            if names.contains(&file_name(mac, source)) {
+                continue;
+            }
+            let bytes = match self.storage.metadata(name).await {
+                Ok(meta) => meta.len,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(AppError::from(e)),
+            };
+            if delete_orphans {
+                let _lock = self.lock_mac(mac).await;
+                // The image may have been stored again since it was listed
+                match self.storage.metadata(&file_name(mac, source)).await {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    _ => continue,
+                }
+                self.storage.remove_set(&[name]).await.internal()?;
+            }
+            report.orphans.push(CleanedFile {
+                name: name.clone(),
+                bytes,
+            });
+        }
+        // After the orphans, whose contents are unreferenced now
+        if let Some(dedup) = self.storage.dedup() {
+            let objects = dedup.collect_garbage(delete_orphans).await.internal()?;
+            report.orphans.extend(objects);
+        }
+        report.orphans_removed = delete_orphans;
+        report.count_removed();
+        Ok(report)
+    }
 
-pub(crate) struct ImageHandler {
-    config: Config,
-    svg_opts: usvg::Options,
-}
+    /// Bytes saved by `--dedup`, if it is set and the objects could be read.
+    pub async fn dedup_usage(&self) -> Option<DedupUsage> {
+        let dedup = self.storage.dedup()?;
+        dedup
+            .usage()
+            .await
+            .map_err(|e| tracing::warn!("Could not read the objects of --dedup: {e}"))
+            .ok()
+    }
 
-impl ImageHandler {
-    pub fn new(config: Config) -> Self {
-        let mut svg_opts = usvg::Options::default();
-        svg_opts.fontdb.load_system_fonts();
-
-        ImageHandler { config, svg_opts }
-    }
-
-    pub async fn get_macs(&self) -> Result<Vec<EpdMac>, AppError> {
-        let image_dir = self.config.image_dir.clone();
-
-        task::spawn_blocking::<_, Result<Vec<EpdMac>, eyre::Error>>(move || {
-            read_dir(image_dir)?
-                .flatten()
-                .filter_map(|f| {
-                    let path = f.path();
-                    match path.extension() {
-                        Some(ext) if ext.to_str()? == "png" => {
-                            Some(f.path().file_stem()?.to_str()?.parse::<EpdMac>())
-                        }
-                        _ => None,
-                    }
-                })
-                .collect()
+    /// Links the images written before `--dedup` was set to their objects.
+    /// Safe to run again, files that are linked already are skipped.
+    pub async fn migrate_dedup(&self) -> Result<DedupReport, AppError> {
+        let Some(dedup) = self.storage.dedup() else {
+            return Err(AppError::BadRequest(eyre!(
+                "Images are only deduplicated with --dedup"
+            )));
+        };
+        let names = self.storage.list().await.internal()?;
+        let mut migrated = 0;
+        for name in names.into_iter().flatten() {
+            let Ok(name) = name.into_string() else {
+                continue;
+            };
+            let Some((mac, _)) = artifact::parse(&name) else {
+                continue;
+            };
+            let _lock = self.lock_mac(mac).await;
+            match dedup.migrate(&name).await {
+                Ok(true) => migrated += 1,
+                Ok(false) => {}
+                // Deleted since it was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(AppError::from(e)),
+            }
+        }
+        tracing::info!("Linked {migrated} images to their contents");
+        Ok(DedupReport {
+            migrated,
+            usage: dedup.usage().await.internal()?,
         })
-        .await
-        .map_err(|e| AppError::InternalServerError(e.into()))?
-        .map_err(AppError::InternalServerError)
     }
 
-    pub async fn get_svg(&self, mac: EpdMac) -> Result<ReaderStream<File>, AppError> {
-        let image_dir = self.config.image_dir.clone();
+    async fn verify_mac(
+        &self,
+        mac: EpdMac,
+        has_svg: bool,
+        has_png: bool,
+        pixel_tolerance: u8,
+        regenerate: bool,
+    ) -> VerifyReport {
+        let mut report = VerifyReport {
+            mac: mac.to_string(),
+            status: VerifyStatus::Ok,
+            differing_pixels: None,
+            error: None,
+            regenerated: false,
+        };
+        if !has_svg {
+            report.status = VerifyStatus::OrphanPng;
+            return report;
+        }
+
+        let rendered = match self.storage.read(&file_name(mac, Artifact::Svg)).await {
+            Ok(svg) => match self.last_render_options(mac).await {
+                Ok(options) => self.render_png(mac, &svg, &options, Priority::Batch).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(image_error(e, mac, Artifact::Svg)),
+        };
+        let rendered = match rendered {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                report.status = VerifyStatus::Unreadable;
+                report.error = Some(e.to_string());
+                return report;
+            }
+        };
+
+        if has_png {
+            let stored = match self.storage.read(&file_name(mac, Artifact::Png)).await {
+                Ok(stored) => stored,
+                Err(e) => {
+                    report.status = VerifyStatus::Unreadable;
+                    report.error = Some(image_error(e, mac, Artifact::Png).to_string());
+                    return report;
+                }
+            };
+            match verify::differing_pixels(&stored, &rendered, pixel_tolerance) {
+                Ok(0) => return report,
+                Ok(differing) => report.differing_pixels = Some(differing),
+                Err(e) => report.error = Some(format!("{e:#}")),
+            }
+            report.status = VerifyStatus::Mismatch;
+        } else {
+            report.status = VerifyStatus::OrphanSvg;
+        }
 
-        let svg_path = image_dir.join(mac.to_string().to_lowercase() + SVG_EXT);
-        self.get_file(svg_path).await
+        if regenerate {
+            match self
+                .storage
+                .write_atomic(&file_name(mac, Artifact::Png), &rendered)
+                .await
+            {
+                Ok(()) => {
+                    report.regenerated = true;
+                    self.images_changed(EventKind::Render, mac, self.clock.now());
+                }
+                Err(e) => {
+                    tracing::warn!("Could not regenerate PNG of {mac}: {e}");
+                    report.error = Some(format!("Could not regenerate PNG of MAC {mac}."));
+                }
+            }
+        }
+        report
     }
 
-    pub async fn get_png(&self, mac: EpdMac) -> Result<ReaderStream<File>, AppError> {
-        let image_dir = self.config.image_dir.clone();
+    /// MACs with stored metadata.
+    async fn meta_macs(&self) -> Result<Vec<EpdMac>, AppError> {
+        self.state.macs().await.internal()
+    }
 
-        let png_path = image_dir.join(mac.to_string().to_lowercase() + PNG_EXT);
-        self.get_file(png_path).await
+    /// Re-renders `mac` with the parameters of its last render: the source
+    /// of a scheduled render with fresh time placeholders, otherwise the
+    /// stored SVG. Returns whether the PNG changed.
+    pub async fn rerender(&self, mac: EpdMac, priority: Priority) -> Result<bool, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        let now = self.clock.now();
+        let record = match &meta.rerender {
+            Some(rerender) => self.render_scheduled(mac, rerender, now, priority).await?,
+            None => {
+                let started = Instant::now();
+                let svg = self
+                    .storage
+                    .read(&file_name(mac, Artifact::Svg))
+                    .await
+                    .map_err(|e| image_error(e, mac, Artifact::Svg))?;
+                let options = RenderOptions::resolve(
+                    &self.config,
+                    meta.display_profile,
+                    &meta.render_options,
+                    &RenderOverrides::default(),
+                );
+                self.render_document(mac, svg, &options, started, priority)
+                    .await?
+                    .record
+            }
+        };
+        let changed = record.changed;
+        let post_render = self.check_post_render(mac, &record);
+        self.record_render(mac, record, |meta| {
+            if let Some(rerender) = meta.rerender.as_mut() {
+                rerender.last_render = now;
+            }
+        })
+        .await
+        .internal()?;
+        post_render?;
+        Ok(changed)
     }
 
-    async fn get_file(&self, path: impl AsRef<Path>) -> Result<ReaderStream<File>, AppError> {
-        let file = File::open(path)
+    /// MACs with a stored SVG, in ascending order.
+    pub async fn svg_macs(&self) -> Result<Vec<EpdMac>, AppError> {
+        let mut macs: Vec<EpdMac> = self
+            .storage
+            .list()
             .await
-            .map_err(|e| AppError::NotFound(e.into()))?;
-        Ok(ReaderStream::new(file))
+            .internal()?
+            .into_iter()
+            .flatten()
+            .filter_map(|name| match artifact::parse(name.to_str()?)? {
+                (mac, Artifact::Svg) => Some(mac),
+                _ => None,
+            })
+            .collect();
+        macs.sort();
+        Ok(macs)
     }
 
-    pub async fn delete_images(&self, mac: EpdMac) -> Result<(), AppError> {
-        let image_dir = self.config.image_dir.clone();
+    async fn render_scheduled(
+        &self,
+        mac: EpdMac,
+        rerender: &Rerender,
+        now: DateTime<Utc>,
+        priority: Priority,
+    ) -> Result<RenderRecord, AppError> {
+        let tz = rerender.timezone.unwrap_or(self.config.timezone);
+        let fragment =
+            schedule::substitute_now(&rerender.source, now.with_timezone(&tz)).bad_request()?;
+        let mut fragment = fragment.unwrap_or_else(|| rerender.source.clone());
+        if rerender.substitute {
+            let profile = self
+                .render_options(mac, &rerender.overrides)
+                .await?
+                .profile();
+            fragment = self.substitute(mac, &fragment, &tz, profile);
+        }
+        let rendered = self
+            .render_fragment(mac, &fragment, &rerender.overrides, priority)
+            .await?;
+        Ok(rendered.record)
+    }
 
-        let png_path = image_dir.join(mac.to_string().to_lowercase() + PNG_EXT);
-        let bmp_path = image_dir.join(mac.to_string().to_lowercase() + BMP_EXT);
-        let svg_path = image_dir.join(mac.to_string().to_lowercase() + SVG_EXT);
+    async fn render_fragment(
+        &self,
+        mac: EpdMac,
+        svg_body: &str,
+        overrides: &RenderOverrides,
+        priority: Priority,
+    ) -> Result<Rendered, AppError> {
+        let started = Instant::now();
+        let options = self.render_options(mac, overrides).await?;
+        let document = self.document(svg_body, options.profile()).internal()?;
+        let PreparedDocument {
+            buf,
+            original,
+            report,
+        } = self.prepare_document(document, &options)?;
+        let mut rendered = self
+            .render_document(mac, buf, &options, started, priority)
+            .await?;
+        rendered.record.color_map = report;
+        self.keep_original(mac, original.as_deref()).await?;
+        Ok(rendered)
+    }
+
+    /// The options of a render of `mac` with the `request` overrides, see
+    /// [`RenderOptions::resolve`].
+    async fn render_options(
+        &self,
+        mac: EpdMac,
+        request: &RenderOverrides,
+    ) -> Result<RenderOptions, AppError> {
+        Ok(self.render_settings(mac, request).await?.0)
+    }
+
+    /// Like [`Self::render_options`], together with the lint thresholds of
+    /// `mac`.
+    async fn render_settings(
+        &self,
+        mac: EpdMac,
+        request: &RenderOverrides,
+    ) -> Result<(RenderOptions, LintThresholds), AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        let options = RenderOptions::resolve(
+            &self.config,
+            meta.display_profile,
+            &meta.render_options,
+            request,
+        );
+        Ok((options, LintThresholds::resolve(&self.config, &meta.lint)))
+    }
+
+    /// Warnings about content of the SVG `document` that won't survive the
+    /// render with `options`. Documents that don't parse fail to render
+    /// anyway, so they have none.
+    fn lint(
+        &self,
+        document: &[u8],
+        options: &RenderOptions,
+        thresholds: LintThresholds,
+    ) -> Vec<LintWarning> {
+        let Ok(tree) = usvg::Tree::from_data(document, &self.svg_opts.to_ref()) else {
+            return Vec::new();
+        };
+        // Mapped colors and thresholded pixels are solid black or white
+        let dithered = options.dither == Dither::FloydSteinberg
+            && self.config.color_map(&options.color_map).is_none();
+        lint::lint(
+            document,
+            &tree,
+            &self.svg_opts.fontdb,
+            thresholds,
+            self.config.luminance_threshold,
+            dithered,
+        )
+    }
 
-        task::spawn_blocking(move || {
-            match (
-                remove_file(svg_path),
-                remove_file(bmp_path),
-                remove_file(png_path),
-            ) {
-                (Err(_), Err(_), Err(_)) => Err(AppError::NotFound(eyre!(
-                    "Could not find any images for MAC {}.",
-                    mac
-                ))),
-                _ => Ok(()),
+    /// Converts the text of `document` to paths and maps its colors as
+    /// `options` ask.
+    fn prepare_document(
+        &self,
+        document: Vec<u8>,
+        options: &RenderOptions,
+    ) -> Result<PreparedDocument, AppError> {
+        match (options.text, self.config.color_map(&options.color_map)) {
+            (TextMode::Live, None) => Ok(PreparedDocument {
+                buf: document,
+                original: None,
+                report: None,
+            }),
+            (_, color_map) => {
+                let (converted, report) = self
+                    .outline_text(&document, color_map.as_ref())
+                    .bad_request()?;
+                Ok(PreparedDocument {
+                    buf: converted,
+                    original: Some(document),
+                    report,
+                })
             }
-        })
-        .await
-        .map_err(|e| AppError::InternalServerError(e.into()))?
+        }
     }
 
-    pub async fn post_svg_body(&self, mac: EpdMac, svg_body: &str) -> Result<(), AppError> {
-        let image_dir = self.config.image_dir.clone();
+    /// Converts the text of the SVG document `buf` to paths, with the fonts
+    /// installed now, and maps its colors with `color_map`.
+    fn outline_text(
+        &self,
+        buf: &[u8],
+        color_map: Option<&ColorMap>,
+    ) -> eyre::Result<(Vec<u8>, Option<ColorMapReport>)> {
+        let tree = usvg::Tree::from_data(buf, &self.svg_opts.to_ref())?;
+        let report = color_map.map(|color_map| color_map.apply(&tree));
+        let mut outlined = vec![];
+        if self.config.xml_declaration {
+            writeln!(outlined, "{XML_DECLARATION}")?;
+        }
+        outlined.extend_from_slice(tree.to_string(&usvg::XmlOptions::default()).as_bytes());
+        Ok((outlined, report))
+    }
 
-        let svg_path = image_dir.join(mac.to_string().to_lowercase() + SVG_EXT);
-        let png_path = image_dir.join(mac.to_string().to_lowercase() + PNG_EXT);
+    /// Keeps the posted document of `mac` if its stored SVG was converted,
+    /// or removes the one of an earlier render.
+    async fn keep_original(&self, mac: EpdMac, original: Option<&[u8]>) -> Result<(), AppError> {
+        let name = file_name(mac, Artifact::OriginalSvg);
+        match original {
+            Some(original) => self.storage.write_atomic(&name, original).await.internal(),
+            None => self.storage.remove_set(&[&name]).await.map(drop).internal(),
+        }
+    }
 
-        let mut file = File::create(svg_path)
+    /// The SVG document as posted, which differs from the stored one if its
+    /// text was converted to paths.
+    pub async fn get_original_svg(&self, mac: EpdMac) -> Result<ByteStream, AppError> {
+        let chunk_size = self.config.stream_chunk_bytes.as_usize();
+        match self
+            .storage
+            .open_with_meta(&file_name(mac, Artifact::OriginalSvg), chunk_size)
             .await
-            .map_err(|e| AppError::InternalServerError(e.into()))?;
+        {
+            Ok((stream, _)) => Ok(stream),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.get_svg(mac).await,
+            Err(e) => Err(image_error(e, mac, Artifact::Svg)),
+        }
+    }
 
+    /// Wraps an SVG fragment into a document of the panel's size.
+    /// Replaces the tokens of [`substitute`] in `fragment` for a render of
+    /// `mac` now, with the canvas of `profile`.
+    fn substitute(&self, mac: EpdMac, fragment: &str, tz: &Tz, profile: DisplayProfile) -> String {
+        let (width, height) = profile.canvas_size(self.config.epd_width, self.config.epd_height);
+        let context = substitute::Context {
+            mac,
+            rendered_at: self.clock.now().with_timezone(tz),
+            time_format: &self.config.rendered_at_format,
+            width,
+            height,
+        };
+        substitute(fragment, &context)
+    }
+
+    fn document(&self, svg_body: &str, profile: DisplayProfile) -> io::Result<Vec<u8>> {
         let mut buf = vec![];
+        if self.config.xml_declaration {
+            writeln!(buf, "{XML_DECLARATION}")?;
+        }
+        let (width, height) = profile.canvas_size(self.config.epd_width, self.config.epd_height);
         write!(
             buf,
-            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {} {}\">",
-            self.config.epd_width, self.config.epd_height
-        )
-        .map_err(|e| AppError::InternalServerError(e.into()))?;
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {width} {height}\">"
+        )?;
         buf.extend_from_slice(svg_body.as_bytes());
-        write!(buf, "</svg>").map_err(|e| AppError::InternalServerError(e.into()))?;
+        write!(buf, "</svg>")?;
+        Ok(buf)
+    }
 
+    /// Renders the complete SVG document `buf` of `mac` into a PNG, put onto
+    /// the panel by the profile of `options` before it is quantized.
+    async fn render_png(
+        &self,
+        mac: EpdMac,
+        buf: &[u8],
+        options: &RenderOptions,
+        priority: Priority,
+    ) -> Result<Vec<u8>, AppError> {
+        let _render = self.watchdog.start(mac);
+        let _permit = self.render_queue.acquire(priority).await.internal()?;
         // https://docs.rs/tokio/latest/tokio/fn.spawn.html#using-send-values-from-a-task
         // Could not get to work with `spawn_blocking`
+        let context = PanicContext::new("render", mac).source(buf);
+        self.panics.call(&context, || self.rasterize(buf, options))
+    }
+
+    fn rasterize(&self, buf: &[u8], options: &RenderOptions) -> Result<Vec<u8>, AppError> {
+        #[cfg(feature = "chaos")]
+        debug_assert!(
+            !buf.windows(crate::chaos::PANIC_MARKER.len())
+                .any(|window| window == crate::chaos::PANIC_MARKER),
+            "Injected panic"
+        );
+        let rtree = usvg::Tree::from_data(buf, &self.svg_opts.to_ref()).bad_request()?;
+
+        // Stored documents keep the canvas of the panel size they were posted
+        // at, so they are scaled to the current one
+        let profile = options.profile();
+        let (width, height) = profile.canvas_size(self.config.epd_width, self.config.epd_height);
+        let fit_to = if rtree.svg_node().size.to_screen_size().dimensions() == (width, height) {
+            usvg::FitTo::Original
+        } else {
+            usvg::FitTo::Size(width, height)
+        };
+        let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or_else(|| {
+            AppError::BadRequest(eyre!(
+                "The canvas is {width}x{height} pixels, which can't be rendered."
+            ))
+        })?;
+        resvg::render(
+            &rtree,
+            fit_to,
+            tiny_skia::Transform::default(),
+            pixmap.as_mut(),
+        )
+        .ok_or_else(|| AppError::InternalServerError(eyre!("Could not render svg!")))?;
+
+        let palette = self.palette();
+        if palette.is_none() && profile.is_identity() {
+            return pixmap.encode_png().internal();
+        }
+        let image = RgbaImage::from_fn(pixmap.width(), pixmap.height(), |x, y| {
+            let color = pixmap.pixel(x, y).unwrap().demultiply();
+            Rgba([color.red(), color.green(), color.blue(), color.alpha()])
+        });
+        let image = profile.apply(image);
+        let Some(palette) = palette else {
+            let mut png = io::Cursor::new(vec![]);
+            image.write_to(&mut png, ImageFormat::Png).internal()?;
+            return Ok(png.into_inner());
+        };
+        let quantized = palette.quantize(&image.into(), options.dither);
+        let mut png = io::Cursor::new(vec![]);
+        quantized.write_to(&mut png, ImageFormat::Png).internal()?;
+        Ok(png.into_inner())
+    }
+
+    /// Renders the complete SVG document `buf` and stores it with its PNG.
+    async fn render_document(
+        &self,
+        mac: EpdMac,
+        buf: Vec<u8>,
+        options: &RenderOptions,
+        started: Instant,
+        priority: Priority,
+    ) -> Result<Rendered, AppError> {
+        let png = self.render_png(mac, &buf, options, priority).await?;
+        let mut rendered = self.store_render(mac, &buf, png, started).await?;
+        rendered.record.options = Some(options.clone());
+        Ok(rendered)
+    }
+
+    /// Stores the SVG document `buf` of `mac` with its rendered `png`.
+    async fn store_render(
+        &self,
+        mac: EpdMac,
+        buf: &[u8],
+        png: Vec<u8>,
+        started: Instant,
+    ) -> Result<Rendered, AppError> {
+        let _lock = self.lock_mac(mac).await;
+        self.write_render(mac, buf, png, started).await
+    }
+
+    /// Like [`Self::store_render`], for callers holding the lock of `mac`.
+    async fn write_render(
+        &self,
+        mac: EpdMac,
+        buf: &[u8],
+        png: Vec<u8>,
+        started: Instant,
+    ) -> Result<Rendered, AppError> {
+        let svg_name = file_name(mac, Artifact::Svg);
+        let png_name = file_name(mac, Artifact::Png);
+
+        let previous = self.storage.read_optional(&png_name).await.ok().flatten();
+        let created = previous.is_none();
+        let changed = !matches!(&previous, Some(previous) if *previous == png);
+        let changed_pixels = match &previous {
+            Some(_) if !changed => Some(0),
+            Some(previous) => refresh::changed_pixels(previous, &png),
+            None => None,
+        };
+        if let Some(previous) = previous.as_ref().filter(|_| changed) {
+            self.keep_previous(mac, previous).await?;
+        }
+        self.storage
+            .write_atomic(&png_name, &png)
+            .await
+            .internal()?;
+        self.storage.write_atomic(&svg_name, buf).await.internal()?;
+
+        let timestamp = self.clock.now();
+        self.images_changed(EventKind::Render, mac, timestamp);
+        let mut record = RenderRecord {
+            timestamp,
+            duration_ms: started.elapsed().as_millis() as u64,
+            svg_bytes: buf.len(),
+            png_bytes: png.len(),
+            changed,
+            changed_pixels,
+            coverage: coverage::analyze(&png),
+            color_map: None,
+            lint: Vec::new(),
+            options: None,
+            post_render: None,
+            pipeline: self.pipeline.clone(),
+        };
+        if let Some(hook) = &self.post_render {
+            record.post_render = Some(hook.run(&self.storage, mac, &png, &record).await);
+        }
+        Ok(Rendered {
+            record,
+            png,
+            created,
+        })
+    }
+
+    /// Waits until no other task replaces the live images of `mac`.
+    async fn lock_mac(&self, mac: EpdMac) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = self
+            .mac_locks
+            .lock()
+            .unwrap()
+            .entry(mac)
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Keeps `previous`, the PNG of `mac` that is about to be replaced.
+    async fn keep_previous(&self, mac: EpdMac, previous: &[u8]) -> Result<(), AppError> {
+        self.storage
+            .write_atomic(&file_name(mac, Artifact::PreviousPng), previous)
+            .await
+            .internal()
+    }
+
+    /// The changes of the last update of `mac` as a PNG, see
+    /// [`raster::ghost_diff`].
+    pub async fn diff(&self, mac: EpdMac, against: Against) -> Result<Vec<u8>, AppError> {
+        if let Against::Version(timestamp) = against {
+            return Err(AppError::NoComparisonImage(eyre!(
+                "No history is kept, so there is no version of {timestamp}. Only \
+                 against=previous is available."
+            )));
+        }
+        let current = self
+            .lookup(
+                mac,
+                Artifact::Png,
+                self.storage.read(&file_name(mac, Artifact::Png)),
+            )
+            .await?;
+        let previous = self
+            .storage
+            .read_optional(&file_name(mac, Artifact::PreviousPng))
+            .await
+            .internal()?
+            .ok_or_else(|| {
+                AppError::NoComparisonImage(eyre!("MAC {mac} has no previous image to compare."))
+            })?;
+
+        let context = PanicContext::new("comparison", mac).source(&current);
+        self.panics
+            .spawn_blocking(context, move || {
+                let load = |png: &[u8]| image::load_from_memory_with_format(png, ImageFormat::Png);
+                let diff =
+                    raster::ghost_diff(&load(&previous).internal()?, &load(&current).internal()?)
+                        .ok_or_else(|| {
+                        AppError::NoComparisonImage(eyre!(
+                            "The previous image of MAC {mac} has a different size."
+                        ))
+                    })?;
+                let mut png = io::Cursor::new(vec![]);
+                diff.write_to(&mut png, ImageFormat::Png).internal()?;
+                Ok(png.into_inner())
+            })
+            .await
+    }
+
+    /// Appends `record` to the render log of `mac` after `update` has been
+    /// applied to the stored metadata.
+    async fn record_render(
+        &self,
+        mac: EpdMac,
+        record: RenderRecord,
+        update: impl FnOnce(&mut MacMetadata) + Send,
+    ) -> eyre::Result<()> {
+        {
+            let mut render_logs = self.render_logs.lock().unwrap();
+            let durations = render_logs.entry(mac).or_default();
+            if durations.len() == RENDER_LOG_LEN {
+                durations.pop_front();
+            }
+            durations.push_back(record.duration_ms);
+        }
+        if let Some(coverage) = &record.coverage {
+            let mut coverages = self.coverages.lock().unwrap();
+            coverages.insert(mac, coverage.percent);
+        }
+
+        self.update_metadata(mac, |meta| {
+            update(meta);
+            meta.push_render(record);
+        })
+        .await
+    }
+
+    async fn update_metadata(
+        &self,
+        mac: EpdMac,
+        update: impl FnOnce(&mut MacMetadata) + Send,
+    ) -> eyre::Result<()> {
+        self.state.update(mac, Box::new(update)).await
+    }
+
+    /// Failed renders of `mac` in a row, read from the metadata once.
+    async fn render_failures(&self, mac: EpdMac) -> Option<RenderFailures> {
+        if let Some(failures) = self.render_failures.lock().unwrap().get(&mac) {
+            return failures.clone();
+        }
+        let failures = match self.state.load(mac).await {
+            Ok(meta) => meta.render_failures,
+            Err(e) => {
+                tracing::warn!("Could not read metadata of {mac}: {e:#}");
+                return None;
+            }
+        };
+        self.render_failures
+            .lock()
+            .unwrap()
+            .insert(mac, failures.clone());
+        failures
+    }
+
+    /// The failures of the source that `mac` refuses to render, if any.
+    pub async fn quarantined(&self, mac: EpdMac) -> Option<RenderFailures> {
+        let threshold = self.config.quarantine_after;
+        self.render_failures(mac)
+            .await
+            .filter(|failures| threshold > 0 && failures.count >= threshold)
+    }
+
+    async fn record_failure(&self, mac: EpdMac, source_hash: &str) {
+        let failures = match self.render_failures(mac).await {
+            Some(failures) if failures.source_hash == source_hash => RenderFailures {
+                count: failures.count + 1,
+                ..failures
+            },
+            _ => RenderFailures {
+                source_hash: source_hash.to_owned(),
+                count: 1,
+            },
+        };
+        if failures.count == self.config.quarantine_after {
+            tracing::warn!(
+                "Quarantining SVG {source_hash} of {mac} after {} failed renders",
+                failures.count
+            );
+        }
+        self.set_render_failures(mac, Some(failures)).await;
+    }
+
+    async fn set_render_failures(&self, mac: EpdMac, failures: Option<RenderFailures>) {
+        self.render_failures
+            .lock()
+            .unwrap()
+            .insert(mac, failures.clone());
+        if let Err(e) = self
+            .update_metadata(mac, |meta| meta.render_failures = failures)
+            .await
         {
-            let rtree = usvg::Tree::from_data(&buf, &self.svg_opts.to_ref())
-                .map_err(|e| AppError::BadRequest(e.into()))?;
-
-            let pixmap_size = rtree.svg_node().size.to_screen_size();
-            let mut pixmap =
-                tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()).unwrap();
-            resvg::render(
-                &rtree,
-                usvg::FitTo::Original,
-                tiny_skia::Transform::default(),
-                pixmap.as_mut(),
+            tracing::warn!("Could not store render failures of {mac}: {e:#}");
+        }
+    }
+
+    /// Lets `mac` render its quarantined source again. Fails if nothing was
+    /// quarantined.
+    pub async fn clear_quarantine(&self, mac: EpdMac) -> Result<(), AppError> {
+        if self.quarantined(mac).await.is_none() {
+            return Err(AppError::NotFound(eyre!(
+                "No SVG of MAC {mac} is quarantined."
+            )));
+        }
+        self.set_render_failures(mac, None).await;
+        Ok(())
+    }
+
+    pub async fn get_render_log(&self, mac: EpdMac) -> Result<Vec<RenderRecord>, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(meta.render_log.into())
+    }
+
+    /// Validators of the bundle of `mac`, `None` if it has neither an SVG
+    /// nor a PNG.
+    pub async fn bundle_validators(
+        &self,
+        mac: EpdMac,
+        include: &Include,
+    ) -> Result<Option<Validators>, AppError> {
+        let mut files = vec![];
+        for artifact in [Artifact::Svg, Artifact::Png] {
+            files.push(
+                match self.storage.metadata(&file_name(mac, artifact)).await {
+                    Ok(meta) => Some(Validators::from_metadata(&meta, artifact.suffix())),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(image_error(e, mac, artifact)),
+                },
+            );
+        }
+        let meta = self.state.version(mac).await.internal()?;
+        files.push(meta.map(|meta| Validators::from_metadata(&meta, Artifact::Meta.suffix())));
+        if files[0].is_none() && files[1].is_none() {
+            return Ok(None);
+        }
+        let files = [files[0].as_ref(), files[1].as_ref(), files[2].as_ref()];
+        // The display profile is covered by the validators of the metadata
+        let profile = self.profile(
+            DisplayProfile::default(),
+            Regions::new(),
+            LintOverrides::default(),
+        );
+        Ok(bundle::validators(files, include, &profile))
+    }
+
+    /// The images and metadata of `mac` in one document for gateways, with
+    /// the entity tag from [`Self::bundle_validators`].
+    pub async fn bundle(
+        &self,
+        mac: EpdMac,
+        include: &Include,
+        etag: String,
+    ) -> Result<Bundle, AppError> {
+        let metadata = self.state.load(mac).await.internal()?;
+        let read = |artifact| async move {
+            match self.storage.read(&file_name(mac, artifact)).await {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(image_error(e, mac, artifact)),
+            }
+        };
+        let svg = read(Artifact::Svg).await?;
+        let png = read(Artifact::Png).await?;
+
+        let mut bundle = Bundle {
+            mac: mac.to_string(),
+            etag,
+            profile: self.profile(
+                metadata.display_profile,
+                metadata.regions.clone(),
+                metadata.lint.clone(),
+            ),
+            metadata,
+            content_hash: svg.as_ref().map(|svg| hex::encode(Sha256::digest(svg))),
+            svg: None,
+            png: None,
+            raw: None,
+            omitted: vec![],
+        };
+        let max_bytes = self.config.bundle_max_bytes.get();
+        if let (true, Some(svg)) = (include.parts.contains(&Part::Svg), &svg) {
+            bundle.insert(Part::Svg, svg, include, max_bytes);
+        }
+        if let (true, Some(png)) = (include.parts.contains(&Part::Png), &png) {
+            bundle.insert(Part::Png, png, include, max_bytes);
+        }
+        if include.parts.contains(&Part::Raw) && png.is_some() {
+            let raw = self.get_raw(mac).await?;
+            bundle.insert(Part::Raw, &raw, include, max_bytes);
+        }
+        Ok(bundle)
+    }
+
+    fn profile(&self, display: DisplayProfile, regions: Regions, lint: LintOverrides) -> Profile {
+        Profile {
+            width: self.config.epd_width,
+            height: self.config.epd_height,
+            palette: self.palette().map(Palette::to_string),
+            display,
+            regions,
+            lint,
+        }
+    }
+
+    /// The panel of `mac` with its display profile, which renders without
+    /// overrides use, its regions and lint thresholds.
+    pub async fn get_profile(&self, mac: EpdMac) -> Result<Profile, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(self.profile(meta.display_profile, meta.regions, meta.lint))
+    }
+
+    /// Stores the display profile of `mac`, used from its next render on,
+    /// its regions, which must be within the panel, and its lint thresholds.
+    pub async fn put_profile(
+        &self,
+        mac: EpdMac,
+        display: DisplayProfile,
+        regions: Regions,
+        lint: LintOverrides,
+    ) -> Result<Profile, AppError> {
+        lint.validate()
+            .wrap_err("Invalid lint thresholds")
+            .bad_request()?;
+        for (name, region) in &regions {
+            region
+                .check(self.config.epd_width, self.config.epd_height)
+                .wrap_err_with(|| format!("Invalid region {name}"))
+                .bad_request()?;
+        }
+        let (stored, stored_lint) = (regions.clone(), lint.clone());
+        self.update_metadata(mac, |meta| {
+            meta.display_profile = display;
+            meta.regions = stored;
+            meta.lint = stored_lint;
+        })
+        .await
+        .internal()?;
+        Ok(self.profile(display, regions, lint))
+    }
+
+    /// The region `name` of `mac`, aligned to the bytes of the raw image for
+    /// `mime`.
+    pub async fn region(
+        &self,
+        mac: EpdMac,
+        name: &str,
+        mime: &Mime,
+        align: Align,
+    ) -> Result<Region, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        let region = *meta
+            .regions
+            .get(name)
+            .ok_or_else(|| AppError::NotFound(eyre!("MAC {mac} has no region {name}")))?;
+        if *mime != mime::APPLICATION_OCTET_STREAM {
+            return Ok(region);
+        }
+        let pixels_per_byte = if self.palette().is_some() { 2 } else { 8 };
+        region
+            .align(pixels_per_byte, align, self.config.epd_width)
+            .bad_request()
+    }
+
+    /// The stored PNG of `mac` cropped to `region`, as PNG or packed like
+    /// [`Self::get_raw`].
+    pub async fn crop(&self, mac: EpdMac, region: Region, mime: &Mime) -> Result<Bytes, AppError> {
+        let png_name = file_name(mac, Artifact::Png);
+        let png = self
+            .lookup(mac, Artifact::Png, self.storage.read(&png_name))
+            .await?;
+        let palette = self.palette().cloned();
+        let raw = *mime == mime::APPLICATION_OCTET_STREAM;
+        let context = PanicContext::new("crop", mac).source(&png);
+        let data = self
+            .panics
+            .spawn_blocking(context, move || {
+                let image =
+                    image::load_from_memory_with_format(&png, ImageFormat::Png).internal()?;
+                let cropped = region.crop(&image);
+                if raw {
+                    return DerivedFormat::Raw
+                        .convert(&cropped, palette.as_ref())
+                        .internal();
+                }
+                let mut png = io::Cursor::new(vec![]);
+                cropped.write_to(&mut png, ImageFormat::Png).internal()?;
+                Ok(png.into_inner())
+            })
+            .await?;
+        Ok(data.into())
+    }
+
+    /// Stores render options of `mac` that replace the configured ones and
+    /// those of its display profile, see [`RenderOptions::resolve`].
+    pub async fn put_render_options(
+        &self,
+        mac: EpdMac,
+        overrides: RenderOverrides,
+    ) -> Result<RenderOverrides, AppError> {
+        let stored = overrides.clone();
+        self.update_metadata(mac, |meta| meta.render_options = stored)
+            .await
+            .internal()?;
+        Ok(overrides)
+    }
+
+    pub async fn get_render_options(&self, mac: EpdMac) -> Result<RenderOverrides, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(meta.render_options)
+    }
+
+    /// The options of the last render of `mac`, which made its stored PNG,
+    /// or the current ones if it doesn't say.
+    async fn last_render_options(&self, mac: EpdMac) -> Result<RenderOptions, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        let last = meta
+            .render_log
+            .back()
+            .and_then(|record| record.options.clone());
+        Ok(last.unwrap_or_else(|| {
+            RenderOptions::resolve(
+                &self.config,
+                meta.display_profile,
+                &meta.render_options,
+                &RenderOverrides::default(),
             )
-            .ok_or_else(|| AppError::InternalServerError(eyre!("Could not render svg!")))?;
+        }))
+    }
+
+    /// Which refresh the device of `mac` should use for the current image.
+    pub async fn refresh_hint(&self, mac: EpdMac) -> Result<RefreshHint, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        let config = &self.config;
+        let thresholds = RefreshThresholds {
+            changed_percent: config.full_refresh_changed_percent,
+            max_partial: config.max_partial_refreshes,
+        };
+        let panel_pixels = u64::from(config.epd_width) * u64::from(config.epd_height);
+        Ok(RefreshHint::new(
+            &meta,
+            panel_pixels,
+            thresholds,
+            self.clock.now(),
+        ))
+    }
+
+    /// How often the device of `mac` should poll, see [`PollCadence`].
+    pub async fn poll_interval(&self, mac: EpdMac) -> Result<PollInterval, AppError> {
+        let meta = self.state.load(mac).await.internal()?;
+        Ok(PollInterval {
+            suggested_secs: self.suggest_poll(mac, &meta.poll),
+            cadence: meta.poll,
+        })
+    }
 
-            pixmap
-                .save_png(png_path)
-                .map_err(|e| AppError::InternalServerError(e.into()))?;
+    /// Sets or, with `None`, removes the poll interval that `mac` is told
+    /// regardless of its cadence.
+    pub async fn put_poll_override(
+        &self,
+        mac: EpdMac,
+        override_secs: Option<u32>,
+    ) -> Result<PollInterval, AppError> {
+        if override_secs == Some(0) {
+            return Err(AppError::BadRequest(eyre!(
+                "The poll interval must be at least one second."
+            )));
         }
+        self.update_metadata(mac, |meta| meta.poll.override_secs = override_secs)
+            .await
+            .internal()?;
+        self.poll_interval(mac).await
+    }
+
+    /// The suggestion for `cadence`, remembered for [`Self::poll_suggestions`].
+    fn suggest_poll(&self, mac: EpdMac, cadence: &PollCadence) -> Option<u32> {
+        // Suggested in whole seconds
+        let secs =
+            |limit: HumanDuration| u32::try_from(limit.get().as_secs().max(1)).unwrap_or(u32::MAX);
+        let limits = PollLimits {
+            min_secs: secs(self.config.poll_min),
+            max_secs: secs(self.config.poll_max),
+        };
+        let suggested = cadence.suggested_secs(limits);
+        let mut suggestions = self.poll_suggestions.lock().unwrap();
+        match suggested {
+            Some(secs) => suggestions.insert(mac, secs),
+            None => suggestions.remove(&mac),
+        };
+        suggested
+    }
+
+    /// The poll intervals last suggested to each MAC since startup.
+    pub fn poll_suggestions(&self) -> Vec<u64> {
+        let suggestions = self.poll_suggestions.lock().unwrap();
+        suggestions.values().map(|&secs| secs.into()).collect()
+    }
+
+    /// Records that the device of `mac` updated its panel with `refresh`.
+    pub async fn ack(&self, mac: EpdMac, refresh: Refresh) -> Result<(), AppError> {
+        if refresh == Refresh::Full {
+            let now = self.clock.now();
+            self.update_metadata(mac, |meta| meta.full_refresh = Some(now))
+                .await
+                .internal()?;
+        }
+        Ok(())
+    }
+
+    /// Logs renders that hang, see [`RenderWatchdog::scan`].
+    pub fn scan_renders(&self, now: Instant) -> Vec<StuckRender> {
+        self.watchdog.scan(now, self.render_queue.available())
+    }
+
+    pub fn panics(&self) -> &PanicSafe {
+        &self.panics
+    }
+
+    pub fn watchdog(&self) -> &RenderWatchdog {
+        &self.watchdog
+    }
+
+    pub fn dependencies(&self) -> &Dependencies {
+        &self.dependencies
+    }
+
+    /// The SVG of the latest render of `mac` as posted, with the options it
+    /// was rendered with, for forwarding it to `--replica-url`. Waits for a
+    /// render in progress to be logged.
+    pub async fn posted_render(
+        &self,
+        mac: EpdMac,
+    ) -> Result<Option<(Vec<u8>, RenderOptions)>, AppError> {
+        let _lock = self.lock_mac(mac).await;
+        let mut svg = None;
+        for artifact in [Artifact::OriginalSvg, Artifact::Svg] {
+            svg = self
+                .storage
+                .read_optional(&file_name(mac, artifact))
+                .await
+                .internal()?;
+            if svg.is_some() {
+                break;
+            }
+        }
+        let Some(svg) = svg else {
+            return Ok(None);
+        };
+        Ok(Some((svg, self.last_render_options(mac).await?)))
+    }
+
+    /// The replication of `--replica-url`, if it is set.
+    pub fn replicator(&self) -> Option<&Replicator> {
+        self.replicator.as_ref()
+    }
+
+    /// Runs the health probes of the integrations that are configured or
+    /// required. The object store of `--storage` has to list its objects
+    /// within [`PROBE_TIMEOUT`].
+    pub async fn check_dependencies(&self) {
+        // Required only with --storage, see ValidatedConfig
+        if self.config.storage.is_some() {
+            let result = match tokio::time::timeout(PROBE_TIMEOUT, self.storage.list()).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(e)) => Err(format!("Listing the objects failed: {e}")),
+                Err(_) => Err(format!(
+                    "Listing the objects took longer than {PROBE_TIMEOUT:?}"
+                )),
+            };
+            self.dependencies
+                .record(Dependency::Storage, result, self.clock.now());
+        }
+    }
+
+    #[cfg(test)]
+    pub fn render_queue(&self) -> &RenderQueue {
+        &self.render_queue
+    }
+
+    /// Renders waiting for a permit.
+    pub fn render_queue_depth(&self) -> QueueDepth {
+        self.render_queue.depth()
+    }
+
+    /// Durations in milliseconds of the most recent renders since startup, over
+    /// all MACs.
+    pub fn render_durations(&self) -> Vec<u64> {
+        self.render_logs
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Average coverage in percent of the latest renders since startup, one
+    /// per MAC, `None` before the first render.
+    pub fn average_coverage(&self) -> Option<f64> {
+        let coverages = self.coverages.lock().unwrap();
+        let sum: f64 = coverages.values().sum();
+        (!coverages.is_empty()).then(|| (sum * 100.0 / coverages.len() as f64).round() / 100.0)
+    }
+
+    pub async fn post_image(
+        &self,
+        mac: EpdMac,
+        format: ImageFormat,
+        data: Vec<u8>,
+        fit: Fit,
+    ) -> Result<(), AppError> {
+        check_upload_dimensions(&data, format)?;
+        self.store_raster(mac, move |width, height| {
+            let image = raster::decode(&data, format).bad_request()?;
+            Ok(raster::fit_to_panel(&image, width, height, fit))
+        })
+        .await
+    }
+
+    /// Stores a PNG that already has the panel's dimensions, or can be made
+    /// to with `autofix`.
+    pub async fn post_png(
+        &self,
+        mac: EpdMac,
+        data: Vec<u8>,
+        autofix: Option<Autofix>,
+    ) -> Result<(), AppError> {
+        check_upload_dimensions(&data, ImageFormat::Png)?;
+        self.store_raster(mac, move |width, height| {
+            let image = raster::decode(&data, ImageFormat::Png).bad_request()?;
+            raster::match_panel(image, width, height, autofix).map_err(AppError::DimensionMismatch)
+        })
+        .await
+    }
+
+    /// Dithers and stores the image produced by `convert` from the panel
+    /// dimensions.
+    async fn store_raster(
+        &self,
+        mac: EpdMac,
+        convert: impl FnOnce(u32, u32) -> Result<DynamicImage, AppError> + Send + 'static,
+    ) -> Result<(), AppError> {
+        let (width, height) = (self.config.epd_width, self.config.epd_height);
+        let dither = self.config.dither;
+        let palette = self.palette().cloned();
 
-        file.write_all(&buf)
+        let context = PanicContext::new("conversion", mac);
+        let png = self
+            .panics
+            .spawn_blocking(context, move || {
+                let image = convert(width, height)?;
+                let mut png = io::Cursor::new(vec![]);
+                match palette {
+                    Some(palette) => palette
+                        .quantize(&image, dither)
+                        .write_to(&mut png, ImageFormat::Png),
+                    None => {
+                        let mut gray = raster::flatten(&image);
+                        raster::dither(&mut gray, dither);
+                        gray.write_to(&mut png, ImageFormat::Png)
+                    }
+                }
+                .internal()?;
+                Ok(png.into_inner())
+            })
+            .await?;
+
+        let png_name = file_name(mac, Artifact::Png);
+        let changed = match self.storage.read_optional(&png_name).await.ok().flatten() {
+            Some(previous) if previous == png => false,
+            Some(previous) => {
+                self.keep_previous(mac, &previous).await?;
+                true
+            }
+            None => true,
+        };
+        self.storage
+            .write_atomic(&png_name, &png)
             .await
-            .map_err(|e| AppError::InternalServerError(e.into()))
+            .internal()?;
+        // The stored SVG no longer describes the current image
+        self.storage
+            .remove_set(&[&file_name(mac, Artifact::Svg)])
+            .await
+            .internal()?;
+        let now = self.clock.now();
+        self.images_changed(EventKind::Upload, mac, now);
+        if changed {
+            self.update_metadata(mac, |meta| meta.poll.observe(now))
+                .await
+                .internal()?;
+        }
+        Ok(())
+    }
+}
+
+/// What [`ImageHandler::diff`] compares the current image with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Against {
+    /// The image replaced by the last change.
+    Previous,
+    /// The image at an RFC 3339 timestamp.
+    Version(DateTime<Utc>),
+}
+
+impl FromStr for Against {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "previous" {
+            return Ok(Against::Previous);
+        }
+        let timestamp = DateTime::parse_from_rfc3339(s)
+            .wrap_err_with(|| format!("Expected previous or a timestamp, not '{s}'"))?;
+        Ok(Against::Version(timestamp.with_timezone(&Utc)))
+    }
+}
+
+/// Rejects uploads larger than [`raster::max_dimension`] or
+/// [`raster::max_pixels`] from their header, so that pixel bombs don't
+/// allocate anything.
+fn check_upload_dimensions(data: &[u8], format: ImageFormat) -> Result<(), AppError> {
+    let raster::Dimensions { width, height } = raster::probe(data, format).bad_request()?;
+    let max = raster::max_dimension(format);
+    if width > max || height > max {
+        return Err(AppError::PayloadTooLarge(eyre!(
+            "The {} upload is {width}x{height} pixels, more than the {max}x{max} that are decoded.",
+            format.to_mime_type()
+        )));
+    }
+    let max_pixels = raster::max_pixels(format);
+    if u64::from(width) * u64::from(height) > max_pixels {
+        return Err(AppError::PayloadTooLarge(eyre!(
+            "The {} upload is {width}x{height} pixels, more than the {max_pixels} pixels that are \
+             decoded.",
+            format.to_mime_type()
+        )));
     }
+    Ok(())
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// Converts an error reading the image `artifact` of `mac` into a client
+/// message without any server paths.
+fn image_error(e: io::Error, mac: EpdMac, artifact: Artifact) -> AppError {
+    let kind = artifact.kind();
+    let message = match e.kind() {
+        io::ErrorKind::NotFound => format!("No {kind} image for MAC {mac}."),
+        _ => format!("Could not read {kind} image for MAC {mac}."),
+    };
+    AppError::from(e).context(message)
+}
+
+/// Result of scanning the image directory for rendered images.
+#[derive(Debug, Default)]
+pub(crate) struct MacListing {
+    pub macs: Vec<MacEntry>,
+    /// Number of PNG files that were ignored because their name is not a MAC
+    /// or the directory entry could not be read.
+    pub skipped: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MacEntry {
+    pub mac: EpdMac,
+    pub has_png: bool,
+    pub has_svg: bool,
+    /// There is only a BMP left by an older tool, see
+    /// [`ImageHandler::migrate_legacy_bmp`].
+    pub bmp_only: bool,
+}
+
+/// Result of [`ImageHandler::migrate_legacy_bmp`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct BmpMigration {
+    pub migrated: usize,
+    pub failed: usize,
+}
+
+/// Ordered by byte value. Its canonical form, used for display, serde and
+/// file names, is lowercase hex; parsing accepts either case.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub(crate) struct EpdMac(pub [u8; MAC_LEN]);
 
+impl EpdMac {
+    /// Parses the already percent-decoded MAC segment of a URL, in either
+    /// case. Anything else, like an encoded `/`, is an
+    /// [`AppError::InvalidMac`], never a path to look up.
+    pub fn from_path(segment: &str) -> Result<Self, AppError> {
+        segment.parse().map_err(|e: eyre::Error| {
+            AppError::InvalidMac(e.wrap_err(format!(
+                "The URL segment '{segment}' is not a MAC of {} hex digits",
+                MAC_LEN * 2
+            )))
+        })
+    }
+
+    /// Uppercase hex, as MACs were formatted before and are printed on tag
+    /// labels.
+    pub fn to_uppercase_string(self) -> String {
+        self.to_string().to_uppercase()
+    }
+}
+
 impl FromStr for EpdMac {
     type Err = eyre::Error;
 
@@ -153,6 +3190,9 @@ impl FromStr for EpdMac {
         if s.len() != MAC_LEN * 2 {
             return Err(eyre::eyre!("Mac must be {} bytes long!", MAC_LEN));
         }
+        if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(eyre::eyre!("Mac must be hexadecimal, got {s}"));
+        }
         let bytes: Vec<_> = (0..s.len())
             .step_by(2)
             .filter_map(|i| {
@@ -172,12 +3212,26 @@ impl FromStr for EpdMac {
 impl Display for EpdMac {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for b in self.0.iter() {
-            write!(f, "{b:02X}")?
+            write!(f, "{b:02x}")?
         }
         Ok(())
     }
 }
 
+impl TryFrom<String> for EpdMac {
+    type Error = eyre::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<EpdMac> for String {
+    fn from(mac: EpdMac) -> Self {
+        mac.to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -193,11 +3247,48 @@ mod tests {
     fn mac_from_str_invalid() {
         assert!("00112233445566".parse::<EpdMac>().is_err());
         assert!("001122334455667z".parse::<EpdMac>().is_err());
+        assert!("+01122334455667f".parse::<EpdMac>().is_err());
     }
 
     #[test]
     fn mac_display() {
         let mac = EpdMac([0xaa, 0xbb, 0xcc, 0xdd, 0x00, 0x11, 0x22, 0x33]);
-        assert_eq!(format!("{mac}"), "AABBCCDD00112233".to_string());
+        assert_eq!(format!("{mac}"), "aabbccdd00112233".to_string());
+        assert_eq!(mac.to_uppercase_string(), "AABBCCDD00112233");
+    }
+
+    #[test]
+    fn mac_round_trip() {
+        // xorshift64, enough for varied test input without a dependency
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..1000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let mac = EpdMac(state.to_be_bytes());
+
+            let s = mac.to_string();
+            assert_eq!(s, s.to_lowercase());
+            assert_eq!(s.parse::<EpdMac>().unwrap(), mac);
+            assert_eq!(mac.to_uppercase_string().parse::<EpdMac>().unwrap(), mac);
+
+            let json = serde_json::to_string(&mac).unwrap();
+            assert_eq!(json, format!("\"{s}\""));
+            assert_eq!(serde_json::from_str::<EpdMac>(&json).unwrap(), mac);
+        }
+    }
+
+    #[test]
+    fn mac_order() {
+        let mut macs: Vec<EpdMac> = ["aabbccddeeffaabb", "0011223344556677", "AA00000000000000"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        macs.sort();
+        let macs: Vec<String> = macs.iter().map(EpdMac::to_string).collect();
+        assert_eq!(
+            macs,
+            ["0011223344556677", "aa00000000000000", "aabbccddeeffaabb"]
+        );
     }
 }
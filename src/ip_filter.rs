@@ -0,0 +1,255 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use axum::{extract::ConnectInfo, middleware::Next, response::Response};
+use eyre::{bail, eyre};
+use hyper::{HeaderMap, Method, Request};
+
+use crate::{config::Config, error::AppError, resource};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// An IPv4 or IPv6 network like `192.168.0.0/16` or `fd00::/8`. A bare
+/// address is a network of just that address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, width) = bits(self.addr);
+        let (ip, ip_width) = bits(ip.to_canonical());
+        width == ip_width && (net ^ ip).checked_shr(width - self.prefix).unwrap_or(0) == 0
+    }
+}
+
+/// The address as a number and its width in bits.
+fn bits(ip: IpAddr) -> (u128, u32) {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip).into(), 32),
+        IpAddr::V6(ip) => (ip.into(), 128),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| eyre!("Invalid IP address in '{s}'"))?;
+        let addr = addr.to_canonical();
+        let width = bits(addr).1;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .map_err(|_| eyre!("Invalid prefix length in '{s}'"))?,
+            None => width,
+        };
+        if prefix > width {
+            bail!("Prefix length of '{s}' is longer than {width} bits");
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// The client address a request was accepted from, after resolving trusted
+/// proxies.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct ClientIp(pub IpAddr);
+
+/// Which networks may use read-only and mutating routes.
+#[derive(Debug, Default)]
+pub(crate) struct IpFilter {
+    allow_write: Vec<Cidr>,
+    deny_write: Vec<Cidr>,
+    allow_read: Vec<Cidr>,
+    trusted_proxies: Vec<Cidr>,
+}
+
+fn any_contains(networks: &[Cidr], ip: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(ip))
+}
+
+impl IpFilter {
+    pub fn new(config: &Config) -> Self {
+        IpFilter {
+            allow_write: config.allow_write_from.clone(),
+            deny_write: config.deny_from.clone(),
+            allow_read: config.allow_read_from.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+        }
+    }
+
+    /// The client behind `peer`: the last address in `X-Forwarded-For` that
+    /// isn't a trusted proxy, if `peer` is one.
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut ip = peer?.to_canonical();
+        let hops: Vec<_> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for hop in hops.into_iter().rev() {
+            if !any_contains(&self.trusted_proxies, ip) {
+                break;
+            }
+            match hop.trim().parse::<IpAddr>() {
+                Ok(hop) => ip = hop.to_canonical(),
+                Err(_) => break,
+            }
+        }
+        Some(ip)
+    }
+
+    /// Fails if `ip` may not make a request with `method`. Unknown clients
+    /// are only let through if there is no restriction.
+    pub fn check(&self, method: &Method, ip: Option<IpAddr>) -> Result<(), AppError> {
+        let write = resource::is_write(method);
+        let allow = if write {
+            &self.allow_write
+        } else {
+            &self.allow_read
+        };
+        let allowed = allow.is_empty() || ip.is_some_and(|ip| any_contains(allow, ip));
+        let denied = write
+            && !self.deny_write.is_empty()
+            && match ip {
+                Some(ip) => any_contains(&self.deny_write, ip),
+                None => true,
+            };
+        if allowed && !denied {
+            return Ok(());
+        }
+        let client = ip.map_or_else(|| "unknown client".to_owned(), |ip| ip.to_string());
+        let routes = if write { "mutating" } else { "read-only" };
+        Err(AppError::Forbidden(eyre!(
+            "Requests to {routes} routes from {client} are not allowed."
+        )))
+    }
+}
+
+/// Records the address of the client as [`ClientIp`], which the
+/// [`policy`](crate::policy) checks against the allowed networks.
+pub(crate) async fn resolve_client<B>(
+    filter: Arc<IpFilter>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(ip) = filter.client_ip(peer, request.headers()) {
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(s: &[&str]) -> Vec<Cidr> {
+        s.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("192.168.3.4")));
+        assert!(net.contains(ip("::ffff:192.168.3.4")));
+        assert!(!net.contains(ip("192.169.0.1")));
+        assert!(!net.contains(ip("::1")));
+
+        let net: Cidr = "fd00::/8".parse().unwrap();
+        assert!(net.contains(ip("fd12:3456::1")));
+        assert!(!net.contains(ip("fe80::1")));
+        assert!(!net.contains(ip("10.0.0.1")));
+
+        let host: Cidr = "10.0.0.1".parse().unwrap();
+        assert_eq!(host.to_string(), "10.0.0.1/32");
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.2")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+        assert!("::/0".parse::<Cidr>().unwrap().contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn cidr_parse_invalid() {
+        for s in ["", "10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x", "/8"] {
+            assert!(s.parse::<Cidr>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn client_ip_behind_proxies() {
+        let filter = IpFilter {
+            trusted_proxies: cidrs(&["10.0.0.0/8"]),
+            ..IpFilter::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            "1.2.3.4, 5.6.7.8, 10.0.0.2".parse().unwrap(),
+        );
+
+        assert_eq!(
+            filter.client_ip(Some(ip("10.0.0.1")), &headers),
+            Some(ip("5.6.7.8"))
+        );
+        // Untrusted peers can't pretend to forward for others
+        assert_eq!(
+            filter.client_ip(Some(ip("5.6.7.9")), &headers),
+            Some(ip("5.6.7.9"))
+        );
+        assert_eq!(filter.client_ip(None, &headers), None);
+    }
+
+    #[test]
+    fn check_methods() {
+        let filter = IpFilter {
+            allow_write: cidrs(&["10.0.0.0/8", "fd00::/8"]),
+            deny_write: cidrs(&["10.0.0.13"]),
+            ..IpFilter::default()
+        };
+        assert!(filter.check(&Method::POST, Some(ip("10.1.2.3"))).is_ok());
+        assert!(filter.check(&Method::PUT, Some(ip("fd00::1"))).is_ok());
+        assert!(filter.check(&Method::POST, Some(ip("10.0.0.13"))).is_err());
+        assert!(filter.check(&Method::DELETE, Some(ip("8.8.8.8"))).is_err());
+        assert!(filter.check(&Method::POST, None).is_err());
+        // Reads aren't restricted without `--allow-read-from`
+        assert!(filter.check(&Method::GET, Some(ip("8.8.8.8"))).is_ok());
+        assert!(filter.check(&Method::GET, None).is_ok());
+
+        let filter = IpFilter {
+            allow_read: cidrs(&["127.0.0.1"]),
+            ..IpFilter::default()
+        };
+        assert!(filter.check(&Method::GET, Some(ip("127.0.0.1"))).is_ok());
+        assert!(filter.check(&Method::HEAD, Some(ip("127.0.0.2"))).is_err());
+        assert!(filter.check(&Method::POST, Some(ip("127.0.0.2"))).is_ok());
+    }
+}
@@ -0,0 +1,349 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use eyre::ensure;
+use serde::{Deserialize, Serialize};
+use usvg::{fontdb::Database, NodeExt, NodeKind, Paint};
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+use crate::{color_map, config::Config, fonts};
+
+/// Font size of text that doesn't set one, as usvg assumes.
+const DEFAULT_FONT_SIZE: f64 = 12.0;
+
+/// What makes content of an SVG unlikely to survive quantization, in device
+/// pixels after scaling. Zero disables a check.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub(crate) struct LintThresholds {
+    pub min_stroke_width: f64,
+    pub min_font_size: f64,
+    /// Distance of the luminance of a fill from `--luminance-threshold`
+    /// within which it dithers into noise.
+    pub gray_band: f64,
+}
+
+/// Thresholds of a MAC replacing the configured ones where set, part of its
+/// display profile since denser panels tolerate finer content.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LintOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_stroke_width: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_font_size: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gray_band: Option<f64>,
+}
+
+impl LintOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> eyre::Result<()> {
+        for value in [self.min_stroke_width, self.min_font_size, self.gray_band]
+            .into_iter()
+            .flatten()
+        {
+            ensure!(
+                value.is_finite() && value >= 0.0,
+                "{value} is not a threshold"
+            );
+        }
+        Ok(())
+    }
+}
+
+impl LintThresholds {
+    pub fn resolve(config: &Config, overrides: &LintOverrides) -> Self {
+        LintThresholds {
+            min_stroke_width: overrides
+                .min_stroke_width
+                .unwrap_or(config.lint_min_stroke_width),
+            min_font_size: overrides.min_font_size.unwrap_or(config.lint_min_font_size),
+            gray_band: overrides.gray_band.unwrap_or(config.lint_gray_band),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LintCode {
+    /// A stroke thinner than `min_stroke_width`.
+    Hairline,
+    /// Text smaller than `min_font_size`.
+    TinyText,
+    /// A fill of about middle gray, which dithers into noise.
+    MidGrayFill,
+    /// Characters of text that no installed font has a glyph for, drawn as
+    /// empty boxes.
+    MissingGlyph,
+}
+
+/// Content of a render that will likely look bad on the panel, reported in
+/// its render log entry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LintWarning {
+    pub code: LintCode,
+    /// Id of the offending element, absent if it has none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub element: Option<String>,
+    /// Stroke width or font size in device pixels, luminance of the fill or
+    /// number of characters without a glyph.
+    pub value: f64,
+}
+
+impl Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.code {
+            LintCode::Hairline => write!(f, "stroke of width {}", self.value)?,
+            LintCode::TinyText => write!(f, "text of size {}", self.value)?,
+            LintCode::MidGrayFill => write!(f, "gray fill of luminance {}", self.value)?,
+            LintCode::MissingGlyph => write!(f, "{} characters without a glyph", self.value)?,
+        }
+        if let Some(element) = &self.element {
+            write!(f, " in #{element}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the SVG `document`, parsed as `tree`, against `thresholds` and
+/// its text against the glyphs of `fontdb`. Gray fills are only reported if
+/// `dithered`, as they become solid otherwise.
+pub(crate) fn lint(
+    document: &[u8],
+    tree: &usvg::Tree,
+    fontdb: &Database,
+    thresholds: LintThresholds,
+    luminance_threshold: f64,
+    dithered: bool,
+) -> Vec<LintWarning> {
+    let svg = tree.svg_node();
+    let scale = (svg.size.width() / svg.view_box.rect.width())
+        .min(svg.size.height() / svg.view_box.rect.height());
+
+    let mut warnings = Vec::new();
+    for node in tree.root().descendants() {
+        let NodeKind::Path(path) = &*node.borrow() else {
+            continue;
+        };
+        let element = (!path.id.is_empty()).then(|| path.id.clone());
+        if let Some(stroke) = &path.stroke {
+            let (sx, sy) = node.abs_transform().get_scale();
+            let width = stroke.width.value() * sx.min(sy) * scale;
+            if width < thresholds.min_stroke_width {
+                warnings.push(warning(LintCode::Hairline, element.clone(), width));
+            }
+        }
+        if let Some(fill) = path.fill.as_ref().filter(|_| dithered) {
+            if let Paint::Color(color) = &fill.paint {
+                let luminance = 1.0 - fill.opacity.value() * (1.0 - color_map::luminance(color));
+                if (luminance - luminance_threshold).abs() < thresholds.gray_band {
+                    warnings.push(warning(LintCode::MidGrayFill, element, luminance));
+                }
+            }
+        }
+    }
+    // Text is converted to paths while parsing, so its sizes are only known
+    // from the document itself
+    if let Ok(texts) = text_sizes(document) {
+        warnings.extend(
+            texts
+                .into_iter()
+                .map(|(element, size)| (element, size * scale))
+                .filter(|(_, size)| *size < thresholds.min_font_size)
+                .map(|(element, size)| warning(LintCode::TinyText, element, size)),
+        );
+    }
+    if let Ok(texts) = text_chars(document) {
+        let mut covered = BTreeMap::new();
+        for (element, text) in texts {
+            let missing = text
+                .chars()
+                .filter(|&c| !fonts::is_invisible(c))
+                .filter(|&c| !*covered.entry(c).or_insert_with(|| fonts::covers(fontdb, c)))
+                .count();
+            if missing > 0 {
+                warnings.push(warning(LintCode::MissingGlyph, element, missing as f64));
+            }
+        }
+    }
+    warnings
+}
+
+fn warning(code: LintCode, element: Option<String>, value: f64) -> LintWarning {
+    LintWarning {
+        code,
+        element,
+        value: (value * 100.0).round() / 100.0,
+    }
+}
+
+/// An open element while tokenizing: its name, id, font size and the scale
+/// of its transforms, the last two inherited by its children.
+struct Open<'a> {
+    local: &'a str,
+    id: Option<&'a str>,
+    font_size: f64,
+    scale: f64,
+}
+
+/// The `text` and `tspan` elements of `document` with their ids and font
+/// sizes in user units of the root, after the scale of their transforms.
+fn text_sizes(document: &[u8]) -> eyre::Result<Vec<(Option<String>, f64)>> {
+    let document = std::str::from_utf8(document)?;
+    let mut open: Vec<Open> = vec![];
+    let mut sizes = vec![];
+    let inherited = |open: &[Open]| {
+        open.last().map_or((DEFAULT_FONT_SIZE, 1.0), |parent| {
+            (parent.font_size, parent.scale)
+        })
+    };
+    for token in Tokenizer::from(document) {
+        match token? {
+            Token::ElementStart { local, .. } => {
+                let (font_size, scale) = inherited(&open);
+                open.push(Open {
+                    local: local.as_str(),
+                    id: None,
+                    font_size,
+                    scale,
+                });
+            }
+            Token::Attribute { local, value, .. } => {
+                let (parent_size, _) = inherited(&open[..open.len().saturating_sub(1)]);
+                let Some(element) = open.last_mut() else {
+                    continue;
+                };
+                match local.as_str() {
+                    "id" => element.id = Some(value.as_str()),
+                    "font-size" => {
+                        if let Some(size) = font_size(value.as_str(), parent_size) {
+                            element.font_size = size;
+                        }
+                    }
+                    "style" => {
+                        let size = value
+                            .as_str()
+                            .split(';')
+                            .filter_map(|declaration| declaration.split_once(':'))
+                            .filter(|(property, _)| property.trim() == "font-size")
+                            .find_map(|(_, value)| font_size(value.trim(), parent_size));
+                        if let Some(size) = size {
+                            element.font_size = size;
+                        }
+                    }
+                    "transform" => element.scale *= transform_scale(value.as_str()),
+                    _ => {}
+                }
+            }
+            Token::ElementEnd {
+                end: ElementEnd::Close(..),
+                ..
+            } => {
+                open.pop();
+            }
+            Token::ElementEnd { end, .. } => {
+                let text = open
+                    .last()
+                    .filter(|element| matches!(element.local, "text" | "tspan"));
+                if let Some(text) = text {
+                    sizes.push((text.id.map(str::to_owned), text.font_size * text.scale));
+                }
+                if matches!(end, ElementEnd::Empty) {
+                    open.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(sizes)
+}
+
+/// The text of each `text` and `tspan` element of `document` with its id,
+/// excluding the text of nested ones.
+fn text_chars(document: &[u8]) -> eyre::Result<Vec<(Option<String>, String)>> {
+    let document = roxmltree::Document::parse(std::str::from_utf8(document)?)?;
+    let texts = document
+        .descendants()
+        .filter(|node| matches!(node.tag_name().name(), "text" | "tspan"))
+        .map(|node| {
+            let text: String = node
+                .children()
+                .filter(|child| child.is_text())
+                .filter_map(|child| child.text())
+                .collect();
+            (node.attribute("id").map(str::to_owned), text)
+        })
+        .filter(|(_, text)| !text.is_empty())
+        .collect();
+    Ok(texts)
+}
+
+/// A `font-size` in user units; keywords other than lengths are ignored.
+fn font_size(value: &str, parent: f64) -> Option<f64> {
+    let (number, factor) = if let Some(number) = value.strip_suffix("px") {
+        (number, 1.0)
+    } else if let Some(number) = value.strip_suffix("pt") {
+        (number, 4.0 / 3.0)
+    } else if let Some(number) = value.strip_suffix("em") {
+        (number, parent)
+    } else if let Some(number) = value.strip_suffix('%') {
+        (number, parent / 100.0)
+    } else {
+        (value, 1.0)
+    };
+    let size: f64 = number.trim().parse().ok()?;
+    (size.is_finite() && size >= 0.0).then_some(size * factor)
+}
+
+/// The smaller scale factor of a `transform` attribute. Only `scale` and
+/// `matrix` change it.
+fn transform_scale(value: &str) -> f64 {
+    let mut scale = 1.0;
+    for function in value.split_inclusive(')') {
+        let Some((name, arguments)) = function.split_once('(') else {
+            continue;
+        };
+        let arguments: Vec<f64> = arguments
+            .trim_end_matches(')')
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|argument| !argument.is_empty())
+            .filter_map(|argument| argument.parse().ok())
+            .collect();
+        let name = name.trim_matches(|c: char| c == ',' || c.is_whitespace());
+        scale *= match (name, arguments.as_slice()) {
+            ("scale", &[s]) => s.abs(),
+            ("scale", &[sx, sy]) => sx.abs().min(sy.abs()),
+            ("matrix", &[a, b, c, d, _, _]) => a.hypot(b).min(c.hypot(d)),
+            _ => 1.0,
+        };
+    }
+    scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_sizes_and_transforms() {
+        let document = br#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 128 296">
+            <text id="title" font-size="20">Title</text>
+            <g transform="translate(4, 4) scale(0.5)" style="fill: red; font-size: 12px">
+                <text id="small">Small<tspan font-size="50%">tiny</tspan></text>
+            </g>
+            <text transform="matrix(2 0 0 3 0 0)" font-size="1em">Wide</text>
+        </svg>"#;
+        assert_eq!(
+            text_sizes(document).unwrap(),
+            [
+                (Some("title".to_owned()), 20.0),
+                (Some("small".to_owned()), 6.0),
+                (None, 3.0),
+                (None, 24.0),
+            ]
+        );
+        assert_eq!(font_size("9pt", 12.0), Some(12.0));
+        assert_eq!(font_size("large", 12.0), None);
+    }
+}
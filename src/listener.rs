@@ -0,0 +1,134 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{body::Body, middleware::Next, response::Response};
+use hyper::Request;
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{config::Config, policy::RouteClass};
+
+/// The API served on an address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Listener {
+    /// Everything, on `--listen`.
+    Full,
+    /// What the devices need, on `--device-listen`.
+    Device,
+}
+
+impl Listener {
+    /// The listeners of `config` with their addresses.
+    pub fn addrs(config: &Config) -> Vec<(Listener, SocketAddr)> {
+        let mut addrs = vec![(Listener::Full, config.listen)];
+        addrs.extend(config.device_listen.map(|addr| (Listener::Device, addr)));
+        addrs
+    }
+
+    /// Whether routes of `class` are served, for a `device_route` that the
+    /// devices fetch their images from or report to. The device listener
+    /// only serves reads and reports on those, so that nothing can be listed
+    /// or changed through it even with a misconfigured policy.
+    pub fn serves(self, class: RouteClass, device_route: bool) -> bool {
+        match self {
+            Listener::Full => true,
+            Listener::Device => {
+                device_route && matches!(class, RouteClass::ReadImage | RouteClass::DeviceSelf)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Listener::Full => "full",
+            Listener::Device => "device",
+        })
+    }
+}
+
+/// Requests answered by each listener since startup.
+#[derive(Debug, Default)]
+pub(crate) struct ListenerRequests {
+    full: AtomicU64,
+    device: AtomicU64,
+}
+
+impl ListenerRequests {
+    fn counter(&self, listener: Listener) -> &AtomicU64 {
+        match listener {
+            Listener::Full => &self.full,
+            Listener::Device => &self.device,
+        }
+    }
+
+    pub fn totals(&self) -> BTreeMap<Listener, u64> {
+        [Listener::Full, Listener::Device]
+            .into_iter()
+            .map(|listener| (listener, self.counter(listener).load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Middleware counting the requests of `listener`.
+pub(crate) async fn count(
+    requests: Arc<ListenerRequests>,
+    listener: Listener,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    requests.counter(listener).fetch_add(1, Ordering::Relaxed);
+    next.run(request).await
+}
+
+/// Completes on Ctrl-C or SIGTERM, after which all listeners stop accepting
+/// connections and finish the requests in flight.
+pub(crate) async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::error!("Listening for SIGTERM failed: {e}");
+            tokio::signal::ctrl_c().await.ok();
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    tracing::info!("Shutting down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_serves_no_changes() {
+        for class in [
+            RouteClass::ReadImage,
+            RouteClass::WriteImage,
+            RouteClass::Admin,
+            RouteClass::DeviceSelf,
+            RouteClass::Status,
+        ] {
+            assert!(Listener::Full.serves(class, false));
+            assert!(Listener::Full.serves(class, true));
+            assert!(!Listener::Device.serves(class, false));
+        }
+        assert!(Listener::Device.serves(RouteClass::ReadImage, true));
+        assert!(Listener::Device.serves(RouteClass::DeviceSelf, true));
+        assert!(!Listener::Device.serves(RouteClass::WriteImage, true));
+        assert!(!Listener::Device.serves(RouteClass::Admin, true));
+        assert!(!Listener::Device.serves(RouteClass::Status, true));
+    }
+}
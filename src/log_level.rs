@@ -0,0 +1,244 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::units::HumanDuration;
+
+/// Filter used if `RUST_LOG` is unset.
+pub(crate) const DEFAULT_FILTER: &str = "eps_server=debug,tower_http=debug";
+
+/// The layer that filters all logs, whose filter [`LogLevel`] swaps.
+pub(crate) type FilterLayer = reload::Layer<EnvFilter, Registry>;
+
+/// Body of `PUT /admin/log_level`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LogLevelChange {
+    /// Directives like `RUST_LOG`, e.g. `eps_server=trace,tower_http=debug`.
+    pub filter: String,
+    /// Reverts to the previous filter after this time.
+    #[serde(default)]
+    pub duration: Option<HumanDuration>,
+}
+
+/// The active filter, as returned by `GET /admin/log_level`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LogFilter {
+    pub filter: String,
+    /// When a temporary filter is replaced by `reverts_to`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revert_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverts_to: Option<String>,
+}
+
+/// Changes the log filter at runtime, so that a problem can be traced
+/// without restarting and losing the state that shows it.
+pub(crate) struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+    state: Mutex<State>,
+}
+
+struct State {
+    filter: String,
+    revert: Option<Revert>,
+    /// Incremented by every change, so that a revert that raced with a later
+    /// change leaves it alone.
+    generation: u64,
+}
+
+struct Revert {
+    at: DateTime<Utc>,
+    to: String,
+    task: JoinHandle<()>,
+}
+
+impl LogLevel {
+    /// Starts with `filter`, ignoring invalid directives like `RUST_LOG`
+    /// does. The returned layer has to be added to the subscriber.
+    pub fn new(filter: String) -> (FilterLayer, Arc<Self>) {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new(&filter));
+        let log_level = LogLevel {
+            handle,
+            state: Mutex::new(State {
+                filter,
+                revert: None,
+                generation: 0,
+            }),
+        };
+        (layer, Arc::new(log_level))
+    }
+
+    pub fn current(&self) -> LogFilter {
+        let state = self.state.lock().unwrap();
+        LogFilter {
+            filter: state.filter.clone(),
+            revert_at: state.revert.as_ref().map(|revert| revert.at),
+            reverts_to: state.revert.as_ref().map(|revert| revert.to.clone()),
+        }
+    }
+
+    /// Swaps in the filter of `change`, leaving the current one in place if
+    /// it is invalid. A temporary change reverts to the last permanent
+    /// filter, even if it replaced another temporary one.
+    pub fn set(self: &Arc<Self>, change: LogLevelChange) -> eyre::Result<LogFilter> {
+        let filter = EnvFilter::try_new(&change.filter)?;
+        let revert_at = change
+            .duration
+            .map(|duration| {
+                chrono::Duration::from_std(duration.get())
+                    .ok()
+                    .and_then(|duration| Utc::now().checked_add_signed(duration))
+                    .ok_or_else(|| eyre!("The duration {duration} is too long"))
+            })
+            .transpose()?;
+        let mut state = self.state.lock().unwrap();
+        self.handle.reload(filter)?;
+        tracing::info!("Log filter changed to {}", change.filter);
+
+        state.generation += 1;
+        let previous = std::mem::replace(&mut state.filter, change.filter);
+        let reverts_to = match state.revert.take() {
+            Some(revert) => {
+                revert.task.abort();
+                revert.to
+            }
+            None => previous,
+        };
+        if let (Some(duration), Some(at)) = (change.duration, revert_at) {
+            let duration = duration.get();
+            let task = tokio::spawn({
+                let log_level = self.clone();
+                let reverts_to = reverts_to.clone();
+                let generation = state.generation;
+                async move {
+                    tokio::time::sleep(duration).await;
+                    log_level.revert(generation, reverts_to);
+                }
+            });
+            state.revert = Some(Revert {
+                at,
+                to: reverts_to,
+                task,
+            });
+        }
+        drop(state);
+        Ok(self.current())
+    }
+
+    /// Restores `filter` unless the filter changed again since `generation`.
+    fn revert(&self, generation: u64, filter: String) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        // Was valid when it was set
+        if let Err(e) = self.handle.reload(EnvFilter::new(&filter)) {
+            tracing::error!("Could not revert the log filter: {e}");
+            return;
+        }
+        tracing::info!("Log filter reverted to {filter}");
+        state.generation += 1;
+        state.filter = filter;
+        state.revert = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time::Duration};
+
+    use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+    use super::*;
+
+    /// Collects the formatted logs.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn contains(&self, text: &str) -> bool {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).contains(text)
+        }
+    }
+
+    fn change(filter: &str, duration: Option<&str>) -> LogLevelChange {
+        LogLevelChange {
+            filter: filter.to_owned(),
+            duration: duration.map(|duration| duration.parse().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn swap_and_revert() {
+        let (layer, log_level) = LogLevel::new("eps_server=info".to_owned());
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let _subscriber = tracing_subscriber::registry()
+            .with(layer)
+            .with(fmt::layer().with_writer(move || writer.clone()))
+            .set_default();
+
+        tracing::debug!("hidden");
+        assert!(!captured.contains("hidden"));
+
+        assert!(log_level.set(change("eps_server=loud", None)).is_err());
+        assert_eq!(log_level.current().filter, "eps_server=info");
+
+        let current = log_level
+            .set(change("eps_server=debug", Some("200ms")))
+            .unwrap();
+        assert_eq!(current.filter, "eps_server=debug");
+        assert_eq!(current.reverts_to.as_deref(), Some("eps_server=info"));
+        tracing::debug!("shown");
+        assert!(captured.contains("shown"));
+
+        // Reverts to the filter before the first temporary change
+        log_level
+            .set(change("eps_server=trace", Some("100ms")))
+            .unwrap();
+        assert_eq!(
+            log_level.current().reverts_to.as_deref(),
+            Some("eps_server=info")
+        );
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(
+            log_level.current(),
+            LogFilter {
+                filter: "eps_server=info".to_owned(),
+                revert_at: None,
+                reverts_to: None,
+            }
+        );
+        tracing::debug!("hidden again");
+        assert!(!captured.contains("hidden again"));
+    }
+
+    #[tokio::test]
+    async fn permanent_change_cancels_revert() {
+        let (layer, log_level) = LogLevel::new("eps_server=info".to_owned());
+        let _subscriber = tracing_subscriber::registry().with(layer).set_default();
+
+        log_level
+            .set(change("eps_server=debug", Some("100ms")))
+            .unwrap();
+        log_level.set(change("eps_server=warn", None)).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(log_level.current().filter, "eps_server=warn");
+        assert_eq!(log_level.current().revert_at, None);
+    }
+}
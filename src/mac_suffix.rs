@@ -0,0 +1,145 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use eyre::eyre;
+use hyper::{Request, Uri};
+use tower::ServiceExt;
+
+use crate::{
+    error::AppError,
+    image_handler::{EpdMac, ImageHandler},
+    ip_filter::ClientIp,
+    policy::RouteClass,
+    resource::is_write,
+    tenant::TenantService,
+    AppState,
+};
+
+const MIN_SUFFIX_LEN: usize = 2;
+const MAX_SUFFIX_LEN: usize = 8;
+/// Prefix of a path whose MAC is given by the end of its hex digits, like
+/// `/macs/~def1/png`.
+const SHORTHAND: &str = "/macs/~";
+
+/// The lowercase hex digits that MACs are looked up by, from the last few
+/// characters printed on a tag label.
+pub(crate) fn parse(suffix: &str) -> Result<String, AppError> {
+    if !(MIN_SUFFIX_LEN..=MAX_SUFFIX_LEN).contains(&suffix.len())
+        || !suffix.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return Err(AppError::InvalidMac(eyre!(
+            "A MAC suffix must be {MIN_SUFFIX_LEN} to {MAX_SUFFIX_LEN} hex digits, got '{suffix}'."
+        )));
+    }
+    Ok(suffix.to_ascii_lowercase())
+}
+
+/// The routes of a state, to route requests again once their MAC is
+/// resolved.
+pub(crate) struct Shorthands {
+    state: Arc<AppState>,
+    // Cloned for each request; the lock only makes them `Sync`
+    routes: Mutex<TenantService>,
+}
+
+impl Shorthands {
+    pub fn new(state: Arc<AppState>, routes: TenantService) -> Self {
+        Shorthands {
+            state,
+            routes: Mutex::new(routes),
+        }
+    }
+}
+
+/// Resolves the MAC of reads below `/macs/~<suffix>/` to the only known MAC
+/// ending with `suffix`, for quick checks with curl, and passes them to the
+/// routes again. Several matching MACs are a conflict listing them. Changes
+/// always need the full MAC, so that a shorthand never deletes or
+/// overwrites the wrong device. Runs after [`ClientIp`] is resolved, as the
+/// request must pass the policy of reads before anything about the MACs is
+/// looked up.
+pub(crate) async fn resolve(
+    shorthands: Arc<Shorthands>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(rest) = request
+        .uri()
+        .path()
+        .strip_prefix(SHORTHAND)
+        .map(str::to_owned)
+    else {
+        return next.run(request).await;
+    };
+    let (suffix, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let (mut parts, body) = request.into_parts();
+    // The routes below `/macs/:mac/` that may be read all need at least what
+    // reading an image does
+    let state = &shorthands.state;
+    if let Err(e) = state
+        .policy
+        .check(RouteClass::ReadImage, &mut parts, state)
+        .await
+    {
+        return e.into_response();
+    }
+    if is_write(&parts.method) {
+        return AppError::BadRequest(eyre!(
+            "MAC shorthands like ~{suffix} are only resolved for reads, changes need the full MAC."
+        ))
+        .into_response();
+    }
+    let mac = match find_one(&state.image_handler, suffix).await {
+        Ok(mac) => mac,
+        Err(e) => return e.into_response(),
+    };
+
+    let path_and_query = match parts.uri.query() {
+        Some(query) => format!("/macs/{mac}{path}?{query}"),
+        None => format!("/macs/{mac}{path}"),
+    };
+    let uri = match path_and_query.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(e) => return AppError::BadRequest(eyre!(e)).into_response(),
+    };
+    // A new request, as the path parameters of the shorthand's route kept in
+    // the extensions would clash with those of the resolved one
+    let mut request = Request::new(body);
+    *request.method_mut() = parts.method;
+    *request.uri_mut() = uri;
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers;
+    if let Some(connect_info) = parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        request.extensions_mut().insert(*connect_info);
+    }
+    if let Some(client_ip) = parts.extensions.get::<ClientIp>() {
+        request.extensions_mut().insert(*client_ip);
+    }
+    let routes = shorthands.routes.lock().unwrap().clone();
+    match routes.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+async fn find_one(image_handler: &ImageHandler, suffix: &str) -> Result<EpdMac, AppError> {
+    let suffix = parse(suffix)?;
+    let macs = image_handler.macs_ending_with(&suffix).await?;
+    match macs.as_slice() {
+        [] => Err(AppError::NotFound(eyre!(
+            "No known MAC ends with {suffix}."
+        ))),
+        [mac] => Ok(*mac),
+        _ => Err(AppError::AmbiguousMac(
+            macs.iter().map(ToString::to_string).collect(),
+        )),
+    }
+}
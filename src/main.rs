@@ -1,270 +1,8974 @@
+mod accept;
+mod artifact;
+mod audit;
+mod auth;
+mod blocking;
+mod boot_report;
+mod broadcast;
+mod bundle;
+mod capabilities;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod cleanup;
+#[cfg(feature = "client")]
+mod client;
+mod clock;
+mod coalesce;
+#[cfg(feature = "coap")]
+mod coap;
+mod color_map;
 mod config;
+mod coverage;
+mod daily_stats;
+mod dedup;
+mod dependency;
+mod derived;
+mod display_profile;
+mod encoding;
 mod error;
+mod events;
+mod fonts;
+mod groups;
+mod htpasswd;
+mod idempotency;
+mod image_cache;
 mod image_handler;
+mod ip_filter;
+mod lint;
+mod listener;
+mod log_level;
+mod mac_suffix;
+mod maintenance;
+mod metadata;
+mod multipart;
+mod negative_cache;
+mod object_storage;
+mod outbound;
+mod playlist;
+mod policy;
+mod poll_interval;
+mod post_render;
+mod precondition;
+mod provision;
+mod raster;
+mod refresh;
+mod region;
+mod render_options;
+mod render_queue;
+#[cfg(feature = "script")]
+mod render_script;
+mod replication;
+mod rerender_job;
+mod resource;
+mod response_headers;
+mod rle;
+mod schedule;
+mod shard;
+#[cfg(feature = "sqlite")]
+mod sqlite_state;
+mod state;
+mod storage;
+mod substitute;
+mod svg_optimize;
+mod svgz;
+mod tenant;
+mod throttle;
+mod timeout;
+mod units;
+mod upload;
+mod validated_config;
+mod verify;
+mod version;
+mod watchdog;
+mod websocket;
 
 use axum::{
-    body::{Body, StreamBody},
+    body::{Body, Bytes, StreamBody},
     debug_handler,
-    extract::{Path, State},
-    response::IntoResponse,
-    routing::{delete, get, post},
+    extract::{ws::WebSocketUpgrade, Path, Query, RawBody, RawQuery, State},
+    middleware::{self, Next},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
     Json, Router,
 };
+use chrono::{DateTime, NaiveDate, SubsecRound, Utc};
 use clap::Parser;
+use eyre::eyre;
 use eyre::Result;
-use hyper::header;
+use eyre::WrapErr;
+use futures_util::{stream, Stream, StreamExt};
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Method, Request, StatusCode, Uri,
+};
+use image::ImageFormat;
 use mime::Mime;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::fs::File;
-use tokio_util::io::ReaderStream;
-use tower_http::trace::TraceLayer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tower::util::BoxCloneService;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::{config::Config, error::AppError, image_handler::ImageHandler};
+use crate::{
+    audit::{AuditEntry, AuditLog, Operation, RequestContext},
+    auth::Credentials,
+    boot_report::BootReport,
+    broadcast::{Broadcast, BroadcastResult},
+    bundle::{Include, Profile},
+    capabilities::{Capabilities, Compression},
+    cleanup::CleanupReport,
+    clock::SystemClock,
+    config::{Config, Limits},
+    dedup::{DedupReport, DedupUsage},
+    dependency::{Dependency, Health},
+    display_profile::DisplayProfile,
+    error::{AppError, ResultExt},
+    events::{Event, History},
+    groups::{GroupName, GroupRender, GroupRenderResult},
+    idempotency::{IdempotencyKeys, IDEMPOTENCY_FILE},
+    image_handler::{Against, EpdMac, ImageHandler, RerenderOptions, StagingStatus},
+    ip_filter::IpFilter,
+    lint::LintOverrides,
+    listener::{Listener, ListenerRequests},
+    log_level::{LogFilter, LogLevel, LogLevelChange},
+    mac_suffix::Shorthands,
+    maintenance::{Maintenance, MaintenanceMode, MAINTENANCE_FILE},
+    metadata::{Checkin, RenderRecord},
+    playlist::{Playlist, PlaylistStatus},
+    policy::{Policy, Requirement, RouteClass},
+    poll_interval::{PollInterval, PollOverride},
+    precondition::Validators,
+    raster::{Autofix, Fit},
+    refresh::Ack,
+    region::{Align, Regions},
+    render_options::RenderOverrides,
+    render_queue::{Priority, QueueDepth},
+    replication::ReplicationStatus,
+    rerender_job::{RerenderJob, RerenderJobs},
+    resource::Resource,
+    response_headers::ResponseHeaders,
+    schedule::Schedule,
+    storage::Storage,
+    tenant::{TenantKey, Tenants},
+    timeout::Timeouts,
+    validated_config::ValidatedConfig,
+    verify::VerifyReport,
+    version::VersionInfo,
+};
+
+const SCHEDULER_PERIOD: Duration = Duration::from_secs(30);
+const WATCHDOG_PERIOD: Duration = Duration::from_secs(10);
+const DEPENDENCY_PERIOD: Duration = Duration::from_secs(60);
+const STATS_FLUSH_PERIOD: Duration = Duration::from_secs(60);
+const AUDIT_LOG_FILE: &str = "audit.log";
+const LAST_EVENT_ID: &str = "last-event-id";
+const STALE_DIMENSIONS: HeaderName = HeaderName::from_static("x-eps-stale-dimensions");
 
 struct AppState {
-    image_handler: ImageHandler,
+    image_handler: Arc<ImageHandler>,
+    audit_log: AuditLog,
+    rerender_jobs: RerenderJobs,
+    credentials: Credentials,
+    policy: Policy,
+    maintenance: MaintenanceMode,
+    idempotency_keys: Arc<IdempotencyKeys>,
+    /// Process-wide, so only the default tenant's admin may change it.
+    log_level: Option<Arc<LogLevel>>,
+    ip_filter: Arc<IpFilter>,
+    timeouts: Timeouts,
+    listener_requests: Arc<ListenerRequests>,
+    #[cfg(feature = "chaos")]
+    chaos: Arc<chaos::Chaos>,
 }
 
 #[tokio::main]
 async fn main() {
+    let (filter_layer, log_level) = LogLevel::new(
+        std::env::var("RUST_LOG").unwrap_or_else(|_| log_level::DEFAULT_FILTER.into()),
+    );
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG")
-                .unwrap_or_else(|_| "eps_server=debug,tower_http=debug".into()),
-        ))
+        .with(filter_layer)
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // parse args
-    let config = Config::parse();
-    tracing::debug!("{config:?}");
+    // parse args
+    let config = Config::parse();
+    tracing::debug!("{config:?}");
+    let config = match ValidatedConfig::new(config) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if config.check_config {
+        match check_config(config).await {
+            Ok(()) => {
+                tracing::info!("The configuration is valid");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                tracing::error!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(command) = &config.post_render_cmd {
+        if let Err(e) = post_render::validate(command) {
+            tracing::error!("Invalid --post-render-cmd {}: {e:#}", command.display());
+            std::process::exit(1);
+        }
+    }
+
+    let storage = match Storage::from_config(&config) {
+        Ok(storage) => storage,
+        Err(e) => {
+            tracing::error!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+    if config.migrate_shards {
+        match shard::migrate(&config.image_dir, config.shard_depth).await {
+            Ok(moved) => tracing::info!("Moved {moved} files into shard directories"),
+            Err(e) => tracing::error!("Moving files into shard directories failed: {e:#}"),
+        }
+    }
+    let migrate_legacy_bmp = config.migrate_legacy_bmp;
+    let image_handler = match ImageHandler::with_storage(config, Arc::new(SystemClock), storage) {
+        Ok(image_handler) => Arc::new(image_handler),
+        Err(e) => {
+            tracing::error!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+    if migrate_legacy_bmp {
+        match image_handler.migrate_legacy_bmp().await {
+            Ok(migration) => tracing::info!(
+                "Migrated {} legacy BMPs to PNG, {} failed",
+                migration.migrated,
+                migration.failed
+            ),
+            Err(e) => tracing::error!("Migrating legacy BMPs failed: {e:#}"),
+        }
+    }
+    let tenants = match tenant_handlers(image_handler.config()) {
+        Ok(tenants) => tenants,
+        Err(e) => {
+            tracing::error!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+    let handlers: Vec<_> = std::iter::once(&image_handler)
+        .chain(tenants.iter().map(|(_, handler)| handler))
+        .cloned()
+        .collect();
+    for handler in &handlers {
+        if handler.config().migrate_state {
+            match handler.migrate_state().await {
+                Ok(imported) => tracing::info!("Imported the metadata of {imported} MACs"),
+                Err(e) => {
+                    tracing::error!("Importing the metadata failed: {e:#}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        handler.check_dependencies().await;
+        match handler.cleanup(false).await {
+            Ok(report) => report.log(&handler.config().image_dir),
+            Err(e) => tracing::error!("Cleaning up the image directory failed: {e:#}"),
+        }
+        tokio::spawn(schedule::run(handler.clone(), SCHEDULER_PERIOD));
+        tokio::spawn(watchdog::run(handler.clone(), WATCHDOG_PERIOD));
+        tokio::spawn(dependency::run(handler.clone(), DEPENDENCY_PERIOD));
+        tokio::spawn(daily_stats::run(handler.clone(), STATS_FLUSH_PERIOD));
+        tokio::spawn(replication::run(handler.clone()));
+    }
+
+    // run it
+    let config = image_handler.config();
+    let shared = SharedState::new(image_handler.clone(), &tenants, log_level);
+    #[cfg(feature = "coap")]
+    if let Some(addr) = config.coap_listen {
+        match tokio::net::UdpSocket::bind(addr).await {
+            Ok(socket) => {
+                tracing::debug!("Listening for CoAP on {addr}");
+                let server = coap::CoapServer::new(socket, shared.default.clone());
+                tokio::spawn(Arc::new(server).run());
+            }
+            Err(e) => {
+                tracing::error!("Binding --coap-listen {addr} failed: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    let (stop, stopped) = tokio::sync::watch::channel(());
+    let mut servers = vec![];
+    for (listener, addr) in Listener::addrs(config) {
+        let builder = match axum::Server::try_bind(&addr) {
+            Ok(builder) => builder,
+            Err(e) => {
+                tracing::error!("Binding the {listener} listener to {addr} failed: {e}");
+                std::process::exit(1);
+            }
+        };
+        tracing::debug!("Listening for the {listener} API on {addr}");
+        let mut stopped = stopped.clone();
+        let server = configure_http(builder, config)
+            .serve(
+                shared
+                    .router(listener)
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(async move {
+                stopped.changed().await.ok();
+            });
+        // A failing listener doesn't take the others down
+        servers.push(tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!("The {listener} listener failed: {e}");
+            }
+        }));
+    }
+    tokio::spawn(async move {
+        listener::shutdown_signal().await;
+        stop.send_replace(());
+    });
+    if config.warmup_derived {
+        for handler in handlers {
+            tokio::spawn(derived::warm_up(
+                handler,
+                config.warmup_formats.clone(),
+                config.warmup_concurrency,
+            ));
+        }
+    }
+    for server in servers {
+        server.await.unwrap();
+    }
+    // Also the responses stored just before
+    let states =
+        std::iter::once(&shared.default).chain(shared.tenants.iter().map(|(_, state)| state));
+    for state in states {
+        state.idempotency_keys.save().await;
+    }
+}
+
+/// The startup checks of `--check-config`: what the server needs is there,
+/// but nothing is migrated or cleaned up.
+async fn check_config(config: ValidatedConfig) -> Result<()> {
+    if let Some(command) = &config.post_render_cmd {
+        post_render::validate(command)
+            .wrap_err_with(|| format!("Invalid --post-render-cmd {}", command.display()))?;
+    }
+    let storage = Storage::from_config(&config)?;
+    let image_handler = Arc::new(ImageHandler::with_storage(
+        config,
+        Arc::new(SystemClock),
+        storage,
+    )?);
+    let tenants = tenant_handlers(image_handler.config())?;
+    for handler in std::iter::once(&image_handler).chain(tenants.iter().map(|(_, handler)| handler))
+    {
+        handler.check_dependencies().await;
+        let failing = handler.dependencies().failing_required();
+        if let Some(&dependency) = failing.first() {
+            let health = &handler.dependencies().health()[&dependency];
+            return Err(eyre!(
+                "Required dependency {} is failing: {}",
+                dependency::dependency_name(dependency),
+                health.error.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Applies the connection settings of `config` to the listener.
+fn configure_http<I>(
+    builder: hyper::server::Builder<I>,
+    config: &Config,
+) -> hyper::server::Builder<I> {
+    builder
+        .http1_header_read_timeout(config.request_timeout.get())
+        // Without TLS, clients have to know that HTTP/2 is spoken
+        .http1_only(!config.http2)
+}
+
+#[cfg(test)]
+fn app(config: Config) -> Router<Arc<AppState>, Body> {
+    let image_handler = Arc::new(ImageHandler::new(config));
+    let tenants = tenant_handlers(image_handler.config()).unwrap();
+    router(image_handler, &tenants, detached_log_level())
+}
+
+/// A log level whose layer isn't part of any subscriber, so changing it
+/// fails.
+#[cfg(test)]
+fn detached_log_level() -> Arc<LogLevel> {
+    LogLevel::new(log_level::DEFAULT_FILTER.to_owned()).1
+}
+
+/// The image handlers of the tenants of `config`, creating their
+/// directories.
+fn tenant_handlers(config: &ValidatedConfig) -> Result<Vec<(TenantKey, Arc<ImageHandler>)>> {
+    let mut handlers = vec![];
+    for tenant in &config.tenants {
+        let config = config.for_tenant(tenant);
+        std::fs::create_dir_all(&config.image_dir)
+            .wrap_err_with(|| format!("Creating {} failed", config.image_dir.display()))?;
+        let storage = Storage::from_config(&config)?;
+        let handler = ImageHandler::with_storage(config, Arc::new(SystemClock), storage)?;
+        handlers.push((tenant.clone(), Arc::new(handler)));
+    }
+    Ok(handlers)
+}
+
+/// The full API, see [`SharedState::router`].
+#[cfg(test)]
+fn router(
+    image_handler: Arc<ImageHandler>,
+    tenants: &[(TenantKey, Arc<ImageHandler>)],
+    log_level: Arc<LogLevel>,
+) -> Router<Arc<AppState>, Body> {
+    SharedState::new(image_handler, tenants, log_level).router(Listener::Full)
+}
+
+/// The state of the default tenant and of the tenants, which all listeners
+/// share.
+struct SharedState {
+    default: Arc<AppState>,
+    tenants: Vec<(TenantKey, Arc<AppState>)>,
+}
+
+impl SharedState {
+    fn new(
+        image_handler: Arc<ImageHandler>,
+        tenants: &[(TenantKey, Arc<ImageHandler>)],
+        log_level: Arc<LogLevel>,
+    ) -> Self {
+        SharedState {
+            default: app_state(image_handler, Some(log_level)),
+            tenants: tenants
+                .iter()
+                .map(|(tenant, handler)| (tenant.clone(), app_state(handler.clone(), None)))
+                .collect(),
+        }
+    }
+
+    /// The routes of the default tenant served by `listener`, with the
+    /// routes of the tenants below `/t/<name>/`.
+    fn router(&self, listener: Listener) -> Router<Arc<AppState>, Body> {
+        let mut services = Tenants::default();
+        for (tenant, state) in &self.tenants {
+            let routes = routes(state.clone(), listener).into_service();
+            services.insert(tenant, BoxCloneService::new(routes));
+        }
+        let tenants = Arc::new(services);
+        routes(self.default.clone(), listener)
+            .layer(middleware::from_fn(
+                move |request: Request<Body>, next: Next<Body>| {
+                    tenant::dispatch(tenants.clone(), request, next)
+                },
+            ))
+            .layer(middleware::from_fn(error::log_errors))
+            .layer(middleware::from_fn(error::negotiate_errors))
+            .layer(TraceLayer::new_for_http())
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+    }
+}
+
+/// The state of the routes serving the images of `image_handler`.
+fn app_state(image_handler: Arc<ImageHandler>, log_level: Option<Arc<LogLevel>>) -> Arc<AppState> {
+    let config = image_handler.config();
+    let audit_log = AuditLog::spawn(
+        config
+            .audit_log
+            .clone()
+            .unwrap_or_else(|| config.image_dir.join(AUDIT_LOG_FILE)),
+        config.audit_log_max_bytes.get(),
+    );
+    let credentials = Credentials::new(config.htpasswd.clone());
+    let maintenance = MaintenanceMode::load(config.image_dir.join(MAINTENANCE_FILE));
+    let idempotency_keys = IdempotencyKeys::spawn(config.image_dir.join(IDEMPOTENCY_FILE), config);
+    let ip_filter = Arc::new(IpFilter::new(config));
+    let policy = Policy::new(config, ip_filter.clone());
+    let timeouts = Timeouts {
+        body: config.request_timeout.get(),
+        slow: config.slow_request_threshold.get(),
+    };
+    #[cfg(feature = "chaos")]
+    let chaos = Arc::new(chaos::Chaos::load(config.chaos_config.as_deref()));
+    Arc::new(AppState {
+        image_handler,
+        audit_log,
+        rerender_jobs: RerenderJobs::default(),
+        credentials,
+        policy,
+        maintenance,
+        idempotency_keys,
+        log_level,
+        ip_filter,
+        timeouts,
+        listener_requests: Arc::default(),
+        #[cfg(feature = "chaos")]
+        chaos,
+    })
+}
+
+/// The routes of `state` served by `listener`.
+fn routes(state: Arc<AppState>, listener: Listener) -> Router<Arc<AppState>, Body> {
+    let resource = || Resource::new(&state, listener);
+    let admin = || Resource::of(RouteClass::Admin, &state, listener);
+    let status = || Resource::of(RouteClass::Status, &state, listener);
+    // What the devices fetch and report, also on the device listener
+    let device = || resource().device();
+    let device_self = || Resource::of(RouteClass::DeviceSelf, &state, listener).device();
+
+    // build our application with a route
+    let router = Router::with_state(state.clone())
+        .route("/macs", resource().get(get_macs).build())
+        .route("/macs/find/:suffix", resource().get(find_macs).build())
+        .route("/macs/:mac", resource().delete(delete_images).build())
+        .route("/macs/:mac/svg", resource().get(get_svg).build())
+        .route(
+            "/macs/:mac/svg/original",
+            resource().get(get_original_svg).build(),
+        )
+        .route("/macs/:mac/render_svg", resource().post(render_svg).build())
+        .route(
+            "/macs/:mac/staging",
+            resource().get(get_staging).delete(discard_staging).build(),
+        )
+        .route(
+            "/macs/:mac/staging/render_svg",
+            resource().post(stage_svg).build(),
+        )
+        .route(
+            "/macs/:mac/staging/png",
+            resource().get(get_staging_png).build(),
+        )
+        .route("/macs/:mac/promote", resource().post(promote).build())
+        .route(
+            "/macs/:mac/quarantine",
+            admin().delete(clear_quarantine).build(),
+        )
+        .route("/macs/:mac/ack", device_self().post(ack).build())
+        .route(
+            "/macs/:mac/bootreport",
+            device_self()
+                .get(get_boot_report)
+                .post(post_boot_report)
+                .build(),
+        )
+        .route("/macs/:mac/checkin", device_self().get(get_checkin).build())
+        .route(
+            "/macs/:mac/capabilities",
+            device_self()
+                .get(get_capabilities)
+                .put(put_capabilities)
+                .build(),
+        )
+        .route(
+            "/macs/:mac/profile",
+            resource().get(get_profile).put(put_profile).build(),
+        )
+        .route(
+            "/macs/:mac/render_options",
+            resource()
+                .get(get_render_options)
+                .put(put_render_options)
+                .build(),
+        )
+        .route("/macs/:mac/payload", device().get(get_payload).build())
+        .route(
+            "/macs/:mac/response_headers",
+            resource()
+                .get(get_response_headers)
+                .put(put_response_headers)
+                .delete(delete_response_headers)
+                .build(),
+        )
+        .route(
+            "/macs/:mac/poll_interval",
+            resource()
+                .get(get_poll_interval)
+                .put(put_poll_interval)
+                .delete(delete_poll_interval)
+                .build(),
+        )
+        .route("/macs/:mac/regenerate", resource().post(regenerate).build())
+        .route(
+            "/macs/:mac/png",
+            device().get(get_png).post(post_png).build(),
+        )
+        .route(
+            "/macs/:mac/playlist",
+            resource().get(get_playlist).put(put_playlist).build(),
+        )
+        .route(
+            "/macs/:mac/groups",
+            resource().get(get_groups).put(put_groups).build(),
+        )
+        .route(
+            "/groups/:group/render_svg",
+            resource().post(render_group_svg).build(),
+        )
+        .route(
+            "/macs/:mac/render_log",
+            resource().get(get_render_log).build(),
+        )
+        .route("/stats", status().get(get_stats).build())
+        .route("/stats/daily", status().get(get_daily_stats).build())
+        .route("/ready", status().get(get_ready).build())
+        .route(
+            "/replication/status",
+            status().get(get_replication_status).build(),
+        )
+        .route("/version", status().get(get_version).build())
+        .route("/events", resource().get(get_events).build())
+        .route("/events/history", resource().get(get_event_history).build())
+        .route("/ws", device_self().get(get_ws).build())
+        .route("/macs/:mac/diff.png", resource().get(get_diff).build())
+        .route("/macs/:mac/bmp", device().get(get_bmp).build())
+        .route("/macs/:mac/raw", device().get(get_raw).build())
+        .route("/macs/:mac/bundle", device().get(get_bundle).build())
+        .route("/provision/:mac", resource().get(get_provision).build())
+        .route(
+            "/macs/:mac/provision_qr.png",
+            resource().get(get_provision_qr).build(),
+        )
+        .route(
+            "/macs/:mac/image",
+            device().get(get_image).post(post_image).build(),
+        )
+        .route("/audit", admin().get(get_audit).build())
+        .route("/config", admin().get(get_config).build())
+        .route("/admin/rerender", admin().post(start_rerender).build())
+        .route("/admin/reload", admin().post(reload).build())
+        .route(
+            "/admin/maintenance",
+            admin()
+                .post(start_maintenance)
+                .delete(end_maintenance)
+                .build(),
+        )
+        .route("/admin/verify", admin().post(verify_images).build())
+        .route("/admin/cleanup", admin().post(run_cleanup).build())
+        .route("/admin/dedup", admin().post(migrate_dedup).build())
+        .route("/admin/rerender/:id", admin().get(get_rerender).build())
+        .route(
+            "/broadcast",
+            admin().post(start_broadcast).delete(end_broadcast).build(),
+        )
+        .fallback(unknown_route);
+    let router = match state.log_level.clone() {
+        Some(log_level) => {
+            let put_level = log_level.clone();
+            router.route(
+                "/admin/log_level",
+                admin()
+                    .get(move || get_log_level(log_level.clone()))
+                    .put(move |change| put_log_level(put_level.clone(), change))
+                    .build(),
+            )
+        }
+        None => router,
+    };
+    // Innermost, so that injected failures are logged and negotiated like
+    // real ones
+    #[cfg(feature = "chaos")]
+    let router = {
+        let chaos = state.chaos.clone();
+        router
+            .route("/chaos", admin().get(get_chaos).put(put_chaos).build())
+            .layer(middleware::from_fn(
+                move |request: Request<Body>, next: Next<Body>| {
+                    chaos::inject(chaos.clone(), request, next)
+                },
+            ))
+    };
+    // Resolved requests are routed again below the layers that resolved the
+    // client, and only where the MACs they could match may be listed anyway
+    let router = if listener.serves(RouteClass::ReadImage, false) {
+        let shorthands = Arc::new(Shorthands::new(
+            state.clone(),
+            BoxCloneService::new(router.clone().into_service()),
+        ));
+        router.layer(middleware::from_fn(
+            move |request: Request<Body>, next: Next<Body>| {
+                mac_suffix::resolve(shorthands.clone(), request, next)
+            },
+        ))
+    } else {
+        router
+    };
+    let (timeouts, ip_filter) = (state.timeouts, state.ip_filter.clone());
+    let requests = state.listener_requests.clone();
+    router
+        .layer(middleware::from_fn({
+            let state = state.clone();
+            move |request: Request<Body>, next: Next<Body>| {
+                replication::mark_forwarded(state.clone(), request, next)
+            }
+        }))
+        .layer(middleware::from_fn(
+            move |request: Request<Body>, next: Next<Body>| timeout::limit(timeouts, request, next),
+        ))
+        .layer(middleware::from_fn(
+            move |request: Request<Body>, next: Next<Body>| {
+                ip_filter::resolve_client(ip_filter.clone(), request, next)
+            },
+        ))
+        .layer(middleware::from_fn(
+            move |request: Request<Body>, next: Next<Body>| {
+                listener::count(requests.clone(), listener, request, next)
+            },
+        ))
+}
+
+/// Fallback for paths that match no route. Paths below `/macs/` with a
+/// trailing slash are redirected to the route without it.
+async fn unknown_route(uri: Uri) -> Response {
+    let path = uri.path();
+    match path.strip_suffix('/') {
+        Some(trimmed) if path.starts_with("/macs/") => {
+            let location = match uri.query() {
+                Some(query) => format!("{trimmed}?{query}"),
+                None => trimmed.to_owned(),
+            };
+            (
+                StatusCode::PERMANENT_REDIRECT,
+                [(header::LOCATION, location)],
+            )
+                .into_response()
+        }
+        _ => AppError::UnknownRoute(path.to_owned()).into_response(),
+    }
+}
+
+/// The known MACs ending with the hex digits `suffix`, e.g. those printed on
+/// a tag label.
+#[debug_handler]
+async fn find_macs(
+    Path(suffix): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<Vec<String>>, AppError> {
+    let suffix = mac_suffix::parse(&suffix)?;
+    let macs = state.image_handler.macs_ending_with(&suffix).await?;
+    Ok(Json(macs.iter().map(ToString::to_string).collect()))
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    detail: bool,
+    /// Only list the members of this group.
+    group: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct MacDetail {
+    mac: String,
+    /// False if only an SVG or a legacy BMP exists.
+    has_png: bool,
+    has_svg: bool,
+    /// Only a legacy BMP exists, which isn't served until it is migrated.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    bmp_only: bool,
+    /// SHA-256 of the posted SVG that is rejected after failing to render
+    /// repeatedly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quarantined: Option<String>,
+    /// The PNG isn't the size of the panel and is served as it is.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    dimensions_stale: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct MacListingDetail {
+    macs: Vec<MacDetail>,
+    skipped: usize,
+}
+
+#[debug_handler]
+async fn get_macs(
+    Query(query): Query<ListQuery>,
+    state: State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let mut listing = state.image_handler.get_macs().await?;
+    listing.macs.sort_by_key(|entry| entry.mac);
+    if let Some(group) = query.group {
+        let group: GroupName = group.parse().bad_request()?;
+        let members = state.image_handler.group_members(&group).await?;
+        listing
+            .macs
+            .retain(|entry| members.binary_search(&entry.mac).is_ok());
+    }
+
+    if query.detail {
+        let mut macs = Vec::with_capacity(listing.macs.len());
+        for entry in &listing.macs {
+            let quarantined = state.image_handler.quarantined(entry.mac).await;
+            let dimensions_stale =
+                entry.has_png && state.image_handler.dimensions_stale(entry.mac).await;
+            macs.push(MacDetail {
+                mac: format!("{}", entry.mac),
+                has_png: entry.has_png,
+                has_svg: entry.has_svg,
+                bmp_only: entry.bmp_only,
+                quarantined: quarantined.map(|failures| failures.source_hash),
+                dimensions_stale,
+            });
+        }
+        return Ok(Json(MacListingDetail {
+            macs,
+            skipped: listing.skipped,
+        })
+        .into_response());
+    }
+
+    let macs: Vec<_> = listing
+        .macs
+        .iter()
+        .filter(|entry| entry.has_png || entry.bmp_only)
+        .map(|entry| format!("{}", entry.mac))
+        .collect();
+    Ok(Json(macs).into_response())
+}
+
+#[debug_handler]
+async fn delete_images(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    headers: HeaderMap,
+) -> Result<(), AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    check_write(&state, mac, Method::DELETE, &headers).await?;
+    state.image_handler.delete_images(mac).await?;
+    state
+        .audit_log
+        .record(Operation::Delete, mac, context, None);
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RenderQuery {
+    rerender: Option<String>,
+    timezone: Option<String>,
+    /// RFC 3339 time at which the render replaces the live image; until
+    /// then it is staged
+    activate_at: Option<String>,
+    #[serde(default)]
+    strict_lint: bool,
+    /// Replace tokens like `{{MAC}}` in the body, see [`substitute`]
+    #[serde(default)]
+    substitute: bool,
+    #[serde(flatten)]
+    render: RenderOverrides,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TargetStatus {
+    Created,
+    Updated,
+}
+
+/// Outcome of a render for one of several MACs.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct RenderTarget {
+    mac: String,
+    status: TargetStatus,
+}
+
+/// Parses the MACs of a render: comma-separated in the path, with more in
+/// `also` query parameters. Duplicates are dropped.
+fn render_targets(
+    path: &str,
+    raw_query: Option<&str>,
+    max: usize,
+) -> Result<Vec<EpdMac>, AppError> {
+    let also = raw_query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|pair| pair.strip_prefix("also="));
+    let mut macs = Vec::new();
+    let mut invalid = Vec::new();
+    for s in path.split(',').chain(also) {
+        match s.parse::<EpdMac>() {
+            Ok(mac) if !macs.contains(&mac) => macs.push(mac),
+            Ok(_) => {}
+            Err(_) => invalid.push(s),
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(AppError::BadRequest(eyre!(
+            "Invalid MACs: {}",
+            invalid.join(", ")
+        )));
+    }
+    if macs.len() > max {
+        return Err(AppError::BadRequest(eyre!(
+            "At most {max} MACs can be rendered at once, got {}.",
+            macs.len()
+        )));
+    }
+    Ok(macs)
+}
+
+/// Renders an SVG fragment for one MAC, or once for several MACs given as
+/// `/macs/<mac>,<mac>/render_svg` or with `?also=<mac>`. Several MACs get a
+/// [`RenderTarget`] each in the response.
+#[debug_handler]
+async fn render_svg(
+    Path(mac): Path<String>,
+    Query(query): Query<RenderQuery>,
+    RawQuery(raw_query): RawQuery,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Result<Response, AppError> {
+    let macs = render_targets(
+        &mac,
+        raw_query.as_deref(),
+        state.image_handler.config().max_render_targets,
+    )?;
+    for &mac in &macs {
+        check_write(&state, mac, Method::POST, &headers).await?;
+    }
+    let handler = &state.image_handler;
+    let body = upload::read_svg_body(
+        body,
+        handler.storage(),
+        handler.config().svg_spill_threshold.as_usize(),
+    )
+    .await?;
+    let body = svgz::decode_body(&body).bad_request()?;
+    let schedule = query
+        .rerender
+        .map(|s| s.parse::<Schedule>())
+        .transpose()
+        .bad_request()?;
+    let timezone = query
+        .timezone
+        .map(|tz| tz.parse().map_err(|_| eyre!("Unknown time zone {tz}")))
+        .transpose()
+        .bad_request()?;
+    let options = RerenderOptions {
+        schedule,
+        timezone,
+        render: query.render,
+        strict_lint: query.strict_lint,
+        substitute: query.substitute,
+    };
+    let priority = Priority::from_headers(&headers, Priority::Interactive)?;
+    if let Some(activate_at) = &query.activate_at {
+        let activate_at = DateTime::parse_from_rfc3339(activate_at)
+            .map_err(|e| eyre!("Invalid activate_at {activate_at}: {e}"))
+            .bad_request()?
+            .with_timezone(&Utc);
+        return defer_render(
+            &state,
+            &macs,
+            &body,
+            options,
+            priority,
+            activate_at,
+            context,
+        )
+        .await;
+    }
+    let mut rendered = state
+        .image_handler
+        .post_svg_body_to(&macs, &body, options, priority)
+        .await?;
+    for &mac in &macs {
+        record_write(&state, Operation::Render, mac, context.clone()).await;
+    }
+
+    if macs.len() > 1 {
+        let targets: Vec<_> = macs
+            .iter()
+            .zip(&rendered)
+            .map(|(mac, rendered)| RenderTarget {
+                mac: mac.to_string(),
+                status: if rendered.created {
+                    TargetStatus::Created
+                } else {
+                    TargetStatus::Updated
+                },
+            })
+            .collect();
+        return Ok(Json(targets).into_response());
+    }
+    let rendered = rendered.remove(0);
+
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let Some(subtype) = multipart::requested(accept) else {
+        return Ok(().into_response());
+    };
+    // The render log entry together with the PNG, as it may be replaced
+    // before a separate request could fetch it
+    let report = serde_json::to_vec(&rendered.record).internal()?;
+    Ok(multipart::response(
+        subtype,
+        vec![
+            (mime::APPLICATION_JSON, report.into()),
+            (mime::IMAGE_PNG, rendered.png.into()),
+        ],
+    ))
+}
+
+/// Stages the render of `macs` until `activate_at`, answering with 202 and
+/// the [`StagingStatus`] they share, or renders right away if that has
+/// passed.
+async fn defer_render(
+    state: &AppState,
+    macs: &[EpdMac],
+    body: &str,
+    options: RerenderOptions,
+    priority: Priority,
+    activate_at: DateTime<Utc>,
+    context: RequestContext,
+) -> Result<Response, AppError> {
+    if options.schedule.is_some() || options.substitute || options.strict_lint {
+        return Err(AppError::BadRequest(eyre!(
+            "activate_at can't be combined with rerender, substitute or strict_lint."
+        )));
+    }
+    let handler = &state.image_handler;
+    let mut activated = false;
+    for &mac in macs {
+        let promoted = handler
+            .stage_svg_body(mac, body, &options.render, priority, Some(activate_at))
+            .await?;
+        activated = promoted.is_some();
+        if activated {
+            record_write(state, Operation::Render, mac, context.clone()).await;
+        } else {
+            // The live image is still the old one
+            state
+                .audit_log
+                .record(Operation::Render, mac, context.clone(), None);
+        }
+    }
+    if activated {
+        return Ok(().into_response());
+    }
+    let status = StagingStatus {
+        activate_at: Some(activate_at),
+    };
+    Ok((StatusCode::ACCEPTED, Json(status)).into_response())
+}
+
+/// Renders an SVG like `render_svg`, but only into the staging slot of `mac`
+/// for review, see `POST /macs/:mac/promote`.
+#[debug_handler]
+async fn stage_svg(
+    Path(mac): Path<String>,
+    Query(overrides): Query<RenderOverrides>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Result<(), AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let handler = &state.image_handler;
+    let body = upload::read_svg_body(
+        body,
+        handler.storage(),
+        handler.config().svg_spill_threshold.as_usize(),
+    )
+    .await?;
+    let body = svgz::decode_body(&body).bad_request()?;
+    let priority = Priority::from_headers(&headers, Priority::Interactive)?;
+    handler
+        .stage_svg_body(mac, &body, &overrides, priority, None)
+        .await?;
+    Ok(())
+}
+
+/// Whether `mac` has staged images and when they become live.
+#[debug_handler]
+async fn get_staging(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<StagingStatus>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.get_staging(mac).await?))
+}
+
+#[debug_handler]
+async fn get_staging_png(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let handler = &state.image_handler;
+    let stream = handler.get_staging_png(mac).await?;
+    Ok(stream_to_response(
+        stream,
+        mime::IMAGE_PNG,
+        handler.config(),
+    ))
+}
+
+/// Makes the staged render of `mac` its live image.
+#[debug_handler]
+async fn promote(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    headers: HeaderMap,
+) -> Result<(), AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    check_write(&state, mac, Method::POST, &headers).await?;
+    state.image_handler.promote(mac).await?;
+    record_write(&state, Operation::Promote, mac, context).await;
+    Ok(())
+}
+
+#[debug_handler]
+async fn discard_staging(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<StatusCode, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    state.image_handler.discard_staging(mac).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Regenerated {
+    changed: bool,
+}
+
+/// Renders the PNG of `mac` again from its stored SVG, e.g. after the PNG
+/// went missing.
+#[debug_handler]
+async fn regenerate(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    headers: HeaderMap,
+) -> Result<Json<Regenerated>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    check_write(&state, mac, Method::POST, &headers).await?;
+    let priority = Priority::from_headers(&headers, Priority::Interactive)?;
+    let changed = state.image_handler.rerender(mac, priority).await?;
+    record_write(&state, Operation::Render, mac, context).await;
+    Ok(Json(Regenerated { changed }))
+}
+
+/// Records how the device of `mac` updated its panel, for the refresh
+/// suggested with the next image.
+#[debug_handler]
+async fn ack(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<StatusCode, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let ack: Ack = serde_json::from_slice(&body).bad_request()?;
+    state.image_handler.ack(mac, ack.refresh).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stores what the device of `mac` can decode, used by `GET
+/// /macs/:mac/payload`.
+#[debug_handler]
+async fn put_capabilities(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<Json<Capabilities>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let capabilities: Capabilities = serde_json::from_slice(&body).bad_request()?;
+    capabilities.validate().bad_request()?;
+    Ok(Json(
+        state
+            .image_handler
+            .put_capabilities(mac, capabilities)
+            .await?,
+    ))
+}
+
+#[debug_handler]
+async fn get_capabilities(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<Capabilities>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    match state.image_handler.get_capabilities(mac).await? {
+        Some(capabilities) => Ok(Json(capabilities)),
+        None => Err(AppError::NotFound(eyre!(
+            "The device of {mac} reported no capabilities."
+        ))),
+    }
+}
+
+/// Stores the opaque boot report a device sent, decoding it if its format is
+/// known.
+#[debug_handler]
+async fn post_boot_report(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<BootReport>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if content_type.parse::<Mime>().ok() != Some(mime::APPLICATION_OCTET_STREAM) {
+        return Err(AppError::UnsupportedMediaType(eyre!(
+            "Unsupported content type '{content_type}', expected application/octet-stream."
+        )));
+    }
+    let max_bytes = state
+        .image_handler
+        .config()
+        .boot_report_max_bytes
+        .as_usize();
+    if body.len() > max_bytes {
+        return Err(AppError::PayloadTooLarge(eyre!(
+            "Boot reports are at most {max_bytes} bytes."
+        )));
+    }
+    Ok(Json(state.image_handler.put_boot_report(mac, &body).await?))
+}
+
+/// The latest boot report of `mac` as the device sent it, with the time it
+/// arrived as `Last-Modified`.
+#[debug_handler]
+async fn get_boot_report(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let (boot_report, report) = state.image_handler.get_boot_report(mac).await?;
+    let validators = Validators {
+        etag: format!("\"{}\"", hex::encode(Sha256::digest(&report))),
+        last_modified: boot_report.received.trunc_subsecs(0),
+    };
+    Ok((
+        validators.headers(),
+        [(
+            header::CONTENT_TYPE,
+            mime::APPLICATION_OCTET_STREAM.as_ref(),
+        )],
+        report,
+    )
+        .into_response())
+}
+
+#[debug_handler]
+async fn get_checkin(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<Checkin>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.get_checkin(mac).await?))
+}
+
+/// Stores how the images of `mac` are put onto its panel, see
+/// [`DisplayProfile`]. Stored images are left alone until the next render.
+#[debug_handler]
+async fn put_profile(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    body: Bytes,
+) -> Result<Json<Profile>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let update: ProfileUpdate = serde_json::from_slice(&body).bad_request()?;
+    let profile = state
+        .image_handler
+        .put_profile(mac, update.display, update.regions, update.lint)
+        .await?;
+    state
+        .audit_log
+        .record(Operation::DisplayProfile, mac, context, None);
+    Ok(Json(profile))
+}
+
+/// Body of `PUT /macs/:mac/profile`, replacing all of the stored profile.
+#[derive(Debug, Deserialize)]
+struct ProfileUpdate {
+    #[serde(flatten)]
+    display: DisplayProfile,
+    #[serde(default)]
+    regions: Regions,
+    #[serde(default)]
+    lint: LintOverrides,
+}
+
+/// The panel of `mac` with the display profile its renders use.
+#[debug_handler]
+async fn get_profile(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<Profile>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.get_profile(mac).await?))
+}
+
+/// Stores render options of `mac` that renders without them in their query
+/// use, see [`RenderOptions::resolve`](render_options::RenderOptions::resolve).
+#[debug_handler]
+async fn put_render_options(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    body: Bytes,
+) -> Result<Json<RenderOverrides>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let overrides: RenderOverrides = serde_json::from_slice(&body).bad_request()?;
+    let overrides = state
+        .image_handler
+        .put_render_options(mac, overrides)
+        .await?;
+    state
+        .audit_log
+        .record(Operation::RenderOptions, mac, context, None);
+    Ok(Json(overrides))
+}
+
+#[debug_handler]
+async fn get_render_options(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<RenderOverrides>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.get_render_options(mac).await?))
+}
+
+/// The image of `mac` encoded for its device, described by the
+/// `X-EPS-Format` and `X-EPS-Compression` headers.
+#[debug_handler]
+async fn get_payload(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let handler = &state.image_handler;
+    let payload = handler.payload(mac).await?;
+    let headers = payload.headers();
+    let content_type = payload.content_type();
+    let response = bytes_to_response(payload.data.into(), content_type, handler.config());
+    Ok(image_response(&state, mac, (headers, response).into_response()).await)
+}
+
+/// Stores headers added to the image responses of `mac`, see
+/// [`ResponseHeaders`].
+#[debug_handler]
+async fn put_response_headers(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    body: Bytes,
+) -> Result<Json<ResponseHeaders>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let headers: ResponseHeaders = serde_json::from_slice(&body).bad_request()?;
+    let headers = headers.validate().bad_request()?;
+    let headers = state
+        .image_handler
+        .put_response_headers(mac, headers)
+        .await?;
+    state
+        .audit_log
+        .record(Operation::ResponseHeaders, mac, context, None);
+    Ok(Json(headers))
+}
+
+#[debug_handler]
+async fn get_response_headers(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<ResponseHeaders>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.get_response_headers(mac).await?))
+}
+
+#[debug_handler]
+async fn delete_response_headers(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+) -> Result<StatusCode, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    state
+        .image_handler
+        .put_response_headers(mac, ResponseHeaders::default())
+        .await?;
+    state
+        .audit_log
+        .record(Operation::ResponseHeaders, mac, context, None);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Finishes one of the image responses of `mac`: counts it for the daily
+/// statistics and adds the custom headers of `mac`. They are left out if they
+/// can't be read, rather than failing the response.
+async fn image_response(state: &AppState, mac: EpdMac, mut response: Response) -> Response {
+    state.image_handler.count_fetch(mac);
+    poll_headers(state, mac, response.headers_mut()).await;
+    match state.image_handler.get_response_headers(mac).await {
+        Ok(headers) => headers.apply(response.headers_mut()),
+        Err(e) => tracing::warn!("Could not read the response headers of {mac}: {e}"),
+    }
+    response
+}
+
+/// Adds `X-EPS-Suggested-Poll-Seconds` for `mac` to `headers`.
+async fn poll_headers(state: &AppState, mac: EpdMac, headers: &mut HeaderMap) {
+    match state.image_handler.poll_interval(mac).await {
+        Ok(interval) => headers.extend(poll_interval::headers(interval.suggested_secs)),
+        Err(e) => tracing::warn!("Could not read the poll interval of {mac}: {e}"),
+    }
+}
+
+/// How often the device of `mac` is told to poll and what that is learned
+/// from.
+#[debug_handler]
+async fn get_poll_interval(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<PollInterval>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.poll_interval(mac).await?))
+}
+
+/// Tells the device of `mac` to poll at a fixed interval instead of the one
+/// learned from its changes.
+#[debug_handler]
+async fn put_poll_interval(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    body: Bytes,
+) -> Result<Json<PollInterval>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let update: PollOverride = serde_json::from_slice(&body).bad_request()?;
+    let interval = state
+        .image_handler
+        .put_poll_override(mac, Some(update.override_secs))
+        .await?;
+    state
+        .audit_log
+        .record(Operation::PollInterval, mac, context, None);
+    Ok(Json(interval))
+}
+
+#[debug_handler]
+async fn delete_poll_interval(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+) -> Result<Json<PollInterval>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let interval = state.image_handler.put_poll_override(mac, None).await?;
+    state
+        .audit_log
+        .record(Operation::PollInterval, mac, context, None);
+    Ok(Json(interval))
+}
+
+/// Stores a playlist whose entries replace the image of `mac` when their
+/// cron expressions fire.
+#[debug_handler]
+async fn put_playlist(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<PlaylistStatus>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let playlist: Playlist = serde_json::from_slice(&body).bad_request()?;
+    check_write(&state, mac, Method::PUT, &headers).await?;
+    let status = state.image_handler.put_playlist(mac, playlist).await?;
+    record_write(&state, Operation::Playlist, mac, context).await;
+    Ok(Json(status))
+}
+
+#[debug_handler]
+async fn get_playlist(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<PlaylistStatus>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.get_playlist(mac).await?))
+}
+
+/// Replaces the groups of `mac` with those in the JSON array of the body.
+#[debug_handler]
+async fn put_groups(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    body: Bytes,
+) -> Result<Json<BTreeSet<GroupName>>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let groups: BTreeSet<GroupName> = serde_json::from_slice(&body).bad_request()?;
+    let groups = state.image_handler.put_groups(mac, groups).await?;
+    state
+        .audit_log
+        .record(Operation::Groups, mac, context, None);
+    Ok(Json(groups))
+}
+
+#[debug_handler]
+async fn get_groups(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<BTreeSet<GroupName>>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.get_groups(mac).await?))
+}
+
+/// Renders an SVG fragment for every member of a group, see [`GroupRender`].
+/// The renders are batch renders unless requested otherwise.
+#[debug_handler]
+async fn render_group_svg(
+    Path(group): Path<String>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Vec<GroupRenderResult>>, AppError> {
+    let group: GroupName = group.parse().bad_request()?;
+    let render: GroupRender = serde_json::from_slice(&body).bad_request()?;
+    let priority = Priority::from_headers(&headers, Priority::Batch)?;
+    let results = state
+        .image_handler
+        .render_group(&group, &render, priority)
+        .await?;
+
+    let mut response = Vec::with_capacity(results.len());
+    for (mac, result) in results {
+        let error = match result {
+            Ok(()) => {
+                record_write(&state, Operation::Render, mac, context.clone()).await;
+                None
+            }
+            Err(e) => {
+                tracing::warn!("Render of {mac} in group {group} failed: {e:#}");
+                Some(e.body())
+            }
+        };
+        response.push(GroupRenderResult {
+            mac: mac.to_string(),
+            error,
+        });
+    }
+    Ok(Json(response))
+}
+
+/// Evaluates the preconditions of a write to `mac` against its current PNG.
+async fn check_write(
+    state: &AppState,
+    mac: EpdMac,
+    method: Method,
+    headers: &HeaderMap,
+) -> Result<(), AppError> {
+    let validators = state
+        .image_handler
+        .validators(mac, &mime::IMAGE_PNG, None)
+        .await?;
+    precondition::check_write(&method, headers, validators.as_ref())
+}
+
+/// Adds a successful write of `mac` to the audit log.
+async fn record_write(
+    state: &AppState,
+    operation: Operation,
+    mac: EpdMac,
+    context: RequestContext,
+) {
+    let hash = state.image_handler.png_hash(mac).await;
+    state.audit_log.record(operation, mac, context, hash);
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditQuery {
+    mac: Option<String>,
+    #[serde(default = "default_audit_limit")]
+    limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+#[debug_handler]
+async fn get_audit(
+    Query(query): Query<AuditQuery>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<Vec<AuditEntry>>, AppError> {
+    let mac = query.mac.map(|mac| mac.parse()).transpose().bad_request()?;
+    let entries = state.audit_log.query(mac, query.limit).await.internal()?;
+    Ok(Json(entries))
+}
+
+#[debug_handler]
+async fn get_render_log(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<Vec<RenderRecord>>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    Ok(Json(state.image_handler.get_render_log(mac).await?))
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Percentiles {
+    count: usize,
+    p50: Option<u64>,
+    p95: Option<u64>,
+}
+
+impl Percentiles {
+    fn new(mut values: Vec<u64>) -> Self {
+        values.sort_unstable();
+        // Nearest-rank method
+        let rank = |p: usize| {
+            let index = (values.len() * p).div_ceil(100).max(1) - 1;
+            values.get(index).copied()
+        };
+        Percentiles {
+            count: values.len(),
+            p50: rank(50),
+            p95: rank(95),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Stats {
+    render_duration_ms: Percentiles,
+    /// Requests for missing images answered without touching the disk
+    negative_cache_hits: u64,
+    /// Renders that took longer than `--render-stuck-secs`
+    render_stuck_total: u64,
+    /// Progress of `--warmup-derived`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    warmup_percent: Option<f64>,
+    /// Renders waiting for a permit
+    render_queue: QueueDepth,
+    /// Renders and conversions that panicked and failed with a 500
+    panics_total: u64,
+    /// Average coverage of the latest render of each MAC since startup;
+    /// very low values usually mean broken renders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    average_coverage_percent: Option<f64>,
+    /// Fetches of PNGs and SVGs served from `--image-cache-bytes`, and those
+    /// that had to read the file
+    image_cache_hits: u64,
+    image_cache_misses: u64,
+    /// Latest health probes of the integrations, see `--require-dependency`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dependencies: BTreeMap<Dependency, Health>,
+    /// Logical and physical bytes of the images with `--dedup`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dedup: Option<DedupUsage>,
+    /// Poll intervals last suggested to each MAC since startup
+    suggested_poll_secs: Percentiles,
+    /// Requests answered by each listener since startup, see
+    /// `--device-listen`
+    requests_total: BTreeMap<Listener, u64>,
+}
+
+#[debug_handler]
+async fn get_stats(state: State<Arc<AppState>>) -> Json<Stats> {
+    Json(Stats {
+        render_duration_ms: Percentiles::new(state.image_handler.render_durations()),
+        negative_cache_hits: state.image_handler.negative_cache_hits(),
+        render_stuck_total: state.image_handler.watchdog().stuck_total(),
+        warmup_percent: state.image_handler.warmup().percent(),
+        render_queue: state.image_handler.render_queue_depth(),
+        panics_total: state.image_handler.panics().total(),
+        average_coverage_percent: state.image_handler.average_coverage(),
+        image_cache_hits: state.image_handler.image_cache_hits(),
+        image_cache_misses: state.image_handler.image_cache_misses(),
+        dependencies: state.image_handler.dependencies().health(),
+        dedup: state.image_handler.dedup_usage().await,
+        suggested_poll_secs: Percentiles::new(state.image_handler.poll_suggestions()),
+        requests_total: state.listener_requests.totals(),
+    })
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StatsFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DailyStatsQuery {
+    /// First day, like `2024-03-01`
+    from: Option<NaiveDate>,
+    /// Last day, included
+    to: Option<NaiveDate>,
+    #[serde(default)]
+    format: StatsFormat,
+}
+
+/// Renders and fetches per day and MAC, as JSON or as CSV for spreadsheets.
+#[debug_handler]
+async fn get_daily_stats(
+    Query(query): Query<DailyStatsQuery>,
+    state: State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(AppError::BadRequest(eyre!(
+                "The range from {from} to {to} is empty."
+            )));
+        }
+    }
+    let handler = &state.image_handler;
+    handler.flush_stats().await.internal()?;
+    let rows = handler
+        .daily_stats()
+        .rows(query.from, query.to)
+        .await
+        .internal()?;
+    Ok(match query.format {
+        StatsFormat::Json => Json(rows).into_response(),
+        StatsFormat::Csv => (
+            [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+            daily_stats::to_csv(&rows),
+        )
+            .into_response(),
+    })
+}
+
+/// Version, build and capabilities of the server, for clients to detect
+/// features with.
+#[debug_handler]
+async fn get_version(state: State<Arc<AppState>>) -> Json<VersionInfo> {
+    Json(VersionInfo::new(state.image_handler.config()))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Readiness {
+    /// `degraded` while an integration that is not required is failing
+    status: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dependencies: BTreeMap<Dependency, Health>,
+}
+
+/// Readiness probe, failing during maintenance, while a render hangs and
+/// while a dependency given by `--require-dependency` is failing.
+#[debug_handler]
+async fn get_ready(state: State<Arc<AppState>>) -> Result<Json<Readiness>, AppError> {
+    if let Some(maintenance) = state.maintenance.active(Utc::now()) {
+        return Err(AppError::Maintenance(maintenance));
+    }
+    if state.image_handler.watchdog().degraded() {
+        return Err(AppError::ServiceUnavailable(eyre!(
+            "A render has been running for more than {}.",
+            state.image_handler.config().render_degraded_secs
+        )));
+    }
+    let dependencies = state.image_handler.dependencies();
+    let failing = dependencies.failing_required();
+    if !failing.is_empty() {
+        let names: Vec<_> = failing
+            .into_iter()
+            .map(dependency::dependency_name)
+            .collect();
+        return Err(AppError::ServiceUnavailable(eyre!(
+            "Required dependencies are failing: {}.",
+            names.join(", ")
+        )));
+    }
+    let status = if dependencies.degraded() {
+        "degraded"
+    } else {
+        "ready"
+    };
+    Ok(Json(Readiness {
+        status: status.to_owned(),
+        dependencies: dependencies.health(),
+    }))
+}
+
+#[debug_handler]
+async fn get_replication_status(
+    state: State<Arc<AppState>>,
+) -> Result<Json<ReplicationStatus>, AppError> {
+    let replicator = state.image_handler.replicator().ok_or_else(|| {
+        AppError::NotFound(eyre!("Replication is not configured, see --replica-url."))
+    })?;
+    Ok(Json(replicator.status(Utc::now())))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EventQuery {
+    /// Only events after this sequence number
+    #[serde(default)]
+    since: u64,
+    mac: Option<String>,
+}
+
+#[debug_handler]
+async fn get_event_history(
+    Query(query): Query<EventQuery>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<History>, AppError> {
+    let mac = query.mac.map(|mac| mac.parse()).transpose().bad_request()?;
+    Ok(Json(state.image_handler.events().history(query.since, mac)))
+}
+
+/// Streams change events as server-sent events with their sequence number
+/// as id. A client resuming with `Last-Event-ID` first gets the events it
+/// missed, or a `resync` event if they are no longer buffered.
+#[debug_handler]
+async fn get_events(
+    Query(query): Query<EventQuery>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, serde_json::Error>>>, AppError> {
+    let mac: Option<EpdMac> = query.mac.map(|mac| mac.parse()).transpose().bad_request()?;
+    let since = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let (history, receiver) = state.image_handler.events().subscribe(since, mac);
+
+    let resync = history.resync.then(resync_event);
+    let backlog = resync
+        .into_iter()
+        .chain(history.events.iter().map(sse_event))
+        .collect::<Vec<_>>();
+    let filter = mac.map(|mac| mac.to_string());
+    let live = stream::unfold(receiver, move |mut receiver| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if matches!(&filter, Some(mac) if *mac != event.mac) => {}
+                    Ok(event) => return Some((sse_event(&event), receiver)),
+                    Err(RecvError::Lagged(_)) => return Some((resync_event(), receiver)),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    Ok(Sse::new(stream::iter(backlog).chain(live)).keep_alive(KeepAlive::default()))
+}
+
+/// Opens a WebSocket session in which devices subscribe to MACs, get their
+/// changes pushed and send acks and check-ins, see [`websocket::session`].
+#[debug_handler]
+async fn get_ws(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
+    ws.on_upgrade(move |socket| websocket::session(socket, state))
+}
+
+fn sse_event(event: &Event) -> Result<sse::Event, serde_json::Error> {
+    sse::Event::default()
+        .id(event.seq.to_string())
+        .event(event.kind.as_str())
+        .json_data(event)
+}
+
+/// Tells the client that it missed events and should reload all MACs.
+fn resync_event() -> Result<sse::Event, serde_json::Error> {
+    Ok(sse::Event::default().event("resync").data("{}"))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RerenderQuery {
+    mac_prefix: Option<String>,
+    /// Only MACs whose PNG isn't the size of the panel.
+    #[serde(default)]
+    stale_only: bool,
+}
+
+/// Starts re-rendering all stored SVGs, e.g. after a configuration change.
+/// Progress is polled with `get_rerender`.
+#[debug_handler]
+async fn start_rerender(
+    Query(query): Query<RerenderQuery>,
+    state: State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<RerenderJob>), AppError> {
+    let job = state
+        .rerender_jobs
+        .start(
+            state.image_handler.clone(),
+            query.mac_prefix.as_deref(),
+            query.stale_only,
+        )
+        .await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[debug_handler]
+async fn get_rerender(
+    Path(id): Path<u64>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<RerenderJob>, AppError> {
+    state
+        .rerender_jobs
+        .get(id)
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(eyre!("No re-render job with id {id}.")))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum VerifyFix {
+    /// Render mismatched and missing PNGs from their SVG
+    Regenerate,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VerifyQuery {
+    /// Largest difference of a color channel that still counts as equal
+    #[serde(default)]
+    pixel_tolerance: u8,
+    fix: Option<VerifyFix>,
+}
+
+/// Lets `mac` render the SVG again that was quarantined after failing
+/// repeatedly.
+#[debug_handler]
+async fn clear_quarantine(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<(), AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    state.image_handler.clear_quarantine(mac).await
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigView {
+    /// Requirements of each route class, in the order they are checked.
+    policy: BTreeMap<RouteClass, Vec<Requirement>>,
+    read_only: bool,
+    limits: Limits,
+}
+
+/// The active configuration, without secrets.
+#[debug_handler]
+async fn get_config(state: State<Arc<AppState>>) -> Json<ConfigView> {
+    Json(ConfigView {
+        policy: state.policy.classes().clone(),
+        read_only: state.image_handler.config().read_only,
+        limits: state.image_handler.config().into(),
+    })
+}
+
+/// Answers image and mutating routes with 503 until the given time, e.g.
+/// while the image directory is moved. Persists across restarts.
+#[debug_handler]
+async fn start_maintenance(
+    state: State<Arc<AppState>>,
+    Json(maintenance): Json<Maintenance>,
+) -> Result<(), AppError> {
+    if maintenance.until <= Utc::now() {
+        return Err(AppError::BadRequest(eyre!(
+            "The end of the maintenance {} has passed.",
+            maintenance.until
+        )));
+    }
+    state.maintenance.start(maintenance).await.internal()
+}
+
+#[debug_handler]
+async fn end_maintenance(state: State<Arc<AppState>>) -> Result<(), AppError> {
+    state.maintenance.end().await.internal()
+}
+
+/// Shows an SVG fragment on every MAC until `DELETE /broadcast` restores
+/// what they showed before, e.g. for announcements to the whole fleet.
+#[debug_handler]
+async fn start_broadcast(
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    body: Bytes,
+) -> Result<Json<Vec<BroadcastResult>>, AppError> {
+    let broadcast: Broadcast = serde_json::from_slice(&body).bad_request()?;
+    let results = state.image_handler.start_broadcast(broadcast).await?;
+    Ok(Json(
+        broadcast_results(&state, results, context, "Showing the broadcast on").await,
+    ))
+}
+
+#[debug_handler]
+async fn end_broadcast(
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+) -> Result<Json<Vec<BroadcastResult>>, AppError> {
+    let results = state.image_handler.end_broadcast().await?;
+    Ok(Json(
+        broadcast_results(&state, results, context, "Restoring").await,
+    ))
+}
+
+/// Audits the MACs a broadcast changed and reports the failures of the
+/// others.
+async fn broadcast_results(
+    state: &AppState,
+    results: Vec<(EpdMac, Result<(), AppError>)>,
+    context: RequestContext,
+    action: &str,
+) -> Vec<BroadcastResult> {
+    let mut response = Vec::with_capacity(results.len());
+    for (mac, result) in results {
+        let error = match result {
+            Ok(()) => {
+                record_write(state, Operation::Broadcast, mac, context.clone()).await;
+                None
+            }
+            Err(e) => {
+                tracing::warn!("{action} {mac} failed: {e:#}");
+                Some(e.body())
+            }
+        };
+        response.push(BroadcastResult {
+            mac: mac.to_string(),
+            error,
+        });
+    }
+    response
+}
+
+async fn get_log_level(log_level: Arc<LogLevel>) -> Json<LogFilter> {
+    Json(log_level.current())
+}
+
+/// Swaps the log filter without a restart, for a while if a duration is
+/// given.
+async fn put_log_level(
+    log_level: Arc<LogLevel>,
+    Json(change): Json<LogLevelChange>,
+) -> Result<Json<LogFilter>, AppError> {
+    Ok(Json(log_level.set(change).bad_request()?))
+}
+
+#[cfg(feature = "chaos")]
+#[debug_handler]
+async fn get_chaos(state: State<Arc<AppState>>) -> Json<chaos::ChaosConfig> {
+    Json(state.chaos.config())
+}
+
+/// Replaces the rules of the failures injected into responses.
+#[cfg(feature = "chaos")]
+#[debug_handler]
+async fn put_chaos(
+    state: State<Arc<AppState>>,
+    Json(config): Json<chaos::ChaosConfig>,
+) -> Result<Json<chaos::ChaosConfig>, AppError> {
+    state.chaos.set(config).bad_request()?;
+    Ok(Json(state.chaos.config()))
+}
+
+/// Re-reads the htpasswd file and `--render-script`.
+#[debug_handler]
+async fn reload(state: State<Arc<AppState>>) -> Result<(), AppError> {
+    state.credentials.reload().internal()?;
+    #[cfg(feature = "script")]
+    state.image_handler.reload_render_script()?;
+    Ok(())
+}
+
+/// Checks that every stored PNG is a render of its stored SVG, e.g. after an
+/// unclean shutdown.
+#[debug_handler]
+async fn verify_images(
+    Query(query): Query<VerifyQuery>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<Vec<VerifyReport>>, AppError> {
+    let regenerate = query.fix == Some(VerifyFix::Regenerate);
+    let reports = state
+        .image_handler
+        .verify(query.pixel_tolerance, regenerate)
+        .await?;
+    Ok(Json(reports))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CleanupQuery {
+    /// Also remove the orphaned files instead of only listing them
+    #[serde(default)]
+    delete: bool,
+}
+
+/// Removes leftovers of interrupted writes, see [`ImageHandler::cleanup`].
+#[debug_handler]
+async fn run_cleanup(
+    Query(query): Query<CleanupQuery>,
+    state: State<Arc<AppState>>,
+) -> Result<Json<CleanupReport>, AppError> {
+    let report = state.image_handler.cleanup(query.delete).await?;
+    Ok(Json(report))
+}
+
+#[debug_handler]
+async fn migrate_dedup(state: State<Arc<AppState>>) -> Result<Json<DedupReport>, AppError> {
+    let report = state.image_handler.migrate_dedup().await?;
+    Ok(Json(report))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImageQuery {
+    #[serde(default)]
+    fit: Fit,
+}
+
+#[debug_handler]
+async fn post_image(
+    Path(mac): Path<String>,
+    Query(query): Query<ImageQuery>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(), AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    check_write(&state, mac, Method::POST, &headers).await?;
+    let format = upload_format(
+        &headers,
+        &body,
+        &[ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Bmp],
+    )?;
+    state
+        .image_handler
+        .post_image(mac, format, body.to_vec(), query.fit)
+        .await?;
+    record_write(&state, Operation::Upload, mac, context).await;
+    Ok(())
+}
+
+/// The format of an uploaded image, one of `accepted`. Its magic bytes
+/// decide unless they are unknown; a `Content-Type` other than
+/// `application/octet-stream` has to agree with them, so that nothing is
+/// decoded as something it is not labeled as.
+fn upload_format(
+    headers: &HeaderMap,
+    data: &[u8],
+    accepted: &[ImageFormat],
+) -> Result<ImageFormat, AppError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let mime = content_type.parse::<Mime>().ok();
+    let generic = content_type.is_empty()
+        || mime.as_ref().map(Mime::essence_str) == Some(mime::APPLICATION_OCTET_STREAM.as_ref());
+    let sniffed = raster::sniff_format(data);
+    let format = if generic {
+        sniffed
+    } else {
+        let claimed = mime.as_ref().and_then(raster::format_from_mime);
+        match (claimed, sniffed) {
+            (Some(claimed), Some(sniffed)) if claimed != sniffed => {
+                return Err(AppError::UnsupportedMediaType(eyre!(
+                    "The upload is labeled {content_type} but is {}.",
+                    sniffed.to_mime_type()
+                )))
+            }
+            // Left to the decoder to reject
+            (claimed, _) => claimed,
+        }
+    };
+    let expected: Vec<_> = accepted.iter().map(ImageFormat::to_mime_type).collect();
+    let expected = expected.join(", ");
+    match format {
+        Some(format) if accepted.contains(&format) => Ok(format),
+        Some(format) => Err(AppError::UnsupportedMediaType(eyre!(
+            "The upload is {}, expected {expected}.",
+            format.to_mime_type()
+        ))),
+        None if generic => Err(AppError::UnsupportedMediaType(eyre!(
+            "The upload is none of {expected}."
+        ))),
+        None => Err(AppError::UnsupportedMediaType(eyre!(
+            "Unsupported content type '{content_type}', expected {expected}."
+        ))),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PngQuery {
+    autofix: Option<Autofix>,
+}
+
+/// Stores a PNG made for the panel; unlike `post_image` it is not fitted
+/// unless `autofix` is given.
+#[debug_handler]
+async fn post_png(
+    Path(mac): Path<String>,
+    Query(query): Query<PngQuery>,
+    state: State<Arc<AppState>>,
+    context: RequestContext,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(), AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    check_write(&state, mac, Method::POST, &headers).await?;
+    upload_format(&headers, &body, &[ImageFormat::Png])?;
+    state
+        .image_handler
+        .post_png(mac, body.to_vec(), query.autofix)
+        .await?;
+    record_write(&state, Operation::Upload, mac, context).await;
+    Ok(())
+}
+
+#[debug_handler]
+async fn get_svg(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let accept_encoding = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok());
+    let gzip = accept::accepts_encoding(accept_encoding, "gzip");
+    let validators = state
+        .image_handler
+        .validators(mac, &mime::IMAGE_SVG, gzip.then_some("gzip"))
+        .await?;
+    if let Some(response) = precondition::check_read(&headers, validators.as_ref())? {
+        return Ok(response);
+    }
+
+    let response = if gzip {
+        let svgz = state.image_handler.get_svg_gzip(mac).await?;
+        (
+            [(header::CONTENT_ENCODING, "gzip")],
+            bytes_to_response(svgz.into(), mime::IMAGE_SVG, state.image_handler.config()),
+        )
+            .into_response()
+    } else if let Some(svg) = state
+        .image_handler
+        .cached_image(mac, &mime::IMAGE_SVG)
+        .await?
+    {
+        bytes_to_response(svg, mime::IMAGE_SVG, state.image_handler.config())
+    } else {
+        let stream = state.image_handler.get_svg(mac).await?;
+        stream_to_response(stream, mime::IMAGE_SVG, state.image_handler.config())
+    };
+    let response = (
+        validator_headers(validators),
+        [(header::VARY, "accept-encoding")],
+        response,
+    )
+        .into_response();
+    Ok(image_response(&state, mac, response).await)
+}
+
+/// The SVG document as posted, before its text was converted to paths.
+#[debug_handler]
+async fn get_original_svg(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let handler = &state.image_handler;
+    let stream = handler.get_original_svg(mac).await?;
+    Ok(stream_to_response(
+        stream,
+        mime::IMAGE_SVG,
+        handler.config(),
+    ))
+}
+
+#[debug_handler]
+async fn get_png(
+    Path(mac): Path<String>,
+    Query(region): Query<RegionQuery>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    if let Some(name) = &region.region_name {
+        let compress = Compression::None;
+        return get_region(
+            &state,
+            mac,
+            mime::IMAGE_PNG,
+            name,
+            region.align,
+            compress,
+            &headers,
+        )
+        .await;
+    }
+    get_representation(&state, mac, mime::IMAGE_PNG, &headers).await
+}
+
+#[debug_handler]
+async fn get_bmp(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    get_representation(&state, mac, mime::IMAGE_BMP, &headers).await
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct RawFormatQuery {
+    #[serde(default)]
+    pub compress: Compression,
+}
+
+#[debug_handler]
+async fn get_raw(
+    Path(mac): Path<String>,
+    Query(query): Query<RawFormatQuery>,
+    Query(region): Query<RegionQuery>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    if let Some(name) = &region.region_name {
+        let mime = mime::APPLICATION_OCTET_STREAM;
+        return get_region(
+            &state,
+            mac,
+            mime,
+            name,
+            region.align,
+            query.compress,
+            &headers,
+        )
+        .await;
+    }
+    if query.compress == Compression::None {
+        return get_representation(&state, mac, mime::APPLICATION_OCTET_STREAM, &headers).await;
+    }
+
+    let handler = &state.image_handler;
+    let validators = handler
+        .validators(mac, &mime::APPLICATION_OCTET_STREAM, Some("rle"))
+        .await?;
+    if let Some(response) = precondition::check_read(&headers, validators.as_ref())? {
+        return Ok(response);
+    }
+    let refresh_hint = handler.refresh_hint(mac).await?;
+    let raw = handler.get_raw(mac).await?;
+    let encoded = rle::encode(&raw);
+    let mut headers = validator_headers(validators);
+    headers.extend(refresh_hint.headers());
+    headers.extend(capabilities::compression_headers(query.compress, raw.len()));
+    let response = bytes_to_response(
+        encoded.into(),
+        mime::APPLICATION_OCTET_STREAM,
+        handler.config(),
+    );
+    Ok(image_response(&state, mac, (headers, response).into_response()).await)
+}
+
+/// Selects a region of the profile of a MAC, see [`get_region`].
+#[derive(Debug, Default, Deserialize)]
+struct RegionQuery {
+    region_name: Option<String>,
+    #[serde(default)]
+    align: Align,
+}
+
+/// The region `name` of the image of `mac` as `mime`, PNG or raw.
+async fn get_region(
+    state: &AppState,
+    mac: EpdMac,
+    mime: Mime,
+    name: &str,
+    align: Align,
+    compress: Compression,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let handler = &state.image_handler;
+    let region = handler.region(mac, name, &mime, align).await?;
+    // Changes with the PNG as well as with the region
+    let mut variant = format!("{}x{}+{}+{}", region.w, region.h, region.x, region.y);
+    if compress == Compression::Rle {
+        variant += "-rle";
+    }
+    let validators = handler.validators(mac, &mime, Some(&variant)).await?;
+    if let Some(response) = precondition::check_read(headers, validators.as_ref())? {
+        return Ok(response);
+    }
+    let data = handler.crop(mac, region, &mime).await?;
+    let mut response_headers = validator_headers(validators);
+    response_headers.extend(handler.refresh_hint(mac).await?.headers());
+    let data = match compress {
+        Compression::None => data,
+        Compression::Rle => {
+            response_headers.extend(capabilities::compression_headers(compress, data.len()));
+            rle::encode(&data).into()
+        }
+    };
+    let response = bytes_to_response(data, mime, handler.config());
+    Ok(image_response(state, mac, (response_headers, response).into_response()).await)
+}
+
+#[derive(Debug, Deserialize)]
+struct BundleQuery {
+    /// Comma separated parts, like `svg,raw`.
+    include: Option<String>,
+}
+
+/// Everything a gateway caches for `mac`, in one request.
+#[debug_handler]
+async fn get_bundle(
+    Path(mac): Path<String>,
+    Query(query): Query<BundleQuery>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let include: Include = match query.include {
+        Some(include) => include.parse().bad_request()?,
+        None => Include::default(),
+    };
+    let handler = &state.image_handler;
+    let Some(validators) = handler.bundle_validators(mac, &include).await? else {
+        return Err(AppError::NotFound(eyre!("No images for MAC {mac}.")));
+    };
+    if let Some(response) = precondition::check_read(&headers, Some(&validators))? {
+        return Ok(response);
+    }
+    let bundle = handler
+        .bundle(mac, &include, validators.etag.clone())
+        .await?;
+    Ok((validators.headers(), Json(bundle)).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct DiffQuery {
+    /// `previous` or the timestamp of a version.
+    against: Option<String>,
+}
+
+/// Highlights what changed with the last update of `mac`, for review in a
+/// browser. Nothing is stored.
+#[debug_handler]
+async fn get_diff(
+    Path(mac): Path<String>,
+    Query(query): Query<DiffQuery>,
+    state: State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let against = match query.against {
+        Some(against) => against.parse().bad_request()?,
+        None => Against::Previous,
+    };
+    let png = state.image_handler.diff(mac, against).await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime::IMAGE_PNG.as_ref()),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+/// Page for installing a display, linked by the QR code of
+/// [`get_provision_qr`].
+#[debug_handler]
+async fn get_provision(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let has_image = state.image_handler.png_hash(mac).await.is_some();
+    Ok(Html(provision::page(
+        state.image_handler.config(),
+        mac,
+        has_image,
+    )))
+}
+
+/// QR code of the provisioning page of `mac`, for printing on stickers.
+#[debug_handler]
+async fn get_provision_qr(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let url = provision::url(state.image_handler.config(), &headers, mac).bad_request()?;
+    let png = provision::qr_png(&url).internal()?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime::IMAGE_PNG.as_ref()),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+/// Serves the format the client prefers according to its `Accept` header.
+#[debug_handler]
+async fn get_image(
+    Path(mac): Path<String>,
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let mac = EpdMac::from_path(&mac)?;
+    let supported = [
+        mime::IMAGE_PNG,
+        mime::IMAGE_SVG,
+        mime::IMAGE_BMP,
+        mime::APPLICATION_OCTET_STREAM,
+    ];
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let mime = accept::negotiate(accept, &supported).ok_or_else(|| {
+        let supported: Vec<_> = supported.iter().map(Mime::to_string).collect();
+        AppError::NotAcceptable(eyre!(
+            "None of the accepted types is available, supported types are: {}",
+            supported.join(", ")
+        ))
+    })?;
+
+    let response = get_representation(&state, mac, mime.clone(), &headers).await?;
+    Ok(([(header::VARY, "accept")], response).into_response())
+}
+
+/// Responds with the image of `mac` as `mime`, honoring the preconditions
+/// of the request.
+async fn get_representation(
+    state: &AppState,
+    mac: EpdMac,
+    mime: Mime,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let handler = &state.image_handler;
+    // Only for the formats devices put on their panels
+    let on_panel = matches!(mime.subtype().as_str(), "png" | "octet-stream");
+    let stale = on_panel && handler.fix_stale_dimensions(mac).await;
+    let validators = handler.validators(mac, &mime, None).await?;
+    if let Some(mut response) = precondition::check_read(headers, validators.as_ref())? {
+        // Unchanged images are what most polls get
+        if on_panel {
+            poll_headers(state, mac, response.headers_mut()).await;
+        }
+        return Ok(response);
+    }
+
+    let refresh_hint = if on_panel {
+        Some(handler.refresh_hint(mac).await?)
+    } else {
+        None
+    };
+    let response = match mime.subtype().as_str() {
+        "png" | "svg" => match handler.cached_image(mac, &mime).await? {
+            Some(bytes) => bytes_to_response(bytes, mime, handler.config()),
+            None if mime == mime::IMAGE_SVG => {
+                stream_to_response(handler.get_svg(mac).await?, mime, handler.config())
+            }
+            None => stream_to_response(handler.get_png(mac).await?, mime, handler.config()),
+        },
+        "bmp" => bytes_to_response(handler.get_bmp(mac).await?, mime, handler.config()),
+        _ => bytes_to_response(handler.get_raw(mac).await?, mime, handler.config()),
+    };
+    let mut headers = validator_headers(validators);
+    if let Some(refresh_hint) = refresh_hint {
+        headers.extend(refresh_hint.headers());
+    }
+    if stale {
+        headers.insert(STALE_DIMENSIONS, HeaderValue::from_static("true"));
+    }
+    Ok(image_response(state, mac, (headers, response).into_response()).await)
+}
+
+fn validator_headers(validators: Option<Validators>) -> HeaderMap {
+    validators
+        .as_ref()
+        .map(Validators::headers)
+        .unwrap_or_default()
+}
+
+/// Streams an image, throttled to `--max-download-rate` if set.
+fn stream_to_response(
+    stream: impl Stream<Item = io::Result<Bytes>> + Send + Unpin + 'static,
+    content_type: Mime,
+    config: &Config,
+) -> Response {
+    let body = match config.max_download_rate {
+        Some(rate) => {
+            let max_transfer = config.max_transfer_secs.get();
+            StreamBody::new(throttle::throttle(stream, rate.get(), max_transfer).boxed())
+        }
+        None => StreamBody::new(stream.boxed()),
+    };
+    (
+        [(header::CONTENT_TYPE, content_type_header(&content_type))],
+        body,
+    )
+        .into_response()
+}
+
+/// `Content-Type` of a response with `mime`. SVGs are always stored as UTF-8,
+/// which is declared so that clients don't have to sniff it.
+fn content_type_header(mime: &Mime) -> String {
+    if *mime == mime::IMAGE_SVG {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+fn bytes_to_response(bytes: Bytes, content_type: Mime, config: &Config) -> Response {
+    if config.max_download_rate.is_some() {
+        let chunks = throttle::chunked(bytes, config.stream_chunk_bytes.as_usize());
+        return stream_to_response(chunks, content_type, config);
+    }
+    (
+        [(header::CONTENT_TYPE, content_type_header(&content_type))],
+        bytes,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+    use image::{GrayImage, ImageFormat, Luma, Rgb, RgbImage};
+    use serde_json::{json, Value};
+    use test_dir::{DirBuilder, FileType, TestDir};
+    use tower::{Service, ServiceExt};
+
+    use super::*;
+    use crate::bundle::{self, Bundle};
+    use crate::color_map::MappedPaint;
+    use crate::config::{ColorMode, Dither, StaleDimensions, TextMode};
+    use crate::derived::{self, DerivedFormat};
+    use crate::image_handler::BmpMigration;
+    use crate::raster::{Dimensions, ACEP_PALETTE};
+    use crate::state::StateBackend;
+    use crate::tenant::TENANTS_DIR;
+    use crate::units::{ByteSize, HumanDuration};
+    use crate::verify::VerifyStatus;
+    use crate::{capabilities::PayloadFormat, error::ErrorBody};
+    use sha2::{Digest, Sha256};
+    use std::convert::Infallible;
+
+    pub(crate) struct Fixture {
+        pub config: Config,
+        pub temp_dir: TestDir,
+    }
+
+    pub(crate) fn get_test_fixture() -> Fixture {
+        let temp_dir = TestDir::temp()
+            .create("0011223344556677.png", FileType::EmptyFile)
+            .create("aabbccddeeffaabb.png", FileType::EmptyFile)
+            .create("aabbccddeeffaabb.svg", FileType::EmptyFile)
+            .create("garbage.png", FileType::EmptyFile)
+            .create("not-hex-name.png", FileType::EmptyFile);
+
+        Fixture {
+            config: Config {
+                image_dir: temp_dir.path(""),
+                storage: None,
+                https_proxy: None,
+                no_proxy: vec![],
+                extra_ca_cert: None,
+                public_url: None,
+                read_only: false,
+                check_config: false,
+                shard_depth: 0,
+                dedup: false,
+                migrate_shards: false,
+                state_backend: StateBackend::Files,
+                migrate_state: false,
+                require_dependency: vec![],
+                epd_height: 296,
+                epd_width: 128,
+                stale_dimensions: StaleDimensions::Rerender,
+                dither: Dither::FloydSteinberg,
+                color_mode: ColorMode::Mono,
+                palette: ACEP_PALETTE.parse().unwrap(),
+                timezone: chrono_tz::Tz::UTC,
+                rendered_at_format: "%Y-%m-%d %H:%M".to_owned(),
+                optimize_svg: false,
+                convert_text_to_paths: false,
+                font_fallbacks: vec![],
+                deterministic_render: false,
+                map_colors: false,
+                color_map: vec![],
+                luminance_threshold: 0.18,
+                lint_min_stroke_width: 1.0,
+                lint_min_font_size: 8.0,
+                lint_gray_band: 0.1,
+                svg_precision: 3,
+                quarantine_after: 3,
+                xml_declaration: false,
+                migrate_legacy_bmp: false,
+                max_concurrent_renders: 4,
+                interactive_per_batch: 8,
+                max_render_targets: 16,
+                stream_chunk_bytes: ByteSize::new(4096),
+                max_download_rate: None,
+                max_transfer_secs: HumanDuration::from_secs(300),
+                full_refresh_changed_percent: 50,
+                max_partial_refreshes: 5,
+                poll_min: HumanDuration::from_secs(60),
+                poll_max: HumanDuration::from_secs(3600),
+                bundle_max_bytes: ByteSize::new(256 * 1024),
+                boot_report_max_bytes: ByteSize::new(4 * 1024),
+                request_timeout: HumanDuration::from_secs(30),
+                listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+                device_listen: None,
+                http2: false,
+                slow_request_threshold: HumanDuration::from_millis(2000),
+                idempotency_ttl: HumanDuration::from_secs(24 * 60 * 60),
+                idempotency_max_keys: 1000,
+                render_stuck_secs: HumanDuration::from_secs(60),
+                render_degraded_secs: HumanDuration::from_secs(300),
+                temp_file_grace: HumanDuration::from_secs(60 * 60),
+                svg_spill_threshold: ByteSize::new(256 * 1024),
+                negative_cache_ttl: HumanDuration::from_secs(5),
+                image_cache_bytes: ByteSize::new(0),
+                image_cache_file_bytes: ByteSize::new(1024 * 1024),
+                warmup_derived: false,
+                warmup_formats: vec![DerivedFormat::Bmp, DerivedFormat::Raw],
+                warmup_concurrency: 1,
+                default_payload_format: PayloadFormat::Png,
+                event_history: 1000,
+                ws_ping_interval: HumanDuration::from_secs(30),
+                ws_max_subscriptions: 64,
+                admin_key: None,
+                htpasswd: None,
+                tenants: vec![],
+                allow_write_from: vec![],
+                deny_from: vec![],
+                allow_read_from: vec![],
+                trusted_proxies: vec![],
+                audit_log: None,
+                audit_log_max_bytes: ByteSize::new(1024 * 1024),
+                stats_retention_days: HumanDuration::from_secs(90 * 86400),
+                post_render_cmd: None,
+                post_render_timeout: HumanDuration::from_secs(30),
+                post_render_concurrency: 2,
+                #[cfg(feature = "script")]
+                render_script: None,
+                #[cfg(feature = "script")]
+                render_script_timeout: HumanDuration::from_secs(1),
+                #[cfg(feature = "script")]
+                render_script_max_bytes: ByteSize::new(1 << 20),
+                post_render_strict: false,
+                replica_url: None,
+                replica_key: None,
+                replica_queue_max: 10000,
+                #[cfg(feature = "chaos")]
+                chaos_config: None,
+                #[cfg(feature = "coap")]
+                coap_listen: None,
+            },
+            temp_dir,
+        }
+    }
+
+    #[tokio::test]
+    async fn get_macs() {
+        let fix = get_test_fixture();
+        let app = app(fix.config).into_service();
+
+        let response = app
+            .oneshot(Request::builder().uri("/macs").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body, json!(["0011223344556677", "aabbccddeeffaabb"]));
+    }
+
+    #[tokio::test]
+    async fn get_macs_detail() {
+        let fix = get_test_fixture();
+        let app = app(fix.config).into_service();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/macs?detail=true")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            body,
+            json!({
+                "macs": [
+                    {"mac": "0011223344556677", "has_png": true, "has_svg": false},
+                    {"mac": "aabbccddeeffaabb", "has_png": true, "has_svg": true},
+                ],
+                "skipped": 2,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn migrate_legacy_bmp() {
+        let fix = get_test_fixture();
+        let mut bmp = std::io::Cursor::new(vec![]);
+        RgbImage::from_pixel(5, 3, Rgb([0, 0, 0]))
+            .write_to(&mut bmp, ImageFormat::Bmp)
+            .unwrap();
+        std::fs::write(fix.temp_dir.path("123456789abcdef1.bmp"), bmp.into_inner()).unwrap();
+        std::fs::write(fix.temp_dir.path("123456789abcdef2.bmp"), b"garbage").unwrap();
+        // Has a PNG already
+        std::fs::write(fix.temp_dir.path("0011223344556677.bmp"), b"garbage").unwrap();
+
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+
+        let request = Request::builder()
+            .uri("/macs?detail=true")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["macs"],
+            json!([
+                {"mac": "0011223344556677", "has_png": true, "has_svg": false},
+                {"mac": "123456789abcdef1", "has_png": false, "has_svg": false, "bmp_only": true},
+                {"mac": "123456789abcdef2", "has_png": false, "has_svg": false, "bmp_only": true},
+                {"mac": "aabbccddeeffaabb", "has_png": true, "has_svg": true},
+            ])
+        );
+
+        let migration = image_handler.migrate_legacy_bmp().await.unwrap();
+        assert_eq!(
+            migration,
+            BmpMigration {
+                migrated: 1,
+                failed: 1
+            }
+        );
+        assert!(fix.temp_dir.path("123456789abcdef1.bmp").exists());
+        assert!(!fix.temp_dir.path("123456789abcdef2.png").exists());
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let png = image::load_from_memory_with_format(&body, ImageFormat::Png).unwrap();
+        assert_eq!((png.width(), png.height()), (5, 3));
+
+        let request = Request::builder()
+            .uri("/macs?detail=true")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        // Legacy BMPs keep their size
+        assert_eq!(
+            body["macs"][1],
+            json!({
+                "mac": "123456789abcdef1",
+                "has_png": true,
+                "has_svg": false,
+                "dimensions_stale": true
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_hints() {
+        let mut fix = get_test_fixture();
+        fix.config.max_partial_refreshes = 2;
+        let mut app = app(fix.config).into_service();
+        let mac = "123456789abcdef1";
+
+        let render = |x: u32| {
+            let body = format!(
+                r#"<rect width="128" height="296" fill="white"/><rect x="{x}" width="4" height="4"/>"#
+            );
+            Request::builder()
+                .uri(format!("/macs/{mac}/render_svg"))
+                .method("POST")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let get = |format: &str| {
+            Request::builder()
+                .uri(format!("/macs/{mac}/{format}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.ready().await.unwrap().call(render(0)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.ready().await.unwrap().call(get("png")).await.unwrap();
+        assert_eq!(response.headers()["x-epd-suggested-refresh"], "full");
+        assert!(!response.headers().contains_key("x-epd-since-full-refresh"));
+
+        let request = Request::builder()
+            .uri(format!("/macs/{mac}/ack"))
+            .method("POST")
+            .body(Body::from(r#"{"refresh": "full"}"#))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app.ready().await.unwrap().call(get("png")).await.unwrap();
+        assert_eq!(response.headers()["x-epd-suggested-refresh"], "partial");
+        assert_eq!(response.headers()["x-epd-since-full-refresh"], "0");
+
+        // Small changes until there are more than two since the full refresh
+        for (change, expected) in [(1, "partial"), (2, "partial"), (3, "full")] {
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(render(change * 10))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let response = app.ready().await.unwrap().call(get("raw")).await.unwrap();
+            assert_eq!(
+                response.headers()["x-epd-suggested-refresh"],
+                expected,
+                "{change}"
+            );
+            assert_eq!(response.headers()["x-epd-changed-pixels"], "32");
+        }
+
+        // BMPs aren't shown on panels
+        let response = app.ready().await.unwrap().call(get("bmp")).await.unwrap();
+        assert!(!response.headers().contains_key("x-epd-suggested-refresh"));
+    }
+
+    #[tokio::test]
+    async fn boot_report() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let mut call = |request: Request<Body>| {
+            let response = app.call(request);
+            async move { response.await.unwrap() }
+        };
+        let uri = "/macs/123456789abcdef1/bootreport";
+        let post = |content_type: &str, report: Vec<u8>| {
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(report))
+                .unwrap()
+        };
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = call(get(uri)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Battery 2950 mV, a vendor field, firmware 2.7 and reset reason 1
+        let report = vec![
+            0x01, 0x02, 0x0b, 0x86, 0x42, 0x02, 0xff, 0xff, 0x02, 0x02, 0x02, 0x07, 0x03, 0x01,
+            0x01,
+        ];
+        let response = call(post("application/octet-stream", report.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stored: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stored["bytes"], report.len());
+        assert!(fix
+            .temp_dir
+            .path("123456789abcdef1.bootreport.bin")
+            .exists());
+
+        let response = call(get(uri)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, report);
+
+        let response = call(get("/macs/123456789abcdef1/checkin")).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let checkin: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(checkin["battery_mv"], 2950);
+        assert_eq!(checkin["firmware"], "2.7");
+        assert_eq!(checkin["reset_reason"], 1);
+        assert_eq!(checkin["boot_report"], stored["received"]);
+
+        // An unknown format is kept but not decoded
+        let response = call(post("application/octet-stream", b"\xde\xad\xbe".to_vec())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call(get("/macs/123456789abcdef1/checkin")).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let checkin: Value = serde_json::from_slice(&body).unwrap();
+        assert!(checkin.get("battery_mv").is_none(), "{checkin}");
+
+        let response = call(post("text/plain", report)).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let response = call(post("application/octet-stream", vec![0; 4097])).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn payload_by_capabilities() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let put = |capabilities: Value| {
+            Request::put("/macs/123456789abcdef1/capabilities")
+                .body(Body::from(capabilities.to_string()))
+                .unwrap()
+        };
+        let body = |response: Response| async move {
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/raw"))
+            .await
+            .unwrap();
+        let raw = body(response).await;
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        let png = body(response).await;
+
+        // The configured default without reported capabilities
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/payload"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-eps-format"], "png");
+        assert_eq!(body(response).await, png);
+
+        let capabilities = json!({
+            "formats": ["raw1", "png"],
+            "bit_order": "lsb",
+            "max_payload": 32768,
+            "compression": ["none", "rle"],
+        });
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(capabilities.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/capabilities"))
+            .await
+            .unwrap();
+        let stored: Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(stored, capabilities);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/payload"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-eps-format"], "raw1");
+        assert_eq!(response.headers()["x-eps-compression"], "rle");
+        let response_len = response.headers()["x-eps-uncompressed-length"].clone();
+        let encoded = body(response).await;
+        assert!(encoded.len() < raw.len());
+        assert_eq!(response_len, raw.len().to_string());
+        let decoded: Vec<u8> = rle::decode(&encoded, raw.len())
+            .unwrap()
+            .into_iter()
+            .map(u8::reverse_bits)
+            .collect();
+        assert_eq!(decoded, raw);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(
+                json!({"formats": ["png", "bmp"], "compression": ["none"]}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/payload"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-eps-format"], "bmp");
+        assert_eq!(response.headers()["x-eps-compression"], "none");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/bmp");
+        let payload = body(response).await;
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/bmp"))
+            .await
+            .unwrap();
+        assert_eq!(payload, body(response).await);
+
+        // Nothing fits
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(json!({"formats": ["raw1"], "max_payload": 16})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/payload"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(json!({"formats": []})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn custom_response_headers() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let uri = "/macs/0011223344556677/response_headers";
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let put = |headers: Value| {
+            Request::put(uri)
+                .body(Body::from(headers.to_string()))
+                .unwrap()
+        };
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(json!({"X-EPS-Poll-Interval": "900"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/0011223344556677/png"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-eps-poll-interval"], "900");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+
+        // Other MACs are unaffected
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("x-eps-poll-interval"));
+
+        for forbidden in [
+            json!({"Content-Type": "text/plain"}),
+            json!({"ETag": "\"forged\""}),
+            json!({"X-EPS-Format": "bmp"}),
+        ] {
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(put(forbidden))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let response = app.ready().await.unwrap().call(get(uri)).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stored: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stored, json!({"x-eps-poll-interval": "900"}));
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::delete(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/0011223344556677/png"))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("x-eps-poll-interval"));
+    }
+
+    #[tokio::test]
+    async fn staging_and_promote() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let post =
+            |uri: &str, body: &'static str| Request::post(uri).body(Body::from(body)).unwrap();
+        let body = |response: Response| async move {
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(post(
+                "/macs/123456789abcdef1/render_svg",
+                "<circle cx=\"64\" cy=\"64\" r=\"30\" />",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        let live = body(response).await;
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/events/history?since=0&mac=123456789abcdef1"))
+            .await
+            .unwrap();
+        let history: History = serde_json::from_slice(&body(response).await).unwrap();
+        let events = history.events.len();
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(post(
+                "/macs/123456789abcdef1/staging/render_svg",
+                "<rect x=\"10\" y=\"10\" width=\"50\" height=\"50\" />",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(fix.temp_dir.path("123456789abcdef1.staging.png").exists());
+
+        // Live is untouched, without events
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, live);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/events/history?since=0&mac=123456789abcdef1"))
+            .await
+            .unwrap();
+        let history: History = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(history.events.len(), events);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/staging/png"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let staged = body(response).await;
+        assert_ne!(staged, live);
+
+        // Staged files aren't listed as MACs
+        let response = app.ready().await.unwrap().call(get("/macs")).await.unwrap();
+        let listing = String::from_utf8(body(response).await.to_vec()).unwrap();
+        assert!(!listing.contains("staging"));
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(post("/macs/123456789abcdef1/promote", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, staged);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/events/history?since=0&mac=123456789abcdef1"))
+            .await
+            .unwrap();
+        let history: History = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(history.events.len(), events + 1);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/staging/png"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Nothing left to promote or discard
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(post("/macs/123456789abcdef1/promote", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(
+                Request::delete("/macs/123456789abcdef1/staging")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn runtime_log_level() {
+        let fix = get_test_fixture();
+        let (layer, log_level) = LogLevel::new("eps_server=info".to_owned());
+        let _subscriber = tracing_subscriber::registry().with(layer).set_default();
+        let mut app =
+            router(Arc::new(ImageHandler::new(fix.config)), &[], log_level).into_service();
+        let put = |body: Value| {
+            Request::put("/admin/log_level")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let get = || {
+            Request::get("/admin/log_level")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert!(!tracing::enabled!(tracing::Level::DEBUG));
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(
+                json!({"filter": "eps_server=trace,tower_http=debug", "duration": "10m"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let filter: LogFilter = serde_json::from_slice(&body).unwrap();
+        assert_eq!(filter.filter, "eps_server=trace,tower_http=debug");
+        assert_eq!(filter.reverts_to.as_deref(), Some("eps_server=info"));
+        assert!(filter.revert_at.unwrap() > Utc::now() + chrono::Duration::minutes(9));
+        assert!(tracing::enabled!(tracing::Level::TRACE));
+
+        // Invalid filters change nothing
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(json!({"filter": "eps_server=chatty"})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = app.ready().await.unwrap().call(get()).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let current: LogFilter = serde_json::from_slice(&body).unwrap();
+        assert_eq!(current, filter);
+        assert!(tracing::enabled!(tracing::Level::TRACE));
+    }
+
+    #[tokio::test]
+    async fn text_converted_to_paths() {
+        let mut fix = get_test_fixture();
+        fix.config.convert_text_to_paths = true;
+        let mut app = app(fix.config.clone()).into_service();
+        let mac: EpdMac = "123456789abcdef1".parse().unwrap();
+        let text =
+            "<text x=\"10\" y=\"60\" font-family=\"DejaVu Sans\" font-size=\"32\">Hello</text>";
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let body = |response: Response| async move {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(text))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let png_path = fix.temp_dir.path("123456789abcdef1.png");
+        let rendered = std::fs::read(&png_path).unwrap();
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/svg"))
+            .await
+            .unwrap();
+        let stored = body(response).await;
+        assert!(!stored.contains("<text"), "{stored}");
+        assert!(stored.contains("<path"), "{stored}");
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/svg/original"))
+            .await
+            .unwrap();
+        assert!(body(response).await.contains(text));
+
+        // Re-rendering the stored document doesn't need the font anymore
+        let without_fonts = ImageHandler::new(fix.config.clone()).without_fonts();
+        without_fonts.rerender(mac, Priority::Batch).await.unwrap();
+        assert_eq!(std::fs::read(&png_path).unwrap(), rendered);
+
+        // Unlike live text
+        without_fonts
+            .post_svg_body(
+                mac,
+                text,
+                RerenderOptions {
+                    render: RenderOverrides {
+                        text: Some(TextMode::Live),
+                        ..RenderOverrides::default()
+                    },
+                    ..RerenderOptions::default()
+                },
+                Priority::Batch,
+            )
+            .await
+            .unwrap();
+        assert_ne!(std::fs::read(&png_path).unwrap(), rendered);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/svg"))
+            .await
+            .unwrap();
+        assert!(body(response).await.contains(text));
+        assert!(!fix.temp_dir.path("123456789abcdef1.svg.orig").exists());
+    }
+
+    #[cfg(feature = "script")]
+    #[tokio::test]
+    async fn render_script() {
+        let mut fix = get_test_fixture();
+        let script = fix.temp_dir.path("store_code.rhai");
+        std::fs::write(&script, include_str!("../examples/store_code.rhai")).unwrap();
+        fix.config.render_script = Some(script.clone());
+        fix.config.render_script_timeout = HumanDuration::from_millis(200);
+        let mut app = app(fix.config.clone()).into_service();
+        let render = || {
+            Request::post("/macs/123456789abcdef1/render_svg")
+                .body(Body::from("<rect width=\"10\" height=\"10\"/>"))
+                .unwrap()
+        };
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let body = |response: Response| async move {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<Value>(&body).unwrap_or_else(|_| json!(body.to_vec()))
+        };
+
+        let response = app.ready().await.unwrap().call(render()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let png = std::fs::read(fix.temp_dir.path("123456789abcdef1.png")).unwrap();
+        let png = image::load_from_memory(&png).unwrap().to_luma8();
+        // The store code is drawn along the bottom edge
+        let label = (0..128)
+            .flat_map(|x| (266..296).map(move |y| (x, y)))
+            .filter(|&(x, y)| png.get_pixel(x, y).0[0] < 128)
+            .count();
+        assert!(label > 50, "{label}");
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/render_log"))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await[0]["options"]["dither"], "threshold");
+
+        // An endless loop hits the time budget once reloaded
+        std::fs::write(&script, "loop { }").unwrap();
+        let reload = || Request::post("/admin/reload").body(Body::empty()).unwrap();
+        let response = app.ready().await.unwrap().call(reload()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.ready().await.unwrap().call(render()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let error = body(response).await;
+        assert_eq!(error["code"], "script_failed");
+        assert_eq!(
+            error["message"],
+            "The render script took longer than 200ms."
+        );
+
+        // Scripts that don't compile keep the previous one
+        std::fs::write(&script, "let = ;").unwrap();
+        let response = app.ready().await.unwrap().call(reload()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let error = body(response).await;
+        assert!(
+            error["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("The render script was not reloaded"),
+            "{error}"
+        );
+        let response = app.ready().await.unwrap().call(render()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn colors_mapped_to_black_and_white() {
+        let mut fix = get_test_fixture();
+        fix.config.map_colors = true;
+        let mut app = app(fix.config).into_service();
+        let red_on_navy = "<rect width=\"128\" height=\"296\" fill=\"#000080\"/>\
+            <circle cx=\"64\" cy=\"148\" r=\"50\" fill=\"#ff0000\"/>";
+        let png_path = fix.temp_dir.path("123456789abcdef1.png");
+        let mut render = |query: &'static str| {
+            let request = Request::post(format!("/macs/123456789abcdef1/render_svg{query}"))
+                .body(Body::from(red_on_navy))
+                .unwrap();
+            let response = app.call(request);
+            let png_path = png_path.clone();
+            async move {
+                assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+                let png = image::load_from_memory(&std::fs::read(png_path).unwrap()).unwrap();
+                let mut colors: Vec<_> = png.to_rgb8().pixels().map(|pixel| pixel.0).collect();
+                colors.sort();
+                colors.dedup();
+                colors
+            }
+        };
+
+        let preserved = render("?color_map=preserve").await;
+        assert!(preserved.contains(&[0, 0, 128]));
+        assert!(preserved.contains(&[255, 0, 0]));
+        // Anti-aliased edges blend both colors
+        assert!(preserved.len() > 2);
+
+        assert_eq!(render("").await, [[0, 0, 0], [255, 255, 255]]);
+        assert_eq!(render("?color_map=%23ff0000=black").await, [[0, 0, 0]]);
+
+        let request = Request::get("/macs/123456789abcdef1/render_log")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let log: Vec<RenderRecord> = serde_json::from_slice(&body).unwrap();
+        let reports: Vec<_> = log
+            .iter()
+            .map(|record| {
+                record.color_map.as_ref().map(|report| {
+                    report
+                        .mapped
+                        .iter()
+                        .map(|mapped| (mapped.from.as_str(), mapped.to, mapped.explicit))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        assert_eq!(
+            reports,
+            [
+                None,
+                Some(vec![
+                    ("#000080", MappedPaint::Black, false),
+                    ("#ff0000", MappedPaint::White, false),
+                ]),
+                Some(vec![
+                    ("#000080", MappedPaint::Black, false),
+                    ("#ff0000", MappedPaint::Black, true),
+                ]),
+            ]
+        );
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn render_panic() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let svg = format!(
+            "<!-- {} --><circle cx=\"64\" cy=\"64\" r=\"30\" />",
+            std::str::from_utf8(chaos::PANIC_MARKER).unwrap()
+        );
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(svg))
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let report = response
+            .extensions()
+            .get::<error::ErrorReport>()
+            .unwrap()
+            .0
+            .clone();
+        assert!(
+            report.contains("render of MAC 123456789abcdef1 from source"),
+            "{report}"
+        );
+        assert!(report.contains("Injected panic"), "{report}");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let error: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.code, "internal_server_error");
+
+        // The server keeps going
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(r#"<circle cx="64" cy="64" r="30" />"#))
+            .unwrap();
+        assert_eq!(app.call(request).await.unwrap().status(), StatusCode::OK);
+        let request = Request::get("/stats").body(Body::empty()).unwrap();
+        let response = app.call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["panics_total"], 1);
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn chaos_truncation() {
+        let fix = get_test_fixture();
+        std::fs::write(fix.temp_dir.path("123456789abcdef1.png"), vec![7; 3000]).unwrap();
+        let mut app = app(fix.config).into_service();
+        let put = |rules: Value| {
+            Request::put("/chaos")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(rules.to_string()))
+                .unwrap()
+        };
+        let get_png = || {
+            Request::get("/macs/123456789abcdef1/png")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(json!({"rules": [
+                {"route": "/macs/*/png", "truncate_after": 100, "etag_churn": true}
+            ]})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.ready().await.unwrap().call(get_png()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), 100);
+        let response = app.ready().await.unwrap().call(get_png()).await.unwrap();
+        assert_ne!(response.headers()[header::ETAG], etag);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(
+                json!({"rules": [{"error_rate": 1.0, "error_status": 404}]}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(put(json!({"rules": []})))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.ready().await.unwrap().call(get_png()).await.unwrap();
+        let etag = response.headers()[header::ETAG].clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), 3000);
+        let response = app.ready().await.unwrap().call(get_png()).await.unwrap();
+        assert_eq!(response.headers()[header::ETAG], etag);
+    }
+
+    #[tokio::test]
+    async fn bundle() {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+        let mut fix = get_test_fixture();
+        fix.config.bundle_max_bytes = ByteSize::new(4000);
+        let mut app = app(fix.config).into_service();
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let body = |response: Response| async move {
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let uri = "/macs/123456789abcdef1/bundle?include=svg,png,raw";
+        let response = app.ready().await.unwrap().call(get(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+        let bundle: Bundle = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(bundle.etag, etag.to_str().unwrap());
+        assert_eq!(bundle.profile.width, 128);
+        assert_eq!(bundle.metadata.render_log.len(), 1);
+        assert!(bundle.omitted.is_empty());
+        for (part, encoded) in [
+            ("svg", &bundle.svg),
+            ("png", &bundle.png),
+            ("raw", &bundle.raw),
+        ] {
+            let uri = format!("/macs/123456789abcdef1/{part}");
+            let response = app.ready().await.unwrap().call(get(&uri)).await.unwrap();
+            let expected = body(response).await;
+            assert_eq!(
+                BASE64.decode(encoded.as_ref().unwrap()).unwrap(),
+                expected,
+                "{part}"
+            );
+            if part == "svg" {
+                let hash = hex::encode(Sha256::digest(&expected));
+                assert_eq!(bundle.content_hash.as_ref(), Some(&hash));
+            }
+        }
+
+        let request = Request::get(uri)
+            .header(header::IF_NONE_MATCH, etag.clone())
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Without `include`, the raw image of 4736 bytes is left out
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/bundle"))
+            .await
+            .unwrap();
+        assert_ne!(response.headers()[header::ETAG], etag);
+        let bundle: Bundle = serde_json::from_slice(&body(response).await).unwrap();
+        assert!(bundle.svg.is_some() && bundle.png.is_some());
+        assert_eq!(bundle.raw, None);
+        assert_eq!(bundle.omitted, [bundle::Part::Raw]);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/1111111111111111/bundle"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/bundle?include=bmp"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn ghost_diff() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let render = |x: u32| {
+            let body = format!(
+                r#"<rect width="128" height="296" fill="white"/><rect x="{x}" y="40" width="20" height="20"/>"#
+            );
+            Request::post("/macs/123456789abcdef1/render_svg")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let diff = |query: &str| {
+            Request::get(format!("/macs/123456789abcdef1/diff.png{query}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.ready().await.unwrap().call(render(10)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.ready().await.unwrap().call(diff("")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, "no_comparison_image");
+
+        // Moves the square 10 pixels to the right
+        let response = app.ready().await.unwrap().call(render(20)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(diff("?against=previous"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let png = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let diff_image = image::load_from_memory(&png).unwrap().to_rgb8();
+        for (x, y, pixel) in diff_image.enumerate_pixels() {
+            let expected = match (x, y) {
+                (10..=19, 40..=59) => raster::TURNED_WHITE,
+                (30..=39, 40..=59) => raster::TURNED_BLACK,
+                _ => {
+                    assert_eq!(pixel.0[0], pixel.0[1], "{x} {y}");
+                    assert!(pixel.0[0] >= 0xc0, "{x} {y}");
+                    continue;
+                }
+            };
+            assert_eq!(*pixel, expected, "{x} {y}");
+        }
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(diff("?against=2024-05-01T12:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(diff("?against=yesterday"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn png_missing_svg_present() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        std::fs::remove_file(fix.temp_dir.path("123456789abcdef1.png")).unwrap();
+
+        let request = Request::builder()
+            .uri("/macs?detail=true")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["macs"][1],
+            json!({"mac": "123456789abcdef1", "has_png": false, "has_svg": true})
+        );
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, "png_missing_svg_present");
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/regenerate")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn write_allowlist() {
+        let mut fix = get_test_fixture();
+        fix.config.allow_write_from = vec!["10.0.0.0/8".parse().unwrap()];
+        fix.config.deny_from = vec!["2001:db8::/32".parse().unwrap()];
+        let mut app = app(fix.config).into_service();
+
+        let render = |peer: &str| {
+            let peer: std::net::IpAddr = peer.parse().unwrap();
+            Request::builder()
+                .uri("/macs/123456789abcdef1/render_svg")
+                .method("POST")
+                .extension(axum::extract::ConnectInfo(SocketAddr::from((peer, 4000))))
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                .unwrap()
+        };
+        for peer in ["192.0.2.1", "2001:db8::1"] {
+            let response = app.ready().await.unwrap().call(render(peer)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body.code, "forbidden");
+        }
+        assert!(!fix.temp_dir.path("123456789abcdef1.png").exists());
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(render("10.1.2.3"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/macs")
+            .extension(axum::extract::ConnectInfo(SocketAddr::from((
+                [192, 0, 2, 1],
+                4000,
+            ))))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn policy_precedence() {
+        let mut fix = get_test_fixture();
+        fix.config.allow_write_from = vec!["10.0.0.0/8".parse().unwrap()];
+        fix.config.admin_key = Some("secret".to_owned());
+        let mut app = app(fix.config).into_service();
+
+        let request = |method: &str, uri: &str, peer: [u8; 4], key: Option<&str>| {
+            let mut request = Request::builder()
+                .uri(uri)
+                .method(method)
+                .extension(axum::extract::ConnectInfo(SocketAddr::from((peer, 4000))));
+            if let Some(key) = key {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {key}"));
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        for (method, uri, peer, key, status) in [
+            // The network is checked before the credentials
+            (
+                "POST",
+                "/admin/reload",
+                [192, 0, 2, 1],
+                Some("secret"),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "POST",
+                "/admin/reload",
+                [10, 1, 2, 3],
+                Some("wrong"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                "POST",
+                "/admin/reload",
+                [10, 1, 2, 3],
+                Some("secret"),
+                StatusCode::OK,
+            ),
+            // Reading admin routes from anywhere only needs the key
+            (
+                "GET",
+                "/config",
+                [192, 0, 2, 1],
+                None,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                "GET",
+                "/config",
+                [192, 0, 2, 1],
+                Some("secret"),
+                StatusCode::OK,
+            ),
+            // The admin key doesn't open writes to other networks
+            (
+                "DELETE",
+                "/macs/aabbccddeeffaabb",
+                [192, 0, 2, 1],
+                Some("secret"),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "POST",
+                "/macs/aabbccddeeffaabb/ack",
+                [192, 0, 2, 1],
+                None,
+                StatusCode::FORBIDDEN,
+            ),
+            // Image routes don't need credentials
+            (
+                "GET",
+                "/macs/aabbccddeeffaabb/svg",
+                [192, 0, 2, 1],
+                None,
+                StatusCode::OK,
+            ),
+        ] {
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(request(method, uri, peer, key))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{method} {uri} {peer:?} {key:?}");
+        }
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(request("GET", "/config", [10, 1, 2, 3], Some("secret")))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "policy": {
+                    "read-image": ["network"],
+                    "write-image": ["network"],
+                    "admin": ["network", "credentials"],
+                    "device-self": ["network"],
+                    "status": ["network"],
+                },
+                "read_only": false,
+                "limits": {
+                    "stream_chunk_bytes": "4KiB",
+                    "max_download_rate": null,
+                    "max_transfer_secs": "5m",
+                    "bundle_max_bytes": "256KiB",
+                    "request_timeout": "30s",
+                    "slow_request_threshold": "2s",
+                    "render_stuck_secs": "1m",
+                    "render_degraded_secs": "5m",
+                    "svg_spill_threshold": "256KiB",
+                    "negative_cache_ttl": "5s",
+                    "audit_log_max_bytes": "1MiB",
+                },
+            })
+        );
+        assert!(!body.to_string().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn version() {
+        let fix = get_test_fixture();
+        let version = |config: Config| async {
+            let request = Request::get("/version").body(Body::empty()).unwrap();
+            let response = app(config).into_service().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<VersionInfo>(&body).unwrap()
+        };
+
+        let open = version(fix.config.clone()).await;
+        assert_eq!(open.version, env!("CARGO_PKG_VERSION"));
+        assert!(open
+            .capabilities
+            .iter()
+            .any(|capability| capability == "raw"));
+        assert!(!open
+            .capabilities
+            .iter()
+            .any(|capability| capability == "auth"));
+
+        // Still without credentials once the admin routes need them
+        let mut config = fix.config.clone();
+        config.admin_key = Some("secret".to_owned());
+        let protected = version(config).await;
+        assert!(protected
+            .capabilities
+            .iter()
+            .any(|capability| capability == "auth"));
+        assert_eq!(protected.git_commit, open.git_commit);
+    }
+
+    #[tokio::test]
+    async fn object_storage() {
+        let mut fix = get_test_fixture();
+        fix.config.storage = Some("memory:///".parse().unwrap());
+        let mut app = app(fix.config).into_service();
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = app.ready().await.unwrap().call(get("/macs")).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!([]));
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!fix.temp_dir.path("123456789abcdef1.png").exists());
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.starts_with(b"\x89PNG"));
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png")
+            .header(header::IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = app.ready().await.unwrap().call(get("/macs")).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!(["123456789abcdef1"]));
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1")
+            .method("DELETE")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/svg"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn palette_panel() {
+        let mut fix = get_test_fixture();
+        fix.config.color_mode = ColorMode::Palette;
+        let mut app = app(fix.config).into_service();
+
+        // Red and a slightly different orange, split at x = 64
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .body(Body::from(
+                r##"<rect width="64" height="296" fill="#e01010"/><rect x="64" width="64" height="296" fill="#f07000"/>"##,
+            ))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let png = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let png = image::load_from_memory(&png).unwrap().to_rgb8();
+        assert_eq!(png.get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(png.get_pixel(127, 295).0, [255, 128, 0]);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/raw")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let raw = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(raw.len(), 64 * 296);
+        assert_eq!(&raw[..64], [[0x44; 32], [0x66; 32]].concat());
+    }
+
+    #[tokio::test]
+    async fn warmup_derived() {
+        let fix = get_test_fixture();
+        let macs: Vec<EpdMac> = ["1000000000000001", "1000000000000002", "1000000000000003"]
+            .iter()
+            .map(|mac| mac.parse().unwrap())
+            .collect();
+        for mac in &macs {
+            let mut png = std::io::Cursor::new(vec![]);
+            GrayImage::from_pixel(128, 296, Luma([0xff]))
+                .write_to(&mut png, ImageFormat::Png)
+                .unwrap();
+            std::fs::write(fix.temp_dir.path(&format!("{mac}.png")), png.into_inner()).unwrap();
+        }
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+
+        let warmup = tokio::spawn(derived::warm_up(
+            image_handler.clone(),
+            vec![DerivedFormat::Bmp, DerivedFormat::Raw],
+            1,
+        ));
+        let request = Request::builder()
+            .uri(format!("/macs/{}/raw", macs[2]))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), 128 * 296 / 8);
+        warmup.await.unwrap();
+
+        for &mac in &macs {
+            for format in [DerivedFormat::Bmp, DerivedFormat::Raw] {
+                assert!(image_handler.derived_cache().contains(mac, format));
+            }
+        }
+        let request = Request::builder()
+            .uri("/stats")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["warmup_percent"], json!(100.0));
+    }
+
+    #[tokio::test]
+    async fn svg_encodings() {
+        let fragment =
+            "<rect width=\"64\" height=\"296\"/>\r\n<text x=\"70\" y=\"40\">Grüße</text>";
+        let utf8 = fragment.replace("\r\n", "\n").into_bytes();
+        let mut utf8_bom = b"\xEF\xBB\xBF".to_vec();
+        utf8_bom.extend_from_slice(fragment.as_bytes());
+        let mut utf16le = b"\xFF\xFE".to_vec();
+        utf16le.extend(fragment.encode_utf16().flat_map(u16::to_le_bytes));
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let mut stored = vec![];
+        for (mac, body) in [
+            ("0000000000000001", utf8),
+            ("0000000000000002", utf8_bom),
+            ("0000000000000003", utf16le),
+        ] {
+            let request = Request::builder()
+                .uri(format!("/macs/{mac}/render_svg"))
+                .method("POST")
+                .body(Body::from(body))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let mut files = vec![];
+            for format in ["svg", "png"] {
+                let request = Request::builder()
+                    .uri(format!("/macs/{mac}/{format}"))
+                    .body(Body::empty())
+                    .unwrap();
+                let response = app.ready().await.unwrap().call(request).await.unwrap();
+                files.push(hyper::body::to_bytes(response.into_body()).await.unwrap());
+            }
+            stored.push(files);
+        }
+        assert!(!stored[0][0].starts_with(b"\xEF\xBB\xBF"));
+        assert!(!stored[0][0].contains(&b'\r'));
+        assert_eq!(stored[0], stored[1]);
+        assert_eq!(stored[0], stored[2]);
+
+        let mut utf32 = b"\xFF\xFE\0\0".to_vec();
+        utf32.extend(fragment.chars().flat_map(|c| (c as u32).to_le_bytes()));
+        let request = Request::builder()
+            .uri("/macs/0000000000000004/render_svg")
+            .method("POST")
+            .body(Body::from(utf32))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("UTF-32LE"));
+    }
+
+    #[tokio::test]
+    async fn svg_utf8_round_trip() {
+        let fragment = "<rect width=\"64\" height=\"296\"/><text x=\"70\" y=\"40\">Grüße aus Köln, ½ ° €</text>";
+        for optimize_svg in [false, true] {
+            let mut fix = get_test_fixture();
+            fix.config.xml_declaration = true;
+            fix.config.optimize_svg = optimize_svg;
+            let mut app = app(fix.config).into_service();
+
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/render_svg")
+                .method("POST")
+                .body(Body::from(fragment))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/svg")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "image/svg+xml; charset=utf-8"
+            );
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let svg = std::str::from_utf8(&body).unwrap();
+            assert!(
+                svg.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<svg "),
+                "{svg}"
+            );
+            assert!(svg.contains("Grüße aus Köln, ½ ° €"), "{svg}");
+            if !optimize_svg {
+                assert!(svg.contains(fragment));
+            }
+
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/png")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let png = image::load_from_memory_with_format(&body, ImageFormat::Png)
+                .unwrap()
+                .to_luma8();
+            assert_eq!(png.dimensions(), (128, 296));
+            assert_eq!(png.get_pixel(10, 10), &Luma([0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn quarantine_failing_svg() {
+        let fix = get_test_fixture();
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let post = |body: &'static str| {
+            Request::builder()
+                .uri("/macs/aabbccddeeffaabb/render_svg")
+                .method("POST")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let invalid = "<rect width=\"10\" height=\"10\"></circle>";
+
+        for _ in 0..3 {
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(post(invalid))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(post(invalid))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, "quarantined");
+        assert_eq!(image_handler.watchdog().started_total(), 3);
+
+        let request = Request::builder()
+            .uri("/macs?detail=true")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["macs"][1]["quarantined"],
+            hex::encode(Sha256::digest(invalid))
+        );
+
+        let request = Request::builder()
+            .uri("/macs/aabbccddeeffaabb/quarantine")
+            .method("DELETE")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(post(invalid))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(image_handler.watchdog().started_total(), 4);
+
+        // A different body is rendered, and resets the count
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(post("<rect width=\"10\" height=\"10\"/>"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(image_handler
+            .quarantined("aabbccddeeffaabb".parse().unwrap())
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn delete_images() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let png_path = fix.temp_dir.path("0011223344556677.png");
+        assert!(png_path.exists());
+
+        let request = Request::builder()
+            .uri("/macs/0011223344556677")
+            .method("DELETE")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!png_path.exists());
+
+        let request = Request::builder()
+            .uri("/macs/0011223344556677")
+            .method("DELETE")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn get_svg() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let request = Request::builder()
+            .uri("/macs/0011223344556677/svg")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = Request::builder()
+            .uri("/macs/aabbccddeeffaabb/svg")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn get_png() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let request = Request::builder()
+            .uri("/macs/0011223344556677/png")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/macs/1111111111111111/png")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn image_cache() {
+        let mut fix = get_test_fixture();
+        fix.config.image_cache_bytes = ByteSize::new(1024 * 1024);
+        let mut app = app(fix.config).into_service();
+        let mac = "123456789abcdef1";
+        let png_path = fix.temp_dir.path(&format!("{mac}.png"));
+
+        async fn render<S>(app: &mut S, mac: &str, r: u32)
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let request = Request::post(format!("/macs/{mac}/render_svg"))
+                .body(Body::from(format!(
+                    "<circle cx=\"64\" cy=\"64\" r=\"{r}\" />"
+                )))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        async fn fetch<S>(app: &mut S, uri: &str) -> (HeaderMap, Bytes)
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let headers = response.headers().clone();
+            (
+                headers,
+                hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            )
+        }
+        async fn cache_counts<S>(app: &mut S) -> (u64, u64)
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let (_, body) = fetch(app, "/stats").await;
+            let stats: Stats = serde_json::from_slice(&body).unwrap();
+            (stats.image_cache_hits, stats.image_cache_misses)
+        }
+
+        render(&mut app, mac, 30).await;
+        let png_uri = format!("/macs/{mac}/png");
+        let (first_headers, first) = fetch(&mut app, &png_uri).await;
+        assert_eq!(cache_counts(&mut app).await, (0, 1));
+        let (headers, png) = fetch(&mut app, &png_uri).await;
+        assert_eq!(cache_counts(&mut app).await, (1, 1));
+        assert_eq!(png, first);
+        assert_eq!(png, std::fs::read(&png_path).unwrap());
+        assert_eq!(headers[header::ETAG], first_headers[header::ETAG]);
+        assert_eq!(
+            headers[header::CONTENT_LENGTH].to_str().unwrap(),
+            png.len().to_string()
+        );
+        let (_, svg) = fetch(&mut app, &format!("/macs/{mac}/svg")).await;
+        assert_eq!(
+            svg,
+            std::fs::read(fix.temp_dir.path(&format!("{mac}.svg"))).unwrap()
+        );
+        assert_eq!(cache_counts(&mut app).await, (1, 2));
+
+        // Never the PNG of the previous render
+        render(&mut app, mac, 50).await;
+        let (headers, png) = fetch(&mut app, &png_uri).await;
+        assert_ne!(png, first);
+        assert_eq!(png, std::fs::read(&png_path).unwrap());
+        assert_ne!(headers[header::ETAG], first_headers[header::ETAG]);
+        assert_eq!(cache_counts(&mut app).await, (1, 3));
+        fetch(&mut app, &png_uri).await;
+        assert_eq!(cache_counts(&mut app).await, (2, 3));
+    }
+
+    #[tokio::test]
+    async fn mac_suffix() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        for mac in ["123456789abcdef1", "2234567800000ef1"] {
+            let request = Request::post(format!("/macs/{mac}/render_svg"))
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let mut call = |method: &str, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, body)
+            }
+        };
+
+        let (status, body) = call("GET", "/macs/find/EF1").await;
+        assert_eq!(status, StatusCode::OK);
+        let macs: Vec<String> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(macs, ["123456789abcdef1", "2234567800000ef1"]);
+        let (_, body) = call("GET", "/macs/find/def1").await;
+        let macs: Vec<String> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(macs, ["123456789abcdef1"]);
+        for suffix in ["1", "123456789", "xyz"] {
+            let (status, _) = call("GET", &format!("/macs/find/{suffix}")).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{suffix}");
+        }
+
+        let (status, body) = call("GET", "/macs/~DEF1/png").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            std::fs::read(fix.temp_dir.path("123456789abcdef1.png")).unwrap()
+        );
+        let (status, body) = call("GET", "/macs/~ef1/png").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let error: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.code, "ambiguous_mac");
+        assert_eq!(
+            error.details,
+            Some(json!({"candidates": ["123456789abcdef1", "2234567800000ef1"]}))
+        );
+        let (status, _) = call("GET", "/macs/~9999/png").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Changes need the full MAC
+        for (method, uri) in [
+            ("DELETE", "/macs/~def1"),
+            ("POST", "/macs/~def1/render_svg"),
+            ("PUT", "/macs/~def1/groups"),
+        ] {
+            let (status, _) = call(method, uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{method} {uri}");
+        }
+        assert!(fix.temp_dir.path("123456789abcdef1.png").exists());
+    }
+
+    #[tokio::test]
+    async fn mac_suffix_policy() {
+        let mut fix = get_test_fixture();
+        fix.config.allow_read_from = vec!["10.0.0.0/8".parse().unwrap()];
+        fix.config.tenants = vec!["bakery=bread".parse().unwrap()];
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let tenants = tenant_handlers(image_handler.config()).unwrap();
+        let shared = SharedState::new(image_handler, &tenants, detached_log_level());
+        let request = |method: &str, uri: &str, peer: [u8; 4]| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .extension(axum::extract::ConnectInfo(SocketAddr::from((peer, 4000))))
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                .unwrap()
+        };
+        let mut full = shared.router(Listener::Full).into_service();
+        let mut device = shared.router(Listener::Device).into_service();
+        for mac in ["123456789abcdef1", "2234567800000ef1"] {
+            for uri in [
+                format!("/macs/{mac}/render_svg"),
+                format!("/t/bakery/macs/{mac}/render_svg"),
+            ] {
+                let request = Request::post(uri)
+                    .header(header::AUTHORIZATION, "Bearer bread")
+                    .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                    .unwrap();
+                let response = full.ready().await.unwrap().call(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+        }
+
+        for (listener, uri, peer, status) in [
+            (
+                Listener::Full,
+                "/macs/~ef1/png",
+                [10, 1, 2, 3],
+                StatusCode::CONFLICT,
+            ),
+            (
+                Listener::Full,
+                "/macs/~ef1/png",
+                [192, 0, 2, 1],
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Listener::Full,
+                "/macs/~9999/png",
+                [192, 0, 2, 1],
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Listener::Full,
+                "/t/bakery/macs/~ef1/png",
+                [10, 1, 2, 3],
+                StatusCode::UNAUTHORIZED,
+            ),
+            // Devices know their MAC, and nothing may be listed there
+            (
+                Listener::Device,
+                "/macs/~ef1/png",
+                [10, 1, 2, 3],
+                StatusCode::BAD_REQUEST,
+            ),
+        ] {
+            let app = match listener {
+                Listener::Full => &mut full,
+                Listener::Device => &mut device,
+            };
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(request("GET", uri, peer))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{uri} from {peer:?}");
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let leaked = String::from_utf8_lossy(&body).contains("2234567800000ef1");
+            assert_eq!(
+                leaked,
+                status == StatusCode::CONFLICT,
+                "{uri} from {peer:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn negative_cache() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let get_png = || {
+            Request::builder()
+                .uri("/macs/123456789abcdef1/png")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.ready().await.unwrap().call(get_png()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Written behind the server's back, so only a disk probe would find it
+        std::fs::write(fix.temp_dir.path("123456789abcdef1.png"), b"png").unwrap();
+        let response = app.ready().await.unwrap().call(get_png()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.ready().await.unwrap().call(get_png()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/stats")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+        assert!(stats["negative_cache_hits"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn throttled_download() {
+        use hyper::body::HttpBody;
+
+        let mut fix = get_test_fixture();
+        std::fs::write(fix.temp_dir.path("123456789abcdef1.png"), vec![0; 3000]).unwrap();
+        fix.config.stream_chunk_bytes = ByteSize::new(1000);
+
+        for rate in [None, Some(ByteSize::new(4000))] {
+            let mut config = fix.config.clone();
+            config.max_download_rate = rate;
+            let mut app = app(config).into_service();
+
+            let started = std::time::Instant::now();
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/png")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let mut body = response.into_body();
+            let mut chunks = Vec::new();
+            while let Some(chunk) = body.data().await {
+                chunks.push(chunk.unwrap().len());
+            }
+
+            assert_eq!(chunks.iter().sum::<usize>(), 3000);
+            assert!(chunks.iter().all(|&len| len <= 1000), "{chunks:?}");
+            if rate.is_some() {
+                // The last chunk is due after 2000 bytes
+                assert!(started.elapsed() >= Duration::from_millis(500));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn render_svg() {
+        let fix = get_test_fixture();
+        let app = app(fix.config).into_service();
+
+        let png_path = fix.temp_dir.path("123456789abcdef1.png");
+        let svg_path = fix.temp_dir.path("123456789abcdef1.svg");
+        assert!(!png_path.exists());
+        assert!(!svg_path.exists());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/macs/123456789abcdef1/render_svg")
+                    .method("POST")
+                    .body(Body::from("<circle cx=\"125\" cy=\"125\" r=\"75\" />"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(png_path.exists());
+        assert!(svg_path.exists());
+    }
+
+    #[tokio::test]
+    async fn render_substitute() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let svg = "<rect width=\"{{WIDTH}}\" height=\"20\" />\
+            <text y=\"40\" data-size=\"{{WIDTH}}x{{HEIGHT}}\">{{MAC}} {{MAC_SHORT}} \
+            {{RENDERED_AT}} {{OTHER}} {x}</text>";
+
+        for (query, substituted) in [("", false), ("?substitute=true", true)] {
+            let request = Request::builder()
+                .uri(format!("/macs/123456789abcdef1/render_svg{query}"))
+                .method("POST")
+                .body(Body::from(svg))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let stored =
+                std::fs::read_to_string(fix.temp_dir.path("123456789abcdef1.svg")).unwrap();
+            assert_eq!(!stored.contains("{{MAC}}"), substituted, "{stored}");
+            assert!(stored.contains("{{OTHER}} {x}"), "{stored}");
+            if !substituted {
+                continue;
+            }
+            assert!(
+                stored.contains("<rect width=\"128\" height=\"20\""),
+                "{stored}"
+            );
+            assert!(stored.contains("data-size=\"128x296\""), "{stored}");
+            assert!(stored.contains("123456789ABCDEF1 DEF1 "), "{stored}");
+            assert!(
+                stored.contains(&Utc::now().format("%Y-%m-").to_string()),
+                "{stored}"
+            );
+            let png = std::fs::read(fix.temp_dir.path("123456789abcdef1.png")).unwrap();
+            assert!(coverage::analyze(&png).unwrap().percent > 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn render_coverage() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        // The left half of the panel of 128x296
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(r#"<rect width="64" height="296"/>"#))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::get("/macs/123456789abcdef1/render_log")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let render_log: Value = serde_json::from_slice(&body).unwrap();
+        let coverage = &render_log[0]["coverage"];
+        assert!((coverage["percent"].as_f64().unwrap() - 50.0).abs() < 0.5);
+        assert_eq!(
+            coverage["bounding_box"],
+            json!({"x": 0, "y": 0, "w": 64, "h": 296})
+        );
+        assert_eq!(coverage["quadrants"]["top_left"], 100.0);
+        assert_eq!(coverage["quadrants"]["bottom_right"], 0.0);
+
+        let request = Request::get("/stats").body(Body::empty()).unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["average_coverage_percent"], coverage["percent"]);
+    }
+
+    #[tokio::test]
+    async fn coalesce_identical_renders() {
+        let mut fix = get_test_fixture();
+        fix.config.max_concurrent_renders = 1;
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let post = |body: &'static str| {
+            let request = Request::post("/macs/123456789abcdef1/render_svg")
+                .body(Body::from(body))
+                .unwrap();
+            tokio::spawn(app.clone().oneshot(request))
+        };
+        let render_log = || async {
+            let request = Request::get("/macs/123456789abcdef1/render_log")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<Vec<RenderRecord>>(&body).unwrap()
+        };
+        // Holds up renders until all requests arrived
+        let arrive = |posted: usize| {
+            let image_handler = image_handler.clone();
+            async move {
+                while image_handler.render_queue_depth().interactive < posted {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+
+        let circle = r#"<circle cx="64" cy="64" r="30" />"#;
+        let permit = image_handler
+            .render_queue()
+            .acquire(Priority::Batch)
+            .await
+            .unwrap();
+        let posts = [post(circle), post(circle), post(circle)];
+        arrive(1).await;
+        assert_eq!(image_handler.render_queue_depth().interactive, 1);
+        drop(permit);
+        for post in posts {
+            assert_eq!(post.await.unwrap().unwrap().status(), StatusCode::OK);
+        }
+        assert_eq!(render_log().await.len(), 1);
+
+        // Different bodies take turns
+        let square = r#"<rect width="30" height="30" />"#;
+        let permit = image_handler
+            .render_queue()
+            .acquire(Priority::Batch)
+            .await
+            .unwrap();
+        let posts = [post(circle), post(square)];
+        arrive(2).await;
+        drop(permit);
+        for post in posts {
+            assert_eq!(post.await.unwrap().unwrap().status(), StatusCode::OK);
+        }
+        assert_eq!(render_log().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn render_svg_multipart() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .header(header::ACCEPT, "multipart/mixed")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert!(
+            content_type.starts_with("multipart/mixed;"),
+            "{content_type}"
+        );
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let parts = multipart::parse(&content_type, &body);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].0, "application/json");
+        let report: RenderRecord = serde_json::from_slice(&parts[0].1).unwrap();
+        assert_eq!(parts[1].0, "image/png");
+        assert_eq!(report.png_bytes, parts[1].1.len());
+        let stored = std::fs::read(fix.temp_dir.path("123456789abcdef1.png")).unwrap();
+        assert_eq!(parts[1].1, stored);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .header(header::ACCEPT, "*/*")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn render_svg_multiple_macs() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let png = |mac: &str| std::fs::read(fix.temp_dir.path(&format!("{mac}.png"))).ok();
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1,0011223344556677/render_svg?also=123456789abcdef2")
+            .method("POST")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!([
+                {"mac": "123456789abcdef1", "status": "created"},
+                {"mac": "0011223344556677", "status": "updated"},
+                {"mac": "123456789abcdef2", "status": "created"},
+            ])
+        );
+        let first = png("123456789abcdef1").unwrap();
+        assert_eq!(png("0011223344556677").unwrap(), first);
+        assert_eq!(png("123456789abcdef2").unwrap(), first);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef3,nope/render_svg?also=123")
+            .method("POST")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.message, "Invalid MACs: nope, 123");
+        assert!(png("123456789abcdef3").is_none());
+
+        let macs: Vec<_> = (0..17).map(|i| format!("{i:016x}")).collect();
+        let request = Request::builder()
+            .uri(format!("/macs/{}/render_svg", macs.join(",")))
+            .method("POST")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn render_svgz() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let fragment = "<circle cx=\"64\" cy=\"148\" r=\"50\" />";
+        let png_path = fix.temp_dir.path("123456789abcdef1.png");
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .body(Body::from(fragment))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let plain_png = std::fs::read(&png_path).unwrap();
+        std::fs::remove_file(&png_path).unwrap();
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .header(header::CONTENT_TYPE, "image/svg+xml-compressed")
+            .body(Body::from(svgz::compress(fragment.as_bytes()).unwrap()))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(std::fs::read(&png_path).unwrap(), plain_png);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/svg")
+            .header(header::ACCEPT_ENCODING, "gzip, deflate")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let svg = svgz::decode_body(&body).unwrap();
+        assert_eq!(
+            svg,
+            std::fs::read_to_string(fix.temp_dir.path("123456789abcdef1.svg")).unwrap()
+        );
+
+        let mut truncated = svgz::compress(fragment.as_bytes()).unwrap();
+        truncated.truncate(10);
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .body(Body::from(truncated))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn render_svg_oversized_streaming() {
+        const CHUNK: usize = 64 * 1024;
+        const CHUNKS: usize = 5 * 1024 * 1024 / CHUNK;
+
+        let fix = get_test_fixture();
+        let app = app(fix.config).into_service();
+
+        let (mut sender, body) = Body::channel();
+        let sent = tokio::spawn(async move {
+            let mut sent = 0;
+            while sent < CHUNKS {
+                let chunk = Bytes::from(vec![b' '; CHUNK]);
+                if sender.send_data(chunk).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+            sent
+        });
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .body(body)
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "payload_too_large");
+
+        // Rejected right after the chunk crossing the limit
+        let sent = sent.await.unwrap();
+        assert!(sent <= svgz::MAX_SVG_BYTES / CHUNK + 2, "{sent}");
+        assert!(!fix.temp_dir.path("123456789abcdef1.png").exists());
+    }
+
+    #[tokio::test]
+    async fn post_image_jpeg() {
+        let fix = get_test_fixture();
+        let app = app(fix.config).into_service();
+
+        let png_path = fix.temp_dir.path("123456789abcdef1.png");
+        assert!(!png_path.exists());
+
+        let source = RgbImage::from_fn(800, 600, |x, y| Rgb([(x / 4) as u8, (y / 3) as u8, 0x80]));
+        let mut jpeg = std::io::Cursor::new(vec![]);
+        source.write_to(&mut jpeg, ImageFormat::Jpeg).unwrap();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/macs/123456789abcdef1/image?fit=cover")
+                    .method("POST")
+                    .header(header::CONTENT_TYPE, "image/jpeg")
+                    .body(Body::from(jpeg.into_inner()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let stored: GrayImage = image::open(png_path).unwrap().to_luma8();
+        assert_eq!(stored.dimensions(), (128, 296));
+        assert!(stored.pixels().all(|p| p.0[0] == 0 || p.0[0] == u8::MAX));
+    }
+
+    #[tokio::test]
+    async fn post_image_invalid() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/image")
+            .method("POST")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(Body::from("hello"))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/image")
+            .method("POST")
+            .header(header::CONTENT_TYPE, "image/png")
+            .body(Body::from("not a png"))
+            .unwrap();
+
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn upload_sniffing() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let mut upload = |uri: &str, content_type: &str, data: Vec<u8>| {
+            let request = Request::post(uri)
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(data))
+                .unwrap();
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<Value>(&body).unwrap_or_default(),
+                )
+            }
+        };
+        let image_uri = "/macs/123456789abcdef1/image";
+        let png_uri = "/macs/123456789abcdef1/png";
+
+        // Generic labels go by the content
+        let octet_stream = mime::APPLICATION_OCTET_STREAM.as_ref();
+        let (status, _) = upload(image_uri, octet_stream, png(64, 64)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = upload(png_uri, octet_stream, png(128, 296)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = upload(image_uri, octet_stream, b"<svg/>".to_vec()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            body["message"],
+            "The upload is none of image/jpeg, image/png, image/bmp."
+        );
+
+        // Specific labels have to match it
+        let mut jpeg = std::io::Cursor::new(vec![]);
+        RgbImage::new(64, 64)
+            .write_to(&mut jpeg, ImageFormat::Jpeg)
+            .unwrap();
+        let (status, body) = upload(image_uri, "image/png", jpeg.get_ref().clone()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            body["message"],
+            "The upload is labeled image/png but is image/jpeg."
+        );
+        let (status, body) = upload(png_uri, octet_stream, jpeg.into_inner()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            body["message"],
+            "The upload is image/jpeg, expected image/png."
+        );
+
+        // Pixel bombs are rejected from their header
+        let mut bomb = png(1, 1);
+        bomb[16..24].copy_from_slice(&[30000u32.to_be_bytes(), 30000u32.to_be_bytes()].concat());
+        let mut crc = flate2::Crc::new();
+        crc.update(&bomb[12..29]);
+        bomb[29..33].copy_from_slice(&crc.sum().to_be_bytes());
+        for uri in [image_uri, png_uri] {
+            let (status, body) = upload(uri, "image/png", bomb.clone()).await;
+            assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(
+                body["message"],
+                "The image/png upload is 30000x30000 pixels, more than the 8192x8192 that are \
+                 decoded."
+            );
+        }
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = GrayImage::from_fn(width, height, |x, _| Luma([if x < 10 { 0 } else { 0xff }]));
+        let mut png = std::io::Cursor::new(vec![]);
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    #[tokio::test]
+    async fn post_png_mismatch() {
+        let fix = get_test_fixture();
+        let app = app(fix.config).into_service();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/macs/123456789abcdef1/png")
+                    .method("POST")
+                    .header(header::CONTENT_TYPE, "image/png")
+                    .body(Body::from(png(296, 128)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            body,
+            json!({
+                "code": "dimension_mismatch",
+                "message": "Image is 296x128 but the panel is 128x296, try autofix=rotate.",
+                "details": {
+                    "uploaded": {"width": 296, "height": 128},
+                    "expected": {"width": 128, "height": 296},
+                    "rotation_matches": true,
+                    "aspect_matches": false,
+                },
+            })
+        );
+        assert!(!fix.temp_dir.path("123456789abcdef1.png").exists());
+    }
+
+    #[tokio::test]
+    async fn post_png_autofix() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let png_path = fix.temp_dir.path("123456789abcdef1.png");
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png?autofix=rotate")
+            .method("POST")
+            .header(header::CONTENT_TYPE, "image/png")
+            .body(Body::from(png(296, 128)))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The black stripe on the left ends up at the top
+        let stored = image::open(&png_path).unwrap().to_luma8();
+        assert_eq!(stored.dimensions(), (128, 296));
+        assert_eq!(stored.get_pixel(64, 5), &Luma([0]));
+        assert_eq!(stored.get_pixel(64, 100), &Luma([0xff]));
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png?autofix=scale")
+            .method("POST")
+            .header(header::CONTENT_TYPE, "image/png")
+            .body(Body::from(png(300, 100)))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["code"], "dimension_mismatch");
+        assert_eq!(body["details"]["aspect_matches"], false);
+        assert_eq!(body["details"]["rotation_matches"], false);
+    }
+
+    #[tokio::test]
+    async fn admin_rerender() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        for mac in ["123456789abcdef1", "223456789abcdef1"] {
+            let request = Request::builder()
+                .uri(format!("/macs/{mac}/render_svg"))
+                .method("POST")
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // Only the three SVGs of this test
+        std::fs::remove_file(fix.temp_dir.path("aabbccddeeffaabb.svg")).unwrap();
+        // A stale PNG that changes and a stored SVG that no longer renders
+        std::fs::write(fix.temp_dir.path("223456789abcdef1.png"), b"stale").unwrap();
+        std::fs::write(fix.temp_dir.path("323456789abcdef1.svg"), "<svg><circle").unwrap();
+
+        let request = Request::builder()
+            .uri("/admin/rerender")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let job: RerenderJob = serde_json::from_slice(&body).unwrap();
+        assert_eq!(job.total, 3);
+
+        let job = loop {
+            let request = Request::builder()
+                .uri(format!("/admin/rerender/{}", job.id))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let job: RerenderJob = serde_json::from_slice(&body).unwrap();
+            if job.finished {
+                break job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!((job.done, job.unchanged, job.failed), (1, 1, 1));
+        assert_eq!(job.failures[0].mac, "323456789abcdef1");
+        assert_ne!(
+            std::fs::read(fix.temp_dir.path("223456789abcdef1.png")).unwrap(),
+            b"stale"
+        );
+
+        let request = Request::builder()
+            .uri("/admin/rerender?mac_prefix=2234")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let job: RerenderJob = serde_json::from_slice(&body).unwrap();
+        assert_eq!(job.total, 1);
+
+        let request = Request::builder()
+            .uri("/admin/rerender/999")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn stale_dimensions() {
+        let fix = get_test_fixture();
+        let (rendered, uploaded) = ("123456789abcdef1", "223456789abcdef1");
+        let mut original = app(fix.config.clone()).into_service();
+        let request = Request::builder()
+            .uri(format!("/macs/{rendered}/render_svg"))
+            .method("POST")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = original.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = Request::builder()
+            .uri(format!("/macs/{uploaded}/png"))
+            .method("POST")
+            .header(header::CONTENT_TYPE, "image/png")
+            .body(Body::from(png(128, 296)))
+            .unwrap();
+        let response = original.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        async fn fetch_png<S>(app: &mut S, mac: &str) -> (Option<String>, Dimensions)
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let request = Request::builder()
+                .uri(format!("/macs/{mac}/png"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let stale = response
+                .headers()
+                .get("x-eps-stale-dimensions")
+                .map(|value| value.to_str().unwrap().to_owned());
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (stale, raster::png_dimensions(&body).unwrap())
+        }
+        let size = |width, height| Dimensions { width, height };
+
+        // The SVG is rendered again at the new size, the upload only flagged
+        let mut config = fix.config.clone();
+        (config.epd_width, config.epd_height) = (200, 300);
+        let mut swapped = app(config).into_service();
+        assert_eq!(
+            fetch_png(&mut swapped, rendered).await,
+            (None, size(200, 300))
+        );
+        assert_eq!(
+            fetch_png(&mut swapped, uploaded).await,
+            (Some("true".to_owned()), size(128, 296))
+        );
+        let request = Request::builder()
+            .uri("/macs?detail=true")
+            .body(Body::empty())
+            .unwrap();
+        let response = swapped.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let listing: MacListingDetail = serde_json::from_slice(&body).unwrap();
+        let stale: Vec<_> = listing
+            .macs
+            .iter()
+            .filter(|entry| entry.dimensions_stale)
+            .map(|entry| entry.mac.as_str())
+            .collect();
+        assert_eq!(stale, [uploaded]);
+
+        // Only flagged until the fleet is re-rendered
+        let mut config = fix.config.clone();
+        config.stale_dimensions = StaleDimensions::Flag;
+        let mut flag = app(config).into_service();
+        assert_eq!(
+            fetch_png(&mut flag, rendered).await,
+            (Some("true".to_owned()), size(200, 300))
+        );
+        let request = Request::builder()
+            .uri("/admin/rerender?stale_only=true")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = flag.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let job: RerenderJob = serde_json::from_slice(&body).unwrap();
+        assert_eq!(job.total, 1);
+        let job = loop {
+            let request = Request::builder()
+                .uri(format!("/admin/rerender/{}", job.id))
+                .body(Body::empty())
+                .unwrap();
+            let response = flag.ready().await.unwrap().call(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let job: RerenderJob = serde_json::from_slice(&body).unwrap();
+            if job.finished {
+                break job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!((job.done, job.failed), (1, 0));
+        assert_eq!(fetch_png(&mut flag, rendered).await, (None, size(128, 296)));
+    }
+
+    #[tokio::test]
+    async fn not_found_hides_paths() {
+        let fix = get_test_fixture();
+        let image_dir = fix.config.image_dir.to_string_lossy().into_owned();
+        let mut app = app(fix.config).into_service();
+
+        for uri in [
+            "/macs/123456789abcdef1/png",
+            "/macs/123456789abcdef1/svg",
+            "/macs/123456789abcdef1/bmp",
+        ] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let message = body["message"].as_str().unwrap();
+            assert_eq!(body["code"], "not_found");
+            assert!(message.contains("123456789abcdef1"), "{message}");
+            assert!(!message.contains(&image_dir), "{message}");
+            assert!(!message.contains("os error"), "{message}");
+        }
+    }
+
+    #[tokio::test]
+    async fn preconditions() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .header(header::IF_NONE_MATCH, "*")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+        let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+
+        // Proceed
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/png")
+            .header(header::IF_NONE_MATCH, "\"other\"")
+            .header(header::IF_MODIFIED_SINCE, &last_modified)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Not modified
+        for (name, value) in [
+            (header::IF_NONE_MATCH, &etag),
+            (header::IF_MODIFIED_SINCE, &last_modified),
+        ] {
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/png")
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(response.headers()[header::ETAG], etag);
+        }
+
+        // Representations of the same file have different tags
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/bmp")
+            .header(header::IF_NONE_MATCH, &etag)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Precondition failed
+        for (name, value) in [
+            (header::IF_MATCH, "\"other\""),
+            (header::IF_UNMODIFIED_SINCE, "Sat, 01 Jan 2000 00:00:00 GMT"),
+            (header::IF_NONE_MATCH, "*"),
+        ] {
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/render_svg")
+                .method("POST")
+                .header(name, value)
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"40\" />"))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        }
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1")
+            .method("DELETE")
+            .header(header::IF_MATCH, &etag)
+            .header(header::IF_UNMODIFIED_SINCE, "Sat, 01 Jan 2000 00:00:00 GMT")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unknown_route() {
+        let fix = get_test_fixture();
+        let app = app(fix.config).into_service();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/macs/0011223344556677/pngg")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            body,
+            json!({
+                "code": "unknown_route",
+                "message": "unknown route",
+                "path": "/macs/0011223344556677/pngg",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn mac_segments() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let mut get = |uri: &'static str| {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = app.call(request);
+            async move { response.await.unwrap() }
+        };
+
+        let response = get("/macs/0011223344556677/png/?dither=none").await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "/macs/0011223344556677/png?dither=none"
+        );
+
+        // Percent-encoded hex and uppercase digits name the same MAC
+        let response = get("/macs/%30%30%31%31223344556677/png").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get("/macs/AABBccddEEFFaabb/png").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let code = |response: Response| async move {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<ErrorBody>(&body).unwrap().code
+        };
+        let response = get("/macs/1111111111111111/png").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(code(response).await, "not_found");
+        let response = get("/macs/00112233%2F44556677/png").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(code(response).await, "invalid_mac");
+        let response = get("/macs/..%2F..%2Fetc%2Fpasswd/png").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(code(response).await, "invalid_mac");
+    }
+
+    #[tokio::test]
+    async fn method_not_allowed() {
+        let fix = get_test_fixture();
+        let app = app(fix.config).into_service();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/macs/aabbccddeeffaabb/svg")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET,HEAD,OPTIONS");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            body,
+            json!({"code": "method_not_allowed", "message": "method not allowed"})
+        );
+    }
+
+    #[tokio::test]
+    async fn options_allow() {
+        async fn allow(config: Config, uri: &str) -> String {
+            let response = app(config)
+                .into_service()
+                .oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .method("OPTIONS")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            response.headers()[header::ALLOW]
+                .to_str()
+                .unwrap()
+                .to_owned()
+        }
+
+        let fix = get_test_fixture();
+        let mut read_only = fix.config.clone();
+        read_only.read_only = true;
+        for (uri, allowed, allowed_read_only) in [
+            ("/macs", "GET,HEAD,OPTIONS", "GET,HEAD,OPTIONS"),
+            ("/macs/aabbccddeeffaabb", "DELETE,OPTIONS", "OPTIONS"),
+            (
+                "/macs/aabbccddeeffaabb/png",
+                "GET,HEAD,POST,OPTIONS",
+                "GET,HEAD,OPTIONS",
+            ),
+            (
+                "/macs/aabbccddeeffaabb/playlist",
+                "GET,HEAD,PUT,OPTIONS",
+                "GET,HEAD,OPTIONS",
+            ),
+            (
+                "/macs/aabbccddeeffaabb/render_svg",
+                "POST,OPTIONS",
+                "OPTIONS",
+            ),
+        ] {
+            assert_eq!(allow(fix.config.clone(), uri).await, allowed, "{uri}");
+            assert_eq!(
+                allow(read_only.clone(), uri).await,
+                allowed_read_only,
+                "{uri}"
+            );
+        }
+
+        // Writes are rejected like any other unregistered method
+        let response = app(read_only)
+            .into_service()
+            .oneshot(
+                Request::builder()
+                    .uri("/macs/aabbccddeeffaabb/png")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET,HEAD,OPTIONS");
+    }
+
+    struct MockClock(std::sync::Mutex<chrono::DateTime<chrono::Utc>>);
+
+    impl clock::Clock for MockClock {
+        fn now(&self) -> chrono::DateTime<chrono::Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn daily_stats_csv() {
+        let fix = get_test_fixture();
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(
+            "2024-03-12T10:00:00Z".parse().unwrap(),
+        )));
+        let image_handler = Arc::new(ImageHandler::with_clock(fix.config, clock.clone()));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let render = |mac: &str| {
+            Request::post(format!("/macs/{mac}/render_svg"))
+                .body(Body::from("<rect width=\"10\" height=\"10\"/>"))
+                .unwrap()
+        };
+        let fetch = |mac: &str| {
+            Request::get(format!("/macs/{mac}/png"))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let daily = |query: &str| {
+            Request::get(format!("/stats/daily?{query}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for request in [
+            render("123456789abcdef1"),
+            render("123456789abcdef1"),
+            fetch("123456789abcdef1"),
+            render("00000000000000aa"),
+        ] {
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // Counted in the file of the 12th, even when flushed after midnight
+        *clock.0.lock().unwrap() = "2024-03-13T00:00:30Z".parse().unwrap();
+        for request in [fetch("123456789abcdef1"), fetch("123456789abcdef1")] {
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(daily("format=csv"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/csv; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "date,mac,renders,fetches\r\n\
+             2024-03-12,00000000000000aa,1,0\r\n\
+             2024-03-12,123456789abcdef1,2,1\r\n\
+             2024-03-13,123456789abcdef1,0,2\r\n"
+        );
+        assert!(fix.temp_dir.path("stats/2024-03-12.json").exists());
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(daily("from=2024-03-13&to=2024-03-13"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let rows: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            rows,
+            json!([{"date": "2024-03-13", "mac": "123456789abcdef1", "renders": 0, "fetches": 2}])
+        );
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(daily("from=2024-03-13&to=2024-03-12"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn poll_interval() {
+        let mut fix = get_test_fixture();
+        fix.config.poll_max = HumanDuration::from_secs(900);
+        let start: chrono::DateTime<chrono::Utc> = "2024-03-12T10:00:00Z".parse().unwrap();
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(start)));
+        let image_handler = Arc::new(ImageHandler::with_clock(fix.config, clock.clone()));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let uri = "/macs/123456789abcdef1";
+        let suggested = |response: &Response| {
+            response
+                .headers()
+                .get("x-eps-suggested-poll-seconds")
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+
+        // Every 10 minutes, with a change of the image each time
+        let mut minutes = 0;
+        for width in 1..=4 {
+            *clock.0.lock().unwrap() = start + chrono::Duration::minutes(minutes);
+            let request = Request::post(format!("{uri}/render_svg"))
+                .body(Body::from(format!(
+                    "<rect width=\"{width}\" height=\"10\"/>"
+                )))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            minutes += 10;
+        }
+        let request = Request::get(format!("{uri}/png"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(suggested(&response).as_deref(), Some("600"));
+        // Also told when the image is unchanged
+        let etag = response.headers()[header::ETAG].clone();
+        let request = Request::get(format!("{uri}/png"))
+            .header(header::IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(suggested(&response).as_deref(), Some("600"));
+
+        // Moves toward hourly changes, but no further than --poll-max
+        let mut last = 600;
+        for width in 5..=8 {
+            minutes += 60;
+            *clock.0.lock().unwrap() = start + chrono::Duration::minutes(minutes);
+            let request = Request::post(format!("{uri}/render_svg"))
+                .body(Body::from(format!(
+                    "<rect width=\"{width}\" height=\"10\"/>"
+                )))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let request = Request::get(format!("{uri}/checkin"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let checkin: Value = serde_json::from_slice(&body).unwrap();
+            let secs = checkin["suggested_poll_secs"].as_u64().unwrap();
+            assert!(secs > last || secs == 900, "{secs} after {last}");
+            last = secs;
+        }
+        assert_eq!(last, 900);
+        // Unchanged renders don't count
+        minutes += 5;
+        *clock.0.lock().unwrap() = start + chrono::Duration::minutes(minutes);
+        let request = Request::post(format!("{uri}/render_svg"))
+            .body(Body::from("<rect width=\"8\" height=\"10\"/>"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = Request::get(format!("{uri}/poll_interval"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let interval: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(interval["suggested_secs"], 900);
+        assert!(interval["average_secs"].as_f64().unwrap() > 1800.0);
+
+        // The override wins over the cadence and the limits
+        let request = Request::put(format!("{uri}/poll_interval"))
+            .body(Body::from(r#"{"override_secs": 30}"#))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let interval: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(interval["suggested_secs"], 30);
+        let request = Request::get(format!("{uri}/raw"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(suggested(&response).as_deref(), Some("30"));
+        let request = Request::put(format!("{uri}/poll_interval"))
+            .body(Body::from(r#"{"override_secs": 0}"#))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = Request::get("/stats").body(Body::empty()).unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["suggested_poll_secs"]["count"], 1);
+        assert_eq!(stats["suggested_poll_secs"]["p50"], 30);
+
+        let request = Request::delete(format!("{uri}/poll_interval"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let interval: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(interval["suggested_secs"], 900);
+        assert!(interval.get("override_secs").is_none());
+
+        // Unknown for MACs whose image didn't change twice
+        let request = Request::get("/macs/00000000000000aa/checkin")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let checkin: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(checkin["suggested_poll_secs"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn deferred_activation() {
+        let fix = get_test_fixture();
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(
+            "2024-03-12T12:00:00Z".parse().unwrap(),
+        )));
+        let image_handler = Arc::new(ImageHandler::with_clock(fix.config.clone(), clock.clone()));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let render = |query: &str, width: u32| {
+            Request::post(format!("/macs/123456789abcdef1/render_svg{query}"))
+                .body(Body::from(format!(
+                    "<rect width=\"{width}\" height=\"10\"/>"
+                )))
+                .unwrap()
+        };
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let midnight = "?activate_at=2024-03-13T00:00:00%2B00:00";
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(render("", 10))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        let live_etag = response.headers()[header::ETAG].clone();
+
+        // The second deferred render replaces the first
+        for width in [20, 30] {
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(render(midnight, width))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let status: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(status, json!({"activate_at": "2024-03-13T00:00:00Z"}));
+        }
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/staging"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let status: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["activate_at"], "2024-03-13T00:00:00Z");
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/staging/png"))
+            .await
+            .unwrap();
+        let staged = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // Not a second early
+        *clock.0.lock().unwrap() = "2024-03-12T23:59:59Z".parse().unwrap();
+        assert_eq!(image_handler.run_due_activations().await.unwrap(), 0);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::ETAG], live_etag);
+
+        // Kept across a restart
+        let image_handler = Arc::new(ImageHandler::with_clock(fix.config.clone(), clock.clone()));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        *clock.0.lock().unwrap() = "2024-03-13T00:00:00Z".parse().unwrap();
+        assert_eq!(image_handler.run_due_activations().await.unwrap(), 1);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/png"))
+            .await
+            .unwrap();
+        assert_ne!(response.headers()[header::ETAG], live_etag);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, staged);
+        let (history, _) = image_handler.events().subscribe(Some(0), None);
+        assert_eq!(history.events.len(), 1);
+        assert_eq!(history.events[0].kind, events::EventKind::Render);
+        assert_eq!(
+            history.events[0].timestamp,
+            "2024-03-13T00:00:00Z"
+                .parse::<chrono::DateTime<Utc>>()
+                .unwrap()
+        );
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(get("/macs/123456789abcdef1/staging"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(image_handler.run_due_activations().await.unwrap(), 0);
+
+        // Cancelled
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(render("?activate_at=2024-03-14T00:00:00Z", 40))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(
+                Request::delete("/macs/123456789abcdef1/staging")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        *clock.0.lock().unwrap() = "2024-03-15T00:00:00Z".parse().unwrap();
+        assert_eq!(image_handler.run_due_activations().await.unwrap(), 0);
+
+        // Past times activate right away
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(render("?activate_at=2024-03-14T00:00:00Z", 50))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let (history, _) = image_handler.events().subscribe(Some(0), None);
+        assert_eq!(history.events.len(), 2);
+
+        for query in [
+            "?activate_at=tomorrow",
+            "?activate_at=2024-03-16T00:00:00Z&rerender=daily",
+        ] {
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(render(query, 60))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn render_svg_scheduled() {
+        let fix = get_test_fixture();
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(
+            "2024-03-12T10:00:00Z".parse().unwrap(),
+        )));
+        let image_handler = Arc::new(ImageHandler::with_clock(fix.config, clock.clone()));
+        let app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+
+        let svg_path = fix.temp_dir.path("123456789abcdef1.svg");
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/macs/123456789abcdef1/render_svg?rerender=daily@00:05")
+                    .method("POST")
+                    .body(Body::from("<text>Today: {{now \"%A %d %B\"}}</text>"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let svg = std::fs::read_to_string(&svg_path).unwrap();
+        assert!(svg.contains("<text>Today: Tuesday 12 March</text>"));
+
+        *clock.0.lock().unwrap() = "2024-03-12T23:00:00Z".parse().unwrap();
+        assert_eq!(image_handler.run_due_rerenders().await.unwrap(), 0);
+
+        *clock.0.lock().unwrap() = "2024-03-13T00:06:00Z".parse().unwrap();
+        assert_eq!(image_handler.run_due_rerenders().await.unwrap(), 1);
+        let svg = std::fs::read_to_string(&svg_path).unwrap();
+        assert!(svg.contains("<text>Today: Wednesday 13 March</text>"));
+
+        assert_eq!(image_handler.run_due_rerenders().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn playlist() {
+        let fix = get_test_fixture();
+        // Tuesday
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(
+            "2024-03-12T17:59:00Z".parse().unwrap(),
+        )));
+        let image_handler = Arc::new(ImageHandler::with_clock(fix.config, clock.clone()));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let svg_path = fix.temp_dir.path("123456789abcdef1.svg");
+
+        let playlist = json!({
+            "entries": [
+                {"svg": "<text>free</text>", "cron": "0 18 * * *"},
+                {"svg": "<text>schedule</text>", "cron": "0 8 * * MON-FRI"},
+            ]
+        });
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/playlist")
+            .method("PUT")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(playlist.to_string()))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let svg = std::fs::read_to_string(&svg_path).unwrap();
+        assert!(svg.contains("<text>schedule</text>"));
+        assert_eq!(image_handler.run_playlists().await.unwrap(), 0);
+
+        *clock.0.lock().unwrap() = "2024-03-12T18:00:30Z".parse().unwrap();
+        assert_eq!(image_handler.run_playlists().await.unwrap(), 1);
+        let svg = std::fs::read_to_string(&svg_path).unwrap();
+        assert!(svg.contains("<text>free</text>"));
+        assert_eq!(image_handler.run_playlists().await.unwrap(), 0);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/playlist")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["entries"], playlist["entries"]);
+        assert_eq!(body["active"], 0);
+        assert_eq!(body["next_transition"], "2024-03-13T08:00:00Z");
+
+        for body in [
+            json!({"entries": []}),
+            json!({"entries": [{"svg": "<text>", "cron": "0 8 * * *"}]}),
+            json!({"entries": [{"svg": "", "cron": "every day"}]}),
+        ] {
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/playlist")
+                .method("PUT")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{body}");
+        }
+
+        let request = Request::builder()
+            .uri("/macs/0011223344556677/playlist")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn broadcast() {
+        let fix = get_test_fixture();
+        let image_handler = Arc::new(ImageHandler::new(fix.config.clone()));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let file = |name: String| std::fs::read(fix.temp_dir.path(&name)).unwrap();
+        let png = |mac: &str| file(format!("{mac}.png"));
+        let black = |png: &[u8], x, y| {
+            let image = image::load_from_memory(png).unwrap().to_luma8();
+            image.get_pixel(x, y).0[0] < 128
+        };
+
+        let macs = ["123456789abcdef1", "123456789abcdef2", "123456789abcdef3"];
+        let profiles = [
+            json!({}),
+            json!({"rotate": 90}),
+            json!({"flip": "horizontal"}),
+        ];
+        for (mac, profile) in macs.iter().zip(profiles) {
+            let request = Request::put(format!("/macs/{mac}/profile"))
+                .body(Body::from(profile.to_string()))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let request = Request::post(format!("/macs/{mac}/render_svg"))
+                .body(Body::from(format!("<text x=\"10\" y=\"40\">{mac}</text>")))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let shown = |mac: &str| (png(mac), file(format!("{mac}.svg")));
+        let before: Vec<_> = macs.iter().map(|mac| shown(mac)).collect();
+
+        let request = Request::post("/broadcast")
+            .body(Body::from(
+                json!({
+                    "svg": "<rect width=\"400\" height=\"400\" fill=\"white\"/>\
+                        <rect width=\"64\" height=\"64\"/>"
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let results: Vec<BroadcastResult> = serde_json::from_slice(&body).unwrap();
+        assert!(
+            results.iter().all(|result| result.error.is_none()),
+            "{results:?}"
+        );
+        for mac in macs {
+            assert!(results.iter().any(|result| result.mac == mac));
+        }
+        // In the top left corner of each canvas
+        assert!(black(&png(macs[0]), 5, 5));
+        assert!(black(&png(macs[1]), 122, 5));
+        assert!(!black(&png(macs[1]), 5, 5));
+        assert!(black(&png(macs[2]), 122, 5));
+        assert!(!black(&png(macs[2]), 5, 5));
+
+        // Provisioned during the broadcast
+        let request = Request::post("/macs/123456789abcdef4/render_svg")
+            .body(Body::from("<text x=\"10\" y=\"40\">new</text>"))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let provisioned = shown("123456789abcdef4");
+        assert_eq!(image_handler.extend_broadcast().await.unwrap(), 1);
+        assert!(black(&png("123456789abcdef4"), 5, 5));
+        assert_eq!(image_handler.run_due_rerenders().await.unwrap(), 0);
+
+        let request = Request::delete("/broadcast").body(Body::empty()).unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let results: Vec<BroadcastResult> = serde_json::from_slice(&body).unwrap();
+        assert!(
+            results.iter().all(|result| result.error.is_none()),
+            "{results:?}"
+        );
+        for (mac, before) in macs.iter().zip(before) {
+            assert_eq!(shown(mac), before, "{mac}");
+        }
+        assert_eq!(shown("123456789abcdef4"), provisioned);
+        assert!(image_handler.active_broadcast().await.unwrap().is_none());
+
+        let request = Request::delete("/broadcast").body(Body::empty()).unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn broadcast_restores_whole_render() {
+        async fn render_log<S>(app: &mut S, mac: &str) -> Vec<RenderRecord>
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let request = Request::get(format!("/macs/{mac}/render_log"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        let mut fix = get_test_fixture();
+        fix.config.convert_text_to_paths = true;
+        let mut app = app(fix.config.clone()).into_service();
+        let mac = "123456789abcdef1";
+        let files = |suffixes: &[&str]| -> Vec<Option<Vec<u8>>> {
+            suffixes
+                .iter()
+                .map(|suffix| std::fs::read(fix.temp_dir.path(&format!("{mac}{suffix}"))).ok())
+                .collect()
+        };
+        let suffixes = [".png", ".svg", ".svg.orig", ".png.prev"];
+        for (text, query) in [("first", ""), ("second", "?rotate=180")] {
+            let request = Request::post(format!("/macs/{mac}/render_svg{query}"))
+                .body(Body::from(format!("<text x=\"10\" y=\"40\">{text}</text>")))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let before = files(&suffixes);
+        assert!(before.iter().all(Option::is_some));
+        let options = render_log(&mut app, mac).await.pop().unwrap().options;
+        assert!(options.is_some());
+
+        for (method, body) in [
+            (
+                "POST",
+                json!({"svg": "<text x=\"10\" y=\"40\">Fire drill</text>"}),
+            ),
+            ("DELETE", Value::Null),
+        ] {
+            let request = Request::builder()
+                .method(method)
+                .uri("/broadcast")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(files(&suffixes), before);
+        let restored = render_log(&mut app, mac).await.pop().unwrap();
+        assert_eq!(restored.options, options);
+        let broadcast = [
+            ".png.broadcast",
+            ".svg.broadcast",
+            ".svg.orig.broadcast",
+            ".png.prev.broadcast",
+        ];
+        assert!(files(&broadcast).iter().all(Option::is_none));
+    }
+
+    #[tokio::test]
+    async fn render_during_broadcast() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let mac = "123456789abcdef1";
+        let png = || std::fs::read(fix.temp_dir.path(&format!("{mac}.png"))).unwrap();
+        let mut call = |method: &str, uri: &str, body: String| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::from(body))
+                .unwrap();
+            let response = app.call(request);
+            async move { response.await.unwrap().status() }
+        };
+        let render = format!("/macs/{mac}/render_svg");
+        let fire_drill = json!({"svg": "<rect width=\"64\" height=\"64\"/>"}).to_string();
+
+        let before = "<circle cx=\"64\" cy=\"64\" r=\"30\"/>".to_owned();
+        assert_eq!(call("POST", &render, before).await, StatusCode::OK);
+        assert_eq!(
+            call("POST", "/broadcast", fire_drill.clone()).await,
+            StatusCode::OK
+        );
+        let during = "<circle cx=\"64\" cy=\"64\" r=\"50\"/>".to_owned();
+        assert_eq!(call("POST", &render, during).await, StatusCode::OK);
+        let posted = png();
+
+        // Posting the broadcast again shows it, and saves the new render
+        assert_eq!(call("POST", "/broadcast", fire_drill).await, StatusCode::OK);
+        assert_ne!(png(), posted);
+        assert_eq!(
+            call("DELETE", "/broadcast", String::new()).await,
+            StatusCode::OK
+        );
+        assert_eq!(png(), posted);
+
+        // Rendered after the broadcast was shown for the last time
+        let fire_drill = json!({"svg": "<rect width=\"32\" height=\"32\"/>"}).to_string();
+        assert_eq!(call("POST", "/broadcast", fire_drill).await, StatusCode::OK);
+        let after = "<circle cx=\"64\" cy=\"64\" r=\"20\"/>".to_owned();
+        assert_eq!(call("POST", &render, after).await, StatusCode::OK);
+        let posted = png();
+        assert_eq!(
+            call("DELETE", "/broadcast", String::new()).await,
+            StatusCode::OK
+        );
+        assert_eq!(png(), posted);
+    }
+
+    #[tokio::test]
+    async fn group_render() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        for (mac, groups) in [
+            ("123456789abcdef1", json!(["aisle-3"])),
+            ("123456789abcdef2", json!(["aisle-3", "office"])),
+            ("123456789abcdef3", json!(["office"])),
+        ] {
+            let request = Request::builder()
+                .uri(format!("/macs/{mac}/groups"))
+                .method("PUT")
+                .body(Body::from(groups.to_string()))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/groups")
+            .method("PUT")
+            .body(Body::from(r#"["../aisle"]"#))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let render = json!({
+            "svg": "<circle cx=\"64\" cy=\"64\" r=\"30\" />",
+            "overrides": {"123456789abcdef2": "<circle cx=\"64\" cy=\"64\" r=\"40\" />"},
+        });
+        let request = Request::builder()
+            .uri("/groups/aisle-3/render_svg")
+            .method("POST")
+            .body(Body::from(render.to_string()))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!([{"mac": "123456789abcdef1"}, {"mac": "123456789abcdef2"}])
+        );
+
+        let png = |mac: &str| std::fs::read(fix.temp_dir.path(&format!("{mac}.png"))).ok();
+        let (first, second) = (png("123456789abcdef1"), png("123456789abcdef2"));
+        assert!(first.is_some() && second.is_some());
+        assert_ne!(first, second);
+        assert!(png("123456789abcdef3").is_none());
+
+        let request = Request::builder()
+            .uri("/macs?group=aisle-3")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!(["123456789abcdef1", "123456789abcdef2"]));
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1")
+            .method("DELETE")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/groups")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"[]");
+
+        let request = Request::builder()
+            .uri("/groups/cold-room/render_svg")
+            .method("POST")
+            .body(Body::from(render.to_string()))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn verify_images() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        for mac in ["123456789abcdef1", "123456789abcdef2"] {
+            let request = Request::builder()
+                .uri(format!("/macs/{mac}/render_svg"))
+                .method("POST")
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let corrupted = fix.temp_dir.path("123456789abcdef2.png");
+        let mut png = std::fs::read(&corrupted).unwrap();
+        let len = png.len();
+        png.truncate(len / 2);
+        std::fs::write(&corrupted, png).unwrap();
+        std::fs::copy(
+            fix.temp_dir.path("123456789abcdef1.svg"),
+            fix.temp_dir.path("123456789abcdef3.svg"),
+        )
+        .unwrap();
+
+        let verify = |uri: &'static str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(verify("/admin/verify?pixel_tolerance=2"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let reports: Vec<VerifyReport> = serde_json::from_slice(&body).unwrap();
+        let statuses: Vec<_> = reports
+            .iter()
+            .map(|report| (report.mac.as_str(), report.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("0011223344556677", VerifyStatus::OrphanPng),
+                ("123456789abcdef1", VerifyStatus::Ok),
+                ("123456789abcdef2", VerifyStatus::Mismatch),
+                ("123456789abcdef3", VerifyStatus::OrphanSvg),
+                ("aabbccddeeffaabb", VerifyStatus::Unreadable),
+            ]
+        );
+        assert!(reports[2].error.is_some());
+        assert!(reports.iter().all(|report| !report.regenerated));
+        assert!(!fix.temp_dir.path("123456789abcdef3.png").exists());
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(verify("/admin/verify?fix=regenerate"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let reports: Vec<VerifyReport> = serde_json::from_slice(&body).unwrap();
+        assert!(reports[2].regenerated && reports[3].regenerated);
+        assert_eq!(
+            std::fs::read(&corrupted).unwrap(),
+            std::fs::read(fix.temp_dir.path("123456789abcdef1.png")).unwrap()
+        );
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(verify("/admin/verify"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let reports: Vec<VerifyReport> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reports[2].status, VerifyStatus::Ok);
+        assert_eq!(reports[3].status, VerifyStatus::Ok);
+    }
+
+    #[tokio::test]
+    async fn cleanup() {
+        let fix = get_test_fixture();
+        let dir = fix.config.image_dir.clone();
+        let mut app = app(fix.config).into_service();
+        let files = || -> BTreeSet<String> {
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect()
+        };
+        let untouched = files();
+        let hours_ago = std::time::SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        for (name, old) in [
+            (".0011223344556677.png.3.tmp", true),
+            (".0011223344556677.svg.4.tmp", false),
+            ("maintenance.json.tmp", true),
+            ("0011223344556677.png.prev", false),
+            ("1111111111111111.png.prev", false),
+            ("1111111111111111.svg.orig", false),
+            ("notes.txt", true),
+            (".notes.txt.1.tmp", true),
+        ] {
+            let file = std::fs::File::create(fix.temp_dir.path(name)).unwrap();
+            std::io::Write::write_all(&mut &file, b"leftover").unwrap();
+            if old {
+                file.set_modified(hours_ago).unwrap();
+            }
+        }
+
+        let cleanup = |uri: &'static str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(cleanup("/admin/cleanup"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report: CleanupReport = serde_json::from_slice(&body).unwrap();
+        let names = |files: &[cleanup::CleanedFile]| -> Vec<String> {
+            files.iter().map(|file| file.name.clone()).collect()
+        };
+        assert_eq!(
+            names(&report.temp_files),
+            [".0011223344556677.png.3.tmp", "maintenance.json.tmp"]
+        );
+        assert_eq!(
+            names(&report.orphans),
+            ["1111111111111111.png.prev", "1111111111111111.svg.orig"]
+        );
+        assert!(!report.orphans_removed);
+        assert_eq!((report.files_removed, report.bytes_reclaimed), (2, 16));
+        let mut expected = untouched.clone();
+        expected.extend(
+            [
+                ".0011223344556677.svg.4.tmp",
+                "0011223344556677.png.prev",
+                "1111111111111111.png.prev",
+                "1111111111111111.svg.orig",
+                "notes.txt",
+                ".notes.txt.1.tmp",
+            ]
+            .map(String::from),
+        );
+        assert_eq!(files(), expected);
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(cleanup("/admin/cleanup?delete=true"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report: CleanupReport = serde_json::from_slice(&body).unwrap();
+        assert!(report.temp_files.is_empty());
+        assert!(report.orphans_removed);
+        assert_eq!((report.files_removed, report.bytes_reclaimed), (2, 16));
+        expected.remove("1111111111111111.png.prev");
+        expected.remove("1111111111111111.svg.orig");
+        assert_eq!(files(), expected);
+    }
+
+    #[tokio::test]
+    async fn dedup() {
+        let mut fix = get_test_fixture();
+        fix.config.dedup = true;
+        let objects_dir = fix.config.image_dir.join(dedup::OBJECTS_DIR);
+        let mut app = app(fix.config).into_service();
+        let macs = ["1111111111111111", "2222222222222222", "3333333333333333"];
+        let objects = || -> BTreeMap<String, u64> {
+            std::fs::read_dir(&objects_dir)
+                .map(|entries| {
+                    entries
+                        .map(|entry| {
+                            let entry = entry.unwrap();
+                            let links =
+                                std::os::unix::fs::MetadataExt::nlink(&entry.metadata().unwrap());
+                            (entry.file_name().into_string().unwrap(), links)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        async fn call<S>(app: &mut S, request: Request<Body>) -> Bytes
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        }
+        let post = |uri: &str| Request::post(uri).body(Body::empty()).unwrap();
+
+        for mac in macs {
+            let request = Request::post(format!("/macs/{mac}/render_svg"))
+                .body(Body::from(r#"<circle cx="64" cy="64" r="32" />"#))
+                .unwrap();
+            call(&mut app, request).await;
+        }
+        // The SVG and the PNG, each linked from the three MACs
+        let stored = objects();
+        assert_eq!(stored.values().collect::<Vec<_>>(), [&4, &4]);
+        let stats = call(
+            &mut app,
+            Request::get("/stats").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let usage = serde_json::from_slice::<Stats>(&stats)
+            .unwrap()
+            .dedup
+            .unwrap();
+        assert_eq!((usage.objects, usage.references), (2, 6));
+        assert_eq!(usage.logical_bytes, 3 * usage.physical_bytes);
+
+        let request = Request::get(format!("/macs/{}/png", macs[2]))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let png = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let hash = hex::encode(Sha256::digest(&png));
+        assert!(etag.contains(&hash), "{etag}");
+        assert!(stored.contains_key(&hash));
+
+        for mac in &macs[..2] {
+            let request = Request::delete(format!("/macs/{mac}"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert!(response.status().is_success());
+        }
+        let report = call(&mut app, post("/admin/cleanup?delete=true")).await;
+        let report: CleanupReport = serde_json::from_slice(&report).unwrap();
+        assert!(report.orphans.is_empty(), "{report:?}");
+        assert_eq!(objects().values().collect::<Vec<_>>(), [&2, &2]);
+        let request = Request::get(format!("/macs/{}/png", macs[2]))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(call(&mut app, request).await, png);
+
+        let request = Request::delete(format!("/macs/{}", macs[2]))
+            .body(Body::empty())
+            .unwrap();
+        app.ready().await.unwrap().call(request).await.unwrap();
+        let report = call(&mut app, post("/admin/cleanup?delete=true")).await;
+        let report: CleanupReport = serde_json::from_slice(&report).unwrap();
+        assert_eq!(report.orphans.len(), 2);
+        assert!(report
+            .orphans
+            .iter()
+            .any(|file| file.name == format!("objects/{hash}")));
+        assert!(objects().is_empty());
+
+        // The empty files of the fixture
+        let report = call(&mut app, post("/admin/dedup")).await;
+        let report: DedupReport = serde_json::from_slice(&report).unwrap();
+        assert_eq!(report.migrated, 3);
+        assert_eq!((report.usage.objects, report.usage.references), (1, 3));
+        let report = call(&mut app, post("/admin/dedup")).await;
+        let report: DedupReport = serde_json::from_slice(&report).unwrap();
+        assert_eq!(report.migrated, 0);
+    }
+
+    /// Reads the event stream `body` until it contains `until`.
+    async fn read_events<B>(body: &mut B, until: &str) -> String
+    where
+        B: hyper::body::HttpBody<Data = Bytes> + Unpin,
+        B::Error: std::fmt::Debug,
+    {
+        let mut text = String::new();
+        while !text.contains(until) {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .expect("no event in time")
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        text
+    }
+
+    #[tokio::test]
+    async fn http2_prior_knowledge() {
+        async fn connect(config: Config) -> hyper::Result<hyper::client::conn::SendRequest<Body>> {
+            let image_handler = Arc::new(ImageHandler::new(config));
+            let builder = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)));
+            let server = configure_http(builder, image_handler.config()).serve(
+                router(image_handler, &[], detached_log_level())
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            );
+            let stream = tokio::net::TcpStream::connect(server.local_addr())
+                .await
+                .unwrap();
+            tokio::spawn(server);
+            let (sender, connection) = hyper::client::conn::Builder::new()
+                .http2_only(true)
+                .handshake(stream)
+                .await?;
+            tokio::spawn(connection);
+            Ok(sender)
+        }
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let fix = get_test_fixture();
+        let mut config = fix.config.clone();
+        let mut sender = connect(config.clone()).await.unwrap();
+        let request = sender.send_request(get("/macs"));
+        assert!(request.await.is_err());
+
+        config.http2 = true;
+        let mut sender = connect(config).await.unwrap();
+        let mut events = sender.send_request(get("/events")).await.unwrap();
+        assert_eq!(events.version(), hyper::Version::HTTP_2);
+
+        // Several requests at once on the same connection
+        let mut pending = vec![];
+        for uri in ["/macs", "/stats", "/ready", "/macs/aabbccddeeffaabb/svg"] {
+            sender.ready().await.unwrap();
+            pending.push(sender.send_request(get(uri)));
+        }
+        for response in futures_util::future::join_all(pending).await {
+            let response = response.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.version(), hyper::Version::HTTP_2);
+        }
+
+        // Events arrive while the stream stays open
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(r#"<rect width="10" height="10"/>"#))
+            .unwrap();
+        sender.ready().await.unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let text = read_events(events.body_mut(), "id:1\n").await;
+        assert!(text.contains("123456789abcdef1"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn device_listener() {
+        let fix = get_test_fixture();
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let shared = SharedState::new(image_handler, &[], detached_log_level());
+        let mut addrs = BTreeMap::new();
+        for listener in [Listener::Full, Listener::Device] {
+            let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+                shared
+                    .router(listener)
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            );
+            addrs.insert(listener, server.local_addr());
+            tokio::spawn(server);
+        }
+        let (full, device) = (addrs[&Listener::Full], addrs[&Listener::Device]);
+        let client = hyper::Client::new();
+        let request = |method: Method, addr: SocketAddr, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{path}"))
+                .body(Body::from(r#"<rect width="10" height="10"/>"#))
+                .unwrap();
+            client.request(request)
+        };
+
+        let render = "/macs/123456789abcdef1/render_svg";
+        let response = request(Method::POST, device, render).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = request(Method::POST, full, render).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        for addr in [full, device] {
+            let response = request(Method::GET, addr, "/macs/123456789abcdef1/png")
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+            let response = request(Method::GET, addr, "/macs/123456789abcdef1/checkin")
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // Neither deletes nor admin and status routes, nor reads the devices
+        // don't need
+        for (method, path) in [
+            (Method::DELETE, "/macs/123456789abcdef1"),
+            (Method::PUT, "/macs/123456789abcdef1/profile"),
+            (Method::GET, "/config"),
+            (Method::POST, "/admin/rerender"),
+            (Method::GET, "/stats"),
+            (Method::GET, "/macs"),
+            (Method::GET, "/events"),
+            (Method::GET, "/macs/123456789abcdef1/render_log"),
+            (Method::GET, "/macs/123456789abcdef1/svg/original"),
+            (Method::GET, "/provision/123456789abcdef1"),
+        ] {
+            let response = request(method, device, path).await.unwrap();
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{path}");
+        }
+        let response = request(Method::GET, full, "/stats").await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["requests_total"], json!({"full": 4, "device": 13}));
+    }
+
+    #[tokio::test]
+    async fn websocket_session() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut fix = get_test_fixture();
+        fix.config.ws_max_subscriptions = 2;
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            router(image_handler, &[], detached_log_level())
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        );
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        type Socket = tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >;
+        /// Sends `message` unless it's null and returns the next message.
+        async fn exchange(socket: &mut Socket, message: Value) -> Value {
+            if !message.is_null() {
+                socket
+                    .send(Message::text(message.to_string()))
+                    .await
+                    .unwrap();
+            }
+            loop {
+                if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                    return serde_json::from_str(&text).unwrap();
+                }
+            }
+        }
+
+        let reply = exchange(
+            &mut socket,
+            json!({"subscribe": ["123456789ABCDEF1", "0011223344556677"]}),
+        )
+        .await;
+        assert_eq!(
+            reply,
+            json!({"subscribed": ["0011223344556677", "123456789abcdef1"]})
+        );
+        let reply = exchange(
+            &mut socket,
+            json!({"subscribe": ["0011223344556677", "aabbccddeeffaabb", "123456789abcdef1"]}),
+        )
+        .await;
+        assert_eq!(reply["error"]["code"], "bad_request");
+        let reply = exchange(&mut socket, json!({"hello": 1})).await;
+        assert_eq!(reply["error"]["code"], "bad_request");
+
+        let render = |mac: &str| {
+            Request::post(format!("http://{addr}/macs/{mac}/render_svg"))
+                .body(Body::from(r#"<rect width="10" height="10"/>"#))
+                .unwrap()
+        };
+        let client = hyper::Client::new();
+        // Not subscribed, so only the second render is pushed
+        for mac in ["aabbccddeeffaabb", "123456789abcdef1"] {
+            let response = client.request(render(mac)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let push = exchange(&mut socket, Value::Null).await;
+        assert_eq!(push["event"], "updated");
+        assert_eq!(push["mac"], "123456789abcdef1");
+        let etag = push["etag"].as_str().unwrap().to_string();
+        assert!(etag.starts_with('"'), "{etag}");
+
+        let reply = exchange(
+            &mut socket,
+            json!({"ack": {"mac": "123456789abcdef1", "etag": "\"stale\""}}),
+        )
+        .await;
+        assert_eq!(reply["error"]["code"], "precondition_failed");
+        let reply = exchange(
+            &mut socket,
+            json!({"ack": {"mac": "123456789abcdef1", "etag": etag, "refresh": "full"}}),
+        )
+        .await;
+        assert_eq!(reply, json!({"acked": "123456789abcdef1"}));
+        let reply = exchange(
+            &mut socket,
+            json!({"checkin": {"mac": "123456789abcdef1", "capabilities": {"formats": ["png"]}}}),
+        )
+        .await;
+        assert_eq!(reply, json!({"checked_in": "123456789abcdef1"}));
+
+        let request = Request::delete(format!("http://{addr}/macs/123456789abcdef1"))
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let push = exchange(&mut socket, Value::Null).await;
+        assert_eq!(push, json!({"event": "deleted", "mac": "123456789abcdef1"}));
+    }
+
+    #[tokio::test]
+    async fn event_history_and_resume() {
+        let mut fix = get_test_fixture();
+        fix.config.event_history = 3;
+        let mut app = app(fix.config).into_service();
+
+        let render = |mac: &str| {
+            Request::builder()
+                .uri(format!("/macs/{mac}/render_svg"))
+                .method("POST")
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                .unwrap()
+        };
+
+        let request = Request::builder()
+            .uri("/events")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut events = response.into_body();
+
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(render("123456789abcdef1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let text = read_events(&mut events, "id:1\n").await;
+        assert!(text.contains("event:render"), "{text}");
+        // Disconnect
+        drop(events);
+
+        for mac in ["123456789abcdef1", "0011223344556677"] {
+            let response = app.ready().await.unwrap().call(render(mac)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let request = Request::builder()
+            .uri("/events/history?since=1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let history: History = serde_json::from_slice(&body).unwrap();
+        let seqs: Vec<_> = history.events.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, [2, 3]);
+        assert_eq!(history.events[1].mac, "0011223344556677");
+        assert!(!history.resync);
+
+        let request = Request::builder()
+            .uri("/events/history?since=1&mac=0011223344556677")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let history: History = serde_json::from_slice(&body).unwrap();
+        assert_eq!(history.events.len(), 1);
+
+        let request = Request::builder()
+            .uri("/events")
+            .header(LAST_EVENT_ID, "1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let mut events = response.into_body();
+        let text = read_events(&mut events, "id:3\n").await;
+        assert!(text.contains("id:2\n"), "{text}");
+        assert!(!text.contains("id:1\n"), "{text}");
+        assert!(!text.contains("resync"), "{text}");
+        drop(events);
+
+        // The first event is no longer buffered
+        let response = app
+            .ready()
+            .await
+            .unwrap()
+            .call(render("0011223344556677"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = Request::builder()
+            .uri("/events")
+            .header(LAST_EVENT_ID, "0")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let mut events = response.into_body();
+        let text = read_events(&mut events, "id:4\n").await;
+        assert!(text.starts_with("event:resync"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn render_log() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+
+        for body in [
+            "<circle cx=\"64\" cy=\"64\" r=\"30\" />",
+            "<circle cx=\"64\" cy=\"64\" r=\"30\" />",
+            "<circle cx=\"64\" cy=\"64\" r=\"40\" />",
+        ] {
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/render_svg")
+                .method("POST")
+                .body(Body::from(body))
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_log")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let log: Vec<RenderRecord> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(log.len(), 3);
+        assert_eq!(
+            log.iter().map(|r| r.changed).collect::<Vec<_>>(),
+            [true, false, true]
+        );
+        assert!(log.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert!(log.iter().all(|r| r.svg_bytes > 0 && r.png_bytes > 0));
+
+        let request = Request::builder()
+            .uri("/stats")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(stats["render_duration_ms"]["count"], 3);
+        assert!(stats["render_duration_ms"]["p95"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn post_render_hook() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut fix = get_test_fixture();
+        let script = |name: &str, body: String| {
+            let path = fix.temp_dir.path(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        let marker = fix.temp_dir.path("marker.json");
+        // Only touches the marker if it got the MAC and the PNG
+        let touch = script(
+            "touch.sh",
+            format!(
+                "[ \"$1\" = 123456789abcdef1 ] && [ -s \"$2\" ] && cat > {}",
+                marker.display()
+            ),
+        );
+        let fail = script("fail.sh", "echo broken >&2; exit 3".to_owned());
+
+        let render = |config: Config| async move {
+            let mut app = app(config).into_service();
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/render_svg")
+                .method("POST")
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                .unwrap();
+            let status = app.call(request).await.unwrap().status();
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/render_log")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let log: Vec<RenderRecord> = serde_json::from_slice(&body).unwrap();
+            (status, log.last().unwrap().post_render.clone().unwrap())
+        };
+
+        fix.config.post_render_cmd = Some(touch);
+        let (status, report) = render(fix.config.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(report.success);
+        assert_eq!(report.exit_code, Some(0));
+        let input: Value = serde_json::from_slice(&std::fs::read(&marker).unwrap()).unwrap();
+        assert_eq!(input["mac"], "123456789abcdef1");
+        assert_eq!(input["render"]["changed"], true);
+
+        // Only recorded, unless strict
+        fix.config.post_render_cmd = Some(fail);
+        let (status, report) = render(fix.config.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!report.success);
+        assert_eq!(report.exit_code, Some(3));
+        fix.config.post_render_strict = true;
+        let (status, report) = render(fix.config).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(report.exit_code, Some(3));
+    }
+
+    #[tokio::test]
+    async fn rotate_and_flip() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        // A wide bar in the top left corner of the canvas
+        let glyph = r#"<rect width="400" height="400" fill="white" />
+            <rect width="20" height="10" fill="black" />"#;
+        let black = |png: &[u8], x, y| {
+            let image = image::load_from_memory(png).unwrap().to_luma8();
+            assert_eq!(image.dimensions(), (128, 296));
+            image.get_pixel(x, y).0[0] < 128
+        };
+
+        let request = Request::post("/macs/123456789abcdef1/render_svg?rotate=90&flip=horizontal")
+            .body(Body::from(glyph))
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let png = std::fs::read(fix.temp_dir.path("123456789abcdef1.png")).unwrap();
+        // Rotated onto the top right as a tall bar, then mirrored to the left
+        assert!(black(&png, 5, 15));
+        assert!(!black(&png, 15, 5));
+        assert!(!black(&png, 122, 5));
+        // The stored SVG is as posted
+        let svg = std::fs::read_to_string(fix.temp_dir.path("123456789abcdef1.svg")).unwrap();
+        assert!(svg.contains(r#"viewBox="0 0 296 128""#), "{svg}");
+
+        let request = Request::put("/macs/123456789abcdef1/profile")
+            .body(Body::from(r#"{"flip":"horizontal"}"#))
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = Request::get("/macs/123456789abcdef1/profile")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let profile: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(profile["flip"], "horizontal");
+        assert_eq!(profile["rotate"], 0);
+        assert_eq!(profile["width"], 128);
+
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(glyph))
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let png = std::fs::read(fix.temp_dir.path("123456789abcdef1.png")).unwrap();
+        assert!(black(&png, 115, 5));
+        assert!(!black(&png, 5, 5));
+
+        let request = Request::post("/macs/123456789abcdef1/render_svg?rotate=45")
+            .body(Body::from(glyph))
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn regions() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let mut call = |request: Request<Body>| {
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, body)
+            }
+        };
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let regions = json!({
+            "header": {"x": 0, "y": 0, "w": 128, "h": 16},
+            "body": {"x": 8, "y": 16, "w": 64, "h": 100},
+            "odd": {"x": 3, "y": 40, "w": 10, "h": 2},
+        });
+        let request = Request::put("/macs/123456789abcdef1/profile")
+            .body(Body::from(json!({ "regions": regions }).to_string()))
+            .unwrap();
+        assert_eq!(call(request).await.0, StatusCode::OK);
+        let (_, body) = call(get("/macs/123456789abcdef1/profile")).await;
+        let profile: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(profile["regions"], regions);
+        let outside = json!({"regions": {"footer": {"x": 0, "y": 290, "w": 128, "h": 16}}});
+        let request = Request::put("/macs/123456789abcdef1/profile")
+            .body(Body::from(outside.to_string()))
+            .unwrap();
+        assert_eq!(call(request).await.0, StatusCode::BAD_REQUEST);
+
+        // A black header above a white body
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(
+                r#"<rect width="128" height="296" fill="white" />
+                <rect width="128" height="16" fill="black" />"#,
+            ))
+            .unwrap();
+        assert_eq!(call(request).await.0, StatusCode::OK);
+
+        let (status, header) = call(get("/macs/123456789abcdef1/raw?region_name=header")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header.len(), 128 / 8 * 16);
+        assert!(header.iter().all(|&byte| byte == 0));
+        let (_, body) = call(get("/macs/123456789abcdef1/raw?region_name=body")).await;
+        assert_eq!(body.len(), 64 / 8 * 100);
+        assert!(body.iter().all(|&byte| byte == 0xff));
+
+        let (status, png) = call(get("/macs/123456789abcdef1/png?region_name=body")).await;
+        assert_eq!(status, StatusCode::OK);
+        let image = image::load_from_memory(&png).unwrap().to_luma8();
+        assert_eq!(image.dimensions(), (64, 100));
+        assert!(image.pixels().all(|pixel| pixel.0[0] == 255));
+
+        // Only the packed rows need whole bytes
+        let (status, png) = call(get("/macs/123456789abcdef1/png?region_name=odd")).await;
+        assert_eq!(status, StatusCode::OK);
+        let image = image::load_from_memory(&png).unwrap();
+        assert_eq!((image.width(), image.height()), (10, 2));
+        let odd = "/macs/123456789abcdef1/raw?region_name=odd";
+        assert_eq!(call(get(odd)).await.0, StatusCode::BAD_REQUEST);
+        let (status, raw) = call(get(&format!("{odd}&align=expand"))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(raw.len(), 2 * 2);
+
+        let missing = "/macs/123456789abcdef1/raw?region_name=footer";
+        assert_eq!(call(get(missing)).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn lint() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let mut call = |request: Request<Body>| {
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+            }
+        };
+        let render = |query: &str, svg: &str| {
+            Request::post(format!("/macs/123456789abcdef1/render_svg{query}"))
+                .body(Body::from(svg.to_owned()))
+                .unwrap()
+        };
+        let codes = |warnings: &Value| -> Vec<(String, String)> {
+            let warnings = warnings.as_array().cloned().unwrap_or_default();
+            warnings
+                .iter()
+                .map(|warning| {
+                    let element = warning["element"].as_str().unwrap_or_default();
+                    (
+                        warning["code"].as_str().unwrap().to_owned(),
+                        element.to_owned(),
+                    )
+                })
+                .collect()
+        };
+        let last_lint = |log: Value| log.as_array().unwrap().last().unwrap()["lint"].clone();
+        let render_log = || {
+            Request::get("/macs/123456789abcdef1/render_log")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let fixtures = [
+            (
+                r#"<line id="rule" x2="128" y1="10" y2="10" stroke="black" stroke-width="0.5" />
+                <line x2="128" y1="20" y2="20" stroke="black" stroke-width="2" />"#,
+                ("hairline", "rule"),
+            ),
+            (
+                r#"<g transform="scale(0.5)"><line id="scaled" x2="128" stroke="black" /></g>"#,
+                ("hairline", "scaled"),
+            ),
+            (
+                r#"<text id="fine" y="20" font-size="6">fine print</text>
+                <text y="40" font-size="16">Headline</text>"#,
+                ("tiny_text", "fine"),
+            ),
+            (
+                r##"<rect id="shade" width="50" height="50" fill="#808080" />
+                <rect y="60" width="50" height="50" fill="#202020" />"##,
+                ("mid_gray_fill", "shade"),
+            ),
+        ];
+        for (svg, (code, element)) in fixtures {
+            let (status, _) = call(render("", svg)).await;
+            assert_eq!(status, StatusCode::OK);
+            let (_, log) = call(render_log()).await;
+            assert_eq!(
+                codes(&last_lint(log)),
+                [(code.to_owned(), element.to_owned())],
+                "{svg}"
+            );
+
+            let (status, error) = call(render("?strict_lint=true", svg)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(error["code"], "lint_failed");
+            assert_eq!(codes(&error["details"]["lint"]).len(), 1);
+        }
+
+        let clean = r#"<rect width="128" height="40" fill="black" />
+            <text y="80" font-size="14">Hello</text>
+            <line y1="100" x2="128" y2="100" stroke="black" stroke-width="1.5" />"#;
+        let (status, _) = call(render("?strict_lint=true", clean)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, log) = call(render_log()).await;
+        assert_eq!(last_lint(log), Value::Null);
+
+        // A denser panel tolerates smaller text
+        let request = Request::put("/macs/123456789abcdef1/profile")
+            .body(Body::from(
+                json!({"lint": {"min_font_size": 5}}).to_string(),
+            ))
+            .unwrap();
+        let (status, profile) = call(request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(profile["lint"], json!({"min_font_size": 5.0}));
+        let (status, _) = call(render("?strict_lint=true", fixtures[2].0)).await;
+        assert_eq!(status, StatusCode::OK);
+        let request = Request::put("/macs/123456789abcdef1/profile")
+            .body(Body::from(json!({"lint": {"gray_band": -1}}).to_string()))
+            .unwrap();
+        assert_eq!(call(request).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn shaped_text() {
+        let mut fix = get_test_fixture();
+        // Text without a family is rendered with the first fallback
+        fix.config.font_fallbacks = vec![" DejaVu Sans".to_owned()];
+        let mut app = app(fix.config).into_service();
+
+        async fn render<S>(app: &mut S, svg: &str) -> (GrayImage, Value)
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let mac = "123456789abcdef1";
+            let requests = [
+                Request::post(format!("/macs/{mac}/render_svg")).body(Body::from(format!(
+                    r#"<rect width="128" height="296" fill="white" />{svg}"#
+                ))),
+                Request::get(format!("/macs/{mac}/png")).body(Body::empty()),
+                Request::get(format!("/macs/{mac}/render_log")).body(Body::empty()),
+            ];
+            let mut bodies = vec![];
+            for request in requests {
+                let response = app
+                    .ready()
+                    .await
+                    .unwrap()
+                    .call(request.unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                bodies.push(hyper::body::to_bytes(response.into_body()).await.unwrap());
+            }
+            let (png, log) = (&bodies[1], &bodies[2]);
+            let log: Value = serde_json::from_slice(log).unwrap();
+            let png = image::load_from_memory_with_format(png, ImageFormat::Png).unwrap();
+            (
+                png.to_luma8(),
+                log.as_array().unwrap().last().unwrap()["lint"].clone(),
+            )
+        }
+        let black = |image: &GrayImage| image.pixels().filter(|pixel| pixel.0[0] < 0x80).count();
+
+        let arabic = "مرحبا بالعالم";
+        for text in [arabic, "Shop ١٢ مرحبا 42 بالعالم!"] {
+            let (shaped, lint) = render(
+                &mut app,
+                &format!(r#"<text x="4" y="60" font-size="16">{text}</text>"#),
+            )
+            .await;
+            assert!(black(&shaped) > 0, "{text}");
+            assert_eq!(lint, Value::Null, "{text}");
+
+            // Each character on its own, left to right and unconnected
+            let naive: String = text
+                .chars()
+                .enumerate()
+                .map(|(i, c)| {
+                    format!(
+                        r#"<text x="{}" y="60" font-size="16">{c}</text>"#,
+                        4 + 8 * i
+                    )
+                })
+                .collect();
+            let (naive, _) = render(&mut app, &naive).await;
+            assert_ne!(shaped, naive, "{text}");
+        }
+
+        let (_, lint) = render(
+            &mut app,
+            "<text id=\"private\" y=\"60\">\u{10fffd}\u{10fffd} &#x627;</text>",
+        )
+        .await;
+        assert_eq!(
+            lint,
+            json!([{"code": "missing_glyph", "element": "private", "value": 2.0}])
+        );
+    }
+
+    #[tokio::test]
+    async fn render_options() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
+        let mut call = |request: Request<Body>| {
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, body)
+            }
+        };
+        let last_options = |body: &[u8]| {
+            let log: Value = serde_json::from_slice(body).unwrap();
+            log.as_array().unwrap().last().unwrap()["options"].clone()
+        };
+
+        let request = Request::put("/macs/123456789abcdef1/profile")
+            .body(Body::from(r#"{"rotate":180}"#))
+            .unwrap();
+        assert_eq!(call(request).await.0, StatusCode::OK);
+        let request = Request::put("/macs/123456789abcdef1/render_options")
+            .body(Body::from(r#"{"dither":"threshold","flip":"vertical"}"#))
+            .unwrap();
+        let (status, body) = call(request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({"dither": "threshold", "flip": "vertical"})
+        );
+        let request = Request::put("/macs/123456789abcdef1/render_options")
+            .body(Body::from(r#"{"dither":"sparkles"}"#))
+            .unwrap();
+        assert_eq!(call(request).await.0, StatusCode::BAD_REQUEST);
+
+        // Stored options over the profile over the config
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(r#"<circle cx="64" cy="64" r="30" />"#))
+            .unwrap();
+        assert_eq!(call(request).await.0, StatusCode::OK);
+        let request = Request::get("/macs/123456789abcdef1/render_log")
+            .body(Body::empty())
+            .unwrap();
+        let (_, body) = call(request).await;
+        assert_eq!(
+            last_options(&body),
+            json!({
+                "dither": "threshold",
+                "rotate": 180,
+                "flip": "vertical",
+                "text": "live",
+                "color_map": "preserve",
+            })
+        );
+
+        // The query over all of them
+        let request =
+            Request::post("/macs/123456789abcdef1/render_svg?dither=floyd-steinberg&rotate=0")
+                .body(Body::from(r#"<circle cx="64" cy="64" r="30" />"#))
+                .unwrap();
+        assert_eq!(call(request).await.0, StatusCode::OK);
+        let request = Request::get("/macs/123456789abcdef1/render_log")
+            .body(Body::empty())
+            .unwrap();
+        let (_, body) = call(request).await;
+        let options = last_options(&body);
+        assert_eq!(options["dither"], "floyd-steinberg");
+        assert_eq!(options["rotate"], 0);
+        assert_eq!(options["flip"], "vertical");
+    }
+
+    #[tokio::test]
+    async fn provision() {
+        let mut fix = get_test_fixture();
+        fix.config.admin_key = Some("secret".to_owned());
+        let mut app = app(fix.config).into_service();
+        let mut get = |uri: &str| {
+            let request = Request::get(uri)
+                .header(header::HOST, "eps.example:3000")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let content_type = response.headers()[header::CONTENT_TYPE].clone();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, content_type, body)
+            }
+        };
+
+        let (status, content_type, body) = get("/provision/123456789ABCDEF1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        let page = String::from_utf8(body.to_vec()).unwrap();
+        assert!(page.contains("123456789abcdef1"));
+        assert!(page.contains("No image yet"));
+        assert!(page.contains(r#"id="key""#));
+        let (_, _, body) = get("/provision/0011223344556677").await;
+        let page = String::from_utf8(body.to_vec()).unwrap();
+        assert!(page.contains("../macs/0011223344556677/png"));
+        assert_eq!(get("/provision/nope").await.0, StatusCode::BAD_REQUEST);
+
+        let (status, content_type, body) = get("/macs/123456789abcdef1/provision_qr.png").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "image/png");
+        // Samples the center of each module, after the quiet zone of 4
+        let image = image::load_from_memory(&body).unwrap().to_luma8();
+        let expected =
+            qrcode::QrCode::new("http://eps.example:3000/provision/123456789abcdef1").unwrap();
+        let width = expected.width() as u32;
+        let module = image.width() / (width + 8);
+        assert!(image.width() >= 256);
+        let modules: Vec<_> = (0..width * width)
+            .map(|i| {
+                let (x, y) = (4 + i % width, 4 + i / width);
+                let pixel = image.get_pixel(x * module + module / 2, y * module + module / 2);
+                if pixel.0[0] < 128 {
+                    qrcode::Color::Dark
+                } else {
+                    qrcode::Color::Light
+                }
+            })
+            .collect();
+        assert_eq!(modules, expected.to_colors());
+    }
+
+    #[tokio::test]
+    async fn tenants() {
+        async fn call<S>(app: &mut S, method: &str, uri: &str, key: &str) -> (StatusCode, Value)
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let body = match method {
+                "POST" => Body::from(r#"<rect width="10" height="10"/>"#),
+                _ => Body::empty(),
+            };
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {key}"))
+                .body(body)
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+        }
+
+        let mut fix = get_test_fixture();
+        fix.config.tenants = vec![
+            "bakery=bread".parse().unwrap(),
+            "florist=tulip".parse().unwrap(),
+        ];
+        let mut app = app(fix.config.clone()).into_service();
+
+        let render = "/macs/1111111111111111/render_svg";
+        let (status, _) = call(&mut app, "POST", &format!("/t/bakery{render}"), "bread").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(fix
+            .temp_dir
+            .path("tenants/bakery/1111111111111111.png")
+            .exists());
+        let render = "/macs/2222222222222222/render_svg";
+        let (status, _) = call(&mut app, "POST", &format!("/t/florist{render}"), "tulip").await;
+        assert_eq!(status, StatusCode::OK);
+
+        for (uri, key, macs) in [
+            ("/t/bakery/macs", "bread", json!(["1111111111111111"])),
+            ("/t/florist/macs", "tulip", json!(["2222222222222222"])),
+            ("/macs", "", json!(["0011223344556677", "aabbccddeeffaabb"])),
+        ] {
+            assert_eq!(
+                call(&mut app, "GET", uri, key).await,
+                (StatusCode::OK, macs)
+            );
+        }
+
+        for (method, uri, key, status) in [
+            (
+                "GET",
+                "/t/bakery/macs/1111111111111111/png",
+                "bread",
+                StatusCode::OK,
+            ),
+            (
+                "GET",
+                "/t/bakery/macs/2222222222222222/png",
+                "bread",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "GET",
+                "/t/bakery/macs/aabbccddeeffaabb/png",
+                "bread",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "GET",
+                "/macs/1111111111111111/png",
+                "",
+                StatusCode::NOT_FOUND,
+            ),
+            ("GET", "/t/bakery/macs", "tulip", StatusCode::UNAUTHORIZED),
+            ("GET", "/t/bakery/macs", "", StatusCode::UNAUTHORIZED),
+            ("GET", "/t/butcher/macs", "bread", StatusCode::NOT_FOUND),
+            ("GET", "/t/../macs", "bread", StatusCode::NOT_FOUND),
+            (
+                "GET",
+                "/t/bakery/admin/log_level",
+                "bread",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "PUT",
+                "/t/bakery/admin/log_level",
+                "bread",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "DELETE",
+                "/t/florist/macs/1111111111111111",
+                "tulip",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "DELETE",
+                "/t/bakery/macs/1111111111111111",
+                "bread",
+                StatusCode::OK,
+            ),
+        ] {
+            let (actual, _) = call(&mut app, method, uri, key).await;
+            assert_eq!(actual, status, "{method} {uri}");
+        }
+        assert_eq!(
+            call(&mut app, "GET", "/t/bakery/macs", "bread").await,
+            (StatusCode::OK, json!([]))
+        );
+        assert!(fix
+            .temp_dir
+            .path("tenants/florist/2222222222222222.png")
+            .exists());
+    }
 
-    // run it
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    tracing::debug!("Listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app(config).into_make_service())
-        .await
+    #[tokio::test]
+    async fn sharded_tenants() {
+        let mut fix = get_test_fixture();
+        fix.config.shard_depth = 1;
+        // Named like a shard directory of the default tenant
+        fix.config.tenants = vec!["ab=key".parse().unwrap()];
+        // Stored before the images were sharded
+        let tenant_dir = fix.config.image_dir.join(TENANTS_DIR).join("ab");
+        std::fs::create_dir_all(&tenant_dir).unwrap();
+        std::fs::copy(
+            fix.temp_dir.path("0011223344556677.png"),
+            tenant_dir.join("abab000000000000.png"),
+        )
         .unwrap();
-}
+        let app = app(fix.config.clone());
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, "Bearer key")
+                .body(Body::from(r#"<rect width="10" height="10"/>"#))
+                .unwrap()
+        };
 
-fn app(config: Config) -> Router<Arc<AppState>, Body> {
-    let image_handler = ImageHandler::new(config);
-    let state = Arc::new(AppState { image_handler });
+        for uri in [
+            "/macs/1111111111111111/render_svg",
+            "/t/ab/macs/abab222222222222/render_svg",
+        ] {
+            let response = app.clone().oneshot(request("POST", uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+        assert!(tenant_dir.join("ab/abab222222222222.png").exists());
 
-    // build our application with a route
-    Router::with_state(state)
-        .route("/macs", get(get_macs))
-        .route("/macs/:mac", delete(delete_images))
-        .route("/macs/:mac/svg", get(get_svg))
-        .route("/macs/:mac/render_svg", post(render_svg))
-        .route("/macs/:mac/png", get(get_png))
-        .layer(TraceLayer::new_for_http())
-}
+        for (uri, macs) in [
+            (
+                "/macs",
+                json!(["0011223344556677", "1111111111111111", "aabbccddeeffaabb"]),
+            ),
+            (
+                "/t/ab/macs",
+                json!(["abab000000000000", "abab222222222222"]),
+            ),
+        ] {
+            let response = app.clone().oneshot(request("GET", uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(
+                serde_json::from_slice::<Value>(&body).unwrap(),
+                macs,
+                "{uri}"
+            );
+        }
+    }
 
-#[debug_handler]
-async fn get_macs(state: State<Arc<AppState>>) -> Result<Json<Vec<String>>, AppError> {
-    let mut macs = state.image_handler.get_macs().await?;
-    macs.sort();
-    Ok(Json(macs.iter().map(|mac| format!("{mac}")).collect()))
-}
+    #[tokio::test]
+    async fn idempotency_key() {
+        let fix = get_test_fixture();
+        let mut service = app(fix.config.clone()).into_service();
+        let request = |method: &str, uri: &str, key: &str, body: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("idempotency-key", key)
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let replayed =
+            |response: &Response| response.headers().contains_key("x-eps-idempotent-replay");
 
-#[debug_handler]
-async fn delete_images(
-    Path(mac): Path<String>,
-    state: State<Arc<AppState>>,
-) -> Result<(), AppError> {
-    let mac = mac.parse().map_err(AppError::BadRequest)?;
-    state.image_handler.delete_images(mac).await
-}
+        let mut responses = vec![];
+        for _ in 0..2 {
+            let response = service
+                .ready()
+                .await
+                .unwrap()
+                .call(request("DELETE", "/macs/aabbccddeeffaabb", "first", ""))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let is_replay = replayed(&response);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            responses.push((is_replay, body));
+        }
+        assert!(!responses[0].0);
+        assert!(responses[1].0);
+        assert_eq!(responses[0].1, responses[1].1);
+        assert!(!fix.temp_dir.path("aabbccddeeffaabb.png").exists());
+        // Saved in the background
+        for _ in 0..100 {
+            if fix.temp_dir.path(IDEMPOTENCY_FILE).exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(fix.temp_dir.path(IDEMPOTENCY_FILE).exists());
 
-#[debug_handler]
-async fn render_svg(
-    Path(mac): Path<String>,
-    state: State<Arc<AppState>>,
-    body: String,
-) -> Result<(), AppError> {
-    let mac = mac.parse().map_err(AppError::BadRequest)?;
-    state.image_handler.post_svg_body(mac, &body).await
-}
+        // The same key for another route runs
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(request(
+                "POST",
+                "/macs/123456789abcdef1/render_svg",
+                "first",
+                r#"<rect width="10" height="10"/>"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!replayed(&response));
 
-#[debug_handler]
-async fn get_svg(
-    Path(mac): Path<String>,
-    state: State<Arc<AppState>>,
-) -> Result<impl IntoResponse, AppError> {
-    let mac = mac.parse().map_err(AppError::BadRequest)?;
-    let stream = state.image_handler.get_svg(mac).await?;
-    Ok(stream_to_response(stream, mime::IMAGE_SVG))
-}
-#[debug_handler]
-async fn get_png(
-    Path(mac): Path<String>,
-    state: State<Arc<AppState>>,
-) -> Result<impl IntoResponse, AppError> {
-    let mac = mac.parse().map_err(AppError::BadRequest)?;
-    let stream = state.image_handler.get_png(mac).await?;
-    Ok(stream_to_response(stream, mime::IMAGE_PNG))
-}
+        // Duplicates sent at once run once
+        let first = service.ready().await.unwrap().call(request(
+            "DELETE",
+            "/macs/123456789abcdef1",
+            "second",
+            "",
+        ));
+        let second = service.ready().await.unwrap().call(request(
+            "DELETE",
+            "/macs/123456789abcdef1",
+            "second",
+            "",
+        ));
+        let (first, second) = futures_util::future::join(first, second).await;
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_ne!(replayed(&first), replayed(&second));
 
-fn stream_to_response(
-    stream: ReaderStream<File>,
-    content_type: Mime,
-) -> impl IntoResponse + 'static {
-    let body = StreamBody::new(stream);
-    ([(header::CONTENT_TYPE, content_type.to_string())], body)
-}
+        // Also after a restart
+        let mut restarted = app(fix.config.clone()).into_service();
+        let response = restarted
+            .ready()
+            .await
+            .unwrap()
+            .call(request("DELETE", "/macs/aabbccddeeffaabb", "first", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(replayed(&response));
 
-#[cfg(test)]
-mod tests {
-    use axum::http::{Request, StatusCode};
-    use serde_json::{json, Value};
-    use test_dir::{DirBuilder, FileType, TestDir};
-    use tower::{Service, ServiceExt};
+        let response = restarted
+            .ready()
+            .await
+            .unwrap()
+            .call(request("DELETE", "/macs/aabbccddeeffaabb", "café", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 
-    use super::*;
+    #[tokio::test]
+    async fn maintenance() {
+        let mut fix = get_test_fixture();
+        fix.config.admin_key = Some("secret".to_owned());
+        let mut service = app(fix.config.clone()).into_service();
+        let request = |method: &str, uri: &str, body: Body| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, "Bearer secret")
+                .body(body)
+                .unwrap()
+        };
+        let get = |uri: &str| request("GET", uri, Body::empty());
+        let start = |body: Value| {
+            let mut request = request("POST", "/admin/maintenance", Body::from(body.to_string()));
+            request.headers_mut().insert(
+                header::CONTENT_TYPE,
+                mime::APPLICATION_JSON.as_ref().parse().unwrap(),
+            );
+            request
+        };
 
-    struct Fixture {
-        config: Config,
-        temp_dir: TestDir,
-    }
+        let until = Utc::now() + chrono::Duration::seconds(120);
+        let body = json!({"until": until, "message": "Moving the images"});
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(start(body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(fix.temp_dir.path(MAINTENANCE_FILE).exists());
 
-    fn get_test_fixture() -> Fixture {
-        let temp_dir = TestDir::temp()
-            .create("0011223344556677.png", FileType::EmptyFile)
-            .create("aabbccddeeffaabb.png", FileType::EmptyFile)
-            .create("aabbccddeeffaabb.svg", FileType::EmptyFile);
+        // Also after a restart
+        let mut restarted = app(fix.config.clone()).into_service();
+        for app in [&mut service, &mut restarted] {
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(get("/macs/0011223344556677/png"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let retry_after: i64 = response.headers()[header::RETRY_AFTER]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((110..=120).contains(&retry_after), "{retry_after}");
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body.code, "maintenance");
+            assert_eq!(body.message, "Moving the images");
 
-        Fixture {
-            config: Config {
-                image_dir: temp_dir.path(""),
-                epd_height: 296,
-                epd_width: 128,
-            },
-            temp_dir,
+            for (uri, status) in [
+                ("/ready", StatusCode::SERVICE_UNAVAILABLE),
+                ("/stats", StatusCode::OK),
+                ("/config", StatusCode::OK),
+                ("/macs", StatusCode::SERVICE_UNAVAILABLE),
+            ] {
+                let response = app.ready().await.unwrap().call(get(uri)).await.unwrap();
+                assert_eq!(response.status(), status, "{uri}");
+            }
+        }
+        let response = restarted
+            .ready()
+            .await
+            .unwrap()
+            .call(request(
+                "POST",
+                "/macs/0011223344556677/render_svg",
+                Body::from("<g/>"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = restarted
+            .ready()
+            .await
+            .unwrap()
+            .call(request("DELETE", "/admin/maintenance", Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!fix.temp_dir.path(MAINTENANCE_FILE).exists());
+        for uri in ["/macs/0011223344556677/png", "/ready"] {
+            let response = restarted
+                .ready()
+                .await
+                .unwrap()
+                .call(get(uri))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
         }
+
+        let body = json!({"until": Utc::now() - chrono::Duration::seconds(1)});
+        let response = restarted
+            .ready()
+            .await
+            .unwrap()
+            .call(start(body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
 
     #[tokio::test]
-    async fn get_macs() {
-        let fix = get_test_fixture();
-        let app = app(fix.config).into_service();
+    async fn render_priority() {
+        let mut fix = get_test_fixture();
+        fix.config.max_concurrent_renders = 1;
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let render = |mac: &str, priority: &str| {
+            Request::post(format!("/macs/{mac}/render_svg"))
+                .header("x-eps-priority", priority)
+                .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
+                .unwrap()
+        };
 
         let response = app
-            .oneshot(Request::builder().uri("/macs").body(Body::empty()).unwrap())
+            .clone()
+            .oneshot(render("123456789abcdef1", "urgent"))
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
-        assert_eq!(response.status(), StatusCode::OK);
+        // Holds the only permit like a slow render
+        let held = image_handler
+            .render_queue()
+            .acquire(Priority::Batch)
+            .await
+            .unwrap();
+        let (sender, mut done) = tokio::sync::mpsc::unbounded_channel();
+        let queued = || {
+            let depth = image_handler.render_queue_depth();
+            depth.interactive + depth.batch
+        };
+        for (i, (mac, priority)) in [
+            ("123456789abcdef1", "batch"),
+            ("123456789abcdef2", "batch"),
+            ("123456789abcdef3", "interactive"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let (app, sender) = (app.clone(), sender.clone());
+            let request = render(mac, priority);
+            tokio::spawn(async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                sender.send(mac).unwrap();
+            });
+            while queued() <= i {
+                tokio::task::yield_now().await;
+            }
+        }
 
+        let response = app
+            .clone()
+            .oneshot(Request::get("/stats").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
         let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-        let body: Value = serde_json::from_slice(&body).unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["render_queue"], json!({"interactive": 1, "batch": 2}));
 
-        assert_eq!(body, json!(["0011223344556677", "AABBCCDDEEFFAABB"]));
+        // The permit goes to the interactive render, the batch ones wait on
+        drop(held);
+        assert_eq!(
+            image_handler.render_queue_depth(),
+            QueueDepth {
+                interactive: 0,
+                batch: 2
+            }
+        );
+        drop(sender);
+        let mut rendered = vec![];
+        while let Some(mac) = done.recv().await {
+            rendered.push(mac);
+        }
+        rendered.sort();
+        assert_eq!(
+            rendered,
+            ["123456789abcdef1", "123456789abcdef2", "123456789abcdef3"]
+        );
     }
 
     #[tokio::test]
-    async fn delete_images() {
+    async fn watchdog_stuck_render() {
         let fix = get_test_fixture();
-        let mut app = app(fix.config).into_service();
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+        let mac: EpdMac = "123456789abcdef1".parse().unwrap();
 
-        let png_path = fix.temp_dir.path("0011223344556677.png");
-        assert!(png_path.exists());
+        // A render that never completes
+        let handler = image_handler.clone();
+        let stuck = tokio::spawn(async move {
+            let _render = handler.watchdog().start(mac);
+            std::future::pending::<()>().await
+        });
+        tokio::task::yield_now().await;
+
+        let now = std::time::Instant::now();
+        assert!(image_handler.scan_renders(now).is_empty());
+        let found = image_handler.scan_renders(now + Duration::from_secs(300));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].mac, mac);
 
         let request = Request::builder()
-            .uri("/macs/0011223344556677")
-            .method("DELETE")
+            .uri("/ready")
             .body(Body::empty())
             .unwrap();
-
         let response = app.ready().await.unwrap().call(request).await.unwrap();
-
-        assert_eq!(response.status(), StatusCode::OK);
-        assert!(!png_path.exists());
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
 
         let request = Request::builder()
-            .uri("/macs/0011223344556677")
-            .method("DELETE")
+            .uri("/stats")
             .body(Body::empty())
             .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["render_stuck_total"], 1);
 
+        // Dropping the render unregisters it
+        stuck.abort();
+        let _ = stuck.await;
+        assert!(image_handler
+            .scan_renders(now + Duration::from_secs(300))
+            .is_empty());
+        let request = Request::builder()
+            .uri("/ready")
+            .body(Body::empty())
+            .unwrap();
         let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    #[tokio::test]
+    async fn dependency_health() {
+        use crate::{object_storage::ObjectStorage, outbound::Outbound};
+
+        // An object store refusing the expired credentials
+        let s3 = axum::Router::new().fallback(|| async { StatusCode::FORBIDDEN });
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(s3.into_make_service());
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        let url: url::Url = "s3://bucket/images".parse().unwrap();
+        let options = [
+            ("aws_endpoint", endpoint.as_str()),
+            ("aws_allow_http", "true"),
+            ("aws_skip_signature", "true"),
+            ("aws_region", "eu-central-1"),
+        ]
+        .map(|(key, value)| (key.to_owned(), value.to_owned()));
+
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        for required in [false, true] {
+            let mut fix = get_test_fixture();
+            fix.config.storage = Some(url.clone());
+            if required {
+                fix.config.require_dependency = vec![Dependency::Storage];
+            }
+            let store = ObjectStorage::with_options(&url, options.clone(), &Outbound::default());
+            let storage = Storage::with_store(Arc::new(store.unwrap()), std::env::temp_dir());
+            let config = ValidatedConfig::new(fix.config).unwrap();
+            let image_handler = Arc::new(
+                ImageHandler::with_storage(config, Arc::new(SystemClock), storage).unwrap(),
+            );
+            let mut app = router(image_handler.clone(), &[], detached_log_level()).into_service();
+
+            // Not probed yet
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(get("/ready"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let readiness: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(readiness, json!({"status": "ready"}));
+
+            image_handler.check_dependencies().await;
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(get("/ready"))
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            if required {
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+                assert!(
+                    body["message"].as_str().unwrap().contains("storage"),
+                    "{body}"
+                );
+            } else {
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(body["status"], "degraded");
+                assert_eq!(body["dependencies"]["storage"]["healthy"], false);
+            }
+
+            let response = app
+                .ready()
+                .await
+                .unwrap()
+                .call(get("/stats"))
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let stats: Value = serde_json::from_slice(&body).unwrap();
+            let storage = &stats["dependencies"]["storage"];
+            assert_eq!(storage["healthy"], false);
+            assert_eq!(storage["required"], required);
+            assert!(
+                storage["error"].as_str().unwrap().contains("Listing"),
+                "{storage}"
+            );
+        }
+
+        // Healthy, and nothing probed without --storage
+        let mut fix = get_test_fixture();
+        fix.config.storage = Some("memory:///".parse().unwrap());
+        fix.config.require_dependency = vec![Dependency::Storage];
+        let image_handler = Arc::new(ImageHandler::new(fix.config));
+        image_handler.check_dependencies().await;
+        assert!(image_handler.dependencies().health()[&Dependency::Storage].healthy);
+        let image_handler = Arc::new(ImageHandler::new(get_test_fixture().config));
+        image_handler.check_dependencies().await;
+        assert!(image_handler.dependencies().health().is_empty());
     }
 
     #[tokio::test]
-    async fn get_svg() {
+    async fn check_config_only() {
         let fix = get_test_fixture();
+        let config = ValidatedConfig::new(fix.config.clone()).unwrap();
+        check_config(config).await.unwrap();
+
+        let mut config = fix.config.clone();
+        config.post_render_cmd = Some(fix.temp_dir.path("missing-hook"));
+        let config = ValidatedConfig::new(config).unwrap();
+        let error = check_config(config).await.unwrap_err();
+        assert!(
+            format!("{error:#}").contains("--post-render-cmd"),
+            "{error:#}"
+        );
+
+        let mut config = fix.config;
+        config.storage = Some("memory:///".parse().unwrap());
+        config.require_dependency = vec![Dependency::Storage];
+        check_config(ValidatedConfig::new(config).unwrap())
+            .await
+            .unwrap();
+    }
+
+    /// Text rendered with `--deterministic-render`, `#` for black pixels.
+    const GOLDEN_TEXT: [&str; 16] = [
+        "................................................................................................",
+        "................................................................................................",
+        "................................................................................................",
+        "...#######..#####....#####............####...............##...#####.............................",
+        "...##.......#...##..#................##..##.............###...#...##............................",
+        "...##.......#....#..#................#....#.............###........#............................",
+        "...##.......#....#..##...............#....#............#.##........#............................",
+        "...######...#...##...###.............#.##.#...........##.##.......#.............................",
+        "...##.......#####......###...........#.##.#...........#..##......##.............................",
+        "...##.......#............#...........#....#..........##..##.....##..............................",
+        "...##.......#............#...........#...##...........######...##...............................",
+        "...##.......#.......#....#...........##..##....##........##...##................................",
+        "...#######..#.......#####.............####.....##........##...######............................",
+        "................................................................................................",
+        "................................................................................................",
+        "................................................................................................",
+    ];
+
+    #[tokio::test]
+    async fn deterministic_render_golden() {
+        let mut fix = get_test_fixture();
+        fix.config.deterministic_render = true;
+        fix.config.epd_width = 96;
+        fix.config.epd_height = 16;
+        // Ignored like the installed fonts
+        fix.config.font_fallbacks = vec!["DejaVu Serif".to_owned()];
         let mut app = app(fix.config).into_service();
 
-        let request = Request::builder()
-            .uri("/macs/0011223344556677/svg")
+        let request = Request::post("/macs/123456789abcdef1/render_svg")
+            .body(Body::from(
+                r#"<rect width="96" height="16" fill="white"/>
+                <text x="2" y="13" font-size="14" font-family="Arial">EPS 0.42</text>"#,
+            ))
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let png = std::fs::read(fix.temp_dir.path("123456789abcdef1.png")).unwrap();
+        let png = image::load_from_memory(&png).unwrap().to_luma8();
+        let rendered: Vec<String> = png
+            .rows()
+            .map(|row| row.map(|p| if p.0[0] < 128 { '#' } else { '.' }).collect())
+            .collect();
+        assert_eq!(rendered.len(), GOLDEN_TEXT.len());
+        // Edge pixels may flip between releases of resvg, which the
+        // fingerprint tells apart
+        let differing: usize = rendered
+            .iter()
+            .zip(GOLDEN_TEXT)
+            .map(|(row, golden)| {
+                row.chars()
+                    .zip(golden.chars())
+                    .filter(|(a, b)| a != b)
+                    .count()
+            })
+            .sum();
+        assert!(
+            differing <= 16,
+            "{differing} pixels differ:\n{}",
+            rendered.join("\n")
+        );
+
+        let request = Request::get("/macs/123456789abcdef1/render_log")
             .body(Body::empty())
             .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let log: Vec<RenderRecord> = serde_json::from_slice(&body).unwrap();
+        let pipeline = log[0].pipeline.as_deref().unwrap();
+        assert!(pipeline.starts_with("resvg-0."), "{pipeline}");
+    }
+
+    #[tokio::test]
+    async fn get_image_negotiation() {
+        let fix = get_test_fixture();
+        let mut app = app(fix.config).into_service();
 
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .body(Body::from(
+                "<rect x=\"0\" y=\"0\" width=\"64\" height=\"296\" />",
+            ))
+            .unwrap();
         let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let cases = [
+            ("image/png", "image/png"),
+            (
+                "image/png;q=0.5, image/svg+xml",
+                "image/svg+xml; charset=utf-8",
+            ),
+            ("image/*, image/png;q=0.1, image/svg+xml;q=0.2", "image/bmp"),
+            (
+                "application/octet-stream, image/*;q=0.9",
+                "application/octet-stream",
+            ),
+        ];
+        for (accept, expected) in cases {
+            let request = Request::builder()
+                .uri("/macs/123456789abcdef1/image")
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{accept}");
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                expected,
+                "{accept}"
+            );
+        }
 
         let request = Request::builder()
-            .uri("/macs/aabbccddeeffaabb/svg")
+            .uri("/macs/123456789abcdef1/image")
+            .header(header::ACCEPT, "application/octet-stream")
             .body(Body::empty())
             .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        // 128 pixels wide: 16 bytes per row, left half black
+        assert_eq!(body.len(), 16 * 296);
+        assert_eq!(
+            &body[..16],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
 
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1/image")
+            .header(header::ACCEPT, "text/html, image/gif")
+            .body(Body::empty())
+            .unwrap();
         let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
 
-        assert_eq!(response.status(), StatusCode::OK);
+        // Reported as a page, as HTML is acceptable
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("406 Not Acceptable"));
+        assert!(body.contains("image/svg+xml"));
     }
 
     #[tokio::test]
-    async fn get_png() {
+    async fn error_negotiation() {
         let fix = get_test_fixture();
         let mut app = app(fix.config).into_service();
+        let get = |uri: &str, accept: &str| {
+            Request::get(uri)
+                .header(header::ACCEPT, accept)
+                .header("x-request-id", "error-1")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for (accept, content_type) in [
+            ("application/json", "application/json"),
+            (
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+                "text/html; charset=utf-8",
+            ),
+            ("text/csv", "text/plain; charset=utf-8"),
+        ] {
+            let request = get("/nowhere", accept);
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{accept}");
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                content_type,
+                "{accept}"
+            );
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            match content_type {
+                "application/json" => {
+                    let body: ErrorBody = serde_json::from_str(&body).unwrap();
+                    assert_eq!(body.code, "unknown_route");
+                    assert_eq!(body.path.as_deref(), Some("/nowhere"));
+                }
+                "text/html; charset=utf-8" => {
+                    assert!(body.starts_with("<!DOCTYPE html>"));
+                    assert!(body.contains("<h1>404 Not Found</h1>"));
+                    assert!(body.contains("<code>/nowhere</code>"));
+                    assert!(body.contains("<code>error-1</code>"));
+                    assert!(body.contains("href=\"/macs\""));
+                }
+                _ => {
+                    assert!(body.starts_with("404 Not Found\n"));
+                    assert!(body.contains("Path: /nowhere\n"));
+                    assert!(body.contains("Request id: error-1\n"));
+                }
+            }
+        }
+
+        // The MAC is decoded from the path and shows up in the message
+        let request = get("/macs/%3Cscript%3Ealert(1)/png", "text/html");
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!body.contains("<script>"));
+        assert!(body.contains("&lt;script&gt;alert(1)"));
+    }
+
+    #[tokio::test]
+    async fn audit_log() {
+        let mut fix = get_test_fixture();
+        fix.config.admin_key = Some("secret".to_owned());
+        let mut app = app(fix.config).into_service();
 
         let request = Request::builder()
-            .uri("/macs/0011223344556677/png")
-            .body(Body::empty())
+            .uri("/macs/123456789abcdef1/render_svg")
+            .method("POST")
+            .header("x-request-id", "render-1")
+            .body(Body::from("<circle cx=\"64\" cy=\"64\" r=\"30\" />"))
             .unwrap();
-
         let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
+        let request = Request::builder()
+            .uri("/macs/123456789abcdef1")
+            .method("DELETE")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
         assert_eq!(response.status(), StatusCode::OK);
 
         let request = Request::builder()
-            .uri("/macs/1111111111111111/png")
+            .uri("/audit?mac=123456789abcdef1")
             .body(Body::empty())
             .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 
+        let request = Request::builder()
+            .uri("/audit?mac=123456789abcdef1&limit=10")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
         let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let entries: Vec<AuditEntry> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].operation, Operation::Render);
+        assert_eq!(entries[0].mac, "123456789abcdef1");
+        assert_eq!(entries[0].request_id.as_deref(), Some("render-1"));
+        assert_eq!(entries[0].principal, None);
+        assert_eq!(entries[0].content_hash.as_ref().map(String::len), Some(64));
+        assert_eq!(entries[1].operation, Operation::Delete);
+        assert!(entries[1].request_id.is_some());
+        assert_eq!(entries[1].principal.as_deref(), Some(auth::ADMIN_PRINCIPAL));
+        assert_eq!(entries[1].content_hash, None);
     }
 
     #[tokio::test]
-    async fn render_svg() {
-        let fix = get_test_fixture();
-        let app = app(fix.config).into_service();
+    async fn basic_auth() {
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 
-        let png_path = fix.temp_dir.path("123456789abcdef1.png");
-        let svg_path = fix.temp_dir.path("123456789abcdef1.svg");
-        assert!(!png_path.exists());
-        assert!(!svg_path.exists());
+        let mut fix = get_test_fixture();
+        let htpasswd = fix.temp_dir.path("htpasswd");
+        let bob = bcrypt::hash("hunter2", 4).unwrap();
+        std::fs::write(
+            &htpasswd,
+            format!("alice:$apr1$xyz$HXgo9gtz4gpj4JWTLYmjB0\nbob:{bob}\n"),
+        )
+        .unwrap();
+        fix.config.htpasswd = Some(htpasswd.clone());
+        let mut app = app(fix.config).into_service();
 
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/macs/123456789abcdef1/render_svg")
-                    .method("POST")
-                    .body(Body::from("<circle cx=\"125\" cy=\"125\" r=\"75\" />"))
-                    .unwrap(),
-            )
-            .await
+        let basic = |credentials: &str| format!("Basic {}", BASE64.encode(credentials));
+        for (authorization, status) in [
+            (basic("alice:secret"), StatusCode::OK),
+            (basic("bob:hunter2"), StatusCode::OK),
+            (basic("alice:hunter2"), StatusCode::UNAUTHORIZED),
+            (basic("carol:secret"), StatusCode::UNAUTHORIZED),
+            (basic("alice"), StatusCode::UNAUTHORIZED),
+            ("Basic not-base64!".to_owned(), StatusCode::UNAUTHORIZED),
+            ("Bearer secret".to_owned(), StatusCode::UNAUTHORIZED),
+        ] {
+            let request = Request::builder()
+                .uri("/audit")
+                .header(header::AUTHORIZATION, &authorization)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), status, "{authorization}");
+            if status == StatusCode::UNAUTHORIZED {
+                assert_eq!(
+                    response.headers()[header::WWW_AUTHENTICATE],
+                    "Basic realm=\"eps-server\""
+                );
+            }
+        }
+
+        let request = Request::builder()
+            .uri("/macs/0011223344556677")
+            .method("DELETE")
+            .header(header::AUTHORIZATION, basic("alice:secret"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .uri("/audit")
+            .header(header::AUTHORIZATION, basic("bob:hunter2"))
+            .body(Body::empty())
             .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let entries: Vec<AuditEntry> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entries.last().unwrap().principal.as_deref(), Some("alice"));
 
+        // New users are picked up on reload
+        let mut users = std::fs::read_to_string(&htpasswd).unwrap();
+        users.push_str("carol:$apr1$xyz$HXgo9gtz4gpj4JWTLYmjB0\n");
+        std::fs::write(&htpasswd, users).unwrap();
+        let request = Request::builder()
+            .uri("/admin/reload")
+            .method("POST")
+            .header(header::AUTHORIZATION, basic("alice:secret"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
         assert_eq!(response.status(), StatusCode::OK);
-        assert!(png_path.exists());
-        assert!(svg_path.exists());
+
+        let request = Request::builder()
+            .uri("/audit")
+            .header(header::AUTHORIZATION, basic("carol:secret"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.ready().await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn replication() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // The standby, unavailable while `down` is set
+        let mut standby = get_test_fixture();
+        standby.config.admin_key = Some("standby".to_owned());
+        // Pointing back at the primary, which must not happen for its
+        // replicated changes
+        standby.config.replica_url = Some("http://127.0.0.1:9/".parse().unwrap());
+        let standby_handler = Arc::new(ImageHandler::new(standby.config.clone()));
+        let down = Arc::new(AtomicBool::new(false));
+        let gate = down.clone();
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            router(standby_handler.clone(), &[], detached_log_level())
+                .layer(middleware::from_fn(
+                    move |request: Request<Body>, next: Next<Body>| {
+                        let down = gate.load(Ordering::SeqCst);
+                        async move {
+                            if down {
+                                return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                            }
+                            next.run(request).await
+                        }
+                    },
+                ))
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        );
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let mut primary = get_test_fixture();
+        primary.config.replica_url = Some(format!("http://{addr}").parse().unwrap());
+        primary.config.replica_key = Some("standby".to_owned());
+        let image_handler = Arc::new(ImageHandler::new(primary.config.clone()));
+        tokio::spawn(replication::run(image_handler.clone()));
+        let mut app = router(image_handler, &[], detached_log_level()).into_service();
+
+        async fn call<S>(app: &mut S, request: Request<Body>) -> Bytes
+        where
+            S: Service<Request<Body>, Response = Response, Error = Infallible>,
+        {
+            let response = app.ready().await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        }
+        let render = |mac: &str| {
+            Request::post(format!("/macs/{mac}/render_svg?rotate=180"))
+                .body(Body::from(r#"<rect width="64" height="32" />"#))
+                .unwrap()
+        };
+        let status_request = || {
+            Request::get("/replication/status")
+                .body(Body::empty())
+                .unwrap()
+        };
+        /// Waits until `done` holds, checking every 50 ms.
+        async fn eventually(mut done: impl FnMut() -> bool) {
+            for _ in 0..400 {
+                if done() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            panic!("Timed out");
+        }
+        let png =
+            |fix: &Fixture, mac: &str| std::fs::read(fix.temp_dir.path(&format!("{mac}.png")));
+
+        call(&mut app, render("1111111111111111")).await;
+        eventually(|| png(&standby, "1111111111111111").is_ok()).await;
+        assert_eq!(
+            png(&standby, "1111111111111111").unwrap(),
+            png(&primary, "1111111111111111").unwrap()
+        );
+        let status = call(&mut app, status_request()).await;
+        let status: ReplicationStatus = serde_json::from_slice(&status).unwrap();
+        assert_eq!(status.queue_depth, 0);
+        assert!(status.last_success.is_some());
+
+        // Changes during the downtime are kept in order until it ends
+        down.store(true, Ordering::SeqCst);
+        call(&mut app, render("2222222222222222")).await;
+        call(
+            &mut app,
+            Request::delete("/macs/1111111111111111")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        let journal = primary.temp_dir.path(replication::REPLICATION_FILE);
+        eventually(|| {
+            std::fs::read_to_string(&journal)
+                .is_ok_and(|journal| journal.contains("1111111111111111"))
+        })
+        .await;
+        let status = call(&mut app, status_request()).await;
+        let status: ReplicationStatus = serde_json::from_slice(&status).unwrap();
+        assert_eq!(status.queue_depth, 2);
+        assert!(status.last_error.unwrap().contains("503"));
+        assert!(png(&standby, "2222222222222222").is_err());
+
+        down.store(false, Ordering::SeqCst);
+        eventually(|| {
+            png(&standby, "2222222222222222").is_ok() && png(&standby, "1111111111111111").is_err()
+        })
+        .await;
+        // Rendered with the same options
+        assert_eq!(
+            png(&standby, "2222222222222222").unwrap(),
+            png(&primary, "2222222222222222").unwrap()
+        );
+        // Not forwarded back
+        let standby_status = standby_handler.replicator().unwrap().status(Utc::now());
+        assert_eq!(standby_status.queue_depth, 0);
+
+        // Only the peer with the key may keep its changes from being
+        // forwarded
+        let mut standby_app = router(standby_handler.clone(), &[], detached_log_level());
+        let request = Request::post("/macs/3333333333333333/render_svg")
+            .header(replication::REPLICATED, "1")
+            .body(Body::from(r#"<rect width="64" height="32" />"#))
+            .unwrap();
+        call(&mut standby_app, request).await;
+        let standby_status = standby_handler.replicator().unwrap().status(Utc::now());
+        assert_eq!(standby_status.queue_depth, 1);
     }
 }
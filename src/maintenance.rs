@@ -0,0 +1,95 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+/// Marker file in the image directory that keeps maintenance mode across
+/// restarts.
+pub(crate) const MAINTENANCE_FILE: &str = "maintenance.json";
+
+/// Body of `POST /admin/maintenance`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Maintenance {
+    pub until: DateTime<Utc>,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl Maintenance {
+    /// Seconds until the maintenance ends, at least one.
+    pub fn retry_after(&self, now: DateTime<Utc>) -> u64 {
+        (self.until - now).num_seconds().max(1) as u64
+    }
+}
+
+/// Whether the server is in maintenance, during which devices are told to
+/// come back later instead of fetching images that may be half moved.
+#[derive(Debug)]
+pub(crate) struct MaintenanceMode {
+    path: PathBuf,
+    current: RwLock<Option<Maintenance>>,
+}
+
+impl MaintenanceMode {
+    /// Resumes a maintenance persisted at `path` before a restart. A marker
+    /// that can't be read is logged and ignored.
+    pub fn load(path: PathBuf) -> Self {
+        let current = match read(&path) {
+            Ok(current) => current,
+            Err(e) => {
+                tracing::error!("Ignoring maintenance marker {}: {e}", path.display());
+                None
+            }
+        };
+        if let Some(maintenance) = &current {
+            tracing::warn!("In maintenance until {}", maintenance.until);
+        }
+        MaintenanceMode {
+            path,
+            current: RwLock::new(current),
+        }
+    }
+
+    /// The maintenance in progress at `now`.
+    pub fn active(&self, now: DateTime<Utc>) -> Option<Maintenance> {
+        self.current
+            .read()
+            .unwrap()
+            .clone()
+            .filter(|maintenance| maintenance.until > now)
+    }
+
+    pub async fn start(&self, maintenance: Maintenance) -> io::Result<()> {
+        let json = serde_json::to_vec(&maintenance)?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, json).await?;
+        fs::rename(&temp, &self.path).await?;
+        tracing::warn!("In maintenance until {}", maintenance.until);
+        *self.current.write().unwrap() = Some(maintenance);
+        Ok(())
+    }
+
+    pub async fn end(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        if self.current.write().unwrap().take().is_some() {
+            tracing::info!("Maintenance ended");
+        }
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> io::Result<Option<Maintenance>> {
+    match std::fs::read(path) {
+        Ok(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
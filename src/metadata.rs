@@ -0,0 +1,184 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boot_report::{BootFields, BootReport},
+    capabilities::Capabilities,
+    color_map::ColorMapReport,
+    coverage::Coverage,
+    display_profile::DisplayProfile,
+    groups::GroupName,
+    lint::{LintOverrides, LintWarning},
+    playlist::Playlist,
+    poll_interval::PollCadence,
+    post_render::HookReport,
+    region::Regions,
+    render_options::{RenderOptions, RenderOverrides},
+    response_headers::ResponseHeaders,
+    schedule::Schedule,
+    storage::Storage,
+};
+
+/// Number of renders kept in the render log of each MAC.
+pub(crate) const RENDER_LOG_LEN: usize = 50;
+
+/// Per-MAC state that is not part of the images themselves, persisted next to
+/// them as `<mac>.meta.json`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MacMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rerender: Option<Rerender>,
+    /// The most recent successful renders, oldest first.
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    pub render_log: VecDeque<RenderRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist: Option<PlaylistState>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub groups: BTreeSet<GroupName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub render_failures: Option<RenderFailures>,
+    /// When the device last reported a full refresh of its panel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_refresh: Option<DateTime<Utc>>,
+    /// What the device reported it can decode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+    /// The latest boot report of the device, without its bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_report: Option<BootReport>,
+    #[serde(default, skip_serializing_if = "ResponseHeaders::is_empty")]
+    pub response_headers: ResponseHeaders,
+    #[serde(default, skip_serializing_if = "DisplayProfile::is_identity")]
+    pub display_profile: DisplayProfile,
+    #[serde(default, skip_serializing_if = "RenderOverrides::is_empty")]
+    pub render_options: RenderOverrides,
+    #[serde(default, skip_serializing_if = "Regions::is_empty")]
+    pub regions: Regions,
+    #[serde(default, skip_serializing_if = "LintOverrides::is_empty")]
+    pub lint: LintOverrides,
+    #[serde(default, skip_serializing_if = "PollCadence::is_empty")]
+    pub poll: PollCadence,
+    /// When the staged images become live, see `?activate_at`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activate_at: Option<DateTime<Utc>>,
+}
+
+/// What a device last reported about itself, in `GET /macs/:mac/checkin`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Checkin {
+    /// The decoded fields of its latest boot report.
+    #[serde(flatten)]
+    pub boot: BootFields,
+    /// When its latest boot report arrived.
+    pub boot_report: Option<DateTime<Utc>>,
+    pub full_refresh: Option<DateTime<Utc>>,
+    pub capabilities: Option<Capabilities>,
+    /// Seconds to wait before the next poll, see `X-EPS-Suggested-Poll-Seconds`.
+    pub suggested_poll_secs: Option<u32>,
+}
+
+/// Renders of the same posted source that failed in a row.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RenderFailures {
+    /// SHA-256 of the posted fragment.
+    pub source_hash: String,
+    pub count: u32,
+}
+
+/// A time-dependent render that the scheduler repeats.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Rerender {
+    pub schedule: Schedule,
+    /// The posted fragment with its placeholders intact.
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<Tz>,
+    /// Overrides of the posting request, applied again to every render.
+    #[serde(flatten)]
+    pub overrides: RenderOverrides,
+    /// Whether the tokens of [`crate::substitute`] are replaced.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub substitute: bool,
+    pub last_render: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PlaylistState {
+    #[serde(flatten)]
+    pub playlist: Playlist,
+    /// The entry rendered last, used to detect when another one becomes
+    /// active.
+    pub rendered: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RenderRecord {
+    pub timestamp: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub svg_bytes: usize,
+    pub png_bytes: usize,
+    /// Whether the rendered PNG differs from the one it replaced.
+    pub changed: bool,
+    /// Pixels that differ from the PNG it replaced, if there was one of the
+    /// same size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed_pixels: Option<u64>,
+    /// How much of the panel the PNG draws on, if it could be decoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<Coverage>,
+    /// How the colors of the SVG were mapped, if they were.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_map: Option<ColorMapReport>,
+    /// What the render's content will likely lose on the panel.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lint: Vec<LintWarning>,
+    /// The options the render used, absent for promoted renders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<RenderOptions>,
+    /// How `--post-render-cmd` ended, if it is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_render: Option<HookReport>,
+    /// Fingerprint of the render pipeline with `--deterministic-render`;
+    /// renders with the same one are comparable pixel by pixel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<String>,
+}
+
+impl MacMetadata {
+    /// Appends `record` to the render log, learning the cadence of the MAC
+    /// from it if it changed the image.
+    pub fn push_render(&mut self, record: RenderRecord) {
+        if record.changed {
+            self.poll.observe(record.timestamp);
+        }
+        self.restore_render(record);
+    }
+
+    /// Appends `record` of an earlier render that is live again, which says
+    /// nothing about the cadence of the MAC.
+    pub fn restore_render(&mut self, record: RenderRecord) {
+        if self.render_log.len() == RENDER_LOG_LEN {
+            self.render_log.pop_front();
+        }
+        self.render_log.push_back(record);
+    }
+
+    /// Reads the metadata file `name`, treating a missing file as empty
+    /// metadata.
+    pub async fn load(storage: &Storage, name: &str) -> eyre::Result<Self> {
+        match storage.read_optional(name).await? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub async fn store(&self, storage: &Storage, name: &str) -> eyre::Result<()> {
+        storage
+            .write_atomic(name, &serde_json::to_vec_pretty(self)?)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::convert::Infallible;
+
+use axum::{
+    body::{Bytes, StreamBody},
+    response::{IntoResponse, Response},
+};
+use futures_util::stream;
+use hyper::header;
+use mime::Mime;
+use sha2::{Digest, Sha256};
+
+use crate::accept;
+
+/// The multipart subtype listed in an `Accept` header, if any. Wildcards
+/// don't count, so only clients asking for multipart get it.
+pub(crate) fn requested(accept: Option<&str>) -> Option<&'static str> {
+    let ranges = accept::parse_accept(accept?);
+    let accepts = |subtype: &str| {
+        ranges.iter().any(|range| {
+            range.q > 0.0
+                && range.mime.type_() == mime::MULTIPART
+                && range.mime.subtype() == subtype
+        })
+    };
+    ["mixed", "related"]
+        .into_iter()
+        .find(|subtype| accepts(subtype))
+}
+
+/// A `multipart/<subtype>` response of `parts`. The parts are sent as they
+/// are instead of being copied into one buffer.
+pub(crate) fn response(subtype: &str, parts: Vec<(Mime, Bytes)>) -> Response {
+    // Derived from the content so that it can't occur in it
+    let mut hasher = Sha256::new();
+    for (_, data) in &parts {
+        hasher.update(data);
+    }
+    let boundary = format!("eps-{}", hex::encode(&hasher.finalize()[..16]));
+
+    let mut chunks = Vec::with_capacity(parts.len() * 2 + 1);
+    for (mime, data) in parts {
+        chunks.push(Bytes::from(format!(
+            "--{boundary}\r\ncontent-type: {mime}\r\ncontent-length: {}\r\n\r\n",
+            data.len()
+        )));
+        chunks.push(data);
+        chunks.push(Bytes::from_static(b"\r\n"));
+    }
+    chunks.push(Bytes::from(format!("--{boundary}--\r\n")));
+
+    let content_type = format!("multipart/{subtype}; boundary={boundary}");
+    let body = StreamBody::new(stream::iter(chunks.into_iter().map(Ok::<_, Infallible>)));
+    ([(header::CONTENT_TYPE, content_type)], body).into_response()
+}
+
+/// Splits a multipart body into the content types and data of its parts.
+#[cfg(test)]
+pub(crate) fn parse(content_type: &str, body: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mime: Mime = content_type.parse().unwrap();
+    let delimiter = format!("--{}", mime.get_param("boundary").unwrap());
+    let delimiter = delimiter.as_bytes();
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    };
+
+    let mut parts = Vec::new();
+    let mut rest = &body[find(body, delimiter).unwrap() + delimiter.len()..];
+    while !rest.starts_with(b"--") {
+        let rest_part = rest.strip_prefix(b"\r\n").unwrap();
+        let header_end = find(rest_part, b"\r\n\r\n").unwrap();
+        let headers = std::str::from_utf8(&rest_part[..header_end]).unwrap();
+        let content_type = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-type: "))
+            .unwrap()
+            .to_owned();
+        let data = &rest_part[header_end + 4..];
+        let end = find(data, delimiter).unwrap();
+        parts.push((content_type, data[..end - 2].to_vec()));
+        rest = &data[end + delimiter.len()..];
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requested_subtype() {
+        assert_eq!(requested(None), None);
+        assert_eq!(requested(Some("*/*")), None);
+        assert_eq!(requested(Some("multipart/*")), None);
+        assert_eq!(
+            requested(Some("application/json, multipart/mixed")),
+            Some("mixed")
+        );
+        assert_eq!(requested(Some("multipart/related")), Some("related"));
+        assert_eq!(requested(Some("multipart/mixed;q=0")), None);
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let parts = vec![
+            (mime::APPLICATION_JSON, Bytes::from_static(b"{}")),
+            (mime::IMAGE_PNG, Bytes::from_static(b"\r\n--png\r\n")),
+        ];
+        let response = response("mixed", parts);
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert!(content_type.starts_with("multipart/mixed; boundary=eps-"));
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            parse(&content_type, &body),
+            [
+                ("application/json".to_owned(), b"{}".to_vec()),
+                ("image/png".to_owned(), b"\r\n--png\r\n".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{artifact::Artifact, image_handler::EpdMac};
+
+/// Entries kept before expired ones are pruned, bounding memory if many
+/// different MACs are requested.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Remembers image files found missing, so repeated requests for them don't
+/// touch the disk. Files are identified by MAC and artifact.
+pub(crate) struct NegativeCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<(EpdMac, Artifact), DateTime<Utc>>>,
+    hits: AtomicU64,
+}
+
+impl NegativeCache {
+    /// A zero `ttl` disables the cache.
+    pub fn new(ttl: Duration) -> Self {
+        NegativeCache {
+            ttl,
+            entries: Mutex::default(),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the file was found missing less than the TTL before `now`.
+    pub fn contains(&self, mac: EpdMac, artifact: Artifact, now: DateTime<Utc>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&(mac, artifact)) {
+            Some(&expires) if expires > now => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Some(_) => {
+                entries.remove(&(mac, artifact));
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, mac: EpdMac, artifact: Artifact, now: DateTime<Utc>) {
+        if self.ttl <= Duration::zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, expires| *expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert((mac, artifact), now + self.ttl);
+    }
+
+    /// Forgets all files of `mac`, e.g. because it was just rendered.
+    pub fn forget(&self, mac: EpdMac) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(entry_mac, _), _| *entry_mac != mac);
+    }
+
+    /// Number of lookups answered from the cache since startup.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: EpdMac = EpdMac([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let cache = NegativeCache::new(Duration::seconds(5));
+        let now = utc("2024-03-12T10:00:00Z");
+        assert!(!cache.contains(MAC, Artifact::Png, now));
+
+        cache.insert(MAC, Artifact::Png, now);
+        assert!(cache.contains(MAC, Artifact::Png, now + Duration::seconds(4)));
+        assert!(!cache.contains(MAC, Artifact::Svg, now));
+        assert!(!cache.contains(MAC, Artifact::Png, now + Duration::seconds(5)));
+        assert!(!cache.contains(MAC, Artifact::Png, now));
+        assert_eq!(cache.hits(), 1);
+    }
+
+    #[test]
+    fn forget_mac() {
+        let cache = NegativeCache::new(Duration::seconds(5));
+        let now = utc("2024-03-12T10:00:00Z");
+        let other = EpdMac([0xAA; 8]);
+        cache.insert(MAC, Artifact::Png, now);
+        cache.insert(MAC, Artifact::Svg, now);
+        cache.insert(other, Artifact::Png, now);
+
+        cache.forget(MAC);
+        assert!(!cache.contains(MAC, Artifact::Png, now));
+        assert!(!cache.contains(MAC, Artifact::Svg, now));
+        assert!(cache.contains(other, Artifact::Png, now));
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = NegativeCache::new(Duration::zero());
+        let now = utc("2024-03-12T10:00:00Z");
+        cache.insert(MAC, Artifact::Png, now);
+        assert!(!cache.contains(MAC, Artifact::Png, now));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{ffi::OsString, io, sync::Arc};
+
+use axum::{async_trait, body::Bytes};
+use futures_util::{StreamExt, TryStreamExt};
+use object_store::{
+    aws::AmazonS3Builder, path::Path, ObjectMeta, ObjectStore, ObjectStoreScheme, PutPayload,
+};
+use url::Url;
+
+use crate::{
+    outbound::Outbound,
+    storage::{ByteStream, FileMeta, ImageStore},
+    throttle,
+};
+
+/// Files kept as objects below a prefix of an object store, like an S3
+/// bucket, so that several servers can share them.
+#[derive(Debug)]
+pub(crate) struct ObjectStorage {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+}
+
+impl ObjectStorage {
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: Path) -> Self {
+        ObjectStorage { store, prefix }
+    }
+
+    /// The store at a URL like `s3://bucket/prefix` or `memory:///`.
+    /// Credentials and the region are taken from the `AWS_*` environment
+    /// variables.
+    pub fn from_url(url: &Url, outbound: &Outbound) -> eyre::Result<Self> {
+        let options = std::env::vars()
+            .filter(|(key, _)| key.starts_with("AWS_"))
+            .map(|(key, value)| (key.to_ascii_lowercase(), value));
+        Self::with_options(url, options, outbound)
+    }
+
+    /// Like [`Self::from_url`], with the `options` of `object_store`, like
+    /// `aws_endpoint`, instead of the environment. Unknown options are
+    /// ignored.
+    pub fn with_options(
+        url: &Url,
+        options: impl IntoIterator<Item = (String, String)>,
+        outbound: &Outbound,
+    ) -> eyre::Result<Self> {
+        let (scheme, prefix) = ObjectStoreScheme::parse(url)?;
+        if scheme != ObjectStoreScheme::AmazonS3 {
+            // Not connecting anywhere
+            let (store, prefix) = object_store::parse_url_opts(url, options)?;
+            return Ok(Self::new(store.into(), prefix));
+        }
+        let builder = AmazonS3Builder::new()
+            .with_url(url.as_str())
+            .with_client_options(outbound.client_options());
+        let builder =
+            options
+                .into_iter()
+                .fold(builder, |builder, (key, value)| match key.parse() {
+                    Ok(key) => builder.with_config(key, value),
+                    Err(_) => builder,
+                });
+        Ok(Self::new(Arc::new(builder.build()?), Path::parse(prefix)?))
+    }
+
+    fn path(&self, name: &str) -> Path {
+        self.prefix.child(name)
+    }
+}
+
+/// Keeps the kind of missing objects so callers can tell them apart.
+fn io_error(e: object_store::Error) -> io::Error {
+    let kind = match e {
+        object_store::Error::NotFound { .. } => io::ErrorKind::NotFound,
+        object_store::Error::PermissionDenied { .. }
+        | object_store::Error::Unauthenticated { .. } => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, e)
+}
+
+fn file_meta(meta: &ObjectMeta) -> FileMeta {
+    FileMeta {
+        len: meta.size as u64,
+        modified: meta.last_modified.into(),
+        tag: meta.e_tag.clone(),
+    }
+}
+
+#[async_trait]
+impl ImageStore for ObjectStorage {
+    async fn get(&self, name: &str, chunk_size: usize) -> io::Result<(ByteStream, FileMeta)> {
+        let result = self.store.get(&self.path(name)).await.map_err(io_error)?;
+        let meta = file_meta(&result.meta);
+        let stream = result
+            .into_stream()
+            .map_err(io_error)
+            .map_ok(move |bytes| throttle::chunked(bytes, chunk_size))
+            .try_flatten()
+            .boxed();
+        Ok((stream, meta))
+    }
+
+    /// Object stores replace objects as a whole, so a plain overwrite is
+    /// atomic.
+    async fn put(&self, name: &str, data: Bytes) -> io::Result<()> {
+        self.store
+            .put(&self.path(name), PutPayload::from(data))
+            .await
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        let path = self.path(name);
+        // Deleting a missing object succeeds in most stores
+        self.store.head(&path).await.map_err(io_error)?;
+        self.store.delete(&path).await.map_err(io_error)
+    }
+
+    async fn list(&self) -> io::Result<Vec<io::Result<OsString>>> {
+        let prefix = (!self.prefix.as_ref().is_empty()).then_some(&self.prefix);
+        let listing = self
+            .store
+            .list_with_delimiter(prefix)
+            .await
+            .map_err(io_error)?;
+        Ok(listing
+            .objects
+            .iter()
+            .filter_map(|meta| meta.location.filename())
+            .map(|name| Ok(name.into()))
+            .collect())
+    }
+
+    async fn stat(&self, name: &str) -> io::Result<FileMeta> {
+        let meta = self.store.head(&self.path(name)).await.map_err(io_error)?;
+        Ok(file_meta(&meta))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+
+    use super::*;
+    use crate::storage::{self, Storage};
+
+    #[tokio::test]
+    async fn in_memory_store() {
+        let memory = Arc::new(InMemory::new());
+        for (name, len) in [("images/a.png", 10), ("images/b.svg", 20), ("other.png", 1)] {
+            memory
+                .put(&Path::from(name), PutPayload::from(vec![0; len]))
+                .await
+                .unwrap();
+        }
+        let store = ObjectStorage::new(memory, Path::from("images"));
+        let storage = Storage::with_store(Arc::new(store), std::env::temp_dir());
+        storage::check_store(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn from_url() {
+        let outbound = Outbound::default();
+        let store = ObjectStorage::from_url(&"memory:///".parse().unwrap(), &outbound).unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        assert!("ftp://example.com/images"
+            .parse()
+            .map(|url| ObjectStorage::from_url(&url, &outbound))
+            .unwrap()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn through_proxy() {
+        let requested = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let proxy = axum::Router::new().fallback({
+            let requested = requested.clone();
+            move |request: hyper::Request<hyper::Body>| async move {
+                requested.lock().unwrap().push(request.uri().to_string());
+                "via proxy"
+            }
+        });
+        let server =
+            axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(proxy.into_make_service());
+        let outbound = Outbound {
+            https_proxy: Some(format!("http://{}", server.local_addr()).parse().unwrap()),
+            ..Outbound::default()
+        };
+        tokio::spawn(server);
+
+        let options = [
+            ("aws_endpoint", "http://s3.example.invalid"),
+            ("aws_allow_http", "true"),
+            ("aws_skip_signature", "true"),
+            ("aws_region", "eu-central-1"),
+            ("aws_unknown", "ignored"),
+        ]
+        .map(|(key, value)| (key.to_owned(), value.to_owned()));
+        let url = "s3://bucket/images".parse().unwrap();
+        let store = ObjectStorage::with_options(&url, options, &outbound).unwrap();
+        assert_eq!(store.read("a.png").await.unwrap(), b"via proxy");
+        assert_eq!(
+            *requested.lock().unwrap(),
+            ["http://s3.example.invalid/bucket/images/a.png"]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use eyre::ensure;
+use object_store::{Certificate, ClientOptions};
+use url::Url;
+
+use crate::config::Config;
+
+/// Proxy and TLS trust of the HTTP clients the server creates for outbound
+/// connections, so far only the one of an object store given by `--storage`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Outbound {
+    pub https_proxy: Option<Url>,
+    /// Hosts and domains connected to directly, like in `NO_PROXY`.
+    pub no_proxy: Vec<String>,
+    /// Trusted in addition to the default roots.
+    pub extra_ca_certs: Vec<Certificate>,
+}
+
+impl Outbound {
+    /// The settings of `config`. Invalid ones are logged and left out, so
+    /// that connections fail with errors naming the proxy or certificate
+    /// instead of the server not starting.
+    pub fn from_config(config: &Config) -> Self {
+        let https_proxy = config.https_proxy.clone().filter(|proxy| {
+            let supported = matches!(proxy.scheme(), "http" | "https");
+            if !supported {
+                tracing::warn!("Ignoring --https-proxy {proxy}, only http and https are supported");
+            }
+            supported
+        });
+        let extra_ca_certs = match &config.extra_ca_cert {
+            Some(path) => read_certificates(path).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring --extra-ca-cert {}: {e:#}", path.display());
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        Outbound {
+            https_proxy,
+            no_proxy: config.no_proxy.clone(),
+            extra_ca_certs,
+        }
+    }
+
+    pub fn client_options(&self) -> ClientOptions {
+        let mut options = ClientOptions::new();
+        if let Some(proxy) = &self.https_proxy {
+            options = options.with_proxy_url(proxy.as_str());
+        }
+        if !self.no_proxy.is_empty() {
+            options = options.with_proxy_excludes(self.no_proxy.join(","));
+        }
+        for certificate in &self.extra_ca_certs {
+            options = options.with_root_certificate(certificate.clone());
+        }
+        options
+    }
+}
+
+fn read_certificates(path: &Path) -> eyre::Result<Vec<Certificate>> {
+    let pem = std::fs::read(path)?;
+    let certificates = Certificate::from_pem_bundle(&pem)?;
+    ensure!(!certificates.is_empty(), "The file has no PEM certificates");
+    Ok(certificates)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use object_store::ClientConfigKey;
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+
+    const CA_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBejCCASGgAwIBAgIUUK2ksptHHNOix4ocD0J250nEr+kwCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTYxMjAwMTVaGA8yMTI2MDkyMjEy
+MDAxNVowEjEQMA4GA1UEAwwHVGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABMTCrQ/KU3pUr24FOdQL1MLuyOalDDAZCbbJ/AA3kOlkf4lMzFoZ6/kTsLCb
+eJ55UOe+GVMlGvfkgzr3Ovjr+iyjUzBRMB0GA1UdDgQWBBQS5m/5lJrZuRbHj9XF
+/7vYZYLtSzAfBgNVHSMEGDAWgBQS5m/5lJrZuRbHj9XF/7vYZYLtSzAPBgNVHRMB
+Af8EBTADAQH/MAoGCCqGSM49BAMCA0cAMEQCIC03zWD6N5GaiTQP+mt0ttDpjRze
+gj5cLm8bBzcJQHPxAiAk8B5nj0s3by0GFslh5tfZ5INtyK1Qc6hZZ11e/y0Y7A==
+-----END CERTIFICATE-----
+";
+
+    fn config(args: &[&str]) -> Config {
+        let required = ["eps_server", "-i", "images", "-H", "296", "-W", "128"];
+        Config::try_parse_from(required.iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn client_options() {
+        let temp_dir = TestDir::temp();
+        std::fs::write(temp_dir.path("ca.pem"), [CA_CERT, CA_CERT].concat()).unwrap();
+        std::fs::write(temp_dir.path("empty.pem"), "").unwrap();
+        let ca = temp_dir.path("ca.pem");
+        let outbound = Outbound::from_config(&config(&[
+            "--https-proxy",
+            "http://proxy.internal:3128",
+            "--no-proxy",
+            "localhost,.internal",
+            "--extra-ca-cert",
+            ca.to_str().unwrap(),
+        ]));
+        assert_eq!(outbound.extra_ca_certs.len(), 2);
+        let options = outbound.client_options();
+        assert_eq!(
+            options
+                .get_config_value(&ClientConfigKey::ProxyUrl)
+                .as_deref(),
+            Some("http://proxy.internal:3128/")
+        );
+        assert_eq!(
+            options
+                .get_config_value(&ClientConfigKey::ProxyExcludes)
+                .as_deref(),
+            Some("localhost,.internal")
+        );
+
+        // Left out with a warning
+        let empty = temp_dir.path("empty.pem");
+        let outbound = Outbound::from_config(&config(&[
+            "--https-proxy",
+            "socks5://proxy.internal:1080",
+            "--extra-ca-cert",
+            empty.to_str().unwrap(),
+        ]));
+        assert!(outbound.https_proxy.is_none());
+        assert!(outbound.extra_ca_certs.is_empty());
+        let missing = temp_dir.path("missing.pem");
+        let outbound =
+            Outbound::from_config(&config(&["--extra-ca-cert", missing.to_str().unwrap()]));
+        assert!(outbound.extra_ca_certs.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use std::{fmt::Display, str::FromStr};
+
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use eyre::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+/// A cron expression in the usual five fields (minute, hour, day of month,
+/// month, day of week) or with leading seconds. Days of the week are best
+/// given by name since numbers start with 1 for Sunday.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct CronExpr {
+    source: String,
+    schedule: cron::Schedule,
+}
+
+impl CronExpr {
+    /// The most recent firing at or before `now`.
+    fn last_at(&self, now: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let after = (now + Duration::seconds(1)).with_timezone(&tz);
+        let last = self.schedule.after(&after).next_back()?;
+        Some(last.with_timezone(&Utc))
+    }
+
+    /// The first firing strictly after `now`.
+    fn next_after(&self, now: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let next = self.schedule.after(&now.with_timezone(&tz)).next()?;
+        Some(next.with_timezone(&Utc))
+    }
+}
+
+impl FromStr for CronExpr {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().count();
+        let expr = match fields {
+            5 => format!("0 {s}"),
+            6 | 7 => s.to_owned(),
+            _ => bail!("Cron expression must have 5 to 7 fields, got '{s}'"),
+        };
+        let schedule = expr
+            .parse()
+            .wrap_err_with(|| format!("Invalid cron expression '{s}'"))?;
+        Ok(CronExpr {
+            source: s.to_owned(),
+            schedule,
+        })
+    }
+}
+
+impl Display for CronExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl TryFrom<String> for CronExpr {
+    type Error = eyre::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CronExpr> for String {
+    fn from(expr: CronExpr) -> Self {
+        expr.source
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PlaylistEntry {
+    /// SVG fragment, rendered like the body of `render_svg`.
+    pub svg: String,
+    /// The entry becomes active whenever the expression fires.
+    pub cron: CronExpr,
+}
+
+/// Images of a MAC that take turns on a schedule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Playlist {
+    pub entries: Vec<PlaylistEntry>,
+    /// Overrides the configured time zone for the cron expressions and
+    /// time placeholders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<Tz>,
+}
+
+impl Playlist {
+    /// Index of the entry whose expression fired most recently. If several
+    /// fired at the same time, the last listed one wins.
+    pub fn active_at(&self, now: DateTime<Utc>, tz: Tz) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| Some((entry.cron.last_at(now, tz)?, i)))
+            .max()
+            .map(|(_, i)| i)
+    }
+
+    /// The first point in time after `now` at which another entry becomes
+    /// active.
+    pub fn next_transition(&self, now: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let active = self.active_at(now, tz);
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.cron.next_after(now, tz))
+            .filter(|&at| self.active_at(at, tz) != active)
+            .min()
+    }
+}
+
+/// A playlist with the state it has at the time of the request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PlaylistStatus {
+    #[serde(flatten)]
+    pub playlist: Playlist,
+    /// Index of the entry that is shown, `None` if no expression fired yet.
+    pub active: Option<usize>,
+    pub next_transition: Option<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::Europe::Berlin;
+
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn playlist(crons: &[&str]) -> Playlist {
+        Playlist {
+            entries: crons
+                .iter()
+                .map(|cron| PlaylistEntry {
+                    svg: String::new(),
+                    cron: cron.parse().unwrap(),
+                })
+                .collect(),
+            timezone: None,
+        }
+    }
+
+    #[test]
+    fn cron_expr_parse() {
+        let expr: CronExpr = "0 8 * * MON-FRI".parse().unwrap();
+        assert_eq!(expr.to_string(), "0 8 * * MON-FRI");
+        assert!("30 0 8 * * Mon-Fri".parse::<CronExpr>().is_ok());
+        for s in ["", "* * * *", "0 25 * * *", "0 8 * * XYZ"] {
+            assert!(s.parse::<CronExpr>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn active_entry() {
+        let playlist = playlist(&["0 18 * * *", "0 8 * * MON-FRI"]);
+        // Tuesday
+        assert_eq!(
+            playlist.active_at(utc("2024-03-12T07:59:00Z"), Tz::UTC),
+            Some(0)
+        );
+        assert_eq!(
+            playlist.active_at(utc("2024-03-12T08:00:00Z"), Tz::UTC),
+            Some(1)
+        );
+        assert_eq!(
+            playlist.active_at(utc("2024-03-12T17:59:59Z"), Tz::UTC),
+            Some(1)
+        );
+        assert_eq!(
+            playlist.active_at(utc("2024-03-12T18:00:00Z"), Tz::UTC),
+            Some(0)
+        );
+        // Saturday
+        assert_eq!(
+            playlist.active_at(utc("2024-03-16T12:00:00Z"), Tz::UTC),
+            Some(0)
+        );
+        // In local time
+        assert_eq!(
+            playlist.active_at(utc("2024-03-12T07:30:00Z"), Berlin),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn overlapping_entries() {
+        let playlist = playlist(&["0 8 * * *", "0 8 * * MON-FRI", "0 8 * * *"]);
+        assert_eq!(
+            playlist.active_at(utc("2024-03-12T09:00:00Z"), Tz::UTC),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn next_transition() {
+        let playlist = playlist(&["0 18 * * *", "0 8 * * MON-FRI"]);
+        assert_eq!(
+            playlist.next_transition(utc("2024-03-12T10:00:00Z"), Tz::UTC),
+            Some(utc("2024-03-12T18:00:00Z"))
+        );
+        // The evening entry firing again on Saturday changes nothing
+        assert_eq!(
+            playlist.next_transition(utc("2024-03-15T19:00:00Z"), Tz::UTC),
+            Some(utc("2024-03-18T08:00:00Z"))
+        );
+
+        // A single entry stays active forever
+        let playlist = self::playlist(&["0 8 * * *"]);
+        assert_eq!(
+            playlist.next_transition(utc("2024-03-12T10:00:00Z"), Tz::UTC),
+            None
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    body::Body,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use eyre::eyre;
+use hyper::{http::request::Parts, Request};
+use serde::Serialize;
+
+use crate::{
+    auth,
+    config::Config,
+    error::AppError,
+    ip_filter::{ClientIp, IpFilter},
+    AppState,
+};
+
+/// What a route does, which decides what requests to it need.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RouteClass {
+    /// Reading images and their metadata.
+    ReadImage,
+    /// Changing images and their metadata.
+    WriteImage,
+    /// Operating the server.
+    Admin,
+    /// Devices reporting about themselves.
+    DeviceSelf,
+    /// Health and statistics of the server.
+    Status,
+}
+
+impl RouteClass {
+    /// Whether the routes are served during maintenance.
+    fn in_maintenance(self) -> bool {
+        matches!(self, RouteClass::Admin | RouteClass::Status)
+    }
+}
+
+/// A check of a request, see [`Policy::check`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Requirement {
+    /// The client is in a network allowed to read, or to write for mutating
+    /// methods, see [`IpFilter::check`].
+    Network,
+    /// The admin key or a user's credentials.
+    Credentials,
+}
+
+/// The requirements of each route class, resolved from the configuration at
+/// startup.
+#[derive(Debug)]
+pub(crate) struct Policy {
+    ip_filter: Arc<IpFilter>,
+    classes: BTreeMap<RouteClass, Vec<Requirement>>,
+}
+
+impl Policy {
+    pub fn new(config: &Config, ip_filter: Arc<IpFilter>) -> Self {
+        let network = !(config.allow_write_from.is_empty()
+            && config.deny_from.is_empty()
+            && config.allow_read_from.is_empty());
+        let credentials = config.admin_key.is_some() || config.htpasswd.is_some();
+
+        let mut classes = BTreeMap::new();
+        for class in [
+            RouteClass::ReadImage,
+            RouteClass::WriteImage,
+            RouteClass::Admin,
+            RouteClass::DeviceSelf,
+            RouteClass::Status,
+        ] {
+            let mut requirements = vec![];
+            if network {
+                requirements.push(Requirement::Network);
+            }
+            if credentials && class == RouteClass::Admin {
+                requirements.push(Requirement::Credentials);
+            }
+            classes.insert(class, requirements);
+        }
+        Policy { ip_filter, classes }
+    }
+
+    /// The requirements of every route class, in the order they are checked.
+    pub fn classes(&self) -> &BTreeMap<RouteClass, Vec<Requirement>> {
+        &self.classes
+    }
+
+    /// Fails with the first requirement of `class` the request doesn't meet,
+    /// or if the routes of `class` are not served during the current
+    /// maintenance.
+    pub async fn check(
+        &self,
+        class: RouteClass,
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<(), AppError> {
+        for requirement in &self.classes[&class] {
+            match requirement {
+                Requirement::Network => {
+                    let ip = parts.extensions.get::<ClientIp>().map(|&ClientIp(ip)| ip);
+                    self.ip_filter.check(&parts.method, ip)?;
+                }
+                Requirement::Credentials => {
+                    if auth::principal(parts, state).await.is_none() {
+                        return Err(AppError::Unauthorized(eyre!(
+                            "This route requires the admin key or valid credentials."
+                        )));
+                    }
+                }
+            }
+        }
+        if !class.in_maintenance() {
+            if let Some(maintenance) = state.maintenance.active(Utc::now()) {
+                return Err(AppError::Maintenance(maintenance));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rejects requests to a route of `class` that don't meet its requirements,
+/// before their body is read.
+pub(crate) async fn enforce(
+    state: Arc<AppState>,
+    class: RouteClass,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    if let Err(e) = state.policy.check(class, &mut parts, &state).await {
+        return e.into_response();
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
@@ -0,0 +1,130 @@
+use chrono::{DateTime, Utc};
+use hyper::{header::HeaderName, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+const SUGGESTED_POLL: HeaderName = HeaderName::from_static("x-eps-suggested-poll-seconds");
+
+/// Weight of the latest interval in the moving average of a cadence.
+const CADENCE_WEIGHT: f64 = 0.3;
+
+/// How often the image of a MAC changes, learned from its changes, and how
+/// often its device is told to poll.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PollCadence {
+    /// When the image last changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_change: Option<DateTime<Utc>>,
+    /// Exponential moving average of the seconds between changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average_secs: Option<f64>,
+    /// Poll interval set with `PUT /macs/:mac/poll_interval`, suggested
+    /// regardless of the cadence and the limits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub override_secs: Option<u32>,
+}
+
+impl PollCadence {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Learns from a change of the image at `at`. Changes that aren't later
+    /// than the last one are ignored.
+    pub fn observe(&mut self, at: DateTime<Utc>) {
+        if let Some(last_change) = self.last_change {
+            if at <= last_change {
+                return;
+            }
+            let interval = (at - last_change).num_milliseconds() as f64 / 1000.0;
+            self.average_secs = Some(match self.average_secs {
+                Some(average) => average + CADENCE_WEIGHT * (interval - average),
+                None => interval,
+            });
+        }
+        self.last_change = Some(at);
+    }
+
+    /// Seconds the device should wait between polls: the override if set,
+    /// otherwise the average interval between changes clamped to `limits`.
+    /// Unknown until the image changed twice.
+    pub fn suggested_secs(&self, limits: PollLimits) -> Option<u32> {
+        if let Some(secs) = self.override_secs {
+            return Some(secs);
+        }
+        let average = self.average_secs?;
+        Some((average.round() as u64).clamp(limits.min_secs.into(), limits.max_secs.into()) as u32)
+    }
+}
+
+/// Bounds of learned poll intervals, see `--poll-min`.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct PollLimits {
+    pub min_secs: u32,
+    pub max_secs: u32,
+}
+
+/// Body of `PUT /macs/:mac/poll_interval`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PollOverride {
+    pub override_secs: u32,
+}
+
+/// Answer of `GET /macs/:mac/poll_interval`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PollInterval {
+    pub suggested_secs: Option<u32>,
+    #[serde(flatten)]
+    pub cadence: PollCadence,
+}
+
+/// The `X-EPS-Suggested-Poll-Seconds` header, none without a suggestion.
+pub(crate) fn headers(suggested_secs: Option<u32>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(secs) = suggested_secs {
+        headers.insert(SUGGESTED_POLL, secs.into());
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    const LIMITS: PollLimits = PollLimits {
+        min_secs: 60,
+        max_secs: 3600,
+    };
+
+    #[test]
+    fn converges_to_cadence() {
+        let start: DateTime<Utc> = "2024-03-12T10:00:00Z".parse().unwrap();
+        let mut cadence = PollCadence::default();
+        cadence.observe(start);
+        assert_eq!(cadence.suggested_secs(LIMITS), None);
+
+        // From every 10 minutes to every 5 minutes
+        let mut at = start;
+        for _ in 0..5 {
+            at += Duration::minutes(10);
+            cadence.observe(at);
+        }
+        assert_eq!(cadence.suggested_secs(LIMITS), Some(600));
+        for _ in 0..20 {
+            at += Duration::minutes(5);
+            cadence.observe(at);
+        }
+        let suggested = cadence.suggested_secs(LIMITS).unwrap();
+        assert!((300..=302).contains(&suggested), "{suggested}");
+
+        // Out of order changes don't count
+        cadence.observe(start);
+        assert_eq!(cadence.last_change, Some(at));
+        assert_eq!(
+            headers(Some(suggested))[SUGGESTED_POLL],
+            suggested.to_string()
+        );
+        assert!(headers(None).is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use eyre::ensure;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command, sync::Semaphore};
+
+use crate::{
+    artifact::{self, Artifact},
+    config::Config,
+    image_handler::EpdMac,
+    metadata::RenderRecord,
+    storage::Storage,
+};
+
+/// The only environment variable the command gets.
+const PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Outcome of `--post-render-cmd` for a render, kept in its render log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct HookReport {
+    /// `None` if the command could not be started, was killed by a signal or
+    /// timed out.
+    pub exit_code: Option<i32>,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+    pub duration_ms: u64,
+}
+
+/// Written to the command's stdin.
+#[derive(Serialize)]
+struct HookInput<'a> {
+    mac: EpdMac,
+    render: &'a RenderRecord,
+}
+
+/// The command of `--post-render-cmd`.
+#[derive(Debug)]
+pub(crate) struct PostRenderHook {
+    command: PathBuf,
+    timeout: Duration,
+    running: Semaphore,
+}
+
+/// Checks at startup that `command` is an executable file.
+pub(crate) fn validate(command: &Path) -> eyre::Result<()> {
+    let metadata = std::fs::metadata(command)?;
+    ensure!(metadata.is_file(), "Not a file");
+    ensure!(metadata.permissions().mode() & 0o111 != 0, "Not executable");
+    Ok(())
+}
+
+impl PostRenderHook {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let command = config.post_render_cmd.clone()?;
+        Some(PostRenderHook {
+            command,
+            timeout: config.post_render_timeout.get(),
+            running: Semaphore::new(config.post_render_concurrency.max(1)),
+        })
+    }
+
+    /// Runs the command for the render `record` of `mac`, passing it a
+    /// temporary copy of `png`. Its output is logged, failures only reported.
+    pub async fn run(
+        &self,
+        storage: &Storage,
+        mac: EpdMac,
+        png: &[u8],
+        record: &RenderRecord,
+    ) -> HookReport {
+        let _permit = self
+            .running
+            .acquire()
+            .await
+            .expect("The semaphore is never closed");
+        let started = Instant::now();
+        let result =
+            tokio::time::timeout(self.timeout, self.execute(storage, mac, png, record)).await;
+        let (exit_code, success, timed_out) = match result {
+            Ok(Ok(status)) => {
+                if !status.success() {
+                    tracing::warn!(%mac, "Post-render command failed with {status}");
+                }
+                (status.code(), status.success(), false)
+            }
+            Ok(Err(e)) => {
+                tracing::warn!(%mac, "Could not run the post-render command: {e:#}");
+                (None, false, false)
+            }
+            Err(_) => {
+                tracing::warn!(%mac, "Post-render command killed after {:?}", self.timeout);
+                (None, false, true)
+            }
+        };
+        HookReport {
+            exit_code,
+            success,
+            timed_out,
+            duration_ms: started.elapsed().as_millis() as u64,
+        }
+    }
+
+    async fn execute(
+        &self,
+        storage: &Storage,
+        mac: EpdMac,
+        png: &[u8],
+        record: &RenderRecord,
+    ) -> eyre::Result<ExitStatus> {
+        let mut temp = storage
+            .create_temp(&artifact::file_name(mac, Artifact::Png))
+            .await?;
+        temp.write_all(png).await?;
+        temp.flush().await?;
+
+        // Killed when the timeout drops it
+        let mut child = Command::new(&self.command)
+            .arg(mac.to_string())
+            .arg(temp.path())
+            .env_clear()
+            .env("PATH", PATH)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let input = serde_json::to_vec(&HookInput {
+            mac,
+            render: record,
+        })?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        match stdin.write_all(&input).await {
+            // The command doesn't have to read its input
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            result => result?,
+        }
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            tracing::info!(%mac, "post-render: {line}");
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            tracing::warn!(%mac, "post-render: {line}");
+        }
+        Ok(output.status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+
+    const MAC: EpdMac = EpdMac([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+    fn script(temp_dir: &TestDir, name: &str, body: &str) -> PathBuf {
+        let path = temp_dir.path(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn timeout_and_validation() {
+        let temp_dir = TestDir::temp();
+        let sleep = script(&temp_dir, "sleep.sh", "sleep 10");
+        let config = Config::try_parse_from([
+            "eps_server",
+            "-i",
+            temp_dir.root().to_str().unwrap(),
+            "-H",
+            "296",
+            "-W",
+            "128",
+            "--post-render-cmd",
+            sleep.to_str().unwrap(),
+            "--post-render-timeout",
+            "100ms",
+        ])
+        .unwrap();
+        validate(&sleep).unwrap();
+        let hook = PostRenderHook::from_config(&config).unwrap();
+        let record = RenderRecord {
+            timestamp: chrono::Utc::now(),
+            duration_ms: 1,
+            svg_bytes: 1,
+            png_bytes: 3,
+            changed: true,
+            changed_pixels: None,
+            coverage: None,
+            color_map: None,
+            lint: Vec::new(),
+            options: None,
+            post_render: None,
+            pipeline: None,
+        };
+        let storage = Storage::new(temp_dir.root().to_owned());
+        let report = hook.run(&storage, MAC, b"png", &record).await;
+        assert!(report.timed_out);
+        assert!(!report.success);
+        assert_eq!(report.exit_code, None);
+
+        std::fs::write(temp_dir.path("plain.sh"), "").unwrap();
+        assert!(validate(&temp_dir.path("plain.sh")).is_err());
+        assert!(validate(temp_dir.root()).is_err());
+        assert!(validate(&temp_dir.path("missing.sh")).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use std::time::UNIX_EPOCH;
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, SubsecRound, Utc};
+use eyre::eyre;
+
+use crate::{error::AppError, storage::FileMeta};
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Validators of the current representation of a resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Validators {
+    /// Strong entity tag including the quotes.
+    pub etag: String,
+    /// Modification time, truncated to seconds like HTTP dates.
+    pub last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    /// Derives validators from the metadata of the file backing a
+    /// representation. `variant` distinguishes representations converted
+    /// from the same file.
+    pub fn from_metadata(meta: &FileMeta, variant: &str) -> Self {
+        let etag = match &meta.tag {
+            Some(tag) => format!("\"{}-{variant}\"", tag.trim_matches('"')),
+            None => {
+                let nanos = meta
+                    .modified
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_nanos());
+                format!("\"{:x}-{nanos:x}-{variant}\"", meta.len)
+            }
+        };
+        Validators {
+            etag,
+            last_modified: DateTime::<Utc>::from(meta.modified).trunc_subsecs(0),
+        }
+    }
+
+    /// `ETag` and `Last-Modified` headers for responses.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Ok(date) =
+            HeaderValue::from_str(&self.last_modified.format(HTTP_DATE_FORMAT).to_string())
+        {
+            headers.insert(header::LAST_MODIFIED, date);
+        }
+        headers
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+/// Evaluates the conditional headers of a request in the order of RFC 9110,
+/// section 13.2.2. `current` is `None` if the resource doesn't exist.
+pub(crate) fn evaluate(
+    method: &Method,
+    headers: &HeaderMap,
+    current: Option<&Validators>,
+) -> Outcome {
+    let safe = method == Method::GET || method == Method::HEAD;
+
+    if let Some(if_match) = header_str(headers, header::IF_MATCH) {
+        let matches = current.is_some_and(|current| {
+            entity_tags(if_match).any(|tag| tag == "*" || strong_eq(tag, &current.etag))
+        });
+        if !matches {
+            return Outcome::PreconditionFailed;
+        }
+    } else if let (Some(since), Some(current)) =
+        (header_date(headers, header::IF_UNMODIFIED_SINCE), current)
+    {
+        if current.last_modified > since {
+            return Outcome::PreconditionFailed;
+        }
+    }
+
+    if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+        let matches = current.is_some_and(|current| {
+            entity_tags(if_none_match).any(|tag| tag == "*" || weak_eq(tag, &current.etag))
+        });
+        if matches {
+            return if safe {
+                Outcome::NotModified
+            } else {
+                Outcome::PreconditionFailed
+            };
+        }
+    } else if let (true, Some(since), Some(current)) = (
+        safe,
+        header_date(headers, header::IF_MODIFIED_SINCE),
+        current,
+    ) {
+        if current.last_modified <= since {
+            return Outcome::NotModified;
+        }
+    }
+
+    Outcome::Proceed
+}
+
+/// Returns the response ending a read early, if the preconditions say so.
+/// They are ignored for missing representations, so the request ends with a
+/// 404 instead.
+pub(crate) fn check_read(
+    headers: &HeaderMap,
+    current: Option<&Validators>,
+) -> Result<Option<Response>, AppError> {
+    let Some(current) = current else {
+        return Ok(None);
+    };
+    match evaluate(&Method::GET, headers, Some(current)) {
+        Outcome::Proceed => Ok(None),
+        Outcome::NotModified => Ok(Some(
+            (StatusCode::NOT_MODIFIED, current.headers()).into_response(),
+        )),
+        Outcome::PreconditionFailed => Err(precondition_failed()),
+    }
+}
+
+/// Rejects a write whose preconditions fail.
+pub(crate) fn check_write(
+    method: &Method,
+    headers: &HeaderMap,
+    current: Option<&Validators>,
+) -> Result<(), AppError> {
+    match evaluate(method, headers, current) {
+        Outcome::PreconditionFailed => Err(precondition_failed()),
+        Outcome::Proceed | Outcome::NotModified => Ok(()),
+    }
+}
+
+fn precondition_failed() -> AppError {
+    AppError::PreconditionFailed(eyre!(
+        "The image does not match the request's preconditions."
+    ))
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name)?.to_str().ok()
+}
+
+/// Parses an HTTP date; invalid dates make the header be ignored.
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<DateTime<Utc>> {
+    let date = DateTime::parse_from_rfc2822(header_str(headers, name)?).ok()?;
+    Some(date.with_timezone(&Utc))
+}
+
+fn entity_tags(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|tag| !tag.is_empty())
+}
+
+fn strong_eq(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"a-1-png\"";
+    const MODIFIED: &str = "Tue, 12 Mar 2024 10:00:00 GMT";
+    const BEFORE: &str = "Tue, 12 Mar 2024 09:59:59 GMT";
+    const AFTER: &str = "Tue, 12 Mar 2024 10:00:01 GMT";
+
+    fn current() -> Validators {
+        Validators {
+            etag: ETAG.to_owned(),
+            last_modified: "2024-03-12T10:00:00Z".parse().unwrap(),
+        }
+    }
+
+    fn eval(method: Method, conditions: &[(header::HeaderName, &str)], exists: bool) -> Outcome {
+        let mut headers = HeaderMap::new();
+        for (name, value) in conditions {
+            headers.insert(name, value.parse().unwrap());
+        }
+        let current = current();
+        evaluate(&method, &headers, exists.then_some(&current))
+    }
+
+    #[test]
+    fn no_conditions() {
+        assert_eq!(eval(Method::GET, &[], true), Outcome::Proceed);
+        assert_eq!(eval(Method::POST, &[], false), Outcome::Proceed);
+    }
+
+    #[test]
+    fn if_match() {
+        use header::IF_MATCH;
+        let cases = [
+            (ETAG, true, Outcome::Proceed),
+            ("\"other\", \"a-1-png\"", true, Outcome::Proceed),
+            ("*", true, Outcome::Proceed),
+            ("\"other\"", true, Outcome::PreconditionFailed),
+            // If-Match uses the strong comparison
+            ("W/\"a-1-png\"", true, Outcome::PreconditionFailed),
+            ("*", false, Outcome::PreconditionFailed),
+            (ETAG, false, Outcome::PreconditionFailed),
+        ];
+        for (value, exists, expected) in cases {
+            for method in [Method::GET, Method::POST] {
+                assert_eq!(
+                    eval(method.clone(), &[(IF_MATCH, value)], exists),
+                    expected,
+                    "{method} {value} {exists}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn if_unmodified_since() {
+        use header::IF_UNMODIFIED_SINCE;
+        let cases = [
+            (MODIFIED, true, Outcome::Proceed),
+            (AFTER, true, Outcome::Proceed),
+            (BEFORE, true, Outcome::PreconditionFailed),
+            // Ignored without a current representation or a valid date
+            (BEFORE, false, Outcome::Proceed),
+            ("yesterday", true, Outcome::Proceed),
+        ];
+        for (value, exists, expected) in cases {
+            assert_eq!(
+                eval(Method::PUT, &[(IF_UNMODIFIED_SINCE, value)], exists),
+                expected,
+                "{value} {exists}"
+            );
+        }
+    }
+
+    #[test]
+    fn if_match_takes_precedence_over_if_unmodified_since() {
+        let conditions = [
+            (header::IF_MATCH, ETAG),
+            (header::IF_UNMODIFIED_SINCE, BEFORE),
+        ];
+        assert_eq!(eval(Method::POST, &conditions, true), Outcome::Proceed);
+    }
+
+    #[test]
+    fn if_none_match() {
+        use header::IF_NONE_MATCH;
+        let cases = [
+            (
+                ETAG,
+                true,
+                Outcome::NotModified,
+                Outcome::PreconditionFailed,
+            ),
+            // If-None-Match uses the weak comparison
+            (
+                "W/\"a-1-png\"",
+                true,
+                Outcome::NotModified,
+                Outcome::PreconditionFailed,
+            ),
+            ("*", true, Outcome::NotModified, Outcome::PreconditionFailed),
+            ("\"other\"", true, Outcome::Proceed, Outcome::Proceed),
+            ("*", false, Outcome::Proceed, Outcome::Proceed),
+        ];
+        for (value, exists, read, write) in cases {
+            assert_eq!(
+                eval(Method::GET, &[(IF_NONE_MATCH, value)], exists),
+                read,
+                "GET {value} {exists}"
+            );
+            assert_eq!(
+                eval(Method::HEAD, &[(IF_NONE_MATCH, value)], exists),
+                read,
+                "HEAD {value} {exists}"
+            );
+            assert_eq!(
+                eval(Method::POST, &[(IF_NONE_MATCH, value)], exists),
+                write,
+                "POST {value} {exists}"
+            );
+        }
+    }
+
+    #[test]
+    fn if_modified_since() {
+        use header::IF_MODIFIED_SINCE;
+        let cases = [
+            (MODIFIED, Outcome::NotModified),
+            (AFTER, Outcome::NotModified),
+            (BEFORE, Outcome::Proceed),
+            ("not a date", Outcome::Proceed),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                eval(Method::GET, &[(IF_MODIFIED_SINCE, value)], true),
+                expected,
+                "{value}"
+            );
+        }
+        // Only for reads and existing representations
+        assert_eq!(
+            eval(Method::POST, &[(IF_MODIFIED_SINCE, AFTER)], true),
+            Outcome::Proceed
+        );
+        assert_eq!(
+            eval(Method::GET, &[(IF_MODIFIED_SINCE, AFTER)], false),
+            Outcome::Proceed
+        );
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let stale_tag = [
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, AFTER),
+        ];
+        assert_eq!(eval(Method::GET, &stale_tag, true), Outcome::Proceed);
+
+        let current_tag = [
+            (header::IF_NONE_MATCH, ETAG),
+            (header::IF_MODIFIED_SINCE, BEFORE),
+        ];
+        assert_eq!(eval(Method::GET, &current_tag, true), Outcome::NotModified);
+    }
+
+    #[test]
+    fn failed_if_match_wins_over_if_none_match() {
+        let conditions = [
+            (header::IF_MATCH, "\"other\""),
+            (header::IF_NONE_MATCH, ETAG),
+        ];
+        assert_eq!(
+            eval(Method::GET, &conditions, true),
+            Outcome::PreconditionFailed
+        );
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let headers = current().headers();
+        assert_eq!(headers[header::ETAG], ETAG);
+        assert_eq!(headers[header::LAST_MODIFIED], MODIFIED);
+    }
+}
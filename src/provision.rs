@@ -0,0 +1,157 @@
+use std::io::Cursor;
+
+use axum::http::HeaderMap;
+use hyper::header;
+use image::{ImageFormat, Luma};
+use qrcode::QrCode;
+use url::Url;
+
+use crate::{config::Config, image_handler::EpdMac};
+
+/// Minimum width and height of the QR code PNG, big enough to print.
+const QR_SIZE: u32 = 256;
+
+const PROVISION_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Provision {mac} - eps-server</title>
+<style>
+body { font-family: sans-serif; max-width: 40em; margin: 2em auto; padding: 0 1em; color: #222; }
+img { max-width: 100%; border: 1px solid #ccc; }
+textarea, input { width: 100%; box-sizing: border-box; }
+textarea { height: 12em; font-family: monospace; }
+#status { font-weight: bold; }
+</style>
+</head>
+<body>
+<h1>Display <code>{mac}</code></h1>
+{image}
+<form id="provision">
+<p><label for="svg">SVG of the display</label>
+<textarea id="svg" name="svg" required placeholder="&lt;svg ...&gt;"></textarea></p>
+{key}<p><button type="submit">Render</button> <span id="status"></span></p>
+</form>
+<script>
+document.getElementById("provision").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const status = document.getElementById("status");
+  const headers = { "Content-Type": "image/svg+xml", "Accept": "application/json" };
+  const key = document.getElementById("key");
+  if (key && key.value) {
+    headers["Authorization"] = "Bearer " + key.value;
+  }
+  status.textContent = "Rendering...";
+  try {
+    const response = await fetch("../macs/{mac}/render_svg", {
+      method: "POST",
+      headers,
+      body: document.getElementById("svg").value,
+    });
+    if (response.ok) {
+      status.textContent = "Done";
+      const image = document.getElementById("image");
+      if (image) {
+        image.src = "../macs/{mac}/png?" + Date.now();
+      } else {
+        location.reload();
+      }
+    } else {
+      const error = await response.json().catch(() => ({}));
+      status.textContent = "Failed: " + (error.message || response.status);
+    }
+  } catch (e) {
+    status.textContent = "Failed: " + e;
+  }
+});
+</script>
+</body>
+</html>
+"#;
+
+/// The page a technician opens by scanning the QR code of `mac`: its current
+/// image, if `has_image`, and a form posting an SVG to `render_svg`. Asks
+/// for the admin key if one is configured.
+pub(crate) fn page(config: &Config, mac: EpdMac, has_image: bool) -> String {
+    let image = if has_image {
+        r#"<p><img id="image" src="../macs/{mac}/png" alt="Current image"></p>"#
+    } else {
+        "<p>No image yet.</p>"
+    };
+    let key = if config.admin_key.is_some() {
+        "<p><label for=\"key\">Admin key</label>\n\
+         <input id=\"key\" type=\"password\" autocomplete=\"current-password\"></p>\n"
+    } else {
+        ""
+    };
+    PROVISION_PAGE
+        .replace("{image}", image)
+        .replace("{key}", key)
+        .replace("{mac}", &mac.to_string())
+}
+
+/// The URL of the provisioning page of `mac`: below `--public-url`, or else
+/// the `Host` the request was sent to.
+pub(crate) fn url(config: &Config, headers: &HeaderMap, mac: EpdMac) -> eyre::Result<Url> {
+    let mut base = match &config.public_url {
+        Some(base) => base.clone(),
+        None => {
+            let host = headers
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .ok_or_else(|| eyre::eyre!("The request has no Host to link to"))?;
+            Url::parse(&format!("http://{host}/"))?
+        }
+    };
+    // Keeps a path prefix like `/eps` of a reverse proxy
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    Ok(base.join(&format!("provision/{mac}"))?)
+}
+
+/// `url` as a black on white QR code PNG.
+pub(crate) fn qr_png(url: &Url) -> eyre::Result<Vec<u8>> {
+    let code = QrCode::new(url.as_str())?;
+    let image = code
+        .render::<Luma<u8>>()
+        .min_dimensions(QR_SIZE, QR_SIZE)
+        .build();
+    let mut png = Cursor::new(vec![]);
+    image.write_to(&mut png, ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    const MAC: EpdMac = EpdMac([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+    #[test]
+    fn provisioning_url() {
+        let config = |args: &[&str]| {
+            let defaults = ["eps_server", "-i", "images", "-H", "296", "-W", "128"];
+            Config::try_parse_from(defaults.iter().chain(args)).unwrap()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "10.0.0.5:3000".parse().unwrap());
+
+        let url = |config: &Config, headers: &HeaderMap| url(config, headers, MAC).unwrap();
+        assert_eq!(
+            url(&config(&[]), &headers).as_str(),
+            "http://10.0.0.5:3000/provision/0011223344556677"
+        );
+        for public_url in ["https://tags.example/eps", "https://tags.example/eps/"] {
+            let config = config(&["--public-url", public_url]);
+            assert_eq!(
+                url(&config, &HeaderMap::new()).as_str(),
+                "https://tags.example/eps/provision/0011223344556677"
+            );
+        }
+        assert!(super::url(&config(&[]), &HeaderMap::new(), MAC).is_err());
+    }
+}
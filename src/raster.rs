@@ -0,0 +1,576 @@
+use std::{fmt::Display, io::Cursor, str::FromStr};
+
+use eyre::{bail, eyre};
+use image::{
+    error::{LimitError, LimitErrorKind},
+    imageops::{self, BiLevel, ColorMap, FilterType},
+    DynamicImage, GenericImageView, GrayImage, ImageDecoder, ImageError, ImageFormat, ImageReader,
+    ImageResult, Limits, Luma, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Dither;
+
+/// The inks of 7-color ACeP panels, in the order of their indices.
+pub(crate) const ACEP_PALETTE: &str = "#000000,#ffffff,#00ff00,#0000ff,#ff0000,#ffff00,#ff8000";
+
+/// How an uploaded image is mapped onto the panel if the aspect ratios differ.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Fit {
+    /// Scale to fit inside the panel and letterbox the rest with white.
+    #[default]
+    Contain,
+    /// Scale to fill the panel and crop the overflow.
+    Cover,
+    /// Scale both axes independently to the panel size.
+    Stretch,
+}
+
+/// Correction applied to a direct upload whose size doesn't match the panel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Autofix {
+    /// Rotate by 90° if that makes the image match exactly.
+    Rotate,
+    /// Resize if the aspect ratio matches the panel.
+    Scale,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Dimensions of a PNG from its header, `None` if `png` isn't one.
+pub(crate) fn png_dimensions(png: &[u8]) -> Option<Dimensions> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    // The IHDR chunk comes first, its length and type then width and height
+    let header = png.strip_prefix(SIGNATURE)?.get(8..16)?;
+    let (width, height) = header.split_at(4);
+    Some(Dimensions {
+        width: u32::from_be_bytes(width.try_into().ok()?),
+        height: u32::from_be_bytes(height.try_into().ok()?),
+    })
+}
+
+/// Why a direct upload was rejected, with hints on how to fix it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct DimensionMismatch {
+    pub uploaded: Dimensions,
+    pub expected: Dimensions,
+    /// Whether rotating the image by 90° makes it match exactly.
+    pub rotation_matches: bool,
+    /// Whether the image can be scaled to the panel without distortion.
+    pub aspect_matches: bool,
+    #[serde(skip)]
+    pub autofix: Option<Autofix>,
+}
+
+impl Display for DimensionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Dimensions { width, height } = self.uploaded;
+        let expected = self.expected;
+        write!(
+            f,
+            "Image is {width}x{height} but the panel is {}x{}",
+            expected.width, expected.height
+        )?;
+        match self.autofix {
+            Some(Autofix::Rotate) => write!(f, ", rotating does not make it fit."),
+            Some(Autofix::Scale) => write!(f, ", scaling would distort it."),
+            None if self.rotation_matches => write!(f, ", try autofix=rotate."),
+            None if self.aspect_matches => write!(f, ", try autofix=scale."),
+            None => write!(f, "."),
+        }
+    }
+}
+
+/// Checks that `image` is exactly `width` x `height`, applying `autofix` if
+/// it doesn't.
+pub(crate) fn match_panel(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    autofix: Option<Autofix>,
+) -> Result<DynamicImage, DimensionMismatch> {
+    if image.dimensions() == (width, height) {
+        return Ok(image);
+    }
+    let rotation_matches = image.dimensions() == (height, width);
+    // Allow 1% difference for rounding in the exporting tool
+    let (a, b) = (
+        u64::from(image.width()) * u64::from(height),
+        u64::from(image.height()) * u64::from(width),
+    );
+    let aspect_matches = a.abs_diff(b) * 100 <= a.max(b);
+    match autofix {
+        Some(Autofix::Rotate) if rotation_matches => Ok(image.rotate90()),
+        Some(Autofix::Scale) if aspect_matches => {
+            Ok(image.resize_exact(width, height, FilterType::Triangle))
+        }
+        _ => Err(DimensionMismatch {
+            uploaded: Dimensions {
+                width: image.width(),
+                height: image.height(),
+            },
+            expected: Dimensions { width, height },
+            rotation_matches,
+            aspect_matches,
+            autofix,
+        }),
+    }
+}
+
+/// Maps an upload content type to the decoder that handles it.
+pub(crate) fn format_from_mime(content_type: &Mime) -> Option<ImageFormat> {
+    match (content_type.type_(), content_type.subtype().as_str()) {
+        (mime::IMAGE, "jpeg") => Some(ImageFormat::Jpeg),
+        (mime::IMAGE, "png") => Some(ImageFormat::Png),
+        (mime::IMAGE, "bmp") => Some(ImageFormat::Bmp),
+        _ => None,
+    }
+}
+
+/// The format of an upload from its magic bytes, `None` for anything but
+/// PNG, BMP and JPEG.
+pub(crate) fn sniff_format(data: &[u8]) -> Option<ImageFormat> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::Png)
+    } else if data.starts_with(b"BM") {
+        Some(ImageFormat::Bmp)
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some(ImageFormat::Jpeg)
+    } else {
+        None
+    }
+}
+
+/// Largest width and height of uploads of `format` that are decoded. Photos
+/// come as JPEGs, PNGs and BMPs are usually made for a panel.
+pub(crate) fn max_dimension(format: ImageFormat) -> u32 {
+    match format {
+        ImageFormat::Jpeg => 12_000,
+        _ => 8_192,
+    }
+}
+
+/// Largest number of pixels of uploads of `format` that are decoded, as an
+/// image within [`max_dimension`] may still take gigabytes.
+pub(crate) fn max_pixels(format: ImageFormat) -> u64 {
+    match format {
+        ImageFormat::Jpeg => 50_000_000,
+        _ => 16_777_216,
+    }
+}
+
+/// Bytes per pixel of the widest decoded color type, 16-bit RGBA.
+const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+/// Dimensions of `data` from its header alone, before decoding allocates
+/// the pixels.
+pub(crate) fn probe(data: &[u8], format: ImageFormat) -> ImageResult<Dimensions> {
+    let (width, height) = ImageReader::with_format(Cursor::new(data), format).into_dimensions()?;
+    Ok(Dimensions { width, height })
+}
+
+/// Decodes `data` and applies the EXIF orientation if the format carries one.
+/// Images larger than [`max_dimension`] or [`max_pixels`] fail.
+pub(crate) fn decode(data: &[u8], format: ImageFormat) -> ImageResult<DynamicImage> {
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_dimension(format));
+    limits.max_image_height = Some(max_dimension(format));
+    limits.max_alloc = Some(max_pixels(format) * MAX_BYTES_PER_PIXEL);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    if u64::from(width) * u64::from(height) > max_pixels(format) {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Scales `image` to exactly `width` x `height`.
+pub(crate) fn fit_to_panel(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    fit: Fit,
+) -> DynamicImage {
+    match fit {
+        Fit::Stretch => image.resize_exact(width, height, FilterType::Triangle),
+        Fit::Cover => image.resize_to_fill(width, height, FilterType::Triangle),
+        Fit::Contain => {
+            let scaled = image.resize(width, height, FilterType::Triangle).to_rgba8();
+            let mut canvas = RgbaImage::from_pixel(width, height, Rgba([u8::MAX; 4]));
+            let x = (width - scaled.width()) / 2;
+            let y = (height - scaled.height()) / 2;
+            imageops::overlay(&mut canvas, &scaled, x.into(), y.into());
+            DynamicImage::ImageRgba8(canvas)
+        }
+    }
+}
+
+/// Converts to grayscale, compositing transparent areas onto white.
+pub(crate) fn flatten(image: &DynamicImage) -> GrayImage {
+    let rgba = image.to_rgba8();
+    GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let luma = (299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b)) / 1000;
+        let alpha = u32::from(a);
+        Luma([((luma * alpha + u32::from(u8::MAX) * (u32::from(u8::MAX) - alpha)) / 255) as u8])
+    })
+}
+
+/// Composites transparent areas onto white.
+pub(crate) fn flatten_rgb(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let alpha = u32::from(a);
+        Rgb([r, g, b].map(|c| {
+            ((u32::from(c) * alpha + u32::from(u8::MAX) * (u32::from(u8::MAX) - alpha)) / 255) as u8
+        }))
+    })
+}
+
+/// Packs an image into the raw panel format: one bit per pixel, MSB first,
+/// each row padded to a full byte, set bits are white.
+pub(crate) fn pack_1bpp(image: &GrayImage) -> Vec<u8> {
+    let row_bytes = (image.width() as usize).div_ceil(8);
+    let mut packed = vec![0; row_bytes * image.height() as usize];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if pixel.0[0] >= 0x80 {
+            packed[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    packed
+}
+
+/// Reduces a grayscale image to pure black and white.
+pub(crate) fn dither(image: &mut GrayImage, mode: Dither) {
+    match mode {
+        Dither::FloydSteinberg => imageops::dither(image, &BiLevel),
+        Dither::Threshold => {
+            for pixel in image.pixels_mut() {
+                pixel.0[0] = if pixel.0[0] >= 0x80 { u8::MAX } else { 0 };
+            }
+        }
+    }
+}
+
+/// Highlight of pixels that turn black in [`ghost_diff`].
+pub(crate) const TURNED_BLACK: Rgb<u8> = Rgb([0xd0, 0x20, 0x20]);
+/// Highlight of pixels that turn white in [`ghost_diff`].
+pub(crate) const TURNED_WHITE: Rgb<u8> = Rgb([0x20, 0x70, 0xe0]);
+
+/// Shows the changes from `previous` to `current`: unchanged pixels are
+/// dimmed to light gray, pixels that turn black or white are highlighted.
+/// `None` if the images differ in size.
+pub(crate) fn ghost_diff(previous: &DynamicImage, current: &DynamicImage) -> Option<RgbImage> {
+    if previous.dimensions() != current.dimensions() {
+        return None;
+    }
+    let (previous, current) = (flatten(previous), flatten(current));
+    Some(RgbImage::from_fn(
+        current.width(),
+        current.height(),
+        |x, y| {
+            let before = previous.get_pixel(x, y).0[0] >= 0x80;
+            let Luma([after]) = *current.get_pixel(x, y);
+            match (before, after >= 0x80) {
+                (true, false) => TURNED_BLACK,
+                (false, true) => TURNED_WHITE,
+                _ => Rgb([0xc0 + after / 4; 3]),
+            }
+        },
+    ))
+}
+
+/// The colors a panel can show, like the seven inks of ACeP panels. Their
+/// position is the index sent to the controller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Palette(Vec<Rgb<u8>>);
+
+impl Palette {
+    /// Maps every pixel of `image` to a palette color, spreading the error
+    /// across the neighbouring pixels unless `mode` is a plain threshold.
+    pub fn quantize(&self, image: &DynamicImage, mode: Dither) -> RgbImage {
+        let mut rgb = flatten_rgb(image);
+        match mode {
+            Dither::FloydSteinberg => imageops::dither(&mut rgb, self),
+            Dither::Threshold => rgb.pixels_mut().for_each(|pixel| self.map_color(pixel)),
+        }
+        rgb
+    }
+
+    /// Packs an image into the raw format of palette panels: the indices of
+    /// the nearest palette colors, two pixels per byte with the first one in
+    /// the high nibble, each row padded to a full byte.
+    pub fn pack_4bpp(&self, image: &RgbImage) -> Vec<u8> {
+        let row_bytes = (image.width() as usize).div_ceil(2);
+        let mut packed = vec![0; row_bytes * image.height() as usize];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let index = self.index_of(pixel) as u8;
+            let shift = if x % 2 == 0 { 4 } else { 0 };
+            packed[y as usize * row_bytes + x as usize / 2] |= index << shift;
+        }
+        packed
+    }
+}
+
+impl ColorMap for Palette {
+    type Color = Rgb<u8>;
+
+    fn index_of(&self, color: &Rgb<u8>) -> usize {
+        let distance = |entry: &Rgb<u8>| -> u32 {
+            entry
+                .0
+                .iter()
+                .zip(color.0)
+                .map(|(&a, b)| u32::from(a.abs_diff(b)).pow(2))
+                .sum()
+        };
+        (0..self.0.len())
+            .min_by_key(|&i| distance(&self.0[i]))
+            .unwrap_or_default()
+    }
+
+    fn lookup(&self, index: usize) -> Option<Rgb<u8>> {
+        self.0.get(index).copied()
+    }
+
+    fn has_lookup(&self) -> bool {
+        true
+    }
+
+    fn map_color(&self, color: &mut Rgb<u8>) {
+        *color = self.0[self.index_of(color)];
+    }
+}
+
+impl FromStr for Palette {
+    type Err = eyre::Error;
+
+    /// Parses comma separated colors like `#ff8000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let colors = s
+            .split(',')
+            .map(|color| {
+                let hex = color.trim().trim_start_matches('#');
+                let rgb = <[u8; 3]>::try_from(hex::decode(hex).unwrap_or_default())
+                    .map_err(|_| eyre!("Invalid color '{color}', expected #rrggbb"))?;
+                Ok(Rgb(rgb))
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        // Indices have three bits
+        if !(2..=8).contains(&colors.len()) {
+            bail!("A palette has 2 to 8 colors, not {}", colors.len());
+        }
+        Ok(Palette(colors))
+    }
+}
+
+impl Display for Palette {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let colors: Vec<_> = self
+            .0
+            .iter()
+            .map(|color| format!("#{}", hex::encode(color.0)))
+            .collect();
+        write!(f, "{}", colors.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    const WHITE: Luma<u8> = Luma([u8::MAX]);
+
+    #[test]
+    fn format_from_mime_supported() {
+        assert_eq!(format_from_mime(&mime::IMAGE_JPEG), Some(ImageFormat::Jpeg));
+        assert_eq!(format_from_mime(&mime::IMAGE_PNG), Some(ImageFormat::Png));
+        assert_eq!(format_from_mime(&mime::IMAGE_BMP), Some(ImageFormat::Bmp));
+        assert_eq!(format_from_mime(&mime::IMAGE_GIF), None);
+        assert_eq!(format_from_mime(&mime::TEXT_PLAIN), None);
+    }
+
+    #[test]
+    fn sniffs_uploads() {
+        let mut png = Cursor::new(vec![]);
+        RgbImage::new(300, 100)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        assert_eq!(sniff_format(&png), Some(ImageFormat::Png));
+        assert_eq!(
+            probe(&png, ImageFormat::Png).unwrap(),
+            Dimensions {
+                width: 300,
+                height: 100
+            }
+        );
+        assert_eq!(sniff_format(b"BM\0\0"), Some(ImageFormat::Bmp));
+        assert_eq!(
+            sniff_format(&[0xff, 0xd8, 0xff, 0xe0]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(sniff_format(b"<svg/>"), None);
+        assert_eq!(sniff_format(b""), None);
+
+        let large = RgbImage::new(max_dimension(ImageFormat::Png) + 1, 1);
+        let mut png = Cursor::new(vec![]);
+        large.write_to(&mut png, ImageFormat::Png).unwrap();
+        assert!(decode(&png.into_inner(), ImageFormat::Png).is_err());
+
+        // A 1-bit BMP header of 8000x8000 pixels, each side within the limit
+        let mut bmp = b"BM".to_vec();
+        for value in [62u32, 0, 62, 40, 8000, 8000] {
+            bmp.extend(value.to_le_bytes());
+        }
+        bmp.extend(1u16.to_le_bytes());
+        bmp.extend(1u16.to_le_bytes());
+        bmp.extend([0; 24]);
+        bmp.extend([0, 0, 0, 0, 0xff, 0xff, 0xff, 0]);
+        let dimensions = probe(&bmp, ImageFormat::Bmp).unwrap();
+        assert!(dimensions.width <= max_dimension(ImageFormat::Bmp));
+        assert!(matches!(
+            decode(&bmp, ImageFormat::Bmp),
+            Err(ImageError::Limits(_))
+        ));
+    }
+
+    #[test]
+    fn fit_to_panel_dimensions() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(300, 100));
+        for fit in [Fit::Contain, Fit::Cover, Fit::Stretch] {
+            let out = fit_to_panel(&image, 128, 296, fit);
+            assert_eq!(out.dimensions(), (128, 296), "{fit:?}");
+        }
+    }
+
+    #[test]
+    fn fit_contain_letterboxes_white() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(100, 100));
+        let out = fit_to_panel(&image, 100, 200, Fit::Contain).to_luma8();
+        assert_eq!(out.get_pixel(50, 0), &WHITE);
+        assert_eq!(out.get_pixel(50, 100), &Luma([0]));
+        assert_eq!(out.get_pixel(50, 199), &WHITE);
+    }
+
+    #[test]
+    fn match_panel_autofix() {
+        let transposed = || DynamicImage::ImageRgb8(RgbImage::new(296, 128));
+        let mismatch = match_panel(transposed(), 128, 296, None).unwrap_err();
+        assert!(mismatch.rotation_matches);
+        assert!(!mismatch.aspect_matches);
+        assert_eq!(
+            match_panel(transposed(), 128, 296, Some(Autofix::Rotate))
+                .unwrap()
+                .dimensions(),
+            (128, 296)
+        );
+        assert!(match_panel(transposed(), 128, 296, Some(Autofix::Scale)).is_err());
+
+        let double = DynamicImage::ImageRgb8(RgbImage::new(256, 592));
+        let scaled = match_panel(double, 128, 296, Some(Autofix::Scale)).unwrap();
+        assert_eq!(scaled.dimensions(), (128, 296));
+    }
+
+    #[test]
+    fn flatten_transparent_is_white() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(2, 1, |x, _| {
+            image::Rgba([0, 0, 0, if x == 0 { 0 } else { u8::MAX }])
+        }));
+        let gray = flatten(&image);
+        assert_eq!(gray.get_pixel(0, 0), &WHITE);
+        assert_eq!(gray.get_pixel(1, 0), &Luma([0]));
+    }
+
+    #[test]
+    fn pack_1bpp_pads_rows() {
+        // 10 pixels wide: two bytes per row, only the first bits of the
+        // second byte are used
+        let image = GrayImage::from_fn(10, 2, |x, y| {
+            Luma([if (x + y) % 2 == 0 { u8::MAX } else { 0 }])
+        });
+        assert_eq!(
+            pack_1bpp(&image),
+            [0b1010_1010, 0b1000_0000, 0b0101_0101, 0b0100_0000]
+        );
+    }
+
+    #[test]
+    fn dither_is_bilevel() {
+        for mode in [Dither::FloydSteinberg, Dither::Threshold] {
+            let mut image = GrayImage::from_fn(64, 8, |x, _| Luma([(x * 4) as u8]));
+            dither(&mut image, mode);
+            assert!(image.pixels().all(|p| p.0[0] == 0 || p.0[0] == u8::MAX));
+        }
+    }
+
+    #[test]
+    fn quantize_rainbow() {
+        let palette: Palette = ACEP_PALETTE.parse().unwrap();
+        let rainbow = RgbImage::from_fn(96, 16, |x, y| {
+            let hue = x * 6 * 255 / 96;
+            let (rise, fall) = ((hue % 255) as u8, 255 - (hue % 255) as u8);
+            let rgb = match hue / 255 {
+                0 => [255, rise, 0],
+                1 => [fall, 255, 0],
+                2 => [0, 255, rise],
+                3 => [0, fall, 255],
+                4 => [rise, 0, 255],
+                _ => [255, 0, fall],
+            };
+            // Fade towards gray further down
+            Rgb(rgb.map(|c| (u32::from(c) * (16 - y) / 16 + 255 * y / 32) as u8))
+        });
+        for mode in [Dither::FloydSteinberg, Dither::Threshold] {
+            let out = palette.quantize(&DynamicImage::ImageRgb8(rainbow.clone()), mode);
+            assert!(out.pixels().all(|p| palette.0.contains(p)), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn pack_4bpp_row() {
+        let palette: Palette = ACEP_PALETTE.parse().unwrap();
+        // Black, white, red, green and orange, slightly off
+        let row = [
+            [10, 10, 10],
+            [250, 240, 255],
+            [220, 20, 30],
+            [0, 200, 40],
+            [255, 140, 0],
+        ];
+        let image = RgbImage::from_fn(5, 1, |x, _| Rgb(row[x as usize]));
+        assert_eq!(palette.pack_4bpp(&image), [0x01, 0x42, 0x60]);
+    }
+
+    #[test]
+    fn parse_palette() {
+        let palette: Palette = ACEP_PALETTE.parse().unwrap();
+        assert_eq!(palette.to_string(), ACEP_PALETTE);
+        assert_eq!(palette.lookup(6), Some(Rgb([255, 128, 0])));
+        for s in [
+            "#000000",
+            "#000000,#fffff",
+            "#000000,white",
+            &[ACEP_PALETTE; 2].join(","),
+        ] {
+            assert!(s.parse::<Palette>().is_err(), "{s}");
+        }
+    }
+}
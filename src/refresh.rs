@@ -0,0 +1,222 @@
+use chrono::{DateTime, Utc};
+use hyper::{header::HeaderName, HeaderMap};
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::MacMetadata;
+
+const SUGGESTED_REFRESH: HeaderName = HeaderName::from_static("x-epd-suggested-refresh");
+const CHANGED_PIXELS: HeaderName = HeaderName::from_static("x-epd-changed-pixels");
+const SINCE_FULL_REFRESH: HeaderName = HeaderName::from_static("x-epd-since-full-refresh");
+
+/// Waveform a panel updates with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Refresh {
+    Full,
+    Partial,
+}
+
+impl Refresh {
+    fn as_str(self) -> &'static str {
+        match self {
+            Refresh::Full => "full",
+            Refresh::Partial => "partial",
+        }
+    }
+}
+
+/// Body of `POST /macs/:mac/ack`, sent by devices after updating the panel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Ack {
+    pub refresh: Refresh,
+}
+
+/// When partial refreshes leave too much ghosting.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct RefreshThresholds {
+    /// Share of the panel in percent that may change in a partial refresh.
+    pub changed_percent: u8,
+    /// Image changes shown with partial refreshes after a full one.
+    pub max_partial: u32,
+}
+
+/// Which refresh a device should use for the current image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RefreshHint {
+    pub suggested: Refresh,
+    /// Pixels changed by the last render, unknown for the first one.
+    pub changed_pixels: Option<u64>,
+    /// Seconds since the device reported its last full refresh.
+    pub since_full_refresh: Option<i64>,
+}
+
+impl RefreshHint {
+    /// Suggests a full refresh if the device never reported one, the last
+    /// render changed too much or the changes since the last full refresh
+    /// are too many.
+    pub fn new(
+        meta: &MacMetadata,
+        panel_pixels: u64,
+        thresholds: RefreshThresholds,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let changed_pixels = meta
+            .render_log
+            .back()
+            .and_then(|record| record.changed_pixels);
+        let Some(full_refresh) = meta.full_refresh else {
+            return RefreshHint {
+                suggested: Refresh::Full,
+                changed_pixels,
+                since_full_refresh: None,
+            };
+        };
+        let since_full_refresh = Some((now - full_refresh).num_seconds());
+
+        let changes = meta
+            .render_log
+            .iter()
+            .filter(|record| record.timestamp > full_refresh && record.changed)
+            .count();
+        let too_much = match changed_pixels {
+            Some(changed) => changed * 100 >= u64::from(thresholds.changed_percent) * panel_pixels,
+            None => true,
+        };
+        let suggested = if changes == 0 {
+            Refresh::Partial
+        } else if too_much || changes > thresholds.max_partial as usize {
+            Refresh::Full
+        } else {
+            Refresh::Partial
+        };
+        RefreshHint {
+            suggested,
+            changed_pixels,
+            since_full_refresh,
+        }
+    }
+
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(SUGGESTED_REFRESH, self.suggested.as_str().parse().unwrap());
+        if let Some(changed) = self.changed_pixels {
+            headers.insert(CHANGED_PIXELS, changed.into());
+        }
+        if let Some(seconds) = self.since_full_refresh {
+            headers.insert(SINCE_FULL_REFRESH, seconds.into());
+        }
+        headers
+    }
+}
+
+/// Number of pixels that differ between two PNGs, `None` if they can't be
+/// compared.
+pub(crate) fn changed_pixels(previous: &[u8], current: &[u8]) -> Option<u64> {
+    let previous = image::load_from_memory_with_format(previous, ImageFormat::Png).ok()?;
+    let current = image::load_from_memory_with_format(current, ImageFormat::Png).ok()?;
+    let (previous, current) = (previous.to_rgba8(), current.to_rgba8());
+    if previous.dimensions() != current.dimensions() {
+        return None;
+    }
+    let changed = previous
+        .pixels()
+        .zip(current.pixels())
+        .filter(|(a, b)| a != b)
+        .count();
+    Some(changed as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use chrono::Duration;
+    use image::{GrayImage, Luma};
+
+    use super::*;
+    use crate::metadata::RenderRecord;
+
+    const THRESHOLDS: RefreshThresholds = RefreshThresholds {
+        changed_percent: 50,
+        max_partial: 2,
+    };
+
+    fn render(meta: &mut MacMetadata, timestamp: DateTime<Utc>, changed_pixels: u64) {
+        meta.push_render(RenderRecord {
+            timestamp,
+            duration_ms: 1,
+            svg_bytes: 1,
+            png_bytes: 1,
+            changed: changed_pixels > 0,
+            changed_pixels: Some(changed_pixels),
+            coverage: None,
+            color_map: None,
+            lint: Vec::new(),
+            options: None,
+            post_render: None,
+            pipeline: None,
+        });
+    }
+
+    #[test]
+    fn full_after_too_many_partials() {
+        let start = Utc::now();
+        let mut meta = MacMetadata::default();
+        render(&mut meta, start, 10);
+        let hint = RefreshHint::new(&meta, 100, THRESHOLDS, start);
+        assert_eq!(hint.suggested, Refresh::Full);
+        assert_eq!(hint.since_full_refresh, None);
+
+        meta.full_refresh = Some(start + Duration::seconds(1));
+        let mut now = start + Duration::seconds(1);
+        for partial in 1..=3 {
+            now += Duration::seconds(10);
+            render(&mut meta, now, 10);
+            let hint = RefreshHint::new(&meta, 100, THRESHOLDS, now);
+            let expected = if partial <= 2 {
+                Refresh::Partial
+            } else {
+                Refresh::Full
+            };
+            assert_eq!(hint.suggested, expected, "{partial}");
+            assert_eq!(hint.changed_pixels, Some(10));
+            assert_eq!(hint.since_full_refresh, Some(partial * 10));
+        }
+    }
+
+    #[test]
+    fn full_after_large_change() {
+        let start = Utc::now();
+        let mut meta = MacMetadata {
+            full_refresh: Some(start),
+            ..MacMetadata::default()
+        };
+        render(&mut meta, start + Duration::seconds(1), 49);
+        assert_eq!(
+            RefreshHint::new(&meta, 100, THRESHOLDS, start).suggested,
+            Refresh::Partial
+        );
+        render(&mut meta, start + Duration::seconds(2), 50);
+        let hint = RefreshHint::new(&meta, 100, THRESHOLDS, start);
+        assert_eq!(hint.suggested, Refresh::Full);
+        assert_eq!(hint.headers()[SUGGESTED_REFRESH], "full");
+        assert_eq!(hint.headers()[CHANGED_PIXELS], "50");
+    }
+
+    #[test]
+    fn count_changed_pixels() {
+        let png = |image: GrayImage| {
+            let mut png = Cursor::new(vec![]);
+            image.write_to(&mut png, ImageFormat::Png).unwrap();
+            png.into_inner()
+        };
+        let white = png(GrayImage::from_pixel(4, 4, Luma([255])));
+        let dot = png(GrayImage::from_fn(4, 4, |x, y| {
+            Luma([if (x, y) == (1, 2) { 0 } else { 255 }])
+        }));
+        assert_eq!(changed_pixels(&white, &dot), Some(1));
+        assert_eq!(changed_pixels(&white, &white), Some(0));
+        assert_eq!(changed_pixels(&white, &png(GrayImage::new(2, 2))), None);
+    }
+}
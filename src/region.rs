@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+use eyre::{bail, ensure};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+/// Rectangle of a panel that its firmware updates on its own, like the
+/// header strip of a panel split by its housing. In pixels of the panel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+/// Regions of a MAC by name, set with its display profile.
+pub(crate) type Regions = BTreeMap<String, Region>;
+
+/// What fetching a region does if its left edge or width don't fall on the
+/// byte boundaries of the packed rows, the `align` query parameter.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Align {
+    /// Fails the request.
+    #[default]
+    Reject,
+    /// Widens the region to the next boundaries.
+    Expand,
+}
+
+impl Region {
+    /// Fails unless the region is within a panel of `width` x `height`.
+    pub fn check(&self, width: u32, height: u32) -> eyre::Result<()> {
+        ensure!(self.w > 0 && self.h > 0, "The region is empty");
+        let (right, bottom) = (self.x.checked_add(self.w), self.y.checked_add(self.h));
+        ensure!(
+            matches!(right, Some(right) if right <= width)
+                && matches!(bottom, Some(bottom) if bottom <= height),
+            "{}x{} at {},{} exceeds the panel of {width}x{height}",
+            self.w,
+            self.h,
+            self.x,
+            self.y
+        );
+        Ok(())
+    }
+
+    /// The region with its left edge and width on multiples of
+    /// `pixels_per_byte`, as packed rows of a panel of `width` need.
+    pub fn align(self, pixels_per_byte: u32, align: Align, width: u32) -> eyre::Result<Self> {
+        let aligned = |n: u32| n.is_multiple_of(pixels_per_byte);
+        if aligned(self.x) && aligned(self.w) {
+            return Ok(self);
+        }
+        if align == Align::Reject {
+            bail!(
+                "The region starts at x {} and is {} wide, which are not multiples of \
+                 {pixels_per_byte} pixels; pass align=expand to widen it",
+                self.x,
+                self.w
+            );
+        }
+        let x = self.x - self.x % pixels_per_byte;
+        let right = (self.x + self.w)
+            .next_multiple_of(pixels_per_byte)
+            .min(width);
+        Ok(Region {
+            x,
+            w: right - x,
+            ..self
+        })
+    }
+
+    pub fn crop(&self, image: &DynamicImage) -> DynamicImage {
+        image.crop_imm(self.x, self.y, self.w, self.h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_and_align() {
+        let region = |x, y, w, h| Region { x, y, w, h };
+        assert!(region(0, 0, 128, 296).check(128, 296).is_ok());
+        assert!(region(120, 0, 9, 10).check(128, 296).is_err());
+        assert!(region(0, 290, 8, 7).check(128, 296).is_err());
+        assert!(region(0, 0, 0, 7).check(128, 296).is_err());
+        assert!(region(u32::MAX, 0, 8, 7).check(128, 296).is_err());
+
+        let header = region(8, 0, 16, 20);
+        assert_eq!(header.align(8, Align::Reject, 128).unwrap(), header);
+        let body = region(3, 20, 14, 10);
+        assert!(body.align(8, Align::Reject, 128).is_err());
+        assert_eq!(
+            body.align(8, Align::Expand, 128).unwrap(),
+            region(0, 20, 24, 10)
+        );
+        // Never past the right edge, whose row is padded anyway
+        assert_eq!(
+            region(121, 0, 6, 1).align(8, Align::Expand, 127).unwrap(),
+            region(120, 0, 7, 1)
+        );
+        assert_eq!(
+            body.align(2, Align::Expand, 128).unwrap(),
+            region(2, 20, 16, 10)
+        );
+    }
+}
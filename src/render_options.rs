@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color_map::ColorMapRequest,
+    config::{Config, Dither, TextMode},
+    display_profile::{DisplayProfile, Flip, Rotation},
+};
+
+/// The knobs of a render after merging all layers, see [`Self::resolve`].
+/// Kept in its render log entry to show what applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RenderOptions {
+    pub dither: Dither,
+    pub rotate: Rotation,
+    pub flip: Flip,
+    pub text: TextMode,
+    pub color_map: ColorMapRequest,
+}
+
+impl Default for RenderOptions {
+    /// The options of the command line defaults.
+    fn default() -> Self {
+        RenderOptions {
+            dither: Dither::FloydSteinberg,
+            rotate: Rotation::default(),
+            flip: Flip::default(),
+            text: TextMode::Live,
+            color_map: ColorMapRequest::Preserve,
+        }
+    }
+}
+
+/// Knobs replacing those of the lower layers where set: stored for a MAC
+/// with `PUT /macs/:mac/render_options`, or given with a render as query
+/// parameters or fields of its body.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RenderOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dither: Option<Dither>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotate: Option<Rotation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flip: Option<Flip>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<TextMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_map: Option<ColorMapRequest>,
+}
+
+impl RenderOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl RenderOptions {
+    /// Merges the layers of a render of a MAC, each replacing the previous:
+    /// the global `config`, the display `profile` of the MAC, the overrides
+    /// `stored` for it and those of the `request`.
+    pub fn resolve(
+        config: &Config,
+        profile: DisplayProfile,
+        stored: &RenderOverrides,
+        request: &RenderOverrides,
+    ) -> Self {
+        let mut options = RenderOptions {
+            dither: config.dither,
+            rotate: profile.rotate,
+            flip: profile.flip,
+            text: config.text_mode(),
+            color_map: config.color_map_request(),
+        };
+        for overrides in [stored, request] {
+            options.apply(overrides);
+        }
+        options
+    }
+
+    pub fn apply(&mut self, overrides: &RenderOverrides) {
+        let RenderOverrides {
+            dither,
+            rotate,
+            flip,
+            text,
+            color_map,
+        } = overrides.clone();
+        self.dither = dither.unwrap_or(self.dither);
+        self.rotate = rotate.unwrap_or(self.rotate);
+        self.flip = flip.unwrap_or(self.flip);
+        self.text = text.unwrap_or(self.text);
+        self.color_map = color_map.unwrap_or_else(|| self.color_map.clone());
+    }
+
+    pub fn profile(&self) -> DisplayProfile {
+        DisplayProfile {
+            rotate: self.rotate,
+            flip: self.flip,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        let config = Config::try_parse_from([
+            "eps_server",
+            "-i",
+            "images",
+            "-H",
+            "296",
+            "-W",
+            "128",
+            "--dither",
+            "threshold",
+            "--convert-text-to-paths",
+        ])
+        .unwrap();
+        let profile = DisplayProfile {
+            rotate: Rotation::Deg90,
+            flip: Flip::Horizontal,
+        };
+        let stored = RenderOverrides {
+            flip: Some(Flip::Vertical),
+            text: Some(TextMode::Live),
+            color_map: Some(ColorMapRequest::Auto),
+            ..RenderOverrides::default()
+        };
+        let request = RenderOverrides {
+            text: Some(TextMode::Paths),
+            ..RenderOverrides::default()
+        };
+        let none = RenderOverrides::default();
+
+        // Each layer alone on top of the config, then all of them
+        let table = [
+            (
+                DisplayProfile::default(),
+                &none,
+                &none,
+                RenderOptions {
+                    dither: Dither::Threshold,
+                    text: TextMode::Paths,
+                    ..RenderOptions::default()
+                },
+            ),
+            (
+                profile,
+                &none,
+                &none,
+                RenderOptions {
+                    dither: Dither::Threshold,
+                    rotate: Rotation::Deg90,
+                    flip: Flip::Horizontal,
+                    text: TextMode::Paths,
+                    ..RenderOptions::default()
+                },
+            ),
+            (
+                DisplayProfile::default(),
+                &stored,
+                &none,
+                RenderOptions {
+                    dither: Dither::Threshold,
+                    flip: Flip::Vertical,
+                    color_map: ColorMapRequest::Auto,
+                    ..RenderOptions::default()
+                },
+            ),
+            (
+                profile,
+                &stored,
+                &request,
+                RenderOptions {
+                    dither: Dither::Threshold,
+                    rotate: Rotation::Deg90,
+                    flip: Flip::Vertical,
+                    text: TextMode::Paths,
+                    color_map: ColorMapRequest::Auto,
+                },
+            ),
+        ];
+        for (i, (profile, stored, request, expected)) in table.into_iter().enumerate() {
+            let options = RenderOptions::resolve(&config, profile, stored, request);
+            assert_eq!(options, expected, "row {i}");
+        }
+
+        // Query strings have the rotation as text
+        let overrides: RenderOverrides =
+            serde_json::from_str(r#"{"rotate":"270","dither":"floyd-steinberg"}"#).unwrap();
+        assert_eq!(overrides.rotate, Some(Rotation::Deg270));
+        assert_eq!(overrides.dither, Some(Dither::FloydSteinberg));
+        assert!(serde_json::from_str::<RenderOverrides>(r#"{"rotate":"45"}"#).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::{collections::VecDeque, str::FromStr, sync::Mutex};
+
+use eyre::{bail, eyre};
+use hyper::{header::HeaderName, HeaderMap};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::error::AppError;
+
+const PRIORITY: HeaderName = HeaderName::from_static("x-eps-priority");
+
+/// Which renders go first when all render permits are taken.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Priority {
+    /// Someone waits for the result.
+    Interactive,
+    /// Part of many renders, like a group render or a re-render job.
+    Batch,
+}
+
+impl FromStr for Priority {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interactive" => Ok(Priority::Interactive),
+            "batch" => Ok(Priority::Batch),
+            _ => bail!("Unknown priority '{s}', expected interactive or batch"),
+        }
+    }
+}
+
+impl Priority {
+    /// The priority requested with the `X-EPS-Priority` header, `default`
+    /// without one.
+    pub fn from_headers(headers: &HeaderMap, default: Priority) -> Result<Self, AppError> {
+        let Some(value) = headers.get(PRIORITY) else {
+            return Ok(default);
+        };
+        value
+            .to_str()
+            .map_err(|_| eyre!("Invalid X-EPS-Priority header"))
+            .and_then(str::parse)
+            .map_err(AppError::BadRequest)
+    }
+}
+
+/// Renders waiting for a permit, by priority.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct QueueDepth {
+    pub interactive: usize,
+    pub batch: usize,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    available: usize,
+    interactive: VecDeque<oneshot::Sender<()>>,
+    batch: VecDeque<oneshot::Sender<()>>,
+    /// Interactive renders admitted since the last batch render.
+    interactive_streak: u32,
+}
+
+/// Limits how many SVGs are rendered at the same time. Waiting interactive
+/// renders are admitted before batch ones, but after `interactive_per_batch`
+/// interactive renders in a row a waiting batch render goes first, so that
+/// batches still progress under constant interactive load.
+#[derive(Debug)]
+pub(crate) struct RenderQueue {
+    queue: Mutex<Queue>,
+    interactive_per_batch: u32,
+}
+
+impl RenderQueue {
+    pub fn new(permits: usize, interactive_per_batch: u32) -> Self {
+        RenderQueue {
+            queue: Mutex::new(Queue {
+                available: permits,
+                ..Queue::default()
+            }),
+            interactive_per_batch,
+        }
+    }
+
+    /// Waits for a permit to render.
+    pub async fn acquire(
+        &self,
+        priority: Priority,
+    ) -> Result<Permit<'_>, oneshot::error::RecvError> {
+        let admitted = {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.available > 0 && queue.interactive.is_empty() && queue.batch.is_empty() {
+                queue.available -= 1;
+                return Ok(Permit { queue: self });
+            }
+            let (sender, admitted) = oneshot::channel();
+            match priority {
+                Priority::Interactive => queue.interactive.push_back(sender),
+                Priority::Batch => queue.batch.push_back(sender),
+            }
+            admitted
+        };
+        let mut waiting = Waiting {
+            queue: self,
+            admitted,
+        };
+        (&mut waiting.admitted).await?;
+        Ok(Permit { queue: self })
+    }
+
+    /// Permits that are not taken.
+    pub fn available(&self) -> usize {
+        self.queue.lock().unwrap().available
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        let queue = self.queue.lock().unwrap();
+        QueueDepth {
+            interactive: queue.interactive.len(),
+            batch: queue.batch.len(),
+        }
+    }
+
+    /// Hands a returned permit to the next waiting render.
+    fn release(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let batch_due = queue.interactive_streak >= self.interactive_per_batch;
+            let next = if batch_due || queue.interactive.is_empty() {
+                queue.interactive_streak = 0;
+                queue.batch.pop_front()
+            } else {
+                None
+            };
+            let next = match next {
+                Some(next) => Some(next),
+                None => {
+                    let next = queue.interactive.pop_front();
+                    if next.is_some() {
+                        queue.interactive_streak += 1;
+                    }
+                    next
+                }
+            };
+            let Some(next) = next else {
+                queue.available += 1;
+                return;
+            };
+            // Fails if the render stopped waiting
+            if next.send(()).is_ok() {
+                return;
+            }
+        }
+    }
+}
+
+/// A render that was queued. If it stops waiting after being admitted, the
+/// permit is passed on.
+struct Waiting<'a> {
+    queue: &'a RenderQueue,
+    admitted: oneshot::Receiver<()>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.admitted.close();
+        if self.admitted.try_recv().is_ok() {
+            self.queue.release();
+        }
+    }
+}
+
+/// Permission to render, returned when dropped.
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    queue: &'a RenderQueue,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    /// Queues renders of `priorities` behind a held permit and returns the
+    /// order in which they were admitted.
+    async fn admission_order(priorities: &[Priority], interactive_per_batch: u32) -> Vec<usize> {
+        let queue = Arc::new(RenderQueue::new(1, interactive_per_batch));
+        let held = queue.acquire(Priority::Batch).await.unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        for (i, &priority) in priorities.iter().enumerate() {
+            let (render_queue, sender) = (queue.clone(), sender.clone());
+            tokio::spawn(async move {
+                let permit = render_queue.acquire(priority).await.unwrap();
+                // The slow render
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                sender.send(i).unwrap();
+                drop(permit);
+            });
+            // Queue them in order
+            while queue.depth().interactive + queue.depth().batch <= i {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(sender);
+        drop(held);
+
+        let mut order = vec![];
+        while let Some(i) = receiver.recv().await {
+            order.push(i);
+        }
+        assert_eq!(queue.available(), 1);
+        order
+    }
+
+    #[tokio::test]
+    async fn interactive_before_batch() {
+        use Priority::{Batch, Interactive};
+        let order = admission_order(&[Batch, Batch, Batch, Interactive], 8).await;
+        assert_eq!(order, [3, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn batch_not_starved() {
+        use Priority::{Batch, Interactive};
+        let priorities = [
+            Batch,
+            Interactive,
+            Interactive,
+            Interactive,
+            Batch,
+            Interactive,
+        ];
+        let order = admission_order(&priorities, 2).await;
+        assert_eq!(order, [1, 2, 0, 3, 5, 4]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiter_passes_permit_on() {
+        let queue = RenderQueue::new(1, 8);
+        let held = queue.acquire(Priority::Batch).await.unwrap();
+        let waiting = queue.acquire(Priority::Interactive);
+        tokio::select! {
+            biased;
+            _ = waiting => unreachable!(),
+            _ = tokio::task::yield_now() => {}
+        }
+        assert_eq!(queue.depth().interactive, 1);
+        drop(held);
+        assert_eq!(queue.available(), 1);
+        assert_eq!(queue.depth(), QueueDepth::default());
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use eyre::{eyre, WrapErr};
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    display_profile::DisplayProfile,
+    error::{AppError, ResultExt},
+    image_handler::EpdMac,
+    render_options::{RenderOptions, RenderOverrides},
+};
+
+/// Elements of the arrays and maps a script may build, however large
+/// `--render-script-max-bytes`.
+const MAX_COLLECTION_LEN: usize = 10_000;
+const MAX_CALL_LEVELS: usize = 32;
+
+/// The `ctx` variable of the script.
+#[derive(Serialize)]
+struct Context<'a> {
+    mac: String,
+    profile: DisplayProfile,
+    options: &'a RenderOptions,
+    svg: &'a str,
+}
+
+/// What a script changes: it returns nothing to keep the render as it is,
+/// the new SVG as a string, or a map with an `svg` and `options` to
+/// override.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub(crate) struct ScriptOutput {
+    #[serde(default)]
+    pub svg: Option<String>,
+    #[serde(default)]
+    pub options: RenderOverrides,
+}
+
+/// The Rhai script of `--render-script`, run before each render. Scripts
+/// have no access to files or the network, and are stopped once they take
+/// longer than `--render-script-timeout` or build values whose strings or
+/// elements take more than `--render-script-max-bytes`.
+#[derive(Debug)]
+pub(crate) struct RenderScript {
+    path: PathBuf,
+    timeout: Duration,
+    max_bytes: usize,
+    ast: RwLock<Arc<AST>>,
+}
+
+impl RenderScript {
+    /// Compiles the script of `config`, if any.
+    pub fn from_config(config: &Config) -> eyre::Result<Option<Self>> {
+        let Some(path) = config.render_script.clone() else {
+            return Ok(None);
+        };
+        let mut script = RenderScript {
+            path,
+            timeout: config.render_script_timeout.get(),
+            max_bytes: config.render_script_max_bytes.as_usize(),
+            ast: RwLock::default(),
+        };
+        let ast = script.compile().wrap_err_with(|| {
+            format!("Loading --render-script {} failed", script.path.display())
+        })?;
+        script.ast = RwLock::new(Arc::new(ast));
+        Ok(Some(script))
+    }
+
+    /// Compiles the script again, keeping the previous one if that fails.
+    pub fn reload(&self) -> Result<(), AppError> {
+        let ast = self
+            .compile()
+            .map_err(|e| AppError::Script(eyre!("The render script was not reloaded: {e:#}")))?;
+        *self.ast.write().unwrap() = Arc::new(ast);
+        Ok(())
+    }
+
+    fn compile(&self) -> eyre::Result<AST> {
+        let source = std::fs::read_to_string(&self.path)?;
+        Ok(self.engine(Instant::now()).compile(source)?)
+    }
+
+    /// An engine whose runs started at `started`. Rhai limits the bytes of
+    /// all strings in a value, and all elements of its nested arrays and
+    /// maps, which take at least a [`Dynamic`] each.
+    fn engine(&self, started: Instant) -> Engine {
+        let mut engine = Engine::new();
+        let timeout = self.timeout;
+        let max_len =
+            (self.max_bytes / std::mem::size_of::<Dynamic>()).clamp(1, MAX_COLLECTION_LEN);
+        engine
+            .set_max_string_size(self.max_bytes)
+            .set_max_array_size(max_len)
+            .set_max_map_size(max_len)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .on_progress(move |_| (started.elapsed() > timeout).then_some(Dynamic::UNIT))
+            .on_print(|text| tracing::info!("Render script: {text}"))
+            .on_debug(|text, _, position| tracing::debug!("Render script at {position}: {text}"));
+        engine
+    }
+
+    /// Runs the script on the `svg` of a render of `mac` with `options`.
+    /// Blocks for up to `--render-script-timeout`.
+    pub fn run(
+        &self,
+        mac: EpdMac,
+        svg: &str,
+        options: &RenderOptions,
+    ) -> Result<ScriptOutput, AppError> {
+        let context = Context {
+            mac: mac.to_string(),
+            profile: options.profile(),
+            options,
+            svg,
+        };
+        let mut scope = Scope::new();
+        scope.push("ctx", rhai::serde::to_dynamic(&context).internal()?);
+        let ast = self.ast.read().unwrap().clone();
+        let result = self
+            .engine(Instant::now())
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &ast)
+            .map_err(|e| match *e {
+                EvalAltResult::ErrorTerminated(..) => AppError::Script(eyre!(
+                    "The render script took longer than {:?}.",
+                    self.timeout
+                )),
+                e => AppError::Script(eyre!("The render script failed: {e}")),
+            })?;
+        output(result)
+    }
+}
+
+fn output(result: Dynamic) -> Result<ScriptOutput, AppError> {
+    if result.is_unit() {
+        return Ok(ScriptOutput::default());
+    }
+    if result.is_string() {
+        return Ok(ScriptOutput {
+            svg: Some(result.cast()),
+            ..ScriptOutput::default()
+        });
+    }
+    if !result.is_map() {
+        return Err(AppError::Script(eyre!(
+            "The render script returned a {}, not a string or a map.",
+            result.type_name()
+        )));
+    }
+    rhai::serde::from_dynamic(&result)
+        .map_err(|e| AppError::Script(eyre!("The render script returned an invalid map: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Dither;
+
+    const MAC: EpdMac = EpdMac([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+    fn script(source: &str, max_bytes: usize) -> RenderScript {
+        let script = RenderScript {
+            path: PathBuf::new(),
+            timeout: Duration::from_millis(100),
+            max_bytes,
+            ast: RwLock::default(),
+        };
+        let ast = script.engine(Instant::now()).compile(source).unwrap();
+        *script.ast.write().unwrap() = Arc::new(ast);
+        script
+    }
+
+    fn run(source: &str) -> Result<ScriptOutput, AppError> {
+        script(source, 1 << 20).run(MAC, "<rect/>", &RenderOptions::default())
+    }
+
+    #[test]
+    fn outputs() {
+        assert_eq!(run("").unwrap(), ScriptOutput::default());
+        let output = run(r#"if ctx.mac.ends_with("77") { ctx.svg + "<circle/>" }"#).unwrap();
+        assert_eq!(output.svg.as_deref(), Some("<rect/><circle/>"));
+        let output = run(r#"#{options: #{dither: "threshold"}}"#).unwrap();
+        assert_eq!(output.svg, None);
+        assert_eq!(output.options.dither, Some(Dither::Threshold));
+        let output = run(r#"ctx.options.dither + " " + ctx.profile.rotate"#).unwrap();
+        assert_eq!(output.svg.as_deref(), Some("floyd-steinberg 0"));
+
+        let e = run("42").unwrap_err();
+        assert!(matches!(e, AppError::Script(_)));
+        assert!(e.to_string().contains("returned a i64"), "{e}");
+        let e = run(r#"#{options: #{dither: "blue"}}"#).unwrap_err();
+        assert!(e.to_string().contains("invalid map"), "{e}");
+        let e = run("ctx.svg.frobnicate()").unwrap_err();
+        assert!(e.to_string().contains("frobnicate"), "{e}");
+    }
+
+    #[test]
+    fn budgets() {
+        let started = Instant::now();
+        let e = run("loop {}").unwrap_err();
+        assert!(matches!(e, AppError::Script(_)));
+        assert!(e.to_string().contains("took longer than 100ms"), "{e}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        for source in [
+            "let s = ctx.svg; loop { s += s; }",
+            // 64 elements of 16 bytes
+            "let a = []; for i in 0..100 { a.push(i); }",
+            // The strings of an array count together
+            "let s = ctx.svg; s += s; s += s; s += s; s += s; let a = []; loop { a.push(s); }",
+        ] {
+            let e = script(source, 1024)
+                .run(MAC, "<rect/>", &RenderOptions::default())
+                .unwrap_err();
+            assert!(e.to_string().contains("too large"), "{source}: {e}");
+        }
+        assert!(run("let a = []; for i in 0..100 { a.push(i); }").is_ok());
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::VecDeque,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{body::Body, middleware::Next, response::Response};
+use chrono::{DateTime, Utc};
+use eyre::eyre;
+use hyper::{
+    client::HttpConnector,
+    header::{self, HeaderName, HeaderValue},
+    Method, Request, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::Notify};
+use url::Url;
+
+use crate::{
+    artifact::{file_name, Artifact},
+    auth,
+    config::Config,
+    events::EventKind,
+    image_handler::{EpdMac, ImageHandler},
+    render_options::RenderOptions,
+    AppState,
+};
+
+/// File in the image directory with the changes not replicated yet.
+pub(crate) const REPLICATION_FILE: &str = "replication.json";
+
+/// Marks requests sent by the replication of another server, whose changes
+/// aren't forwarded again.
+pub(crate) const REPLICATED: HeaderName = HeaderName::from_static("x-eps-replicated");
+
+/// Time a request to the replica may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Wait before sending again after the replica failed, doubled with every
+/// failure in a row up to [`RETRY_MAX`].
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+tokio::task_local! {
+    /// Set while a request from another server's replication is handled.
+    static FORWARDED: ();
+}
+
+/// A change of the images of a MAC waiting to be sent to the replica. What
+/// is sent is read when it is sent, so a later change of the same MAC
+/// replaces it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Pending {
+    kind: EventKind,
+    mac: EpdMac,
+    queued: DateTime<Utc>,
+}
+
+/// Response of `GET /replication/status`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ReplicationStatus {
+    pub replica: String,
+    /// Changes not sent yet.
+    pub queue_depth: usize,
+    /// Age of the oldest change not sent yet, 0 if there is none.
+    pub lag_secs: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<DateTime<Utc>>,
+    /// Why the latest attempt failed, until one succeeds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Changes the replica refused with a client error, which are not sent
+    /// again.
+    pub rejected: u64,
+    /// Changes dropped because the queue was full.
+    pub dropped: u64,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    rejected: u64,
+    dropped: u64,
+}
+
+/// Why a change could not be sent.
+enum Failure {
+    /// Sent again after a while: the replica is unreachable or failed.
+    Retry(eyre::Error),
+    /// The replica refused it, so sending it again won't help.
+    Rejected(eyre::Error),
+}
+
+/// The changes to send to the server of `--replica-url`, in the order they
+/// happened, kept in [`REPLICATION_FILE`] across restarts.
+#[derive(Debug)]
+pub(crate) struct Replicator {
+    replica: Url,
+    key: Option<String>,
+    path: PathBuf,
+    max_queue: usize,
+    queue: Mutex<VecDeque<Pending>>,
+    progress: Mutex<Progress>,
+    /// Notified when a change is queued.
+    wake: Notify,
+    /// Serializes writes of the file.
+    saving: tokio::sync::Mutex<()>,
+    client: hyper::Client<HttpConnector>,
+}
+
+impl Replicator {
+    /// The replication of `--replica-url`, resuming the changes that weren't
+    /// sent before a restart. A file that can't be read is logged and
+    /// ignored.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let url = config.replica_url.as_ref()?;
+        let mut replica = url.clone();
+        if !replica.path().ends_with('/') {
+            replica.set_path(&format!("{}/", replica.path()));
+        }
+        let path = config.image_dir.join(REPLICATION_FILE);
+        let queue = match read(&path) {
+            Ok(queue) => queue,
+            Err(e) => {
+                tracing::error!("Ignoring replication queue {}: {e}", path.display());
+                VecDeque::new()
+            }
+        };
+        if !queue.is_empty() {
+            tracing::info!("Resuming replication of {} changes", queue.len());
+        }
+        Some(Replicator {
+            replica,
+            key: config.replica_key.clone(),
+            path,
+            max_queue: config.replica_queue_max.max(1),
+            queue: Mutex::new(queue),
+            progress: Mutex::default(),
+            wake: Notify::new(),
+            saving: tokio::sync::Mutex::new(()),
+            client: hyper::Client::new(),
+        })
+    }
+
+    /// Queues a change of the images of `mac`, unless it was made by a
+    /// request of another server's replication.
+    pub fn enqueue(&self, kind: EventKind, mac: EpdMac, now: DateTime<Utc>) {
+        if FORWARDED.try_with(|_| ()).is_ok() {
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        // The first one may be being sent
+        let mut first = true;
+        queue.retain(|pending| std::mem::take(&mut first) || pending.mac != mac);
+        while queue.len() >= self.max_queue {
+            let Some(dropped) = queue.pop_front() else {
+                break;
+            };
+            tracing::warn!(
+                "Replication queue is full, dropped the change of {}; the replica needs a full sync",
+                dropped.mac
+            );
+            self.progress.lock().unwrap().dropped += 1;
+        }
+        queue.push_back(Pending {
+            kind,
+            mac,
+            queued: now,
+        });
+        drop(queue);
+        self.wake.notify_one();
+    }
+
+    pub fn status(&self, now: DateTime<Utc>) -> ReplicationStatus {
+        let queue = self.queue.lock().unwrap();
+        let progress = self.progress.lock().unwrap();
+        ReplicationStatus {
+            replica: self.replica.to_string(),
+            queue_depth: queue.len(),
+            lag_secs: queue.front().map_or(0.0, |pending| {
+                (now - pending.queued).num_milliseconds().max(0) as f64 / 1000.0
+            }),
+            last_success: progress.last_success,
+            last_error: progress.last_error.clone(),
+            rejected: progress.rejected,
+            dropped: progress.dropped,
+        }
+    }
+
+    fn front(&self) -> Option<Pending> {
+        self.queue.lock().unwrap().front().cloned()
+    }
+
+    /// Removes `pending` once it was handled, unless it was dropped meanwhile.
+    fn done(&self, pending: &Pending) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.front() == Some(pending) {
+            queue.pop_front();
+        }
+    }
+
+    /// Keeps the error of a failed attempt, logging it unless the previous
+    /// one failed the same way.
+    fn failed(&self, error: String) {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.last_error.as_ref() != Some(&error) {
+            tracing::warn!("Replicating to {} failed: {error}", self.replica);
+        }
+        progress.last_error = Some(error);
+    }
+
+    /// Writes the queue to the file, logging failures.
+    async fn save(&self) {
+        let _saving = self.saving.lock().await;
+        let queue: Vec<_> = self.queue.lock().unwrap().iter().cloned().collect();
+        if let Err(e) = write(&self.path, &queue).await {
+            tracing::warn!("Could not save the replication queue: {e}");
+        }
+    }
+
+    /// Sends the change to the replica through its API: a render as the
+    /// posted SVG with the options it was rendered with, an upload as the
+    /// stored PNG, and a delete as a delete. Changes whose images are gone
+    /// by now are superseded by a later change and skipped.
+    async fn forward(
+        &self,
+        image_handler: &ImageHandler,
+        pending: &Pending,
+    ) -> Result<(), Failure> {
+        let mac = pending.mac;
+        let (method, path, body) = match pending.kind {
+            EventKind::Render => {
+                let posted = image_handler
+                    .posted_render(mac)
+                    .await
+                    .map_err(|e| Failure::Retry(eyre!("{e}")))?;
+                let Some((svg, options)) = posted else {
+                    return Ok(());
+                };
+                let path = format!("macs/{mac}/render_svg?{}", render_query(&options));
+                (Method::POST, path, Body::from(svg))
+            }
+            EventKind::Upload => {
+                let png = image_handler
+                    .storage()
+                    .read_optional(&file_name(mac, Artifact::Png))
+                    .await
+                    .map_err(|e| Failure::Retry(eyre!("Could not read the PNG: {e}")))?;
+                let Some(png) = png else {
+                    return Ok(());
+                };
+                (Method::POST, format!("macs/{mac}/png"), Body::from(png))
+            }
+            EventKind::Delete => (Method::DELETE, format!("macs/{mac}"), Body::empty()),
+        };
+        let url = self
+            .replica
+            .join(&path)
+            .map_err(|e| Failure::Rejected(eyre!(e)))?;
+        let mut request = Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .header(REPLICATED, HeaderValue::from_static("1"));
+        if let Some(key) = &self.key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {key}"));
+        }
+        let request = request
+            .body(body)
+            .map_err(|e| Failure::Rejected(eyre!(e)))?;
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| Failure::Retry(eyre!("The replica did not answer in time")))?
+            .map_err(|e| Failure::Retry(eyre!(e)))?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap_or_default();
+        let error = || eyre!("{status}: {}", String::from_utf8_lossy(&body).trim());
+        match status {
+            _ if status.is_success() => Ok(()),
+            // Deleted on the replica already
+            StatusCode::NOT_FOUND if pending.kind == EventKind::Delete => Ok(()),
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+                Err(Failure::Retry(error()))
+            }
+            _ if status.is_client_error() => Err(Failure::Rejected(error())),
+            _ => Err(Failure::Retry(error())),
+        }
+    }
+}
+
+/// Handles a request of another server's replication so that its changes
+/// aren't forwarded again, which would loop between two servers replicating
+/// to each other. The header is only honored from requests with the admin
+/// key or credentials, and removed from others.
+pub(crate) async fn mark_forwarded(
+    state: Arc<AppState>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if !request.headers().contains_key(REPLICATED) {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    if auth::principal(&mut parts, &state).await.is_some() {
+        return FORWARDED
+            .scope((), next.run(Request::from_parts(parts, body)))
+            .await;
+    }
+    parts.headers.remove(REPLICATED);
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Sends the changes of `image_handler` to its replica one after another,
+/// retrying with increasing waits while it fails.
+pub(crate) async fn run(image_handler: Arc<ImageHandler>) {
+    let Some(replicator) = image_handler.replicator() else {
+        return;
+    };
+    let mut retry = RETRY_MIN;
+    loop {
+        replicator.save().await;
+        let Some(pending) = replicator.front() else {
+            replicator.wake.notified().await;
+            continue;
+        };
+        match replicator.forward(&image_handler, &pending).await {
+            Ok(()) => {
+                replicator.done(&pending);
+                let mut progress = replicator.progress.lock().unwrap();
+                progress.last_success = Some(Utc::now());
+                progress.last_error = None;
+                retry = RETRY_MIN;
+            }
+            Err(Failure::Rejected(e)) => {
+                tracing::warn!(
+                    "The replica refused the {} of MAC {}, not sending it again: {e}",
+                    pending.kind.as_str(),
+                    pending.mac
+                );
+                replicator.done(&pending);
+                let mut progress = replicator.progress.lock().unwrap();
+                progress.rejected += 1;
+                progress.last_error = Some(e.to_string());
+            }
+            Err(Failure::Retry(e)) => {
+                replicator.failed(e.to_string());
+                tokio::time::sleep(retry).await;
+                retry = (retry * 2).min(RETRY_MAX);
+            }
+        }
+    }
+}
+
+/// The query of a render with all of `options`, so that the replica renders
+/// the same regardless of its defaults.
+fn render_query(options: &RenderOptions) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(options) {
+        for (name, value) in fields {
+            match value {
+                serde_json::Value::String(value) => query.append_pair(&name, &value),
+                value => query.append_pair(&name, &value.to_string()),
+            };
+        }
+    }
+    query.finish()
+}
+
+fn read(path: &Path) -> io::Result<VecDeque<Pending>> {
+    match std::fs::read(path) {
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(VecDeque::new()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn write(path: &Path, queue: &[Pending]) -> io::Result<()> {
+    let json = serde_json::to_vec(queue)?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json).await?;
+    fs::rename(&temp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+
+    #[test]
+    fn queue_per_mac() {
+        let temp_dir = TestDir::temp();
+        let config = Config::try_parse_from([
+            "eps-server",
+            "--image-dir",
+            temp_dir.root().to_str().unwrap(),
+            "--epd-width",
+            "128",
+            "--epd-height",
+            "296",
+            "--replica-url",
+            "http://standby:3000/eps",
+            "--replica-queue-max",
+            "2",
+        ])
+        .unwrap();
+        let replicator = Replicator::from_config(&config).unwrap();
+        assert_eq!(replicator.replica.as_str(), "http://standby:3000/eps/");
+        let macs: Vec<EpdMac> = ["0011223344556677", "1111111111111111", "2222222222222222"]
+            .iter()
+            .map(|mac| mac.parse().unwrap())
+            .collect();
+        let now = Utc::now();
+        replicator.enqueue(EventKind::Render, macs[0], now);
+        replicator.enqueue(EventKind::Render, macs[1], now);
+        // Replaces the render of the same MAC, but not the first change
+        replicator.enqueue(EventKind::Delete, macs[1], now);
+        replicator.enqueue(EventKind::Upload, macs[0], now);
+        let kinds = |replicator: &Replicator| -> Vec<(EventKind, EpdMac)> {
+            let queue = replicator.queue.lock().unwrap();
+            queue
+                .iter()
+                .map(|pending| (pending.kind, pending.mac))
+                .collect()
+        };
+        assert_eq!(
+            kinds(&replicator),
+            [(EventKind::Delete, macs[1]), (EventKind::Upload, macs[0])]
+        );
+        assert_eq!(replicator.status(now).dropped, 1);
+
+        FORWARDED.sync_scope((), || replicator.enqueue(EventKind::Render, macs[2], now));
+        assert_eq!(replicator.status(now).queue_depth, 2);
+
+        assert_eq!(
+            render_query(&RenderOptions::default()),
+            "color_map=preserve&dither=floyd-steinberg&flip=none&rotate=0&text=live"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::AppError,
+    image_handler::{EpdMac, ImageHandler},
+    render_queue::Priority,
+};
+
+/// Number of jobs kept for polling; the oldest are forgotten first.
+const MAX_JOBS: usize = 20;
+
+/// Progress of a batch re-render of stored SVGs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RerenderJob {
+    pub id: u64,
+    pub finished: bool,
+    /// Number of MACs the job covers.
+    pub total: usize,
+    /// Renders that changed the PNG.
+    pub done: usize,
+    /// Renders that produced the same PNG as before.
+    pub unchanged: usize,
+    pub failed: usize,
+    pub failures: Vec<RerenderFailure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RerenderFailure {
+    pub mac: String,
+    pub error: String,
+}
+
+/// Batch re-render jobs started through the admin API.
+#[derive(Default)]
+pub(crate) struct RerenderJobs {
+    jobs: Mutex<BTreeMap<u64, Arc<Mutex<RerenderJob>>>>,
+}
+
+impl RerenderJobs {
+    /// Starts re-rendering every MAC with a stored SVG whose hex string starts
+    /// with `mac_prefix` and returns the new job, only those whose PNG has
+    /// stale dimensions if `stale_only`. Failed renders are recorded in the
+    /// job and don't stop it.
+    pub async fn start(
+        &self,
+        image_handler: Arc<ImageHandler>,
+        mac_prefix: Option<&str>,
+        stale_only: bool,
+    ) -> Result<RerenderJob, AppError> {
+        let prefix = mac_prefix.unwrap_or_default().to_lowercase();
+        if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(AppError::BadRequest(eyre!(
+                "MAC prefix '{prefix}' is not hexadecimal."
+            )));
+        }
+        let macs: Vec<EpdMac> = image_handler
+            .svg_macs()
+            .await?
+            .into_iter()
+            .filter(|mac| mac.to_string().starts_with(&prefix))
+            .collect();
+        let macs = if stale_only {
+            let mut stale = Vec::with_capacity(macs.len());
+            for mac in macs {
+                if image_handler.dimensions_stale(mac).await {
+                    stale.push(mac);
+                }
+            }
+            stale
+        } else {
+            macs
+        };
+
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let id = jobs.keys().next_back().map_or(1, |id| id + 1);
+            let job = Arc::new(Mutex::new(RerenderJob {
+                id,
+                total: macs.len(),
+                finished: macs.is_empty(),
+                ..RerenderJob::default()
+            }));
+            jobs.insert(id, job.clone());
+            while jobs.len() > MAX_JOBS {
+                jobs.pop_first();
+            }
+            job
+        };
+        let status = job.lock().unwrap().clone();
+
+        tokio::spawn(async move {
+            for mac in macs {
+                let result = image_handler.rerender(mac, Priority::Batch).await;
+                let mut job = job.lock().unwrap();
+                match result {
+                    Ok(true) => job.done += 1,
+                    Ok(false) => job.unchanged += 1,
+                    Err(e) => {
+                        tracing::warn!("Re-render of {mac} failed: {e}");
+                        job.failed += 1;
+                        job.failures.push(RerenderFailure {
+                            mac: mac.to_string(),
+                            error: e.to_string(),
+                        });
+                    }
+                }
+            }
+            job.lock().unwrap().finished = true;
+        });
+
+        Ok(status)
+    }
+
+    pub fn get(&self, id: u64) -> Option<RerenderJob> {
+        let job = self.jobs.lock().unwrap().get(&id)?.clone();
+        let job = job.lock().unwrap().clone();
+        Some(job)
+    }
+}
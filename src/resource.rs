@@ -0,0 +1,196 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    handler::Handler,
+    middleware::{self, Next},
+    routing::{MethodFilter, MethodRouter},
+};
+use hyper::{header, Method, Request, StatusCode};
+
+use crate::{
+    error::AppError,
+    idempotency,
+    listener::Listener,
+    policy::{self, RouteClass},
+    AppState,
+};
+
+/// Whether requests with `method` may change the stored images.
+pub(crate) fn is_write(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// The handlers of one path. Keeps track of the methods they are registered
+/// for, so that `OPTIONS` requests are answered with the same `Allow` header
+/// as requests with unregistered methods, and guards the handlers with the
+/// [`policy`] of their route class. Handlers that `listener` doesn't serve
+/// aren't registered.
+pub(crate) struct Resource {
+    router: MethodRouter<Arc<AppState>, Body>,
+    methods: Vec<Method>,
+    state: Arc<AppState>,
+    class: Option<RouteClass>,
+    listener: Listener,
+    device: bool,
+}
+
+impl Resource {
+    /// An image resource: reads are [`RouteClass::ReadImage`], everything
+    /// else is [`RouteClass::WriteImage`].
+    pub fn new(state: &Arc<AppState>, listener: Listener) -> Self {
+        Resource {
+            router: MethodRouter::new(),
+            methods: vec![],
+            state: state.clone(),
+            class: None,
+            listener,
+            device: false,
+        }
+    }
+
+    /// A resource whose methods are all of `class`.
+    pub fn of(class: RouteClass, state: &Arc<AppState>, listener: Listener) -> Self {
+        Resource {
+            class: Some(class),
+            ..Resource::new(state, listener)
+        }
+    }
+
+    /// Marks a route of the devices, which is also served on the device
+    /// listener, see [`Listener::serves`]. Called before the handlers are
+    /// added.
+    pub fn device(self) -> Self {
+        Resource {
+            device: true,
+            ..self
+        }
+    }
+
+    pub fn get<H, T>(self, handler: H) -> Self
+    where
+        H: Handler<T, Arc<AppState>, Body>,
+        T: 'static,
+    {
+        self.on(Method::GET, handler)
+    }
+
+    pub fn post<H, T>(self, handler: H) -> Self
+    where
+        H: Handler<T, Arc<AppState>, Body>,
+        T: 'static,
+    {
+        self.on(Method::POST, handler)
+    }
+
+    pub fn put<H, T>(self, handler: H) -> Self
+    where
+        H: Handler<T, Arc<AppState>, Body>,
+        T: 'static,
+    {
+        self.on(Method::PUT, handler)
+    }
+
+    pub fn delete<H, T>(self, handler: H) -> Self
+    where
+        H: Handler<T, Arc<AppState>, Body>,
+        T: 'static,
+    {
+        self.on(Method::DELETE, handler)
+    }
+
+    fn on<H, T>(mut self, method: Method, handler: H) -> Self
+    where
+        H: Handler<T, Arc<AppState>, Body>,
+        T: 'static,
+    {
+        if self.state.image_handler.config().read_only && is_write(&method) {
+            return self;
+        }
+        let class = self.class.unwrap_or_else(|| image_class(&method));
+        if !self.listener.serves(class, self.device) {
+            return self;
+        }
+        let filter = match method {
+            Method::GET => MethodFilter::GET,
+            Method::POST => MethodFilter::POST,
+            Method::PUT => MethodFilter::PUT,
+            Method::DELETE => MethodFilter::DELETE,
+            _ => unreachable!("no handlers are registered for {method}"),
+        };
+        self.router = self.router.on(filter, handler);
+        self.methods.push(method);
+        self
+    }
+
+    /// The handlers behind the policy and the [`idempotency`] replay, plus an
+    /// `OPTIONS` handler and a fallback for other methods.
+    pub fn build(self) -> MethodRouter<Arc<AppState>, Body> {
+        let allow = allow(&self.methods);
+        let mut router = self.router;
+        if !self.methods.is_empty() {
+            let (state, class) = (self.state, self.class);
+            let idempotency_state = state.clone();
+            router = router.route_layer(middleware::from_fn(
+                move |request: Request<Body>, next: Next<Body>| {
+                    idempotency::replay(idempotency_state.clone(), request, next)
+                },
+            ));
+            router = router.route_layer(middleware::from_fn(
+                move |request: Request<Body>, next: Next<Body>| {
+                    let class = class.unwrap_or_else(|| image_class(request.method()));
+                    policy::enforce(state.clone(), class, request, next)
+                },
+            ));
+        }
+        router
+            .options(move || {
+                let allow = allow.clone();
+                async move { (StatusCode::NO_CONTENT, [(header::ALLOW, allow)]) }
+            })
+            .fallback(method_not_allowed)
+    }
+}
+
+fn image_class(method: &Method) -> RouteClass {
+    if is_write(method) {
+        RouteClass::WriteImage
+    } else {
+        RouteClass::ReadImage
+    }
+}
+
+/// The `Allow` header value for handlers of `methods`, in the order axum
+/// lists them.
+fn allow(methods: &[Method]) -> String {
+    let mut allow = vec![];
+    for method in methods {
+        allow.push(method.as_str());
+        if *method == Method::GET {
+            allow.push(Method::HEAD.as_str());
+        }
+    }
+    allow.push(Method::OPTIONS.as_str());
+    allow.join(",")
+}
+
+/// Fallback for known paths requested with an unregistered method. axum adds
+/// the `Allow` header listing the registered methods.
+async fn method_not_allowed() -> AppError {
+    AppError::MethodNotAllowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allow_follows_registration() {
+        assert_eq!(
+            allow(&[Method::GET, Method::PUT, Method::DELETE]),
+            "GET,HEAD,PUT,DELETE,OPTIONS"
+        );
+        assert_eq!(allow(&[Method::POST]), "POST,OPTIONS");
+        assert_eq!(allow(&[]), "OPTIONS");
+    }
+}
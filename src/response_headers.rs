@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+
+use eyre::{bail, ensure};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use serde::{Deserialize, Serialize};
+
+/// Prefix every custom header name must have.
+const PREFIX: &str = "x-eps-";
+/// Headers the server sets itself.
+const RESERVED: [&str; 4] = [
+    "x-eps-format",
+    "x-eps-compression",
+    "x-eps-uncompressed-length",
+    "x-eps-priority",
+];
+const MAX_HEADERS: usize = 16;
+const MAX_VALUE_LEN: usize = 256;
+
+/// Headers added to the image responses of a MAC, for devices that read
+/// settings like their poll interval from them. Set with `PUT
+/// /macs/:mac/response_headers`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ResponseHeaders(BTreeMap<String, String>);
+
+impl ResponseHeaders {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Checks that all names start with `X-EPS-` and aren't set by the
+    /// server, and that the values are short and valid. Names are
+    /// normalized to lowercase.
+    pub fn validate(self) -> eyre::Result<Self> {
+        ensure!(
+            self.0.len() <= MAX_HEADERS,
+            "At most {MAX_HEADERS} response headers are allowed"
+        );
+        let mut headers = BTreeMap::new();
+        for (name, value) in self.0 {
+            let name = name.to_ascii_lowercase();
+            if !name.starts_with(PREFIX) || HeaderName::from_bytes(name.as_bytes()).is_err() {
+                bail!("Invalid response header '{name}', names must start with X-EPS-");
+            }
+            if RESERVED.contains(&name.as_str()) {
+                bail!("The response header '{name}' is set by the server");
+            }
+            ensure!(
+                value.len() <= MAX_VALUE_LEN,
+                "The value of '{name}' is longer than {MAX_VALUE_LEN} bytes"
+            );
+            ensure!(
+                HeaderValue::from_str(&value).is_ok(),
+                "Invalid value of '{name}'"
+            );
+            headers.insert(name, value);
+        }
+        Ok(ResponseHeaders(headers))
+    }
+
+    /// Adds the headers to `headers`, keeping those already set.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.0 {
+            let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) else {
+                continue;
+            };
+            headers.entry(name).or_insert(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> ResponseHeaders {
+        ResponseHeaders(
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn validation() {
+        let valid = headers(&[("X-EPS-Poll-Interval", "900"), ("x-eps-beep", "1")])
+            .validate()
+            .unwrap();
+        assert_eq!(
+            valid,
+            headers(&[("x-eps-beep", "1"), ("x-eps-poll-interval", "900")])
+        );
+
+        for invalid in [
+            headers(&[("Content-Type", "text/plain")]),
+            headers(&[("ETag", "\"1\"")]),
+            headers(&[("X-EPS-Format", "png")]),
+            headers(&[("X-EPS-Bad Name", "1")]),
+            headers(&[("X-EPS-Newline", "a\nb")]),
+            headers(&[("X-EPS-Long", &"a".repeat(MAX_VALUE_LEN + 1))]),
+        ] {
+            assert!(invalid.clone().validate().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn apply_keeps_server_headers() {
+        let mut response = HeaderMap::new();
+        response.insert("x-eps-beep", HeaderValue::from_static("0"));
+        headers(&[("x-eps-beep", "1"), ("x-eps-poll-interval", "900")]).apply(&mut response);
+        assert_eq!(response["x-eps-beep"], "0");
+        assert_eq!(response["x-eps-poll-interval"], "900");
+    }
+}
@@ -0,0 +1,194 @@
+#[cfg(any(test, feature = "client"))]
+use eyre::{bail, ensure};
+
+/// Marks that the length of a run continues in the next byte.
+const CONTINUATION: u8 = 0xff;
+
+/// Run-length encodes a framebuffer of 1 bit per pixel, read MSB first, like
+/// the raw format.
+///
+/// The output are the lengths of the alternating runs of set (white) and
+/// clear (black) bits, starting with white, so a framebuffer starting with
+/// black begins with an empty run. Each length is written as one byte below
+/// 0xFF, preceded by a 0xFF for every 255 bits of it: a run of 300 bits is
+/// `FF 2D`, one of 255 bits `FF 00`. The last run ends with the framebuffer,
+/// whose length has to be known to decode it.
+///
+/// At worst every bit is a run of its own, so the output has at most
+/// `8 * data.len() + 1` bytes.
+pub(crate) fn encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut white = true;
+    let mut run = 0;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            if (byte >> bit & 1 == 1) == white {
+                run += 1;
+            } else {
+                push_run(&mut encoded, run);
+                white = !white;
+                run = 1;
+            }
+        }
+    }
+    if run > 0 {
+        push_run(&mut encoded, run);
+    }
+    encoded
+}
+
+fn push_run(encoded: &mut Vec<u8>, mut run: usize) {
+    while run >= usize::from(CONTINUATION) {
+        encoded.push(CONTINUATION);
+        run -= usize::from(CONTINUATION);
+    }
+    encoded.push(run as u8);
+}
+
+/// Decodes the output of [`encode`] back into a framebuffer of `len` bytes.
+/// Fails if the runs don't add up to exactly that.
+#[cfg(any(test, feature = "client"))]
+pub(crate) fn decode(encoded: &[u8], len: usize) -> eyre::Result<Vec<u8>> {
+    let bits = len * 8;
+    let mut data = vec![0; len];
+    let mut position = 0;
+    let mut white = true;
+    let mut run = 0;
+    for &byte in encoded {
+        run += usize::from(byte);
+        if byte == CONTINUATION {
+            continue;
+        }
+        ensure!(
+            position + run <= bits,
+            "The runs exceed the {len} bytes of the framebuffer"
+        );
+        if white {
+            for bit in position..position + run {
+                data[bit / 8] |= 0x80 >> (bit % 8);
+            }
+        }
+        position += run;
+        white = !white;
+        run = 0;
+    }
+    if run > 0 {
+        bail!("The last run is truncated");
+    }
+    ensure!(
+        position == bits,
+        "The runs cover {position} of the {bits} bits of the framebuffer"
+    );
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+    use crate::raster;
+
+    /// Deterministic pseudo-random bytes, see xorshift.
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.max(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(encode(&[]), Vec::<u8>::new());
+        assert_eq!(encode(&[0xff; 2]), [16]);
+        // Starts with an empty white run
+        assert_eq!(encode(&[0x00, 0xff]), [0, 8, 8]);
+        assert_eq!(encode(&[0xf0]), [4, 4]);
+        assert_eq!(encode(&[0x0f]), [0, 4, 4]);
+        // 256 bits
+        assert_eq!(encode(&[0xff; 32]), [0xff, 1]);
+        // Exactly 255 bits need a terminating zero
+        let mut data = vec![0xff; 32];
+        data[31] = 0xfe;
+        assert_eq!(encode(&data), [0xff, 0, 1]);
+        // 300 bits
+        let mut data = vec![0xff; 38];
+        data[37] = 0xf0;
+        assert_eq!(encode(&data), [0xff, 45, 4]);
+    }
+
+    #[test]
+    fn round_trip_random() {
+        for seed in 1..200 {
+            let len = (seed as usize * 7) % 300;
+            let mut data = random_bytes(seed, len);
+            // From noise to long runs
+            let density = seed % 4;
+            for (i, byte) in data.iter_mut().enumerate() {
+                match density {
+                    1 => *byte |= 0xf0,
+                    2 if i % 16 != 0 => *byte = 0xff,
+                    3 if i % 64 != 0 => *byte = 0x00,
+                    _ => {}
+                }
+            }
+            let encoded = encode(&data);
+            assert!(encoded.len() <= 8 * data.len() + 1, "seed {seed}");
+            assert_eq!(decode(&encoded, data.len()).unwrap(), data, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn worst_case_expansion() {
+        for len in [1, 2, 100, 6000] {
+            // Every bit is a run of one
+            let encoded = encode(&vec![0xaa; len]);
+            assert_eq!(encoded.len(), 8 * len);
+            let encoded = encode(&vec![0x55; len]);
+            assert_eq!(encoded.len(), 8 * len + 1);
+            assert_eq!(decode(&encoded, len).unwrap(), vec![0x55; len]);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_mismatched_length() {
+        let encoded = encode(&[0x0f, 0xf0]);
+        assert!(decode(&encoded, 1).is_err());
+        assert!(decode(&encoded, 3).is_err());
+        assert!(decode(&[0xff], 32).is_err());
+        assert_eq!(decode(&encoded, 2).unwrap(), [0x0f, 0xf0]);
+    }
+
+    #[test]
+    fn mostly_white_label_compresses() {
+        // 48 KB framebuffer with lines of word-sized black blocks
+        let mut image = GrayImage::from_pixel(800, 480, Luma([0xff]));
+        for line in 0..12 {
+            let top = 40 + line * 36;
+            for word in 0..10 {
+                let left = 40 + word * 72 + line % 3 * 4;
+                for y in top..top + 14 {
+                    for x in left..left + 48 {
+                        image.put_pixel(x, y, Luma([0]));
+                    }
+                }
+            }
+        }
+        let raw = raster::pack_1bpp(&image);
+        assert_eq!(raw.len(), 48000);
+
+        let encoded = encode(&raw);
+        assert!(
+            encoded.len() * 5 < raw.len(),
+            "{} of {} bytes",
+            encoded.len(),
+            raw.len()
+        );
+        assert_eq!(decode(&encoded, raw.len()).unwrap(), raw);
+    }
+}
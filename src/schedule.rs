@@ -0,0 +1,229 @@
+use std::{fmt::Display, str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Days, NaiveTime, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+use eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::image_handler::ImageHandler;
+
+const NOW_PLACEHOLDER: &str = "{{now";
+
+/// When a time-dependent render is repeated, in the render's time zone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum Schedule {
+    /// Every day at the given local time, written as `daily@HH:MM`.
+    Daily(NaiveTime),
+    /// Every hour at the given minute, written as `hourly@MM`.
+    Hourly(u32),
+}
+
+impl Default for Schedule {
+    /// Used for renders containing placeholders but no explicit schedule.
+    fn default() -> Self {
+        Self::Daily(NaiveTime::MIN)
+    }
+}
+
+impl Schedule {
+    /// Returns the first scheduled point in time strictly after `after`.
+    pub fn next_after(&self, after: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        let local = after.with_timezone(&tz).naive_local();
+        let candidates = (0..=2).filter_map(|day| local.date().checked_add_days(Days::new(day)));
+        let candidates: Vec<_> = match *self {
+            Self::Daily(time) => candidates.map(|date| date.and_time(time)).collect(),
+            Self::Hourly(minute) => candidates
+                .flat_map(|date| (0..24).filter_map(move |hour| date.and_hms_opt(hour, minute, 0)))
+                .collect(),
+        };
+        candidates
+            .into_iter()
+            // Local times skipped by a DST change resolve to no instant and
+            // are left out
+            .filter_map(|naive| tz.from_local_datetime(&naive).earliest())
+            .map(|dt| dt.with_timezone(&Utc))
+            .find(|dt| *dt > after)
+            .unwrap_or_else(|| after + chrono::Duration::days(1))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('@') {
+            Some(("daily", time)) => Ok(Self::Daily(
+                NaiveTime::parse_from_str(time, "%H:%M")
+                    .wrap_err_with(|| format!("Invalid time in schedule {s}"))?,
+            )),
+            Some(("hourly", minute)) => match minute.parse() {
+                Ok(minute) if minute < 60 => Ok(Self::Hourly(minute)),
+                _ => Err(eyre!("Invalid minute in schedule {s}")),
+            },
+            _ => Err(eyre!("Schedule must be daily@HH:MM or hourly@MM, got {s}")),
+        }
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Daily(time) => write!(f, "daily@{:02}:{:02}", time.hour(), time.minute()),
+            Self::Hourly(minute) => write!(f, "hourly@{minute:02}"),
+        }
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = eyre::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(value: Schedule) -> Self {
+        value.to_string()
+    }
+}
+
+/// Replaces every `{{now "<strftime format>"}}` in `text` with `now` formatted
+/// accordingly and XML-escaped. Returns `None` if there are no placeholders.
+pub(crate) fn substitute_now(text: &str, now: DateTime<Tz>) -> eyre::Result<Option<String>> {
+    if !text.contains(NOW_PLACEHOLDER) {
+        return Ok(None);
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(NOW_PLACEHOLDER) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + NOW_PLACEHOLDER.len()..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| eyre!("Unterminated {NOW_PLACEHOLDER} placeholder"))?;
+        let format = after[..end]
+            .trim()
+            .strip_prefix('"')
+            .and_then(|f| f.strip_suffix('"'))
+            .ok_or_else(|| eyre!("{NOW_PLACEHOLDER} expects a quoted format string"))?;
+
+        let items: Vec<_> = StrftimeItems::new(format).collect();
+        if items.iter().any(|item| matches!(item, Item::Error)) {
+            return Err(eyre!("Invalid time format \"{format}\""));
+        }
+        let formatted = now.format_with_items(items.into_iter()).to_string();
+        for c in formatted.chars() {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                c => out.push(c),
+            }
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(Some(out))
+}
+
+/// Periodically re-renders all time-dependent images that are due and
+/// switches playlists to their active entries, or shows a broadcast in
+/// progress on new MACs.
+pub(crate) async fn run(image_handler: Arc<ImageHandler>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match image_handler.extend_broadcast().await {
+            Ok(0) => {}
+            Ok(n) => tracing::info!("Showed the broadcast on {n} new MACs"),
+            Err(e) => tracing::error!("Could not extend the broadcast: {e:#}"),
+        }
+        match image_handler.run_due_rerenders().await {
+            Ok(0) => {}
+            Ok(n) => tracing::debug!("Re-rendered {n} scheduled images"),
+            Err(e) => tracing::error!("Could not run scheduled renders: {e:#}"),
+        }
+        match image_handler.run_playlists().await {
+            Ok(0) => {}
+            Ok(n) => tracing::debug!("Rendered {n} playlist entries"),
+            Err(e) => tracing::error!("Could not run playlists: {e:#}"),
+        }
+        match image_handler.run_due_activations().await {
+            Ok(0) => {}
+            Ok(n) => tracing::info!("Activated the staged images of {n} MACs"),
+            Err(e) => tracing::error!("Could not activate staged images: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::Europe::Berlin;
+
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn schedule_round_trip() {
+        for s in ["daily@00:05", "daily@23:59", "hourly@00", "hourly@30"] {
+            assert_eq!(s.parse::<Schedule>().unwrap().to_string(), s);
+        }
+        for s in ["daily@24:00", "hourly@60", "weekly@00:00", "daily"] {
+            assert!(s.parse::<Schedule>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn schedule_next_after() {
+        let daily: Schedule = "daily@00:05".parse().unwrap();
+        assert_eq!(
+            daily.next_after(utc("2024-03-12T10:00:00Z"), Tz::UTC),
+            utc("2024-03-13T00:05:00Z")
+        );
+        assert_eq!(
+            daily.next_after(utc("2024-03-12T00:04:00Z"), Tz::UTC),
+            utc("2024-03-12T00:05:00Z")
+        );
+        assert_eq!(
+            daily.next_after(utc("2024-03-12T10:00:00Z"), Berlin),
+            utc("2024-03-12T23:05:00Z")
+        );
+
+        let hourly: Schedule = "hourly@15".parse().unwrap();
+        assert_eq!(
+            hourly.next_after(utc("2024-03-12T10:15:00Z"), Tz::UTC),
+            utc("2024-03-12T11:15:00Z")
+        );
+    }
+
+    #[test]
+    fn substitute_now_placeholders() {
+        let now = utc("2024-03-12T10:00:00Z").with_timezone(&Tz::UTC);
+        assert_eq!(substitute_now("<text>plain</text>", now).unwrap(), None);
+        assert_eq!(
+            substitute_now(
+                r#"<text>Today: {{now "%A %d %B"}} <{{now "%H:%M"}}></text>"#,
+                now
+            )
+            .unwrap()
+            .unwrap(),
+            "<text>Today: Tuesday 12 March <10:00></text>"
+        );
+        assert_eq!(
+            substitute_now(r#"{{now "<%Y>"}}"#, now).unwrap().unwrap(),
+            "&lt;2024&gt;"
+        );
+        assert!(substitute_now(r#"{{now "%Q"}}"#, now).is_err());
+        assert!(substitute_now(r#"{{now %Y}}"#, now).is_err());
+        assert!(substitute_now(r#"{{now "%Y""#, now).is_err());
+    }
+}
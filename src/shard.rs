@@ -0,0 +1,194 @@
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::fs;
+
+/// Characters of the MAC per shard directory level.
+const SHARD_CHARS: usize = 2;
+const MAC_CHARS: usize = 16;
+
+/// Where the file `name` lives in the image directory with `depth` levels of
+/// shard directories, like `00/11/0011223344556677.png` for a depth of 2.
+/// Files that don't belong to a MAC, or whose name starts with a `.` like
+/// temporary ones, stay in the top directory.
+pub(crate) fn relative_path(name: &str, depth: u8) -> PathBuf {
+    let mut path = PathBuf::new();
+    let stem = name.split('.').next().unwrap_or_default();
+    if stem.len() == MAC_CHARS && stem.bytes().all(|b| b.is_ascii_hexdigit()) {
+        for level in 0..usize::from(depth) {
+            path.push(&stem[level * SHARD_CHARS..(level + 1) * SHARD_CHARS]);
+        }
+    }
+    path.push(name);
+    path
+}
+
+fn is_shard_dir(name: &OsString) -> bool {
+    name.to_str().is_some_and(|name| {
+        name.len() == SHARD_CHARS && name.bytes().all(|b| b.is_ascii_hexdigit())
+    })
+}
+
+/// Names of the files in `dir` and in its shard directories up to `depth`
+/// levels deep. Entries that could not be read are returned as errors.
+pub(crate) async fn list(dir: &Path, depth: u8) -> io::Result<Vec<io::Result<OsString>>> {
+    let paths = list_paths(dir, depth).await?;
+    Ok(paths
+        .into_iter()
+        .map(|path| path.map(|path| path.file_name().unwrap_or_default().to_owned()))
+        .collect())
+}
+
+/// Like [`list`], with the paths of the files.
+pub(crate) async fn list_paths(dir: &Path, depth: u8) -> io::Result<Vec<io::Result<PathBuf>>> {
+    let mut names = Vec::new();
+    let mut dirs = vec![(dir.to_owned(), 0)];
+    while let Some((dir, level)) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if level == 0 => return Err(e),
+            Err(e) => {
+                names.push(Err(e));
+                continue;
+            }
+        };
+        loop {
+            let entry = match entries.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    names.push(Err(e));
+                    continue;
+                }
+            };
+            let name = entry.file_name();
+            if level < depth && is_shard_dir(&name) {
+                match entry.file_type().await {
+                    Ok(file_type) if file_type.is_dir() => {
+                        dirs.push((entry.path(), level + 1));
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        names.push(Err(e));
+                        continue;
+                    }
+                }
+            }
+            names.push(Ok(entry.path()));
+        }
+    }
+    Ok(names)
+}
+
+/// Moves the files of MACs in the top directory of `dir` into shard
+/// directories `depth` levels deep. Files already moved are skipped, so an
+/// interrupted migration is resumed by running it again. A file in the top
+/// directory that also exists in its shard is older, as writes go to the
+/// shard, and is removed. Returns how many files were moved.
+pub(crate) async fn migrate(dir: &Path, depth: u8) -> io::Result<usize> {
+    let mut names = vec![];
+    for name in list(dir, 0).await? {
+        let name = name?;
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let target = relative_path(name, depth);
+        if !name.starts_with('.') && target.parent().is_some_and(|p| p != Path::new("")) {
+            names.push((name.to_owned(), target));
+        }
+    }
+
+    let total = names.len();
+    tracing::info!("Moving {total} files into shard directories");
+    let mut moved = 0;
+    for (i, (name, target)) in names.into_iter().enumerate() {
+        let source = dir.join(&name);
+        let target = dir.join(target);
+        if fs::metadata(&target).await.is_ok() {
+            fs::remove_file(&source).await?;
+        } else {
+            fs::create_dir_all(target.parent().unwrap()).await?;
+            fs::rename(&source, &target).await?;
+            moved += 1;
+        }
+        let done = i + 1;
+        if done * 10 / total != i * 10 / total {
+            tracing::info!("Shard migration {}% done", done * 100 / total);
+        }
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use test_dir::{DirBuilder, FileType, TestDir};
+
+    use super::*;
+
+    #[test]
+    fn paths_by_depth() {
+        let name = "0011223344556677.png";
+        assert_eq!(relative_path(name, 0), Path::new(name));
+        assert_eq!(relative_path(name, 1), Path::new("00").join(name));
+        assert_eq!(relative_path(name, 2), Path::new("00/11").join(name));
+        assert_eq!(
+            relative_path("aabbccddeeffaabb.meta.json", 1),
+            Path::new("aa/aabbccddeeffaabb.meta.json")
+        );
+        for name in [
+            "audit.log",
+            "not-hex-name.png",
+            ".0011223344556677.png.1.tmp",
+        ] {
+            assert_eq!(relative_path(name, 2), Path::new(name));
+        }
+    }
+
+    #[tokio::test]
+    async fn migrate_flat_dir() {
+        let temp_dir = TestDir::temp()
+            .create("0011223344556677.png", FileType::ZeroFile(10))
+            .create("0011223344556677.svg", FileType::ZeroFile(10))
+            .create("aabbccddeeffaabb.png", FileType::ZeroFile(10))
+            .create("not-hex-name.png", FileType::ZeroFile(10))
+            .create("aa", FileType::Dir)
+            .create("aa/aabbccddeeffaabb.svg", FileType::ZeroFile(10));
+        let dir = temp_dir.path("");
+
+        assert_eq!(migrate(&dir, 2).await.unwrap(), 3);
+        for path in [
+            "00/11/0011223344556677.png",
+            "00/11/0011223344556677.svg",
+            "aa/bb/aabbccddeeffaabb.png",
+            "not-hex-name.png",
+        ] {
+            assert!(temp_dir.path(path).exists(), "{path}");
+        }
+        assert!(!temp_dir.path("0011223344556677.png").exists());
+
+        // Resuming finds nothing left to move
+        assert_eq!(migrate(&dir, 2).await.unwrap(), 0);
+
+        let mut names: Vec<_> = list(&dir, 2)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|name| name.unwrap().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "0011223344556677.png",
+                "0011223344556677.svg",
+                "aabbccddeeffaabb.png",
+                "aabbccddeeffaabb.svg",
+                "not-hex-name.png"
+            ]
+        );
+    }
+}
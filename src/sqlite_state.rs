@@ -0,0 +1,232 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::async_trait;
+use eyre::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::{sync::Mutex, task};
+
+use crate::{
+    image_handler::EpdMac,
+    metadata::MacMetadata,
+    state::{StateStore, Update},
+    storage::FileMeta,
+};
+
+/// Name of the database in the image directory.
+pub(crate) const STATE_DB: &str = "state.sqlite3";
+
+/// Metadata of all MACs in one SQLite database, see
+/// `--state-backend sqlite`. Statements run on blocking threads, as they may
+/// wait up to the busy timeout for another process holding the database;
+/// the lock serializes them.
+#[derive(Debug)]
+pub(crate) struct SqliteState {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteState {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        let connection = Connection::open(path)
+            .wrap_err_with(|| format!("Opening {} failed", path.display()))?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS mac_state (
+                mac TEXT PRIMARY KEY,
+                meta TEXT NOT NULL,
+                revision INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+        )?;
+        Ok(SqliteState {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs `statements` on a blocking thread once the connection is free.
+    async fn run<T: Send + 'static>(
+        &self,
+        statements: impl FnOnce(&Connection) -> eyre::Result<T> + Send + 'static,
+    ) -> eyre::Result<T> {
+        let connection = self.connection.clone().lock_owned().await;
+        task::spawn_blocking(move || {
+            roll_back_abandoned(&connection)?;
+            statements(&connection)
+        })
+        .await?
+    }
+}
+
+fn read(connection: &Connection, mac: EpdMac) -> eyre::Result<MacMetadata> {
+    let meta: Option<String> = connection
+        .query_row(
+            "SELECT meta FROM mac_state WHERE mac = ?1",
+            [mac.to_string()],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match meta {
+        Some(meta) => Ok(serde_json::from_str(&meta)?),
+        None => Ok(MacMetadata::default()),
+    }
+}
+
+fn write(connection: &Connection, mac: EpdMac, meta: &MacMetadata) -> eyre::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as i64;
+    connection.execute(
+        "INSERT INTO mac_state (mac, meta, revision, updated_at) VALUES (?1, ?2, 1, ?3)
+        ON CONFLICT (mac) DO UPDATE
+        SET meta = excluded.meta, revision = revision + 1, updated_at = excluded.updated_at",
+        params![mac.to_string(), serde_json::to_string(meta)?, now],
+    )?;
+    Ok(())
+}
+
+/// Rolls back the transaction of an update that was cancelled or panicked
+/// before it committed.
+fn roll_back_abandoned(connection: &Connection) -> eyre::Result<()> {
+    if !connection.is_autocommit() {
+        connection.execute_batch("ROLLBACK")?;
+    }
+    Ok(())
+}
+
+/// Runs `statements` in the transaction begun by [`StateStore::update`],
+/// rolling it back if they fail.
+fn in_transaction<T>(
+    connection: &Connection,
+    statements: impl FnOnce() -> eyre::Result<T>,
+) -> eyre::Result<T> {
+    let result = statements();
+    if result.is_err() {
+        if let Err(e) = connection.execute_batch("ROLLBACK") {
+            tracing::warn!("Rolling back a metadata update failed: {e}");
+        }
+    }
+    result
+}
+
+#[async_trait]
+impl StateStore for SqliteState {
+    async fn load(&self, mac: EpdMac) -> eyre::Result<MacMetadata> {
+        self.run(move |connection| read(connection, mac)).await
+    }
+
+    async fn store(&self, mac: EpdMac, meta: &MacMetadata) -> eyre::Result<()> {
+        let meta = meta.clone();
+        self.run(move |connection| write(connection, mac, &meta))
+            .await
+    }
+
+    /// Reads and writes in one transaction, so concurrent updates of the
+    /// same MAC never lose each other's changes. The connection stays locked
+    /// while `update` runs on the calling task in between.
+    async fn update(&self, mac: EpdMac, update: Update<'_>) -> eyre::Result<()> {
+        let connection = self.connection.clone().lock_owned().await;
+        let (connection, meta) = task::spawn_blocking(move || {
+            let meta = (|| {
+                roll_back_abandoned(&connection)?;
+                connection.execute_batch("BEGIN IMMEDIATE")?;
+                in_transaction(&connection, || read(&connection, mac))
+            })();
+            (connection, meta)
+        })
+        .await?;
+        let mut meta = meta?;
+        update(&mut meta);
+        task::spawn_blocking(move || {
+            in_transaction(&connection, || {
+                write(&connection, mac, &meta)?;
+                Ok(connection.execute_batch("COMMIT")?)
+            })
+        })
+        .await?
+    }
+
+    async fn remove(&self, mac: EpdMac) -> eyre::Result<bool> {
+        self.run(move |connection| {
+            let removed =
+                connection.execute("DELETE FROM mac_state WHERE mac = ?1", [mac.to_string()])?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn macs(&self) -> eyre::Result<Vec<EpdMac>> {
+        self.run(|connection| {
+            let mut statement = connection.prepare("SELECT mac FROM mac_state")?;
+            let macs = statement
+                .query_map([], |row| row.get::<_, String>(0))?
+                .filter_map(|mac| mac.ok()?.parse().ok())
+                .collect();
+            Ok(macs)
+        })
+        .await
+    }
+
+    async fn version(&self, mac: EpdMac) -> eyre::Result<Option<FileMeta>> {
+        let version = self
+            .run(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT length(meta), revision, updated_at FROM mac_state WHERE mac = ?1",
+                        [mac.to_string()],
+                        |row| {
+                            Ok((
+                                row.get::<_, i64>(0)?,
+                                row.get::<_, i64>(1)?,
+                                row.get::<_, i64>(2)?,
+                            ))
+                        },
+                    )
+                    .optional()?)
+            })
+            .await?;
+        Ok(version.map(|(len, revision, updated_at)| FileMeta {
+            len: len as u64,
+            modified: UNIX_EPOCH + Duration::from_nanos(updated_at as u64),
+            tag: Some(format!("{revision:x}-{updated_at:x}")),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+    use crate::state;
+
+    #[tokio::test]
+    async fn sqlite_state() {
+        let temp_dir = TestDir::temp();
+        state::tests::suite(&SqliteState::open(&temp_dir.path(STATE_DB)).unwrap()).await;
+
+        // Kept across restarts
+        let reopened = SqliteState::open(&temp_dir.path(STATE_DB)).unwrap();
+        assert_eq!(reopened.macs().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn panicked_update() {
+        let temp_dir = TestDir::temp();
+        let state = Arc::new(SqliteState::open(&temp_dir.path(STATE_DB)).unwrap());
+        let mac: EpdMac = "0011223344556677".parse().unwrap();
+        let panicked = tokio::spawn({
+            let state = state.clone();
+            async move { state.update(mac, Box::new(|_| panic!("update"))).await }
+        });
+        assert!(panicked.await.is_err());
+
+        state.store(mac, &MacMetadata::default()).await.unwrap();
+        let reopened = SqliteState::open(&temp_dir.path(STATE_DB)).unwrap();
+        assert_eq!(reopened.macs().await.unwrap(), [mac]);
+    }
+}
@@ -0,0 +1,257 @@
+use std::{fmt::Debug, io, sync::Arc};
+
+use axum::async_trait;
+use clap::ValueEnum;
+
+use crate::{
+    artifact::{self, Artifact},
+    config::Config,
+    image_handler::EpdMac,
+    metadata::MacMetadata,
+    storage::{FileMeta, Storage},
+};
+
+/// See `--state-backend`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub(crate) enum StateBackend {
+    /// A `<mac>.meta.json` file next to the images of each MAC
+    Files,
+    /// One SQLite database in the image directory
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+/// A change of the metadata of a MAC, see [`StateStore::update`].
+pub(crate) type Update<'a> = Box<dyn FnOnce(&mut MacMetadata) + Send + 'a>;
+
+/// Where the [`MacMetadata`] of the MACs is kept. Images are always files of
+/// the [`Storage`].
+#[async_trait]
+pub(crate) trait StateStore: Debug + Send + Sync {
+    /// The metadata of `mac`, empty if none was stored.
+    async fn load(&self, mac: EpdMac) -> eyre::Result<MacMetadata>;
+
+    /// Replaces the metadata of `mac`.
+    async fn store(&self, mac: EpdMac, meta: &MacMetadata) -> eyre::Result<()>;
+
+    /// Applies `update` to the metadata of `mac` and stores the result.
+    async fn update(&self, mac: EpdMac, update: Update<'_>) -> eyre::Result<()> {
+        let mut meta = self.load(mac).await?;
+        update(&mut meta);
+        self.store(mac, &meta).await
+    }
+
+    /// Removes the metadata of `mac`, returning whether there was any.
+    async fn remove(&self, mac: EpdMac) -> eyre::Result<bool>;
+
+    /// MACs with stored metadata, in no particular order.
+    async fn macs(&self) -> eyre::Result<Vec<EpdMac>>;
+
+    /// Size and time of the last change of the metadata of `mac`, for the
+    /// validators of responses including it.
+    async fn version(&self, mac: EpdMac) -> eyre::Result<Option<FileMeta>>;
+}
+
+/// Metadata in `<mac>.meta.json` files of the [`Storage`].
+#[derive(Debug, Clone)]
+pub(crate) struct FileState {
+    storage: Storage,
+}
+
+impl FileState {
+    pub fn new(storage: Storage) -> Self {
+        FileState { storage }
+    }
+}
+
+fn meta_name(mac: EpdMac) -> String {
+    artifact::file_name(mac, Artifact::Meta)
+}
+
+#[async_trait]
+impl StateStore for FileState {
+    async fn load(&self, mac: EpdMac) -> eyre::Result<MacMetadata> {
+        MacMetadata::load(&self.storage, &meta_name(mac)).await
+    }
+
+    async fn store(&self, mac: EpdMac, meta: &MacMetadata) -> eyre::Result<()> {
+        meta.store(&self.storage, &meta_name(mac)).await
+    }
+
+    async fn remove(&self, mac: EpdMac) -> eyre::Result<bool> {
+        Ok(self.storage.remove_set(&[&meta_name(mac)]).await? > 0)
+    }
+
+    async fn macs(&self) -> eyre::Result<Vec<EpdMac>> {
+        Ok(self
+            .storage
+            .list()
+            .await?
+            .into_iter()
+            .flatten()
+            .filter_map(|name| match artifact::parse(name.to_str()?)? {
+                (mac, Artifact::Meta) => Some(mac),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn version(&self, mac: EpdMac) -> eyre::Result<Option<FileMeta>> {
+        match self.storage.metadata(&meta_name(mac)).await {
+            Ok(meta) => Ok(Some(meta)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The store selected by `--state-backend`, keeping metadata files in
+/// `storage`.
+pub(crate) fn from_config(config: &Config, storage: &Storage) -> eyre::Result<Arc<dyn StateStore>> {
+    open(config.state_backend, config, storage)
+}
+
+/// The store of the backend that `--migrate-state` imports from: the one
+/// not selected by `--state-backend`.
+#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+pub(crate) fn migration_source(
+    config: &Config,
+    storage: &Storage,
+) -> eyre::Result<Arc<dyn StateStore>> {
+    match config.state_backend {
+        #[cfg(feature = "sqlite")]
+        StateBackend::Files => open(StateBackend::Sqlite, config, storage),
+        #[cfg(feature = "sqlite")]
+        StateBackend::Sqlite => open(StateBackend::Files, config, storage),
+        #[cfg(not(feature = "sqlite"))]
+        StateBackend::Files => Err(eyre::eyre!(
+            "--migrate-state needs another state backend, build with the sqlite feature"
+        )),
+    }
+}
+
+#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+fn open(
+    backend: StateBackend,
+    config: &Config,
+    storage: &Storage,
+) -> eyre::Result<Arc<dyn StateStore>> {
+    match backend {
+        StateBackend::Files => Ok(Arc::new(FileState::new(storage.clone()))),
+        #[cfg(feature = "sqlite")]
+        StateBackend::Sqlite => {
+            let path = config.image_dir.join(crate::sqlite_state::STATE_DB);
+            Ok(Arc::new(crate::sqlite_state::SqliteState::open(&path)?))
+        }
+    }
+}
+
+/// Copies the metadata of all MACs in `from` into `to`, replacing what `to`
+/// has for them, and returns how many were copied. `from` is left as it
+/// is, so an interrupted migration is resumed by running it again.
+pub(crate) async fn migrate(from: &dyn StateStore, to: &dyn StateStore) -> eyre::Result<usize> {
+    let macs = from.macs().await?;
+    for &mac in &macs {
+        to.store(mac, &from.load(mac).await?).await?;
+    }
+    Ok(macs.len())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeSet;
+
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+
+    fn mac(n: u8) -> EpdMac {
+        format!("00112233445566{n:02x}").parse().unwrap()
+    }
+
+    fn groups(names: &[&str]) -> BTreeSet<crate::groups::GroupName> {
+        names.iter().map(|name| name.parse().unwrap()).collect()
+    }
+
+    /// The behavior every [`StateStore`] shares, starting out empty.
+    pub(crate) async fn suite(store: &dyn StateStore) {
+        assert_eq!(store.load(mac(1)).await.unwrap(), MacMetadata::default());
+        assert!(store.macs().await.unwrap().is_empty());
+        assert!(store.version(mac(1)).await.unwrap().is_none());
+        assert!(!store.remove(mac(1)).await.unwrap());
+
+        let meta = MacMetadata {
+            groups: groups(&["kitchen"]),
+            ..MacMetadata::default()
+        };
+        store.store(mac(1), &meta).await.unwrap();
+        assert_eq!(store.load(mac(1)).await.unwrap(), meta);
+        let stored = store.version(mac(1)).await.unwrap().unwrap();
+
+        store
+            .update(
+                mac(1),
+                Box::new(|meta| {
+                    meta.groups.insert("hall".parse().unwrap());
+                }),
+            )
+            .await
+            .unwrap();
+        store
+            .update(mac(2), Box::new(|meta| meta.groups = groups(&["hall"])))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.load(mac(1)).await.unwrap().groups,
+            groups(&["hall", "kitchen"])
+        );
+        assert_ne!(store.version(mac(1)).await.unwrap().unwrap(), stored);
+        let mut macs = store.macs().await.unwrap();
+        macs.sort();
+        assert_eq!(macs, [mac(1), mac(2)]);
+
+        assert!(store.remove(mac(1)).await.unwrap());
+        assert_eq!(store.load(mac(1)).await.unwrap(), MacMetadata::default());
+        assert!(store.version(mac(1)).await.unwrap().is_none());
+        assert_eq!(store.macs().await.unwrap(), [mac(2)]);
+    }
+
+    #[tokio::test]
+    async fn file_state() {
+        let temp_dir = TestDir::temp();
+        suite(&FileState::new(Storage::new(temp_dir.path("")))).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn migrate_round_trip() {
+        use crate::sqlite_state::SqliteState;
+
+        let files_dir = TestDir::temp();
+        let files = FileState::new(Storage::new(files_dir.path("")));
+        let metas: Vec<_> = ["kitchen", "hall"]
+            .iter()
+            .map(|group| MacMetadata {
+                groups: groups(&[group]),
+                ..MacMetadata::default()
+            })
+            .collect();
+        for (n, meta) in metas.iter().enumerate() {
+            files.store(mac(n as u8), meta).await.unwrap();
+        }
+
+        let db_dir = TestDir::temp();
+        let sqlite = SqliteState::open(&db_dir.path("state.sqlite3")).unwrap();
+        assert_eq!(migrate(&files, &sqlite).await.unwrap(), 2);
+        // Resuming copies the same again
+        assert_eq!(migrate(&files, &sqlite).await.unwrap(), 2);
+
+        let back_dir = TestDir::temp();
+        let back = FileState::new(Storage::new(back_dir.path("")));
+        assert_eq!(migrate(&sqlite, &back).await.unwrap(), 2);
+        for (n, meta) in metas.iter().enumerate() {
+            assert_eq!(&sqlite.load(mac(n as u8)).await.unwrap(), meta);
+            assert_eq!(&back.load(mac(n as u8)).await.unwrap(), meta);
+        }
+    }
+}
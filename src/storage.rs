@@ -0,0 +1,523 @@
+use std::{
+    ffi::OsString,
+    fmt::Debug,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use axum::{async_trait, body::Bytes};
+use eyre::Context;
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    config::Config, dedup::DedupStore, object_storage::ObjectStorage, outbound::Outbound, shard,
+};
+
+/// Distinguishes temporary files of concurrent writes to the same name.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Contents of a stored file, streamed in chunks.
+pub(crate) type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Size and modification time of a stored file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileMeta {
+    pub len: u64,
+    pub modified: SystemTime,
+    /// Entity tag assigned by the store, if it has them.
+    pub tag: Option<String>,
+}
+
+/// Where the files of the image directory are kept. Names never contain a
+/// `/`. All methods keep the `io::ErrorKind` of the failing operation so
+/// callers can tell missing from inaccessible files.
+#[async_trait]
+pub(crate) trait ImageStore: Debug + Send + Sync {
+    /// Streams `name` in chunks of about `chunk_size` bytes.
+    async fn get(&self, name: &str, chunk_size: usize) -> io::Result<(ByteStream, FileMeta)>;
+
+    async fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        let (stream, meta) = self.get(name, 64 * 1024).await?;
+        stream
+            .try_fold(
+                Vec::with_capacity(meta.len as usize),
+                |mut data, chunk| async move {
+                    data.extend_from_slice(&chunk);
+                    Ok(data)
+                },
+            )
+            .await
+    }
+
+    /// Replaces `name` with `data`. Readers see either the old or the new
+    /// content, never a partially written file.
+    async fn put(&self, name: &str, data: Bytes) -> io::Result<()>;
+
+    /// Removes `name`, failing with `NotFound` if it doesn't exist.
+    async fn delete(&self, name: &str) -> io::Result<()>;
+
+    /// Names of all files. Entries that could not be read are returned as
+    /// errors so callers can skip them.
+    async fn list(&self) -> io::Result<Vec<io::Result<OsString>>>;
+
+    async fn stat(&self, name: &str) -> io::Result<FileMeta>;
+}
+
+/// Files of the image directory, kept by an [`ImageStore`].
+#[derive(Debug, Clone)]
+pub(crate) struct Storage {
+    store: Arc<dyn ImageStore>,
+    /// Local directory for temporary files.
+    temp_dir: PathBuf,
+    /// The same store if images are deduplicated.
+    dedup: Option<Arc<DedupStore>>,
+}
+
+impl Storage {
+    /// Files in the local directory `dir`.
+    #[cfg(test)]
+    pub fn new(dir: PathBuf) -> Self {
+        Self::sharded(dir, 0)
+    }
+
+    /// Files in the local directory `dir`, in shard directories `depth`
+    /// levels deep, see [`shard::relative_path`].
+    pub fn sharded(dir: PathBuf, depth: u8) -> Self {
+        Storage {
+            store: Arc::new(LocalStore::new(dir.clone(), depth)),
+            temp_dir: dir,
+            dedup: None,
+        }
+    }
+
+    /// Like [`Storage::sharded`], with images kept once per content, see
+    /// `--dedup`.
+    pub fn deduplicated(dir: PathBuf, depth: u8) -> Self {
+        let dedup = Arc::new(DedupStore::new(dir.clone(), depth));
+        Storage {
+            store: dedup.clone(),
+            temp_dir: dir,
+            dedup: Some(dedup),
+        }
+    }
+
+    pub fn with_store(store: Arc<dyn ImageStore>, temp_dir: PathBuf) -> Self {
+        Storage {
+            store,
+            temp_dir,
+            dedup: None,
+        }
+    }
+
+    /// The object store given by `--storage`, otherwise the image directory.
+    pub fn from_config(config: &Config) -> eyre::Result<Self> {
+        if config.dedup {
+            return Ok(Self::deduplicated(
+                config.image_dir.clone(),
+                config.shard_depth,
+            ));
+        }
+        match &config.storage {
+            Some(url) => {
+                let store = ObjectStorage::from_url(url, &Outbound::from_config(config))
+                    .wrap_err_with(|| format!("Invalid storage URL {url}"))?;
+                Ok(Self::with_store(Arc::new(store), std::env::temp_dir()))
+            }
+            None => Ok(Self::sharded(config.image_dir.clone(), config.shard_depth)),
+        }
+    }
+
+    /// Opens `name` for streaming together with its metadata.
+    pub async fn open_with_meta(
+        &self,
+        name: &str,
+        chunk_size: usize,
+    ) -> io::Result<(ByteStream, FileMeta)> {
+        self.store.get(name, chunk_size.max(1)).await
+    }
+
+    pub async fn metadata(&self, name: &str) -> io::Result<FileMeta> {
+        self.store.stat(name).await
+    }
+
+    pub async fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        self.store.read(name).await
+    }
+
+    /// Like [`Storage::read`], but a missing file is `None`.
+    pub async fn read_optional(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.read(name).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes all of `names` and returns how many of them existed. All files
+    /// are attempted even if one fails; the first error other than a missing
+    /// file is returned.
+    pub async fn remove_set(&self, names: &[&str]) -> io::Result<usize> {
+        let mut removed = 0;
+        let mut error = None;
+        for name in names {
+            match self.store.delete(name).await {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(removed),
+        }
+    }
+
+    /// Replaces `name` with `data` so readers never see a partially written
+    /// file.
+    pub async fn write_atomic(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.store.put(name, Bytes::copy_from_slice(data)).await
+    }
+
+    /// Creates an empty local temporary file for `name` that is removed again
+    /// when it is dropped.
+    pub async fn create_temp(&self, name: &str) -> io::Result<TempFile> {
+        let path = self.temp_dir.join(temp_name(name));
+        let file = File::create(&path).await?;
+        Ok(TempFile { path, file })
+    }
+
+    /// Names of all stored files. Entries that could not be read are
+    /// returned as errors so callers can skip them.
+    pub async fn list(&self) -> io::Result<Vec<io::Result<OsString>>> {
+        self.store.list().await
+    }
+
+    /// The store of `--dedup`, if it is set.
+    pub fn dedup(&self) -> Option<&DedupStore> {
+        self.dedup.as_deref()
+    }
+}
+
+pub(crate) fn temp_name(name: &str) -> String {
+    format!(
+        ".{name}.{}.tmp",
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Files in a local directory, the default store. With shard directories,
+/// files not moved into them yet are still found in the top directory.
+#[derive(Debug)]
+pub(crate) struct LocalStore {
+    dir: PathBuf,
+    shard_depth: u8,
+}
+
+impl LocalStore {
+    pub fn new(dir: PathBuf, shard_depth: u8) -> Self {
+        LocalStore { dir, shard_depth }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(shard::relative_path(name, self.shard_depth))
+    }
+
+    /// Runs `op` on the path of `name`, and on its path in the top directory
+    /// if it isn't found in its shard.
+    pub async fn with_fallback<T, F, Fut>(&self, name: &str, op: F) -> io::Result<T>
+    where
+        F: Fn(PathBuf) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let path = self.path(name);
+        let flat = self.dir.join(name);
+        match op(path.clone()).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && path != flat => op(flat).await,
+            result => result,
+        }
+    }
+}
+
+pub(crate) fn file_meta(meta: &std::fs::Metadata) -> io::Result<FileMeta> {
+    Ok(FileMeta {
+        len: meta.len(),
+        modified: meta.modified()?,
+        tag: None,
+    })
+}
+
+#[async_trait]
+impl ImageStore for LocalStore {
+    async fn get(&self, name: &str, chunk_size: usize) -> io::Result<(ByteStream, FileMeta)> {
+        let file = self.with_fallback(name, File::open).await?;
+        let meta = file_meta(&file.metadata().await?)?;
+        Ok((ReaderStream::with_capacity(file, chunk_size).boxed(), meta))
+    }
+
+    async fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        self.with_fallback(name, fs::read).await
+    }
+
+    /// Writes a temporary file and renames it. The temporary file is removed
+    /// if any step fails.
+    async fn put(&self, name: &str, data: Bytes) -> io::Result<()> {
+        let path = self.path(name);
+        let temp_path = path.with_file_name(temp_name(name));
+        let result = async {
+            if let Some(shard) = path.parent().filter(|_| self.shard_depth > 0) {
+                fs::create_dir_all(shard).await?;
+            }
+            fs::write(&temp_path, &data).await?;
+            fs::rename(&temp_path, &path).await
+        }
+        .await;
+        if result.is_err() {
+            if let Err(e) = fs::remove_file(&temp_path).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!("Could not remove {}: {e}", temp_path.display());
+                }
+            }
+        }
+        result
+    }
+
+    /// Also removes a leftover in the top directory.
+    async fn delete(&self, name: &str) -> io::Result<()> {
+        let result = fs::remove_file(self.path(name)).await;
+        if self.shard_depth == 0 {
+            return result;
+        }
+        match (result, fs::remove_file(self.dir.join(name)).await) {
+            (Err(e), _) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            (Ok(()), _) => Ok(()),
+            (Err(_), flat) => flat,
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<io::Result<OsString>>> {
+        shard::list(&self.dir, self.shard_depth).await
+    }
+
+    async fn stat(&self, name: &str) -> io::Result<FileMeta> {
+        file_meta(&self.with_fallback(name, fs::metadata).await?)
+    }
+}
+
+/// A file of the image directory that only lives as long as this value.
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data).await
+    }
+
+    /// Makes everything written so far visible to other processes.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+
+    /// Reads back everything written so far.
+    pub async fn read_all(mut self) -> io::Result<Vec<u8>> {
+        self.file.flush().await?;
+        fs::read(&self.path).await
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("Could not remove {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Checks the operations every [`ImageStore`] supports on a `storage` with
+/// the files `a.png` of 10 bytes and `b.svg` of 20 bytes.
+#[cfg(test)]
+pub(crate) async fn check_store(storage: &Storage) {
+    let (stream, meta) = storage.open_with_meta("b.svg", 8).await.unwrap();
+    assert_eq!(meta.len, 20);
+    let chunks: Vec<_> = stream.map(|chunk| chunk.unwrap().len()).collect().await;
+    assert_eq!(chunks.iter().sum::<usize>(), 20);
+    let Err(e) = storage.open_with_meta("missing.svg", 8).await else {
+        panic!("missing.svg was opened");
+    };
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+    assert_eq!(storage.metadata("a.png").await.unwrap().len, 10);
+    let e = storage.metadata("missing.png").await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+    assert_eq!(
+        storage.read_optional("a.png").await.unwrap().unwrap().len(),
+        10
+    );
+    assert_eq!(storage.read_optional("missing.png").await.unwrap(), None);
+
+    let mut names: Vec<_> = storage
+        .list()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["a.png", "b.svg"]);
+
+    storage.write_atomic("a.png", b"new").await.unwrap();
+    assert_eq!(storage.read("a.png").await.unwrap(), b"new");
+    assert_eq!(storage.metadata("a.png").await.unwrap().len, 3);
+
+    let removed = storage
+        .remove_set(&["a.png", "b.svg", "missing.bmp"])
+        .await
+        .unwrap();
+    assert_eq!(removed, 2);
+    assert!(storage.list().await.unwrap().is_empty());
+    assert_eq!(storage.remove_set(&["a.png"]).await.unwrap(), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use test_dir::{DirBuilder, FileType, TestDir};
+
+    use super::*;
+
+    fn storage() -> (TestDir, Storage) {
+        let temp_dir = TestDir::temp()
+            .create("a.png", FileType::RandomFile(10))
+            .create("b.svg", FileType::RandomFile(20));
+        let storage = Storage::new(temp_dir.root().to_owned());
+        (temp_dir, storage)
+    }
+
+    #[tokio::test]
+    async fn local_store() {
+        let (_temp_dir, storage) = storage();
+        check_store(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn remove_set() {
+        let (temp_dir, storage) = storage();
+
+        let removed = storage
+            .remove_set(&["a.png", "b.svg", "missing.bmp"])
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+        assert!(!temp_dir.path("a.png").exists());
+        assert_eq!(storage.remove_set(&["a.png"]).await.unwrap(), 0);
+
+        // A directory can't be removed as a file, but the others still are
+        let temp_dir = temp_dir
+            .create("dir", FileType::Dir)
+            .create("c.png", FileType::EmptyFile);
+        let e = storage.remove_set(&["dir", "c.png"]).await.unwrap_err();
+        assert_ne!(e.kind(), io::ErrorKind::NotFound);
+        assert!(!temp_dir.path("c.png").exists());
+    }
+
+    #[tokio::test]
+    async fn sharded_store() {
+        let temp_dir = TestDir::temp()
+            .create("a.png", FileType::RandomFile(10))
+            .create("b.svg", FileType::RandomFile(20));
+        let storage = Storage::sharded(temp_dir.root().to_owned(), 1);
+        check_store(&storage).await;
+
+        // Not moved into its shard yet
+        let temp_dir = temp_dir.create("0011223344556677.png", FileType::RandomFile(5));
+
+        storage
+            .write_atomic("aabbccddeeffaabb.svg", b"<svg/>")
+            .await
+            .unwrap();
+        assert!(temp_dir.path("aa/aabbccddeeffaabb.svg").exists());
+        assert_eq!(storage.read("0011223344556677.png").await.unwrap().len(), 5);
+        let mut names: Vec<_> = storage
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["0011223344556677.png", "aabbccddeeffaabb.svg"]);
+
+        let removed = storage
+            .remove_set(&["0011223344556677.png", "aabbccddeeffaabb.svg"])
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn write_atomic() {
+        let (temp_dir, storage) = storage();
+
+        storage.write_atomic("a.png", b"new").await.unwrap();
+        assert_eq!(storage.read("a.png").await.unwrap(), b"new");
+
+        // Renaming over a non-empty directory fails after the temporary file
+        // has been written
+        let temp_dir = temp_dir
+            .create("dir", FileType::Dir)
+            .create("dir/inner", FileType::EmptyFile);
+        storage.write_atomic("dir", b"data").await.unwrap_err();
+
+        let names: Vec<_> = storage
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert!(
+            names
+                .iter()
+                .all(|name| !name.to_string_lossy().ends_with(".tmp")),
+            "{names:?}"
+        );
+        assert!(temp_dir.path("dir/inner").exists());
+    }
+
+    #[tokio::test]
+    async fn temp_file() {
+        let (temp_dir, storage) = storage();
+
+        let mut file = storage.create_temp("upload").await.unwrap();
+        file.write_all(b"first ").await.unwrap();
+        file.write_all(b"second").await.unwrap();
+        assert_eq!(file.read_all().await.unwrap(), b"first second");
+
+        let file = storage.create_temp("upload").await.unwrap();
+        assert!(file.path.starts_with(temp_dir.root()));
+        let path = file.path.clone();
+        drop(file);
+        assert!(!path.exists());
+        assert_eq!(storage.list().await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime,
+};
+use chrono_tz::Tz;
+
+use crate::image_handler::EpdMac;
+
+/// Hex digits of the MAC in `{{MAC_SHORT}}`.
+const MAC_SHORT_LEN: usize = 4;
+
+/// What the tokens of a posted SVG are replaced with when it is rendered for
+/// a MAC with `?substitute=true`.
+#[derive(Debug, Clone)]
+pub(crate) struct Context<'a> {
+    pub mac: EpdMac,
+    pub rendered_at: DateTime<Tz>,
+    /// strftime format of `{{RENDERED_AT}}`, see `--rendered-at-format`.
+    pub time_format: &'a str,
+    /// Size of the canvas of the profile of the MAC.
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Context<'_> {
+    fn value(&self, token: &str) -> Option<String> {
+        let mac = self.mac.to_uppercase_string();
+        Some(match token {
+            "MAC" => mac,
+            "MAC_SHORT" => mac[mac.len() - MAC_SHORT_LEN..].to_owned(),
+            "RENDERED_AT" => self.rendered_at.format(self.time_format).to_string(),
+            "WIDTH" => self.width.to_string(),
+            "HEIGHT" => self.height.to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// Replaces the tokens `{{MAC}}`, `{{MAC_SHORT}}`, `{{RENDERED_AT}}`,
+/// `{{WIDTH}}` and `{{HEIGHT}}` in `text` with their XML-escaped values.
+/// Anything else in braces is kept as it is.
+pub(crate) fn substitute(text: &str, context: &Context) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after
+            .find("}}")
+            .and_then(|end| Some((end, context.value(&after[..end])?)));
+        match value {
+            Some((end, value)) => {
+                push_escaped(&mut out, &value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn push_escaped(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Parses a strftime format like `%Y-%m-%d %H:%M`, rejecting unknown
+/// specifiers.
+pub(crate) fn time_format(s: &str) -> Result<String, String> {
+    if StrftimeItems::new(s).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("Invalid time format \"{s}\""));
+    }
+    Ok(s.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let context = Context {
+            mac: "00112233445566aa".parse().unwrap(),
+            rendered_at: Tz::Europe__Berlin
+                .with_ymd_and_hms(2024, 3, 1, 8, 5, 0)
+                .unwrap(),
+            time_format: "%d.%m. %H:%M <%Z>",
+            width: 128,
+            height: 296,
+        };
+        assert_eq!(
+            substitute(
+                "{{MAC}} {{MAC_SHORT}} {{RENDERED_AT}} {{WIDTH}}x{{HEIGHT}}",
+                &context
+            ),
+            "00112233445566AA 66AA 01.03. 08:05 &lt;CET&gt; 128x296"
+        );
+        for kept in ["{{mac}}", "{{NAME}} {", "{{{MAC}", "{{MAC", "}}{{"] {
+            assert_eq!(substitute(kept, &context), kept);
+        }
+        assert_eq!(substitute("{{{{MAC}}}}", &context), "{{00112233445566AA}}");
+    }
+
+    #[test]
+    fn parse_time_format() {
+        assert!(time_format("%Y-%m-%d %H:%M").is_ok());
+        assert!(time_format("%Q").is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use std::fmt::Write;
+
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+/// Namespace prefixes of editor-only elements and attributes.
+const EDITOR_PREFIXES: [&str; 2] = ["sodipodi", "inkscape"];
+
+/// Attributes whose numbers are coordinates or lengths and may be rounded.
+const COORDINATE_ATTRIBUTES: [&str; 15] = [
+    "d", "points", "x", "y", "x1", "y1", "x2", "y2", "cx", "cy", "r", "rx", "ry", "width", "height",
+];
+
+/// Shrinks an SVG fragment without changing how it renders: comments, editor
+/// metadata and whitespace between elements are removed and coordinates are
+/// rounded to `precision` decimals.
+pub(crate) fn optimize(fragment: &str, precision: u8) -> eyre::Result<String> {
+    let mut out = String::with_capacity(fragment.len());
+    // Open elements, used to close them again and to tell if text is inside
+    // a `text` element where whitespace matters
+    let mut open: Vec<(&str, &str)> = vec![];
+    // Depth inside an element that is dropped together with its children
+    let mut skip = 0usize;
+    // Whether the last element start was dropped, so are its attributes
+    let mut dropped_start = false;
+
+    for token in Tokenizer::from_fragment(fragment, 0..fragment.len()) {
+        match token? {
+            Token::ElementStart { prefix, local, .. } => {
+                if skip > 0 || is_editor_element(prefix.as_str(), local.as_str()) {
+                    skip += 1;
+                    dropped_start = true;
+                    continue;
+                }
+                dropped_start = false;
+                out.push('<');
+                push_name(&mut out, prefix.as_str(), local.as_str());
+                open.push((prefix.as_str(), local.as_str()));
+            }
+            Token::Attribute {
+                prefix,
+                local,
+                value,
+                ..
+            } => {
+                let (prefix, local, value) = (prefix.as_str(), local.as_str(), value.as_str());
+                if dropped_start
+                    || EDITOR_PREFIXES.contains(&prefix)
+                    || (prefix == "xmlns" && EDITOR_PREFIXES.contains(&local))
+                {
+                    continue;
+                }
+                let value = if prefix.is_empty() && is_roundable(local, value) {
+                    round_numbers(value, precision)
+                } else {
+                    value.to_owned()
+                };
+                let quote = if value.contains('"') { '\'' } else { '"' };
+                out.push(' ');
+                push_name(&mut out, prefix, local);
+                write!(out, "={quote}{value}{quote}")?;
+            }
+            Token::ElementEnd { end, .. } => {
+                if skip > 0 {
+                    if !matches!(end, ElementEnd::Open) {
+                        skip -= 1;
+                    }
+                    continue;
+                }
+                match end {
+                    ElementEnd::Open => out.push('>'),
+                    ElementEnd::Empty => {
+                        out.push_str("/>");
+                        open.pop();
+                    }
+                    ElementEnd::Close(prefix, local) => {
+                        out.push_str("</");
+                        push_name(&mut out, prefix.as_str(), local.as_str());
+                        out.push('>');
+                        open.pop();
+                    }
+                }
+            }
+            Token::Text { text } => {
+                if skip > 0 {
+                    continue;
+                }
+                let in_text = open.iter().any(|&(_, local)| local == "text");
+                let text = text.as_str();
+                if text.trim().is_empty() {
+                    // Spaces between `tspan`s separate words
+                    if in_text && !text.is_empty() {
+                        out.push(' ');
+                    }
+                } else {
+                    collapse_whitespace(&mut out, text);
+                }
+            }
+            Token::Comment { .. } => {}
+            token => {
+                if skip == 0 {
+                    out.push_str(token.span().as_str());
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn is_editor_element(prefix: &str, local: &str) -> bool {
+    EDITOR_PREFIXES.contains(&prefix) || (prefix.is_empty() && local == "metadata")
+}
+
+/// Path arcs are left alone: their flags may be written without separators,
+/// which the number scanner would misread.
+fn is_roundable(local: &str, value: &str) -> bool {
+    COORDINATE_ATTRIBUTES.contains(&local) && !(local == "d" && value.contains(['a', 'A']))
+}
+
+fn push_name(out: &mut String, prefix: &str, local: &str) {
+    if !prefix.is_empty() {
+        out.push_str(prefix);
+        out.push(':');
+    }
+    out.push_str(local);
+}
+
+fn collapse_whitespace(out: &mut String, text: &str) {
+    let mut last_space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !last_space {
+                out.push(' ');
+            }
+            last_space = true;
+        } else {
+            out.push(c);
+            last_space = false;
+        }
+    }
+}
+
+/// Rounds every decimal number in `value` and keeps everything else as is.
+/// Integers are copied verbatim.
+fn round_numbers(value: &str, precision: u8) -> String {
+    let bytes = value.as_bytes();
+    let mut out = String::with_capacity(value.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let Some(end) = number_end(bytes, i) else {
+            let c = value[i..].chars().next().unwrap_or_default();
+            out.push(c);
+            i += c.len_utf8();
+            continue;
+        };
+        let number = &value[i..end];
+        let rounded = match number.parse::<f64>() {
+            Ok(n) if number.contains(['.', 'e', 'E']) => {
+                let rounded = format_rounded(n, precision);
+                if rounded.len() < number.len() {
+                    rounded
+                } else {
+                    number.to_owned()
+                }
+            }
+            _ => number.to_owned(),
+        };
+        // Numbers may follow each other without a separator, e.g. `1.5.5`
+        if out.ends_with(|c: char| c.is_ascii_digit() || c == '.') && !rounded.starts_with('-') {
+            out.push(' ');
+        }
+        out.push_str(&rounded);
+        i = end;
+    }
+    out
+}
+
+/// Returns the end of the number starting at `start`, if there is one.
+fn number_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let digits = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+
+    let mut i = start;
+    if matches!(bytes.get(i), Some(b'+' | b'-')) {
+        i += 1;
+    }
+    let int_end = digits(i);
+    let mut end = int_end;
+    if bytes.get(end) == Some(&b'.') {
+        end = digits(end + 1);
+        if end == int_end + 1 && int_end == i {
+            // A lone `.`
+            return None;
+        }
+    } else if int_end == i {
+        return None;
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let mut exp = end + 1;
+        if matches!(bytes.get(exp), Some(b'+' | b'-')) {
+            exp += 1;
+        }
+        let exp_end = digits(exp);
+        if exp_end > exp {
+            end = exp_end;
+        }
+    }
+    Some(end)
+}
+
+fn format_rounded(n: f64, precision: u8) -> String {
+    let mut s = format!("{:.*}", usize::from(precision), n);
+    if s.contains('.') {
+        let trimmed = s.trim_end_matches('0').trim_end_matches('.').len();
+        s.truncate(trimmed);
+    }
+    if s == "-0" {
+        s.remove(0);
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const BLOATED: &str = r##"<!-- Created with Inkscape (http://www.inkscape.org/) -->
+<svg
+   xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape"
+   xmlns:sodipodi="http://sodipodi.sourceforge.net/DTD/sodipodi-0.dtd"
+   xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"
+   xmlns:dc="http://purl.org/dc/elements/1.1/"
+   inkscape:version="1.2.1 (9c6d41e410, 2022-07-14)"
+   sodipodi:docname="badge.svg">
+  <sodipodi:namedview
+     id="namedview7"
+     pagecolor="#ffffff"
+     bordercolor="#666666"
+     inkscape:pageshadow="2"
+     inkscape:zoom="2.8284271247461903"
+     inkscape:cx="64.00000000000001"
+     inkscape:cy="148.00000000000003"
+     inkscape:window-width="1920"
+     inkscape:window-height="1016"
+     inkscape:current-layer="layer1" />
+  <metadata
+     id="metadata5">
+    <rdf:RDF>
+      <dc:title>Badge</dc:title>
+      <dc:creator>Someone</dc:creator>
+    </rdf:RDF>
+  </metadata>
+  <!-- Layer 1 -->
+  <g
+     inkscape:label="Layer 1"
+     inkscape:groupmode="layer"
+     id="layer1">
+    <rect
+       style="fill:#000000;stroke:none"
+       id="rect1"
+       width="100.00000190734863"
+       height="40.000000953674316"
+       x="13.999999046325684"
+       y="20.000000476837158"
+       inkscape:label="header" />
+    <circle
+       style="fill:none;stroke:#000000;stroke-width:4"
+       id="circle1"
+       cx="64.00000095367432"
+       cy="148.00000286102295"
+       r="40.00000190734863" />
+    <path
+       style="fill:#000000"
+       d="M 20.000000298023224,240.00000476837158 L 108.00000071525574,240.00000476837158 L 64.00000095367432,280.0000047683716 Z"
+       id="path1"
+       sodipodi:nodetypes="cccc" />
+    <polyline
+       style="fill:none;stroke:#000000;stroke-width:2"
+       points="10.000000149011612,100.00000149011612 30.000000447034836,80.00000119209290 50.000000745058060,100.00000149011612"
+       id="polyline1" />
+  </g>
+</svg>
+"##;
+
+    fn render(fragment: &str) -> tiny_skia::Pixmap {
+        let mut buf = vec![];
+        write!(
+            buf,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 128 296\">{fragment}</svg>"
+        )
+        .unwrap();
+        let rtree = usvg::Tree::from_data(&buf, &usvg::Options::default().to_ref()).unwrap();
+        let size = rtree.svg_node().size.to_screen_size();
+        let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height()).unwrap();
+        resvg::render(
+            &rtree,
+            usvg::FitTo::Original,
+            tiny_skia::Transform::default(),
+            pixmap.as_mut(),
+        )
+        .unwrap();
+        pixmap
+    }
+
+    #[test]
+    fn optimize_renders_the_same() {
+        let optimized = optimize(BLOATED, 3).unwrap();
+        assert!(
+            optimized.len() * 2 < BLOATED.len(),
+            "{} -> {}",
+            BLOATED.len(),
+            optimized.len()
+        );
+        for stripped in ["<!--", "metadata", "sodipodi", "inkscape", "\n", "  "] {
+            assert!(!optimized.contains(stripped), "{stripped:?} in {optimized}");
+        }
+
+        let before = render(BLOATED);
+        let after = render(&optimized);
+        let max_diff = before
+            .data()
+            .iter()
+            .zip(after.data())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap();
+        assert!(max_diff <= 2, "max channel difference {max_diff}");
+    }
+
+    #[test]
+    fn optimize_keeps_text_spacing() {
+        let optimized = optimize(
+            "<text x=\"1.00001\">\n  <tspan>a</tspan>\n  <tspan>b  c</tspan>\n</text>",
+            3,
+        )
+        .unwrap();
+        assert_eq!(
+            optimized,
+            "<text x=\"1\"> <tspan>a</tspan> <tspan>b c</tspan> </text>"
+        );
+    }
+
+    #[test]
+    fn round_numbers_separates_and_skips_integers() {
+        assert_eq!(round_numbers("M1.5.7 L-0.0001,3e-7", 0), "M2 1 L0,0");
+        assert_eq!(round_numbers("10.26,20 0110", 1), "10.3,20 0110");
+        assert_eq!(round_numbers("50.000001%", 3), "50%");
+    }
+
+    #[test]
+    fn arcs_are_not_rounded() {
+        let path = "<path d=\"M0 0a1.12345 1.12345 0 011.5 1.5\"/>";
+        assert_eq!(optimize(path, 2).unwrap(), path);
+    }
+}
@@ -0,0 +1,69 @@
+use std::io::{Read, Write};
+
+use eyre::{bail, Context};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::encoding;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Largest SVG accepted after decompression, the same as axum's default limit
+/// for uncompressed request bodies.
+pub(crate) const MAX_SVG_BYTES: usize = 2 * 1024 * 1024;
+
+/// Whether `data` starts like a gzip stream.
+pub(crate) fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// Returns the SVG text of a request body, decompressing it first if it is
+/// gzip compressed (`.svgz`) and converting it from UTF-16, see
+/// [`encoding::decode`].
+pub(crate) fn decode_body(body: &[u8]) -> eyre::Result<String> {
+    let data = if is_gzip(body) {
+        let mut data = Vec::new();
+        // Read one byte more than allowed to detect oversized content
+        GzDecoder::new(body)
+            .take(MAX_SVG_BYTES as u64 + 1)
+            .read_to_end(&mut data)
+            .wrap_err("Invalid gzip stream")?;
+        if data.len() > MAX_SVG_BYTES {
+            bail!("Decompressed SVG is larger than {MAX_SVG_BYTES} bytes");
+        }
+        data
+    } else {
+        body.to_vec()
+    };
+    encoding::decode(data)
+}
+
+pub(crate) fn compress(data: &[u8]) -> eyre::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_body_plain_and_compressed() {
+        let svg = "<circle r=\"1\"/>";
+        assert_eq!(decode_body(svg.as_bytes()).unwrap(), svg);
+        assert_eq!(
+            decode_body(&compress(svg.as_bytes()).unwrap()).unwrap(),
+            svg
+        );
+    }
+
+    #[test]
+    fn decode_body_invalid() {
+        let mut truncated = compress(b"<circle r=\"1\"/>").unwrap();
+        truncated.truncate(8);
+        assert!(decode_body(&truncated).is_err());
+
+        let bomb = compress(&vec![b' '; MAX_SVG_BYTES + 1]).unwrap();
+        assert!(decode_body(&bomb).is_err());
+    }
+}
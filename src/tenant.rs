@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use eyre::{bail, eyre};
+use hyper::{header, Request, Uri};
+use tower::{util::BoxCloneService, ServiceExt};
+
+use crate::{auth::constant_time_eq, error::AppError};
+
+const MAX_TENANT_NAME_LEN: usize = 64;
+/// Prefix of the routes of a tenant, followed by its name.
+const PREFIX: &str = "/t/";
+/// Subdirectory of the image directory with a directory per tenant, apart
+/// from the shard directories and state of the default tenant.
+pub(crate) const TENANTS_DIR: &str = "tenants";
+
+/// Name of a tenant, used as its subdirectory of [`TENANTS_DIR`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct TenantName(String);
+
+impl FromStr for TenantName {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > MAX_TENANT_NAME_LEN {
+            bail!("Tenant name must have 1 to {MAX_TENANT_NAME_LEN} characters, got '{s}'");
+        }
+        if !s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Tenant name may only contain ASCII letters, digits, '-' and '_', got '{s}'");
+        }
+        Ok(TenantName(s.to_owned()))
+    }
+}
+
+impl Display for TenantName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<std::path::Path> for TenantName {
+    fn as_ref(&self) -> &std::path::Path {
+        self.0.as_ref()
+    }
+}
+
+/// A tenant and its API key, as given by `--tenant <name>=<key>`.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct TenantKey {
+    pub name: TenantName,
+    pub key: String,
+}
+
+// Keeps the key out of the logged configuration
+impl std::fmt::Debug for TenantKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TenantKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for TenantKey {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, key)) = s.split_once('=') else {
+            bail!("Expected <name>=<key>, got '{s}'");
+        };
+        if key.is_empty() {
+            bail!("The key of tenant '{name}' is empty");
+        }
+        Ok(TenantKey {
+            name: name.parse()?,
+            key: key.to_owned(),
+        })
+    }
+}
+
+/// The routes of one tenant.
+pub(crate) type TenantService = BoxCloneService<Request<Body>, Response, Infallible>;
+
+/// The routes of the tenants by name, each behind its key.
+#[derive(Default)]
+pub(crate) struct Tenants {
+    // Cloned for each request; the lock only makes them `Sync`
+    routes: HashMap<TenantName, (String, Mutex<TenantService>)>,
+}
+
+impl Tenants {
+    pub fn insert(&mut self, tenant: &TenantKey, service: TenantService) {
+        self.routes.insert(
+            tenant.name.clone(),
+            (tenant.key.clone(), Mutex::new(service)),
+        );
+    }
+}
+
+/// Passes requests below `/t/<name>/` to the routes of that tenant with the
+/// prefix removed, if they carry its key as `Authorization: Bearer <key>`.
+/// Unknown tenants are not found. Other requests go to the routes of the
+/// default tenant.
+pub(crate) async fn dispatch(
+    tenants: Arc<Tenants>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(rest) = request.uri().path().strip_prefix(PREFIX) else {
+        return next.run(request).await;
+    };
+    let (name, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let tenant = name
+        .parse::<TenantName>()
+        .ok()
+        .and_then(|name| tenants.routes.get(&name));
+    let Some((key, service)) = tenant else {
+        return AppError::NotFound(eyre!("There is no tenant '{name}'.")).into_response();
+    };
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "));
+    if !token.is_some_and(|token| constant_time_eq(token.as_bytes(), key.as_bytes())) {
+        return AppError::Unauthorized(eyre!("The key of tenant '{name}' is required."))
+            .into_response();
+    }
+
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_owned(),
+    };
+    match path_and_query.parse::<Uri>() {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(e) => return AppError::BadRequest(eyre!(e)).into_response(),
+    }
+    let service = service.lock().unwrap().clone();
+    match service.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tenant_key() {
+        let tenant: TenantKey = "bakery=s3cret=".parse().unwrap();
+        assert_eq!(tenant.name.to_string(), "bakery");
+        assert_eq!(tenant.key, "s3cret=");
+        assert!(!format!("{tenant:?}").contains("s3cret"));
+
+        for invalid in ["bakery", "bakery=", "=key", "../up=key", "a b=key"] {
+            assert!(invalid.parse::<TenantKey>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use axum::body::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+
+struct Progress<S> {
+    stream: S,
+    started: Instant,
+    sent: u64,
+}
+
+/// Delays the chunks of `stream` so that no more than `rate` bytes per second
+/// are sent on average. Fails instead of sending a chunk after
+/// `max_transfer`, dropping `stream` and any file it reads right away.
+pub(crate) fn throttle<S>(
+    stream: S,
+    rate: u64,
+    max_transfer: Duration,
+) -> impl Stream<Item = io::Result<Bytes>> + Send
+where
+    S: Stream<Item = io::Result<Bytes>> + Send + Unpin,
+{
+    let progress = Progress {
+        stream,
+        started: Instant::now(),
+        sent: 0,
+    };
+    stream::unfold(Some(progress), move |progress| async move {
+        let mut progress = progress?;
+        let chunk = match progress.stream.next().await? {
+            Ok(chunk) => chunk,
+            Err(e) => return Some((Err(e), None)),
+        };
+        let due = progress.started + Duration::from_secs_f64(progress.sent as f64 / rate as f64);
+        if due.max(Instant::now()) > progress.started + max_transfer {
+            let e = io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Download took longer than {max_transfer:?}"),
+            );
+            return Some((Err(e), None));
+        }
+        tokio::time::sleep_until(due.into()).await;
+        progress.sent += chunk.len() as u64;
+        Some((Ok(chunk), Some(progress)))
+    })
+}
+
+/// Splits `bytes` into chunks of at most `chunk_size` bytes without copying.
+pub(crate) fn chunked(
+    bytes: Bytes,
+    chunk_size: usize,
+) -> impl Stream<Item = io::Result<Bytes>> + Send + Unpin {
+    let chunk_size = chunk_size.max(1);
+    let chunks: Vec<_> = (0..bytes.len())
+        .step_by(chunk_size)
+        .map(|start| Ok(bytes.slice(start..bytes.len().min(start + chunk_size))))
+        .collect();
+    stream::iter(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn paces_chunks() {
+        let started = Instant::now();
+        let chunks: Vec<_> = throttle(
+            chunked(Bytes::from(vec![0; 4000]), 1000),
+            4000,
+            Duration::from_secs(10),
+        )
+        .collect()
+        .await;
+
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.as_ref().unwrap().len() == 1000));
+        // The last chunk is due after 3000 bytes
+        assert!(started.elapsed() >= Duration::from_millis(750));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_transfer() {
+        let chunks: Vec<_> = throttle(
+            chunked(Bytes::from(vec![0; 3000]), 1000),
+            2000,
+            Duration::from_millis(600),
+        )
+        .collect()
+        .await;
+
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[1].is_ok());
+        assert_eq!(
+            chunks[2].as_ref().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    #[tokio::test]
+    async fn chunked_sizes() {
+        for (len, sizes) in [(5, vec![2, 2, 1]), (4, vec![2, 2]), (0, vec![])] {
+            let chunks: Vec<_> = chunked(Bytes::from(vec![0; len]), 2)
+                .map(|chunk| chunk.unwrap().len())
+                .collect()
+                .await;
+            assert_eq!(chunks, sizes);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, HttpBody},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use eyre::eyre;
+use futures_util::stream;
+use hyper::Request;
+use tokio::time::Instant;
+
+use crate::{error::AppError, ip_filter::ClientIp};
+
+/// Limits on how long requests may take.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Timeouts {
+    /// Time the client has to send the request body.
+    pub body: Duration,
+    /// Time to the response after which the request is logged as slow.
+    pub slow: Duration,
+}
+
+#[derive(Debug, Default)]
+struct BodyProgress {
+    read: AtomicU64,
+    timed_out: AtomicBool,
+}
+
+/// Fails reading the request body once `deadline` has passed, instead of
+/// waiting for clients that trickle it.
+fn deadline_body(body: Body, deadline: Instant, progress: Arc<BodyProgress>) -> Body {
+    Body::wrap_stream(stream::unfold(Some(body), move |body| {
+        let progress = progress.clone();
+        async move {
+            let mut body = body?;
+            match tokio::time::timeout_at(deadline, body.data()).await {
+                Ok(Some(Ok(chunk))) => {
+                    progress
+                        .read
+                        .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                    Some((Ok(chunk), Some(body)))
+                }
+                Ok(Some(Err(e))) => Some((Err(io::Error::other(e)), None)),
+                Ok(None) => None,
+                Err(_) => {
+                    progress.timed_out.store(true, Ordering::Relaxed);
+                    let e = io::Error::new(io::ErrorKind::TimedOut, "Request body timed out");
+                    Some((Err(e), None))
+                }
+            }
+        }
+    }))
+}
+
+/// Responds with 408 if the request body isn't complete within the timeout,
+/// and logs requests that take longer than the slow threshold to respond.
+/// Only the request is limited, so slow downloads of large or throttled
+/// images are not cut off.
+pub(crate) async fn limit(
+    timeouts: Timeouts,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let client = request.extensions().get::<ClientIp>().copied();
+
+    let progress = Arc::new(BodyProgress::default());
+    if !request.body().is_end_stream() {
+        let body = std::mem::take(request.body_mut());
+        *request.body_mut() = deadline_body(body, started + timeouts.body, progress.clone());
+    }
+    let mut response = next.run(request).await;
+    if progress.timed_out.load(Ordering::Relaxed) {
+        response = AppError::RequestTimeout(eyre!(
+            "The request body was not received within {:?}",
+            timeouts.body
+        ))
+        .into_response();
+    }
+
+    let elapsed = started.elapsed();
+    if elapsed > timeouts.slow {
+        let client = client.map_or_else(
+            || "unknown client".to_owned(),
+            |ClientIp(ip)| ip.to_string(),
+        );
+        tracing::warn!(
+            %method,
+            path,
+            ?elapsed,
+            %client,
+            bytes_read = progress.read.load(Ordering::Relaxed),
+            "Slow request"
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::{middleware, routing::post, Router};
+    use hyper::StatusCode;
+    use tower::ServiceExt;
+    use tracing_subscriber::fmt::MakeWriter;
+
+    use super::*;
+
+    const TIMEOUTS: Timeouts = Timeouts {
+        body: Duration::from_millis(200),
+        slow: Duration::from_millis(100),
+    };
+
+    fn app() -> Router<(), Body> {
+        Router::new()
+            .route(
+                "/slow",
+                post(|body: String| async move {
+                    tokio::time::sleep(Duration::from_millis(150)).await;
+                    body
+                }),
+            )
+            .route("/echo", post(|body: String| async move { body }))
+            .layer(middleware::from_fn(
+                |request: Request<Body>, next: Next<Body>| limit(TIMEOUTS, request, next),
+            ))
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MakeWriter<'_> for Captured {
+        type Writer = Self;
+
+        fn make_writer(&self) -> Self {
+            self.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_slow_requests() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(captured.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        for path in ["/echo", "/slow"] {
+            let response = app()
+                .oneshot(Request::post(path).body(Body::from("hello")).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(logs.lines().count(), 1, "{logs}");
+        assert!(logs.contains("Slow request"), "{logs}");
+        assert!(logs.contains("method=POST path=\"/slow\""), "{logs}");
+        assert!(logs.contains("bytes_read=5"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn stalled_body_times_out() {
+        let (mut sender, body) = Body::channel();
+        sender.send_data("<rect".into()).await.unwrap();
+
+        let response = app()
+            .oneshot(Request::post("/echo").body(body).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        drop(sender);
+    }
+}
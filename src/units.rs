@@ -0,0 +1,328 @@
+use std::{
+    fmt::{self, Debug, Display},
+    str::FromStr,
+    time::Duration,
+};
+
+use eyre::{bail, eyre};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Units of [`humantime`] whose length varies, which are rejected.
+const VARYING_UNITS: [&str; 6] = ["M", "month", "months", "y", "year", "years"];
+
+/// A duration given like `90s`, `12h` or `1h 30m`. Plain integers are
+/// seconds, or milliseconds and days for the options parsed with [`millis`]
+/// and [`days`].
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct HumanDuration(Duration);
+
+impl HumanDuration {
+    #[cfg(test)]
+    pub const fn from_secs(secs: u64) -> Self {
+        HumanDuration(Duration::from_secs(secs))
+    }
+
+    #[cfg(test)]
+    pub const fn from_millis(millis: u64) -> Self {
+        HumanDuration(Duration::from_millis(millis))
+    }
+
+    pub fn get(self) -> Duration {
+        self.0
+    }
+
+    /// Parses `s`, taking a plain integer as a number of `unit`s.
+    fn parse(s: &str, unit: Duration) -> eyre::Result<Self> {
+        let s = s.trim();
+        if let Ok(count) = s.parse::<u32>() {
+            return Ok(HumanDuration(unit * count));
+        }
+        let varying = s
+            .split(|c: char| c.is_ascii_digit() || c.is_whitespace())
+            .find(|unit| VARYING_UNITS.contains(unit));
+        if let Some(unit) = varying {
+            bail!("Ambiguous unit '{unit}' in '{s}', use days instead");
+        }
+        humantime::parse_duration(s)
+            .map(HumanDuration)
+            .map_err(|e| eyre!("Invalid duration '{s}': {e}, expected e.g. 90s, 12h or 30d"))
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, Duration::from_secs(1))
+    }
+}
+
+/// Parses a duration whose plain integers are milliseconds.
+pub(crate) fn millis(s: &str) -> eyre::Result<HumanDuration> {
+    HumanDuration::parse(s, Duration::from_millis(1))
+}
+
+/// Parses a duration whose plain integers are days.
+pub(crate) fn days(s: &str) -> eyre::Result<HumanDuration> {
+    HumanDuration::parse(s, Duration::from_secs(24 * 3600))
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_zero() {
+            return write!(f, "0s");
+        }
+        write!(f, "{}", humantime::format_duration(self.0))
+    }
+}
+
+impl Debug for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Binary and decimal units, in bytes.
+const SIZE_UNITS: [(&str, u64); 9] = [
+    ("B", 1),
+    ("kB", 1000),
+    ("KiB", 1 << 10),
+    ("MB", 1000 * 1000),
+    ("MiB", 1 << 20),
+    ("GB", 1000 * 1000 * 1000),
+    ("GiB", 1 << 30),
+    ("TB", 1000 * 1000 * 1000 * 1000),
+    ("TiB", 1 << 40),
+];
+
+/// A size given like `512KiB` or `2MB`. Plain integers are bytes.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ByteSize(u64);
+
+impl ByteSize {
+    #[cfg(test)]
+    pub const fn new(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The size for buffers, which can't exceed the address space anyway.
+    pub fn as_usize(self) -> usize {
+        usize::try_from(self.0).unwrap_or(usize::MAX)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (count, unit) = s.split_at(split);
+        let count: u64 = count
+            .parse()
+            .map_err(|_| eyre!("Invalid size '{s}', expected e.g. 4096, 512KiB or 2MB"))?;
+        let unit = unit.trim_start();
+        let factor = match SIZE_UNITS
+            .iter()
+            .find(|(name, _)| unit.is_empty() || name.eq_ignore_ascii_case(unit))
+        {
+            Some((_, factor)) => *factor,
+            None if ["k", "m", "g", "t", "ki", "mi", "gi", "ti"]
+                .contains(&unit.to_ascii_lowercase().as_str()) =>
+            {
+                let prefix = unit[..1].to_ascii_uppercase();
+                bail!("Ambiguous unit '{unit}' in '{s}', use {count}{prefix}B or {count}{prefix}iB")
+            }
+            None => bail!(
+                "Unknown unit '{unit}' in '{s}', expected B, kB, KiB, MB, MiB, GB, GiB, TB or TiB"
+            ),
+        };
+        count
+            .checked_mul(factor)
+            .map(ByteSize)
+            .ok_or_else(|| eyre!("Size '{s}' is too large"))
+    }
+}
+
+impl Display for ByteSize {
+    /// With the largest unit that divides the size exactly.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "0B");
+        }
+        let (name, factor) = SIZE_UNITS
+            .iter()
+            .rev()
+            .find(|(_, factor)| self.0.is_multiple_of(*factor))
+            .unwrap_or(&SIZE_UNITS[0]);
+        write!(f, "{}{name}", self.0 / factor)
+    }
+}
+
+impl Debug for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Parses a duration that must not be zero.
+pub(crate) fn nonzero_duration(s: &str) -> eyre::Result<HumanDuration> {
+    let duration: HumanDuration = s.parse()?;
+    if duration.0.is_zero() {
+        bail!("Duration must not be zero");
+    }
+    Ok(duration)
+}
+
+/// Parses a size that must not be zero.
+pub(crate) fn nonzero_size(s: &str) -> eyre::Result<ByteSize> {
+    let size: ByteSize = s.parse()?;
+    if size.0 == 0 {
+        bail!("Size must not be zero");
+    }
+    Ok(size)
+}
+
+macro_rules! serde_as_string {
+    ($type:ty, $expected:literal) => {
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        /// From a string like on the command line, or a plain integer.
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                #[derive(Deserialize)]
+                #[serde(untagged)]
+                enum Value {
+                    Integer(u64),
+                    String(String),
+                }
+                match Value::deserialize(deserializer)? {
+                    Value::Integer(count) => count.to_string().parse(),
+                    Value::String(s) => s.parse(),
+                }
+                .map_err(|e| de::Error::custom(format!("{e}, expected {}", $expected)))
+            }
+        }
+    };
+}
+
+serde_as_string!(HumanDuration, "a duration");
+serde_as_string!(ByteSize, "a size");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        for (s, secs) in [
+            ("90s", 90),
+            ("12h", 12 * 3600),
+            ("30d", 30 * 86400),
+            ("1h 30m", 5400),
+            ("1h30m", 5400),
+            ("1.5h", 5400),
+            (" 2m ", 120),
+            ("45", 45),
+            ("0", 0),
+        ] {
+            assert_eq!(
+                s.parse::<HumanDuration>().unwrap().get().as_secs(),
+                secs,
+                "{s}"
+            );
+        }
+        assert_eq!(millis("250").unwrap().get(), Duration::from_millis(250));
+        assert_eq!(millis("2s").unwrap().get(), Duration::from_secs(2));
+        assert!(nonzero_duration("0s").is_err());
+        assert_eq!(
+            nonzero_duration("5m").unwrap().get(),
+            Duration::from_secs(300)
+        );
+        assert_eq!(days("90").unwrap().get(), Duration::from_secs(90 * 86400));
+        assert_eq!(days("12h").unwrap().get(), Duration::from_secs(12 * 3600));
+        assert!(days("0").unwrap().get().is_zero());
+
+        // Ambiguous
+        for s in ["1M", "2months", "1y", "1h30"] {
+            assert!(s.parse::<HumanDuration>().is_err(), "{s}");
+        }
+        // Nonsense
+        for s in ["", "soon", "-5s", "5 parsecs", "99999999999999999999"] {
+            assert!(s.parse::<HumanDuration>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn normalized_durations() {
+        for (s, normalized) in [
+            ("90s", "1m 30s"),
+            ("3600", "1h"),
+            ("0", "0s"),
+            ("1500ms", "1s 500ms"),
+        ] {
+            let duration: HumanDuration = s.parse().unwrap();
+            assert_eq!(duration.to_string(), normalized);
+            assert_eq!(normalized.parse::<HumanDuration>().unwrap(), duration);
+        }
+    }
+
+    #[test]
+    fn parse_sizes() {
+        for (s, bytes) in [
+            ("4096", 4096),
+            ("512KiB", 512 * 1024),
+            ("2MB", 2_000_000),
+            ("2 mib", 2 << 20),
+            ("10B", 10),
+            ("1TiB", 1 << 40),
+            ("0", 0),
+        ] {
+            assert_eq!(s.parse::<ByteSize>().unwrap().get(), bytes, "{s}");
+        }
+
+        // Ambiguous
+        for s in ["2M", "512k", "1Gi"] {
+            let e = s.parse::<ByteSize>().unwrap_err();
+            assert!(e.to_string().contains("Ambiguous"), "{s}: {e}");
+        }
+        // Nonsense
+        for s in ["", "MB", "1.5MB", "-1", "2 bananas", "99999999TiB"] {
+            assert!(s.parse::<ByteSize>().is_err(), "{s}");
+        }
+        assert!(nonzero_size("0KiB").is_err());
+    }
+
+    #[test]
+    fn normalized_sizes() {
+        for (bytes, normalized) in [
+            (256 * 1024, "256KiB"),
+            (10 << 20, "10MiB"),
+            (2_000_000, "2MB"),
+            (1000 * 1024, "1000KiB"),
+            (4095, "4095B"),
+            (0, "0B"),
+        ] {
+            assert_eq!(ByteSize::new(bytes).to_string(), normalized);
+        }
+    }
+
+    #[test]
+    fn serde_strings_and_integers() {
+        let size: ByteSize = serde_json::from_str("\"2MiB\"").unwrap();
+        assert_eq!(size.get(), 2 << 20);
+        let size: ByteSize = serde_json::from_str("4096").unwrap();
+        assert_eq!(serde_json::to_string(&size).unwrap(), "\"4KiB\"");
+        let duration: HumanDuration = serde_json::from_str("30").unwrap();
+        assert_eq!(serde_json::to_string(&duration).unwrap(), "\"30s\"");
+        assert!(serde_json::from_str::<HumanDuration>("\"1y\"").is_err());
+    }
+}
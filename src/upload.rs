@@ -0,0 +1,220 @@
+use axum::body::Bytes;
+use eyre::eyre;
+use hyper::body::HttpBody;
+
+use crate::{
+    encoding,
+    error::{AppError, ResultExt},
+    storage::{Storage, TempFile},
+    svgz::{self, MAX_SVG_BYTES},
+};
+
+/// Start of the body kept to tell its encoding.
+const HEAD_LEN: usize = 256;
+
+/// Reads an SVG request body chunk by chunk. The body is rejected as soon as
+/// it grows beyond [`MAX_SVG_BYTES`] or, unless it is gzip compressed or
+/// starts with the byte order mark of UTF-16, contains invalid UTF-8. Once more than `spill_threshold` bytes arrived,
+/// they are moved to a temporary file until the body is complete.
+pub(crate) async fn read_svg_body<B>(
+    mut body: B,
+    storage: &Storage,
+    spill_threshold: usize,
+) -> Result<Vec<u8>, AppError>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut len = 0;
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    let mut utf8 = Some(Utf8Validator::default());
+    let mut memory = Vec::new();
+    let mut spill: Option<TempFile> = None;
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.bad_request()?;
+        len += chunk.len();
+        if len > MAX_SVG_BYTES {
+            return Err(AppError::PayloadTooLarge(eyre!(
+                "Request body is larger than {MAX_SVG_BYTES} bytes."
+            )));
+        }
+
+        if head.len() < HEAD_LEN {
+            head.extend(chunk.iter().take(HEAD_LEN - head.len()));
+        }
+        if let Some(validator) = utf8.as_mut() {
+            if !validator.push(&chunk) {
+                // The second byte of the gzip magic is never valid UTF-8,
+                // neither are the byte order marks
+                if !svgz::is_gzip(&head) && !encoding::has_bom(&head) {
+                    return Err(AppError::BadRequest(encoding::unsupported(&head)));
+                }
+                utf8 = None;
+            }
+        }
+
+        match spill.as_mut() {
+            Some(file) => file.write_all(&chunk).await.internal()?,
+            None if memory.len() + chunk.len() > spill_threshold => {
+                let mut file = storage.create_temp("svg-upload").await.internal()?;
+                file.write_all(&memory).await.internal()?;
+                file.write_all(&chunk).await.internal()?;
+                memory = Vec::new();
+                spill = Some(file);
+            }
+            None => memory.extend_from_slice(&chunk),
+        }
+    }
+
+    if utf8.is_some_and(|validator| !validator.finish()) {
+        return Err(AppError::BadRequest(eyre!("SVG is not valid UTF-8")));
+    }
+    match spill {
+        Some(file) => file.read_all().await.internal(),
+        None => Ok(memory),
+    }
+}
+
+/// Validates UTF-8 that arrives in chunks, which may split characters.
+#[derive(Debug, Default)]
+struct Utf8Validator {
+    /// Start of a character continued in the next chunk.
+    incomplete: Vec<u8>,
+}
+
+impl Utf8Validator {
+    /// Returns false once invalid UTF-8 was found.
+    fn push(&mut self, mut chunk: &[u8]) -> bool {
+        if let Some(&lead) = self.incomplete.first() {
+            let width = match lead {
+                0xC2..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                _ => 4,
+            };
+            let missing = width - self.incomplete.len();
+            if chunk.len() < missing {
+                self.incomplete.extend_from_slice(chunk);
+                return true;
+            }
+            self.incomplete.extend_from_slice(&chunk[..missing]);
+            if std::str::from_utf8(&self.incomplete).is_err() {
+                return false;
+            }
+            self.incomplete.clear();
+            chunk = &chunk[missing..];
+        }
+
+        match std::str::from_utf8(chunk) {
+            Ok(_) => true,
+            Err(e) if e.error_len().is_none() => {
+                self.incomplete = chunk[e.valid_up_to()..].to_vec();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Returns whether the input ended with a complete character.
+    fn finish(self) -> bool {
+        self.incomplete.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Body;
+    use test_dir::{DirBuilder, TestDir};
+
+    use super::*;
+
+    /// A body that arrives in `chunks`.
+    fn chunked(chunks: Vec<Vec<u8>>) -> Body {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                if sender.send_data(chunk.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        body
+    }
+
+    fn storage() -> (TestDir, Storage) {
+        let temp_dir = TestDir::temp();
+        let storage = Storage::new(temp_dir.root().to_owned());
+        (temp_dir, storage)
+    }
+
+    #[tokio::test]
+    async fn read_in_memory_and_spilled() {
+        let (_temp_dir, storage) = storage();
+        let chunks = vec![b"<rect".to_vec(), b" width=\"1\"/>".to_vec()];
+
+        for threshold in [1024, 4] {
+            let body = read_svg_body(chunked(chunks.clone()), &storage, threshold)
+                .await
+                .unwrap();
+            assert_eq!(body, b"<rect width=\"1\"/>");
+            assert!(storage.list().await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn read_gzip() {
+        let (_temp_dir, storage) = storage();
+        let svgz = svgz::compress(b"<rect/>").unwrap();
+        let chunks = vec![svgz[..1].to_vec(), svgz[1..].to_vec()];
+
+        let body = read_svg_body(chunked(chunks), &storage, 1024)
+            .await
+            .unwrap();
+        assert_eq!(body, svgz);
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_utf8() {
+        let (_temp_dir, storage) = storage();
+        for chunks in [
+            vec![b"<text>\xFF".to_vec()],
+            vec![b"<text>\xE2\x82".to_vec()],
+        ] {
+            let e = read_svg_body(chunked(chunks), &storage, 1024)
+                .await
+                .unwrap_err();
+            assert!(matches!(e, AppError::BadRequest(_)), "{e}");
+        }
+    }
+
+    #[test]
+    fn utf8_validator_split_characters() {
+        let text = "Grüße € 𝄞".as_bytes();
+        for split in 0..text.len() {
+            let mut validator = Utf8Validator::default();
+            assert!(validator.push(&text[..split]), "{split}");
+            assert!(validator.push(&text[split..]), "{split}");
+            assert!(validator.finish(), "{split}");
+        }
+
+        let mut validator = Utf8Validator::default();
+        for byte in text {
+            assert!(validator.push(std::slice::from_ref(byte)));
+        }
+        assert!(validator.finish());
+    }
+
+    #[test]
+    fn utf8_validator_invalid() {
+        let mut validator = Utf8Validator::default();
+        assert!(validator.push(b"ok \xE2\x82"));
+        assert!(!validator.push(b"A"));
+
+        let mut validator = Utf8Validator::default();
+        assert!(!validator.push(b"\xFF"));
+
+        let mut validator = Utf8Validator::default();
+        assert!(validator.push(b"\xF0\x9D"));
+        assert!(!validator.finish());
+    }
+}
@@ -0,0 +1,388 @@
+use std::{fmt, ops::Deref};
+
+use crate::{config::Config, dependency::Dependency, tenant::TenantKey};
+
+/// A check of options that are valid on their own but not together,
+/// returning what is wrong.
+type Constraint = fn(&Config) -> Option<String>;
+
+const CONSTRAINTS: &[Constraint] = &[
+    dedup_needs_image_dir,
+    tenants_need_image_dir,
+    tenants_dont_collide,
+    state_needs_image_dir,
+    migrate_shards_needs_sharding,
+    replica_url_is_http,
+    replica_without_tenants,
+    replica_key_needs_url,
+    required_storage_is_set,
+    poll_limits_ordered,
+    render_thresholds_ordered,
+    image_cache_fits_file,
+    warmup_has_concurrency,
+    listeners_differ,
+];
+
+/// A [`Config`] whose options fit together, which is what the image
+/// handlers are built from.
+#[derive(Debug, Clone)]
+pub(crate) struct ValidatedConfig(Config);
+
+impl ValidatedConfig {
+    /// Checks all constraints, reporting every violation rather than the
+    /// first.
+    pub fn new(config: Config) -> Result<Self, ConfigErrors> {
+        let errors: Vec<String> = CONSTRAINTS
+            .iter()
+            .filter_map(|constraint| constraint(&config))
+            .collect();
+        if errors.is_empty() {
+            Ok(ValidatedConfig(config))
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    /// See [`Config::for_tenant`], which keeps the constraints.
+    pub fn for_tenant(&self, tenant: &TenantKey) -> Self {
+        ValidatedConfig(self.0.for_tenant(tenant))
+    }
+}
+
+impl Deref for ValidatedConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        &self.0
+    }
+}
+
+/// The violated constraints of a configuration, one per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for error in &self.0 {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+fn dedup_needs_image_dir(config: &Config) -> Option<String> {
+    (config.dedup && config.storage.is_some())
+        .then(|| "--dedup requires the image directory, not --storage".to_owned())
+}
+
+fn tenants_need_image_dir(config: &Config) -> Option<String> {
+    (!config.tenants.is_empty() && config.storage.is_some())
+        .then(|| "--tenant requires the image directory, not --storage".to_owned())
+}
+
+/// Tenants live in [`crate::tenant::TENANTS_DIR`], apart from the shard
+/// directories, `--dedup` objects and stats of the default tenant, but two
+/// names that only differ in case share a directory on case-insensitive
+/// file systems.
+fn tenants_dont_collide(config: &Config) -> Option<String> {
+    let mut seen = std::collections::HashMap::new();
+    config.tenants.iter().find_map(|tenant| {
+        let name = tenant.name.to_string();
+        let previous = seen.insert(name.to_ascii_lowercase(), name.clone())?;
+        Some(format!(
+            "--tenant {name} shares its directory with --tenant {previous}"
+        ))
+    })
+}
+
+/// The SQLite database is a file in the image directory, which is also
+/// where `--migrate-state` finds the backend it imports from.
+fn state_needs_image_dir(config: &Config) -> Option<String> {
+    config.storage.as_ref()?;
+    #[cfg(feature = "sqlite")]
+    if config.state_backend == crate::state::StateBackend::Sqlite {
+        return Some("--state-backend sqlite requires the image directory, not --storage".into());
+    }
+    config
+        .migrate_state
+        .then(|| "--migrate-state requires the image directory, not --storage".to_owned())
+}
+
+fn migrate_shards_needs_sharding(config: &Config) -> Option<String> {
+    (config.migrate_shards && (config.shard_depth == 0 || config.storage.is_some())).then(|| {
+        "--migrate-shards requires --shard-depth and the image directory, not --storage".to_owned()
+    })
+}
+
+/// No TLS client is built in, see [`crate::replication`].
+fn replica_url_is_http(config: &Config) -> Option<String> {
+    let url = config.replica_url.as_ref()?;
+    (url.scheme() != "http").then(|| format!("--replica-url must be an http URL, {url} is not"))
+}
+
+fn replica_without_tenants(config: &Config) -> Option<String> {
+    (config.replica_url.is_some() && !config.tenants.is_empty())
+        .then(|| "--replica-url can't replicate the images of --tenant".to_owned())
+}
+
+fn replica_key_needs_url(config: &Config) -> Option<String> {
+    (config.replica_key.is_some() && config.replica_url.is_none())
+        .then(|| "--replica-key requires --replica-url".to_owned())
+}
+
+fn required_storage_is_set(config: &Config) -> Option<String> {
+    (config.require_dependency.contains(&Dependency::Storage) && config.storage.is_none())
+        .then(|| "--require-dependency storage requires --storage".to_owned())
+}
+
+fn poll_limits_ordered(config: &Config) -> Option<String> {
+    (config.poll_min > config.poll_max).then(|| {
+        format!(
+            "--poll-min {} exceeds --poll-max {}",
+            config.poll_min, config.poll_max
+        )
+    })
+}
+
+/// Only stuck renders are checked for degrading the server.
+fn render_thresholds_ordered(config: &Config) -> Option<String> {
+    (config.render_stuck_secs > config.render_degraded_secs).then(|| {
+        format!(
+            "--render-stuck-secs {} exceeds --render-degraded-secs {}",
+            config.render_stuck_secs, config.render_degraded_secs
+        )
+    })
+}
+
+fn image_cache_fits_file(config: &Config) -> Option<String> {
+    let cache = config.image_cache_bytes;
+    (cache.get() > 0 && config.image_cache_file_bytes > cache).then(|| {
+        format!(
+            "--image-cache-file-bytes {} exceeds --image-cache-bytes {cache}",
+            config.image_cache_file_bytes
+        )
+    })
+}
+
+fn warmup_has_concurrency(config: &Config) -> Option<String> {
+    (config.warmup_derived && config.warmup_concurrency == 0)
+        .then(|| "--warmup-derived requires a --warmup-concurrency of at least 1".to_owned())
+}
+
+fn listeners_differ(config: &Config) -> Option<String> {
+    (config.device_listen == Some(config.listen))
+        .then(|| format!("--device-listen {} is also --listen", config.listen))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::units::{ByteSize, HumanDuration};
+
+    fn parse(args: &[&str]) -> Config {
+        let required = [
+            "eps-server",
+            "--image-dir",
+            "images",
+            "--epd-width",
+            "128",
+            "--epd-height",
+            "296",
+        ];
+        Config::try_parse_from(required.iter().chain(args)).unwrap()
+    }
+
+    /// Asserts that `constraint` accepts `valid` and reports `invalid` with
+    /// a message naming `flag`.
+    fn check(constraint: Constraint, valid: &[&str], invalid: &[&str], flag: &str) {
+        assert_eq!(constraint(&parse(valid)), None, "{valid:?}");
+        let error = constraint(&parse(invalid)).expect("not reported");
+        assert!(error.contains(flag), "{error}");
+    }
+
+    const S3: &str = "s3://bucket/images";
+
+    #[test]
+    fn dedup() {
+        check(
+            dedup_needs_image_dir,
+            &["--dedup"],
+            &["--dedup", "--storage", S3],
+            "--dedup",
+        );
+    }
+
+    #[test]
+    fn tenants() {
+        check(
+            tenants_need_image_dir,
+            &["--tenant", "a=key"],
+            &["--tenant", "a=key", "--storage", S3],
+            "--tenant",
+        );
+    }
+
+    #[test]
+    fn tenant_names() {
+        check(
+            tenants_dont_collide,
+            &[
+                "--tenant",
+                "ab=1",
+                "--tenant",
+                "objects=2",
+                "--tenant",
+                "stats=3",
+                "--dedup",
+                "--shard-depth",
+                "1",
+            ],
+            &["--tenant", "shop=1", "--tenant", "Shop=2"],
+            "--tenant Shop shares its directory with --tenant shop",
+        );
+    }
+
+    #[test]
+    fn state() {
+        check(
+            state_needs_image_dir,
+            &["--migrate-state"],
+            &["--migrate-state", "--storage", S3],
+            "--migrate-state",
+        );
+        #[cfg(feature = "sqlite")]
+        check(
+            state_needs_image_dir,
+            &["--state-backend", "sqlite"],
+            &["--state-backend", "sqlite", "--storage", S3],
+            "--state-backend",
+        );
+    }
+
+    #[test]
+    fn migrate_shards() {
+        check(
+            migrate_shards_needs_sharding,
+            &["--migrate-shards", "--shard-depth", "1"],
+            &["--migrate-shards"],
+            "--shard-depth",
+        );
+        let config = parse(&["--migrate-shards", "--shard-depth", "1", "--storage", S3]);
+        assert!(migrate_shards_needs_sharding(&config).is_some());
+    }
+
+    #[test]
+    fn replica_url() {
+        check(
+            replica_url_is_http,
+            &["--replica-url", "http://standby:3000/"],
+            &["--replica-url", "https://standby/"],
+            "https://standby/",
+        );
+    }
+
+    #[test]
+    fn replica_tenants() {
+        check(
+            replica_without_tenants,
+            &["--replica-url", "http://standby/"],
+            &["--replica-url", "http://standby/", "--tenant", "a=key"],
+            "--tenant",
+        );
+    }
+
+    #[test]
+    fn replica_key() {
+        let url = ["--replica-url", "http://standby/"];
+        check(
+            replica_key_needs_url,
+            &[&url[..], &["--replica-key", "secret"]].concat(),
+            &["--replica-key", "secret"],
+            "--replica-url",
+        );
+    }
+
+    #[test]
+    fn required_storage() {
+        check(
+            required_storage_is_set,
+            &["--require-dependency", "storage", "--storage", S3],
+            &["--require-dependency", "storage"],
+            "--storage",
+        );
+    }
+
+    #[test]
+    fn poll_limits() {
+        check(
+            poll_limits_ordered,
+            &["--poll-min", "10m", "--poll-max", "600"],
+            &["--poll-min", "601", "--poll-max", "10m"],
+            "--poll-min 10m 1s exceeds --poll-max 10m",
+        );
+    }
+
+    #[test]
+    fn render_thresholds() {
+        check(
+            render_thresholds_ordered,
+            &["--render-stuck-secs", "5m"],
+            &["--render-stuck-secs", "6m"],
+            "--render-degraded-secs 5m",
+        );
+    }
+
+    #[test]
+    fn image_cache() {
+        check(
+            image_cache_fits_file,
+            &["--image-cache-file-bytes", "2MiB"],
+            &[
+                "--image-cache-bytes",
+                "1MiB",
+                "--image-cache-file-bytes",
+                "2MiB",
+            ],
+            "--image-cache-file-bytes",
+        );
+    }
+
+    #[test]
+    fn warmup() {
+        check(
+            warmup_has_concurrency,
+            &["--warmup-concurrency", "0"],
+            &["--warmup-derived", "--warmup-concurrency", "0"],
+            "--warmup-concurrency",
+        );
+    }
+
+    #[test]
+    fn listeners() {
+        check(
+            listeners_differ,
+            &["--device-listen", "0.0.0.0:3001"],
+            &["--device-listen", "127.0.0.1:3000"],
+            "--device-listen 127.0.0.1:3000",
+        );
+    }
+
+    #[test]
+    fn reports_all_violations() {
+        assert!(ValidatedConfig::new(parse(&[])).is_ok());
+        let mut config = parse(&["--dedup", "--storage", S3]);
+        config.poll_min = HumanDuration::from_secs(7200);
+        config.render_stuck_secs = HumanDuration::from_secs(600);
+        config.image_cache_bytes = ByteSize::new(1);
+        let errors = ValidatedConfig::new(config).unwrap_err();
+        assert_eq!(errors.0.len(), 4, "{errors}");
+        let message = errors.to_string();
+        assert!(message.starts_with("Invalid configuration:\n  - --dedup"));
+        assert_eq!(message.lines().count(), 5);
+    }
+}
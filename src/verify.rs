@@ -0,0 +1,101 @@
+use eyre::{bail, Context};
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum VerifyStatus {
+    /// The stored PNG matches a render of the stored SVG.
+    Ok,
+    /// The stored PNG differs from a render of the stored SVG or can't be
+    /// decoded.
+    Mismatch,
+    /// There is an SVG but no PNG.
+    OrphanSvg,
+    /// There is a PNG but no SVG, as for uploaded raster images.
+    OrphanPng,
+    /// The SVG could not be read or rendered, or the PNG not be read.
+    Unreadable,
+}
+
+/// Result of verifying the images of one MAC.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct VerifyReport {
+    pub mac: String,
+    pub status: VerifyStatus,
+    /// Pixels differing by more than the tolerance, for mismatches of the
+    /// same size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub differing_pixels: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The PNG was rewritten from the SVG.
+    #[serde(default)]
+    pub regenerated: bool,
+}
+
+/// Counts the pixels of two PNGs with a channel differing by more than
+/// `tolerance`. Identical files are compared without decoding them.
+pub(crate) fn differing_pixels(stored: &[u8], rendered: &[u8], tolerance: u8) -> eyre::Result<u64> {
+    if stored == rendered {
+        return Ok(0);
+    }
+    let decode = |data| image::load_from_memory_with_format(data, ImageFormat::Png);
+    let stored = decode(stored)
+        .wrap_err("Stored PNG can't be decoded")?
+        .into_rgba8();
+    let rendered = decode(rendered)?.into_rgba8();
+    if stored.dimensions() != rendered.dimensions() {
+        bail!(
+            "Stored PNG is {}x{}, the render {}x{}",
+            stored.width(),
+            stored.height(),
+            rendered.width(),
+            rendered.height()
+        );
+    }
+    let differing = stored
+        .pixels()
+        .zip(rendered.pixels())
+        .filter(|(a, b)| a.0.iter().zip(b.0).any(|(x, y)| x.abs_diff(y) > tolerance))
+        .count();
+    Ok(differing as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn png(image: &RgbaImage) -> Vec<u8> {
+        let mut png = std::io::Cursor::new(vec![]);
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn compare_pixels() {
+        let image = RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255]));
+        let mut shifted = image.clone();
+        shifted.put_pixel(0, 0, Rgba([250, 255, 255, 255]));
+        shifted.put_pixel(1, 0, Rgba([0, 0, 0, 255]));
+
+        assert_eq!(differing_pixels(&png(&image), &png(&image), 0).unwrap(), 0);
+        assert_eq!(
+            differing_pixels(&png(&image), &png(&shifted), 0).unwrap(),
+            2
+        );
+        assert_eq!(
+            differing_pixels(&png(&image), &png(&shifted), 5).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn compare_invalid() {
+        let image = RgbaImage::new(4, 4);
+        assert!(differing_pixels(b"garbage", &png(&image), 0).is_err());
+        assert!(differing_pixels(&png(&RgbaImage::new(4, 5)), &png(&image), 0).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Routes every build serves.
+const BUILT_IN: [&str; 6] = ["bmp", "bundle", "events", "profiles", "raw", "ws"];
+
+/// What `GET /version` tells clients, so that they can detect features
+/// without parsing the API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct VersionInfo {
+    pub version: String,
+    /// Absent if the build wasn't from a git checkout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_timestamp: Option<DateTime<Utc>>,
+    /// Cargo features the server was built with.
+    pub features: Vec<String>,
+    /// Short names of what the server supports, from its features and its
+    /// configuration, like `raw` or `auth`.
+    pub capabilities: Vec<String>,
+}
+
+impl VersionInfo {
+    pub fn new(config: &Config) -> Self {
+        let mut features = vec![];
+        if cfg!(feature = "chaos") {
+            features.push("chaos");
+        }
+        if cfg!(feature = "client") {
+            features.push("client");
+        }
+        if cfg!(feature = "coap") {
+            features.push("coap");
+        }
+
+        let mut capabilities = BUILT_IN.to_vec();
+        // As the admin routes require then
+        if config.admin_key.is_some() || config.htpasswd.is_some() {
+            capabilities.push("auth");
+        }
+        if cfg!(feature = "chaos") {
+            capabilities.push("chaos");
+        }
+        #[cfg(feature = "coap")]
+        if config.coap_listen.is_some() {
+            capabilities.push("coap");
+        }
+        if !config.read_only {
+            capabilities.push("write");
+        }
+        capabilities.sort_unstable();
+
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            git_commit: Some(env!("EPS_GIT_COMMIT"))
+                .filter(|commit| !commit.is_empty())
+                .map(str::to_owned),
+            build_timestamp: env!("EPS_BUILD_TIMESTAMP")
+                .parse()
+                .ok()
+                .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+            features: features.into_iter().map(str::to_owned).collect(),
+            capabilities: capabilities.into_iter().map(str::to_owned).collect(),
+        }
+    }
+}
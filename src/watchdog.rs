@@ -0,0 +1,175 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::image_handler::{EpdMac, ImageHandler};
+
+/// Renders in progress, to detect those that hang.
+pub(crate) struct RenderWatchdog {
+    renders: Mutex<HashMap<u64, InProgress>>,
+    next_id: AtomicU64,
+    /// Renders taking longer are logged and counted once.
+    stuck_after: Duration,
+    /// Renders taking longer mark the server as degraded.
+    degraded_after: Duration,
+    stuck_total: AtomicU64,
+    degraded: AtomicBool,
+}
+
+struct InProgress {
+    mac: EpdMac,
+    started: Instant,
+    reported: bool,
+}
+
+/// Removes its render from the watchdog when dropped, also when the render
+/// panicked or its future was dropped.
+pub(crate) struct RenderGuard<'a> {
+    watchdog: &'a RenderWatchdog,
+    id: u64,
+}
+
+/// A render that takes longer than expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StuckRender {
+    pub mac: EpdMac,
+    pub elapsed: Duration,
+}
+
+impl RenderWatchdog {
+    pub fn new(stuck_after: Duration, degraded_after: Duration) -> Self {
+        RenderWatchdog {
+            renders: Mutex::default(),
+            next_id: AtomicU64::new(0),
+            stuck_after,
+            degraded_after,
+            stuck_total: AtomicU64::new(0),
+            degraded: AtomicBool::new(false),
+        }
+    }
+
+    /// Registers a render of `mac` until the returned guard is dropped.
+    pub fn start(&self, mac: EpdMac) -> RenderGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.renders.lock().unwrap().insert(
+            id,
+            InProgress {
+                mac,
+                started: Instant::now(),
+                reported: false,
+            },
+        );
+        RenderGuard { watchdog: self, id }
+    }
+
+    /// Logs renders that became stuck since the last scan, updates whether
+    /// the server is degraded and returns all stuck renders, longest first.
+    /// `available_permits` is logged to tell a wedged semaphore from a hung
+    /// render.
+    pub fn scan(&self, now: Instant, available_permits: usize) -> Vec<StuckRender> {
+        let mut renders = self.renders.lock().unwrap();
+        let in_progress = renders.len();
+        let mut stuck = Vec::new();
+        for render in renders.values_mut() {
+            let elapsed = now.saturating_duration_since(render.started);
+            if elapsed < self.stuck_after {
+                continue;
+            }
+            if !render.reported {
+                render.reported = true;
+                self.stuck_total.fetch_add(1, Ordering::Relaxed);
+                tracing::error!(
+                    "Render of {} has been running for {elapsed:?}; {in_progress} renders in \
+                     progress, {available_permits} render permits available",
+                    render.mac
+                );
+            }
+            stuck.push(StuckRender {
+                mac: render.mac,
+                elapsed,
+            });
+        }
+        stuck.sort_by_key(|render| Reverse(render.elapsed));
+
+        let degraded = stuck
+            .first()
+            .is_some_and(|render| render.elapsed >= self.degraded_after);
+        self.degraded.store(degraded, Ordering::Relaxed);
+        stuck
+    }
+
+    /// Whether the last scan found a render beyond the hard threshold.
+    pub fn degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Renders started since startup.
+    #[cfg(test)]
+    pub fn started_total(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    /// Renders that were found stuck since startup.
+    pub fn stuck_total(&self) -> u64 {
+        self.stuck_total.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for RenderGuard<'_> {
+    fn drop(&mut self) {
+        self.watchdog.renders.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Periodically scans for hanging renders.
+pub(crate) async fn run(image_handler: Arc<ImageHandler>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        image_handler.scan_renders(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: EpdMac = EpdMac([0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+
+    #[test]
+    fn stuck_and_degraded() {
+        let watchdog = RenderWatchdog::new(Duration::from_secs(10), Duration::from_secs(60));
+        let guard = watchdog.start(MAC);
+        let now = Instant::now();
+
+        assert!(watchdog.scan(now, 4).is_empty());
+        assert_eq!(watchdog.scan(now + Duration::from_secs(10), 3).len(), 1);
+        assert!(!watchdog.degraded());
+        // Counted once however often it is found
+        let stuck = watchdog.scan(now + Duration::from_secs(61), 3);
+        assert_eq!(stuck[0].mac, MAC);
+        assert!(watchdog.degraded());
+        assert_eq!(watchdog.stuck_total(), 1);
+
+        drop(guard);
+        assert!(watchdog.scan(now + Duration::from_secs(61), 4).is_empty());
+        assert!(!watchdog.degraded());
+    }
+
+    #[test]
+    fn guard_removed_on_panic() {
+        let watchdog = RenderWatchdog::new(Duration::ZERO, Duration::ZERO);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = watchdog.start(MAC);
+            panic!("render failed");
+        }));
+        assert!(result.is_err());
+        assert!(watchdog.scan(Instant::now(), 4).is_empty());
+    }
+}
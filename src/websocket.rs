@@ -0,0 +1,211 @@
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+use axum::extract::ws::{Message, WebSocket};
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{
+    capabilities::Capabilities,
+    error::{AppError, ErrorBody},
+    events::{Event, EventKind},
+    image_handler::EpdMac,
+    refresh::Refresh,
+    AppState,
+};
+
+/// Messages of devices on `GET /ws`, like `{"subscribe": ["<mac>"]}`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClientMessage {
+    /// Replaces the MACs whose changes are pushed.
+    Subscribe(Vec<EpdMac>),
+    /// Like `POST /macs/:mac/ack`.
+    Ack(SessionAck),
+    /// Like `PUT /macs/:mac/capabilities`.
+    Checkin(Checkin),
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionAck {
+    mac: EpdMac,
+    /// Entity tag of the image shown, rejected if it isn't the current one.
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default = "partial")]
+    refresh: Refresh,
+}
+
+fn partial() -> Refresh {
+    Refresh::Partial
+}
+
+#[derive(Debug, Deserialize)]
+struct Checkin {
+    mac: EpdMac,
+    capabilities: Capabilities,
+}
+
+/// Changes of subscribed MACs, like `{"event": "updated", ...}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Push {
+    Updated {
+        mac: EpdMac,
+        etag: Option<String>,
+    },
+    Deleted {
+        mac: EpdMac,
+    },
+    /// Changes were missed; clients should fetch all subscribed MACs.
+    Resync,
+}
+
+/// Answers to client messages.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Reply {
+    Subscribed(BTreeSet<EpdMac>),
+    Acked(EpdMac),
+    CheckedIn(EpdMac),
+    Error(ErrorBody),
+}
+
+/// Session of a device on `GET /ws` until either side closes it or a ping
+/// goes unanswered.
+pub(crate) async fn session(mut socket: WebSocket, state: Arc<AppState>) {
+    let config = state.image_handler.config();
+    let max_subscriptions = config.ws_max_subscriptions;
+    let mut ping = time::interval(config.ws_ping_interval.get().max(Duration::from_millis(10)));
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let (_, mut events) = state.image_handler.events().subscribe(None, None);
+    let mut subscribed = BTreeSet::new();
+    let mut awaiting_pong = false;
+
+    loop {
+        let outgoing = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = match serde_json::from_str(&text) {
+                        Ok(message) => {
+                            handle(&state, message, &mut subscribed, max_subscriptions).await
+                        }
+                        Err(e) => Reply::Error(AppError::BadRequest(eyre!(e)).body()),
+                    };
+                    Some(serde_json::to_string(&reply))
+                }
+                Some(Ok(Message::Pong(_))) => {
+                    awaiting_pong = false;
+                    None
+                }
+                // Pings are answered by the socket
+                Some(Ok(Message::Ping(_) | Message::Binary(_))) => None,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            },
+            event = events.recv() => match event {
+                Ok(event) => push(&state, &subscribed, event)
+                    .await
+                    .map(|push| serde_json::to_string(&push)),
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::debug!("WebSocket session missed {missed} events");
+                    Some(serde_json::to_string(&Push::Resync))
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = ping.tick() => {
+                if awaiting_pong {
+                    tracing::debug!("Closing WebSocket session without pong");
+                    break;
+                }
+                awaiting_pong = true;
+                if socket.send(Message::Ping(vec![])).await.is_err() {
+                    break;
+                }
+                None
+            }
+        };
+        let text = match outgoing {
+            Some(Ok(text)) => text,
+            Some(Err(e)) => {
+                tracing::warn!("Could not serialize WebSocket message: {e}");
+                continue;
+            }
+            None => continue,
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+    }
+    let _ = socket.close().await;
+}
+
+async fn handle(
+    state: &AppState,
+    message: ClientMessage,
+    subscribed: &mut BTreeSet<EpdMac>,
+    max_subscriptions: usize,
+) -> Reply {
+    let handler = &state.image_handler;
+    let result = match message {
+        ClientMessage::Subscribe(macs) => {
+            let macs: BTreeSet<_> = macs.into_iter().collect();
+            if macs.len() > max_subscriptions {
+                Err(AppError::BadRequest(eyre!(
+                    "A session can subscribe to at most {max_subscriptions} MACs, not {}.",
+                    macs.len()
+                )))
+            } else {
+                *subscribed = macs;
+                Ok(Reply::Subscribed(subscribed.clone()))
+            }
+        }
+        ClientMessage::Ack(ack) => {
+            let current = etag(state, ack.mac).await;
+            match ack.etag {
+                Some(etag) if current.as_ref() != Some(&etag) => Err(AppError::PreconditionFailed(
+                    eyre!("The image {etag} of MAC {} is no longer current.", ack.mac),
+                )),
+                _ => handler
+                    .ack(ack.mac, ack.refresh)
+                    .await
+                    .map(|()| Reply::Acked(ack.mac)),
+            }
+        }
+        ClientMessage::Checkin(checkin) => match checkin.capabilities.validate() {
+            Ok(()) => handler
+                .put_capabilities(checkin.mac, checkin.capabilities)
+                .await
+                .map(|_| Reply::CheckedIn(checkin.mac)),
+            Err(e) => Err(AppError::BadRequest(e)),
+        },
+    };
+    result.unwrap_or_else(|e| Reply::Error(e.body()))
+}
+
+/// The push of `event`, if its MAC is subscribed.
+async fn push(state: &AppState, subscribed: &BTreeSet<EpdMac>, event: Event) -> Option<Push> {
+    let mac: EpdMac = event.mac.parse().ok()?;
+    if !subscribed.contains(&mac) {
+        return None;
+    }
+    Some(match event.kind {
+        EventKind::Render | EventKind::Upload => Push::Updated {
+            mac,
+            etag: etag(state, mac).await,
+        },
+        EventKind::Delete => Push::Deleted { mac },
+    })
+}
+
+/// Entity tag of the current PNG of `mac`.
+async fn etag(state: &AppState, mac: EpdMac) -> Option<String> {
+    let validators = state
+        .image_handler
+        .validators(mac, &mime::IMAGE_PNG, None)
+        .await
+        .ok()??;
+    Some(validators.etag)
+}